@@ -0,0 +1,255 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::Result;
+
+use crate::{
+    handler::{Context, Next, RequestHandler},
+    proto::{rcode, Message, Type},
+};
+
+/// Address range in CIDR notation, e.g. `192.0.2.0/24`. A bare address is a
+/// range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// The `prefix` bit long network `addr` is in, the prefix capped at the
+    /// address length.
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self {
+            addr,
+            prefix: prefix.min(max),
+        }
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // v4 clients on dual-stack sockets show up as ::ffff:a.b.c.d, which
+        // IPv6 networks can still be about
+        let addr = match self.addr {
+            IpAddr::V4(_) => addr.to_canonical(),
+            IpAddr::V6(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if net[..bytes] != addr[..bytes] {
+        return false;
+    }
+    let mask = !(0xFFu8 >> bits);
+    bits == 0 || net[bytes] & mask == addr[bytes] & mask
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{}: {:?}", e, s))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length: {:?}", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Clients allowed to do something: any not denied, and if there is an
+/// allow list, on it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    pub allow: Vec<Network>,
+    pub deny: Vec<Network>,
+}
+
+impl Acl {
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(addr))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(addr)))
+    }
+}
+
+/// What a client may be allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    // anything at all
+    Query,
+    // have names resolved that aren't answered locally
+    Recursion,
+    // zone transfers
+    Transfer,
+}
+
+/// Refuses requests needing a capability to clients its ACL doesn't permit.
+/// Placed where the requests needing it pass: queries at the start of the
+/// chain, recursion in front of the resolver.
+pub struct AclHandler {
+    pub capability: Capability,
+    pub acl: Acl,
+}
+
+impl RequestHandler for AclHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let needed = match self.capability {
+            Capability::Query | Capability::Recursion => true,
+            Capability::Transfer => request.questions.iter().any(|q| q.qtype == Type::AXFR),
+        };
+        if needed && !self.acl.permits(ctx.source.ip()) {
+            return Ok(request.error_reply(rcode::REFUSED));
+        }
+        next.run(ctx, request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Acl, AclHandler, Capability, Network};
+    use crate::{
+        handler::{Chain, Context, Next, RequestHandler, Transport},
+        proto::{rcode, Class, Message, Name, Question, Type},
+    };
+    use anyhow::Result;
+
+    #[test]
+    fn test_contains() {
+        let net: Network = "192.0.2.0/23".parse().unwrap();
+        assert!(net.contains("192.0.3.255".parse().unwrap()));
+        assert!(!net.contains("192.0.4.1".parse().unwrap()));
+        assert!(net.contains("::ffff:192.0.2.1".parse().unwrap()));
+
+        let host: Network = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+        assert!(!any.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("fd00::/8".parse::<Network>().is_ok());
+    }
+
+    #[test]
+    fn test_permits() {
+        let acl = Acl {
+            allow: vec![
+                "192.0.2.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            deny: vec!["192.0.2.66".parse().unwrap()],
+        };
+        assert!(acl.permits("192.0.2.1".parse().unwrap()));
+        assert!(acl.permits("2001:db8::1".parse().unwrap()));
+        assert!(!acl.permits("192.0.2.66".parse().unwrap()));
+        assert!(!acl.permits("198.51.100.1".parse().unwrap()));
+
+        let deny_only = Acl {
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Acl::default()
+        };
+        assert!(deny_only.permits("198.51.100.1".parse().unwrap()));
+        assert!(!deny_only.permits("10.1.2.3".parse().unwrap()));
+        assert!(Acl::default().permits("::1".parse().unwrap()));
+    }
+
+    struct Answer;
+
+    impl RequestHandler for Answer {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            Ok(request.reply())
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        let acl = Acl {
+            allow: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Acl::default()
+        };
+        let rcode = |capability, source: &str, qtype| {
+            let chain = Chain::default()
+                .with(AclHandler {
+                    capability,
+                    acl: acl.clone(),
+                })
+                .with(Answer);
+            let ctx = Context {
+                source: source.parse().unwrap(),
+                transport: Transport::Tcp,
+                key: None,
+            };
+            let request = Message {
+                questions: vec![Question {
+                    name: Name::from("example.com"),
+                    qtype,
+                    class: Class::IN,
+                }],
+                ..Message::default()
+            };
+            chain.handle(&ctx, request).unwrap().rcode
+        };
+
+        assert_eq!(
+            rcode::REFUSED,
+            rcode(Capability::Query, "198.51.100.1:53", Type::A)
+        );
+        assert_eq!(
+            rcode::NOERROR,
+            rcode(Capability::Query, "192.0.2.9:53", Type::A)
+        );
+        assert_eq!(
+            rcode::REFUSED,
+            rcode(Capability::Recursion, "198.51.100.1:53", Type::A)
+        );
+
+        // only transfers need the transfer capability
+        assert_eq!(
+            rcode::NOERROR,
+            rcode(Capability::Transfer, "198.51.100.1:53", Type::A)
+        );
+        assert_eq!(
+            rcode::REFUSED,
+            rcode(Capability::Transfer, "198.51.100.1:53", Type::AXFR)
+        );
+        assert_eq!(
+            rcode::NOERROR,
+            rcode(Capability::Transfer, "192.0.2.9:53", Type::AXFR)
+        );
+    }
+}
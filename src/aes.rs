@@ -0,0 +1,258 @@
+//! AES (FIPS 197) in Galois/Counter Mode (NIST SP 800-38D), the AEAD of
+//! TLS_AES_128_GCM_SHA256 (RFC 8446) and of the HPKE suite of ODoH. Only
+//! the forward cipher is needed. The S-box is a table lookup, so this is not
+//! hardened against cache-timing attacks.
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// Length of the authentication tag appended to ciphertexts.
+pub const TAG_LEN: usize = 16;
+
+/// Length of the nonces GCM is used with here (SP 800-38D, section 5.2.1.1).
+pub const NONCE_LEN: usize = 12;
+
+/// AES-128 or AES-256 key, expanded into its round keys.
+#[derive(Clone)]
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+}
+
+impl Aes {
+    /// Expands a 16 or 32 byte key (FIPS 197, section 5.2), `None` for other
+    /// lengths.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = match key.len() {
+            16 => 4,
+            32 => 8,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+        let mut words: Vec<[u8; 4]> = key.chunks(4).map(|w| w.try_into().unwrap()).collect();
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut w = words[i - 1];
+            if i % nk == 0 {
+                w = [
+                    SBOX[w[1] as usize],
+                    SBOX[w[2] as usize],
+                    SBOX[w[3] as usize],
+                    SBOX[w[0] as usize],
+                ];
+                w[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                w = w.map(|b| SBOX[b as usize]);
+            }
+            let prev = words[i - nk];
+            words.push([
+                w[0] ^ prev[0],
+                w[1] ^ prev[1],
+                w[2] ^ prev[2],
+                w[3] ^ prev[3],
+            ]);
+        }
+        let round_keys = words
+            .chunks(4)
+            .map(|ws| {
+                let mut k = [0u8; 16];
+                for (chunk, w) in k.chunks_mut(4).zip(ws) {
+                    chunk.copy_from_slice(w);
+                }
+                k
+            })
+            .collect();
+        Some(Self { round_keys })
+    }
+
+    /// Encrypts a single block (FIPS 197, section 5.1).
+    pub fn encrypt_block(&self, block: &[u8; 16]) -> [u8; 16] {
+        let mut state = *block;
+        add_round_key(&mut state, &self.round_keys[0]);
+        let last = self.round_keys.len() - 1;
+        for (round, key) in self.round_keys.iter().enumerate().skip(1) {
+            state = state.map(|b| SBOX[b as usize]);
+            shift_rows(&mut state);
+            if round != last {
+                mix_columns(&mut state);
+            }
+            add_round_key(&mut state, key);
+        }
+        state
+    }
+
+    /// GCM authenticated encryption: the ciphertext of `plaintext` with the
+    /// tag over it and `aad` appended.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = plaintext.to_vec();
+        self.ctr(nonce, &mut out);
+        let tag = self.tag(nonce, aad, &out);
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    /// GCM authenticated decryption, `None` if the tag doesn't match.
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let split = sealed.len().checked_sub(TAG_LEN)?;
+        let (ciphertext, tag) = sealed.split_at(split);
+        if !crate::digest::constant_time_eq(&self.tag(nonce, aad, ciphertext), tag) {
+            return None;
+        }
+        let mut out = ciphertext.to_vec();
+        self.ctr(nonce, &mut out);
+        Some(out)
+    }
+
+    /// XORs `data` with the key stream, counting from 2 (SP 800-38D,
+    /// section 7.1).
+    fn ctr(&self, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(16).enumerate() {
+            let stream = self.encrypt_block(&counter_block(nonce, i as u32 + 2));
+            for (b, s) in chunk.iter_mut().zip(stream) {
+                *b ^= s;
+            }
+        }
+    }
+
+    fn tag(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+        let h = u128::from_be_bytes(self.encrypt_block(&[0; 16]));
+        let mut y = 0u128;
+        for data in [aad, ciphertext] {
+            for chunk in data.chunks(16) {
+                let mut block = [0u8; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = gf_mul(y ^ u128::from_be_bytes(block), h);
+            }
+        }
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        y = gf_mul(y ^ lengths, h);
+        let mask = u128::from_be_bytes(self.encrypt_block(&counter_block(nonce, 1)));
+        (y ^ mask).to_be_bytes()
+    }
+}
+
+fn counter_block(nonce: &[u8; NONCE_LEN], counter: u32) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[..NONCE_LEN].copy_from_slice(nonce);
+    block[NONCE_LEN..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// Multiplication in GF(2^128) with GCM's bit order (SP 800-38D, section
+/// 6.3).
+fn gf_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        // without branching on the secret bits
+        z ^= v & 0u128.wrapping_sub((x >> i) & 1);
+        v = (v >> 1) ^ (R & 0u128.wrapping_sub(v & 1));
+    }
+    z
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (((b >> 7) & 1) * 0x1b)
+}
+
+fn add_round_key(state: &mut [u8; 16], key: &[u8; 16]) {
+    for (s, k) in state.iter_mut().zip(key) {
+        *s ^= k;
+    }
+}
+
+/// The state is column-major: byte `r + 4c` is row r of column c.
+fn shift_rows(state: &mut [u8; 16]) {
+    let old = *state;
+    for c in 0..4 {
+        for r in 1..4 {
+            state[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    for column in state.chunks_mut(4) {
+        let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+        let all = a ^ b ^ c ^ d;
+        column[0] ^= all ^ xtime(a ^ b);
+        column[1] ^= all ^ xtime(b ^ c);
+        column[2] ^= all ^ xtime(c ^ d);
+        column[3] ^= all ^ xtime(d ^ a);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Aes;
+    use crate::encoding::{hex_decode, hex_encode};
+
+    #[test]
+    fn test_block() {
+        // FIPS 197, appendix C.1 and C.3
+        let plaintext: [u8; 16] = hex_decode("00112233445566778899aabbccddeeff")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let key: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            "69c4e0d86a7b0430d8cdb78070b4c55a",
+            hex_encode(&Aes::new(&key).unwrap().encrypt_block(&plaintext))
+        );
+        let key: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            "8ea2b7ca516745bfeafc49904b496089",
+            hex_encode(&Aes::new(&key).unwrap().encrypt_block(&plaintext))
+        );
+        assert!(Aes::new(&[0; 24]).is_none());
+    }
+
+    #[test]
+    fn test_gcm() {
+        // "The Galois/Counter Mode of Operation (GCM)", test case 4
+        let key = hex_decode("feffe9928665731c6d6a8f9467308308").unwrap();
+        let plaintext = hex_decode(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        )
+        .unwrap();
+        let aad = hex_decode("feedfacedeadbeeffeedfacedeadbeefabaddad2").unwrap();
+        let nonce = hex_decode("cafebabefacedbaddecaf888")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let aes = Aes::new(&key).unwrap();
+
+        let sealed = aes.seal(&nonce, &aad, &plaintext);
+        assert_eq!(
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091\
+             5bc94fbc3221a5db94fae95ae7121a47",
+            hex_encode(&sealed)
+        );
+        assert_eq!(Some(plaintext), aes.open(&nonce, &aad, &sealed));
+
+        let mut tampered = sealed.clone();
+        tampered[3] ^= 1;
+        assert_eq!(None, aes.open(&nonce, &aad, &tampered));
+        assert_eq!(None, aes.open(&nonce, b"", &sealed));
+        assert_eq!(None, aes.open(&nonce, &aad, &sealed[..10]));
+    }
+}
@@ -0,0 +1,561 @@
+//! Trust anchors: the root zone's key signing keys built in, more given in
+//! the configuration or read from files, and automated updates of anchored
+//! keys as their zones roll them over (RFC 5011).
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    dnssec::{self, REVOKE, SEP},
+    encoding::{base64_decode, hex_decode},
+    proto::{Name, Record},
+    rdata::{Dnskey, Ds, RData, Rrsig},
+};
+
+/// The root zone's key signing keys, KSK-2017 and KSK-2024, as published
+/// by IANA (https://data.iana.org/root-anchors/root-anchors.xml).
+const ROOT_KEYS: [&str; 2] = [
+    ". IN DNSKEY 257 3 8 AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixHlF\
+     lExOLAJr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WGe2R8PzgCmr3EgVLrjyBxW\
+     ezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eNbuv7pr+eoZG+SrDK6nWeL3c6H5Apxz7LjVc1uTIds\
+     IXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6+cn8HFRm+2hM8AnXGXws9555KrUB5qihylGa8subX2Nn6UwNR1AkUTV74bU=",
+    ". IN DNSKEY 257 3 8 AwEAAa96jeuknZlaeSrvyAJj6ZHv28hhOKkx3rLGXVaC6rXTsDc449/cidltpkyGwCJNnOAlFN\
+     KF2jBosZBU5eeHspaQWOmOElZsjICMQMC3aeHbGiShvZsx4wMYSjH8e7Vrhbu6irwCzVBApESjbUdpWWmEnhathWu1jo\
+     +siFUiRAAxm9qyJNg/wOZqqzL/dL/q8PkcRU5oUKEpUge71M3ej2/7CPqpdVwuMoTvoB+ZOT4YeGyxMvHmbrxlFzGOHO\
+     ijtzN+u1TQNatX2XBuzZNQ1K+s2CXkPIZo7s6JgZyvaBevYtxPvYLw4z9mR7K2vaF18UYH9Z9GNUUeayffKC73PYc=",
+];
+
+/// How long a new key must be seen before it is trusted, and a revoked one
+/// is remembered (RFC 5011, section 2.4.1).
+const ADD_HOLD_DOWN: u64 = 30 * 86400;
+const REMOVE_HOLD_DOWN: u64 = 30 * 86400;
+
+/// Key a chain of trust starts from, as a DS record of its zone. Anchors
+/// given as a DNSKEY are kept as their SHA-256 DS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustAnchor {
+    pub zone: Name,
+    pub ds: Ds,
+}
+
+impl FromStr for TrustAnchor {
+    type Err = String;
+
+    /// Parses a DS or DNSKEY record in presentation format, the TTL and
+    /// class optional: `<zone> [<ttl>] [IN] DS <key tag> <algorithm>
+    /// <digest type> <digest>` or `<zone> [<ttl>] [IN] DNSKEY <flags>
+    /// <protocol> <algorithm> <public key>`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid trust anchor {:?}", s);
+        let (zone, rtype, fields) = split_record(s).ok_or_else(invalid)?;
+        let ds = match rtype.as_str() {
+            "DS" => match fields.as_slice() {
+                [key_tag, algorithm, digest_type, digest @ ..] => Ds {
+                    key_tag: key_tag.parse().map_err(|_| invalid())?,
+                    algorithm: algorithm.parse().map_err(|_| invalid())?,
+                    digest_type: digest_type.parse().map_err(|_| invalid())?,
+                    digest: hex_decode(&digest.concat()).ok_or_else(invalid)?,
+                },
+                _ => return Err(invalid()),
+            },
+            "DNSKEY" => {
+                let key = parse_dnskey(&fields).ok_or_else(invalid)?;
+                dnssec::ds(&zone, &key, dnssec::DIGEST_SHA256).ok_or_else(invalid)?
+            }
+            _ => return Err(invalid()),
+        };
+        if ds.digest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { zone, ds })
+    }
+}
+
+/// Owner, uppercased type and RDATA fields of a record in presentation
+/// format, without the TTL and class.
+fn split_record(s: &str) -> Option<(Name, String, Vec<&str>)> {
+    let mut fields = s.split_whitespace().peekable();
+    let zone: Name = fields.next()?.parse().ok()?;
+    if fields.peek()?.bytes().all(|b| b.is_ascii_digit()) {
+        fields.next();
+    }
+    if fields.peek()?.eq_ignore_ascii_case("IN") {
+        fields.next();
+    }
+    let rtype = fields.next()?.to_ascii_uppercase();
+    Some((zone, rtype, fields.collect()))
+}
+
+fn parse_dnskey(fields: &[&str]) -> Option<Dnskey> {
+    let [flags, protocol, algorithm, public_key @ ..] = fields else {
+        return None;
+    };
+    Some(Dnskey {
+        flags: flags.parse().ok()?,
+        protocol: protocol.parse().ok()?,
+        algorithm: algorithm.parse().ok()?,
+        public_key: base64_decode(&public_key.concat())?,
+    })
+}
+
+/// The built-in anchors of the root zone.
+pub fn root() -> Vec<TrustAnchor> {
+    ROOT_KEYS
+        .iter()
+        .map(|key| key.parse().expect("valid root key"))
+        .collect()
+}
+
+/// Reads a file of trust anchors, one DS or DNSKEY record per line. Blank
+/// lines and `;` comments are skipped.
+pub fn load(path: &Path) -> Result<Vec<TrustAnchor>> {
+    let text = fs::read_to_string(path)?;
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split(';').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(line, record)| {
+            record
+                .parse()
+                .map_err(|e: String| anyhow!("{}:{}: {}", path.display(), line, e))
+        })
+        .collect()
+}
+
+/// Where a tracked key is in its life (RFC 5011, section 4).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // seen, but not trusted before the add hold-down is over
+    AddPend,
+    Valid,
+    // trusted, but gone from the zone's DNSKEY RRset
+    Missing,
+    // revoked by its owner, remembered until the remove hold-down is over
+    Revoked,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::AddPend => "ADDPEND",
+            Self::Valid => "VALID",
+            Self::Missing => "MISSING",
+            Self::Revoked => "REVOKED",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        [Self::AddPend, Self::Valid, Self::Missing, Self::Revoked]
+            .into_iter()
+            .find(|state| state.name().eq_ignore_ascii_case(s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Tracked {
+    zone: Name,
+    key: Dnskey,
+    state: State,
+    // Unix time of the last state change
+    since: u64,
+}
+
+/// Keys of the anchored zones as tracked across rollovers, persisted in a
+/// state file. Once a zone has tracked keys, its trusted ones replace the
+/// configured anchors.
+#[derive(Debug)]
+pub struct Tracker {
+    path: PathBuf,
+    keys: Vec<Tracked>,
+}
+
+impl Tracker {
+    /// Reads the state file, nothing is tracked yet if there is none.
+    ///
+    /// Each line is a DNSKEY record, then the key's state and since when:
+    /// `<zone> DNSKEY <flags> <protocol> <algorithm> <key> ; <state> <time>`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut tracker = Self {
+            path: path.to_path_buf(),
+            keys: Vec::new(),
+        };
+        if !path.exists() {
+            return Ok(tracker);
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let tracked = parse_tracked(line)
+                .ok_or_else(|| anyhow!("{}:{}: invalid key state", path.display(), i + 1))?;
+            tracker.keys.push(tracked);
+        }
+        Ok(tracker)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut text = String::from("; trust anchor state (RFC 5011), updated as keys roll over\n");
+        for tracked in self.keys.iter() {
+            text.push_str(&format!(
+                "{} DNSKEY {} ; {} {}\n",
+                tracked.zone.to_fqdn(),
+                RData::DNSKEY(tracked.key.clone()),
+                tracked.state.name(),
+                tracked.since
+            ));
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// DS records of the keys trusted for `zone`, `None` while none of its
+    /// keys are tracked.
+    pub fn anchors(&self, zone: &Name) -> Option<Vec<Ds>> {
+        let mut tracked = self.keys.iter().filter(|t| t.zone == *zone).peekable();
+        tracked.peek()?;
+        Some(
+            tracked
+                .filter(|t| matches!(t.state, State::Valid | State::Missing))
+                .filter_map(|t| dnssec::ds(zone, &t.key, dnssec::DIGEST_SHA256))
+                .collect(),
+        )
+    }
+
+    /// Moves the keys of `zone` along with its DNSKEY RRset, validated with
+    /// the `trusted` DS records (RFC 5011, section 4). The first time a zone
+    /// is seen, the keys matching them are trusted right away. Returns
+    /// whether anything changed.
+    pub fn update(
+        &mut self,
+        zone: &Name,
+        trusted: &[Ds],
+        rrset: &[Record],
+        sigs: &[&Rrsig],
+        now: u64,
+    ) -> bool {
+        let keys: Vec<&Dnskey> = rrset
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::DNSKEY(key) if key.flags & SEP != 0 => Some(key),
+                _ => None,
+            })
+            .collect();
+        let first = !self.keys.iter().any(|t| t.zone == *zone);
+        let before = self.keys.clone();
+
+        for key in keys.iter() {
+            let tracked = self
+                .keys
+                .iter_mut()
+                .find(|t| t.zone == *zone && same_key(&t.key, key));
+            if key.flags & REVOKE != 0 {
+                // only the key itself can revoke it (section 2.1)
+                let self_signed = sigs.iter().any(|sig| {
+                    sig.key_tag == dnssec::key_tag(key) && dnssec::verify(sig, key, rrset)
+                });
+                match tracked {
+                    Some(t) if self_signed && t.state != State::Revoked => {
+                        t.state = State::Revoked;
+                        t.since = now;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match tracked {
+                None => {
+                    let anchored = dnssec::ds(zone, key, dnssec::DIGEST_SHA256)
+                        .is_some_and(|ds| trusted.contains(&ds));
+                    self.keys.push(Tracked {
+                        zone: zone.clone(),
+                        key: (*key).clone(),
+                        state: match first && anchored {
+                            true => State::Valid,
+                            false => State::AddPend,
+                        },
+                        since: now,
+                    });
+                }
+                Some(t) => match t.state {
+                    State::AddPend if now.saturating_sub(t.since) >= ADD_HOLD_DOWN => {
+                        t.state = State::Valid;
+                        t.since = now;
+                    }
+                    State::Missing => {
+                        t.state = State::Valid;
+                        t.since = now;
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        // keys gone from the RRset
+        self.keys.retain_mut(|t| {
+            if t.zone != *zone || keys.iter().any(|key| same_key(&t.key, key)) {
+                return true;
+            }
+            match t.state {
+                State::AddPend => false,
+                State::Valid => {
+                    t.state = State::Missing;
+                    t.since = now;
+                    true
+                }
+                State::Missing => true,
+                State::Revoked => now.saturating_sub(t.since) < REMOVE_HOLD_DOWN,
+            }
+        });
+        self.keys != before
+    }
+}
+
+/// Whether two DNSKEYs are the same key, revoked or not.
+fn same_key(a: &Dnskey, b: &Dnskey) -> bool {
+    a.algorithm == b.algorithm && a.public_key == b.public_key
+}
+
+fn parse_tracked(line: &str) -> Option<Tracked> {
+    let (record, state) = line.split_once(';')?;
+    let (zone, rtype, fields) = split_record(record)?;
+    if rtype != "DNSKEY" {
+        return None;
+    }
+    let mut state = state.split_whitespace();
+    let tracked = Tracked {
+        zone,
+        key: parse_dnskey(&fields)?,
+        state: State::parse(state.next()?)?,
+        since: state.next()?.parse().ok()?,
+    };
+    state.next().is_none().then_some(tracked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, root, State, Tracker, TrustAnchor, ADD_HOLD_DOWN, REMOVE_HOLD_DOWN};
+    use crate::{
+        dnssec::{self, SigningKey, REVOKE},
+        ed25519,
+        encoding::hex_encode,
+        proto::{Class, Name, Record, Type},
+        rdata::{Dnskey, Ds, RData, Rrsig},
+    };
+
+    #[test]
+    fn test_root() {
+        let anchors = root();
+        let ds: Vec<(String, u16, String)> = anchors
+            .iter()
+            .map(|a| (a.zone.to_string(), a.ds.key_tag, hex_encode(&a.ds.digest)))
+            .collect();
+        // the DS records IANA publishes along with the keys
+        assert_eq!(
+            vec![
+                (
+                    String::new(),
+                    20326,
+                    "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d".into()
+                ),
+                (
+                    String::new(),
+                    38696,
+                    "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16".into()
+                ),
+            ],
+            ds.iter()
+                .map(|(zone, tag, digest)| (zone.clone(), *tag, digest.to_ascii_lowercase()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let anchor: TrustAnchor = "example.com. IN DS 60485 15 2 0A0B 0C0D".parse().unwrap();
+        assert_eq!(Name::from("example.com"), anchor.zone);
+        assert_eq!(
+            Ds {
+                key_tag: 60485,
+                algorithm: 15,
+                digest_type: 2,
+                digest: vec![0x0a, 0x0b, 0x0c, 0x0d],
+            },
+            anchor.ds
+        );
+        // with a TTL, as in zone files
+        let with_ttl: TrustAnchor = "example.com. 3600 DS 60485 15 2 0A0B0C0D".parse().unwrap();
+        assert_eq!(anchor, with_ttl);
+
+        // a DNSKEY becomes its SHA-256 DS
+        let key = SigningKey::from_seed([2; 32]);
+        let text = format!(
+            "example.com. DNSKEY 257 3 15 {}",
+            crate::encoding::base64_encode(&key.dnskey().public_key)
+        );
+        let anchor: TrustAnchor = text.parse().unwrap();
+        let zone = Name::from("example.com");
+        assert_eq!(
+            dnssec::ds(&zone, &key.dnskey(), dnssec::DIGEST_SHA256),
+            Some(anchor.ds)
+        );
+
+        assert!("example.com. DS 60485 15 2".parse::<TrustAnchor>().is_err());
+        assert!("example.com. DS 60485 15 2 zz"
+            .parse::<TrustAnchor>()
+            .is_err());
+        assert!("example.com. A 192.0.2.1 1 2"
+            .parse::<TrustAnchor>()
+            .is_err());
+        assert!("example.com.".parse::<TrustAnchor>().is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("anchors-test-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "; private zones\n\nexample.com. DS 60485 15 2 0A0B0C0D ; comment\n\
+             example.net. 86400 IN DS 1 15 2 0E0F\n",
+        )
+        .unwrap();
+        let anchors = load(&path).unwrap();
+        assert_eq!(2, anchors.len());
+        assert_eq!(Name::from("example.net"), anchors[1].zone);
+
+        std::fs::write(
+            &path,
+            "example.com. DS 60485 15 2 0A0B0C0D\nexample.net. DS\n",
+        )
+        .unwrap();
+        let err = load(&path).unwrap_err().to_string();
+        assert!(
+            err.ends_with(":2: invalid trust anchor \"example.net. DS\""),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// DNSKEY RRset of example.com with the given keys, signed by the
+    /// keys of the seeds in `signers`.
+    fn rrset(keys: &[Dnskey], signers: &[([u8; 32], &Dnskey)]) -> (Vec<Record>, Vec<Rrsig>) {
+        let zone = Name::from("example.com");
+        let records: Vec<Record> = keys
+            .iter()
+            .map(|key| Record {
+                name: zone.clone(),
+                rtype: Type::DNSKEY,
+                class: Class::IN,
+                ttl: 3600,
+                rdata: RData::DNSKEY(key.clone()),
+            })
+            .collect();
+        let sigs = signers
+            .iter()
+            .map(|(seed, key)| {
+                let mut sig = Rrsig {
+                    type_covered: Type::DNSKEY,
+                    algorithm: key.algorithm,
+                    labels: 2,
+                    original_ttl: 3600,
+                    expiration: u32::MAX,
+                    inception: 0,
+                    key_tag: dnssec::key_tag(key),
+                    signer_name: zone.clone(),
+                    signature: Vec::new(),
+                };
+                let data = dnssec::signed_data(&sig, &records).unwrap();
+                sig.signature = ed25519::sign(seed, &data).to_vec();
+                sig
+            })
+            .collect();
+        (records, sigs)
+    }
+
+    #[test]
+    fn test_tracker() {
+        let path = std::env::temp_dir().join(format!("anchors-state-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let zone = Name::from("example.com");
+        let (old_seed, new_seed) = ([1; 32], [2; 32]);
+        let old = SigningKey::from_seed(old_seed).dnskey();
+        let new = SigningKey::from_seed(new_seed).dnskey();
+        let revoked = Dnskey {
+            flags: old.flags | REVOKE,
+            ..old.clone()
+        };
+        let ds = |key: &Dnskey| dnssec::ds(&zone, key, dnssec::DIGEST_SHA256).unwrap();
+        let trusted = [ds(&old)];
+        let states =
+            |tracker: &Tracker| -> Vec<State> { tracker.keys.iter().map(|t| t.state).collect() };
+
+        let mut tracker = Tracker::load(&path).unwrap();
+        assert_eq!(None, tracker.anchors(&zone));
+
+        // the configured key is trusted from the start
+        let day = 86400;
+        let (records, sigs) = rrset(std::slice::from_ref(&old), &[(old_seed, &old)]);
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        assert!(tracker.update(&zone, &trusted, &records, &sigs, 0));
+        assert_eq!(vec![State::Valid], states(&tracker));
+        assert!(!tracker.update(&zone, &trusted, &records, &sigs, day));
+
+        // a new key waits out the hold-down
+        let (records, sigs) = rrset(&[old.clone(), new.clone()], &[(old_seed, &old)]);
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        tracker.update(&zone, &trusted, &records, &sigs, day);
+        assert_eq!(vec![State::Valid, State::AddPend], states(&tracker));
+        tracker.update(&zone, &trusted, &records, &sigs, day + ADD_HOLD_DOWN - 1);
+        assert_eq!(vec![ds(&old)], tracker.anchors(&zone).unwrap());
+        tracker.update(&zone, &trusted, &records, &sigs, day + ADD_HOLD_DOWN);
+        assert_eq!(vec![State::Valid, State::Valid], states(&tracker));
+
+        // the state survives a restart
+        tracker.save().unwrap();
+        let mut tracker = Tracker::load(&path).unwrap();
+        assert_eq!(vec![State::Valid, State::Valid], states(&tracker));
+
+        // a revocation only counts signed by the revoked key itself
+        let now = 40 * day;
+        let (records, sigs) = rrset(&[revoked.clone(), new.clone()], &[(new_seed, &new)]);
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        tracker.update(&zone, &trusted, &records, &sigs, now);
+        assert_eq!(vec![State::Valid, State::Valid], states(&tracker));
+        let (records, sigs) = rrset(
+            &[revoked.clone(), new.clone()],
+            &[(new_seed, &new), (old_seed, &revoked)],
+        );
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        tracker.update(&zone, &trusted, &records, &sigs, now);
+        assert_eq!(vec![State::Revoked, State::Valid], states(&tracker));
+        assert_eq!(vec![ds(&new)], tracker.anchors(&zone).unwrap());
+
+        // and is forgotten once gone for the remove hold-down
+        let (records, sigs) = rrset(std::slice::from_ref(&new), &[(new_seed, &new)]);
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        tracker.update(&zone, &trusted, &records, &sigs, now + day);
+        assert_eq!(vec![State::Revoked, State::Valid], states(&tracker));
+        tracker.update(&zone, &trusted, &records, &sigs, now + REMOVE_HOLD_DOWN);
+        assert_eq!(vec![State::Valid], states(&tracker));
+
+        // a trusted key that goes missing stays trusted, until it's back
+        let other = SigningKey::from_seed([3; 32]).dnskey();
+        let (records, sigs) = rrset(std::slice::from_ref(&other), &[([3; 32], &other)]);
+        let sigs: Vec<&Rrsig> = sigs.iter().collect();
+        tracker.update(&zone, &trusted, &records, &sigs, now + REMOVE_HOLD_DOWN);
+        assert_eq!(vec![State::Missing, State::AddPend], states(&tracker));
+        assert_eq!(vec![ds(&new)], tracker.anchors(&zone).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::{
+    handler::{Context, Next, RequestHandler, Transport},
+    proto::{Class, Message, Question, Record, Type},
+    rdata::{Hinfo, RData},
+};
+
+/// TTL of the synthetic HINFO answer, RFC 8482 section 4.2 suggests a long
+/// one so resolvers cache it.
+const HINFO_TTL: u32 = 86400;
+
+/// How ANY (QTYPE 255) queries are answered. Returning every record for a
+/// name makes ANY a cheap amplification vector, so RFC 8482 allows
+/// responders to answer with less.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnyPolicy {
+    /// Answer with all records, like any other query type
+    Full,
+    /// Answer with a single synthetic HINFO "RFC8482" record (section 4.2)
+    #[default]
+    Hinfo,
+    /// Answer with one RRset of those that exist for the name (section 4.1)
+    Subset,
+}
+
+impl FromStr for AnyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "hinfo" => Ok(Self::Hinfo),
+            "subset" => Ok(Self::Subset),
+            _ => Err(format!("unknown ANY policy {:?}", s)),
+        }
+    }
+}
+
+/// Applies the ANY policy of the transport a request arrived on, HTTPS,
+/// QUIC and DNSCrypt counting as TCP.
+pub struct AnyHandler {
+    pub udp: AnyPolicy,
+    pub tcp: AnyPolicy,
+}
+
+impl RequestHandler for AnyHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let policy = match ctx.transport {
+            Transport::Udp => self.udp,
+            Transport::Tcp
+            | Transport::Http
+            | Transport::Https
+            | Transport::Quic
+            | Transport::DnsCrypt => self.tcp,
+        };
+        let any = |q: &Question| q.qtype == Type::ANY;
+
+        if policy == AnyPolicy::Hinfo
+            && !request.questions.is_empty()
+            && request.questions.iter().all(any)
+        {
+            return Ok(Message {
+                answers: request.questions.iter().map(hinfo_answer).collect(),
+                ..request.reply()
+            });
+        }
+
+        let mut reply = next.run(ctx, request)?;
+        if policy != AnyPolicy::Full && reply.questions.iter().any(any) {
+            reply.answers = minimize_answers(&reply.questions, reply.answers);
+        }
+        Ok(reply)
+    }
+}
+
+/// Synthetic HINFO answer for an ANY question (RFC 8482, section 4.2).
+pub fn hinfo_answer(q: &Question) -> Record {
+    Record {
+        name: q.name.clone(),
+        rtype: Type::HINFO,
+        class: Class::IN,
+        ttl: HINFO_TTL,
+        rdata: RData::HINFO(Hinfo {
+            cpu: "RFC8482".into(),
+            os: "".into(),
+        }),
+    }
+}
+
+/// Reduces the answers to ANY questions to the first RRset found for each
+/// name, keeping any CNAME chain and the answers to other questions intact.
+pub fn minimize_answers(questions: &[Question], answers: Vec<Record>) -> Vec<Record> {
+    let mut kept: Vec<(&Question, Type)> = Vec::new();
+    answers
+        .into_iter()
+        .filter(|r| {
+            let Some(q) = questions
+                .iter()
+                .find(|q| q.qtype == Type::ANY && q.name == r.name)
+            else {
+                return true;
+            };
+            match kept.iter().find(|(k, _)| k.name == q.name) {
+                Some((_, rtype)) => *rtype == r.rtype,
+                None => {
+                    kept.push((q, r.rtype));
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hinfo_answer, minimize_answers, AnyHandler, AnyPolicy};
+    use crate::{
+        handler::{Chain, Context, Transport},
+        proto::{test::question, Class, Message, Name, Record, Type},
+        rdata::RData,
+    };
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, rtype: Type, rdata: RData) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype,
+            class: Class::IN,
+            ttl: 60,
+            rdata,
+        }
+    }
+
+    #[test]
+    fn test_hinfo_answer() {
+        let answer = hinfo_answer(&question("example.com", Type::ANY));
+        assert_eq!(Type::HINFO, answer.rtype);
+        assert_eq!("\"RFC8482\" \"\"", answer.rdata.to_string());
+    }
+
+    #[test]
+    fn test_minimize_answers() {
+        let a1 = record(
+            "example.com",
+            Type::A,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        let a2 = record(
+            "example.com",
+            Type::A,
+            RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+        );
+        let ns = record(
+            "EXAMPLE.com",
+            Type::NS,
+            RData::NS(Name::from("ns.example.com")),
+        );
+        let other = record("other.com", Type::NS, RData::NS(Name::from("ns.other.com")));
+        let answers = vec![a1.clone(), ns, a2.clone(), other.clone()];
+
+        let questions = vec![
+            question("example.com", Type::ANY),
+            question("other.com", Type::NS),
+        ];
+        assert_eq!(
+            vec![a1.clone(), a2.clone(), other.clone()],
+            minimize_answers(&questions, answers.clone())
+        );
+
+        // nothing to minimize without ANY questions
+        let questions = vec![question("example.com", Type::A)];
+        assert_eq!(answers, minimize_answers(&questions, answers.clone()));
+    }
+
+    #[test]
+    fn test_any_handler_per_transport() {
+        let chain = Chain::default().with(AnyHandler {
+            udp: AnyPolicy::Hinfo,
+            tcp: AnyPolicy::Full,
+        });
+        let request = Message {
+            questions: vec![question("example.com", Type::ANY)],
+            ..Message::default()
+        };
+        let ctx = |transport| Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport,
+            key: None,
+        };
+
+        let reply = chain.handle(&ctx(Transport::Udp), request.clone()).unwrap();
+        assert_eq!(Type::HINFO, reply.answers[0].rtype);
+
+        // passed on, nothing further in the chain answers
+        let reply = chain.handle(&ctx(Transport::Tcp), request).unwrap();
+        assert!(reply.answers.is_empty());
+    }
+}
@@ -0,0 +1,610 @@
+//! HTTP API to the records of the primary zones, so tools can register
+//! hosts and services without editing zone files. Requests carry the token
+//! as `Authorization: Bearer TOKEN`, and records go both ways in master file
+//! format, one per line, names relative to the zone:
+//!
+//! ```text
+//! GET    /zones                   origins of the zones, one per line
+//! GET    /zones/ZONE              records of the zone
+//! GET    /zones/ZONE/NAME[/TYPE]  records of a name, of one type
+//! POST   /zones/ZONE              adds the records of the body
+//! PUT    /zones/ZONE/NAME/TYPE    replaces the records with those of the body
+//! DELETE /zones/ZONE/NAME[/TYPE]  deletes the records
+//! ```
+//!
+//! Changes are written back to the zone's file, its comments and layout
+//! lost, with the SOA serial incremented. The zone is then served anew and
+//! its secondaries notified.
+
+use std::{
+    fmt, fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    digest::constant_time_eq,
+    doh::{is_idle, read_request, Request},
+    encoding::percent_decode,
+    notify, privacy,
+    proto::{Name, Record, Type},
+    rdata::RData,
+    tls::{Identity, TlsStream},
+    zone::{parse_records, Authoritative, Zone, ZoneConfig},
+};
+
+/// Idle time after which the server closes a connection.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections served at once, further ones are closed.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The zones served and the configs they were loaded from.
+pub struct Zones {
+    pub authoritative: Arc<Authoritative>,
+    pub configs: Vec<ZoneConfig>,
+}
+
+/// Response to a request, the body in plain text.
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn no_content() -> Self {
+        Self::error(204, "")
+    }
+
+    fn error(status: u16, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            body: message.to_string(),
+        }
+    }
+
+    fn to_bytes(&self, keep_alive: bool) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        let mut body = self.body.clone();
+        if !body.is_empty() && !body.ends_with('\n') {
+            body.push('\n');
+        }
+        let challenge = match self.status {
+            401 => "WWW-Authenticate: Bearer\r\n",
+            _ => "",
+        };
+        let connection = match keep_alive {
+            true => "keep-alive",
+            false => "close",
+        };
+        format!(
+            "HTTP/1.1 {} {}\r\n{}Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            self.status,
+            reason,
+            challenge,
+            body.len(),
+            connection,
+            body
+        )
+        .into_bytes()
+    }
+}
+
+/// The records a request is about: all of a zone, of a name, or of a name
+/// and type.
+struct Selector {
+    name: Option<Name>,
+    rtype: Option<Type>,
+}
+
+impl Selector {
+    /// Selector of the path segments after the zone, names relative to
+    /// `origin` unless they end in a dot.
+    fn parse(segments: &[&str], origin: &Name) -> Result<Self, Response> {
+        let name = segments
+            .first()
+            .map(|name| absolute(name, origin))
+            .transpose()
+            .map_err(|e| Response::error(400, e))?;
+        let rtype = match segments.get(1) {
+            Some(rtype) => Some(
+                rtype
+                    .to_ascii_uppercase()
+                    .parse()
+                    .map_err(|_| Response::error(400, format!("unknown type {}", rtype)))?,
+            ),
+            None => None,
+        };
+        Ok(Self { name, rtype })
+    }
+
+    fn selects(&self, record: &Record) -> bool {
+        self.name.as_ref().is_none_or(|name| *name == record.name)
+            && self.rtype.is_none_or(|rtype| rtype == record.rtype)
+    }
+}
+
+/// `name` made absolute below `origin`, `@` being the origin itself.
+fn absolute(name: &str, origin: &Name) -> Result<Name, String> {
+    match name {
+        "@" => Ok(origin.clone()),
+        _ if name.ends_with('.') => name.parse(),
+        _ => name.parse::<Name>()?.append(origin),
+    }
+}
+
+/// The records of a zone's file, the origin being the owner of its SOA.
+fn read(config: &ZoneConfig) -> Result<(Name, Vec<Record>)> {
+    let text = fs::read_to_string(&config.file)?;
+    let records = parse_records(&text, config.origin.as_ref(), config.default_ttl)?;
+    let origin = records
+        .iter()
+        .find(|r| r.rtype == Type::SOA)
+        .map(|soa| soa.name.clone())
+        .ok_or_else(|| anyhow!("no SOA record"))?;
+    Ok((origin, records))
+}
+
+/// Master file of `records`, the SOA first.
+fn render(origin: &Name, records: &[Record]) -> String {
+    let mut text = format!("; {} as last changed through the API\n", origin);
+    let (soa, others): (Vec<_>, Vec<_>) = records.iter().partition(|r| r.rtype == Type::SOA);
+    for record in soa.into_iter().chain(others) {
+        text.push_str(&format!("{}\n", record));
+    }
+    text
+}
+
+/// What a change does to the records of a zone.
+enum Change {
+    Add(Vec<Record>),
+    Replace(Selector, Vec<Record>),
+    Delete(Selector),
+}
+
+struct Api {
+    identity: Option<Identity>,
+    token: String,
+    zones: Box<dyn Fn() -> Option<Zones> + Send + Sync>,
+    // a change reads, edits and writes a file, one at a time
+    changing: Mutex<()>,
+}
+
+impl Api {
+    fn authorized(&self, request: &Request) -> bool {
+        request
+            .authorization
+            .strip_prefix("Bearer ")
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    fn respond(&self, request: &Request, source: SocketAddr) -> Response {
+        if !self.authorized(request) {
+            return Response::error(401, "missing or wrong token");
+        }
+        let path = request
+            .target
+            .split_once('?')
+            .map_or(&request.target[..], |(path, _)| path);
+        let Some(segments) = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(percent_decode)
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Response::error(400, "invalid path");
+        };
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let Some(zones) = (self.zones)() else {
+            return Response::error(404, "no zones are served");
+        };
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["zones"]) => {
+                let origins: Vec<_> = primaries(&zones)
+                    .filter_map(|config| read(config).ok())
+                    .map(|(origin, _)| origin.to_string())
+                    .collect();
+                Response::ok(origins.join("\n"))
+            }
+            (_, ["zones"]) => Response::error(405, "only GET lists zones"),
+            (method, ["zones", zone, rest @ ..]) if rest.len() <= 2 => {
+                let origin = match zone.parse::<Name>() {
+                    Ok(origin) => origin,
+                    Err(e) => return Response::error(400, e),
+                };
+                match self.zone_request(&zones, &origin, method, rest, &request.body) {
+                    Ok(response) => {
+                        if method != "GET" && response.status < 300 {
+                            tracing::info!(
+                                zone = %origin, %method, %path, client = %privacy::client(source),
+                                "Changed zone through the API"
+                            );
+                        }
+                        response
+                    }
+                    Err(response) => response,
+                }
+            }
+            _ => Response::error(404, "no such resource"),
+        }
+    }
+
+    /// Lists or changes the records of the zone at `origin`.
+    fn zone_request(
+        &self,
+        zones: &Zones,
+        origin: &Name,
+        method: &str,
+        segments: &[&str],
+        body: &[u8],
+    ) -> Result<Response, Response> {
+        let _changing = self.changing.lock().unwrap();
+        let not_found = || Response::error(404, format!("no primary zone {}", origin));
+        let config = primaries(zones)
+            .find(|config| match &config.origin {
+                Some(configured) => configured == origin,
+                None => read(config).is_ok_and(|(loaded, _)| loaded == *origin),
+            })
+            .ok_or_else(not_found)?;
+        let (origin, records) =
+            read(config).map_err(|e| Response::error(500, format!("{:#}", e)))?;
+        let selector = Selector::parse(segments, &origin)?;
+        if selector.rtype == Some(Type::SOA) && method != "GET" {
+            return Err(Response::error(400, "the SOA record is kept by the server"));
+        }
+        let body = || {
+            let text = std::str::from_utf8(body)
+                .map_err(|_| Response::error(400, "the body isn't UTF-8"))?;
+            let default_ttl = config.default_ttl.or(records.first().map(|soa| soa.ttl));
+            let records = parse_records(text, Some(&origin), default_ttl)
+                .map_err(|e| Response::error(400, e))?;
+            if let Some(record) = records.iter().find(|r| !r.name.is_subdomain_of(&origin)) {
+                return Err(Response::error(
+                    400,
+                    format!("{} is outside the zone {}", record.name, origin),
+                ));
+            }
+            if records.iter().any(|r| r.rtype == Type::SOA) {
+                return Err(Response::error(400, "the SOA record is kept by the server"));
+            }
+            Ok(records)
+        };
+        let change = match (method, segments.len()) {
+            ("GET", _) => {
+                let selected: Vec<_> = records
+                    .iter()
+                    .filter(|r| selector.selects(r))
+                    .map(Record::to_string)
+                    .collect();
+                if selected.is_empty() && selector.name.is_some() {
+                    return Err(Response::error(404, "no such records"));
+                }
+                return Ok(Response::ok(selected.join("\n")));
+            }
+            ("POST", 0) => Change::Add(body()?),
+            ("PUT", 2) => {
+                let replacements = body()?;
+                if replacements.is_empty() {
+                    return Err(Response::error(
+                        400,
+                        "no records given, DELETE removes them",
+                    ));
+                }
+                if !replacements.iter().all(|r| selector.selects(r)) {
+                    return Err(Response::error(
+                        400,
+                        "the records aren't of the name and type of the path",
+                    ));
+                }
+                Change::Replace(selector, replacements)
+            }
+            ("DELETE", 1 | 2) => Change::Delete(selector),
+            ("POST" | "PUT" | "DELETE", _) => {
+                return Err(Response::error(405, "not allowed on this path"))
+            }
+            _ => return Err(Response::error(405, "unsupported method")),
+        };
+        let records = apply(records, change)?;
+        let zone = write(config, &origin, records)
+            .map_err(|e| Response::error(500, format!("{:#}", e)))?;
+        let soa = zone.soa().clone();
+        zones.authoritative.replace(zone);
+        if !config.notify.is_empty() {
+            notify::send(soa, config.notify.clone());
+        }
+        Ok(Response::no_content())
+    }
+}
+
+/// The configs of the zones with master files.
+fn primaries(zones: &Zones) -> impl Iterator<Item = &ZoneConfig> {
+    zones
+        .configs
+        .iter()
+        .filter(|config| config.primary.is_none())
+}
+
+/// `records` after `change`, with the SOA serial incremented.
+fn apply(mut records: Vec<Record>, change: Change) -> Result<Vec<Record>, Response> {
+    match change {
+        Change::Add(added) => {
+            for record in added {
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+        }
+        Change::Replace(selector, replacements) => {
+            records.retain(|r| !selector.selects(r));
+            records.extend(replacements);
+        }
+        Change::Delete(selector) => {
+            let before = records.len();
+            records.retain(|r| r.rtype == Type::SOA || !selector.selects(r));
+            if records.len() == before {
+                return Err(Response::error(404, "no such records"));
+            }
+        }
+    }
+    for record in records.iter_mut() {
+        if let RData::SOA(soa) = &mut record.rdata {
+            soa.serial = soa.serial.wrapping_add(1);
+        }
+    }
+    Ok(records)
+}
+
+/// Replaces the file of a zone with `records`, once they make a valid
+/// zone, and loads it again.
+fn write(config: &ZoneConfig, origin: &Name, records: Vec<Record>) -> Result<Zone> {
+    let text = render(origin, &records);
+    Zone::parse(&text, Some(origin), None)?;
+    let mut partial = config.file.clone().into_os_string();
+    partial.push(".tmp");
+    fs::write(&partial, text)?;
+    fs::rename(&partial, &config.file)?;
+    Zone::load(config)
+}
+
+/// Accepts API connections on `listener`, over TLS with an `identity` or
+/// plain HTTP without one, and serves each on its own thread. Requests need
+/// `token`, and change the zones `zones` gives at the time.
+pub fn spawn(
+    listener: TcpListener,
+    identity: Option<Identity>,
+    token: String,
+    zones: impl Fn() -> Option<Zones> + Send + Sync + 'static,
+) -> Result<()> {
+    let api = Arc::new(Api {
+        identity,
+        token,
+        zones: Box::new(zones),
+        changing: Mutex::new(()),
+    });
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new().name("api".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+            let (api, connections) = (api.clone(), connections.clone());
+            thread::spawn(move || {
+                if let Err(e) = serve_conn(stream, &api) {
+                    tracing::warn!(error = %e, "API connection failed");
+                }
+                connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    })?;
+    Ok(())
+}
+
+fn serve_conn(stream: TcpStream, api: &Api) -> Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    match &api.identity {
+        Some(identity) => {
+            let conn = TlsStream::accept(stream, identity, &["http/1.1"])?;
+            serve_requests(conn, source, api)
+        }
+        None => serve_requests(stream, source, api),
+    }
+}
+
+fn serve_requests(mut conn: impl Read + Write, source: SocketAddr, api: &Api) -> Result<()> {
+    loop {
+        let request = match read_request(&mut conn) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if is_idle(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let response = api.respond(&request, source);
+        conn.write_all(&response.to_bytes(request.keep_alive))?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Api, Response, Zones};
+    use crate::{
+        doh::Request,
+        proto::{Class, Name, Question, Type},
+        zone::{Authoritative, Zone, ZoneConfig},
+    };
+    use std::{
+        fs,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    const ZONE: &str = "\
+$ORIGIN home.test.
+$TTL 300
+@ IN SOA ns1 hostmaster 7 3600 600 86400 300
+@ IN NS ns1
+ns1 IN A 192.0.2.1
+nas IN A 192.0.2.10
+";
+
+    fn api(file: &std::path::Path) -> (Api, Arc<Authoritative>) {
+        fs::write(file, ZONE).unwrap();
+        let config = ZoneConfig {
+            file: file.to_path_buf(),
+            ..ZoneConfig::default()
+        };
+        let authoritative = Arc::new(Authoritative::new(vec![Zone::load(&config).unwrap()]));
+        let served = authoritative.clone();
+        let api = Api {
+            identity: None,
+            token: "s3cret".into(),
+            zones: Box::new(move || {
+                Some(Zones {
+                    authoritative: served.clone(),
+                    configs: vec![config.clone()],
+                })
+            }),
+            changing: Mutex::new(()),
+        };
+        (api, authoritative)
+    }
+
+    fn request(api: &Api, method: &str, target: &str, body: &str) -> Response {
+        let request = Request {
+            method: method.into(),
+            target: target.into(),
+            authorization: "Bearer s3cret".into(),
+            body: body.as_bytes().to_vec(),
+            ..Request::default()
+        };
+        let source: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        api.respond(&request, source)
+    }
+
+    fn served(authoritative: &Authoritative, name: &str) -> usize {
+        let zone = authoritative.find(&Name::from(name)).unwrap().unwrap();
+        let q = Question {
+            name: Name::from(name),
+            qtype: Type::A,
+            class: Class::IN,
+        };
+        zone.lookup(&q).answers.len()
+    }
+
+    #[test]
+    fn test_records() {
+        let file = std::env::temp_dir().join(format!("api-test-{}.zone", std::process::id()));
+        let (api, authoritative) = api(&file);
+
+        let unauthorized = Request {
+            method: "GET".into(),
+            target: "/zones".into(),
+            authorization: "Bearer guess".into(),
+            ..Request::default()
+        };
+        let source = "127.0.0.1:40000".parse().unwrap();
+        assert_eq!(401, api.respond(&unauthorized, source).status);
+
+        assert_eq!(
+            Response::ok("home.test".into()),
+            request(&api, "GET", "/zones", "")
+        );
+        let response = request(&api, "GET", "/zones/home.test/nas", "");
+        assert_eq!(
+            Response::ok("nas.home.test.\t300\tIN\tA\t192.0.2.10".into()),
+            response
+        );
+        assert_eq!(404, request(&api, "GET", "/zones/other.test", "").status);
+
+        // added, served and written back with the next serial
+        let response = request(&api, "POST", "/zones/home.test", "printer A 192.0.2.20\n");
+        assert_eq!(204, response.status);
+        assert_eq!(1, served(&authoritative, "printer.home.test"));
+        let text = fs::read_to_string(&file).unwrap();
+        assert!(text.contains("hostmaster.home.test. 8 3600"), "{}", text);
+
+        let response = request(
+            &api,
+            "PUT",
+            "/zones/home.test/nas/A",
+            "nas 60 A 192.0.2.11\nnas 60 A 192.0.2.12",
+        );
+        assert_eq!(204, response.status);
+        assert_eq!(2, served(&authoritative, "nas.home.test"));
+        let response = request(&api, "PUT", "/zones/home.test/nas/A", "printer A 192.0.2.9");
+        assert_eq!(400, response.status);
+
+        assert_eq!(
+            204,
+            request(&api, "DELETE", "/zones/home.test/printer", "").status
+        );
+        assert_eq!(0, served(&authoritative, "printer.home.test"));
+        assert_eq!(
+            404,
+            request(&api, "DELETE", "/zones/home.test/printer", "").status
+        );
+
+        // the SOA stays the server's
+        assert_eq!(
+            400,
+            request(&api, "DELETE", "/zones/home.test/@/SOA", "").status
+        );
+        let response = request(
+            &api,
+            "POST",
+            "/zones/home.test",
+            "www.other.test. A 192.0.2.1",
+        );
+        assert_eq!(400, response.status);
+        assert_eq!(405, request(&api, "PATCH", "/zones/home.test", "").status);
+
+        let reloaded = Zone::load(&ZoneConfig {
+            file: file.clone(),
+            ..ZoneConfig::default()
+        })
+        .unwrap();
+        assert_eq!(5, reloaded.records().count());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_response() {
+        let response = Response::error(401, "missing or wrong token").to_bytes(false);
+        assert_eq!(
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 23\r\n\
+             Connection: close\r\n\r\nmissing or wrong token\n",
+            String::from_utf8(response).unwrap()
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use rand::{seq::SliceRandom, Rng};
+
+use crate::forward::Endpoint;
+
+/// Share of queries under the fastest strategy that go to another upstream
+/// first, so a slow one that recovered gets noticed.
+const PROBE_RATE: f64 = 0.05;
+
+/// How the forwarder orders upstream resolvers for each query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Always in configured order, later ones only on failure
+    Ordered,
+    /// Lowest smoothed round-trip time first
+    #[default]
+    Fastest,
+    /// Rotate the first upstream on every query
+    RoundRobin,
+    /// Shuffle the upstreams on every query
+    Random,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ordered" => Ok(Self::Ordered),
+            "fastest" => Ok(Self::Fastest),
+            "round-robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            _ => Err(format!("unknown upstream strategy {:?}", s)),
+        }
+    }
+}
+
+/// Orders upstreams by a strategy, tracking a smoothed round-trip time for
+/// each of them.
+#[derive(Debug)]
+pub struct Balancer {
+    strategy: Strategy,
+    addrs: Vec<Endpoint>,
+    // upstream -> smoothed RTT, missing until its first reply
+    rtt: Mutex<HashMap<Endpoint, Duration>>,
+    // round-robin position
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: Strategy, addrs: Vec<Endpoint>) -> Self {
+        Self {
+            strategy,
+            addrs,
+            rtt: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Endpoint] {
+        &self.addrs
+    }
+
+    /// Upstreams in the order to try them for the next query.
+    pub fn order(&self) -> Vec<Endpoint> {
+        let mut addrs = self.addrs.clone();
+        if addrs.len() < 2 {
+            return addrs;
+        }
+        match self.strategy {
+            Strategy::Ordered => {}
+            Strategy::Fastest => {
+                let rtt = self.rtt.lock().unwrap();
+                // upstreams never measured sort first, to get measured
+                addrs.sort_by_key(|addr| rtt.get(addr).copied().unwrap_or_default());
+                if rand::random::<f64>() < PROBE_RATE {
+                    let probe = rand::thread_rng().gen_range(1..addrs.len());
+                    let addr = addrs.remove(probe);
+                    addrs.insert(0, addr);
+                }
+            }
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                addrs.rotate_left(next % self.addrs.len());
+            }
+            Strategy::Random => addrs.shuffle(&mut rand::thread_rng()),
+        }
+        addrs
+    }
+
+    /// Folds a round-trip time measured for `addr` into its smoothed RTT,
+    /// weighting the new sample by 1/8 as in RFC 6298.
+    pub fn record(&self, addr: &Endpoint, sample: Duration) {
+        let mut rtt = self.rtt.lock().unwrap();
+        let srtt = rtt
+            .get(addr)
+            .map_or(sample, |srtt| (*srtt * 7 + sample) / 8);
+        rtt.insert(addr.clone(), srtt);
+    }
+
+    pub fn rtt(&self, addr: &Endpoint) -> Option<Duration> {
+        self.rtt.lock().unwrap().get(addr).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Balancer, Strategy};
+    use crate::forward::Endpoint;
+    use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+    fn addrs() -> Vec<Endpoint> {
+        ["192.0.2.1:53", "192.0.2.2:53", "192.0.2.3:53"]
+            .iter()
+            .map(|addr| addr.parse::<SocketAddr>().unwrap().into())
+            .collect()
+    }
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let addrs = addrs();
+        let balancer = Balancer::new(Strategy::Fastest, addrs.clone());
+        balancer.record(&addrs[0], Duration::from_millis(80));
+        assert_eq!(Some(Duration::from_millis(80)), balancer.rtt(&addrs[0]));
+        balancer.record(&addrs[0], Duration::from_millis(0));
+        assert_eq!(Some(Duration::from_millis(70)), balancer.rtt(&addrs[0]));
+        assert_eq!(None, balancer.rtt(&addrs[1]));
+    }
+
+    #[test]
+    fn test_fastest() {
+        let addrs = addrs();
+        let balancer = Balancer::new(Strategy::Fastest, addrs.clone());
+        balancer.record(&addrs[0], Duration::from_millis(50));
+        balancer.record(&addrs[1], Duration::from_millis(5));
+        balancer.record(&addrs[2], Duration::from_millis(20));
+
+        // probing only ever moves one upstream in front of the fastest
+        let mut first = 0;
+        for _ in 0..200 {
+            let order = balancer.order();
+            if order[0] == addrs[1] {
+                first += 1;
+                assert_eq!(
+                    vec![addrs[1].clone(), addrs[2].clone(), addrs[0].clone()],
+                    order
+                );
+            }
+        }
+        assert!(first > 150);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let addrs = addrs();
+        let balancer = Balancer::new(Strategy::RoundRobin, addrs.clone());
+        let rotated = |n| {
+            let mut addrs = addrs.clone();
+            addrs.rotate_left(n);
+            addrs
+        };
+        assert_eq!(addrs, balancer.order());
+        assert_eq!(rotated(1), balancer.order());
+        assert_eq!(rotated(2), balancer.order());
+        assert_eq!(addrs, balancer.order());
+    }
+
+    #[test]
+    fn test_ordered_and_random() {
+        let addrs = addrs();
+        assert_eq!(
+            addrs,
+            Balancer::new(Strategy::Ordered, addrs.clone()).order()
+        );
+
+        let mut order = Balancer::new(Strategy::Random, addrs.clone()).order();
+        order.sort();
+        assert_eq!(addrs, order);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(Strategy::RoundRobin), Strategy::from_str("round-robin"));
+        assert!(Strategy::from_str("weighted").is_err());
+    }
+}
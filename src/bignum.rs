@@ -0,0 +1,292 @@
+//! Unsigned integers of arbitrary size, just enough arithmetic to verify RSA
+//! and ECDSA signatures. Nothing here runs in constant time, only public
+//! values go through it.
+
+use std::cmp::Ordering;
+
+/// Unsigned integer as little-endian 32 bit limbs, without leading zero
+/// limbs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BigUint(Vec<u32>);
+
+impl BigUint {
+    pub fn from_u32(n: u32) -> Self {
+        Self(vec![n]).normalized()
+    }
+
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let limbs = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+            .collect();
+        Self(limbs).normalized()
+    }
+
+    /// Parses big-endian hex digits, for constants.
+    pub fn from_hex(hex: &str) -> Self {
+        let bytes: Vec<u8> = hex
+            .as_bytes()
+            .rchunks(2)
+            .rev()
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect();
+        Self::from_be_bytes(&bytes)
+    }
+
+    /// Big-endian bytes, left-padded with zeros to `len`. Higher bytes that
+    /// don't fit are dropped.
+    pub fn to_be_bytes(&self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        for (i, b) in bytes.iter_mut().rev().enumerate() {
+            if let Some(limb) = self.0.get(i / 4) {
+                *b = (limb >> (8 * (i % 4))) as u8;
+            }
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn bits(&self) -> usize {
+        match self.0.last() {
+            Some(top) => self.0.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    pub fn bit(&self, i: usize) -> bool {
+        self.0
+            .get(i / 32)
+            .is_some_and(|limb| limb >> (i % 32) & 1 == 1)
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        let len = self.0.len().max(other.0.len());
+        let mut limbs = Vec::with_capacity(len + 1);
+        let mut carry = 0u64;
+        for i in 0..len {
+            let sum = self.limb(i) as u64 + other.limb(i) as u64 + carry;
+            limbs.push(sum as u32);
+            carry = sum >> 32;
+        }
+        limbs.push(carry as u32);
+        Self(limbs).normalized()
+    }
+
+    /// `self - other`, which must not be negative.
+    pub fn sub(&self, other: &Self) -> Self {
+        assert!(*self >= *other, "negative difference");
+        let mut limbs = Vec::with_capacity(self.0.len());
+        let mut borrow = 0i64;
+        for i in 0..self.0.len() {
+            let diff = self.limb(i) as i64 - other.limb(i) as i64 - borrow;
+            limbs.push(diff as u32);
+            borrow = (diff < 0) as i64;
+        }
+        Self(limbs).normalized()
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        let mut limbs = vec![0u32; self.0.len() + other.0.len()];
+        for (i, a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.0.iter().enumerate() {
+                let t = *a as u64 * *b as u64 + limbs[i + j] as u64 + carry;
+                limbs[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            limbs[i + other.0.len()] = carry as u32;
+        }
+        Self(limbs).normalized()
+    }
+
+    /// Remainder of `self / m`, `m` must not be zero.
+    pub fn rem(&self, m: &Self) -> Self {
+        self.div_rem(m).1
+    }
+
+    /// Quotient and remainder, by Knuth's algorithm D (TAOCP vol. 2,
+    /// section 4.3.1).
+    pub fn div_rem(&self, d: &Self) -> (Self, Self) {
+        assert!(!d.is_zero(), "division by zero");
+        if self < d {
+            return (Self::default(), self.clone());
+        }
+        if let [divisor] = d.0.as_slice() {
+            let mut quotient = vec![0u32; self.0.len()];
+            let mut rem = 0u64;
+            for i in (0..self.0.len()).rev() {
+                let cur = (rem << 32) | self.0[i] as u64;
+                quotient[i] = (cur / *divisor as u64) as u32;
+                rem = cur % *divisor as u64;
+            }
+            return (Self(quotient).normalized(), Self::from_u32(rem as u32));
+        }
+
+        // normalize so the divisor's top limb has its high bit set
+        let shift = d.0.last().unwrap().leading_zeros();
+        let v = d.shl_limbs(shift);
+        let mut u = self.shl_limbs(shift);
+        u.push(0);
+        let n = v.len();
+        let m = u.len() - n - 1;
+        let mut quotient = vec![0u32; m + 1];
+
+        for j in (0..=m).rev() {
+            let num = ((u[j + n] as u64) << 32) | u[j + n - 1] as u64;
+            let mut qhat = num / v[n - 1] as u64;
+            let mut rhat = num % v[n - 1] as u64;
+            while qhat >> 32 != 0 || qhat * v[n - 2] as u64 > ((rhat << 32) | u[j + n - 2] as u64) {
+                qhat -= 1;
+                rhat += v[n - 1] as u64;
+                if rhat >> 32 != 0 {
+                    break;
+                }
+            }
+
+            // u[j..=j+n] -= qhat * v
+            let mut borrow = 0i64;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let p = qhat * v[i] as u64 + carry;
+                carry = p >> 32;
+                let t = u[i + j] as i64 - borrow - (p & 0xFFFF_FFFF) as i64;
+                u[i + j] = t as u32;
+                borrow = (t < 0) as i64;
+            }
+            let t = u[j + n] as i64 - borrow - carry as i64;
+            u[j + n] = t as u32;
+
+            // qhat was one too large, add the divisor back
+            if t < 0 {
+                qhat -= 1;
+                let mut carry = 0u64;
+                for i in 0..n {
+                    let sum = u[i + j] as u64 + v[i] as u64 + carry;
+                    u[i + j] = sum as u32;
+                    carry = sum >> 32;
+                }
+                u[j + n] = u[j + n].wrapping_add(carry as u32);
+            }
+            quotient[j] = qhat as u32;
+        }
+
+        let rem = Self(u[..n].to_vec()).normalized();
+        (Self(quotient).normalized(), rem.shr_bits(shift))
+    }
+
+    /// `self^exp mod m`, by square and multiply.
+    pub fn mod_pow(&self, exp: &Self, m: &Self) -> Self {
+        let base = self.rem(m);
+        let mut result = Self::from_u32(1).rem(m);
+        for i in (0..exp.bits()).rev() {
+            result = result.mul(&result).rem(m);
+            if exp.bit(i) {
+                result = result.mul(&base).rem(m);
+            }
+        }
+        result
+    }
+
+    fn limb(&self, i: usize) -> u32 {
+        self.0.get(i).copied().unwrap_or(0)
+    }
+
+    fn normalized(mut self) -> Self {
+        while self.0.last() == Some(&0) {
+            self.0.pop();
+        }
+        self
+    }
+
+    /// Limbs shifted left by less than a limb, with room for the carry.
+    fn shl_limbs(&self, shift: u32) -> Vec<u32> {
+        let mut limbs = Vec::with_capacity(self.0.len() + 1);
+        let mut carry = 0u32;
+        for limb in self.0.iter() {
+            limbs.push((limb << shift) | carry);
+            carry = if shift == 0 { 0 } else { limb >> (32 - shift) };
+        }
+        if carry != 0 {
+            limbs.push(carry);
+        }
+        limbs
+    }
+
+    fn shr_bits(&self, shift: u32) -> Self {
+        if shift == 0 {
+            return self.clone();
+        }
+        let limbs = (0..self.0.len())
+            .map(|i| (self.0[i] >> shift) | (self.limb(i + 1) << (32 - shift)))
+            .collect();
+        Self(limbs).normalized()
+    }
+}
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .len()
+            .cmp(&other.0.len())
+            .then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+    }
+}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BigUint;
+
+    fn hex(s: &str) -> BigUint {
+        BigUint::from_hex(s)
+    }
+
+    #[test]
+    fn test_bytes() {
+        let n = BigUint::from_be_bytes(&[0, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(hex("102030405"), n);
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], n.to_be_bytes(6));
+        assert_eq!(33, n.bits());
+        assert!(n.bit(0) && !n.bit(1) && n.bit(32));
+        assert!(BigUint::from_be_bytes(&[0, 0]).is_zero());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = hex("ffffffffffffffffffffffff");
+        let b = hex("1");
+        assert_eq!(hex("1000000000000000000000000"), a.add(&b));
+        assert_eq!(a, a.add(&b).sub(&b));
+        assert_eq!(
+            hex("fffffffffffffffffffffffe000000000000000000000001"),
+            a.mul(&a)
+        );
+
+        // values checked with Python
+        let n = hex("1d3c8f5e2b7a9c4d6e8f0a1b2c3d4e5f60718293a4b5c6d7e8f9");
+        let d = hex("b7a9c4d6e8f0a1b2c3d4e5f6");
+        let (q, r) = n.div_rem(&d);
+        assert_eq!(n, q.mul(&d).add(&r));
+        assert!(r < d);
+        assert_eq!(hex("28c0662613e465cf0885f093464f"), q);
+        assert_eq!((BigUint::default(), d.clone()), d.div_rem(&n));
+        assert_eq!(hex("3"), hex("17").rem(&hex("5")));
+    }
+
+    #[test]
+    fn test_mod_pow() {
+        assert_eq!(hex("1bd"), hex("4").mod_pow(&hex("d"), &hex("1f1")));
+        // Fermat: a^(p-1) = 1 mod p for the P-256 prime
+        let p = hex("ffffffff00000001000000000000000000000000ffffffffffffffffffffffff");
+        let a = hex("123456789abcdef0123456789abcdef");
+        assert_eq!(hex("1"), a.mod_pow(&p.sub(&hex("1")), &p));
+    }
+}
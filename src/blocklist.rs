@@ -0,0 +1,484 @@
+//! Domain blocking in the manner of Pi-hole: names on a blocklist, and every
+//! name below them, get a fixed answer instead of being resolved. Lists are
+//! hosts files (`0.0.0.0 ads.example`) or plain lists of domains, one per
+//! line, read from files or downloaded over HTTPS, and loaded again
+//! periodically.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock, Weak},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::{
+    config::Config,
+    doh::{self, DohClient},
+    handler::{local_soa, Context, Next, RequestHandler},
+    privacy,
+    proto::{rcode, Class, Message, Name, Question, Record, Type},
+    rdata::RData,
+    x509::{self, Certificate, HostPins},
+};
+
+/// TTL of the answers to blocked names.
+const BLOCKED_TTL: u32 = 60;
+
+/// How long downloading a list may stall.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Names of hosts files that are about the host itself, not blocked.
+const LOCAL_NAMES: [&str; 7] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// Where a blocklist is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Url(doh::Url),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// An `https://` URL, or a path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.starts_with("https://") {
+            true => s.parse().map(Self::Url),
+            false if s.is_empty() => Err("empty blocklist path".into()),
+            false => Ok(Self::File(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// How blocked names are answered.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BlockResponse {
+    // the name doesn't exist
+    #[default]
+    Nxdomain,
+    // the unspecified address, 0.0.0.0 or ::
+    Null,
+    // the address for its type, NODATA for the other
+    Address(IpAddr),
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    /// `nxdomain`, `null` or an address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(Self::Nxdomain),
+            "null" => Ok(Self::Null),
+            _ => s
+                .parse()
+                .map(Self::Address)
+                .map_err(|_| format!("expected nxdomain, null or an address, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for BlockResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nxdomain => f.write_str("nxdomain"),
+            Self::Null => f.write_str("null"),
+            Self::Address(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// Domains of a blocklist, lowercased. Comments start with `#`, lines of a
+/// hosts file with an address, anything else that isn't a single domain
+/// is skipped.
+pub fn parse_list(text: &str) -> HashSet<Name> {
+    let mut domains = HashSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let names = match fields.as_slice() {
+            [addr, names @ ..] if addr.parse::<IpAddr>().is_ok() => names,
+            [_] => &fields[..],
+            _ => continue,
+        };
+        for name in names {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            if !is_domain(&name) || LOCAL_NAMES.contains(&name.as_str()) {
+                continue;
+            }
+            if let Ok(name) = name.parse() {
+                domains.insert(name);
+            }
+        }
+    }
+    domains
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+/// Blocked domains, from each source the last time it loaded.
+pub struct Blocklist {
+    sources: Vec<Source>,
+    response: BlockResponse,
+    // domains of each source, in the order of `sources`
+    lists: RwLock<Vec<HashSet<Name>>>,
+    client: DohClient,
+    // for downloading each list
+    timeout: Duration,
+}
+
+impl Blocklist {
+    pub fn new(sources: Vec<Source>, response: BlockResponse, timeout: Duration) -> Self {
+        Self {
+            lists: RwLock::new(vec![HashSet::new(); sources.len()]),
+            sources,
+            response,
+            client: DohClient::new(Vec::new(), Default::default()),
+            timeout,
+        }
+    }
+
+    /// Root certificates list servers are authenticated with, and the pins
+    /// the certificates of some hosts must match one of.
+    pub fn with_tls(self, roots: Vec<Certificate>, pins: &HostPins) -> Self {
+        Self {
+            client: DohClient::new(roots, pins.clone()),
+            ..self
+        }
+    }
+
+    /// Whether any source is downloaded.
+    pub fn uses_tls(&self) -> bool {
+        self.sources
+            .iter()
+            .any(|source| matches!(source, Source::Url(_)))
+    }
+
+    /// Loads the lists once, then again every `refresh` on a thread for as
+    /// long as the returned handle is alive. Never again for a zero
+    /// `refresh`.
+    pub fn start(self, refresh: Duration) -> Arc<Self> {
+        let blocklist = Arc::new(self);
+        blocklist.load();
+        if !refresh.is_zero() {
+            let weak = Arc::downgrade(&blocklist);
+            thread::spawn(move || refresh_loop(weak, refresh));
+        }
+        blocklist
+    }
+
+    /// Loads every source, keeping the previous domains of those that
+    /// fail.
+    fn load(&self) {
+        for (i, source) in self.sources.iter().enumerate() {
+            match self.read(source) {
+                Ok(domains) => {
+                    tracing::info!(domains = domains.len(), %source, "Loaded blocklist");
+                    self.lists.write().unwrap()[i] = domains;
+                }
+                Err(e) => {
+                    tracing::warn!(%source, error = %format!("{:#}", e), "Failed to load blocklist")
+                }
+            }
+        }
+    }
+
+    fn read(&self, source: &Source) -> Result<HashSet<Name>> {
+        let text = match source {
+            Source::File(path) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+            Source::Url(url) => {
+                String::from_utf8_lossy(&self.client.download(url, self.timeout)?).into_owned()
+            }
+        };
+        Ok(parse_list(&text))
+    }
+
+    /// Whether `name` or a domain it is under is blocked.
+    pub fn contains(&self, name: &Name) -> bool {
+        let lists = self.lists.read().unwrap();
+        let mut suffix = name.clone();
+        loop {
+            if lists.iter().any(|list| list.contains(&suffix)) {
+                return true;
+            }
+            match suffix.parent() {
+                Some(parent) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Answer to `q` about a blocked name.
+    fn answer(&self, request: &Message, q: &Question) -> Message {
+        let addr = match (self.response, q.qtype) {
+            (BlockResponse::Nxdomain, _) => {
+                return Message {
+                    ra: 1,
+                    rcode: rcode::NXDOMAIN,
+                    authorities: vec![local_soa(&q.name)],
+                    ..request.reply()
+                }
+            }
+            (BlockResponse::Null, Type::A) => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            (BlockResponse::Null, Type::AAAA) => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            (BlockResponse::Address(ip @ IpAddr::V4(_)), Type::A)
+            | (BlockResponse::Address(ip @ IpAddr::V6(_)), Type::AAAA) => Some(ip),
+            _ => None,
+        };
+        let record = |rtype, rdata| Record {
+            name: q.name.clone(),
+            rtype,
+            class: Class::IN,
+            ttl: BLOCKED_TTL,
+            rdata,
+        };
+        let (answers, authorities) = match addr {
+            Some(IpAddr::V4(ip)) => (vec![record(Type::A, RData::A(ip))], Vec::new()),
+            Some(IpAddr::V6(ip)) => (vec![record(Type::AAAA, RData::AAAA(ip))], Vec::new()),
+            // NODATA
+            None => (Vec::new(), vec![local_soa(&q.name)]),
+        };
+        Message {
+            ra: 1,
+            answers,
+            authorities,
+            ..request.reply()
+        }
+    }
+}
+
+/// Blocklist of the configuration, loaded. List servers are authenticated
+/// like DoH upstreams.
+pub fn from_config(config: &Config) -> Result<Arc<Blocklist>> {
+    let mut blocklist = Blocklist::new(
+        config.blocklists.clone(),
+        config.block_response,
+        DOWNLOAD_TIMEOUT,
+    );
+    if blocklist.uses_tls() {
+        let path = config
+            .tls_roots
+            .clone()
+            .unwrap_or_else(|| x509::DEFAULT_ROOTS.into());
+        let roots = x509::load_pem(&path)
+            .with_context(|| format!("Failed to load TLS roots from {}", path.display()))?;
+        blocklist = blocklist.with_tls(roots, &config.tls_pins);
+    }
+    Ok(blocklist.start(config.blocklist_refresh))
+}
+
+fn refresh_loop(blocklist: Weak<Blocklist>, refresh: Duration) {
+    loop {
+        thread::sleep(refresh);
+        let Some(blocklist) = blocklist.upgrade() else {
+            return;
+        };
+        blocklist.load();
+    }
+}
+
+/// Answers requests for blocked names, and passes the rest on.
+pub struct BlocklistHandler(pub Arc<Blocklist>);
+
+impl RequestHandler for BlocklistHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        match request.questions.as_slice() {
+            [q] if self.0.contains(&q.name) => {
+                tracing::debug!(
+                    id = %request.id, client = %privacy::client(ctx.source), qname = %q.name,
+                    qtype = %q.qtype,
+                    "Blocked"
+                );
+                Ok(self.0.answer(&request, q))
+            }
+            _ => next.run(ctx, request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_list, BlockResponse, Blocklist, BlocklistHandler, Source};
+    use crate::{
+        handler::{Chain, Context, Transport},
+        proto::{rcode, test::request, Name, Type},
+        rdata::RData,
+    };
+    use std::{
+        env, fs,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_parse_list() {
+        let domains = parse_list(
+            "# hosts format\n\
+             127.0.0.1 localhost\n\
+             0.0.0.0 Ads.Example.com tracker.example.net # two at once\n\
+             :: ipv6.example.org.\n\
+             # a plain list\n\
+             metrics.example\n\
+             \n\
+             not a domain\n\
+             ||adblock.example^\n",
+        );
+        let mut domains: Vec<_> = domains.iter().map(Name::to_string).collect();
+        domains.sort();
+        assert_eq!(
+            vec![
+                "ads.example.com",
+                "ipv6.example.org",
+                "metrics.example",
+                "tracker.example.net"
+            ],
+            domains
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(Source::File("/etc/blocklist".into())),
+            "/etc/blocklist".parse()
+        );
+        assert!(matches!(
+            "https://lists.example/hosts".parse(),
+            Ok(Source::Url(_))
+        ));
+        assert_eq!(Ok(BlockResponse::Nxdomain), "nxdomain".parse());
+        assert_eq!(Ok(BlockResponse::Null), "null".parse());
+        assert_eq!(
+            Ok(BlockResponse::Address("192.0.2.1".parse().unwrap())),
+            "192.0.2.1".parse()
+        );
+        assert!("sinkhole".parse::<BlockResponse>().is_err());
+    }
+
+    /// File with `text` in the temp directory, unique to the test.
+    fn list_file(text: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "blocklist-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_handler() {
+        let path = list_file("0.0.0.0 ads.example.com\n");
+        let blocklist = |response| {
+            Blocklist::new(
+                vec![Source::File(path.clone())],
+                response,
+                Duration::from_secs(1),
+            )
+            .start(Duration::ZERO)
+        };
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+
+        let chain = Chain::default().with(BlocklistHandler(blocklist(BlockResponse::Nxdomain)));
+        // the domain and what's under it, not its parent
+        let reply = chain
+            .handle(&ctx, request("ads.example.com", Type::A))
+            .unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        assert_eq!(Type::SOA, reply.authorities[0].rtype);
+        let reply = chain
+            .handle(&ctx, request("Cdn.Ads.Example.COM.", Type::A))
+            .unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        // nothing further down the chain answers
+        let reply = chain.handle(&ctx, request("example.com", Type::A)).unwrap();
+        assert_eq!(rcode::REFUSED, reply.rcode);
+
+        let chain = Chain::default().with(BlocklistHandler(blocklist(BlockResponse::Null)));
+        let reply = chain
+            .handle(&ctx, request("ads.example.com", Type::AAAA))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(RData::AAAA("::".parse().unwrap()), reply.answers[0].rdata);
+        let reply = chain
+            .handle(&ctx, request("ads.example.com", Type::MX))
+            .unwrap();
+        assert!(reply.answers.is_empty());
+        assert_eq!(Type::SOA, reply.authorities[0].rtype);
+
+        let sinkhole = BlockResponse::Address("192.0.2.1".parse().unwrap());
+        let chain = Chain::default().with(BlocklistHandler(blocklist(sinkhole)));
+        let reply = chain
+            .handle(&ctx, request("ads.example.com", Type::A))
+            .unwrap();
+        assert_eq!(
+            RData::A("192.0.2.1".parse().unwrap()),
+            reply.answers[0].rdata
+        );
+        let reply = chain
+            .handle(&ctx, request("ads.example.com", Type::AAAA))
+            .unwrap();
+        assert!(reply.answers.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_load_keeps_domains() {
+        let path = list_file("ads.example.com\n");
+        let blocklist = Blocklist::new(
+            vec![Source::File(path.clone())],
+            BlockResponse::Nxdomain,
+            Duration::from_secs(1),
+        )
+        .start(Duration::ZERO);
+        assert!(blocklist.contains(&Name::from("ads.example.com")));
+
+        fs::remove_file(&path).unwrap();
+        blocklist.load();
+        assert!(blocklist.contains(&Name::from("ads.example.com")));
+
+        fs::write(&path, "tracker.example.com\n").unwrap();
+        blocklist.load();
+        assert!(!blocklist.contains(&Name::from("ads.example.com")));
+        assert!(blocklist.contains(&Name::from("tracker.example.com")));
+        fs::remove_file(path).unwrap();
+    }
+}
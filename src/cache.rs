@@ -0,0 +1,1091 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    acl::Network,
+    edns::{ClientSubnet, EdnsOption, Opt},
+    handler::{set_origin, Context, Next, Origin, RequestHandler},
+    proto::{rcode, Class, Message, Name, Question, Record, Type},
+    rdata::RData,
+    resolver::Resolver,
+    validator::strip_dnssec,
+};
+
+/// Hits after which an entry counts as popular and gets prefetched.
+const PREFETCH_HITS: u32 = 3;
+
+/// Share of an entry's lifetime, at its end, in which a hit on a popular
+/// entry refreshes it.
+const PREFETCH_WINDOW: f64 = 0.1;
+
+/// Name, type and class of a question. The name keeps its case, lookups
+/// match any case since names compare case-insensitively.
+pub type Key = (Name, Type, Class);
+
+/// Replies by question, kept for the lowest TTL among their records.
+/// Negative replies are kept as long as their SOA allows (RFC 2308). Holds
+/// at most `capacity` entries, evicting the least recently used one to make
+/// room. Shared across configuration reloads.
+///
+/// Hits carry the TTLs left, not the ones originally received, so clients
+/// caching them don't extend their lifetime.
+///
+/// Replies tailored to a client subnet (RFC 7871) only answer queries from
+/// within the scope they were returned with, one subnet's at a time.
+///
+/// Replies to queries with DO set keep their DNSSEC records, which are
+/// stripped on hits for clients that didn't set it; replies without them
+/// don't answer clients that did. Replies to queries with CD set may not
+/// have been validated and are never cached.
+pub struct Cache {
+    capacity: usize,
+    // lowest TTL handed out on hits, in seconds
+    min_ttl: u32,
+    entries: Mutex<Entries>,
+    evictions: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    // last use -> key, oldest first
+    lru: BTreeMap<u64, Key>,
+    // incremented on every use
+    tick: u64,
+    // estimated memory held by the entries
+    bytes: usize,
+    hits_by_type: HashMap<Type, u64>,
+}
+
+/// Snapshot of the cache counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // most hit first
+    pub hits_by_type: Vec<(Type, u64)>,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cache entries (~{} KiB), {} hits, {} misses ({:.1}% hit ratio), {} evicted",
+            self.entries,
+            self.bytes / 1024,
+            self.hits,
+            self.misses,
+            self.hit_ratio() * 100.0,
+            self.evictions
+        )?;
+        if !self.hits_by_type.is_empty() {
+            let by_type: Vec<_> = self
+                .hits_by_type
+                .iter()
+                .map(|(qtype, hits)| format!("{}={}", qtype, hits))
+                .collect();
+            write!(f, "; hits by type: {}", by_type.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Entries {
+    fn insert(&mut self, key: Key, entry: Entry) {
+        self.remove(&key);
+        self.bytes += entry.size(&key);
+        self.map.insert(key.clone(), entry);
+        self.touch(&key);
+    }
+
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        if let Some(entry) = self.map.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = self.tick;
+            self.lru.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.lru.remove(&entry.used);
+        self.bytes -= entry.size(key);
+        Some(entry)
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        match self.lru.first_key_value() {
+            Some((_, oldest)) => {
+                let oldest = oldest.clone();
+                self.remove(&oldest);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Positive,
+    // the name doesn't exist (RFC 2308, section 2.1)
+    NxDomain,
+    // the name exists without records of the type (RFC 2308, section 2.2)
+    NoData,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    kind: Kind,
+    ra: u8,
+    // the answer was validated with DNSSEC
+    ad: u8,
+    // the records include the DNSSEC ones, the request had DO set
+    dnssec: bool,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+    additionals: Vec<Record>,
+    // the client subnet the answer holds for, everywhere if none
+    scope: Option<Network>,
+    // the records' TTLs count down from here
+    stored: Instant,
+    expires: Instant,
+    // tick of the last use
+    used: u64,
+    hits: u32,
+    // a refresh is under way
+    prefetching: bool,
+}
+
+impl Entry {
+    /// Rough memory use of the entry stored under `key`.
+    fn size(&self, key: &Key) -> usize {
+        let records = self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals);
+        mem::size_of::<(Key, Entry)>()
+            + key.0.labels().iter().map(Vec::len).sum::<usize>()
+            + records
+                .map(|r| {
+                    mem::size_of::<Record>() + r.name.labels().iter().map(Vec::len).sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+}
+
+pub fn key(question: &Question) -> Key {
+    (question.name.clone(), question.qtype, question.class)
+}
+
+impl Cache {
+    /// Cache of at most `capacity` entries, 0 disables caching. TTLs on hits
+    /// are not lowered below `min_ttl` seconds.
+    pub fn new(capacity: usize, min_ttl: u32) -> Self {
+        Self {
+            capacity,
+            min_ttl,
+            entries: Mutex::new(Entries::default()),
+            evictions: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached reply to `request`, if it has a single question with an
+    /// unexpired entry.
+    pub fn lookup(&self, request: &Message) -> Option<Message> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+        let key = key(question);
+        let mut entries = self.entries.lock().unwrap();
+        let subnet = request.opt.as_ref().and_then(|opt| opt.client_subnet());
+        let dnssec_ok = request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok);
+        let entry = entries.map.get(&key);
+        let fresh = entry.map(|entry| entry.expires > Instant::now());
+        let in_scope = entry.is_some_and(|entry| match (entry.scope, subnet) {
+            (None, _) => true,
+            (Some(scope), Some(subnet)) => scope.contains(subnet.address),
+            (Some(_), None) => false,
+        });
+        let complete = entry.is_some_and(|entry| entry.dnssec || !dnssec_ok);
+        if fresh != Some(true) || !in_scope || !complete {
+            if fresh == Some(false) {
+                entries.remove(&key);
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        *entries.hits_by_type.entry(question.qtype).or_default() += 1;
+        entries.touch(&key);
+        let entry = entries.map.get_mut(&key).unwrap();
+        entry.hits += 1;
+        let mut entry = entry.clone();
+        drop(entries);
+
+        let elapsed = entry.stored.elapsed().as_secs();
+        for record in entry
+            .answers
+            .iter_mut()
+            .chain(entry.authorities.iter_mut())
+            .chain(entry.additionals.iter_mut())
+        {
+            let remaining = u64::from(record.ttl).saturating_sub(elapsed) as u32;
+            record.ttl = remaining.max(self.min_ttl);
+        }
+        let mut reply = request.reply();
+        if let (Some(subnet), Some(opt)) = (subnet, reply.opt.as_mut()) {
+            opt.set_option(EdnsOption::ClientSubnet(ClientSubnet {
+                scope_prefix: entry.scope.map_or(0, |scope| scope.prefix()),
+                ..subnet.clone()
+            }));
+        }
+        let mut reply = Message {
+            rcode: match entry.kind {
+                Kind::NxDomain => rcode::NXDOMAIN,
+                Kind::Positive | Kind::NoData => rcode::NOERROR,
+            },
+            ra: entry.ra,
+            // only for clients that understand it (RFC 6840, section 5.8)
+            ad: match request.ad == 1 || request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok) {
+                true => entry.ad,
+                false => 0,
+            },
+            answers: entry.answers,
+            authorities: entry.authorities,
+            additionals: entry.additionals,
+            ..reply
+        };
+        if entry.dnssec && !dnssec_ok {
+            strip_dnssec(&mut reply);
+        }
+        Some(reply)
+    }
+
+    /// Stores `reply` to `request` if it is a complete answer to a single
+    /// question with a non-zero TTL: positive, or negative with an SOA. Not
+    /// if `request` has CD set, its reply may be bogus.
+    pub fn insert(&self, request: &Message, reply: &Message) {
+        let [question] = request.questions.as_slice() else {
+            return;
+        };
+        if reply.tc == 1 || request.cd == 1 || self.capacity == 0 {
+            return;
+        }
+        let kind = match reply.rcode {
+            rcode::NOERROR if !reply.answers.is_empty() => Kind::Positive,
+            rcode::NOERROR => Kind::NoData,
+            rcode::NXDOMAIN => Kind::NxDomain,
+            _ => return,
+        };
+        let records = reply
+            .answers
+            .iter()
+            .chain(&reply.authorities)
+            .chain(&reply.additionals);
+        let mut ttl = records.map(|r| r.ttl).min().unwrap_or(0);
+        if kind != Kind::Positive {
+            let Some(negative_ttl) = negative_ttl(reply) else {
+                return;
+            };
+            ttl = ttl.min(negative_ttl);
+        }
+        if ttl == 0 {
+            return;
+        }
+        // the scope can't be wider than the subnet asked about
+        let subnet = request.opt.as_ref().and_then(|opt| opt.client_subnet());
+        let scope = reply
+            .opt
+            .as_ref()
+            .and_then(|opt| opt.client_subnet())
+            .filter(|ecs| ecs.scope_prefix > 0);
+        let scope = match (scope, subnet) {
+            (None, _) => None,
+            (Some(scope), Some(subnet)) => Some(Network::new(
+                subnet.address,
+                scope.scope_prefix.min(subnet.source_prefix),
+            )),
+            // tailored to a subnet nobody asked about
+            (Some(_), None) => return,
+        };
+
+        let key = key(question);
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.map.len() >= self.capacity && entries.evict_oldest() {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        let now = Instant::now();
+        entries.insert(
+            key,
+            Entry {
+                kind,
+                ra: reply.ra,
+                ad: reply.ad,
+                dnssec: request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok),
+                answers: reply.answers.clone(),
+                authorities: reply.authorities.clone(),
+                additionals: reply.additionals.clone(),
+                scope,
+                stored: now,
+                expires: now + Duration::from_secs(ttl.into()),
+                used: 0,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+
+    /// Whether the entry for `request` is popular and close enough to expiry
+    /// to be refreshed. Returns true once per entry, the caller then does
+    /// the refresh.
+    pub fn due_for_prefetch(&self, request: &Message) -> bool {
+        let [question] = request.questions.as_slice() else {
+            return false;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.map.get_mut(&key(question)) else {
+            return false;
+        };
+        let lifetime = entry.expires.duration_since(entry.stored);
+        let remaining = entry.expires.saturating_duration_since(Instant::now());
+        if entry.prefetching
+            || entry.hits < PREFETCH_HITS
+            || remaining > lifetime.mul_f64(PREFETCH_WINDOW)
+        {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    /// Writes the unexpired entries not scoped to a client subnet to `path`,
+    /// replacing it atomically. Returns how many were written.
+    ///
+    /// Each entry is its reply in wire format, with DO set if it keeps its
+    /// DNSSEC records, preceded by the Unix times it was stored and expires
+    /// at and the reply's length:
+    /// `stored: u64, expires: u64, len: u32, reply: [u8; len]`.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut buf = Vec::new();
+        let mut count = 0;
+        for ((name, qtype, class), entry) in self.entries.lock().unwrap().map.iter() {
+            // only meant for the subnet it was asked for
+            if entry.expires <= now || entry.scope.is_some() {
+                continue;
+            }
+            let reply = Message {
+                qr: 1,
+                ra: entry.ra,
+                ad: entry.ad,
+                rcode: match entry.kind {
+                    Kind::NxDomain => rcode::NXDOMAIN,
+                    Kind::Positive | Kind::NoData => rcode::NOERROR,
+                },
+                questions: vec![Question {
+                    name: name.clone(),
+                    qtype: *qtype,
+                    class: *class,
+                }],
+                answers: entry.answers.clone(),
+                authorities: entry.authorities.clone(),
+                additionals: entry.additionals.clone(),
+                opt: entry.dnssec.then(|| Opt {
+                    dnssec_ok: true,
+                    ..Opt::default()
+                }),
+                ..Message::default()
+            }
+            .to_bytes()?;
+            let stored = unix_now.saturating_sub(now - entry.stored);
+            let expires = unix_now + (entry.expires - now);
+            buf.extend(stored.as_secs().to_be_bytes());
+            buf.extend(expires.as_secs().to_be_bytes());
+            buf.extend((reply.len() as u32).to_be_bytes());
+            buf.extend(reply);
+            count += 1;
+        }
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, buf)?;
+        fs::rename(&tmp, path)?;
+        Ok(count)
+    }
+
+    /// Adds the entries saved in `path` that haven't expired since, their
+    /// TTLs counting down from when they were first stored. Returns how many
+    /// were added.
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let buf = fs::read(path)?;
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut count = 0;
+        let mut rest = buf.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < 20 {
+                bail!("truncated cache file");
+            }
+            let stored = u64::from_be_bytes(rest[0..8].try_into()?);
+            let expires = u64::from_be_bytes(rest[8..16].try_into()?);
+            let len = u32::from_be_bytes(rest[16..20].try_into()?) as usize;
+            let Some(reply) = rest.get(20..20 + len) else {
+                bail!("truncated cache file");
+            };
+            rest = &rest[20 + len..];
+
+            if expires <= unix_now {
+                continue;
+            }
+            // entries older than the monotonic clock can't count down
+            let Some(stored) =
+                now.checked_sub(Duration::from_secs(unix_now.saturating_sub(stored)))
+            else {
+                continue;
+            };
+            let reply = Message::from_bytes(reply)?;
+            let kind = match reply.rcode {
+                rcode::NOERROR if !reply.answers.is_empty() => Kind::Positive,
+                rcode::NOERROR => Kind::NoData,
+                _ => Kind::NxDomain,
+            };
+            let Some(question) = reply.questions.first() else {
+                bail!("cache entry without a question");
+            };
+            let key = key(question);
+
+            let mut entries = self.entries.lock().unwrap();
+            if entries.map.len() >= self.capacity {
+                break;
+            }
+            entries.insert(
+                key,
+                Entry {
+                    kind,
+                    ra: reply.ra,
+                    ad: reply.ad,
+                    dnssec: reply.opt.as_ref().is_some_and(|opt| opt.dnssec_ok),
+                    answers: reply.answers,
+                    authorities: reply.authorities,
+                    additionals: reply.additionals,
+                    scope: None,
+                    stored,
+                    expires: now + Duration::from_secs(expires - unix_now),
+                    used: 0,
+                    hits: 0,
+                    prefetching: false,
+                },
+            );
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Drops every entry, returns how many there were.
+    pub fn flush(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.map.len();
+        entries.map.clear();
+        entries.lru.clear();
+        entries.bytes = 0;
+        count
+    }
+
+    /// Drops the entries for `name`, and with `subtree` also those for the
+    /// names below it. Returns how many were dropped.
+    pub fn flush_name(&self, name: &Name, subtree: bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<_> = entries
+            .map
+            .keys()
+            .filter(|(key_name, _, _)| {
+                if subtree {
+                    key_name.is_subdomain_of(name)
+                } else {
+                    key_name == name
+                }
+            })
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            entries.remove(key);
+        }
+        keys.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Entries dropped to make room for new ones.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        let mut hits_by_type: Vec<_> = entries
+            .hits_by_type
+            .iter()
+            .map(|(qtype, hits)| (*qtype, *hits))
+            .collect();
+        hits_by_type.sort_by_key(|(qtype, hits)| (std::cmp::Reverse(*hits), u16::from(*qtype)));
+        CacheStats {
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions(),
+            hits_by_type,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        self.stats().to_string()
+    }
+}
+
+/// How long a negative reply may be cached: the lower of the SOA record's TTL
+/// and its MINIMUM field (RFC 2308, section 5). Without an SOA it mustn't be.
+pub fn negative_ttl(reply: &Message) -> Option<u32> {
+    reply
+        .authorities
+        .iter()
+        .find_map(|record| match &record.rdata {
+            RData::SOA(soa) => Some(record.ttl.min(soa.minimum)),
+            _ => None,
+        })
+}
+
+/// Answers standard queries from the cache, and caches what the rest of the
+/// chain answers. Popular entries about to expire are refreshed in the
+/// background with `prefetch`.
+pub struct CacheHandler {
+    pub cache: Arc<Cache>,
+    pub prefetch: Option<Arc<dyn Resolver>>,
+}
+
+impl RequestHandler for CacheHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        if request.opcode != 0 {
+            return next.run(ctx, request);
+        }
+        if let Some(reply) = self.cache.lookup(&request) {
+            tracing::debug!(id = %request.id, qname = %request.questions[0].name, "Cache hit");
+            set_origin(Origin::Cache);
+            if let Some(resolver) = &self.prefetch {
+                if self.cache.due_for_prefetch(&request) {
+                    prefetch(self.cache.clone(), resolver.clone(), request);
+                }
+            }
+            return Ok(reply);
+        }
+        let reply = next.run(ctx, request.clone())?;
+        self.cache.insert(&request, &reply);
+        Ok(reply)
+    }
+}
+
+/// Resolves `request` again on a separate thread and caches the reply.
+fn prefetch(cache: Arc<Cache>, resolver: Arc<dyn Resolver>, request: Message) {
+    thread::spawn(move || {
+        tracing::debug!(qname = %request.questions[0].name, "Prefetching");
+        match resolver.resolve(&request) {
+            Ok(reply) => cache.insert(&request, &reply),
+            Err(e) => tracing::warn!(
+                qname = %request.questions[0].name, error = %e,
+                "Prefetch failed"
+            ),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negative_ttl, Cache, CacheHandler};
+    use crate::{
+        edns::{ClientSubnet, EdnsOption, Opt},
+        handler::{local_soa, Chain, Context, Next, RequestHandler, Transport},
+        proto::{rcode, Class, Message, Name, Record, Type},
+        rdata::{RData, Rrsig},
+        resolver::Resolver,
+    };
+    use anyhow::Result;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn request(id: u16, name: &str) -> Message {
+        Message {
+            id,
+            ..crate::proto::test::request(name, Type::A)
+        }
+    }
+
+    fn answer(request: &Message, ttl: u32) -> Message {
+        Message {
+            ra: 1,
+            answers: vec![Record {
+                name: request.questions[0].name.clone(),
+                rtype: Type::A,
+                class: Class::IN,
+                ttl,
+                rdata: RData::A([192, 0, 2, 1].into()),
+            }],
+            ..request.reply()
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let cache = Cache::new(100, 0);
+        let first = request(1, "www.example.com");
+        assert_eq!(None, cache.lookup(&first));
+
+        cache.insert(&first, &answer(&first, 60));
+        let second = request(2, "WWW.Example.com.");
+        let reply = cache.lookup(&second).unwrap();
+        assert_eq!(2, reply.id);
+        assert_eq!(1, reply.ra);
+        assert_eq!(second.questions, reply.questions);
+        assert_eq!(answer(&first, 60).answers, reply.answers);
+    }
+
+    #[test]
+    fn test_client_subnet_scope() {
+        let with_subnet = |address: &str, source_prefix, scope_prefix| {
+            let mut request = request(1, "www.example.com");
+            let mut opt = Opt::default();
+            opt.set_option(EdnsOption::ClientSubnet(ClientSubnet {
+                source_prefix,
+                scope_prefix,
+                address: address.parse().unwrap(),
+            }));
+            request.opt = Some(opt);
+            request
+        };
+        // replies don't copy options over
+        let scoped = |scope_prefix| Message {
+            opt: with_subnet("192.0.2.0", 24, scope_prefix).opt,
+            ..answer(&request(1, "www.example.com"), 60)
+        };
+        let cache = Cache::new(100, 0);
+        let asked = with_subnet("192.0.2.0", 24, 0);
+        cache.insert(&asked, &scoped(16));
+
+        // within the /16 only, and the hit says so
+        let reply = cache.lookup(&with_subnet("192.0.77.0", 24, 0)).unwrap();
+        let scope = reply.opt.unwrap().client_subnet().unwrap().scope_prefix;
+        assert_eq!(16, scope);
+        assert_eq!(None, cache.lookup(&with_subnet("198.51.100.0", 24, 0)));
+        assert_eq!(None, cache.lookup(&request(1, "www.example.com")));
+        // not thrown away by the misses
+        assert_eq!(1, cache.len());
+
+        // answers meant for everyone hold for every subnet
+        cache.insert(&asked, &scoped(0));
+        assert!(cache.lookup(&with_subnet("198.51.100.0", 24, 0)).is_some());
+        assert!(cache.lookup(&request(1, "www.example.com")).is_some());
+    }
+
+    #[test]
+    fn test_not_cached() {
+        let cache = Cache::new(100, 0);
+        let request = request(1, "www.example.com");
+
+        cache.insert(&request, &answer(&request, 0));
+        cache.insert(&request, &request.error_reply(rcode::SERVFAIL));
+        // negative without an SOA
+        cache.insert(&request, &request.reply());
+        cache.insert(&request, &request.error_reply(rcode::NXDOMAIN));
+        cache.insert(
+            &request,
+            &Message {
+                tc: 1,
+                ..answer(&request, 60)
+            },
+        );
+        assert_eq!(0, cache.len());
+    }
+
+    #[test]
+    fn test_dnssec_bits() {
+        let with_bits = |cd, dnssec_ok| Message {
+            cd,
+            opt: Some(Opt {
+                dnssec_ok,
+                ..Opt::default()
+            }),
+            ..request(1, "www.example.com")
+        };
+        let signed = |request: &Message| {
+            let mut reply = answer(request, 60);
+            reply.answers.push(Record {
+                rtype: Type::RRSIG,
+                rdata: RData::RRSIG(Rrsig {
+                    type_covered: Type::A,
+                    algorithm: 15,
+                    labels: 3,
+                    original_ttl: 60,
+                    expiration: 0,
+                    inception: 0,
+                    key_tag: 1,
+                    signer_name: Name::from("example.com"),
+                    signature: vec![0; 64],
+                }),
+                ..reply.answers[0].clone()
+            });
+            reply
+        };
+
+        // possibly bogus
+        let cache = Cache::new(100, 0);
+        cache.insert(&with_bits(1, true), &signed(&with_bits(1, true)));
+        assert_eq!(0, cache.len());
+
+        // stripped for clients without DO
+        cache.insert(&with_bits(0, true), &signed(&with_bits(0, true)));
+        assert_eq!(2, cache.lookup(&with_bits(0, true)).unwrap().answers.len());
+        assert_eq!(1, cache.lookup(&with_bits(0, false)).unwrap().answers.len());
+        assert_eq!(
+            1,
+            cache
+                .lookup(&request(1, "www.example.com"))
+                .unwrap()
+                .answers
+                .len()
+        );
+
+        // already stripped for clients with DO
+        let cache = Cache::new(100, 0);
+        let without_dnssec = with_bits(0, false);
+        cache.insert(&without_dnssec, &answer(&without_dnssec, 60));
+        assert!(cache.lookup(&with_bits(0, true)).is_none());
+        assert!(cache.lookup(&without_dnssec).is_some());
+    }
+
+    #[test]
+    fn test_ttl_decremented() {
+        let cache = Cache::new(100, 0);
+        let request = request(1, "www.example.com");
+        cache.insert(&request, &answer(&request, 60));
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(59, cache.lookup(&request).unwrap().answers[0].ttl);
+
+        let floored = Cache::new(100, 30);
+        floored.insert(&request, &answer(&request, 10));
+        assert_eq!(30, floored.lookup(&request).unwrap().answers[0].ttl);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = Cache::new(100, 0);
+        let request = request(1, "www.example.com");
+        cache.insert(&request, &answer(&request, 1));
+        assert!(cache.lookup(&request).is_some());
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(None, cache.lookup(&request));
+        assert_eq!(0, cache.len());
+    }
+
+    #[test]
+    fn test_negative() {
+        let cache = Cache::new(100, 0);
+        let request = request(1, "missing.example.com");
+        let mut soa = local_soa(&Name::from("example.com"));
+        soa.ttl = 3600;
+
+        let nxdomain = Message {
+            authorities: vec![soa.clone()],
+            ..request.error_reply(rcode::NXDOMAIN)
+        };
+        cache.insert(&request, &nxdomain);
+        let reply = cache.lookup(&request).unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        assert_eq!(vec![soa.clone()], reply.authorities);
+
+        let nodata = Message {
+            authorities: vec![soa],
+            ..request.reply()
+        };
+        cache.insert(&request, &nodata);
+        let reply = cache.lookup(&request).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert!(reply.answers.is_empty());
+        assert_eq!(1, reply.authorities.len());
+    }
+
+    #[test]
+    fn test_negative_ttl() {
+        let mut soa = local_soa(&Name::from("example.com"));
+        let reply = Message {
+            authorities: vec![soa.clone()],
+            ..Message::default()
+        };
+        // local_soa has TTL and MINIMUM 60
+        assert_eq!(Some(60), negative_ttl(&reply));
+
+        soa.ttl = 10;
+        let reply = Message {
+            authorities: vec![soa],
+            ..Message::default()
+        };
+        assert_eq!(Some(10), negative_ttl(&reply));
+        assert_eq!(None, negative_ttl(&Message::default()));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = Cache::new(2, 0);
+        let [a, b, c] = ["a.example", "b.example", "c.example"].map(|name| request(1, name));
+        cache.insert(&a, &answer(&a, 60));
+        cache.insert(&b, &answer(&b, 60));
+        // a becomes the most recently used
+        assert!(cache.lookup(&a).is_some());
+
+        cache.insert(&c, &answer(&c, 60));
+        assert_eq!(2, cache.len());
+        assert_eq!(1, cache.evictions());
+        assert!(cache.lookup(&a).is_some());
+        assert!(cache.lookup(&b).is_none());
+        assert!(cache.lookup(&c).is_some());
+
+        // replacing an entry evicts nothing
+        cache.insert(&c, &answer(&c, 30));
+        assert_eq!(1, cache.evictions());
+
+        let disabled = Cache::new(0, 0);
+        disabled.insert(&a, &answer(&a, 60));
+        assert!(disabled.is_empty());
+    }
+
+    struct Upstream(Arc<AtomicUsize>);
+
+    impl RequestHandler for Upstream {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(answer(&request, 60))
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chain = Chain::default()
+            .with(CacheHandler {
+                cache: Arc::new(Cache::new(100, 0)),
+                prefetch: None,
+            })
+            .with(Upstream(calls.clone()));
+        let ctx = Context {
+            source: "127.0.0.1:53000".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+
+        for id in 0..3 {
+            let reply = chain.handle(&ctx, request(id, "www.example.com")).unwrap();
+            assert_eq!(id, reply.id);
+            assert_eq!(1, reply.answers.len());
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_due_for_prefetch() {
+        let cache = Cache::new(100, 0);
+        let popular = request(1, "www.example.com");
+        cache.insert(&popular, &answer(&popular, 1));
+        for _ in 0..3 {
+            cache.lookup(&popular).unwrap();
+        }
+        // popular, but not close to expiry yet
+        assert!(!cache.due_for_prefetch(&popular));
+
+        thread::sleep(Duration::from_millis(920));
+        assert!(cache.due_for_prefetch(&popular));
+        // someone is already on it
+        assert!(!cache.due_for_prefetch(&popular));
+
+        // unpopular
+        let other = request(2, "rare.example.com");
+        cache.insert(&other, &answer(&other, 1));
+        thread::sleep(Duration::from_millis(920));
+        assert!(!cache.due_for_prefetch(&other));
+    }
+
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Resolver for Counting {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(answer(request, 60))
+        }
+    }
+
+    #[test]
+    fn test_handler_prefetches() {
+        let cache = Arc::new(Cache::new(100, 0));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chain = Chain::default().with(CacheHandler {
+            cache: cache.clone(),
+            prefetch: Some(Arc::new(Counting(calls.clone()))),
+        });
+        let ctx = Context {
+            source: "127.0.0.1:53000".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = request(1, "www.example.com");
+        cache.insert(&request, &answer(&request, 1));
+        for _ in 0..3 {
+            chain.handle(&ctx, request.clone()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(920));
+        chain.handle(&ctx, request.clone()).unwrap();
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        // the refreshed entry has the full TTL again
+        assert_eq!(60, cache.lookup(&request).unwrap().answers[0].ttl);
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = Cache::new(100, 0);
+        let a = request(1, "www.example.com");
+        let mut mx = request(2, "example.com");
+        mx.questions[0].qtype = Type::MX;
+        cache.insert(&a, &answer(&a, 60));
+        cache.insert(&mx, &answer(&mx, 60));
+        let bytes = cache.stats().bytes;
+        assert!(bytes > 0);
+
+        cache.lookup(&a);
+        cache.lookup(&a);
+        cache.lookup(&mx);
+        cache.lookup(&request(3, "missing.example.com"));
+        let stats = cache.stats();
+        assert_eq!(2, stats.entries);
+        assert_eq!(3, stats.hits);
+        assert_eq!(1, stats.misses);
+        assert_eq!(0.75, stats.hit_ratio());
+        assert_eq!(vec![(Type::A, 2), (Type::MX, 1)], stats.hits_by_type);
+        assert_eq!(
+            format!(
+                "2 cache entries (~{} KiB), 3 hits, 1 misses (75.0% hit ratio), 0 evicted; \
+                 hits by type: A=2 MX=1",
+                bytes / 1024
+            ),
+            stats.to_string()
+        );
+
+        // replacing an entry doesn't count it twice
+        cache.insert(&a, &answer(&a, 60));
+        assert_eq!(bytes, cache.stats().bytes);
+        cache.flush_name(&Name::from("example.com"), true);
+        assert_eq!(0, cache.stats().bytes);
+    }
+
+    #[test]
+    fn test_flush() {
+        let cache = Cache::new(100, 0);
+        for name in [
+            "example.com",
+            "www.example.com",
+            "a.b.example.com",
+            "example.net",
+        ] {
+            let request = request(1, name);
+            cache.insert(&request, &answer(&request, 60));
+        }
+
+        assert_eq!(1, cache.flush_name(&Name::from("WWW.example.com."), false));
+        assert_eq!(2, cache.flush_name(&Name::from("example.com"), true));
+        assert!(cache.lookup(&request(1, "example.net")).is_some());
+        assert_eq!(1, cache.flush());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("cache-test-{}.bin", std::process::id()));
+        let cache = Cache::new(100, 0);
+        let positive = request(1, "www.example.com");
+        cache.insert(&positive, &answer(&positive, 60));
+        let negative = request(2, "missing.example.com");
+        let nxdomain = Message {
+            authorities: vec![local_soa(&Name::from("example.com"))],
+            ..negative.error_reply(rcode::NXDOMAIN)
+        };
+        cache.insert(&negative, &nxdomain);
+        assert_eq!(2, cache.save(&path).unwrap());
+
+        let restored = Cache::new(100, 0);
+        assert_eq!(2, restored.load(&path).unwrap());
+        let reply = restored.lookup(&positive).unwrap();
+        assert_eq!(answer(&positive, 60).answers, reply.answers);
+        let reply = restored.lookup(&negative).unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        assert_eq!(1, reply.authorities.len());
+        // saved without DNSSEC records
+        let dnssec_ok = Message {
+            opt: Some(Opt {
+                dnssec_ok: true,
+                ..Opt::default()
+            }),
+            ..positive.clone()
+        };
+        assert!(restored.lookup(&dnssec_ok).is_none());
+
+        cache.insert(&dnssec_ok, &answer(&dnssec_ok, 60));
+        cache.save(&path).unwrap();
+        let restored = Cache::new(100, 0);
+        restored.load(&path).unwrap();
+        assert!(restored.lookup(&dnssec_ok).is_some());
+
+        std::fs::write(&path, [0u8; 7]).unwrap();
+        assert!(Cache::new(100, 0).load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! CHAOS-class TXT queries that tools identify servers with:
+//! `version.bind` (and `version.server`), `hostname.bind` and `id.server`
+//! (RFC 4892). Nothing else is served in class CH.
+
+use anyhow::Result;
+
+use crate::{
+    handler::{Context, Next, RequestHandler},
+    proto::{rcode, Class, Message, Question, Record, Type},
+    rdata::RData,
+};
+
+/// Answers to the identity queries, each refused if unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chaos {
+    pub version: Option<String>,
+    pub hostname: Option<String>,
+    pub id: Option<String>,
+}
+
+impl Chaos {
+    /// The answer configured for `name`, `None` if it's refused or not an
+    /// identity query at all.
+    fn lookup(&self, name: &str) -> Option<&str> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        match name.as_str() {
+            "version.bind" | "version.server" => self.version.as_deref(),
+            "hostname.bind" => self.hostname.as_deref(),
+            "id.server" => self.id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Answers requests in class CH, passes on all others.
+pub struct ChaosHandler(pub Chaos);
+
+impl RequestHandler for ChaosHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let Some(q) = request.questions.first() else {
+            return next.run(ctx, request);
+        };
+        if q.class != Class::CH {
+            return next.run(ctx, request);
+        }
+        let Some(text) = self.0.lookup(&q.name.to_string()) else {
+            return Ok(request.error_reply(rcode::REFUSED));
+        };
+        let answers = match q.qtype {
+            Type::TXT | Type::ANY => vec![txt_answer(q, text)],
+            _ => Vec::new(),
+        };
+        Ok(Message {
+            aa: 1,
+            answers,
+            ..request.reply()
+        })
+    }
+}
+
+fn txt_answer(q: &Question, text: &str) -> Record {
+    Record {
+        name: q.name.clone(),
+        rtype: Type::TXT,
+        class: Class::CH,
+        ttl: 0,
+        rdata: RData::TXT(vec![text.into()]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Chaos, ChaosHandler};
+    use crate::{
+        handler::{Chain, Context, Next, RequestHandler, Transport},
+        proto::{rcode, Class, Message, Name, Question, Type},
+        rdata::RData,
+    };
+    use anyhow::Result;
+
+    struct Upstream;
+
+    impl RequestHandler for Upstream {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            Ok(Message {
+                rcode: rcode::NXDOMAIN,
+                ..request.reply()
+            })
+        }
+    }
+
+    fn ask(chain: &Chain, name: &str, qtype: Type, class: Class) -> Message {
+        let ctx = Context {
+            source: "192.0.2.7:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from(name),
+                qtype,
+                class,
+            }],
+            ..Message::default()
+        };
+        chain.handle(&ctx, request).unwrap()
+    }
+
+    #[test]
+    fn test_identity() {
+        let chain = Chain::default()
+            .with(ChaosHandler(Chaos {
+                version: Some("dns 1.0".into()),
+                hostname: None,
+                id: Some("ns1".into()),
+            }))
+            .with(Upstream);
+
+        let reply = ask(&chain, "VERSION.BIND.", Type::TXT, Class::CH);
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(1, reply.answers.len());
+        assert_eq!(Class::CH, reply.answers[0].class);
+        assert_eq!(RData::TXT(vec!["dns 1.0".into()]), reply.answers[0].rdata);
+
+        let reply = ask(&chain, "id.server", Type::TXT, Class::CH);
+        assert_eq!(RData::TXT(vec!["ns1".into()]), reply.answers[0].rdata);
+
+        // no data for other types
+        let reply = ask(&chain, "id.server", Type::A, Class::CH);
+        assert_eq!((rcode::NOERROR, 0), (reply.rcode, reply.answers.len()));
+
+        // unset and unknown names are refused, not forwarded
+        let reply = ask(&chain, "hostname.bind", Type::TXT, Class::CH);
+        assert_eq!(rcode::REFUSED, reply.rcode);
+        let reply = ask(&chain, "authors.bind", Type::TXT, Class::CH);
+        assert_eq!(rcode::REFUSED, reply.rcode);
+
+        // class IN goes on as usual
+        let reply = ask(&chain, "version.bind", Type::TXT, Class::IN);
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+    }
+}
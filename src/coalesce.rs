@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Runs identical concurrent work once: callers arriving while the work for
+/// their key is in progress wait for it and get a copy of its result.
+pub struct Coalescer<K, V> {
+    flights: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+struct Flight<V> {
+    state: Mutex<State<V>>,
+    done: Condvar,
+}
+
+enum State<V> {
+    Running,
+    Done(V),
+    // the leader panicked, waiters do the work themselves
+    Abandoned,
+}
+
+/// Ends the leader's flight, also when it unwinds.
+struct Landing<'a, K: Eq + Hash, V> {
+    coalescer: &'a Coalescer<K, V>,
+    key: &'a K,
+    flight: &'a Flight<V>,
+    result: Option<V>,
+}
+
+impl<K: Eq + Hash, V> Drop for Landing<'_, K, V> {
+    fn drop(&mut self) {
+        self.coalescer.flights.lock().unwrap().remove(self.key);
+        *self.flight.state.lock().unwrap() = match self.result.take() {
+            Some(result) => State::Done(result),
+            None => State::Abandoned,
+        };
+        self.flight.done.notify_all();
+    }
+}
+
+impl<K, V> Default for Coalescer<K, V> {
+    fn default() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Coalescer<K, V> {
+    /// Runs `work` for `key`, unless it is already running for another
+    /// caller, in which case waits for that result instead.
+    pub fn run(&self, key: K, work: impl FnOnce() -> V) -> V {
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        state: Mutex::new(State::Running),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut state = flight.state.lock().unwrap();
+            loop {
+                match &*state {
+                    State::Running => state = flight.done.wait(state).unwrap(),
+                    State::Done(result) => return result.clone(),
+                    State::Abandoned => break,
+                }
+            }
+            drop(state);
+            return work();
+        }
+
+        let mut landing = Landing {
+            coalescer: self,
+            key: &key,
+            flight: &flight,
+            result: None,
+        };
+        let result = work();
+        landing.result = Some(result.clone());
+        result
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Coalescer;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_concurrent_calls_share_work() {
+        let coalescer = Arc::new(Coalescer::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(4));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (coalescer, calls, barrier) =
+                    (coalescer.clone(), calls.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    coalescer.run("example.com", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        42
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(42, handle.join().unwrap());
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!(0, coalescer.in_flight());
+
+        // finished work isn't reused
+        assert_eq!(7, coalescer.run("example.com", || 7));
+    }
+
+    #[test]
+    fn test_panicking_leader() {
+        let coalescer = Arc::new(Coalescer::default());
+        let leader = {
+            let coalescer = coalescer.clone();
+            thread::spawn(move || {
+                coalescer.run(1, || -> u32 {
+                    thread::sleep(Duration::from_millis(50));
+                    panic!("upstream exploded");
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(2, coalescer.run(1, || 2));
+        assert!(leader.join().is_err());
+        assert_eq!(0, coalescer.in_flight());
+    }
+}
@@ -0,0 +1,1266 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{
+    acl::{Acl, Network},
+    anchors::TrustAnchor,
+    any::AnyPolicy,
+    balance::Strategy,
+    blocklist::{BlockResponse, Source},
+    chaos::Chaos,
+    dns64::Prefix,
+    dnssec::DenialChain,
+    doh,
+    ecs::{self, EcsMode},
+    encoding::hex_decode,
+    forward::{parse_endpoint, parse_resolver, parse_upstream, Endpoint},
+    hosts::parse_override,
+    policy::{NamePattern, Rule},
+    proto::{Name, Type},
+    rotate::AnswerOrder,
+    rrl::RateLimit,
+    socket::SocketOptions,
+    tsig::Key,
+    x509::HostPins,
+    zone::ZoneConfig,
+};
+
+/// Error in a configuration file, with the 1-based line it was found on.
+#[derive(Error, Debug, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Settings that can be changed without restarting, from the command line
+/// and an optional configuration file. Listen addresses and the serving mode
+/// are fixed at startup.
+///
+/// The file uses a small subset of TOML:
+///
+/// ```text
+/// resolvers = ["8.8.8.8:53", "https://cloudflare-dns.com/dns-query"]
+/// recursive = false
+/// root_hints = "/etc/dns/named.root"
+/// dns64 = true
+/// dns64_prefix = "64:ff9b::/96"
+/// dns64_exclude = ["2001:db8::/32"]
+/// upstream_strategy = "fastest"
+/// answer_order = "rotate"
+/// ecs = "add"
+/// ecs_ipv4_prefix = 24
+/// ecs_ipv6_prefix = 56
+/// upstream_timeout_ms = 2000
+/// reverse = true
+/// udp_any = "hinfo"
+/// dnssec_validation = true
+/// trust_anchors = ["example.com. DS 3613 15 2 3AA5AB37EFCE57F737FC1627013FEE07BDF241BD10F3B1964AB55C78E79A304B"]
+/// trust_anchor_files = ["/etc/dns/anchors"]
+/// trust_anchor_state = "/var/lib/dns/anchors.state"
+/// tls_roots = "/etc/ssl/certs/ca-certificates.crt"
+/// odoh_relay = "https://relay.example/proxy"
+/// hosts_files = ["/etc/hosts"]
+/// address = ["/example.lan/192.168.1.10", "/example.lan/fd00::10"]
+/// blocklists = ["/etc/dns/ads.hosts", "https://lists.example/domains.txt"]
+/// block_response = "nxdomain"
+/// blocklist_refresh_secs = 86400
+/// query_log = "/var/log/dns/queries.log"
+/// query_log_size = 10485760
+/// query_log_versions = 3
+///
+/// [tls_pins]
+/// "dns.example" = ["sha256/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="]
+///
+/// [hosts]
+/// "nas.lan" = "192.168.1.10"
+///
+/// [forward]
+/// "corp.example.com" = ["10.0.0.53", "10.0.0.54:53"]
+///
+/// [keys]
+/// "xfr.example.com" = "hmac-sha256:c2VjcmV0"
+///
+/// [rate_limit]
+/// responses_per_second = 10
+/// window = 15
+/// slip = 2
+/// exempt = ["192.0.2.0/24"]
+///
+/// [acl]
+/// allow_recursion = ["127.0.0.0/8", "192.168.0.0/16"]
+/// deny_query = ["203.0.113.0/24"]
+///
+/// [chaos]
+/// version = "dns 1.0"
+/// hostname = "ns1.example.com"
+/// id = "ns1"
+///
+/// [rules."bedtime"]
+/// names = ["*.games.example"]
+/// regex = "^play[0-9]*\."
+/// qtypes = ["A", "AAAA"]
+/// clients = ["192.168.1.0/24"]
+/// time = "22:00-06:00"
+/// action = "drop"
+///
+/// [listeners."0.0.0.0:53"]
+/// allow = ["192.168.0.0/16"]
+///
+/// [sockets]
+/// recv_buffer = 4194304
+/// send_buffer = 1048576
+/// dscp = 46
+/// ipv6_only = true
+///
+/// [zones."example.com"]
+/// file = "/etc/dns/example.com.zone"
+/// default_ttl = 3600
+/// allow_transfer = ["192.0.2.0/24", "2001:db8::53"]
+/// transfer_keys = ["xfr.example.com"]
+/// notify = ["192.0.2.54"]
+/// dnssec_key = "/etc/dns/Kexample.com.private"
+/// nsec3 = true
+/// nsec3_salt = "aabbccdd"
+/// nsec3_iterations = 0
+///
+/// [zones."example.net"]
+/// primary = "192.0.2.53"
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    // upstreams in order of preference, empty for the stub
+    pub resolvers: Vec<Endpoint>,
+    // resolve from the root servers instead of asking upstreams
+    pub recursive: bool,
+    // root servers recursion starts from, if not the built-in ones
+    pub root_hints: Option<PathBuf>,
+    // synthesize AAAA records from A records under the prefix, for names
+    // without AAAA records outside the excluded networks
+    pub dns64: bool,
+    pub dns64_prefix: Prefix,
+    pub dns64_exclude: Vec<Network>,
+    // zone -> upstreams for the names under it
+    pub forward_rules: Vec<(String, Vec<Endpoint>)>,
+    // how upstreams are ordered for each query
+    pub upstream_strategy: Strategy,
+    // how the addresses of each name are ordered in answers
+    pub answer_order: AnswerOrder,
+    // what upstreams are told of client subnets, and at most how much of
+    // client addresses when adding them
+    pub ecs: EcsMode,
+    pub ecs_ipv4_prefix: u8,
+    pub ecs_ipv6_prefix: u8,
+    // how long to wait for each upstream attempt
+    pub upstream_timeout: Duration,
+    // further attempts after the first one times out
+    pub upstream_retries: u32,
+    // PEM bundle DoH, DoQ and ODoH upstreams are authenticated with, if not
+    // the system's
+    pub tls_roots: Option<PathBuf>,
+    pub tls_pins: HostPins,
+    // relay ODoH upstreams are reached through
+    pub odoh_relay: Option<doh::Url>,
+    // validate forwarded answers with DNSSEC
+    pub dnssec_validation: bool,
+    // where chains of trust start, besides the root's keys
+    pub trust_anchors: Vec<TrustAnchor>,
+    // files of more trust anchors
+    pub trust_anchor_files: Vec<PathBuf>,
+    // where key rollovers of the anchored zones are tracked, if anywhere
+    pub trust_anchor_state: Option<PathBuf>,
+    pub hosts: Vec<(String, IpAddr)>,
+    // files of more host entries, with their PTR names
+    pub hosts_files: Vec<PathBuf>,
+    // domain -> address answered for it and the names under it
+    pub overrides: Vec<(String, IpAddr)>,
+    pub reverse: bool,
+    // lists of names answered with `block_response` instead of resolved,
+    // and how often they're loaded again, never if zero
+    pub blocklists: Vec<Source>,
+    pub block_response: BlockResponse,
+    pub blocklist_refresh: Duration,
+    // file every query is logged to, `-` for stdout, rotated once it grows
+    // past `query_log_size` bytes if that isn't zero
+    pub query_log: Option<PathBuf>,
+    pub query_log_size: u64,
+    pub query_log_versions: u32,
+    // zones served authoritatively
+    pub zones: Vec<ZoneConfig>,
+    // TSIG keys requests may be signed with
+    pub keys: Vec<Key>,
+    pub udp_any: AnyPolicy,
+    pub tcp_any: AnyPolicy,
+    // answers to version.bind, hostname.bind and id.server in class CH
+    pub chaos: Chaos,
+    // limits on identical UDP responses
+    pub rate_limit: RateLimit,
+    // clients that may query at all, have names resolved that aren't
+    // answered locally, and transfer zones
+    pub query_acl: Acl,
+    pub recursion_acl: Acl,
+    pub transfer_acl: Acl,
+    // listen address -> clients it serves
+    pub listener_acls: Vec<(SocketAddr, Acl)>,
+    // what happens to the requests they match, the first one deciding
+    pub rules: Vec<Rule>,
+    // tuning of listener and upstream sockets, listeners only get it at
+    // startup
+    pub sockets: SocketOptions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            resolvers: Vec::new(),
+            recursive: false,
+            root_hints: None,
+            dns64: false,
+            dns64_prefix: Prefix::default(),
+            dns64_exclude: Vec::new(),
+            forward_rules: Vec::new(),
+            upstream_strategy: Strategy::default(),
+            answer_order: AnswerOrder::default(),
+            ecs: EcsMode::default(),
+            ecs_ipv4_prefix: ecs::IPV4_PREFIX,
+            ecs_ipv6_prefix: ecs::IPV6_PREFIX,
+            upstream_timeout: Duration::from_secs(2),
+            upstream_retries: 2,
+            tls_roots: None,
+            tls_pins: HostPins::default(),
+            odoh_relay: None,
+            dnssec_validation: false,
+            trust_anchors: Vec::new(),
+            trust_anchor_files: Vec::new(),
+            trust_anchor_state: None,
+            hosts: Vec::new(),
+            hosts_files: Vec::new(),
+            overrides: Vec::new(),
+            reverse: false,
+            blocklists: Vec::new(),
+            block_response: BlockResponse::default(),
+            blocklist_refresh: Duration::from_secs(24 * 60 * 60),
+            query_log: None,
+            query_log_size: 0,
+            query_log_versions: 3,
+            zones: Vec::new(),
+            keys: Vec::new(),
+            udp_any: AnyPolicy::default(),
+            tcp_any: AnyPolicy::default(),
+            chaos: Chaos::default(),
+            rate_limit: RateLimit::default(),
+            query_acl: Acl::default(),
+            recursion_acl: Acl::default(),
+            transfer_acl: Acl::default(),
+            listener_acls: Vec::new(),
+            rules: Vec::new(),
+            sockets: SocketOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Bool(bool),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+impl Config {
+    /// Reads `path` and applies it on top of `self`.
+    pub fn load(&self, path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(self.apply(&text)?)
+    }
+
+    /// Applies the settings in `text` on top of `self`. Scalar settings are
+    /// replaced, host entries, forward rules, TLS pins, zones, keys,
+    /// listener ACLs and policy rules are added.
+    pub fn apply(&self, text: &str) -> Result<Self, ConfigError> {
+        let mut config = self.clone();
+        let mut section = String::new();
+        // line of each zone section, to report the ones without a file
+        let mut zone_lines = Vec::new();
+        // line of each rule section and whether it has an action
+        let mut rule_lines = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: String| ConfigError {
+                line: i + 1,
+                message,
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                section = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| err("unterminated section header".into()))?
+                    .trim()
+                    .to_string();
+                if let Some(origin) = section.strip_prefix("zones.") {
+                    let origin = parse_key(origin.trim()).map_err(err)?;
+                    config.zones.push(ZoneConfig {
+                        origin: Some(origin.parse::<Name>().map_err(err)?),
+                        ..ZoneConfig::default()
+                    });
+                    zone_lines.push(i + 1);
+                    section = "zones".into();
+                } else if let Some(addr) = section.strip_prefix("listeners.") {
+                    let addr = parse_key(addr.trim()).map_err(err)?;
+                    let addr = parse_addr(&addr).map_err(err)?;
+                    config
+                        .listener_acls
+                        .retain(|(listener, _)| *listener != addr);
+                    config.listener_acls.push((addr, Acl::default()));
+                    section = "listeners".into();
+                } else if let Some(name) = section.strip_prefix("rules.") {
+                    let name = parse_key(name.trim()).map_err(err)?;
+                    config.rules.push(Rule::new(&name));
+                    rule_lines.push((i + 1, false));
+                    section = "rules".into();
+                } else if ![
+                    "hosts",
+                    "forward",
+                    "tls_pins",
+                    "keys",
+                    "rate_limit",
+                    "acl",
+                    "chaos",
+                    "sockets",
+                ]
+                .contains(&section.as_str())
+                {
+                    return Err(err(format!("unknown section [{}]", section)));
+                }
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected key = value".into()))?;
+            let key = parse_key(key.trim()).map_err(err)?;
+            let value = parse_value(value.trim()).map_err(err)?;
+
+            match (section.as_str(), key.as_str(), value) {
+                ("hosts", name, Value::String(addr)) => {
+                    let addr = addr
+                        .parse()
+                        .map_err(|e| err(format!("{}: {:?}", e, addr)))?;
+                    config.hosts.push((name.to_string(), addr));
+                }
+                ("forward", zone, Value::String(addr)) => {
+                    let addr = parse_endpoint(&addr).map_err(err)?;
+                    config.forward_rules.push((zone.to_string(), vec![addr]));
+                }
+                ("forward", zone, Value::Array(addrs)) => {
+                    let addrs = addrs
+                        .iter()
+                        .map(|addr| match addr {
+                            Value::String(addr) => parse_endpoint(addr),
+                            other => Err(format!("expected an address, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                    config.forward_rules.push((zone.to_string(), addrs));
+                }
+                ("tls_pins", host, Value::String(pin)) => {
+                    let pin = pin.parse().map_err(err)?;
+                    config.tls_pins.insert(host, vec![pin]);
+                }
+                ("tls_pins", host, Value::Array(pins)) => {
+                    let pins = pins
+                        .iter()
+                        .map(|pin| match pin {
+                            Value::String(pin) => pin.parse(),
+                            other => Err(format!("expected a pin, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                    config.tls_pins.insert(host, pins);
+                }
+                ("keys", name, Value::String(key)) => {
+                    config.keys.push(Key::parse(name, &key).map_err(err)?);
+                }
+                ("zones", "file", Value::String(file)) => {
+                    config.zones.last_mut().unwrap().file = PathBuf::from(file);
+                }
+                ("zones", "dnssec_key", Value::String(path)) => {
+                    config.zones.last_mut().unwrap().dnssec_key = Some(PathBuf::from(path));
+                }
+                ("zones", "nsec3", Value::Bool(nsec3)) => {
+                    let zone = config.zones.last_mut().unwrap();
+                    let (salt, iterations) = nsec3_params(&zone.denial);
+                    zone.denial = match nsec3 {
+                        true => DenialChain::Nsec3 { salt, iterations },
+                        false => DenialChain::Nsec,
+                    };
+                }
+                ("zones", "nsec3_salt", Value::String(hex)) => {
+                    let salt = match hex.as_str() {
+                        "" | "-" => Vec::new(),
+                        hex => hex_decode(hex)
+                            .filter(|salt| salt.len() <= u8::MAX as usize)
+                            .ok_or_else(|| err(format!("invalid NSEC3 salt {:?}", hex)))?,
+                    };
+                    let zone = config.zones.last_mut().unwrap();
+                    let (_, iterations) = nsec3_params(&zone.denial);
+                    zone.denial = DenialChain::Nsec3 { salt, iterations };
+                }
+                ("zones", "nsec3_iterations", Value::Integer(n)) => {
+                    let iterations = u16::try_from(n)
+                        .map_err(|_| err(format!("invalid NSEC3 iterations {}", n)))?;
+                    let zone = config.zones.last_mut().unwrap();
+                    let (salt, _) = nsec3_params(&zone.denial);
+                    zone.denial = DenialChain::Nsec3 { salt, iterations };
+                }
+                ("zones", "primary", Value::String(addr)) => {
+                    config.zones.last_mut().unwrap().primary =
+                        Some(parse_upstream(&addr).map_err(err)?);
+                }
+                ("zones", "notify", Value::Array(addrs)) => {
+                    config.zones.last_mut().unwrap().notify = addrs
+                        .iter()
+                        .map(|addr| match addr {
+                            Value::String(addr) => parse_upstream(addr),
+                            other => Err(format!("expected an address, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                }
+                ("zones", "default_ttl", Value::Integer(ttl)) => {
+                    config.zones.last_mut().unwrap().default_ttl =
+                        Some(u32::try_from(ttl).map_err(|_| err(format!("invalid TTL {}", ttl)))?);
+                }
+                ("zones", "allow_transfer", Value::Array(networks)) => {
+                    config.zones.last_mut().unwrap().allow_transfer =
+                        parse_networks(&networks).map_err(err)?;
+                }
+                ("zones", "transfer_keys", Value::Array(names)) => {
+                    config.zones.last_mut().unwrap().transfer_keys = names
+                        .iter()
+                        .map(|name| match name {
+                            Value::String(name) => name.parse(),
+                            other => Err(format!("expected a key name, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                }
+                ("", "resolver", Value::String(addr)) => {
+                    config.resolvers = vec![parse_resolver(&addr).map_err(err)?];
+                }
+                ("", "resolvers", Value::Array(addrs)) => {
+                    config.resolvers = addrs
+                        .iter()
+                        .map(|addr| match addr {
+                            Value::String(addr) => parse_resolver(addr),
+                            other => Err(format!("expected an address, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                }
+                ("", "recursive", Value::Bool(recursive)) => config.recursive = recursive,
+                ("", "root_hints", Value::String(path)) => {
+                    config.root_hints = Some(path.into());
+                }
+                ("", "dns64", Value::Bool(dns64)) => config.dns64 = dns64,
+                ("", "dns64_prefix", Value::String(prefix)) => {
+                    config.dns64_prefix = prefix.parse().map_err(err)?;
+                }
+                ("", "dns64_exclude", Value::Array(networks)) => {
+                    config.dns64_exclude = parse_networks(&networks).map_err(err)?;
+                }
+                ("", "upstream_strategy", Value::String(strategy)) => {
+                    config.upstream_strategy = Strategy::from_str(&strategy).map_err(err)?;
+                }
+                ("", "answer_order", Value::String(order)) => {
+                    config.answer_order = AnswerOrder::from_str(&order).map_err(err)?;
+                }
+                ("", "ecs", Value::String(mode)) => {
+                    config.ecs = EcsMode::from_str(&mode).map_err(err)?;
+                }
+                ("", "ecs_ipv4_prefix", Value::Integer(bits)) => {
+                    config.ecs_ipv4_prefix = u8::try_from(bits)
+                        .ok()
+                        .filter(|bits| *bits <= 32)
+                        .ok_or_else(|| err(format!("invalid prefix length {}", bits)))?;
+                }
+                ("", "ecs_ipv6_prefix", Value::Integer(bits)) => {
+                    config.ecs_ipv6_prefix = u8::try_from(bits)
+                        .ok()
+                        .filter(|bits| *bits <= 128)
+                        .ok_or_else(|| err(format!("invalid prefix length {}", bits)))?;
+                }
+                ("", "upstream_timeout_ms", Value::Integer(ms)) => {
+                    let ms = u64::try_from(ms)
+                        .ok()
+                        .filter(|ms| *ms > 0)
+                        .ok_or_else(|| err(format!("invalid timeout {}", ms)))?;
+                    config.upstream_timeout = Duration::from_millis(ms);
+                }
+                ("", "upstream_retries", Value::Integer(retries)) => {
+                    config.upstream_retries = u32::try_from(retries)
+                        .map_err(|_| err(format!("invalid retry count {}", retries)))?;
+                }
+                ("", "reverse", Value::Bool(reverse)) => config.reverse = reverse,
+                ("", "hosts_files", Value::Array(files)) => {
+                    for file in files {
+                        match file {
+                            Value::String(file) => config.hosts_files.push(file.into()),
+                            other => return Err(err(format!("expected a path, got {:?}", other))),
+                        }
+                    }
+                }
+                ("", "address", Value::Array(overrides)) => {
+                    for entry in overrides {
+                        let Value::String(entry) = entry else {
+                            return Err(err(format!("expected an override, got {:?}", entry)));
+                        };
+                        let (domains, addr) = parse_override(&entry).map_err(err)?;
+                        config
+                            .overrides
+                            .extend(domains.into_iter().map(|domain| (domain, addr)));
+                    }
+                }
+                ("", "blocklists", Value::Array(sources)) => {
+                    for source in sources {
+                        match source {
+                            Value::String(source) => {
+                                config.blocklists.push(source.parse().map_err(err)?);
+                            }
+                            other => {
+                                return Err(err(format!("expected a path or URL, got {:?}", other)))
+                            }
+                        }
+                    }
+                }
+                ("", "block_response", Value::String(response)) => {
+                    config.block_response = response.parse().map_err(err)?;
+                }
+                ("", "blocklist_refresh_secs", Value::Integer(secs)) => {
+                    let secs = u64::try_from(secs)
+                        .map_err(|_| err(format!("invalid refresh interval {}", secs)))?;
+                    config.blocklist_refresh = Duration::from_secs(secs);
+                }
+                ("", "query_log", Value::String(path)) => config.query_log = Some(path.into()),
+                ("", "query_log_size", Value::Integer(size)) => {
+                    config.query_log_size = u64::try_from(size)
+                        .map_err(|_| err(format!("invalid query log size {}", size)))?;
+                }
+                ("", "query_log_versions", Value::Integer(n)) => {
+                    config.query_log_versions = u32::try_from(n)
+                        .map_err(|_| err(format!("invalid query log versions {}", n)))?;
+                }
+                ("", "dnssec_validation", Value::Bool(validate)) => {
+                    config.dnssec_validation = validate;
+                }
+                ("", "trust_anchors", Value::Array(anchors)) => {
+                    let anchors: Vec<TrustAnchor> = anchors
+                        .iter()
+                        .map(|anchor| match anchor {
+                            Value::String(anchor) => anchor.parse(),
+                            other => Err(format!("expected a trust anchor, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                    config.trust_anchors.extend(anchors);
+                }
+                ("", "trust_anchor_files", Value::Array(files)) => {
+                    for file in files {
+                        match file {
+                            Value::String(file) => config.trust_anchor_files.push(file.into()),
+                            other => return Err(err(format!("expected a path, got {:?}", other))),
+                        }
+                    }
+                }
+                ("", "tls_roots", Value::String(path)) => {
+                    config.tls_roots = Some(PathBuf::from(path));
+                }
+                ("", "odoh_relay", Value::String(url)) => {
+                    config.odoh_relay = Some(url.parse().map_err(err)?);
+                }
+                ("", "trust_anchor_state", Value::String(path)) => {
+                    config.trust_anchor_state = Some(PathBuf::from(path));
+                }
+                ("", "udp_any", Value::String(policy)) => {
+                    config.udp_any = AnyPolicy::from_str(&policy).map_err(err)?;
+                }
+                ("", "tcp_any", Value::String(policy)) => {
+                    config.tcp_any = AnyPolicy::from_str(&policy).map_err(err)?;
+                }
+                ("chaos", "version", Value::String(text)) => config.chaos.version = Some(text),
+                ("chaos", "hostname", Value::String(text)) => config.chaos.hostname = Some(text),
+                ("chaos", "id", Value::String(text)) => config.chaos.id = Some(text),
+                ("rate_limit", "responses_per_second", Value::Integer(n)) => {
+                    config.rate_limit.responses_per_second =
+                        u32::try_from(n).map_err(|_| err(format!("invalid rate {}", n)))?;
+                }
+                ("rate_limit", "window", Value::Integer(secs)) => {
+                    config.rate_limit.window = u32::try_from(secs)
+                        .ok()
+                        .filter(|secs| (1..=3600).contains(secs))
+                        .ok_or_else(|| err(format!("invalid window {}", secs)))?;
+                }
+                ("rate_limit", "slip", Value::Integer(n)) => {
+                    config.rate_limit.slip = u32::try_from(n)
+                        .ok()
+                        .filter(|n| *n <= 10)
+                        .ok_or_else(|| err(format!("invalid slip {}", n)))?;
+                }
+                ("rate_limit", "ipv4_prefix", Value::Integer(bits)) => {
+                    config.rate_limit.ipv4_prefix = u8::try_from(bits)
+                        .ok()
+                        .filter(|bits| *bits <= 32)
+                        .ok_or_else(|| err(format!("invalid prefix length {}", bits)))?;
+                }
+                ("rate_limit", "ipv6_prefix", Value::Integer(bits)) => {
+                    config.rate_limit.ipv6_prefix = u8::try_from(bits)
+                        .ok()
+                        .filter(|bits| *bits <= 128)
+                        .ok_or_else(|| err(format!("invalid prefix length {}", bits)))?;
+                }
+                ("rate_limit", "exempt", Value::Array(networks)) => {
+                    config.rate_limit.exempt = parse_networks(&networks).map_err(err)?;
+                }
+                ("sockets", key @ ("recv_buffer" | "send_buffer"), Value::Integer(size)) => {
+                    let size = u32::try_from(size)
+                        .ok()
+                        .filter(|size| *size > 0)
+                        .ok_or_else(|| err(format!("invalid buffer size {}", size)))?;
+                    match key {
+                        "recv_buffer" => config.sockets.recv_buffer = Some(size),
+                        _ => config.sockets.send_buffer = Some(size),
+                    }
+                }
+                ("sockets", "dscp", Value::Integer(dscp)) => {
+                    config.sockets.dscp = Some(
+                        u8::try_from(dscp)
+                            .ok()
+                            .filter(|dscp| *dscp < 64)
+                            .ok_or_else(|| err(format!("invalid DSCP {}", dscp)))?,
+                    );
+                }
+                ("sockets", "ipv6_only", Value::Bool(only)) => {
+                    config.sockets.ipv6_only = Some(only);
+                }
+                ("acl", key, Value::Array(networks)) => {
+                    let unknown = || err(format!("unknown ACL {}", key));
+                    let (list, capability) = key.split_once('_').ok_or_else(unknown)?;
+                    let acl = match capability {
+                        "query" => &mut config.query_acl,
+                        "recursion" => &mut config.recursion_acl,
+                        "transfer" => &mut config.transfer_acl,
+                        _ => return Err(unknown()),
+                    };
+                    let networks = parse_networks(&networks).map_err(err)?;
+                    match list {
+                        "allow" => acl.allow = networks,
+                        "deny" => acl.deny = networks,
+                        _ => return Err(unknown()),
+                    }
+                }
+                ("listeners", key @ ("allow" | "deny"), Value::Array(networks)) => {
+                    let (_, acl) = config.listener_acls.last_mut().unwrap();
+                    let networks = parse_networks(&networks).map_err(err)?;
+                    match key {
+                        "allow" => acl.allow = networks,
+                        _ => acl.deny = networks,
+                    }
+                }
+                ("rules", "names", Value::Array(globs)) => {
+                    let rule = config.rules.last_mut().unwrap();
+                    for glob in globs {
+                        match glob {
+                            Value::String(glob) => rule.patterns.push(NamePattern::Glob(glob)),
+                            other => return Err(err(format!("expected a glob, got {:?}", other))),
+                        }
+                    }
+                }
+                ("rules", "regex", Value::String(regex)) => {
+                    let regex = regex.parse().map_err(err)?;
+                    let rule = config.rules.last_mut().unwrap();
+                    rule.patterns.push(NamePattern::Regex(regex));
+                }
+                ("rules", "qtypes", Value::Array(types)) => {
+                    config.rules.last_mut().unwrap().qtypes = types
+                        .iter()
+                        .map(|qtype| match qtype {
+                            Value::String(qtype) => {
+                                Type::from_str(qtype).map_err(|e| e.to_string())
+                            }
+                            other => Err(format!("expected a type, got {:?}", other)),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                }
+                ("rules", "clients", Value::Array(networks)) => {
+                    config.rules.last_mut().unwrap().clients =
+                        parse_networks(&networks).map_err(err)?;
+                }
+                ("rules", "time", Value::String(time)) => {
+                    config.rules.last_mut().unwrap().time = Some(time.parse().map_err(err)?);
+                }
+                ("rules", "action", Value::String(action)) => {
+                    config.rules.last_mut().unwrap().action = action.parse().map_err(err)?;
+                    rule_lines.last_mut().unwrap().1 = true;
+                }
+                (_, key, value) => {
+                    return Err(err(format!("unexpected setting {} = {:?}", key, value)));
+                }
+            }
+        }
+
+        let added = &config.zones[self.zones.len()..];
+        if let Some((line, _)) = zone_lines
+            .iter()
+            .zip(added)
+            .find(|(_, zone)| zone.file.as_os_str().is_empty() && zone.primary.is_none())
+        {
+            return Err(ConfigError {
+                line: *line,
+                message: "zone without a file or primary".into(),
+            });
+        }
+        if let Some((line, _)) = rule_lines.iter().find(|(_, action)| !action) {
+            return Err(ConfigError {
+                line: *line,
+                message: "rule without an action".into(),
+            });
+        }
+        for (line, zone) in zone_lines.iter().zip(added) {
+            if let Some(name) = zone
+                .transfer_keys
+                .iter()
+                .find(|name| !config.keys.iter().any(|key| key.name == **name))
+            {
+                return Err(ConfigError {
+                    line: *line,
+                    message: format!("unknown key {}", name),
+                });
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_networks(values: &[Value]) -> Result<Vec<Network>, String> {
+    values
+        .iter()
+        .map(|network| match network {
+            Value::String(network) => network.parse(),
+            other => Err(format!("expected a network, got {:?}", other)),
+        })
+        .collect()
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse().map_err(|e| format!("{}: {:?}", e, addr))
+}
+
+/// Drops a `#` comment, unless it is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    if let Some(quoted) = key.strip_prefix('"') {
+        return quoted
+            .strip_suffix('"')
+            .map(String::from)
+            .ok_or_else(|| format!("unterminated key {}", key));
+    }
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!("invalid key {:?}", key));
+    }
+    Ok(key.to_string())
+}
+
+/// Salt and iterations of an NSEC3 chain, the RFC 9276 defaults for an
+/// NSEC one.
+fn nsec3_params(chain: &DenialChain) -> (Vec<u8>, u16) {
+    match chain {
+        DenialChain::Nsec3 { salt, iterations } => (salt.clone(), *iterations),
+        DenialChain::Nsec => (Vec::new(), 0),
+    }
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(items) = value.strip_prefix('[') {
+        let items = items
+            .strip_suffix(']')
+            .ok_or_else(|| format!("unterminated array {}", value))?;
+        return items
+            .split(',')
+            .map(str::trim)
+            // allows a trailing comma
+            .filter(|item| !item.is_empty())
+            .map(parse_value)
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        return quoted
+            .strip_suffix('"')
+            .filter(|s| !s.contains('"'))
+            .map(|s| Value::String(s.to_string()))
+            .ok_or_else(|| format!("invalid string {}", value));
+    }
+    match value {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => value
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("invalid value {:?}", value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Acl, AnswerOrder, AnyPolicy, BlockResponse, Chaos, Config, ConfigError, DenialChain,
+        EcsMode, HostPins, Key, Name, NamePattern, Network, Prefix, RateLimit, Rule, SocketOptions,
+        Source, Strategy, TrustAnchor, Type, ZoneConfig,
+    };
+    use crate::{
+        forward::{parse_endpoint, Endpoint},
+        policy::Action,
+        rdata::Ds,
+        x509::Pin,
+    };
+    use std::{path::PathBuf, time::Duration};
+
+    fn endpoint(s: &str) -> Endpoint {
+        parse_endpoint(s).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let text = r#"
+            # upstream
+            resolvers = ["1.1.1.1:53", "[2606:4700:4700::1111]:53", "https://dns.google",]
+            recursive = true
+            root_hints = "named.root"
+            dns64 = true
+            dns64_prefix = "2001:db8:64::/96"
+            dns64_exclude = ["2001:db8::/32"]
+            upstream_strategy = "round-robin"
+            answer_order = "preserve"
+            ecs = "add"
+            ecs_ipv4_prefix = 20
+            upstream_timeout_ms = 500
+            upstream_retries = 0
+            reverse = true
+            udp_any = "subset" # trailing comment
+            dnssec_validation = true
+            trust_anchors = ["example.com. IN DS 3613 15 2 3AA5AB37EFCE57F7 37FC1627013FEE07"]
+            trust_anchor_files = ["anchors"]
+            trust_anchor_state = "anchors.state"
+            tls_roots = "roots.pem"
+            odoh_relay = "https://relay.example/proxy"
+            hosts_files = ["/etc/hosts", "lan.hosts"]
+            address = ["/example.lan/test.lan/192.168.1.30"]
+            blocklists = ["ads.hosts", "https://lists.example/domains.txt"]
+            block_response = "0.0.0.0"
+            blocklist_refresh_secs = 3600
+            query_log = "-"
+            query_log_size = 1048576
+
+            [tls_pins]
+            "dns.example" = "sha256/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="
+            "doh.example" = ["sha256/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg=", "cert-sha256/47oe/karkVnBrLU/+QnKDLUzGIVUlJHN1jCdXJLU//0="]
+
+            [hosts]
+            "nas.lan" = "192.168.1.10"
+            router = "fd00::1"
+
+            [forward]
+            "corp.example.com" = "10.0.0.53"
+            "lab.example.com" = ["10.1.0.53:5353", "fd00::53", "https://[fd00::54]:8443/q"]
+
+            [keys]
+            "xfr.example.com" = "hmac-sha256:c2VjcmV0"
+
+            [rate_limit]
+            responses_per_second = 5
+            slip = 0
+            ipv6_prefix = 48
+            exempt = ["192.0.2.0/24"]
+
+            [acl]
+            allow_recursion = ["127.0.0.0/8", "192.168.0.0/16"]
+            deny_query = ["203.0.113.0/24"]
+
+            [chaos]
+            version = "dns 1.0"
+            id = "ns1"
+
+            [listeners."[::1]:2053"]
+            deny = ["::1"]
+
+            [sockets]
+            recv_buffer = 4194304
+            dscp = 46
+            ipv6_only = false
+
+            [rules.bedtime]
+            names = ["*.games.example", "games.example"]
+            regex = "^play[0-9]*\."
+            qtypes = ["A", "AAAA"]
+            clients = ["192.168.1.0/24"]
+            time = "22:00-06:00"
+            action = "drop"
+
+            [rules."corp"]
+            action = "forward 10.0.0.53:53"
+
+            [zones."example.com"]
+            file = "example.com.zone"
+            default_ttl = 600
+            allow_transfer = ["192.0.2.0/24"]
+            transfer_keys = ["xfr.example.com"]
+            notify = ["192.0.2.54", "192.0.2.55:5353"]
+            dnssec_key = "Kexample.com.private"
+            nsec3_salt = "aabbccdd"
+            nsec3 = true
+
+            [zones."example.net"]
+            primary = "192.0.2.53"
+        "#;
+        let base = Config {
+            hosts: vec![("printer.lan".into(), "192.168.1.20".parse().unwrap())],
+            ..Config::default()
+        };
+        let config = base.apply(text).unwrap();
+
+        assert_eq!(
+            vec![
+                endpoint("1.1.1.1:53"),
+                endpoint("[2606:4700:4700::1111]:53"),
+                endpoint("https://dns.google/dns-query"),
+            ],
+            config.resolvers
+        );
+        assert!(config.recursive);
+        assert_eq!(Some(PathBuf::from("named.root")), config.root_hints);
+        assert!(config.dns64);
+        assert_eq!(
+            "2001:db8:64::/96".parse::<Prefix>().unwrap(),
+            config.dns64_prefix
+        );
+        assert_eq!(
+            vec!["2001:db8::/32".parse::<Network>().unwrap()],
+            config.dns64_exclude
+        );
+        assert_eq!(Some(PathBuf::from("roots.pem")), config.tls_roots);
+        let spki: Pin = "sha256/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="
+            .parse()
+            .unwrap();
+        let cert: Pin = "cert-sha256/47oe/karkVnBrLU/+QnKDLUzGIVUlJHN1jCdXJLU//0="
+            .parse()
+            .unwrap();
+        assert_eq!(
+            HostPins::from_iter([
+                ("dns.example".into(), vec![spki]),
+                ("doh.example".into(), vec![spki, cert]),
+            ]),
+            config.tls_pins
+        );
+        assert_eq!(
+            Some("https://relay.example/proxy".parse().unwrap()),
+            config.odoh_relay
+        );
+        assert_eq!(Strategy::RoundRobin, config.upstream_strategy);
+        assert_eq!(AnswerOrder::Preserve, config.answer_order);
+        assert_eq!(EcsMode::Add, config.ecs);
+        assert_eq!(20, config.ecs_ipv4_prefix);
+        assert_eq!(56, config.ecs_ipv6_prefix);
+        assert_eq!(Duration::from_millis(500), config.upstream_timeout);
+        assert_eq!(0, config.upstream_retries);
+        assert!(config.reverse);
+        assert_eq!(
+            vec![
+                Source::File("ads.hosts".into()),
+                Source::Url("https://lists.example/domains.txt".parse().unwrap()),
+            ],
+            config.blocklists
+        );
+        assert_eq!(
+            BlockResponse::Address("0.0.0.0".parse().unwrap()),
+            config.block_response
+        );
+        assert_eq!(Duration::from_secs(3600), config.blocklist_refresh);
+        assert_eq!(Some(PathBuf::from("-")), config.query_log);
+        assert_eq!(1048576, config.query_log_size);
+        assert_eq!(3, config.query_log_versions);
+        assert_eq!(
+            Chaos {
+                version: Some("dns 1.0".into()),
+                hostname: None,
+                id: Some("ns1".into()),
+            },
+            config.chaos
+        );
+        assert!(config.dnssec_validation);
+        assert_eq!(
+            vec![TrustAnchor {
+                zone: Name::from("example.com"),
+                ds: Ds {
+                    key_tag: 3613,
+                    algorithm: 15,
+                    digest_type: 2,
+                    digest: vec![
+                        0x3a, 0xa5, 0xab, 0x37, 0xef, 0xce, 0x57, 0xf7, 0x37, 0xfc, 0x16, 0x27,
+                        0x01, 0x3f, 0xee, 0x07
+                    ],
+                },
+            }],
+            config.trust_anchors
+        );
+        assert_eq!(vec![PathBuf::from("anchors")], config.trust_anchor_files);
+        assert_eq!(
+            Some(PathBuf::from("anchors.state")),
+            config.trust_anchor_state
+        );
+        assert_eq!(AnyPolicy::Subset, config.udp_any);
+        assert_eq!(AnyPolicy::Hinfo, config.tcp_any);
+        assert_eq!(
+            vec![
+                ("printer.lan".into(), "192.168.1.20".parse().unwrap()),
+                ("nas.lan".into(), "192.168.1.10".parse().unwrap()),
+                ("router".into(), "fd00::1".parse().unwrap()),
+            ],
+            config.hosts
+        );
+        assert_eq!(
+            vec![PathBuf::from("/etc/hosts"), PathBuf::from("lan.hosts")],
+            config.hosts_files
+        );
+        assert_eq!(
+            vec![
+                ("example.lan".into(), "192.168.1.30".parse().unwrap()),
+                ("test.lan".into(), "192.168.1.30".parse().unwrap()),
+            ],
+            config.overrides
+        );
+        assert_eq!(
+            vec![
+                ("corp.example.com".into(), vec![endpoint("10.0.0.53:53")]),
+                (
+                    "lab.example.com".into(),
+                    vec![
+                        endpoint("10.1.0.53:5353"),
+                        endpoint("[fd00::53]:53"),
+                        endpoint("https://[fd00::54]:8443/q"),
+                    ]
+                ),
+            ],
+            config.forward_rules
+        );
+        assert_eq!(
+            vec![
+                ZoneConfig {
+                    origin: Some(Name::from("example.com")),
+                    file: "example.com.zone".into(),
+                    primary: None,
+                    default_ttl: Some(600),
+                    allow_transfer: vec!["192.0.2.0/24".parse().unwrap()],
+                    transfer_keys: vec![Name::from("xfr.example.com")],
+                    notify: vec![
+                        "192.0.2.54:53".parse().unwrap(),
+                        "192.0.2.55:5353".parse().unwrap()
+                    ],
+                    dnssec_key: Some("Kexample.com.private".into()),
+                    denial: DenialChain::Nsec3 {
+                        salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                        iterations: 0,
+                    },
+                },
+                ZoneConfig {
+                    origin: Some(Name::from("example.net")),
+                    primary: Some("192.0.2.53:53".parse().unwrap()),
+                    ..ZoneConfig::default()
+                },
+            ],
+            config.zones
+        );
+        assert_eq!(
+            vec![Key::parse("xfr.example.com", "hmac-sha256:c2VjcmV0").unwrap()],
+            config.keys
+        );
+        assert_eq!(
+            RateLimit {
+                responses_per_second: 5,
+                slip: 0,
+                ipv6_prefix: 48,
+                exempt: vec!["192.0.2.0/24".parse().unwrap()],
+                ..RateLimit::default()
+            },
+            config.rate_limit
+        );
+        assert_eq!(
+            Acl {
+                allow: vec![
+                    "127.0.0.0/8".parse().unwrap(),
+                    "192.168.0.0/16".parse().unwrap()
+                ],
+                deny: Vec::new(),
+            },
+            config.recursion_acl
+        );
+        assert_eq!(
+            vec!["203.0.113.0/24".parse::<Network>().unwrap()],
+            config.query_acl.deny
+        );
+        assert_eq!(Acl::default(), config.transfer_acl);
+        assert_eq!(
+            vec![(
+                "[::1]:2053".parse().unwrap(),
+                Acl {
+                    allow: Vec::new(),
+                    deny: vec!["::1".parse().unwrap()],
+                }
+            )],
+            config.listener_acls
+        );
+        assert_eq!(
+            SocketOptions {
+                recv_buffer: Some(4194304),
+                send_buffer: None,
+                dscp: Some(46),
+                ipv6_only: Some(false),
+            },
+            config.sockets
+        );
+        assert_eq!(
+            vec![
+                Rule {
+                    patterns: vec![
+                        NamePattern::Glob("*.games.example".into()),
+                        NamePattern::Glob("games.example".into()),
+                        NamePattern::Regex("^play[0-9]*\\.".parse().unwrap()),
+                    ],
+                    qtypes: vec![Type::A, Type::AAAA],
+                    clients: vec!["192.168.1.0/24".parse().unwrap()],
+                    time: Some("22:00-06:00".parse().unwrap()),
+                    action: Action::Drop,
+                    ..Rule::new("bedtime")
+                },
+                Rule {
+                    action: Action::Forward(vec![endpoint("10.0.0.53:53")]),
+                    ..Rule::new("corp")
+                },
+            ],
+            config.rules
+        );
+    }
+
+    #[test]
+    fn test_apply_errors() {
+        let err = |line, message: &str| {
+            Err(ConfigError {
+                line,
+                message: message.into(),
+            })
+        };
+        let config = Config::default();
+
+        assert_eq!(
+            err(2, "expected key = value"),
+            config.apply("reverse = true\nresolver")
+        );
+        assert_eq!(err(1, "unknown section [zones]"), config.apply("[zones]"));
+        assert_eq!(
+            err(1, "unexpected setting reverse = Integer(1)"),
+            config.apply("reverse = 1")
+        );
+        assert!(config.apply("resolver = \"not-an-addr\"").is_err());
+        assert!(config.apply("resolvers = [\"1.1.1.1:53\", 53]").is_err());
+        assert_eq!(
+            vec![endpoint("9.9.9.9:53")],
+            config.apply("resolver = \"9.9.9.9:53\"").unwrap().resolvers
+        );
+        // plain resolvers need their port
+        assert!(config.apply("resolver = \"9.9.9.9\"").is_err());
+        assert!(config.apply("resolver = \"https://\"").is_err());
+        assert!(config.apply("udp_any = \"some\"").is_err());
+        assert!(config.apply("block_response = \"sinkhole\"").is_err());
+        assert!(config
+            .apply("address = [\"example.lan=192.168.1.10\"]")
+            .is_err());
+        assert!(config.apply("blocklists = [\"\"]").is_err());
+        assert!(config
+            .apply("trust_anchors = [\"example.com. DS 3613 15 2\"]")
+            .is_err());
+        assert_eq!(
+            err(1, "invalid timeout -5"),
+            config.apply("upstream_timeout_ms = -5")
+        );
+        assert_eq!(
+            err(1, "zone without a file or primary"),
+            config.apply("[zones.\"example.com\"]\ndefault_ttl = 60")
+        );
+        assert_eq!(
+            err(1, "rule without an action"),
+            config.apply("[rules.any]\nqtypes = [\"ANY\"]")
+        );
+        assert!(config.apply("[rules.r]\naction = \"block\"").is_err());
+        assert!(config.apply("[rules.r]\nregex = \"(ads\"").is_err());
+        assert!(config.apply("[rules.r]\ntime = \"22-06\"").is_err());
+        assert!(config
+            .apply("[zones.\"example.com\"]\nallow_transfer = [\"192.0.2.0/33\"]")
+            .is_err());
+        assert_eq!(
+            err(1, "unknown key xfr"),
+            config.apply("[zones.\"example.com\"]\nfile = \"z\"\ntransfer_keys = [\"xfr\"]")
+        );
+        assert!(config.apply("[keys]\nxfr = \"hmac-md5:c2VjcmV0\"").is_err());
+        assert_eq!(
+            err(3, "invalid NSEC3 iterations 70000"),
+            config.apply("[zones.\"example.com\"]\nfile = \"z\"\nnsec3_iterations = 70000")
+        );
+        assert!(config
+            .apply("[zones.\"example.com\"]\nfile = \"z\"\nnsec3_salt = \"xyz\"")
+            .is_err());
+        assert_eq!(
+            err(2, "invalid window 0"),
+            config.apply("[rate_limit]\nwindow = 0")
+        );
+        assert!(config.apply("[rate_limit]\nipv4_prefix = 33").is_err());
+        assert_eq!(
+            err(2, "unknown ACL allow_everything"),
+            config.apply("[acl]\nallow_everything = [\"::/0\"]")
+        );
+        assert!(config.apply("[listeners.\"localhost\"]").is_err());
+        assert_eq!(
+            err(2, "invalid DSCP 64"),
+            config.apply("[sockets]\ndscp = 64")
+        );
+        assert_eq!(
+            err(2, "invalid buffer size 0"),
+            config.apply("[sockets]\nsend_buffer = 0")
+        );
+    }
+}
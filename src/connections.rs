@@ -0,0 +1,144 @@
+//! Limits on the TCP connections served at once, in total and from any one
+//! client address, so a client can't tie up every connection slot.
+//! Connections past a limit are closed right after they're accepted.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Why a connection wasn't admitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    Total,
+    PerIp,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Total => write!(f, "too many connections"),
+            Refusal::PerIp => write!(f, "too many connections from the client"),
+        }
+    }
+}
+
+/// Connections open now, by client address.
+#[derive(Debug)]
+pub struct Connections {
+    // at most, in total and from one address; zero for no limit
+    max: usize,
+    max_per_ip: usize,
+    open: Mutex<Open>,
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Guard counting a connection as open until it is dropped.
+#[derive(Debug)]
+pub struct Connection {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Connections {
+    pub fn new(max: usize, max_per_ip: usize) -> Self {
+        Self {
+            max,
+            max_per_ip,
+            open: Mutex::new(Open::default()),
+        }
+    }
+
+    /// Counts a connection from `ip` as open, unless that's past a limit.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Connection, Refusal> {
+        // IPv4 clients of a dual-stack listener count as themselves
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().unwrap();
+        if self.max > 0 && open.total >= self.max {
+            return Err(Refusal::Total);
+        }
+        let from_ip = open.by_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip > 0 && from_ip >= self.max_per_ip {
+            return Err(Refusal::PerIp);
+        }
+        open.by_ip.insert(ip, from_ip + 1);
+        open.total += 1;
+        Ok(Connection {
+            connections: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.lock().unwrap().total
+    }
+
+    /// Blocks until every connection is closed or `timeout` passes, returns
+    /// whether they all were.
+    pub fn wait_closed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.open() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Connections, Refusal};
+    use std::{net::IpAddr, sync::Arc};
+
+    #[test]
+    fn test_limits() {
+        let connections = Arc::new(Connections::new(3, 2));
+        let (a, b): (IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+        let first = connections.admit(a).unwrap();
+        // the same client over IPv4 and IPv4-mapped IPv6
+        let second = connections
+            .admit("::ffff:192.0.2.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(Refusal::PerIp, connections.admit(a).unwrap_err());
+        let third = connections.admit(b).unwrap();
+        assert_eq!(Refusal::Total, connections.admit(b).unwrap_err());
+        assert_eq!(3, connections.open());
+
+        drop(first);
+        let _fourth = connections.admit(a).unwrap();
+        drop((second, third));
+        assert_eq!(1, connections.open());
+        assert!(!connections.open.lock().unwrap().by_ip.contains_key(&b));
+
+        let unlimited = Arc::new(Connections::new(0, 0));
+        let all: Vec<_> = (0..100).map(|_| unlimited.admit(a).unwrap()).collect();
+        assert_eq!(100, unlimited.open());
+        drop(all);
+        assert!(unlimited.wait_closed(std::time::Duration::ZERO));
+    }
+}
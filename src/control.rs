@@ -0,0 +1,200 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{log, proto::Name, traffic::TOP_KEYS};
+
+/// Entries `top` lists if not told how many.
+const DEFAULT_TOP: usize = 10;
+
+/// Command read from the control socket, one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `reload`: read the configuration file again, as SIGHUP does
+    Reload,
+    /// `zone-reload [ZONE]`: read the files of the primary zones again, or
+    /// of ZONE, or have secondary zone ZONE checked with its primary
+    ZoneReload(Option<Name>),
+    /// `set-log-level FILTER`: add `EnvFilter` directives, like `debug` or
+    /// `dns_starter_rust::forward=trace`
+    SetLogLevel(String),
+    /// `flush` or `flush-cache`: drop the whole cache
+    Flush,
+    /// `flush-name NAME`: drop the cache entries for NAME
+    FlushName(Name),
+    /// `flush-tree NAME`: drop the cache entries for NAME and below it
+    FlushTree(Name),
+    /// `stats`: query, cache and upstream counters
+    Stats,
+    /// `dump-stats`: log the counters, as SIGUSR1 does
+    DumpStats,
+    /// `top [N]`: the N (10 if not given) most queried names and busiest
+    /// clients, and the query type and RCODE counts
+    Top(usize),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<_> = words.collect();
+        match (command, args.as_slice()) {
+            ("reload", []) => Ok(Self::Reload),
+            ("zone-reload", []) => Ok(Self::ZoneReload(None)),
+            ("zone-reload", [zone]) => Ok(Self::ZoneReload(Some(zone.parse()?))),
+            ("set-log-level", [directives]) => {
+                log::filter(directives)?;
+                Ok(Self::SetLogLevel(directives.to_string()))
+            }
+            ("flush" | "flush-cache", []) => Ok(Self::Flush),
+            ("flush-name", [name]) => Ok(Self::FlushName(name.parse()?)),
+            ("flush-tree", [name]) => Ok(Self::FlushTree(name.parse()?)),
+            ("stats", []) => Ok(Self::Stats),
+            ("dump-stats", []) => Ok(Self::DumpStats),
+            ("top", []) => Ok(Self::Top(DEFAULT_TOP)),
+            ("top", [n]) => match n.parse() {
+                Ok(n) if (1..=TOP_KEYS).contains(&n) => Ok(Self::Top(n)),
+                _ => Err(format!("top takes a count from 1 to {}", TOP_KEYS)),
+            },
+            _ => Err(format!("unknown command {:?}", s)),
+        }
+    }
+}
+
+/// Listens on a Unix socket at `path` for commands, one per line, and
+/// writes back the one-line reply `execute` gives for each. A stale socket
+/// left at `path` is replaced.
+pub fn spawn(
+    path: &Path,
+    execute: impl Fn(Command) -> String + Send + Sync + 'static,
+) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let execute = Arc::new(execute);
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let execute = execute.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_conn(stream, &*execute) {
+                        tracing::warn!(error = %e, "Control connection failed");
+                    }
+                });
+            }
+        })?;
+    Ok(())
+}
+
+/// Sends `command` to the server listening at `path`, returns its reply.
+pub fn send(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to {}", path.display()))?;
+    writeln!(stream, "{}", command)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    if reply.is_empty() {
+        bail!("no reply from {}", path.display());
+    }
+    Ok(reply.trim_end().to_string())
+}
+
+fn serve_conn(stream: UnixStream, execute: &dyn Fn(Command) -> String) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse() {
+            Ok(command) => execute(command),
+            Err(e) => format!("error: {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{send, spawn, Command};
+    use crate::proto::Name;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+        str::FromStr,
+    };
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(Command::Flush), Command::from_str("flush"));
+        assert_eq!(Ok(Command::Flush), Command::from_str("flush-cache"));
+        assert_eq!(Ok(Command::Reload), Command::from_str("reload"));
+        assert_eq!(
+            Ok(Command::ZoneReload(Some(Name::from("example.com")))),
+            Command::from_str("zone-reload example.com")
+        );
+        assert_eq!(
+            Ok(Command::ZoneReload(None)),
+            Command::from_str("zone-reload")
+        );
+        assert_eq!(
+            Ok(Command::SetLogLevel(
+                "info,dns_starter_rust::forward=debug".into()
+            )),
+            Command::from_str("set-log-level info,dns_starter_rust::forward=debug")
+        );
+        assert!(Command::from_str("set-log-level forward=loud").is_err());
+        assert_eq!(
+            Ok(Command::FlushTree(Name::from("example.com"))),
+            Command::from_str(" flush-tree  example.com ")
+        );
+        assert_eq!(Ok(Command::Stats), Command::from_str("stats"));
+        assert_eq!(Ok(Command::DumpStats), Command::from_str("dump-stats"));
+        assert_eq!(Ok(Command::Top(10)), Command::from_str("top"));
+        assert_eq!(Ok(Command::Top(3)), Command::from_str("top 3"));
+        assert!(Command::from_str("top 0").is_err());
+        assert!(Command::from_str("top many").is_err());
+        assert!(Command::from_str("flush-name").is_err());
+        assert!(Command::from_str("flush everything").is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let path = std::env::temp_dir().join(format!("control-test-{}.sock", std::process::id()));
+        spawn(&path, |command| format!("ok {:?}", command)).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        writeln!(writer, "flush").unwrap();
+        assert_eq!("ok Flush", lines.next().unwrap().unwrap());
+        writeln!(writer, "restart").unwrap();
+        assert_eq!(
+            "error: unknown command \"restart\"",
+            lines.next().unwrap().unwrap()
+        );
+
+        assert_eq!("ok Stats", send(&path, "stats").unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(send(&path, "stats").is_err());
+    }
+}
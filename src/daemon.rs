@@ -0,0 +1,148 @@
+//! Running as a classic daemon: detached from the terminal and session,
+//! with a locked PID file for init scripts. The process that was started
+//! exits once the daemon is serving, with an error if it never gets there.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, PipeWriter, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context, Result};
+
+extern "C" {
+    fn fork() -> i32;
+    fn setsid() -> i32;
+    fn dup2(old: i32, new: i32) -> i32;
+}
+
+/// A PID file, locked for as long as the server runs and removed when it
+/// stops.
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Opens and locks the file at `path`. Fails if another server holds
+    /// it, the PID it wrote in the error.
+    pub fn lock(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open PID file {}", path.display()))?;
+        if file.try_lock().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            bail!(
+                "PID file {} is locked, the server runs as PID {}",
+                path.display(),
+                pid.trim()
+            );
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Writes the PID of this process, the daemon's once detached.
+    pub fn write(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        writeln!(self.file, "{}", process::id())?;
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The daemon's end of the pipe the started process waits on.
+pub struct Detached {
+    ready: PipeWriter,
+}
+
+impl Detached {
+    /// Lets the started process exit successfully, and lets go of the
+    /// terminal's stderr, kept until now for errors on the way up.
+    pub fn ready(mut self) -> Result<()> {
+        self.ready.write_all(b"1")?;
+        let null = File::options().write(true).open("/dev/null")?;
+        // SAFETY: a plain system call on descriptors this process owns
+        check(unsafe { dup2(null.as_raw_fd(), 2) }, "dup2")?;
+        Ok(())
+    }
+}
+
+fn check(result: i32, call: &str) -> Result<i32> {
+    match result {
+        -1 => Err(io::Error::last_os_error()).with_context(|| format!("{} failed", call)),
+        result => Ok(result),
+    }
+}
+
+/// Forks the daemon off in a session of its own, with stdin and stdout on
+/// /dev/null. Only the daemon returns, the started process exits once it's
+/// ready or gone. Must be called before any thread is spawned, the daemon
+/// has only the calling one.
+pub fn detach() -> Result<Detached> {
+    let (mut waiting, ready) = io::pipe()?;
+    // SAFETY: no other threads run yet, see above
+    if check(unsafe { fork() }, "fork")? > 0 {
+        drop(ready);
+        let mut byte = [0];
+        let status = match waiting.read(&mut byte) {
+            Ok(1) => 0,
+            _ => 1,
+        };
+        process::exit(status);
+    }
+    drop(waiting);
+    // SAFETY: plain system calls; the second fork leaves a process that
+    // isn't a session leader, so it can't get a controlling terminal back
+    unsafe {
+        check(setsid(), "setsid")?;
+        if check(fork(), "fork")? > 0 {
+            process::exit(0);
+        }
+    }
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    for fd in [0, 1] {
+        // SAFETY: as above
+        check(unsafe { dup2(null.as_raw_fd(), fd) }, "dup2")?;
+    }
+    Ok(Detached { ready })
+}
+
+#[cfg(test)]
+mod test {
+    use super::PidFile;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("pid-test-{}.pid", std::process::id()));
+        let mut pid_file = PidFile::lock(&path).unwrap();
+        pid_file.write().unwrap();
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(format!("{}\n", std::process::id()), pid);
+
+        // another open file description can't lock it
+        let error = PidFile::lock(&path).err().unwrap().to_string();
+        assert!(
+            error.ends_with(&format!("PID {}", std::process::id())),
+            "{}",
+            error
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,335 @@
+//! DNS64 (RFC 6147): AAAA queries for names with only IPv4 addresses get
+//! AAAA records synthesized from their A records, the IPv4 address embedded
+//! in a NAT64 prefix, so IPv6-only clients reach them through a NAT64
+//! gateway.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Result;
+
+use crate::{
+    acl::Network,
+    proto::{rcode, unresolved_cname, Class, Message, Question, Record, Type},
+    rdata::RData,
+    resolver::Resolver,
+};
+
+/// NAT64 prefix IPv4 addresses are embedded in (RFC 6052, section 2.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Prefix {
+    /// The well-known prefix `64:ff9b::/96`.
+    pub const WELL_KNOWN: Prefix = Prefix {
+        addr: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    /// `ip` within the prefix. Bits 64 to 71 stay zero, the IPv4 address
+    /// continues after them.
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.addr.octets();
+        let mut at = (self.len / 8) as usize;
+        for byte in ip.octets() {
+            if at == 8 {
+                at += 1;
+            }
+            octets[at] = byte;
+            at += 1;
+        }
+        octets[at..].fill(0);
+        Ipv6Addr::from(octets)
+    }
+}
+
+impl Default for Prefix {
+    fn default() -> Self {
+        Self::WELL_KNOWN
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    /// An IPv6 prefix of length 32, 40, 48, 56, 64 or 96, bits 64 to 71 zero.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected a prefix with its length, got {:?}", s))?;
+        let addr: Ipv6Addr = addr.parse().map_err(|e| format!("{}: {:?}", e, s))?;
+        let len: u8 = len
+            .parse()
+            .ok()
+            .filter(|len| [32, 40, 48, 56, 64, 96].contains(len))
+            .ok_or_else(|| format!("invalid NAT64 prefix length: {:?}", s))?;
+        if addr.octets()[8] != 0 {
+            return Err(format!(
+                "bits 64 to 71 of a NAT64 prefix must be zero: {:?}",
+                s
+            ));
+        }
+        let mut octets = addr.octets();
+        octets[(len / 8) as usize..].fill(0);
+        Ok(Self {
+            addr: octets.into(),
+            len,
+        })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Resolver synthesizing AAAA answers out of another one's A answers when
+/// a name has no AAAA records, or only excluded ones. IPv4-mapped addresses
+/// are always excluded. Requests with CD set are left alone, the client
+/// couldn't validate synthesized records.
+pub struct Dns64 {
+    resolver: Arc<dyn Resolver>,
+    prefix: Prefix,
+    exclude: Vec<Network>,
+}
+
+impl Dns64 {
+    pub fn new(resolver: Arc<dyn Resolver>, prefix: Prefix, exclude: Vec<Network>) -> Self {
+        Self {
+            resolver,
+            prefix,
+            exclude,
+        }
+    }
+
+    fn excluded(&self, ip: Ipv6Addr) -> bool {
+        ip.to_ipv4_mapped().is_some() || self.exclude.iter().any(|net| net.contains(IpAddr::V6(ip)))
+    }
+}
+
+impl Resolver for Dns64 {
+    fn resolve(&self, request: &Message) -> Result<Message> {
+        let reply = self.resolver.resolve(request)?;
+        let [question] = request.questions.as_slice() else {
+            return Ok(reply);
+        };
+        if question.qtype != Type::AAAA
+            || question.class != Class::IN
+            || request.cd == 1
+            || reply.rcode != rcode::NOERROR
+        {
+            return Ok(reply);
+        }
+        let native = reply
+            .answers
+            .iter()
+            .any(|r| matches!(r.rdata, RData::AAAA(ip) if !self.excluded(ip)));
+        if native {
+            return Ok(reply);
+        }
+
+        // the A records of the name the CNAME chain ends at
+        let target = unresolved_cname(&reply.answers, &question.name, Type::AAAA)
+            .unwrap_or_else(|| question.name.clone());
+        let a_request = Message {
+            questions: vec![Question {
+                name: target,
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..request.clone()
+        };
+        let a_reply = self.resolver.resolve(&a_request)?;
+        if a_reply.rcode != rcode::NOERROR || !a_reply.answers.iter().any(|r| r.rtype == Type::A) {
+            return Ok(reply);
+        }
+
+        // no longer than the AAAA RRset is known not to exist (section 5.1.7)
+        let negative_ttl = reply
+            .authorities
+            .iter()
+            .find_map(|r| match &r.rdata {
+                RData::SOA(soa) => Some(r.ttl.min(soa.minimum)),
+                _ => None,
+            })
+            .unwrap_or(u32::MAX);
+        let cnames = reply.answers.into_iter().filter(|r| r.rtype == Type::CNAME);
+        let synthesized = a_reply.answers.into_iter().filter_map(|r| match r.rdata {
+            RData::A(ip) => Some(Record {
+                rtype: Type::AAAA,
+                ttl: r.ttl.min(negative_ttl),
+                rdata: RData::AAAA(self.prefix.embed(ip)),
+                ..r
+            }),
+            RData::CNAME(_) => Some(r),
+            _ => None,
+        });
+        Ok(Message {
+            ad: 0,
+            answers: cnames.chain(synthesized).collect(),
+            authorities: Vec::new(),
+            ..reply
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dns64, Prefix};
+    use crate::{
+        handler::local_soa,
+        proto::{rcode, test::request, Class, Message, Name, Record, Type},
+        rdata::RData,
+        resolver::Resolver,
+    };
+    use anyhow::Result;
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+    };
+
+    #[test]
+    fn test_prefix() {
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        // RFC 6052, section 2.4
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33"),
+        ] {
+            let prefix: Prefix = prefix.parse().unwrap();
+            assert_eq!(
+                expected.parse::<Ipv6Addr>().unwrap(),
+                prefix.embed(ip),
+                "{}",
+                prefix
+            );
+        }
+        assert_eq!(Prefix::WELL_KNOWN, "64:ff9b::/96".parse().unwrap());
+        assert_eq!("64:ff9b::/96", Prefix::default().to_string());
+        assert!("64:ff9b::".parse::<Prefix>().is_err());
+        assert!("64:ff9b::/80".parse::<Prefix>().is_err());
+        assert!("2001:db8:0:0:ff00::/96".parse::<Prefix>().is_err());
+        assert!("192.0.2.0/96".parse::<Prefix>().is_err());
+    }
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: match rdata {
+                RData::A(_) => Type::A,
+                RData::AAAA(_) => Type::AAAA,
+                _ => Type::CNAME,
+            },
+            class: Class::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    /// v4.example has only an A record, www.example is a CNAME to it,
+    /// mapped.example has an IPv4-mapped AAAA and v6.example a real one.
+    struct Upstream;
+
+    impl Resolver for Upstream {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            let question = &request.questions[0];
+            let mut reply = request.reply();
+            let v4 = || record("v4.example", RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+            let records = match question.name.to_string().as_str() {
+                "www.example" => vec![
+                    record("www.example", RData::CNAME(Name::from("v4.example"))),
+                    v4(),
+                ],
+                "v4.example" => vec![v4()],
+                "mapped.example" => vec![
+                    record(
+                        "mapped.example",
+                        RData::AAAA("::ffff:192.0.2.2".parse().unwrap()),
+                    ),
+                    record("mapped.example", RData::A(Ipv4Addr::new(192, 0, 2, 2))),
+                ],
+                "v6.example" => vec![
+                    record("v6.example", RData::AAAA("2001:db8::6".parse().unwrap())),
+                    record("v6.example", RData::A(Ipv4Addr::new(192, 0, 2, 6))),
+                ],
+                _ => {
+                    reply.rcode = rcode::NXDOMAIN;
+                    Vec::new()
+                }
+            };
+            reply.answers = records
+                .into_iter()
+                .filter(|r| r.rtype == question.qtype || r.rtype == Type::CNAME)
+                .collect();
+            if reply.answers.iter().all(|r| r.rtype == Type::CNAME) {
+                reply.authorities.push(local_soa(&question.name));
+            }
+            Ok(reply)
+        }
+    }
+
+    fn addresses(reply: &Message) -> Vec<String> {
+        reply
+            .answers
+            .iter()
+            .map(|r| format!("{} {}", r.name, r.rdata))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dns64 = Dns64::new(
+            Arc::new(Upstream),
+            Prefix::default(),
+            vec!["2001:db8::/32".parse().unwrap()],
+        );
+
+        let reply = dns64.resolve(&request("v4.example", Type::AAAA)).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(vec!["v4.example 64:ff9b::c000:201"], addresses(&reply));
+        // no longer than the local SOA's minimum
+        assert_eq!(60, reply.answers[0].ttl);
+        assert!(reply.authorities.is_empty());
+
+        let reply = dns64.resolve(&request("www.example", Type::AAAA)).unwrap();
+        assert_eq!(
+            vec!["www.example v4.example.", "v4.example 64:ff9b::c000:201"],
+            addresses(&reply)
+        );
+
+        // excluded AAAA records don't count
+        let reply = dns64
+            .resolve(&request("mapped.example", Type::AAAA))
+            .unwrap();
+        assert_eq!(vec!["mapped.example 64:ff9b::c000:202"], addresses(&reply));
+        let reply = dns64.resolve(&request("v6.example", Type::AAAA)).unwrap();
+        assert_eq!(vec!["v6.example 64:ff9b::c000:206"], addresses(&reply));
+        let dns64 = Dns64::new(Arc::new(Upstream), Prefix::default(), Vec::new());
+        let reply = dns64.resolve(&request("v6.example", Type::AAAA)).unwrap();
+        assert_eq!(vec!["v6.example 2001:db8::6"], addresses(&reply));
+
+        // nothing to synthesize from, or the client validates
+        let reply = dns64.resolve(&request("nope.example", Type::AAAA)).unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        let reply = dns64
+            .resolve(&Message {
+                cd: 1,
+                ..request("v4.example", Type::AAAA)
+            })
+            .unwrap();
+        assert!(reply.answers.is_empty());
+    }
+}
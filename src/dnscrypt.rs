@@ -0,0 +1,842 @@
+//! DNSCrypt version 2 with the X25519-XSalsa20Poly1305 construction. A
+//! provider signs short-lived certificates for its resolver keys with its
+//! long-term Ed25519 key and serves them as TXT records of its provider
+//! name. Clients box each query for the resolver key under a fresh key pair
+//! of their own, the resolver boxes the reply back with the same shared key.
+//! Over UDP replies are never larger than the query, over TCP messages carry
+//! a 2-byte length prefix.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+
+use crate::{
+    ed25519,
+    encoder::{length_prefixed, Decoder, Encoder},
+    encoding::{base64url_decode, base64url_encode},
+    proto::{Class, Message, Name, Question, Type},
+    salsa20,
+    wire::{MessageRef, WireMessage},
+    x25519,
+};
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+
+/// Certificate version of the X25519-XSalsa20Poly1305 construction.
+const ES_VERSION: [u8; 2] = [0, 1];
+
+/// Starts every reply.
+const RESOLVER_MAGIC: [u8; 8] = [0x72, 0x36, 0x66, 0x6e, 0x76, 0x57, 0x6a, 0x38];
+
+/// Length of an encoded certificate.
+const CERT_LEN: usize = 124;
+
+/// Client magic, client public key and the client's half of the nonce.
+const QUERY_HEADER_LEN: usize = 8 + 32 + 12;
+
+/// Resolver magic and the full nonce.
+const REPLY_HEADER_LEN: usize = 8 + salsa20::NONCE_LEN;
+
+/// Queries over UDP are padded to at least this many bytes, so replies that
+/// may not be larger than them have room.
+const MIN_QUERY_LEN: usize = 256;
+
+/// Padded messages are a multiple of this many bytes.
+const PADDING_BLOCK: usize = 64;
+
+/// Port DNSCrypt resolvers listen on by default.
+const PORT: u16 = 443;
+
+/// Provider names start with this label pair.
+pub const PROVIDER_PREFIX: &str = "2.dnscrypt-cert.";
+
+/// How long a client uses a certificate before fetching it again, even if
+/// it is still valid, to pick up rotated keys.
+const CERT_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+/// How long a server's certificates are valid.
+const CERT_LIFETIME: u32 = 24 * 60 * 60;
+
+/// How often a server rotates its resolver key.
+const ROTATE_AFTER: u32 = 12 * 60 * 60;
+
+/// TTL of the certificate TXT records a server hands out.
+const CERT_TTL: u32 = 60 * 60;
+
+/// Queries the server answers at once over UDP, connections over TCP.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// How long the server keeps an idle TCP connection open.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Seconds since the Unix epoch, the clock certificates are checked against.
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// A DNSCrypt server as written in a DNS stamp (`sdns://`, protocol 0x01):
+/// its address, the provider's public key and the provider name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Stamp {
+    // informal properties (DNSSEC, no logs, no filter), passed through
+    props: u64,
+    addr: SocketAddr,
+    provider_key: [u8; 32],
+    provider_name: String,
+}
+
+impl Stamp {
+    pub fn new(addr: SocketAddr, provider_key: [u8; 32], provider_name: &str) -> Self {
+        Self {
+            props: 0,
+            addr,
+            provider_key,
+            provider_name: provider_name.trim_end_matches('.').into(),
+        }
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = String;
+
+    /// The port of the address defaults to 443.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid DNSCrypt stamp {:?}", s);
+        let data = s
+            .strip_prefix("sdns://")
+            .and_then(base64url_decode)
+            .ok_or_else(invalid)?;
+        if data.first() != Some(&0x01) || data.len() < 9 {
+            return Err(invalid());
+        }
+        let props = u64::from_le_bytes(data[1..9].try_into().unwrap());
+        let mut dec = Decoder::new(&data[9..]);
+        let mut fields = Vec::new();
+        for _ in 0..3 {
+            let len = dec.read_u8().map_err(|_| invalid())?;
+            fields.push(dec.read_slice(len as usize).map_err(|_| invalid())?);
+        }
+        let addr = std::str::from_utf8(fields[0]).map_err(|_| invalid())?;
+        let addr = addr
+            .parse()
+            .or_else(|_| {
+                let ip = addr.trim_start_matches('[').trim_end_matches(']');
+                ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, PORT))
+            })
+            .map_err(|_| invalid())?;
+        let provider_key = fields[1].try_into().map_err(|_| invalid())?;
+        let provider_name = std::str::from_utf8(fields[2]).map_err(|_| invalid())?;
+        Ok(Self {
+            props,
+            addr,
+            provider_key,
+            provider_name: provider_name.trim_end_matches('.').into(),
+        })
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = self.addr.to_string();
+        let mut data = vec![0x01];
+        data.extend_from_slice(&self.props.to_le_bytes());
+        for field in [
+            addr.as_bytes(),
+            &self.provider_key,
+            self.provider_name.as_bytes(),
+        ] {
+            data.push(field.len() as u8);
+            data.extend_from_slice(field);
+        }
+        write!(f, "sdns://{}", base64url_encode(&data))
+    }
+}
+
+/// A resolver certificate: the resolver's public key, the magic that
+/// starts queries for it and when it is valid, signed by the provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cert {
+    resolver_key: [u8; 32],
+    client_magic: [u8; 8],
+    serial: u32,
+    valid_from: u32,
+    valid_until: u32,
+}
+
+impl Cert {
+    /// The part covered by the signature.
+    fn signed(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(52);
+        data.extend_from_slice(&self.resolver_key);
+        data.extend_from_slice(&self.client_magic);
+        data.extend_from_slice(&self.serial.to_be_bytes());
+        data.extend_from_slice(&self.valid_from.to_be_bytes());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        data
+    }
+
+    /// Encodes the certificate signed with the provider key `seed`.
+    pub fn encode(&self, seed: &[u8; 32]) -> Vec<u8> {
+        let signed = self.signed();
+        let mut data = Vec::with_capacity(CERT_LEN);
+        data.extend_from_slice(CERT_MAGIC);
+        data.extend_from_slice(&ES_VERSION);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&ed25519::sign(seed, &signed));
+        data.extend_from_slice(&signed);
+        data
+    }
+
+    /// Decodes a certificate of the XSalsa20 construction, checking its
+    /// signature against `provider_key`.
+    pub fn decode(data: &[u8], provider_key: &[u8; 32]) -> Result<Self> {
+        if data.len() < CERT_LEN || &data[..4] != CERT_MAGIC {
+            bail!("not a DNSCrypt certificate");
+        }
+        if data[4..6] != ES_VERSION {
+            bail!("unsupported certificate version {:?}", &data[4..6]);
+        }
+        let (signature, signed) = (&data[8..72], &data[72..]);
+        if !ed25519::verify(provider_key, signed, signature) {
+            bail!("bad certificate signature");
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(signed[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            resolver_key: signed[..32].try_into().unwrap(),
+            client_magic: signed[32..40].try_into().unwrap(),
+            serial: u32_at(40),
+            valid_from: u32_at(44),
+            valid_until: u32_at(48),
+        })
+    }
+
+    fn is_valid_at(&self, now: u32) -> bool {
+        self.valid_from <= now && now < self.valid_until
+    }
+}
+
+/// Pads `data` with 0x80 and zeros (ISO/IEC 7816-4) to a multiple of
+/// `PADDING_BLOCK`, at least `min_len` bytes.
+fn pad(data: &[u8], min_len: usize) -> Vec<u8> {
+    let len = (data.len() + 1)
+        .max(min_len)
+        .next_multiple_of(PADDING_BLOCK);
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(data);
+    padded.push(0x80);
+    padded.resize(len, 0);
+    padded
+}
+
+fn unpad(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    let end = data.iter().rposition(|b| *b != 0)?;
+    if data[end] != 0x80 {
+        return None;
+    }
+    data.truncate(end);
+    Some(data)
+}
+
+/// Boxes `query` for the resolver of `cert` under a fresh client key pair.
+/// Returns the packet with the shared key and nonce the reply is boxed
+/// with.
+fn encrypt_query(
+    cert: &Cert,
+    query: &[u8],
+    min_len: usize,
+) -> Result<(Vec<u8>, [u8; 32], [u8; 12])> {
+    let mut rng = rand::thread_rng();
+    let secret: [u8; 32] = rng.gen();
+    let half: [u8; 12] = rng.gen();
+    let key = salsa20::box_key(&secret, &cert.resolver_key)
+        .ok_or_else(|| anyhow!("invalid resolver key"))?;
+    let mut nonce = [0; salsa20::NONCE_LEN];
+    nonce[..12].copy_from_slice(&half);
+    let mut packet = Vec::with_capacity(QUERY_HEADER_LEN + min_len + salsa20::TAG_LEN);
+    packet.extend_from_slice(&cert.client_magic);
+    packet.extend_from_slice(&x25519::public_key(&secret));
+    packet.extend_from_slice(&half);
+    packet.extend_from_slice(&salsa20::seal(&key, &nonce, &pad(query, min_len)));
+    Ok((packet, key, half))
+}
+
+/// Opens a reply boxed with `key`, `None` unless it answers the query with
+/// the client nonce half `half`.
+fn decrypt_reply(packet: &[u8], key: &[u8; 32], half: &[u8; 12]) -> Option<Vec<u8>> {
+    if packet.len() < REPLY_HEADER_LEN || packet[..8] != RESOLVER_MAGIC || packet[8..20] != *half {
+        return None;
+    }
+    let nonce = packet[8..REPLY_HEADER_LEN].try_into().unwrap();
+    unpad(salsa20::open(key, nonce, &packet[REPLY_HEADER_LEN..])?)
+}
+
+/// Boxes `reply` back with the shared key and client nonce half of the
+/// query it answers.
+fn encrypt_reply(reply: &[u8], key: &[u8; 32], half: &[u8; 12]) -> Vec<u8> {
+    let mut nonce = [0; salsa20::NONCE_LEN];
+    nonce[..12].copy_from_slice(half);
+    rand::thread_rng().fill(&mut nonce[12..]);
+    let mut packet = Vec::with_capacity(REPLY_HEADER_LEN + reply.len() + PADDING_BLOCK);
+    packet.extend_from_slice(&RESOLVER_MAGIC);
+    packet.extend_from_slice(&nonce);
+    packet.extend_from_slice(&salsa20::seal(key, &nonce, &pad(reply, 0)));
+    packet
+}
+
+/// The certificates in the TXT records of a reply, which don't fit the
+/// typed TXT data since they are binary.
+fn txt_records(reply: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let reply = MessageRef::decode(reply)?;
+    let mut records = Vec::new();
+    for answer in reply.records().take(reply.ancount as usize) {
+        let answer = answer?;
+        if answer.rtype != Type::TXT {
+            continue;
+        }
+        let mut rdata = Decoder::new(answer.rdata);
+        let mut record = Vec::new();
+        while rdata.offset() < answer.rdata.len() {
+            let len = rdata.read_u8()?;
+            record.extend_from_slice(rdata.read_slice(len as usize)?);
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// A socket of the address family of `addr`, connected to it.
+fn connected_socket(addr: SocketAddr, timeout: Duration) -> Result<UdpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+    socket.connect(addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    Ok(socket)
+}
+
+/// Sends `packet` and waits up to `timeout` for a datagram `accept` takes.
+fn exchange_udp<T>(
+    addr: SocketAddr,
+    packet: &[u8],
+    timeout: Duration,
+    accept: impl Fn(&[u8]) -> Option<T>,
+) -> Result<T> {
+    let socket = connected_socket(addr, timeout)?;
+    socket.send(packet)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!("Timed out waiting for {}", addr);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = socket.recv(&mut buf)?;
+        if let Some(reply) = accept(&buf[..len]) {
+            return Ok(reply);
+        }
+    }
+}
+
+/// Sends `packet` over a fresh TCP connection and returns the reply.
+fn exchange_tcp(addr: SocketAddr, packet: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(&length_prefixed(packet)?)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+/// Client for DNSCrypt resolvers, caching each provider's current
+/// certificate.
+#[derive(Default)]
+pub struct DnsCryptClient {
+    // stamp -> newest valid certificate, when it was fetched
+    certs: Mutex<HashMap<Stamp, (Cert, Instant)>>,
+}
+
+impl DnsCryptClient {
+    /// Sends `request` to the resolver of `stamp`, over UDP and over TCP if
+    /// the reply comes back truncated, waiting up to `timeout` for each.
+    pub fn query(
+        &self,
+        stamp: &Stamp,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<WireMessage> {
+        let cert = self.cert(stamp, timeout)?;
+        let query = request.to_bytes()?;
+        let (packet, key, half) = encrypt_query(&cert, &query, MIN_QUERY_LEN)?;
+        let decode = |packet: &[u8]| {
+            let reply = WireMessage::new(decrypt_reply(packet, &key, &half)?).ok()?;
+            let view = reply.view();
+            let matches = view.id == request.id && view.is_reply_to(&request.questions);
+            matches.then_some(reply)
+        };
+        let reply = exchange_udp(stamp.addr, &packet, timeout, decode)?;
+        if reply.view().tc == 0 {
+            return Ok(reply);
+        }
+        let (packet, key, half) = encrypt_query(&cert, &query, 0)?;
+        let reply = exchange_tcp(stamp.addr, &packet, timeout)?;
+        let reply = decrypt_reply(&reply, &key, &half)
+            .ok_or_else(|| anyhow!("Undecryptable reply from {}", stamp.addr))?;
+        let reply = WireMessage::new(reply)?;
+        let view = reply.view();
+        if view.id != request.id || !view.is_reply_to(&request.questions) {
+            bail!("Mismatched reply from {}", stamp.addr);
+        }
+        Ok(reply)
+    }
+
+    /// The certificate queries to `stamp` are encrypted for, fetched again
+    /// once it expires or is `CERT_REFRESH` old.
+    fn cert(&self, stamp: &Stamp, timeout: Duration) -> Result<Cert> {
+        if let Some((cert, fetched)) = self.certs.lock().unwrap().get(stamp) {
+            if cert.is_valid_at(now()) && fetched.elapsed() < CERT_REFRESH {
+                return Ok(cert.clone());
+            }
+        }
+        let cert = fetch_cert(stamp, timeout)?;
+        self.certs
+            .lock()
+            .unwrap()
+            .insert(stamp.clone(), (cert.clone(), Instant::now()));
+        Ok(cert)
+    }
+}
+
+/// Asks the resolver of `stamp` for the provider's certificates, in the
+/// clear, and picks the valid one with the highest serial.
+fn fetch_cert(stamp: &Stamp, timeout: Duration) -> Result<Cert> {
+    let id = rand::thread_rng().gen();
+    let request = Message {
+        id,
+        questions: vec![Question {
+            name: stamp.provider_name.parse().map_err(anyhow::Error::msg)?,
+            qtype: Type::TXT,
+            class: Class::IN,
+        }],
+        ..Message::default()
+    };
+    let records = exchange_udp(stamp.addr, &request.to_bytes()?, timeout, |reply| {
+        (reply.len() > 2 && reply[..2] == id.to_be_bytes())
+            .then(|| txt_records(reply).ok())
+            .flatten()
+    })?;
+    let now = now();
+    records
+        .iter()
+        .filter_map(|record| Cert::decode(record, &stamp.provider_key).ok())
+        .filter(|cert| cert.is_valid_at(now))
+        .max_by_key(|cert| cert.serial)
+        .ok_or_else(|| anyhow!("No valid certificate for {}", stamp.provider_name))
+}
+
+/// Resolver key pair with its certificate.
+struct ResolverKey {
+    secret: [u8; 32],
+    cert: Cert,
+    // the certificate as signed by the provider
+    record: Vec<u8>,
+}
+
+/// Provider side of a DNSCrypt server: its name and long-term signing key,
+/// and the resolver keys it currently vouches for. A fresh resolver key is
+/// made every `ROTATE_AFTER`, older ones are kept until their certificate
+/// expires so clients can switch over.
+pub struct Provider {
+    name: Name,
+    seed: [u8; 32],
+    keys: Mutex<Vec<ResolverKey>>,
+}
+
+impl Provider {
+    /// Provider `name` signing with the Ed25519 key `seed`.
+    pub fn new(name: Name, seed: [u8; 32]) -> Self {
+        Self {
+            name,
+            seed,
+            keys: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stamp clients reach this provider at `addr` with.
+    pub fn stamp(&self, addr: SocketAddr) -> Stamp {
+        Stamp::new(
+            addr,
+            ed25519::public_key(&self.seed),
+            &self.name.to_string(),
+        )
+    }
+
+    /// Resolver keys valid now, making a new one when due.
+    fn keys(&self) -> std::sync::MutexGuard<'_, Vec<ResolverKey>> {
+        let now = now();
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|key| key.cert.is_valid_at(now));
+        if keys
+            .last()
+            .is_none_or(|key| now - key.cert.valid_from >= ROTATE_AFTER)
+        {
+            let secret: [u8; 32] = rand::thread_rng().gen();
+            let resolver_key = x25519::public_key(&secret);
+            let cert = Cert {
+                resolver_key,
+                client_magic: resolver_key[..8].try_into().unwrap(),
+                serial: now,
+                valid_from: now,
+                valid_until: now + CERT_LIFETIME,
+            };
+            let record = cert.encode(&self.seed);
+            keys.push(ResolverKey {
+                secret,
+                cert,
+                record,
+            });
+        }
+        keys
+    }
+
+    /// Opens an encrypted query, returning it with the key and client nonce
+    /// half to box the reply with. `None` if it isn't for one of our keys
+    /// or doesn't decrypt.
+    fn decrypt_query(&self, packet: &[u8]) -> Option<(Vec<u8>, [u8; 32], [u8; 12])> {
+        if packet.len() < QUERY_HEADER_LEN + salsa20::TAG_LEN {
+            return None;
+        }
+        let secret = self
+            .keys()
+            .iter()
+            .find(|key| packet[..8] == key.cert.client_magic)?
+            .secret;
+        let client_key = packet[8..40].try_into().unwrap();
+        let half: [u8; 12] = packet[40..52].try_into().unwrap();
+        let key = salsa20::box_key(&secret, client_key)?;
+        let mut nonce = [0; salsa20::NONCE_LEN];
+        nonce[..12].copy_from_slice(&half);
+        let query = unpad(salsa20::open(&key, &nonce, &packet[QUERY_HEADER_LEN..])?)?;
+        Some((query, key, half))
+    }
+
+    /// Reply carrying the certificates if `packet` asks for them in the
+    /// clear.
+    fn cert_reply(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let request = Message::from_bytes(packet).ok()?;
+        let [question] = &request.questions[..] else {
+            return None;
+        };
+        if request.qr == 1 || question.qtype != Type::TXT || question.name != self.name {
+            return None;
+        }
+        let records: Vec<Vec<u8>> = self.keys().iter().map(|key| key.record.clone()).collect();
+        let mut buf = Message {
+            aa: 1,
+            opt: None,
+            ..request.reply()
+        }
+        .to_bytes()
+        .ok()?;
+        let end = buf.len();
+        let mut enc = Encoder::new(&mut buf);
+        enc.patch_count(6, records.len()).ok()?;
+        enc.set_offset(end);
+        for record in records {
+            // pointer to the question name
+            enc.write_slice(&[0xC0, 12]);
+            enc.write_u16(Type::TXT.into());
+            enc.write_u16(Class::IN.into());
+            enc.write_u32(CERT_TTL);
+            let len = u8::try_from(record.len()).ok()?;
+            enc.with_length_prefix(|enc| {
+                enc.write_u8(len);
+                enc.write_slice(&record);
+                Ok(())
+            })
+            .ok()?;
+        }
+        Some(buf)
+    }
+
+    /// Answers a packet from `source`: certificate requests in the clear,
+    /// encrypted queries through `answer`. Over UDP the reply may be at
+    /// most `max_len` bytes, larger ones are sent truncated.
+    fn respond(
+        &self,
+        source: SocketAddr,
+        packet: &[u8],
+        max_len: Option<usize>,
+        answer: &impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let Some((query, key, half)) = self.decrypt_query(packet) else {
+            return self.cert_reply(packet);
+        };
+        let reply = answer(source, &query)?;
+        let sealed = encrypt_reply(&reply, &key, &half);
+        match max_len {
+            Some(max_len) if sealed.len() > max_len => {
+                let sealed = encrypt_reply(&truncated(&reply)?, &key, &half);
+                (sealed.len() <= max_len).then_some(sealed)
+            }
+            _ => Some(sealed),
+        }
+    }
+}
+
+/// `reply` with TC set and its record sections dropped.
+fn truncated(reply: &[u8]) -> Option<Vec<u8>> {
+    let reply = Message::from_bytes(reply).ok()?;
+    Message {
+        tc: 1,
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        ..reply
+    }
+    .to_bytes()
+    .ok()
+}
+
+/// Serves DNSCrypt on `socket` and `listener` as `provider`, passing each
+/// decrypted query with its source to `answer` and encrypting the reply it
+/// returns, if any.
+pub fn spawn(
+    socket: UdpSocket,
+    listener: TcpListener,
+    provider: Provider,
+    answer: impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let provider = Arc::new(provider);
+    let answer = Arc::new(answer);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    {
+        let (provider, answer) = (provider.clone(), answer.clone());
+        thread::Builder::new()
+            .name("dnscrypt".into())
+            .spawn(move || {
+                let mut buf = vec![0; u16::MAX as usize];
+                loop {
+                    let Ok((len, source)) = socket.recv_from(&mut buf) else {
+                        continue;
+                    };
+                    if in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+                        in_flight.fetch_sub(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let packet = buf[..len].to_vec();
+                    let (socket, provider, answer, in_flight) = (
+                        socket.clone(),
+                        provider.clone(),
+                        answer.clone(),
+                        in_flight.clone(),
+                    );
+                    thread::spawn(move || {
+                        if let Some(reply) = provider.respond(source, &packet, Some(len), &*answer)
+                        {
+                            let _ = socket.send_to(&reply, source);
+                        }
+                        in_flight.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+            })?;
+    }
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("dnscrypt-tcp".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                if connections.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                }
+                let (provider, answer, connections) =
+                    (provider.clone(), answer.clone(), connections.clone());
+                thread::spawn(move || {
+                    let _ = serve_conn(stream, &provider, &*answer);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        })?;
+    Ok(())
+}
+
+/// Answers queries on a single TCP connection until the client closes it
+/// or leaves it idle for `IDLE_TIMEOUT`.
+fn serve_conn(
+    mut stream: TcpStream,
+    provider: &Provider,
+    answer: &impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>>,
+) -> Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    loop {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut packet = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut packet)?;
+        let Some(reply) = provider.respond(source, &packet, None, answer) else {
+            continue;
+        };
+        stream.write_all(&length_prefixed(&reply)?)?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pad, spawn, unpad, Cert, DnsCryptClient, Provider, Stamp};
+    use crate::{
+        ed25519,
+        encoding::base64url_encode,
+        proto::{Class, Message, Name, Question, Record, Type},
+        rdata::RData,
+    };
+    use std::{
+        net::{Ipv4Addr, TcpListener, UdpSocket},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_stamp() {
+        let stamp = Stamp::new(
+            "192.0.2.53:8443".parse().unwrap(),
+            ed25519::public_key(&[7; 32]),
+            "2.dnscrypt-cert.example.com.",
+        );
+        let s = stamp.to_string();
+        assert!(s.starts_with("sdns://AQAAAAAAAAAA"));
+        assert_eq!(Ok(stamp), s.parse());
+
+        // the port defaults to 443
+        let mut data = vec![0x01, 7, 0, 0, 0, 0, 0, 0, 0, 9];
+        data.extend_from_slice(b"127.0.0.1");
+        data.push(32);
+        data.extend_from_slice(&[3; 32]);
+        data.push(25);
+        data.extend_from_slice(b"2.dnscrypt-cert.localhost");
+        let stamp: Stamp = format!("sdns://{}", base64url_encode(&data))
+            .parse()
+            .unwrap();
+        assert_eq!("127.0.0.1:443", stamp.addr.to_string());
+        assert_eq!("2.dnscrypt-cert.localhost", stamp.provider_name);
+        assert_eq!(7, stamp.props);
+        assert!("sdns://AgcAAAAAAAAA".parse::<Stamp>().is_err());
+        assert!("https://dns.example".parse::<Stamp>().is_err());
+    }
+
+    #[test]
+    fn test_cert() {
+        let seed = [1; 32];
+        let cert = Cert {
+            resolver_key: [2; 32],
+            client_magic: [2; 8],
+            serial: 1,
+            valid_from: 1_700_000_000,
+            valid_until: 1_700_086_400,
+        };
+        let data = cert.encode(&seed);
+        assert_eq!(124, data.len());
+        assert_eq!(
+            Ok(&cert),
+            Cert::decode(&data, &ed25519::public_key(&seed))
+                .as_ref()
+                .map_err(|_| ())
+        );
+        assert!(Cert::decode(&data, &ed25519::public_key(&[3; 32])).is_err());
+        assert!(cert.is_valid_at(1_700_000_000));
+        assert!(!cert.is_valid_at(1_700_086_400));
+    }
+
+    #[test]
+    fn test_padding() {
+        let padded = pad(b"query", 256);
+        assert_eq!(256, padded.len());
+        assert_eq!(0x80, padded[5]);
+        assert_eq!(64, pad(&[1; 63], 0).len());
+        assert_eq!(128, pad(&[1; 64], 0).len());
+        assert_eq!(Some(b"query".to_vec()), unpad(padded));
+        assert_eq!(None, unpad(vec![1, 0, 0]));
+        assert_eq!(None, unpad(vec![0; 4]));
+    }
+
+    #[test]
+    fn test_exchange() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind(socket.local_addr().unwrap()).unwrap();
+        let provider = Provider::new(Name::from("2.dnscrypt-cert.dns.example"), [5; 32]);
+        let stamp = provider.stamp(socket.local_addr().unwrap());
+        // answers TXT queries with more than fits in a UDP query
+        spawn(socket, listener, provider, |_, query| {
+            let query = Message::from_bytes(query).unwrap();
+            let mut reply = query.reply();
+            let count = if query.questions[0].qtype == Type::TXT {
+                20
+            } else {
+                1
+            };
+            for i in 0..count {
+                reply.answers.push(Record {
+                    name: query.questions[0].name.clone(),
+                    rtype: Type::A,
+                    class: Class::IN,
+                    ttl: 60,
+                    rdata: RData::A(Ipv4Addr::new(192, 0, 2, i)),
+                });
+            }
+            reply.to_bytes().ok()
+        })
+        .unwrap();
+
+        let client = DnsCryptClient::default();
+        for (id, qtype, answers) in [(1, Type::A, 1), (2, Type::TXT, 20)] {
+            let request = Message {
+                id,
+                rd: 1,
+                questions: vec![Question {
+                    name: Name::from("example.com"),
+                    qtype,
+                    class: Class::IN,
+                }],
+                ..Message::default()
+            };
+            let reply = client
+                .query(&stamp, &request, Duration::from_secs(5))
+                .unwrap()
+                .to_message()
+                .unwrap();
+            assert_eq!(id, reply.id);
+            assert_eq!(answers, reply.answers.len());
+        }
+
+        // a stamp with another provider key doesn't verify the certificate
+        let other = Stamp::new(stamp.addr, [9; 32], &stamp.provider_name);
+        let request = Message::default();
+        assert!(client
+            .query(&other, &request, Duration::from_secs(5))
+            .is_err());
+    }
+}
@@ -0,0 +1,918 @@
+//! DNSSEC zone signing (RFC 4034, RFC 4035) with Ed25519 keys (RFC 8080),
+//! authenticated denial with NSEC or NSEC3 chains (RFC 5155), and
+//! verification of signatures by the algorithms validators must support
+//! (RFC 8624, section 3.1).
+
+use std::{collections::BTreeMap, fs, iter, path::Path};
+
+use anyhow::Result;
+use rand::Rng;
+
+use crate::{
+    digest::{sha1, sha256},
+    ecdsa, ed25519,
+    encoder::{Encoder, Error},
+    encoding::{base32hex_encode, base64_decode, base64_encode},
+    proto::{Class, Name, Record, Type},
+    rdata::{Dnskey, Ds, Nsec, Nsec3, Nsec3param, RData, Rrsig, Soa, Srv},
+    rsa,
+};
+
+/// DNSSEC algorithm numbers (RFC 8624, section 3.1).
+pub const RSASHA1: u8 = 5;
+pub const RSASHA1_NSEC3_SHA1: u8 = 7;
+pub const RSASHA256: u8 = 8;
+pub const RSASHA512: u8 = 10;
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ED25519: u8 = 15;
+
+/// DS digest types (RFC 8624, section 3.3).
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+
+/// DNSKEY flag of keys RRSIGs may be made with (RFC 4034, section 2.1.1).
+pub const ZONE_KEY: u16 = 0x0100;
+
+/// DNSKEY flag of keys revoked by their owner (RFC 5011, section 7).
+pub const REVOKE: u16 = 0x0080;
+
+/// DNSKEY flag of keys meant to be trust anchors (RFC 4034, section 2.1.1).
+pub const SEP: u16 = 0x0001;
+
+/// Zone key and secure entry point: one key signs the whole zone, DNSKEY
+/// RRset included (RFC 4034, section 2.1.1).
+const KEY_FLAGS: u16 = 257;
+
+/// NSEC3 hash algorithm, SHA-1 (RFC 5155, section 11).
+pub const NSEC3_SHA1: u8 = 1;
+
+/// How a signed zone proves names and types don't exist.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum DenialChain {
+    // NSEC records linking the names in canonical order (RFC 4034)
+    #[default]
+    Nsec,
+    // NSEC3 records linking hashes of the names (RFC 5155). RFC 9276 has
+    // no salt and no extra iterations as the defaults
+    Nsec3 {
+        salt: Vec<u8>,
+        iterations: u16,
+    },
+}
+
+/// How long signatures stay valid after the zone is signed. The zone is
+/// signed again on every load.
+pub const SIGNATURE_VALIDITY: u32 = 30 * 86400;
+
+/// How far inception is backdated, for validators with slow clocks.
+const INCEPTION_SKEW: u32 = 3600;
+
+/// Private key signing a zone.
+#[derive(Clone, PartialEq)]
+pub struct SigningKey {
+    seed: [u8; 32],
+    public: [u8; 32],
+}
+
+impl SigningKey {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            public: ed25519::public_key(&seed),
+            seed,
+        }
+    }
+
+    pub fn generate() -> Self {
+        Self::from_seed(rand::thread_rng().gen())
+    }
+
+    /// Reads a key in the BIND private key format, generating one and
+    /// writing it there first if the file doesn't exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            let key = Self::generate();
+            fs::write(path, key.to_file())?;
+            tracing::info!(
+                key_tag = %key.key_tag(), path = %path.display(),
+                "Generated DNSSEC key"
+            );
+            return Ok(key);
+        }
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(anyhow::Error::msg)
+    }
+
+    /// Parses the BIND private key format: `Algorithm: 15` and the seed in
+    /// `PrivateKey:`, other fields are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let field = |name: &str| {
+            text.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        };
+        let algorithm = field("Algorithm")
+            .and_then(|a| a.split_whitespace().next())
+            .ok_or("missing Algorithm")?;
+        if algorithm != ED25519.to_string() {
+            return Err(format!("unsupported algorithm {}", algorithm));
+        }
+        let seed = field("PrivateKey")
+            .and_then(base64_decode)
+            .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+            .ok_or("invalid PrivateKey")?;
+        Ok(Self::from_seed(seed))
+    }
+
+    pub fn to_file(&self) -> String {
+        format!(
+            "Private-key-format: v1.3\nAlgorithm: {} (ED25519)\nPrivateKey: {}\n",
+            ED25519,
+            base64_encode(&self.seed)
+        )
+    }
+
+    pub fn dnskey(&self) -> Dnskey {
+        Dnskey {
+            flags: KEY_FLAGS,
+            protocol: 3,
+            algorithm: ED25519,
+            public_key: self.public.to_vec(),
+        }
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        key_tag(&self.dnskey())
+    }
+
+    /// RRSIG of `rrset`, a set of records with the same owner and type,
+    /// made by the zone `signer`. Fails if a record can't be encoded.
+    pub fn sign(
+        &self,
+        signer: &Name,
+        rrset: &[Record],
+        inception: u32,
+        expiration: u32,
+    ) -> Result<Record, Error> {
+        let first = &rrset[0];
+        let mut rrsig = Rrsig {
+            type_covered: first.rtype,
+            algorithm: ED25519,
+            labels: label_count(&first.name),
+            original_ttl: rrset.iter().map(|r| r.ttl).min().unwrap_or(first.ttl),
+            expiration,
+            inception,
+            key_tag: self.key_tag(),
+            signer_name: signer.clone(),
+            signature: Vec::new(),
+        };
+        rrsig.signature = ed25519::sign(&self.seed, &signed_data(&rrsig, rrset)?).to_vec();
+        Ok(Record {
+            name: first.name.clone(),
+            rtype: Type::RRSIG,
+            class: first.class,
+            ttl: rrsig.original_ttl,
+            rdata: RData::RRSIG(rrsig),
+        })
+    }
+}
+
+/// Key tag of a DNSKEY, the checksum RRSIGs and DS records refer to it by
+/// (RFC 4034, appendix B).
+pub fn key_tag(key: &Dnskey) -> u16 {
+    let mut rdata = Vec::new();
+    key.encode(&mut Encoder::new(&mut rdata));
+    let mut acc: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        acc += match i % 2 {
+            0 => (*b as u32) << 8,
+            _ => *b as u32,
+        };
+    }
+    acc += (acc >> 16) & 0xFFFF;
+    (acc & 0xFFFF) as u16
+}
+
+/// DS record for `key` at `owner` with the given digest type, None if the
+/// type is unsupported or the owner too long to encode (RFC 4034, section
+/// 5.1.4).
+pub fn ds(owner: &Name, key: &Dnskey, digest_type: u8) -> Option<Ds> {
+    let mut data = Vec::new();
+    let mut enc = Encoder::uncompressed(&mut data);
+    owner.to_lowercase().encode(&mut enc).ok()?;
+    key.encode(&mut enc);
+    let digest = match digest_type {
+        DIGEST_SHA1 => sha1(&data).to_vec(),
+        DIGEST_SHA256 => sha256(&data).to_vec(),
+        _ => return None,
+    };
+    Some(Ds {
+        key_tag: key_tag(key),
+        algorithm: key.algorithm,
+        digest_type,
+        digest,
+    })
+}
+
+pub fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA1 | RSASHA1_NSEC3_SHA1 | RSASHA256 | RSASHA512 | ECDSAP256SHA256 | ED25519
+    )
+}
+
+pub fn supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256)
+}
+
+/// Whether `rrsig` is a signature of `rrset` by `key`. Only the
+/// cryptography is checked, not the validity period.
+pub fn verify(rrsig: &Rrsig, key: &Dnskey, rrset: &[Record]) -> bool {
+    if key.algorithm != rrsig.algorithm || rrset.is_empty() {
+        return false;
+    }
+    let Ok(data) = signed_data(rrsig, rrset) else {
+        return false;
+    };
+    let (public, signature) = (&key.public_key, &rrsig.signature);
+    match rrsig.algorithm {
+        RSASHA1 | RSASHA1_NSEC3_SHA1 => rsa::verify(rsa::Hash::Sha1, public, &data, signature),
+        RSASHA256 => rsa::verify(rsa::Hash::Sha256, public, &data, signature),
+        RSASHA512 => rsa::verify(rsa::Hash::Sha512, public, &data, signature),
+        ECDSAP256SHA256 => ecdsa::verify(&ecdsa::P256, public, &data, signature),
+        ED25519 => match <[u8; 32]>::try_from(public.as_slice()) {
+            Ok(public) => ed25519::verify(&public, &data, signature),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Labels of an owner name, not counting the root or a leading wildcard
+/// (RFC 4034, section 3.1.3).
+pub fn label_count(name: &Name) -> u8 {
+    let labels = name.labels();
+    let wildcard = labels.first().is_some_and(|l| l == b"*");
+    (labels.len() - wildcard as usize) as u8
+}
+
+/// NSEC3 hash of `name`: SHA-1 over its lowercased wire format and the
+/// salt, repeated `iterations` more times (RFC 5155, section 5). Fails for
+/// names too long to encode.
+pub fn nsec3_hash(name: &Name, salt: &[u8], iterations: u16) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    name.to_lowercase()
+        .encode(&mut Encoder::uncompressed(&mut data))?;
+    let mut hash = sha1(&[data.as_slice(), salt].concat());
+    for _ in 0..iterations {
+        hash = sha1(&[hash.as_slice(), salt].concat());
+    }
+    Ok(hash.to_vec())
+}
+
+/// What an RRSIG signs: its RDATA up to the signature, then the records of
+/// the RRset in canonical form and order, with the original TTL (RFC 4034,
+/// section 3.1.8.1). Records expanded from a wildcard are signed under the
+/// wildcard's name (RFC 4035, section 5.3.2).
+pub fn signed_data(rrsig: &Rrsig, rrset: &[Record]) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    let unsigned = Rrsig {
+        signature: Vec::new(),
+        signer_name: rrsig.signer_name.to_lowercase(),
+        ..rrsig.clone()
+    };
+    unsigned.encode(&mut Encoder::uncompressed(&mut data))?;
+
+    let mut rdatas: Vec<Vec<u8>> = rrset
+        .iter()
+        .map(|r| canonical_rdata(&r.rdata))
+        .collect::<Result<_, _>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    let mut owner = rrset[0].name.to_lowercase();
+    if label_count(&owner) > rrsig.labels {
+        let labels = owner.labels();
+        let suffix = &labels[labels.len() - rrsig.labels as usize..];
+        owner = Name::from_labels(iter::once(&b"*"[..]).chain(suffix.iter().map(Vec::as_slice)));
+    }
+    for rdata in rdatas {
+        let mut record = Vec::new();
+        let mut enc = Encoder::uncompressed(&mut record);
+        owner.encode(&mut enc)?;
+        rrsig.type_covered.encode(&mut enc);
+        rrset[0].class.encode(&mut enc);
+        enc.write_u32(rrsig.original_ttl);
+        enc.write_length_prefixed(&rdata)?;
+        data.extend_from_slice(&record);
+    }
+    Ok(data)
+}
+
+/// RDATA in canonical form: uncompressed, with the embedded names of the
+/// types listed in RFC 4034, section 6.2 lowercased.
+fn canonical_rdata(rdata: &RData) -> Result<Vec<u8>, Error> {
+    let rdata = match rdata {
+        RData::NS(name) => RData::NS(name.to_lowercase()),
+        RData::CNAME(name) => RData::CNAME(name.to_lowercase()),
+        RData::PTR(name) => RData::PTR(name.to_lowercase()),
+        RData::SOA(soa) => RData::SOA(Soa {
+            mname: soa.mname.to_lowercase(),
+            rname: soa.rname.to_lowercase(),
+            ..soa.clone()
+        }),
+        RData::MX(mx) => {
+            let mut mx = mx.clone();
+            mx.exchange = mx.exchange.to_lowercase();
+            RData::MX(mx)
+        }
+        RData::SRV(srv) => RData::SRV(Srv {
+            target: srv.target.to_lowercase(),
+            ..srv.clone()
+        }),
+        RData::RRSIG(sig) => RData::RRSIG(Rrsig {
+            signer_name: sig.signer_name.to_lowercase(),
+            ..sig.clone()
+        }),
+        other => other.clone(),
+    };
+    let mut buf = Vec::new();
+    rdata.encode(&mut Encoder::uncompressed(&mut buf))?;
+    Ok(buf)
+}
+
+/// Signs the records of the zone at `origin`: adds the DNSKEY RRset at the
+/// apex, with the TTL of the SOA, the `chain` of denial records, and an
+/// RRSIG for every RRset the zone is authoritative for. DNSSEC records
+/// already in `records` are replaced. Fails if an RRset can't be encoded.
+pub fn sign_zone(
+    origin: &Name,
+    records: Vec<Record>,
+    key: &SigningKey,
+    chain: &DenialChain,
+    now: u32,
+) -> Result<Vec<Record>, Error> {
+    let inception = now.wrapping_sub(INCEPTION_SKEW);
+    let expiration = now.wrapping_add(SIGNATURE_VALIDITY);
+
+    let mut records: Vec<Record> = records
+        .into_iter()
+        .filter(|r| {
+            !matches!(
+                r.rtype,
+                Type::RRSIG | Type::DNSKEY | Type::NSEC | Type::NSEC3 | Type::NSEC3PARAM
+            )
+        })
+        .collect();
+    let soa = records.iter().find(|r| r.rtype == Type::SOA);
+    let ttl = soa.map_or(3600, |soa| soa.ttl);
+    // denial records live as long as negative answers (RFC 9077)
+    let negative_ttl = match soa.map(|soa| &soa.rdata) {
+        Some(RData::SOA(rdata)) => ttl.min(rdata.minimum),
+        _ => ttl,
+    };
+    records.push(Record {
+        name: origin.clone(),
+        rtype: Type::DNSKEY,
+        class: Class::IN,
+        ttl,
+        rdata: RData::DNSKEY(key.dnskey()),
+    });
+    let cuts = delegations(origin, &records);
+    let denial = match chain {
+        DenialChain::Nsec => nsec_chain(&records, &cuts, negative_ttl),
+        DenialChain::Nsec3 { salt, iterations } => {
+            records.push(Record {
+                name: origin.clone(),
+                rtype: Type::NSEC3PARAM,
+                class: Class::IN,
+                ttl,
+                rdata: RData::NSEC3PARAM(Nsec3param {
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: 0,
+                    iterations: *iterations,
+                    salt: salt.clone(),
+                }),
+            });
+            nsec3_chain(origin, &records, &cuts, salt, *iterations, negative_ttl)?
+        }
+    };
+    records.extend(denial);
+
+    // (owner, type) -> RRset, sorted so the output doesn't depend on input
+    // order
+    let mut rrsets: BTreeMap<(Name, u16), Vec<Record>> = BTreeMap::new();
+    for record in records.iter() {
+        rrsets
+            .entry((record.name.clone(), record.rtype.into()))
+            .or_default()
+            .push(record.clone());
+    }
+    for rrset in rrsets.values() {
+        if authoritative(&cuts, &rrset[0]) {
+            records.push(key.sign(origin, rrset, inception, expiration)?);
+        }
+    }
+    Ok(records)
+}
+
+/// Names below the apex with NS records, where the zone delegates to a
+/// child zone.
+pub fn delegations<'a>(origin: &Name, records: impl IntoIterator<Item = &'a Record>) -> Vec<Name> {
+    let mut cuts: Vec<Name> = Vec::new();
+    for record in records {
+        if record.rtype == Type::NS && record.name != *origin && !cuts.contains(&record.name) {
+            cuts.push(record.name.to_lowercase());
+        }
+    }
+    cuts
+}
+
+/// Whether `name` is below one of the delegations, glue or occluded data
+/// of a child zone.
+fn occluded(cuts: &[Name], name: &Name) -> bool {
+    cuts.iter()
+        .any(|cut| name.is_subdomain_of(cut) && name != cut)
+}
+
+/// Whether the zone is authoritative for `record`: not glue below a
+/// delegation, and at a delegation only the DS and NSEC are (RFC 4035,
+/// section 2.2).
+fn authoritative(cuts: &[Name], record: &Record) -> bool {
+    let at_cut = cuts.contains(&record.name);
+    !occluded(cuts, &record.name) && (!at_cut || matches!(record.rtype, Type::DS | Type::NSEC))
+}
+
+/// Lowercased owner names of the zone's records, glue left out, in
+/// canonical order, each with the types it has.
+fn owners(records: &[Record], cuts: &[Name]) -> Vec<(Name, Vec<Type>)> {
+    let mut owners: BTreeMap<Name, Vec<Type>> = BTreeMap::new();
+    for record in records.iter().filter(|r| !occluded(cuts, &r.name)) {
+        let types = owners.entry(record.name.to_lowercase()).or_default();
+        if !types.contains(&record.rtype) {
+            types.push(record.rtype);
+        }
+    }
+    owners.into_iter().collect()
+}
+
+/// Types in the order of their numbers, as bitmaps list them.
+fn sorted(mut types: Vec<Type>) -> Vec<Type> {
+    types.sort_by_key(|t| u16::from(*t));
+    types.dedup();
+    types
+}
+
+/// NSEC records linking every name of the zone to the next one in canonical
+/// order, the last back to the apex (RFC 4035, section 2.3).
+fn nsec_chain(records: &[Record], cuts: &[Name], ttl: u32) -> Vec<Record> {
+    let owners = owners(records, cuts);
+    owners
+        .iter()
+        .enumerate()
+        .map(|(i, (name, types))| {
+            let (next, _) = &owners[(i + 1) % owners.len()];
+            let mut types = types.clone();
+            types.extend([Type::NSEC, Type::RRSIG]);
+            Record {
+                name: name.clone(),
+                rtype: Type::NSEC,
+                class: Class::IN,
+                ttl,
+                rdata: RData::NSEC(Nsec {
+                    next_domain_name: next.clone(),
+                    types: sorted(types),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// NSEC3 records linking the hashes of every name of the zone, empty
+/// non-terminals included, in hash order (RFC 5155, section 7.1).
+fn nsec3_chain(
+    origin: &Name,
+    records: &[Record],
+    cuts: &[Name],
+    salt: &[u8],
+    iterations: u16,
+    ttl: u32,
+) -> Result<Vec<Record>, Error> {
+    let mut owners = owners(records, cuts);
+    // the names between an owner and the apex exist too
+    let apex_labels = label_count(origin) as usize;
+    for (name, _) in owners.clone() {
+        let labels = name.labels();
+        for i in 1..labels.len().saturating_sub(apex_labels) {
+            let ancestor = Name::from_labels(&labels[i..]);
+            if !owners.iter().any(|(owner, _)| *owner == ancestor) {
+                owners.push((ancestor, Vec::new()));
+            }
+        }
+    }
+
+    let mut hashed: Vec<(Vec<u8>, Vec<Type>)> = owners
+        .into_iter()
+        .map(|(name, mut types)| {
+            // RRSIG if anything there is signed, a delegation without DS
+            // has nothing
+            let unsigned_cut = cuts.contains(&name) && !types.contains(&Type::DS);
+            if !types.is_empty() && !unsigned_cut {
+                types.push(Type::RRSIG);
+            }
+            Ok((nsec3_hash(&name, salt, iterations)?, sorted(types)))
+        })
+        .collect::<Result<_, Error>>()?;
+    hashed.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(hashed
+        .iter()
+        .enumerate()
+        .map(|(i, (hash, types))| {
+            let (next, _) = &hashed[(i + 1) % hashed.len()];
+            let label = base32hex_encode(hash).to_ascii_lowercase();
+            Record {
+                name: Name::from_labels(iter::once(label.as_bytes()).chain(origin.iter_labels())),
+                rtype: Type::NSEC3,
+                class: Class::IN,
+                ttl,
+                rdata: RData::NSEC3(Nsec3 {
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: 0,
+                    iterations,
+                    salt: salt.to_vec(),
+                    next_hashed_owner: next.clone(),
+                    types: types.clone(),
+                }),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ds, key_tag, label_count, nsec3_hash, sign_zone, signed_data, verify, DenialChain,
+        SigningKey,
+    };
+    use crate::{
+        ed25519,
+        encoding::{base32hex_encode, base64_decode, base64_encode, hex_encode},
+        proto::{Class, Name, Record, Type},
+        rdata::{Dnskey, Mx, Nsec, Nsec3, RData},
+        zone::Zone,
+    };
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 3600
+@    SOA ns1 hostmaster 1 7200 3600 1209600 300
+@    NS  ns1
+ns1  A   192.0.2.53
+www  A   192.0.2.1
+www  A   192.0.2.2
+*.wild TXT \"any\"
+";
+
+    fn key() -> SigningKey {
+        SigningKey::from_seed([42; 32])
+    }
+
+    #[test]
+    fn test_key_tag() {
+        // RFC 8080, section 6.1
+        let dnskey = Dnskey {
+            flags: 257,
+            protocol: 3,
+            algorithm: 15,
+            public_key: crate::encoding::base64_decode(
+                "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=",
+            )
+            .unwrap(),
+        };
+        assert_eq!(3613, key_tag(&dnskey));
+    }
+
+    #[test]
+    fn test_rfc8080_example() {
+        // RFC 8080, section 6.1
+        let key = SigningKey::parse(
+            "Private-key-format: v1.2\nAlgorithm: 15 (ED25519)\n\
+             PrivateKey: ODIyNjAzODQ2MjgwODAxMjI2NDUxOTAyMDQxNDIyNjI=\n",
+        )
+        .unwrap();
+        assert_eq!(
+            "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=",
+            base64_encode(&key.dnskey().public_key)
+        );
+        let mx = Record {
+            name: Name::from("example.com"),
+            rtype: Type::MX,
+            class: Class::IN,
+            ttl: 3600,
+            rdata: RData::MX(Mx {
+                preference: 10,
+                exchange: Name::from("mail.example.com"),
+            }),
+        };
+        let sig = key
+            .sign(&mx.name, std::slice::from_ref(&mx), 1438207200, 1440021600)
+            .unwrap();
+        let RData::RRSIG(rrsig) = sig.rdata else {
+            unreachable!()
+        };
+        assert_eq!((2, 3613), (rrsig.labels, rrsig.key_tag));
+        assert_eq!(
+            "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==",
+            base64_encode(&rrsig.signature)
+        );
+    }
+
+    #[test]
+    fn test_label_count() {
+        assert_eq!(3, label_count(&Name::from("www.example.com.")));
+        assert_eq!(2, label_count(&Name::from("*.example.com")));
+        assert_eq!(0, label_count(&Name::root()));
+    }
+
+    #[test]
+    fn test_ds() {
+        // RFC 8080, section 6.1
+        let key = SigningKey::parse(
+            "Algorithm: 15\nPrivateKey: ODIyNjAzODQ2MjgwODAxMjI2NDUxOTAyMDQxNDIyNjI=",
+        )
+        .unwrap();
+        let sha256 = ds(&Name::from("example.com"), &key.dnskey(), 2).unwrap();
+        assert_eq!(
+            (3613, 15, 2),
+            (sha256.key_tag, sha256.algorithm, sha256.digest_type)
+        );
+        assert_eq!(
+            "3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b",
+            hex_encode(&sha256.digest).to_ascii_lowercase()
+        );
+
+        // RFC 4034, section 5.4
+        let dnskey = Dnskey {
+            flags: 256,
+            protocol: 3,
+            algorithm: 5,
+            public_key: base64_decode(
+                "AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZ\
+                 DRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9Xzc\
+                 nOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+            )
+            .unwrap(),
+        };
+        let sha1 = ds(&Name::from("DSKEY.example.com."), &dnskey, 1).unwrap();
+        assert_eq!(60485, sha1.key_tag);
+        assert_eq!(
+            "2bb183af5f22588179a53b0a98631fad1a292118",
+            hex_encode(&sha1.digest).to_ascii_lowercase()
+        );
+        assert_eq!(None, ds(&Name::from("example.com"), &dnskey, 4));
+    }
+
+    #[test]
+    fn test_verify() {
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+        let key = key();
+        let records = sign_zone(
+            &zone.origin,
+            zone.records().cloned().collect(),
+            &key,
+            &DenialChain::Nsec,
+            0,
+        )
+        .unwrap();
+        let of = |name: &str, rtype| {
+            records
+                .iter()
+                .filter(|r| r.name == Name::from(name) && r.rtype == rtype)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let rrsig = |name, rtype| {
+            of(name, Type::RRSIG)
+                .into_iter()
+                .find_map(|r| match r.rdata {
+                    RData::RRSIG(sig) if sig.type_covered == rtype => Some(sig),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let www = of("www.example.com", Type::A);
+        let sig = rrsig("www.example.com", Type::A);
+        assert!(verify(&sig, &key.dnskey(), &www));
+        // names are compared in lowercase
+        let mut upper = www.clone();
+        upper[0].name = Name::from("WWW.Example.COM");
+        assert!(verify(&sig, &key.dnskey(), &upper));
+        assert!(!verify(&sig, &key.dnskey(), &www[..1]));
+        assert!(!verify(
+            &sig,
+            &SigningKey::from_seed([1; 32]).dnskey(),
+            &www
+        ));
+        assert!(!verify(
+            &rrsig("ns1.example.com", Type::A),
+            &key.dnskey(),
+            &www
+        ));
+
+        // a wildcard expansion verifies with the wildcard's signature
+        let expanded = Record {
+            name: Name::from("a.b.wild.example.com"),
+            ..of("*.wild.example.com", Type::TXT)[0].clone()
+        };
+        let sig = rrsig("*.wild.example.com", Type::TXT);
+        assert!(verify(&sig, &key.dnskey(), &[expanded]));
+    }
+
+    #[test]
+    fn test_nsec3_hash() {
+        // RFC 5155, appendix A
+        let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+        for (name, hash) in [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+        ] {
+            let digest = nsec3_hash(&Name::from(name), &salt, 12).unwrap();
+            assert_eq!(hash, base32hex_encode(&digest).to_ascii_lowercase());
+        }
+    }
+
+    #[test]
+    fn test_key_file() {
+        let key = key();
+        assert!(SigningKey::parse(&key.to_file()) == Ok(key.clone()));
+        assert!(SigningKey::parse("Algorithm: 8 (RSASHA256)\nPrivateKey: AAAA").is_err());
+        assert!(SigningKey::parse("Algorithm: 15\nPrivateKey: AAAA").is_err());
+    }
+
+    #[test]
+    fn test_sign_zone() {
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+        let origin = zone.origin.clone();
+        let key = key();
+        let records = sign_zone(
+            &origin,
+            zone.records().cloned().collect(),
+            &key,
+            &DenialChain::Nsec,
+            1_700_000_000,
+        )
+        .unwrap();
+
+        let of_type = |rtype| records.iter().filter(move |r: &&Record| r.rtype == rtype);
+        assert_eq!(1, of_type(Type::DNSKEY).count());
+        // SOA, NS, DNSKEY, ns1 A, www A, wildcard TXT and the 4 NSEC
+        assert_eq!(10, of_type(Type::RRSIG).count());
+
+        for sig in of_type(Type::RRSIG) {
+            let RData::RRSIG(rrsig) = &sig.rdata else {
+                unreachable!()
+            };
+            assert_eq!(key.key_tag(), rrsig.key_tag);
+            let rrset: Vec<Record> = records
+                .iter()
+                .filter(|r| r.name == sig.name && r.rtype == rrsig.type_covered)
+                .cloned()
+                .collect();
+            // canonical order doesn't depend on the order records are in
+            let mut reversed = rrset.clone();
+            reversed.reverse();
+            let public: [u8; 32] = key.dnskey().public_key.try_into().unwrap();
+            for rrset in [rrset, reversed] {
+                assert!(ed25519::verify(
+                    &public,
+                    &signed_data(rrsig, &rrset).unwrap(),
+                    &rrsig.signature
+                ));
+            }
+            if rrsig.type_covered == Type::TXT {
+                assert_eq!(3, rrsig.labels);
+            }
+        }
+    }
+
+    #[test]
+    fn test_denial_chains() {
+        let text = "\
+$ORIGIN example.com.
+$TTL 3600
+@          SOA ns1 hostmaster 1 7200 3600 1209600 300
+@          NS  ns1
+ns1        A   192.0.2.53
+a.b.deep   A   192.0.2.1
+sub        NS  ns.sub
+ns.sub     A   192.0.2.54
+";
+        let zone = Zone::parse(text, None, None).unwrap();
+        let sign = |chain| {
+            sign_zone(
+                &zone.origin,
+                zone.records().cloned().collect(),
+                &key(),
+                &chain,
+                1_700_000_000,
+            )
+            .unwrap()
+        };
+        let signed = |records: &[Record], name: &str, rtype| {
+            records.iter().any(|r| {
+                r.name == Name::from(name)
+                    && matches!(&r.rdata, RData::RRSIG(s) if s.type_covered == rtype)
+            })
+        };
+
+        let records = sign(DenialChain::Nsec);
+        let nsecs: Vec<(String, &Nsec)> = records
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::NSEC(nsec) => Some((r.name.to_string(), nsec)),
+                _ => None,
+            })
+            .collect();
+        // canonical order, glue left out, the last one back to the apex
+        let chain: Vec<(&str, String)> = nsecs
+            .iter()
+            .map(|(owner, nsec)| (owner.as_str(), nsec.next_domain_name.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("example.com", "a.b.deep.example.com".to_string()),
+                ("a.b.deep.example.com", "ns1.example.com".to_string()),
+                ("ns1.example.com", "sub.example.com".to_string()),
+                ("sub.example.com", "example.com".to_string()),
+            ],
+            chain
+        );
+        assert_eq!(vec![Type::NS, Type::RRSIG, Type::NSEC], nsecs[3].1.types);
+        assert!(nsecs[0].1.types.contains(&Type::DNSKEY));
+        // only the NSEC of a delegation is signed, glue not at all
+        assert!(signed(&records, "sub.example.com", Type::NSEC));
+        assert!(!signed(&records, "sub.example.com", Type::NS));
+        assert!(!signed(&records, "ns.sub.example.com", Type::A));
+        assert!(signed(&records, "example.com", Type::NS));
+        assert_eq!(
+            300,
+            records.iter().find(|r| r.rtype == Type::NSEC).unwrap().ttl
+        );
+
+        let salt = vec![0xaa, 0xbb, 0xcc, 0xdd];
+        let records = sign(DenialChain::Nsec3 {
+            salt: salt.clone(),
+            iterations: 12,
+        });
+        assert!(!records.iter().any(|r| r.rtype == Type::NSEC));
+        let param = records.iter().find_map(|r| match &r.rdata {
+            RData::NSEC3PARAM(param) => Some(param),
+            _ => None,
+        });
+        assert_eq!(Some((12, &salt)), param.map(|p| (p.iterations, &p.salt)));
+        let nsec3s: Vec<(&Record, &Nsec3)> = records
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::NSEC3(nsec3) => Some((r, nsec3)),
+                _ => None,
+            })
+            .collect();
+        // the apex, ns1, sub, a.b.deep and the empty non-terminals b.deep
+        // and deep
+        assert_eq!(6, nsec3s.len());
+        for (record, nsec3) in nsec3s.iter() {
+            assert!(signed(&records, &record.name.to_string(), Type::NSEC3));
+            assert_eq!(12, nsec3.iterations);
+        }
+        let hashed = |name: &str| {
+            let hash = nsec3_hash(&Name::from(name), &salt, 12).unwrap();
+            let owner = format!(
+                "{}.example.com",
+                base32hex_encode(&hash).to_ascii_lowercase()
+            );
+            nsec3s
+                .iter()
+                .find(|(r, _)| r.name == Name::from(owner.as_str()))
+                .map(|(_, nsec3)| nsec3.types.clone())
+        };
+        assert_eq!(Some(Vec::new()), hashed("deep.example.com"));
+        assert_eq!(Some(vec![Type::NS]), hashed("sub.example.com"));
+        assert_eq!(
+            Some(vec![Type::A, Type::RRSIG]),
+            hashed("a.b.deep.example.com")
+        );
+        assert!(hashed("example.com").unwrap().contains(&Type::NSEC3PARAM));
+        assert_eq!(None, hashed("ns.sub.example.com"));
+        // every hash links to the next, wrapping around
+        let mut owners: Vec<Vec<u8>> = nsec3s
+            .iter()
+            .map(|(_, nsec3)| nsec3.next_hashed_owner.clone())
+            .collect();
+        owners.sort();
+        owners.dedup();
+        assert_eq!(6, owners.len());
+    }
+}
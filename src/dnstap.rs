@@ -0,0 +1,492 @@
+//! dnstap (https://dnstap.info) output: client and forwarder queries and
+//! responses as protobuf `Dnstap` messages, written to a collector's Unix
+//! socket as a bidirectional Frame Streams connection.
+//!
+//! Frames are queued and written by a thread of their own, queries never
+//! wait for the collector. Frames that don't fit in the queue, or arrive
+//! while the collector is unreachable, are dropped.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    forward::Endpoint,
+    handler::{Context, Next, RequestHandler, Transport},
+    privacy,
+    proto::Message,
+    wire::WireMessage,
+};
+
+/// Content type of the frames, agreed on in the handshake.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frames waiting to be written, at most.
+const QUEUE_SIZE: usize = 10_000;
+
+/// How long to wait before connecting again after the collector went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long the collector may take to accept the connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Frame Streams control frame types.
+mod control {
+    pub const ACCEPT: u32 = 0x01;
+    pub const START: u32 = 0x02;
+    pub const STOP: u32 = 0x03;
+    pub const READY: u32 = 0x04;
+    pub const FINISH: u32 = 0x05;
+
+    // control field carrying a content type
+    pub const CONTENT_TYPE: u32 = 0x01;
+}
+
+/// `Message.Type` values of the events logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// `SocketProtocol` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp = 1,
+    Tcp = 2,
+    Doh = 4,
+    DnsCryptUdp = 5,
+    Doq = 7,
+}
+
+impl From<Transport> for Protocol {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Udp => Self::Udp,
+            Transport::Tcp => Self::Tcp,
+            Transport::Http | Transport::Https => Self::Doh,
+            Transport::Quic => Self::Doq,
+            Transport::DnsCrypt => Self::DnsCryptUdp,
+        }
+    }
+}
+
+impl From<&Endpoint> for Protocol {
+    fn from(endpoint: &Endpoint) -> Self {
+        match endpoint {
+            Endpoint::Dns(_) => Self::Udp,
+            Endpoint::Https(_) | Endpoint::Oblivious(_) => Self::Doh,
+            Endpoint::Quic(_) => Self::Doq,
+            Endpoint::DnsCrypt(_) => Self::DnsCryptUdp,
+        }
+    }
+}
+
+/// A query or response seen, as a dnstap `Message`.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: Kind,
+    pub protocol: Protocol,
+    // the client's or our address
+    pub query_address: Option<SocketAddr>,
+    // our or the upstream's address
+    pub response_address: Option<SocketAddr>,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub query: Option<Vec<u8>>,
+    pub response: Option<Vec<u8>>,
+}
+
+/// Protobuf encoding of the few field types dnstap uses.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn time(&mut self, sec_field: u32, time: SystemTime) {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.uint(sec_field, since_epoch.as_secs());
+        self.fixed32(sec_field + 1, since_epoch.subsec_nanos());
+    }
+}
+
+fn address_bytes(addr: &SocketAddr) -> Vec<u8> {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+impl Event {
+    /// The `Message` protobuf.
+    fn encode(&self) -> Vec<u8> {
+        let mut msg = Proto::default();
+        msg.uint(1, self.kind as u64);
+        let family = self
+            .query_address
+            .or(self.response_address)
+            .map(|addr| addr.ip().to_canonical().is_ipv6());
+        if let Some(ipv6) = family {
+            msg.uint(2, if ipv6 { 2 } else { 1 });
+        }
+        msg.uint(3, self.protocol as u64);
+        if let Some(addr) = &self.query_address {
+            msg.bytes(4, &address_bytes(addr));
+        }
+        if let Some(addr) = &self.response_address {
+            msg.bytes(5, &address_bytes(addr));
+        }
+        if let Some(addr) = &self.query_address {
+            msg.uint(6, addr.port().into());
+        }
+        if let Some(addr) = &self.response_address {
+            msg.uint(7, addr.port().into());
+        }
+        msg.time(8, self.query_time);
+        if let Some(query) = &self.query {
+            msg.bytes(10, query);
+        }
+        if let Some(time) = self.response_time {
+            msg.time(12, time);
+        }
+        if let Some(response) = &self.response {
+            msg.bytes(14, response);
+        }
+        msg.0
+    }
+}
+
+/// The `Dnstap` protobuf wrapping `event`.
+fn encode(identity: Option<&[u8]>, event: &Event) -> Vec<u8> {
+    let mut dnstap = Proto::default();
+    if let Some(identity) = identity {
+        dnstap.bytes(1, identity);
+    }
+    dnstap.bytes(
+        2,
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    dnstap.bytes(14, &event.encode());
+    // MESSAGE
+    dnstap.uint(15, 1);
+    dnstap.0
+}
+
+fn write_control(stream: &mut impl Write, kind: u32, content_type: bool) -> io::Result<()> {
+    let mut payload = kind.to_be_bytes().to_vec();
+    if content_type {
+        payload.extend(control::CONTENT_TYPE.to_be_bytes());
+        payload.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    stream.write_all(&frame)
+}
+
+/// Reads a control frame, returns its type.
+fn read_control(stream: &mut impl Read) -> Result<u32> {
+    let mut word = [0u8; 4];
+    stream.read_exact(&mut word)?;
+    if word != [0; 4] {
+        bail!("expected a control frame");
+    }
+    stream.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=512).contains(&len) {
+        bail!("invalid control frame length {}", len);
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(u32::from_be_bytes(payload[..4].try_into()?))
+}
+
+/// Connects to the collector at `path` and starts a bidirectional stream.
+fn connect(path: &Path) -> Result<UnixStream> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    write_control(&mut stream, control::READY, true)?;
+    match read_control(&mut stream)? {
+        control::ACCEPT => {}
+        other => bail!("collector answered READY with control frame {}", other),
+    }
+    write_control(&mut stream, control::START, true)?;
+    Ok(stream)
+}
+
+/// Writes queued frames to the collector until the queue is closed, then
+/// stops the stream.
+fn run(path: PathBuf, frames: Receiver<Vec<u8>>) {
+    let mut stream: Option<UnixStream> = None;
+    let mut retry_at = Instant::now();
+    for frame in frames.iter() {
+        if stream.is_none() && Instant::now() >= retry_at {
+            match connect(&path) {
+                Ok(connected) => {
+                    tracing::info!(path = %path.display(), "Connected to dnstap collector");
+                    stream = Some(connected);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        path = %path.display(), error = %e,
+                        "Failed to connect to dnstap collector"
+                    );
+                    retry_at = Instant::now() + RECONNECT_DELAY;
+                }
+            }
+        }
+        let Some(connected) = stream.as_mut() else {
+            continue;
+        };
+        let mut data = (frame.len() as u32).to_be_bytes().to_vec();
+        data.extend(frame);
+        if let Err(e) = connected.write_all(&data) {
+            tracing::warn!(path = %path.display(), error = %e, "Lost dnstap collector");
+            stream = None;
+            retry_at = Instant::now() + RECONNECT_DELAY;
+        }
+    }
+    if let Some(mut stream) = stream {
+        if write_control(&mut stream, control::STOP, false).is_ok() {
+            // the collector's FINISH, if it sends one before we go
+            let _ = read_control(&mut stream);
+        }
+    }
+}
+
+/// Where events go, shared by every part of the server that logs them.
+pub struct Dnstap {
+    frames: SyncSender<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+impl Dnstap {
+    /// Sink writing to the collector listening at `path`, naming this server
+    /// `identity` in every message if given.
+    pub fn new(path: &Path, identity: Option<String>) -> Result<Self> {
+        let (frames, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let path = path.to_path_buf();
+        thread::Builder::new()
+            .name("dnstap".into())
+            .spawn(move || run(path, rx))?;
+        Ok(Self {
+            frames,
+            identity: identity.map(String::into_bytes),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues `event`, dropping it if the queue is full.
+    pub fn log(&self, event: &Event) {
+        let frame = encode(self.identity.as_deref(), event);
+        if self.frames.try_send(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Events dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+static SINK: OnceLock<Dnstap> = OnceLock::new();
+
+/// Makes `dnstap` the sink events are logged to, for the rest of the process.
+pub fn install(dnstap: Dnstap) {
+    let _ = SINK.set(dnstap);
+}
+
+/// The installed sink, if any.
+pub fn sink() -> Option<&'static Dnstap> {
+    SINK.get()
+}
+
+/// Logs a query sent to an upstream, and its response if there was one.
+pub fn forwarder_exchange(
+    upstream: &Endpoint,
+    query: &Message,
+    sent: SystemTime,
+    response: Option<&WireMessage>,
+) {
+    let Some(sink) = sink() else {
+        return;
+    };
+    let response_address = match upstream {
+        Endpoint::Dns(addr) => Some(*addr),
+        _ => None,
+    };
+    let mut event = Event {
+        kind: Kind::ForwarderQuery,
+        protocol: upstream.into(),
+        query_address: None,
+        response_address,
+        query_time: sent,
+        response_time: None,
+        query: query.to_bytes().ok(),
+        response: None,
+    };
+    sink.log(&event);
+    if let Some(response) = response {
+        event.kind = Kind::ForwarderResponse;
+        event.response_time = Some(SystemTime::now());
+        event.response = Some(response.as_bytes().to_vec());
+        sink.log(&event);
+    }
+}
+
+/// Logs client queries and the responses to them.
+pub struct DnstapHandler;
+
+impl RequestHandler for DnstapHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let Some(sink) = sink() else {
+            return next.run(ctx, request);
+        };
+        let mut event = Event {
+            kind: Kind::ClientQuery,
+            protocol: ctx.transport.into(),
+            query_address: privacy::address(ctx.source),
+            response_address: None,
+            query_time: SystemTime::now(),
+            response_time: None,
+            query: request.to_bytes().ok(),
+            response: None,
+        };
+        sink.log(&event);
+        let reply = next.run(ctx, request)?;
+        event.kind = Kind::ClientResponse;
+        event.response_time = Some(SystemTime::now());
+        event.response = reply.to_bytes().ok();
+        sink.log(&event);
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        control, encode, read_control, write_control, Dnstap, Event, Kind, Proto, Protocol,
+    };
+    use std::{
+        env, fs,
+        io::Read,
+        os::unix::net::UnixListener,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn event() -> Event {
+        Event {
+            kind: Kind::ClientQuery,
+            protocol: Protocol::Udp,
+            query_address: Some("192.0.2.7:5353".parse().unwrap()),
+            response_address: None,
+            query_time: UNIX_EPOCH + Duration::new(1_792_143_067, 5),
+            response_time: None,
+            query: Some(vec![0xAB]),
+            response: None,
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut proto = Proto::default();
+        proto.varint(1);
+        proto.varint(300);
+        proto.varint(u64::from(u32::MAX));
+        assert_eq!(
+            vec![0x01, 0xAC, 0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+            proto.0
+        );
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let message = event().encode();
+        let expected: Vec<u8> = [
+            &[0x08, 5][..],                        // type CLIENT_QUERY
+            &[0x10, 1],                            // family INET
+            &[0x18, 1],                            // protocol UDP
+            &[0x22, 4, 192, 0, 2, 7],              // query_address
+            &[0x30, 0xE9, 0x29],                   // query_port 5353
+            &[0x40, 0xDB, 0xDD, 0xC7, 0xD6, 0x06], // query_time_sec
+            &[0x4D, 5, 0, 0, 0],                   // query_time_nsec
+            &[0x52, 1, 0xAB],                      // query_message
+        ]
+        .concat();
+        assert_eq!(expected, message);
+
+        let dnstap = encode(Some(b"ns1"), &event());
+        assert!(dnstap.starts_with(&[0x0A, 3, b'n', b's', b'1', 0x12]));
+        assert!(dnstap.ends_with(&[0x78, 1]));
+    }
+
+    #[test]
+    fn test_collector() {
+        let path = env::temp_dir().join(format!("dnstap-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let dnstap = Dnstap::new(&path, None).unwrap();
+        dnstap.log(&event());
+
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(control::READY, read_control(&mut stream).unwrap());
+        write_control(&mut stream, control::ACCEPT, true).unwrap();
+        assert_eq!(control::START, read_control(&mut stream).unwrap());
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut frame).unwrap();
+        assert_eq!(encode(None, &event()), frame);
+
+        // closing the queue stops the stream
+        drop(dnstap);
+        assert_eq!(control::STOP, read_control(&mut stream).unwrap());
+        write_control(&mut stream, control::FINISH, false).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
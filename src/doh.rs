@@ -0,0 +1,956 @@
+//! DNS over HTTPS (RFC 8484) over HTTP/1.1. The client POSTs queries in wire
+//! format, on TLS connections kept open per server for the next query. The
+//! server takes both GET and POST queries on `/dns-query`, and JSON API
+//! queries on `/resolve`, over TLS or plain HTTP.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    cache::negative_ttl,
+    encoding::base64url_decode,
+    eyeballs,
+    json::{self, DNS_JSON},
+    privacy,
+    proto::{rcode, Message},
+    tls::{Identity, TlsStream},
+    wire::WireMessage,
+    x509::{Certificate, HostPins},
+};
+
+/// Media type of DNS messages (RFC 8484, section 6).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Idle connections kept per server.
+const MAX_IDLE: usize = 4;
+
+/// Longest status line or header accepted.
+const MAX_LINE: usize = 8192;
+
+/// Longest body of a request or response carrying a DNS message.
+const MAX_BODY: usize = u16::MAX as usize;
+
+/// Longest body of a downloaded file.
+const MAX_DOWNLOAD: usize = 64 * 1024 * 1024;
+
+/// Path the server answers queries on.
+const PATH: &str = "/dns-query";
+
+/// Idle time after which the server closes a connection.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections the server serves at once, further ones are closed.
+const MAX_CONNECTIONS: usize = 256;
+
+/// URL of a DoH server, `https://host[:port]/path`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Url {
+    // name or address, without brackets
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    /// `host:port`, connections are pooled by it.
+    fn authority(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /// The authority as sent in the Host header, without the default port.
+    pub fn host(&self) -> String {
+        match self.port {
+            443 => self.authority().trim_end_matches(":443").to_string(),
+            _ => self.authority(),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The URL of `path` on the same server.
+    pub fn with_path(&self, path: &str) -> Self {
+        Self {
+            path: path.into(),
+            ..self.clone()
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    /// The path defaults to `/dns-query`, the port to 443.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("https://")
+            .ok_or_else(|| format!("expected an https:// URL, got {:?}", s))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/dns-query"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid URL {:?}", s))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port in {:?}", s))?,
+            None => 443,
+        };
+        if host.is_empty() || path.contains(char::is_whitespace) {
+            return Err(format!("invalid URL {:?}", s));
+        }
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            443 => match self.host.contains(':') {
+                true => write!(f, "https://[{}]{}", self.host, self.path),
+                false => write!(f, "https://{}{}", self.host, self.path),
+            },
+            _ => write!(f, "https://{}{}", self.authority(), self.path),
+        }
+    }
+}
+
+/// Client for DoH servers, authenticating them against `roots` and the
+/// pins of their host, if any.
+pub struct DohClient {
+    roots: Vec<Certificate>,
+    pins: HostPins,
+    // authority -> idle connections
+    idle: Mutex<HashMap<String, Vec<TlsStream<TcpStream>>>>,
+}
+
+impl DohClient {
+    pub fn new(roots: Vec<Certificate>, pins: HostPins) -> Self {
+        Self {
+            roots,
+            pins,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `request` to the server at `url` and waits up to `timeout` for
+    /// each step. The message goes out with ID 0 for HTTP caches (section
+    /// 4.1), the reply gets the ID of `request` back.
+    pub fn query(&self, url: &Url, request: &Message, timeout: Duration) -> Result<WireMessage> {
+        let body = Message {
+            id: 0,
+            ..request.clone()
+        }
+        .to_bytes()?;
+        let reply = self.fetch(
+            url,
+            &url.path,
+            Some((DNS_MESSAGE, &body)),
+            DNS_MESSAGE,
+            timeout,
+        )?;
+        let mut reply = WireMessage::new(reply)?;
+        if !reply.view().is_reply_to(&request.questions) {
+            bail!("unexpected reply from {}", url);
+        }
+        reply.set_id(request.id);
+        Ok(reply)
+    }
+
+    /// Sends a request for `target` on the server of `url`, a POST of `body`
+    /// with its media type if given and a GET otherwise, and returns the
+    /// response body, which must be of media type `accept`.
+    pub fn fetch(
+        &self,
+        url: &Url,
+        target: &str,
+        body: Option<(&str, &[u8])>,
+        accept: &str,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.request(
+            url,
+            ClientRequest {
+                target,
+                body,
+                accept,
+                limit: MAX_BODY,
+            },
+            timeout,
+        )
+    }
+
+    /// The file at `url`, of any media type and up to `MAX_DOWNLOAD` bytes.
+    pub fn download(&self, url: &Url, timeout: Duration) -> Result<Vec<u8>> {
+        self.request(
+            url,
+            ClientRequest {
+                target: &url.path,
+                body: None,
+                accept: "*/*",
+                limit: MAX_DOWNLOAD,
+            },
+            timeout,
+        )
+    }
+
+    fn request(&self, url: &Url, request: ClientRequest, timeout: Duration) -> Result<Vec<u8>> {
+        // the server may have closed a pooled connection in the meantime,
+        // the request is then sent again on a fresh one
+        let pooled = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(&url.authority())
+            .and_then(|idle| idle.pop());
+        let (conn, response, reusable) = match pooled.and_then(|mut conn| {
+            exchange(&mut conn, url, &request, timeout)
+                .ok()
+                .map(|r| (conn, r))
+        }) {
+            Some((conn, (response, reusable))) => (conn, response, reusable),
+            None => {
+                let mut conn = self.connect(url, timeout)?;
+                let (response, reusable) = exchange(&mut conn, url, &request, timeout)?;
+                (conn, response, reusable)
+            }
+        };
+        if reusable {
+            let mut idle = self.idle.lock().unwrap();
+            let idle = idle.entry(url.authority()).or_default();
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Opens a TLS connection to the server, its host name looked up with
+    /// the system resolver. Its IPv6 and IPv4 addresses are raced.
+    fn connect(&self, url: &Url, timeout: Duration) -> Result<TlsStream<TcpStream>> {
+        let addrs: Vec<SocketAddr> = (url.host.as_str(), url.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            bail!("no address for {}", url.host);
+        }
+        let stream = eyeballs::connect(addrs, timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let pins = self.pins.get(&url.host);
+        TlsStream::connect(stream, &url.host, &self.roots, pins, &["http/1.1"])
+    }
+}
+
+/// A request of the client: the request target, the body to POST with its
+/// media type, and the media type accepted back.
+struct ClientRequest<'a> {
+    target: &'a str,
+    body: Option<(&'a str, &'a [u8])>,
+    accept: &'a str,
+    // longest response body taken
+    limit: usize,
+}
+
+/// Sends `request` and reads the response body, and whether the connection
+/// can be used again.
+fn exchange(
+    conn: &mut TlsStream<TcpStream>,
+    url: &Url,
+    request: &ClientRequest,
+    timeout: Duration,
+) -> Result<(Vec<u8>, bool)> {
+    conn.get_ref().set_read_timeout(Some(timeout))?;
+    conn.get_ref().set_write_timeout(Some(timeout))?;
+    let mut head = match request.body {
+        Some((content_type, body)) => format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nContent-Length: {}\r\n\r\n",
+            request.target,
+            url.host(),
+            content_type,
+            request.accept,
+            body.len()
+        ),
+        None => format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+            request.target,
+            url.host(),
+            request.accept
+        ),
+    }
+    .into_bytes();
+    if let Some((_, body)) = request.body {
+        head.extend_from_slice(body);
+    }
+    conn.write_all(&head)?;
+    read_response(conn, request.accept, request.limit)
+}
+
+/// Reads an HTTP/1.1 response of media type `accept`, any for `*/*`, and a
+/// body of at most `limit` bytes, returning the body and whether the
+/// connection stays open after it.
+fn read_response<R: Read>(reader: &mut R, accept: &str, limit: usize) -> Result<(Vec<u8>, bool)> {
+    let status = read_line(reader)?;
+    let mut parts = status.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let code = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        bail!("invalid HTTP status line {:?}", status);
+    }
+
+    let mut length = None;
+    let mut chunked = false;
+    let mut reusable = version == "HTTP/1.1";
+    let mut content_type = String::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            bail!("invalid HTTP header {:?}", line);
+        };
+        let value = value.trim().to_ascii_lowercase();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = Some(value.parse::<usize>()?),
+            "transfer-encoding" => chunked = value.ends_with("chunked"),
+            "connection" if value == "close" => reusable = false,
+            "connection" if value == "keep-alive" => reusable = true,
+            "content-type" => content_type = value,
+            _ => {}
+        }
+    }
+
+    let body = match (chunked, length) {
+        (true, _) => read_chunked(reader, limit)?,
+        (false, Some(len)) if len <= limit => {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        (false, Some(len)) => bail!("HTTP response too long ({} bytes)", len),
+        // the body ends with the connection
+        (false, None) => {
+            let mut body = Vec::new();
+            reader.take(limit as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > limit {
+                bail!("HTTP response too long");
+            }
+            reusable = false;
+            body
+        }
+    };
+    if code != "200" {
+        bail!("HTTP status {}", status);
+    }
+    if accept != "*/*" && content_type != accept {
+        bail!("unexpected content type {:?}", content_type);
+    }
+    Ok((body, reusable))
+}
+
+/// A body in chunked transfer coding (RFC 9112, section 7.1), of at most
+/// `limit` bytes.
+fn read_chunked<R: Read>(reader: &mut R, limit: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow!("invalid HTTP chunk size {:?}", line))?;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > limit {
+            bail!("HTTP response too long");
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.is_empty() {
+            bail!("invalid HTTP chunk");
+        }
+    }
+    // trailer fields
+    while !read_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+/// Reads a CRLF terminated line, without the CRLF.
+fn read_line<R: Read>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        if line.len() > MAX_LINE {
+            bail!("HTTP line too long");
+        }
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8(line)?)
+}
+
+/// Accepts DoH connections on `listener`, authenticated by `identity` or
+/// plain HTTP without one, and serves each on its own thread. `answer` gets
+/// the client's address and a query in wire format, and gives the reply's,
+/// None for malformed queries.
+pub fn spawn(
+    listener: TcpListener,
+    identity: Option<Identity>,
+    answer: impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+) -> Result<()> {
+    let identity = Arc::new(identity);
+    let answer = Arc::new(answer);
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new().name("doh".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+            let (identity, answer, connections) =
+                (identity.clone(), answer.clone(), connections.clone());
+            thread::spawn(move || {
+                if let Err(e) = serve_conn(stream, identity.as_ref().as_ref(), &*answer) {
+                    tracing::warn!(error = %e, "HTTP connection failed");
+                }
+                connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    })?;
+    Ok(())
+}
+
+/// Answers requests on a single connection, over TLS with an `identity`,
+/// until the client closes it or leaves it idle for `IDLE_TIMEOUT`.
+fn serve_conn(
+    stream: TcpStream,
+    identity: Option<&Identity>,
+    answer: &impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>>,
+) -> Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    match identity {
+        Some(identity) => {
+            let conn = TlsStream::accept(stream, identity, &["http/1.1"])?;
+            serve_requests(conn, source, "https", answer)
+        }
+        None => serve_requests(stream, source, "http", answer),
+    }
+}
+
+/// Reads requests from `conn` and writes back the responses.
+fn serve_requests(
+    mut conn: impl Read + Write,
+    source: SocketAddr,
+    scheme: &str,
+    answer: &impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>>,
+) -> Result<()> {
+    loop {
+        let request = match read_request(&mut conn) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if is_idle(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        tracing::trace!(
+            method = %request.method, target = %request.target, client = %privacy::client(source),
+            %scheme,
+            "Received HTTP request"
+        );
+        let response = respond(&request, |query| answer(source, query));
+        conn.write_all(&response.to_bytes(request.keep_alive))?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether reading failed because the client went away or stayed silent.
+pub fn is_idle(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+        )
+    })
+}
+
+/// Parsed HTTP request.
+#[derive(Debug, Default, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub content_type: String,
+    // as sent, credentials are case sensitive
+    pub authorization: String,
+    pub body: Vec<u8>,
+    // the client wants the connection kept open
+    pub keep_alive: bool,
+}
+
+/// Reads an HTTP/1.1 request, None if the connection closes before one
+/// starts.
+pub fn read_request<R: Read>(reader: &mut R) -> Result<Option<Request>> {
+    let mut first = [0u8];
+    if reader.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let line = read_line(&mut first.chain(&mut *reader))?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("invalid HTTP request line {:?}", line);
+    };
+    if !version.starts_with("HTTP/1.") {
+        bail!("unsupported HTTP version {:?}", version);
+    }
+
+    let mut length = 0;
+    let mut chunked = false;
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_type = String::new();
+    let mut authorization = String::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            bail!("invalid HTTP header {:?}", line);
+        };
+        let raw = value.trim();
+        let value = raw.to_ascii_lowercase();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse::<usize>()?,
+            "transfer-encoding" => chunked = value.ends_with("chunked"),
+            "connection" if value == "close" => keep_alive = false,
+            "connection" if value == "keep-alive" => keep_alive = true,
+            "content-type" => content_type = value,
+            "authorization" => authorization = raw.to_string(),
+            _ => {}
+        }
+    }
+
+    let body = match chunked {
+        true => read_chunked(reader, MAX_BODY)?,
+        false if length <= MAX_BODY => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        false => bail!("HTTP request too long ({} bytes)", length),
+    };
+    Ok(Some(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        content_type,
+        authorization,
+        body,
+        keep_alive,
+    }))
+}
+
+/// The response to a request, a JSON API query or a DoH one, with the
+/// reply `answer` gives to the query in wire format.
+fn respond(request: &Request, answer: impl Fn(&[u8]) -> Option<Vec<u8>>) -> Response {
+    let (path, params) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if path == json::PATH {
+        if request.method != "GET" {
+            return Response::Status(405);
+        }
+        let reply = json::query(params)
+            .ok()
+            .and_then(|query| query.to_bytes().ok())
+            .and_then(|query| answer(&query[..]))
+            .and_then(|reply| Message::from_bytes(&reply[..]).ok());
+        return match reply {
+            Some(reply) => Response::Json(json::to_json(&reply), max_age(&reply)),
+            None => Response::Status(400),
+        };
+    }
+    match dns_message(request) {
+        Ok(query) => match answer(&query) {
+            Some(reply) => Response::Message(reply),
+            None => Response::Status(400),
+        },
+        Err(status) => Response::Status(status),
+    }
+}
+
+/// The query of a DoH request, the `dns` parameter of a GET or the body of
+/// a POST (RFC 8484, section 4.1), or the HTTP status to answer with.
+fn dns_message(request: &Request) -> Result<Vec<u8>, u16> {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if path != PATH {
+        return Err(404);
+    }
+    match request.method.as_str() {
+        "GET" => query
+            .split('&')
+            .find_map(|param| param.strip_prefix("dns="))
+            .and_then(base64url_decode)
+            .ok_or(400),
+        "POST" if request.content_type != DNS_MESSAGE => Err(415),
+        "POST" => Ok(request.body.clone()),
+        _ => Err(405),
+    }
+}
+
+/// Response of the server.
+#[derive(Debug, PartialEq)]
+enum Response {
+    // a reply in wire format
+    Message(Vec<u8>),
+    // a reply to a JSON API query, and how long it may be cached
+    Json(String, u32),
+    // an error without a body
+    Status(u16),
+}
+
+impl Response {
+    fn to_bytes(&self, keep_alive: bool) -> Vec<u8> {
+        let connection = match keep_alive {
+            true => "keep-alive",
+            false => "close",
+        };
+        let ok = |content_type: &str, body: &[u8], max_age: u32| {
+            let mut out = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Cache-Control: max-age={}\r\nConnection: {}\r\n\r\n",
+                content_type,
+                body.len(),
+                max_age,
+                connection
+            )
+            .into_bytes();
+            out.extend_from_slice(body);
+            out
+        };
+        match self {
+            Self::Message(reply) => {
+                let max_age = Message::from_bytes(reply).map_or(0, |reply| max_age(&reply));
+                ok(DNS_MESSAGE, reply, max_age)
+            }
+            Self::Json(reply, max_age) => ok(DNS_JSON, reply.as_bytes(), *max_age),
+            Self::Status(status) => {
+                let (reason, allow) = match status {
+                    400 => ("Bad Request", ""),
+                    404 => ("Not Found", ""),
+                    405 => ("Method Not Allowed", "Allow: GET, POST\r\n"),
+                    415 => ("Unsupported Media Type", ""),
+                    _ => ("Error", ""),
+                };
+                format!(
+                    "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: {}\r\n\r\n",
+                    status, reason, allow, connection
+                )
+                .into_bytes()
+            }
+        }
+    }
+}
+
+/// How long HTTP caches may keep a reply: the lowest TTL in its answer, or
+/// the negative caching TTL of a reply without one (RFC 8484, section 5.1).
+/// Errors aren't cached.
+fn max_age(reply: &Message) -> u32 {
+    if reply.rcode != rcode::NOERROR && reply.rcode != rcode::NXDOMAIN {
+        return 0;
+    }
+    match reply.answers.iter().map(|record| record.ttl).min() {
+        Some(ttl) => ttl,
+        None => negative_ttl(reply).unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        dns_message, max_age, read_request, read_response, respond, Request, Response, Url,
+        DNS_MESSAGE, MAX_BODY,
+    };
+    use crate::{
+        encoding::base64url_encode,
+        proto::{rcode, Class, Message, Name, Question, Record, Type},
+        rdata::{RData, Soa},
+    };
+    use std::{io::Cursor, net::Ipv4Addr, str::FromStr};
+
+    #[test]
+    fn test_url() {
+        let url = Url::from_str("https://dns.google/dns-query").unwrap();
+        assert_eq!("dns.google:443", url.authority());
+        assert_eq!("https://dns.google/dns-query", url.to_string());
+
+        let url = Url::from_str("https://[2606:4700::1111]:8443").unwrap();
+        assert_eq!("2606:4700::1111", url.host);
+        assert_eq!("/dns-query", url.path);
+        assert_eq!("https://[2606:4700::1111]:8443/dns-query", url.to_string());
+
+        let url = Url::from_str("https://Cloudflare-DNS.com/resolve?x=1").unwrap();
+        assert_eq!("cloudflare-dns.com", url.host);
+        assert_eq!("/resolve?x=1", url.path);
+
+        assert!(Url::from_str("http://dns.google/dns-query").is_err());
+        assert!(Url::from_str("https://dns.google:https/").is_err());
+        assert!(Url::from_str("https:///dns-query").is_err());
+    }
+
+    #[test]
+    fn test_read_response() {
+        let response = |text: &str| {
+            read_response(
+                &mut Cursor::new(text.as_bytes().to_vec()),
+                DNS_MESSAGE,
+                MAX_BODY,
+            )
+        };
+
+        let (body, reusable) = response(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+             Content-Length: 5\r\n\r\nhello",
+        )
+        .unwrap();
+        assert_eq!(b"hello", &body[..]);
+        assert!(reusable);
+
+        let (body, reusable) = response(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\n\
+             Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             3;ext=1\r\nhel\r\n2\r\nlo\r\n0\r\nTrailer: x\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(b"hello", &body[..]);
+        assert!(!reusable);
+
+        // delimited by the end of the connection
+        let (body, reusable) =
+            response("HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\r\nhello")
+                .unwrap();
+        assert_eq!(b"hello", &body[..]);
+        assert!(!reusable);
+
+        assert!(
+            response("HTTP/1.1 415 Unsupported Media Type\r\nContent-Length: 0\r\n\r\n").is_err()
+        );
+        assert!(response(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello"
+        )
+        .is_err());
+        assert!(response(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+             Content-Length: 10\r\n\r\nhello"
+        )
+        .is_err());
+        assert!(response("SSH-2.0-OpenSSH\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = |text: &[u8]| read_request(&mut Cursor::new(text.to_vec()));
+
+        let get = request(b"GET /dns-query?dns=AAABAA HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!("GET", get.method);
+        assert_eq!("/dns-query?dns=AAABAA", get.target);
+        assert!(get.keep_alive);
+
+        let post = request(
+            b"POST /dns-query HTTP/1.1\r\nContent-Type: Application/DNS-Message\r\n\
+              Content-Length: 3\r\nConnection: close\r\nAuthorization: Bearer AbC\r\n\r\nabcGET",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(DNS_MESSAGE, post.content_type);
+        assert_eq!(b"abc", &post.body[..]);
+        assert_eq!("Bearer AbC", post.authorization);
+        assert!(!post.keep_alive);
+
+        assert_eq!(None, request(b"").unwrap());
+        assert!(request(b"GET /dns-query\r\n\r\n").is_err());
+        assert!(request(b"POST / HTTP/1.1\r\nContent-Length: 99999\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_dns_message() {
+        let request = |method: &str, target: &str, content_type: &str| Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            content_type: content_type.to_string(),
+            body: b"query".to_vec(),
+            keep_alive: true,
+            ..Request::default()
+        };
+        let dns = base64url_encode(b"query");
+        assert_eq!(
+            Ok(b"query".to_vec()),
+            dns_message(&request("GET", &format!("/dns-query?ct&dns={}", dns), ""))
+        );
+        assert_eq!(
+            Ok(b"query".to_vec()),
+            dns_message(&request("POST", "/dns-query", DNS_MESSAGE))
+        );
+        assert_eq!(Err(400), dns_message(&request("GET", "/dns-query", "")));
+        assert_eq!(
+            Err(400),
+            dns_message(&request("GET", "/dns-query?dns=a*b", ""))
+        );
+        assert_eq!(
+            Err(415),
+            dns_message(&request("POST", "/dns-query", "text/plain"))
+        );
+        assert_eq!(Err(404), dns_message(&request("GET", "/", "")));
+        assert_eq!(
+            Err(405),
+            dns_message(&request("PUT", "/dns-query", DNS_MESSAGE))
+        );
+    }
+
+    #[test]
+    fn test_respond() {
+        let request = |method: &str, target: &str| Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            keep_alive: true,
+            ..Request::default()
+        };
+        let answer = |query: &[u8]| {
+            let mut reply = Message::from_bytes(query).ok()?.reply();
+            reply.answers.push(Record {
+                name: Name::from("example.com"),
+                rtype: Type::A,
+                class: Class::IN,
+                ttl: 120,
+                rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+            reply.to_bytes().ok()
+        };
+        let Response::Json(json, max_age) =
+            respond(&request("GET", "/resolve?name=example.com&type=A"), answer)
+        else {
+            panic!("expected a JSON reply");
+        };
+        assert!(json.contains(
+            "\"Answer\":[{\"name\":\"example.com.\",\"type\":1,\"TTL\":120,\
+             \"data\":\"192.0.2.1\"}]"
+        ));
+        assert_eq!(120, max_age);
+        assert_eq!(
+            Response::Status(400),
+            respond(&request("GET", "/resolve?type=A"), answer)
+        );
+        assert_eq!(
+            Response::Status(405),
+            respond(&request("POST", "/resolve?name=example.com"), answer)
+        );
+
+        let query = Message::default().to_bytes().unwrap();
+        let target = format!("/dns-query?dns={}", base64url_encode(&query));
+        assert!(matches!(
+            respond(&request("GET", &target), answer),
+            Response::Message(_)
+        ));
+        assert_eq!(Response::Status(404), respond(&request("GET", "/"), answer));
+    }
+
+    #[test]
+    fn test_response() {
+        let record = |rtype: Type, rdata: RData, ttl: u32| Record {
+            name: Name::from("example.com"),
+            rtype,
+            class: Class::IN,
+            ttl,
+            rdata,
+        };
+        let mut reply = Message {
+            qr: 1,
+            questions: vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            answers: vec![
+                record(Type::A, RData::A(Ipv4Addr::new(192, 0, 2, 1)), 300),
+                record(Type::A, RData::A(Ipv4Addr::new(192, 0, 2, 2)), 60),
+            ],
+            ..Message::default()
+        };
+        assert_eq!(60, max_age(&reply));
+
+        let bytes = reply.to_bytes().unwrap();
+        let response = Response::Message(bytes.clone()).to_bytes(true);
+        let (body, reusable) =
+            read_response(&mut Cursor::new(response.clone()), DNS_MESSAGE, MAX_BODY).unwrap();
+        assert_eq!(bytes, body);
+        assert!(reusable);
+        let text = String::from_utf8_lossy(&response);
+        assert!(text.contains("Cache-Control: max-age=60\r\n"));
+
+        // negative answers are cached for the SOA's minimum
+        reply.rcode = rcode::NXDOMAIN;
+        reply.answers.clear();
+        let soa = Soa {
+            mname: Name::from("ns.example.com"),
+            rname: Name::from("admin.example.com"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 30,
+        };
+        reply.authorities = vec![record(Type::SOA, RData::SOA(soa), 3600)];
+        assert_eq!(30, max_age(&reply));
+        reply.rcode = rcode::SERVFAIL;
+        assert_eq!(0, max_age(&reply));
+
+        let response = Response::Status(405).to_bytes(false);
+        let text = String::from_utf8(response).unwrap();
+        assert!(text.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, POST\r\n"));
+        assert!(text.contains("Connection: close\r\n"));
+    }
+}
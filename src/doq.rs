@@ -0,0 +1,594 @@
+//! DNS over QUIC (RFC 9250). Each query goes on its own client-initiated
+//! stream with a 2-byte length prefix, the reply comes back on the same
+//! stream. The client keeps connections open per server for the next query,
+//! the server answers the queries of a connection concurrently.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    edns::EdnsOption,
+    encoder::length_prefixed,
+    eyeballs, privacy,
+    proto::Message,
+    quic::{self, Connection, Link},
+    tls::Identity,
+    wire::WireMessage,
+    x509::{Certificate, HostPins, Pin},
+};
+
+/// ALPN protocol of DoQ (section 4.1).
+const ALPN: &str = "doq";
+
+/// Port DoQ is served on by default (section 4.1.1).
+const PORT: u16 = 853;
+
+// error codes (section 4.3)
+pub const NO_ERROR: u64 = 0x0;
+pub const INTERNAL_ERROR: u64 = 0x1;
+pub const PROTOCOL_ERROR: u64 = 0x2;
+pub const REQUEST_CANCELLED: u64 = 0x3;
+pub const EXCESSIVE_LOAD: u64 = 0x4;
+pub const UNSPECIFIED_ERROR: u64 = 0x5;
+
+/// Idle connections kept per server.
+const MAX_IDLE: usize = 4;
+
+/// Connections the server serves at once, further ones are closed with
+/// `EXCESSIVE_LOAD` right after the handshake.
+const MAX_CONNECTIONS: usize = 256;
+
+/// How long the server waits for a client to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of a DoQ server, `quic://host[:port]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Url {
+    // name or address, without brackets
+    host: String,
+    port: u16,
+}
+
+impl Url {
+    /// `host:port`, connections are pooled by it.
+    fn authority(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    /// The port defaults to 853.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let authority = s
+            .strip_prefix("quic://")
+            .ok_or_else(|| format!("expected a quic:// URL, got {:?}", s))?;
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid URL {:?}", s))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port in {:?}", s))?,
+            None => PORT,
+        };
+        if host.is_empty() || host.contains(['/', ' ']) {
+            return Err(format!("invalid URL {:?}", s));
+        }
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            PORT if self.host.contains(':') => write!(f, "quic://[{}]", self.host),
+            PORT => write!(f, "quic://{}", self.host),
+            _ => write!(f, "quic://{}", self.authority()),
+        }
+    }
+}
+
+/// A client connection and the socket it runs on.
+type Conn = (Connection, UdpSocket);
+
+/// Client for DoQ servers, authenticating them against `roots` and the
+/// pins of their host, if any.
+pub struct DoqClient {
+    roots: Vec<Certificate>,
+    pins: HostPins,
+    // authority -> idle connections
+    idle: Mutex<HashMap<String, Vec<Conn>>>,
+}
+
+impl DoqClient {
+    pub fn new(roots: Vec<Certificate>, pins: HostPins) -> Self {
+        Self {
+            roots,
+            pins,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `request` to the server at `url` and waits up to `timeout` for
+    /// each step. The message goes out with ID 0 (section 4.2.1), the reply
+    /// gets the ID of `request` back.
+    pub fn query(&self, url: &Url, request: &Message, timeout: Duration) -> Result<WireMessage> {
+        let body = Message {
+            id: 0,
+            ..request.clone()
+        }
+        .to_bytes()?;
+
+        // a pooled connection may have gone idle or closed in the meantime,
+        // the query is then sent again on a fresh one
+        let pooled = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(&url.authority())
+            .and_then(|idle| idle.pop());
+        let (conn, reply) = match pooled.and_then(|mut conn| {
+            exchange(&mut conn, &body, timeout)
+                .ok()
+                .map(|reply| (conn, reply))
+        }) {
+            Some(done) => done,
+            None => {
+                let mut conn = self.connect(url, timeout)?;
+                let reply = exchange(&mut conn, &body, timeout);
+                match reply {
+                    Ok(reply) => (conn, reply),
+                    // the stream was cancelled, the connection still works
+                    Err(e) => {
+                        self.release(url, conn);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        self.release(url, conn);
+
+        let mut reply = WireMessage::new(reply)?;
+        if !reply.view().is_reply_to(&request.questions) {
+            bail!("unexpected reply from {}", url);
+        }
+        reply.set_id(request.id);
+        Ok(reply)
+    }
+
+    /// Opens a connection to the server, its host name looked up with the
+    /// system resolver. Handshakes with its IPv6 and IPv4 addresses are
+    /// raced.
+    fn connect(&self, url: &Url, timeout: Duration) -> Result<Conn> {
+        let addrs: Vec<SocketAddr> = (url.host.as_str(), url.port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            bail!("no address for {}", url.host);
+        }
+        let host = url.host.clone();
+        let roots = self.roots.clone();
+        let pins = self.pins.get(&url.host).to_vec();
+        let (_, conn) = eyeballs::race(
+            eyeballs::interleave(addrs),
+            eyeballs::CONNECTION_ATTEMPT_DELAY,
+            move |addr| open(&host, addr, &roots, &pins, timeout),
+        )?;
+        Ok(conn)
+    }
+
+    /// Opens a connection to `host` at `addr`.
+    fn open(&self, host: &str, addr: SocketAddr, timeout: Duration) -> Result<Conn> {
+        let pins = self.pins.get(host);
+        open(host, addr, &self.roots, pins, timeout)
+    }
+
+    /// Pools a connection that's still open.
+    fn release(&self, url: &Url, conn: Conn) {
+        if conn.0.closed().is_some() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let idle = idle.entry(url.authority()).or_default();
+        if idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Opens a connection to `host` at `addr`, authenticated against `roots`
+/// and `pins`.
+fn open(
+    host: &str,
+    addr: SocketAddr,
+    roots: &[Certificate],
+    pins: &[Pin],
+    timeout: Duration,
+) -> Result<Conn> {
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let mut socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    let mut conn = Connection::client();
+    conn.connect(&mut socket, host, roots, pins, ALPN, timeout)?;
+    Ok((conn, socket))
+}
+
+/// Sends a query on a new stream and reads the reply. The stream is
+/// cancelled if no reply comes in time.
+fn exchange((conn, socket): &mut Conn, query: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    // with its 2-byte length prefix (section 4.2)
+    let data = length_prefixed(query)?;
+    let id = conn
+        .open_stream()
+        .ok_or_else(|| anyhow!("no more streams on the QUIC connection"))?;
+    conn.write_stream(id, &data, true);
+    let deadline = Instant::now() + timeout;
+    let data = loop {
+        match conn.read_stream(id) {
+            Some(Ok(data)) => break data,
+            Some(Err(code)) => bail!("server reset the stream with error {}", code),
+            None => {}
+        }
+        if let Err(e) = conn.step(socket, deadline) {
+            conn.cancel_stream(id, REQUEST_CANCELLED);
+            let _ = conn.flush(socket);
+            return Err(e);
+        }
+    };
+    // acknowledges the reply
+    conn.flush(socket)?;
+    unprefixed(&data).map(<[u8]>::to_vec).map_err(|reason| {
+        conn.close(PROTOCOL_ERROR, reason);
+        let _ = conn.flush(socket);
+        anyhow!("{}", reason)
+    })
+}
+
+/// The message of a stream's data, which must be exactly one.
+fn unprefixed(data: &[u8]) -> Result<&[u8], &'static str> {
+    match data {
+        [high, low, message @ ..]
+            if u16::from_be_bytes([*high, *low]) as usize == message.len() =>
+        {
+            Ok(message)
+        }
+        _ => Err("stream doesn't hold exactly one DNS message"),
+    }
+}
+
+/// Checks a query by the rules of section 4.2.1 and 5.5.2, returning it
+/// without its prefix.
+fn query_message(data: &[u8]) -> Result<&[u8], &'static str> {
+    let message = unprefixed(data)?;
+    if message.get(..2).is_some_and(|id| id != [0, 0]) {
+        return Err("query with a non-zero Message ID");
+    }
+    let keepalive = Message::from_bytes(message)
+        .ok()
+        .and_then(|query| query.opt)
+        .is_some_and(|opt| opt.option(EdnsOption::KEEPALIVE).is_some());
+    if keepalive {
+        return Err("query with an edns-tcp-keepalive option");
+    }
+    Ok(message)
+}
+
+/// What a server connection's thread is woken up for.
+enum Event {
+    // from the client
+    Datagram(Vec<u8>),
+    // the answer to a query on a stream, None for malformed queries
+    Reply(u64, Option<Vec<u8>>),
+}
+
+/// The server's side of a client's datagrams: received through the
+/// dispatcher, sent on the shared socket.
+struct ServerLink {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    events: Receiver<Event>,
+    // replies that came in while waiting for datagrams
+    replies: Vec<(u64, Option<Vec<u8>>)>,
+}
+
+impl Link for ServerLink {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.socket.send_to(datagram, self.peer).map(|_| ())
+    }
+
+    fn recv(&mut self, deadline: Instant) -> io::Result<Option<Vec<u8>>> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.events.recv_timeout(timeout) {
+            Ok(Event::Datagram(datagram)) => Ok(Some(datagram)),
+            Ok(Event::Reply(id, reply)) => {
+                self.replies.push((id, reply));
+                Ok(None)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "dispatcher gone"))
+            }
+        }
+    }
+}
+
+/// Connection IDs -> the thread of their connection.
+type Routes = Arc<Mutex<HashMap<Vec<u8>, Sender<Event>>>>;
+
+/// Serves DoQ on `socket`, authenticated by `identity`. Datagrams are routed
+/// to a thread per connection by connection ID. `answer` gets the client's
+/// address and a query in wire format, and gives the reply's, None for
+/// malformed queries.
+pub fn spawn(
+    socket: UdpSocket,
+    identity: Identity,
+    answer: impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let identity = Arc::new(identity);
+    let answer = Arc::new(answer);
+    let routes = Routes::default();
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new().name("doq".into()).spawn(move || {
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            let datagram = &buf[..len];
+            let Some(header) = quic::parse_header(datagram, quic::CID_LEN) else {
+                continue;
+            };
+            if let Some(events) = routes.lock().unwrap().get(header.dcid) {
+                let _ = events.send(Event::Datagram(datagram.to_vec()));
+                continue;
+            }
+            // only a client's first Initial, padded in full, starts a
+            // connection
+            if header.kind.is_some() && header.version != quic::VERSION {
+                if len >= quic::MAX_DATAGRAM {
+                    let _ = socket.send_to(&quic::version_negotiation(&header), peer);
+                }
+                continue;
+            }
+            if !header.is_initial() || len < quic::MAX_DATAGRAM {
+                continue;
+            }
+            let conn = Connection::server(&header);
+            let (sender, events) = mpsc::channel();
+            {
+                let mut routes = routes.lock().unwrap();
+                routes.insert(conn.original_dcid().to_vec(), sender.clone());
+                routes.insert(conn.scid().to_vec(), sender.clone());
+            }
+            let overloaded = connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS;
+            let link = ServerLink {
+                socket: socket.clone(),
+                peer,
+                events,
+                replies: Vec::new(),
+            };
+            let first = datagram.to_vec();
+            let (identity, answer, routes, connections) = (
+                identity.clone(),
+                answer.clone(),
+                routes.clone(),
+                connections.clone(),
+            );
+            thread::spawn(move || {
+                let mut conn = conn;
+                let result = serve_conn(
+                    &mut conn, link, &first, sender, &identity, answer, overloaded,
+                );
+                if let Err(e) = result {
+                    tracing::warn!(
+                        client = %privacy::client(peer), error = %e,
+                        "QUIC connection failed"
+                    );
+                }
+                let mut routes = routes.lock().unwrap();
+                routes.remove(conn.original_dcid());
+                routes.remove(conn.scid());
+                connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    })?;
+    Ok(())
+}
+
+/// Answers the queries of a single connection until it closes or goes idle,
+/// each on its own thread.
+fn serve_conn(
+    conn: &mut Connection,
+    mut link: ServerLink,
+    first: &[u8],
+    sender: Sender<Event>,
+    identity: &Identity,
+    answer: Arc<impl Fn(SocketAddr, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static>,
+    overloaded: bool,
+) -> Result<()> {
+    conn.accept(&mut link, first, identity, ALPN, HANDSHAKE_TIMEOUT)?;
+    let source = link.peer;
+    if overloaded {
+        conn.close(EXCESSIVE_LOAD, "too many connections");
+        conn.flush(&mut link)?;
+        return Ok(());
+    }
+    loop {
+        for id in conn.incoming() {
+            // a reset stream was cancelled by the client
+            let Some(Ok(data)) = conn.read_stream(id) else {
+                continue;
+            };
+            let query = match query_message(&data) {
+                Ok(query) => query.to_vec(),
+                Err(reason) => {
+                    conn.close(PROTOCOL_ERROR, reason);
+                    break;
+                }
+            };
+            tracing::trace!(
+                stream = %id, client = %privacy::client(source),
+                "Received query over QUIC"
+            );
+            let (answer, sender) = (answer.clone(), sender.clone());
+            thread::spawn(move || {
+                let _ = sender.send(Event::Reply(id, answer(source, &query)));
+            });
+        }
+        for (id, reply) in link.replies.drain(..) {
+            match reply {
+                Some(reply) => match length_prefixed(&reply) {
+                    Ok(data) => conn.write_stream(id, &data, true),
+                    Err(_) => conn.cancel_stream(id, INTERNAL_ERROR),
+                },
+                None => conn.close(PROTOCOL_ERROR, "malformed query"),
+            }
+        }
+        // the idle timeout ends the connection before this deadline
+        if let Err(e) = conn.step(&mut link, Instant::now() + 2 * quic::IDLE_TIMEOUT) {
+            return match conn.closed() {
+                Some(_) => Ok(()),
+                None => Err(e),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{exchange, query_message, spawn, unprefixed, DoqClient, Url};
+    use crate::{
+        edns::{EdnsOption, Opt},
+        encoder::length_prefixed,
+        proto::{Class, Message, Name, Question, Type},
+        tls::{test::identity_files, Identity},
+        x509::{self, HostPins, Pin},
+    };
+    use std::{net::UdpSocket, time::Duration};
+
+    #[test]
+    fn test_url() {
+        let url: Url = "quic://dns.example".parse().unwrap();
+        assert_eq!(url.authority(), "dns.example:853");
+        assert_eq!(url.to_string(), "quic://dns.example");
+        let url: Url = "quic://[2001:db8::1]:8853/".parse().unwrap();
+        assert_eq!(url.authority(), "[2001:db8::1]:8853");
+        assert_eq!(url.to_string(), "quic://[2001:db8::1]:8853");
+        assert!("https://dns.example".parse::<Url>().is_err());
+        assert!("quic://dns.example/dns-query".parse::<Url>().is_err());
+        assert!("quic://dns.example:x".parse::<Url>().is_err());
+    }
+
+    #[test]
+    fn test_query_message() {
+        let mut query = Message {
+            id: 0,
+            questions: vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let bytes = query.to_bytes().unwrap();
+        assert_eq!(
+            query_message(&length_prefixed(&bytes).unwrap()),
+            Ok(&bytes[..])
+        );
+        assert!(query_message(&bytes).is_err());
+        assert!(query_message(&[length_prefixed(&bytes).unwrap(), vec![0]].concat()).is_err());
+        assert_eq!(unprefixed(&[0, 0]), Ok(&[][..]));
+        assert!(unprefixed(&[0]).is_err());
+
+        query.id = 7;
+        assert!(query_message(&length_prefixed(&query.to_bytes().unwrap()).unwrap()).is_err());
+        query.id = 0;
+        let mut opt = Opt::default();
+        opt.set_option(EdnsOption::KeepAlive(None));
+        query.opt = Some(opt);
+        assert!(query_message(&length_prefixed(&query.to_bytes().unwrap()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_exchange() {
+        let (cert, key) = identity_files("doq");
+        let identity = Identity::load(&cert, &key).unwrap();
+        let roots = x509::load_pem(&cert).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        spawn(socket, identity, |_, query| {
+            let mut reply = Message::from_bytes(query).unwrap().reply();
+            reply.id = 0;
+            reply.to_bytes().ok()
+        })
+        .unwrap();
+
+        // the certificate names dns.example, reached on the loopback address
+        let client = DoqClient::new(roots.clone(), HostPins::default());
+        let addr = ([127, 0, 0, 1], port).into();
+        let mut conn = client
+            .open("dns.example", addr, Duration::from_secs(5))
+            .unwrap();
+        for id in [1, 2] {
+            let query = Message {
+                id,
+                questions: vec![Question {
+                    name: Name::from("example.com"),
+                    qtype: Type::A,
+                    class: Class::IN,
+                }],
+                ..Message::default()
+            };
+            let bytes = Message {
+                id: 0,
+                ..query.clone()
+            }
+            .to_bytes()
+            .unwrap();
+            let reply = exchange(&mut conn, &bytes, Duration::from_secs(5)).unwrap();
+            let reply = Message::from_bytes(&reply).unwrap();
+            assert_eq!(reply.questions, query.questions);
+            assert_eq!(reply.qr, 1);
+        }
+
+        // pinned to another key, the server is refused
+        let pins = HostPins::from_iter([("dns.example".to_string(), vec![Pin::Spki([0; 32])])]);
+        let pinned = DoqClient::new(roots, pins);
+        assert!(pinned
+            .open("dns.example", addr, Duration::from_secs(5))
+            .is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! ECDSA signature verification (FIPS 186-4, section 6.4) on the NIST P-256
+//! curve with SHA-256, for DNSSEC algorithm 13 (RFC 6605), and on P-384 with
+//! SHA-384 for TLS certificates. Signing (section 6.3) is for TLS server
+//! keys. Points are kept in Jacobian coordinates to avoid an inversion per
+//! addition.
+
+use rand::RngCore;
+
+use crate::{
+    bignum::BigUint,
+    digest::{sha256, sha384},
+};
+
+/// Domain parameters of a curve y² = x³ - 3x + b, as hex.
+#[derive(Debug, PartialEq)]
+pub struct Curve {
+    p: &'static str,
+    n: &'static str,
+    b: &'static str,
+    gx: &'static str,
+    gy: &'static str,
+    // bytes of a coordinate
+    len: usize,
+}
+
+pub const P256: Curve = Curve {
+    p: "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+    n: "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+    b: "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b",
+    gx: "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+    gy: "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+    len: 32,
+};
+
+pub const P384: Curve = Curve {
+    p: "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe\
+        ffffffff0000000000000000ffffffff",
+    n: "ffffffffffffffffffffffffffffffffffffffffffffffffc7634d81f4372ddf\
+        581a0db248b0a77aecec196accc52973",
+    b: "b3312fa7e23ee7e4988e056be3f82d19181d9c6efe8141120314088f5013875a\
+        c656398d8a2ed19d2a85c8edd3ec2aef",
+    gx: "aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a38\
+         5502f25dbf55296c3a545e3872760ab7",
+    gy: "3617de4a96262c6f5d9e98bf9292dc29f8f41dbd289a147ce9da3113b5f0b8c0\
+         0a60b1ce1d7e819d7a431d7c90ea0e5f",
+    len: 48,
+};
+
+impl Curve {
+    /// Bytes of a coordinate, and of r and s.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    fn field(&self) -> Field {
+        Field {
+            p: BigUint::from_hex(self.p),
+            b: BigUint::from_hex(self.b),
+        }
+    }
+
+    fn generator(&self) -> Point {
+        Point {
+            x: BigUint::from_hex(self.gx),
+            y: BigUint::from_hex(self.gy),
+            z: BigUint::from_u32(1),
+        }
+    }
+}
+
+/// Point in Jacobian coordinates, (X/Z², Y/Z³), the point at infinity has
+/// Z = 0.
+#[derive(Debug, Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+/// Arithmetic modulo the field prime.
+struct Field {
+    p: BigUint,
+    b: BigUint,
+}
+
+impl Field {
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        a.mul(b).rem(&self.p)
+    }
+
+    fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        a.add(b).rem(&self.p)
+    }
+
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        a.add(&self.p).sub(b).rem(&self.p)
+    }
+
+    fn small(&self, k: u32, a: &BigUint) -> BigUint {
+        self.mul(&BigUint::from_u32(k), a)
+    }
+
+    fn inv(&self, a: &BigUint) -> BigUint {
+        a.mod_pow(&self.p.sub(&BigUint::from_u32(2)), &self.p)
+    }
+
+    fn infinity() -> Point {
+        Point {
+            x: BigUint::from_u32(1),
+            y: BigUint::from_u32(1),
+            z: BigUint::default(),
+        }
+    }
+
+    /// 2P, for a = -3 ("dbl-2001-b").
+    fn double(&self, pt: &Point) -> Point {
+        if pt.z.is_zero() || pt.y.is_zero() {
+            return Self::infinity();
+        }
+        let delta = self.mul(&pt.z, &pt.z);
+        let gamma = self.mul(&pt.y, &pt.y);
+        let beta = self.mul(&pt.x, &gamma);
+        let alpha = self.small(
+            3,
+            &self.mul(&self.sub(&pt.x, &delta), &self.add(&pt.x, &delta)),
+        );
+        let x = self.sub(&self.mul(&alpha, &alpha), &self.small(8, &beta));
+        let yz = self.add(&pt.y, &pt.z);
+        let z = self.sub(&self.sub(&self.mul(&yz, &yz), &gamma), &delta);
+        let gamma2 = self.mul(&gamma, &gamma);
+        let y = self.sub(
+            &self.mul(&alpha, &self.sub(&self.small(4, &beta), &x)),
+            &self.small(8, &gamma2),
+        );
+        Point { x, y, z }
+    }
+
+    /// P + Q.
+    fn add_points(&self, a: &Point, b: &Point) -> Point {
+        if a.z.is_zero() {
+            return b.clone();
+        }
+        if b.z.is_zero() {
+            return a.clone();
+        }
+        let z1z1 = self.mul(&a.z, &a.z);
+        let z2z2 = self.mul(&b.z, &b.z);
+        let u1 = self.mul(&a.x, &z2z2);
+        let u2 = self.mul(&b.x, &z1z1);
+        let s1 = self.mul(&a.y, &self.mul(&b.z, &z2z2));
+        let s2 = self.mul(&b.y, &self.mul(&a.z, &z1z1));
+        let h = self.sub(&u2, &u1);
+        let r = self.sub(&s2, &s1);
+        if h.is_zero() {
+            return match r.is_zero() {
+                true => self.double(a),
+                false => Self::infinity(),
+            };
+        }
+        let h2 = self.mul(&h, &h);
+        let h3 = self.mul(&h2, &h);
+        let u1h2 = self.mul(&u1, &h2);
+        let x = self.sub(&self.sub(&self.mul(&r, &r), &h3), &self.small(2, &u1h2));
+        let y = self.sub(&self.mul(&r, &self.sub(&u1h2, &x)), &self.mul(&s1, &h3));
+        let z = self.mul(&self.mul(&a.z, &b.z), &h);
+        Point { x, y, z }
+    }
+
+    /// u1·G + u2·Q, both scalars processed in one pass (Shamir's trick).
+    fn mul_add(&self, u1: &BigUint, g: &Point, u2: &BigUint, q: &Point) -> Point {
+        let gq = self.add_points(g, q);
+        let mut acc = Self::infinity();
+        for i in (0..u1.bits().max(u2.bits())).rev() {
+            acc = self.double(&acc);
+            acc = match (u1.bit(i), u2.bit(i)) {
+                (true, true) => self.add_points(&acc, &gq),
+                (true, false) => self.add_points(&acc, g),
+                (false, true) => self.add_points(&acc, q),
+                (false, false) => acc,
+            };
+        }
+        acc
+    }
+
+    /// k·P, by double and add.
+    fn scalar_mul(&self, k: &BigUint, pt: &Point) -> Point {
+        self.mul_add(k, pt, &BigUint::default(), pt)
+    }
+
+    /// Affine x coordinate, None for the point at infinity.
+    fn affine_x(&self, pt: &Point) -> Option<BigUint> {
+        if pt.z.is_zero() {
+            return None;
+        }
+        let zinv = self.inv(&pt.z);
+        Some(self.mul(&pt.x, &self.mul(&zinv, &zinv)))
+    }
+
+    /// Affine x and y, None for the point at infinity.
+    fn affine(&self, pt: &Point) -> Option<(BigUint, BigUint)> {
+        let x = self.affine_x(pt)?;
+        let zinv = self.inv(&pt.z);
+        Some((
+            x,
+            self.mul(&pt.y, &self.mul(&zinv, &self.mul(&zinv, &zinv))),
+        ))
+    }
+
+    /// Whether (x, y) satisfies y² = x³ - 3x + b.
+    fn on_curve(&self, x: &BigUint, y: &BigUint) -> bool {
+        let x3 = self.mul(&self.mul(x, x), x);
+        let rhs = self.add(&self.sub(&x3, &self.small(3, x)), &self.b);
+        *x < self.p && *y < self.p && self.mul(y, y) == rhs
+    }
+}
+
+/// Whether `signature`, r and s of the coordinate length each, is a valid
+/// signature of `data` under `public_key`, the uncompressed point's x and y
+/// (RFC 6605, section 4). P-256 signs SHA-256 digests, P-384 SHA-384 ones.
+pub fn verify(curve: &Curve, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match curve.len {
+        32 => verify_digest(curve, public_key, &sha256(data), signature),
+        _ => verify_digest(curve, public_key, &sha384(data), signature),
+    }
+}
+
+/// Like `verify`, for a digest already made. Digests longer than the order
+/// are cut to its length.
+pub fn verify_digest(curve: &Curve, public_key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+    let len = curve.len;
+    if public_key.len() != 2 * len || signature.len() != 2 * len {
+        return false;
+    }
+    let field = curve.field();
+    let n = BigUint::from_hex(curve.n);
+
+    let qx = BigUint::from_be_bytes(&public_key[..len]);
+    let qy = BigUint::from_be_bytes(&public_key[len..]);
+    if !field.on_curve(&qx, &qy) {
+        return false;
+    }
+    let r = BigUint::from_be_bytes(&signature[..len]);
+    let s = BigUint::from_be_bytes(&signature[len..]);
+    if r.is_zero() || s.is_zero() || r >= n || s >= n {
+        return false;
+    }
+
+    // both orders are whole bytes long
+    let e = BigUint::from_be_bytes(&digest[..digest.len().min(len)]);
+    let w = s.mod_pow(&n.sub(&BigUint::from_u32(2)), &n);
+    let u1 = e.mul(&w).rem(&n);
+    let u2 = r.mul(&w).rem(&n);
+    let q = Point {
+        x: qx,
+        y: qy,
+        z: BigUint::from_u32(1),
+    };
+    match field.affine_x(&field.mul_add(&u1, &curve.generator(), &u2, &q)) {
+        Some(x) => x.rem(&n) == r,
+        None => false,
+    }
+}
+
+/// Public key of the private scalar `private`, the uncompressed point's x
+/// and y. None if the scalar is out of range.
+pub fn public_key(curve: &Curve, private: &[u8]) -> Option<Vec<u8>> {
+    let d = BigUint::from_be_bytes(private);
+    if d.is_zero() || d >= BigUint::from_hex(curve.n) {
+        return None;
+    }
+    let field = curve.field();
+    let (x, y) = field.affine(&field.scalar_mul(&d, &curve.generator()))?;
+    Some([x.to_be_bytes(curve.len), y.to_be_bytes(curve.len)].concat())
+}
+
+/// Signature of `digest` under the private scalar `private`, r and s of the
+/// coordinate length each, with a random nonce.
+pub fn sign_digest(curve: &Curve, private: &[u8], digest: &[u8]) -> Vec<u8> {
+    let len = curve.len;
+    let field = curve.field();
+    let n = BigUint::from_hex(curve.n);
+    let d = BigUint::from_be_bytes(private);
+    let e = BigUint::from_be_bytes(&digest[..digest.len().min(len)]);
+    let mut rng = rand::thread_rng();
+    loop {
+        let mut bytes = vec![0u8; len];
+        rng.fill_bytes(&mut bytes);
+        let k = BigUint::from_be_bytes(&bytes);
+        if k.is_zero() || k >= n {
+            continue;
+        }
+        let Some(x) = field.affine_x(&field.scalar_mul(&k, &curve.generator())) else {
+            continue;
+        };
+        let r = x.rem(&n);
+        let kinv = k.mod_pow(&n.sub(&BigUint::from_u32(2)), &n);
+        let s = kinv.mul(&e.add(&r.mul(&d))).rem(&n);
+        if !r.is_zero() && !s.is_zero() {
+            return [r.to_be_bytes(len), s.to_be_bytes(len)].concat();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{public_key, sign_digest, verify, verify_digest, P256, P384};
+    use crate::digest::sha256;
+    use crate::encoding::hex_decode;
+
+    // key and signature of "test data" made with OpenSSL
+    const PUBLIC_KEY: &str = "\
+        8f5f7ac7763bd01c6f76c674a0c218943274dd21bdf45bd3edd7b06fb3c9eff0\
+        40d0038a9c464faf50fb11cc7a5e5eac64e761170ca6807c4d12d91977586094";
+    const SIGNATURE: &str = "\
+        106d79ba8350a29cb95e8c6d09769e2fa8d6dac1016873cf4dc7c5f288240d74\
+        58d37704e81359abaadbc8a13668a26e6b63ea58a860b54c05a38b2df736d054";
+    const PUBLIC_KEY_384: &str = "\
+        71ad3fef663e0689a25185bd9f62fc0486346a4f50fd07f73d0778b0aecfd3802de01eda03618739\
+        d7a20e1b9533bfcf4679c287624b5c881bd3931fddd308dc9de9383afb5bb8aefa9e29ec7db3c60b\
+        b7b330f4ea8b3ddae5d2241b2cd5f26c";
+    const SIGNATURE_384: &str = "\
+        6f1042ebf6c7c8e6e5a67f7e8a851805bcba0ab8ba8d287e113eb95dbaf494040e38744169eee423\
+        ab15e41b2a249336a469757bf6100fc9886b3c247dd2241d834dadba65976121cf2c6e83c1ae81a2\
+        2f98e9f485dd2666dcf84d764376cca8";
+
+    #[test]
+    fn test_verify() {
+        let key = hex_decode(PUBLIC_KEY).unwrap();
+        let sig = hex_decode(SIGNATURE).unwrap();
+        assert!(verify(&P256, &key, b"test data", &sig));
+        assert!(!verify(&P256, &key, b"test date", &sig));
+        let mut bad = sig.clone();
+        bad[40] ^= 1;
+        assert!(!verify(&P256, &key, b"test data", &bad));
+        // not a point on the curve
+        let mut off = key.clone();
+        off[63] ^= 1;
+        assert!(!verify(&P256, &off, b"test data", &sig));
+        assert!(!verify(&P256, &key, b"test data", &[0; 64]));
+    }
+
+    #[test]
+    fn test_verify_p384() {
+        let key = hex_decode(PUBLIC_KEY_384).unwrap();
+        let sig = hex_decode(SIGNATURE_384).unwrap();
+        assert!(verify(&P384, &key, b"test data", &sig));
+        assert!(!verify(&P384, &key, b"test date", &sig));
+        assert!(!verify(&P256, &key, b"test data", &sig));
+
+        let key = hex_decode(PUBLIC_KEY).unwrap();
+        let sig = hex_decode(SIGNATURE).unwrap();
+        assert!(verify_digest(&P256, &key, &sha256(b"test data"), &sig));
+    }
+
+    #[test]
+    fn test_sign() {
+        let private = [7u8; 32];
+        let key = public_key(&P256, &private).unwrap();
+        let digest = sha256(b"test data");
+        let sig = sign_digest(&P256, &private, &digest);
+        assert!(verify_digest(&P256, &key, &digest, &sig));
+        assert!(!verify_digest(&P256, &key, &sha256(b"test date"), &sig));
+        // fresh nonces
+        assert_ne!(sig, sign_digest(&P256, &private, &digest));
+        assert_eq!(None, public_key(&P256, &[0; 32]));
+    }
+}
@@ -0,0 +1,273 @@
+//! EDNS Client Subnet (RFC 7871). Upstreams serving different answers by
+//! location see the client's network instead of ours, at the cost of
+//! telling them where clients are.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::{
+    edns::{ClientSubnet, EdnsOption},
+    handler::{Context, Next, RequestHandler},
+    proto::Message,
+};
+
+/// Prefix lengths of client addresses sent upstream by default, the ones
+/// recommended in RFC 7871, section 11.1.
+pub const IPV4_PREFIX: u8 = 24;
+pub const IPV6_PREFIX: u8 = 56;
+
+/// What queries sent upstream say about the client's subnet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum EcsMode {
+    /// Whatever subnet the client sent, if any
+    #[default]
+    Pass,
+    /// The client's subnet, from its address unless it sent one, truncated
+    Add,
+    /// Nothing, for privacy
+    Strip,
+}
+
+impl FromStr for EcsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(Self::Pass),
+            "add" => Ok(Self::Add),
+            "strip" => Ok(Self::Strip),
+            _ => Err(format!("unknown ECS mode {:?}", s)),
+        }
+    }
+}
+
+/// `addr` with the bits past `prefix` cleared, as RFC 7871 section 6
+/// requires of the address in the option.
+pub fn truncate(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix.min(32)));
+            Ipv4Addr::from(u32::from(v4) & mask.unwrap_or(0)).into()
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix.min(128)));
+            Ipv6Addr::from(u128::from(v6) & mask.unwrap_or(0)).into()
+        }
+    }
+}
+
+/// Sets the client subnet of requests on their way upstream, and gives
+/// clients back the subnet they sent with the scope the answer holds for.
+pub struct EcsHandler {
+    mode: EcsMode,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl EcsHandler {
+    /// Handler in `mode`, sending at most `ipv4_prefix` and `ipv6_prefix`
+    /// bits of client addresses upstream when adding subnets.
+    pub fn new(mode: EcsMode, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            mode,
+            ipv4_prefix: ipv4_prefix.min(32),
+            ipv6_prefix: ipv6_prefix.min(128),
+        }
+    }
+
+    fn max_prefix(&self, addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        }
+    }
+
+    /// The subnet to send upstream for a client at `source` that sent
+    /// `sent`, if any.
+    fn upstream_subnet(&self, source: IpAddr, sent: Option<&ClientSubnet>) -> Option<ClientSubnet> {
+        match self.mode {
+            EcsMode::Pass => sent.cloned(),
+            EcsMode::Strip => None,
+            // a source prefix of 0 asks for no subnet to be used (section 7.1.2)
+            EcsMode::Add => match sent {
+                Some(sent) if sent.source_prefix == 0 => Some(sent.clone()),
+                Some(sent) => {
+                    let prefix = sent.source_prefix.min(self.max_prefix(sent.address));
+                    Some(ClientSubnet {
+                        source_prefix: prefix,
+                        scope_prefix: 0,
+                        address: truncate(sent.address, prefix),
+                    })
+                }
+                None => {
+                    let address = source.to_canonical();
+                    let prefix = self.max_prefix(address);
+                    Some(ClientSubnet {
+                        source_prefix: prefix,
+                        scope_prefix: 0,
+                        address: truncate(address, prefix),
+                    })
+                }
+            },
+        }
+    }
+}
+
+impl RequestHandler for EcsHandler {
+    fn handle(&self, ctx: &Context, mut request: Message, next: Next<'_>) -> Result<Message> {
+        let sent = request
+            .opt
+            .as_ref()
+            .and_then(|opt| opt.client_subnet())
+            .cloned();
+        // only EDNS requests have anywhere to put the option
+        if let Some(opt) = request.opt.as_mut() {
+            match self.upstream_subnet(ctx.source.ip(), sent.as_ref()) {
+                Some(subnet) => opt.set_option(EdnsOption::ClientSubnet(subnet)),
+                None => opt.remove_option(EdnsOption::CLIENT_SUBNET),
+            }
+        }
+
+        let mut reply = next.run(ctx, request)?;
+        if let Some(opt) = reply.opt.as_mut() {
+            let scope = opt.client_subnet().map_or(0, |ecs| ecs.scope_prefix);
+            match sent {
+                Some(sent) => opt.set_option(EdnsOption::ClientSubnet(ClientSubnet {
+                    scope_prefix: scope.min(sent.source_prefix),
+                    ..sent
+                })),
+                None => opt.remove_option(EdnsOption::CLIENT_SUBNET),
+            }
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{truncate, EcsHandler, EcsMode};
+    use crate::{
+        edns::{ClientSubnet, EdnsOption, Opt},
+        handler::{Chain, Context, Next, RequestHandler, Transport},
+        proto::{Message, Type},
+    };
+    use anyhow::Result;
+    use std::{
+        net::IpAddr,
+        sync::{Arc, Mutex},
+    };
+
+    // answers for a /16 and remembers the subnet it was asked about
+    struct Upstream(Arc<Mutex<Option<ClientSubnet>>>);
+
+    impl RequestHandler for Upstream {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            let subnet = request
+                .opt
+                .as_ref()
+                .and_then(|opt| opt.client_subnet())
+                .cloned();
+            *self.0.lock().unwrap() = subnet.clone();
+            let mut reply = request.reply();
+            if let (Some(subnet), Some(opt)) = (subnet, reply.opt.as_mut()) {
+                opt.set_option(EdnsOption::ClientSubnet(ClientSubnet {
+                    scope_prefix: 16,
+                    ..subnet
+                }));
+            }
+            Ok(reply)
+        }
+    }
+
+    fn subnet(address: &str, source_prefix: u8) -> ClientSubnet {
+        ClientSubnet {
+            source_prefix,
+            scope_prefix: 0,
+            address: address.parse().unwrap(),
+        }
+    }
+
+    fn request(subnet: Option<ClientSubnet>) -> Message {
+        let mut opt = Opt {
+            udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+            ..Opt::default()
+        };
+        opt.options.extend(subnet.map(EdnsOption::ClientSubnet));
+        Message {
+            opt: Some(opt),
+            ..crate::proto::test::request("www.example", Type::A)
+        }
+    }
+
+    /// The subnet sent upstream and the one the client got back.
+    fn run(
+        mode: EcsMode,
+        sent: Option<ClientSubnet>,
+    ) -> (Option<ClientSubnet>, Option<ClientSubnet>) {
+        let upstream = Arc::new(Mutex::new(None));
+        let chain = Chain::default()
+            .with(EcsHandler::new(mode, 24, 56))
+            .with(Upstream(upstream.clone()));
+        let ctx = Context {
+            source: "198.51.100.77:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let reply = chain.handle(&ctx, request(sent)).unwrap();
+        let upstream = upstream.lock().unwrap().clone();
+        let returned = reply.opt.unwrap().client_subnet().cloned();
+        (upstream, returned)
+    }
+
+    #[test]
+    fn test_truncate() {
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(addr("192.0.2.0"), truncate(addr("192.0.2.77"), 24));
+        assert_eq!(addr("192.0.0.0"), truncate(addr("192.0.2.77"), 22));
+        assert_eq!(addr("0.0.0.0"), truncate(addr("192.0.2.77"), 0));
+        assert_eq!(addr("192.0.2.77"), truncate(addr("192.0.2.77"), 32));
+        assert_eq!(
+            addr("2001:db8:aa00::"),
+            truncate(addr("2001:db8:aabb::1"), 40)
+        );
+    }
+
+    #[test]
+    fn test_modes() {
+        // passed through as is
+        let (upstream, returned) = run(EcsMode::Pass, Some(subnet("192.0.2.77", 32)));
+        assert_eq!(Some(subnet("192.0.2.77", 32)), upstream);
+        assert_eq!(16, returned.unwrap().scope_prefix);
+        assert_eq!((None, None), run(EcsMode::Pass, None));
+
+        // added from the client address, but not given back
+        let (upstream, returned) = run(EcsMode::Add, None);
+        assert_eq!(Some(subnet("198.51.100.0", 24)), upstream);
+        assert_eq!(None, returned);
+
+        // truncated, the client gets its own back
+        let (upstream, returned) = run(EcsMode::Add, Some(subnet("192.0.2.77", 32)));
+        assert_eq!(Some(subnet("192.0.2.0", 24)), upstream);
+        assert_eq!(
+            Some(ClientSubnet {
+                scope_prefix: 16,
+                ..subnet("192.0.2.77", 32)
+            }),
+            returned
+        );
+
+        // opting out is respected
+        let (upstream, _) = run(EcsMode::Add, Some(subnet("0.0.0.0", 0)));
+        assert_eq!(Some(subnet("0.0.0.0", 0)), upstream);
+
+        // nothing leaves, the client is told the answer isn't tailored
+        let (upstream, returned) = run(EcsMode::Strip, Some(subnet("192.0.2.77", 32)));
+        assert_eq!(None, upstream);
+        assert_eq!(0, returned.unwrap().scope_prefix);
+    }
+}
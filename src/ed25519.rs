@@ -0,0 +1,390 @@
+//! Ed25519 signatures (RFC 8032), for signing zones with DNSSEC algorithm 15
+//! (RFC 8080). Field elements are 16 limbs of 16 bits, after TweetNaCl.
+
+use crate::digest::sha512;
+
+pub type Gf = [i64; 16];
+
+pub const GF0: Gf = [0; 16];
+pub const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+// curve constant d = -121665/121666
+const D: Gf = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+    0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+const D2: Gf = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+    0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+// base point
+const X: Gf = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+    0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const Y: Gf = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666,
+];
+// square root of -1
+const I: Gf = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+    0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+
+// order of the base point, little-endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Public key of the private key `seed`.
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    let (scalar, _) = expand(seed);
+    pack(&scalar_base(&scalar))
+}
+
+/// Signature of `message` under the private key `seed`.
+pub fn sign(seed: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let (scalar, prefix) = expand(seed);
+    let public = pack(&scalar_base(&scalar));
+
+    let r = reduce(&hash(&[&prefix, message]));
+    let big_r = pack(&scalar_base(&r));
+    let h = reduce(&hash(&[&big_r, &public, message]));
+
+    let mut x = [0i64; 64];
+    for (x, r) in x.iter_mut().zip(r) {
+        *x = r as i64;
+    }
+    for (i, h) in h.iter().enumerate() {
+        for (j, s) in scalar.iter().enumerate() {
+            x[i + j] += *h as i64 * *s as i64;
+        }
+    }
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&mod_l(&mut x));
+    signature
+}
+
+/// Whether `signature` is a valid signature of `message` under `public`.
+pub fn verify(public: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(signature) = <[u8; 64]>::try_from(signature) else {
+        return false;
+    };
+    let Some(mut q) = unpack_neg(public) else {
+        return false;
+    };
+    let h = reduce(&hash(&[&signature[..32], public, message]));
+    let mut p = scalar_mult(&mut q, &h);
+    let s: [u8; 32] = signature[32..].try_into().unwrap();
+    add(&mut p, &scalar_base(&s));
+    pack(&p)[..] == signature[..32]
+}
+
+/// Clamped scalar and nonce prefix of a private key (RFC 8032, section
+/// 5.1.5).
+fn expand(seed: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let d = sha512(seed);
+    let mut scalar: [u8; 32] = d[..32].try_into().unwrap();
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    (scalar, d[32..].try_into().unwrap())
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 64] {
+    sha512(&parts.concat())
+}
+
+fn reduce(h: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (x, h) in x.iter_mut().zip(h) {
+        *x = *h as i64;
+    }
+    mod_l(&mut x)
+}
+
+/// `x` modulo the group order.
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        for j in (i - 32)..(i - 12) {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+        }
+        x[i - 12] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+fn carry(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swaps `p` and `q` if `b` is 1, in constant time.
+pub fn select(p: &mut Gf, q: &mut Gf, b: i64) {
+    let mask = !(b - 1);
+    for i in 0..16 {
+        let t = mask & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+pub fn pack_gf(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m = GF0;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = (t[i] & 0xff) as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+pub fn unpack_gf(n: &[u8; 32]) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn neq(a: &Gf, b: &Gf) -> bool {
+    pack_gf(a) != pack_gf(b)
+}
+
+fn parity(a: &Gf) -> u8 {
+    pack_gf(a)[0] & 1
+}
+
+pub fn fadd(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+pub fn fsub(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+pub fn fmul(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+pub fn fsquare(a: &Gf) -> Gf {
+    fmul(a, a)
+}
+
+pub fn finv(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=253).rev() {
+        c = fsquare(&c);
+        if a != 2 && a != 4 {
+            c = fmul(&c, i);
+        }
+    }
+    c
+}
+
+/// `i` to the power (p - 5) / 8, for square roots.
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=250).rev() {
+        c = fsquare(&c);
+        if a != 1 {
+            c = fmul(&c, i);
+        }
+    }
+    c
+}
+
+// extended coordinates X, Y, Z, T
+type Point = [Gf; 4];
+
+fn add(p: &mut Point, q: &Point) {
+    let a = fmul(&fsub(&p[1], &p[0]), &fsub(&q[1], &q[0]));
+    let b = fmul(&fadd(&p[0], &p[1]), &fadd(&q[0], &q[1]));
+    let c = fmul(&fmul(&p[3], &q[3]), &D2);
+    let d = fmul(&p[2], &q[2]);
+    let d = fadd(&d, &d);
+    let e = fsub(&b, &a);
+    let f = fsub(&d, &c);
+    let g = fadd(&d, &c);
+    let h = fadd(&b, &a);
+    p[0] = fmul(&e, &f);
+    p[1] = fmul(&h, &g);
+    p[2] = fmul(&g, &f);
+    p[3] = fmul(&e, &h);
+}
+
+fn cswap(p: &mut Point, q: &mut Point, b: i64) {
+    for i in 0..4 {
+        select(&mut p[i], &mut q[i], b);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = finv(&p[2]);
+    let tx = fmul(&p[0], &zi);
+    let ty = fmul(&p[1], &zi);
+    let mut r = pack_gf(&ty);
+    r[31] ^= parity(&tx) << 7;
+    r
+}
+
+fn scalar_mult(q: &mut Point, s: &[u8; 32]) -> Point {
+    let mut p = [GF0, GF1, GF1, GF0];
+    for i in (0..256).rev() {
+        let b = ((s[i / 8] >> (i & 7)) & 1) as i64;
+        cswap(&mut p, q, b);
+        add(q, &p);
+        let p2 = p;
+        add(&mut p, &p2);
+        cswap(&mut p, q, b);
+    }
+    p
+}
+
+fn scalar_base(s: &[u8; 32]) -> Point {
+    let mut q = [X, Y, GF1, fmul(&X, &Y)];
+    scalar_mult(&mut q, s)
+}
+
+/// The negated point of an encoded public key, `None` if it isn't on the
+/// curve.
+fn unpack_neg(p: &[u8; 32]) -> Option<Point> {
+    let mut r = [GF0, unpack_gf(p), GF1, GF0];
+    let num = fsquare(&r[1]);
+    let den = fmul(&num, &D);
+    let num = fsub(&num, &r[2]);
+    let den = fadd(&r[2], &den);
+
+    let den2 = fsquare(&den);
+    let den4 = fsquare(&den2);
+    let den6 = fmul(&den4, &den2);
+    let t = fmul(&fmul(&den6, &num), &den);
+    let t = fmul(&fmul(&pow2523(&t), &num), &den);
+    r[0] = fmul(&fmul(&t, &den), &den);
+
+    if neq(&fmul(&fsquare(&r[0]), &den), &num) {
+        r[0] = fmul(&r[0], &I);
+    }
+    if neq(&fmul(&fsquare(&r[0]), &den), &num) {
+        return None;
+    }
+    if parity(&r[0]) == p[31] >> 7 {
+        r[0] = fsub(&GF0, &r[0]);
+    }
+    r[3] = fmul(&r[0], &r[1]);
+    Some(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{public_key, sign, verify};
+    use crate::encoding::{hex_decode, hex_encode};
+
+    #[test]
+    fn test_rfc8032_vectors() {
+        // RFC 8032, section 7.1, tests 1 and 2
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, public, message, signature) in vectors {
+            let seed: [u8; 32] = hex_decode(seed).unwrap().try_into().unwrap();
+            let message = hex_decode(message).unwrap();
+            assert_eq!(public, hex_encode(&public_key(&seed)));
+            let signed = sign(&seed, &message);
+            assert_eq!(signature, hex_encode(&signed));
+            assert!(verify(&public_key(&seed), &message, &signed));
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects() {
+        let seed = [7u8; 32];
+        let public = public_key(&seed);
+        let mut signature = sign(&seed, b"example.com");
+        assert!(verify(&public, b"example.com", &signature));
+        assert!(!verify(&public, b"example.net", &signature));
+        assert!(!verify(&public, b"example.com", &signature[..63]));
+        signature[10] ^= 1;
+        assert!(!verify(&public, b"example.com", &signature));
+    }
+}
@@ -0,0 +1,385 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{
+    encoder::{Decoder, Encoder, Error},
+    proto::Type,
+};
+
+/// Extended DNS error info codes (RFC 8914, section 4).
+pub mod ede {
+    pub const OTHER: u16 = 0;
+    pub const UNSUPPORTED_DNSKEY_ALGORITHM: u16 = 1;
+    pub const UNSUPPORTED_DS_DIGEST_TYPE: u16 = 2;
+    pub const DNSSEC_BOGUS: u16 = 6;
+    pub const SIGNATURE_EXPIRED: u16 = 7;
+    pub const SIGNATURE_NOT_YET_VALID: u16 = 8;
+    pub const DNSKEY_MISSING: u16 = 9;
+    pub const RRSIGS_MISSING: u16 = 10;
+    pub const NSEC_MISSING: u16 = 12;
+}
+
+/// A single EDNS0 option from the OPT RDATA. Options without a dedicated
+/// variant keep their code and raw data.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EdnsOption {
+    // Name server identifier (RFC 5001), empty in queries
+    Nsid(Vec<u8>),
+
+    // DNS cookie (RFC 7873), 8 byte client cookie and 8 to 32 byte server cookie
+    Cookie {
+        client: [u8; 8],
+        server: Option<Vec<u8>>,
+    },
+
+    // Client subnet (RFC 7871)
+    ClientSubnet(ClientSubnet),
+
+    // Padding (RFC 7830), number of padding bytes
+    Padding(usize),
+
+    // TCP keepalive (RFC 7828), idle timeout in units of 100ms, empty in queries
+    KeepAlive(Option<u16>),
+
+    // Extended DNS error (RFC 8914), info code and optional UTF-8 text
+    ExtendedError(u16, String),
+
+    Unknown(u16, Vec<u8>),
+}
+
+/// EDNS Client Subnet option data (RFC 7871, section 6).
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ClientSubnet {
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+    // only the first `source_prefix` bits are significant
+    pub address: IpAddr,
+}
+
+type OptionDecoder = fn(&[u8]) -> Option<EdnsOption>;
+
+/// Option codes with a typed representation, from the IANA "DNS EDNS0
+/// Option Codes (OPT)" registry. Codes not listed decode to `Unknown`.
+const REGISTRY: &[(u16, OptionDecoder)] = &[
+    (EdnsOption::NSID, decode_nsid),
+    (EdnsOption::CLIENT_SUBNET, decode_client_subnet),
+    (EdnsOption::COOKIE, decode_cookie),
+    (EdnsOption::KEEPALIVE, decode_keepalive),
+    (EdnsOption::PADDING, decode_padding),
+    (EdnsOption::EXTENDED_ERROR, decode_extended_error),
+];
+
+fn decode_nsid(data: &[u8]) -> Option<EdnsOption> {
+    Some(EdnsOption::Nsid(data.to_vec()))
+}
+
+fn decode_cookie(data: &[u8]) -> Option<EdnsOption> {
+    let client = data.get(..8)?.try_into().ok()?;
+    let server = match data.len() {
+        8 => None,
+        16..=40 => Some(data[8..].to_vec()),
+        _ => return None,
+    };
+    Some(EdnsOption::Cookie { client, server })
+}
+
+fn decode_client_subnet(data: &[u8]) -> Option<EdnsOption> {
+    let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    let source_prefix = *data.get(2)?;
+    let scope_prefix = *data.get(3)?;
+    let addr = &data[4..];
+    // the address is truncated to the bytes covering the source prefix
+    if addr.len() != (source_prefix as usize).div_ceil(8) {
+        return None;
+    }
+
+    let address = match family {
+        1 if source_prefix <= 32 => {
+            let mut octets = [0u8; 4];
+            octets[..addr.len()].copy_from_slice(addr);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        2 if source_prefix <= 128 => {
+            let mut octets = [0u8; 16];
+            octets[..addr.len()].copy_from_slice(addr);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(EdnsOption::ClientSubnet(ClientSubnet {
+        source_prefix,
+        scope_prefix,
+        address,
+    }))
+}
+
+fn decode_keepalive(data: &[u8]) -> Option<EdnsOption> {
+    match data {
+        [] => Some(EdnsOption::KeepAlive(None)),
+        [hi, lo] => Some(EdnsOption::KeepAlive(Some(u16::from_be_bytes([*hi, *lo])))),
+        _ => None,
+    }
+}
+
+fn decode_padding(data: &[u8]) -> Option<EdnsOption> {
+    Some(EdnsOption::Padding(data.len()))
+}
+
+fn decode_extended_error(data: &[u8]) -> Option<EdnsOption> {
+    let code = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    // the text may be NUL terminated by mistake (RFC 8914, section 2)
+    let text = String::from_utf8_lossy(&data[2..]);
+    Some(EdnsOption::ExtendedError(
+        code,
+        text.trim_end_matches('\0').to_string(),
+    ))
+}
+
+impl EdnsOption {
+    pub const NSID: u16 = 3;
+    pub const CLIENT_SUBNET: u16 = 8;
+    pub const COOKIE: u16 = 10;
+    pub const KEEPALIVE: u16 = 11;
+    pub const PADDING: u16 = 12;
+    pub const EXTENDED_ERROR: u16 = 15;
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Nsid(_) => Self::NSID,
+            Self::Cookie { .. } => Self::COOKIE,
+            Self::ClientSubnet(_) => Self::CLIENT_SUBNET,
+            Self::Padding(_) => Self::PADDING,
+            Self::KeepAlive(_) => Self::KEEPALIVE,
+            Self::ExtendedError(..) => Self::EXTENDED_ERROR,
+            Self::Unknown(code, _) => *code,
+        }
+    }
+
+    /// Option data, without the code and length.
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            Self::Nsid(data) => data.clone(),
+            Self::Cookie { client, server } => {
+                let mut data = client.to_vec();
+                data.extend(server.iter().flatten());
+                data
+            }
+            Self::ClientSubnet(ecs) => {
+                let (family, octets) = match ecs.address {
+                    IpAddr::V4(v4) => (1u16, v4.octets().to_vec()),
+                    IpAddr::V6(v6) => (2u16, v6.octets().to_vec()),
+                };
+                let mut data = family.to_be_bytes().to_vec();
+                data.push(ecs.source_prefix);
+                data.push(ecs.scope_prefix);
+                data.extend_from_slice(&octets[..(ecs.source_prefix as usize).div_ceil(8)]);
+                data
+            }
+            Self::Padding(len) => vec![0u8; *len],
+            Self::KeepAlive(timeout) => timeout
+                .map(|t| t.to_be_bytes().to_vec())
+                .unwrap_or_default(),
+            Self::ExtendedError(code, text) => [&code.to_be_bytes(), text.as_bytes()].concat(),
+            Self::Unknown(_, data) => data.clone(),
+        }
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_u16(self.code());
+        enc.write_length_prefixed(&self.data())
+    }
+
+    /// Decodes an option through the registry, malformed data for a known
+    /// code is an error (FORMERR, e.g. RFC 7871 section 7.1.1).
+    pub fn decode(code: u16, data: &[u8]) -> Result<Self, Error> {
+        match REGISTRY.iter().find(|(c, _)| *c == code) {
+            Some((_, decode)) => decode(data).ok_or(Error::InvalidEdnsOption(code)),
+            None => Ok(Self::Unknown(code, data.to_vec())),
+        }
+    }
+}
+
+/// EDNS0 OPT pseudo-record (RFC 6891, section 6.1). The fixed RR fields are
+/// repurposed: CLASS holds the requestor's UDP payload size and TTL holds
+/// the extended RCODE, version and flags.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Opt {
+    // Requestor's UDP payload size, 16 bits
+    pub udp_payload_size: u16,
+
+    // Extended RCODE, 8 bits
+    // Upper 8 bits of the 12 bit RCODE, the lower 4 are in the header.
+    pub ext_rcode: u8,
+
+    // EDNS version, 8 bits
+    pub version: u8,
+
+    // DNSSEC OK (DO), 1 bit
+    pub dnssec_ok: bool,
+
+    // Options, as (code, length, data) triples in RDATA
+    pub options: Vec<EdnsOption>,
+}
+
+impl Opt {
+    /// Payload size advertised in our own OPT records, the value recommended
+    /// by DNS flag day 2020 to avoid IP fragmentation.
+    pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+    /// Block size responses over encrypted transports are padded to (RFC
+    /// 8467, section 4.1).
+    pub const RESPONSE_PADDING: usize = 468;
+
+    /// Extended RCODE returned when the requested EDNS version is unsupported.
+    pub const BADVERS: u16 = 16;
+
+    /// First option with the given code.
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|o| o.code() == code)
+    }
+
+    pub fn client_subnet(&self) -> Option<&ClientSubnet> {
+        match self.option(EdnsOption::CLIENT_SUBNET) {
+            Some(EdnsOption::ClientSubnet(ecs)) => Some(ecs),
+            _ => None,
+        }
+    }
+
+    /// Replaces any options with the same code, or appends the option.
+    pub fn set_option(&mut self, option: EdnsOption) {
+        self.remove_option(option.code());
+        self.options.push(option);
+    }
+
+    pub fn remove_option(&mut self, code: u16) {
+        self.options.retain(|o| o.code() != code);
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_u8(0); // root name
+        Type::OPT.encode(enc);
+        enc.write_u16(self.udp_payload_size);
+        enc.write_u8(self.ext_rcode);
+        enc.write_u8(self.version);
+        enc.write_u16(if self.dnssec_ok { 0x8000 } else { 0 });
+
+        enc.with_length_prefix(|enc| {
+            for option in self.options.iter() {
+                option.encode(enc)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        dec.skip_name()?;
+        Type::decode(dec)?;
+
+        let mut opt = Opt {
+            udp_payload_size: dec.read_u16()?,
+            ext_rcode: dec.read_u8()?,
+            version: dec.read_u8()?,
+            dnssec_ok: dec.read_u16()? & 0x8000 != 0,
+            ..Opt::default()
+        };
+
+        let rdlength = dec.read_u16()? as usize;
+        let mut rdata = Decoder::new(dec.read_slice(rdlength)?);
+        while rdata.offset() < rdlength {
+            let code = rdata.read_u16()?;
+            let len = rdata.read_u16()?;
+            let data = rdata.read_slice(len as usize)?;
+            opt.options.push(EdnsOption::decode(code, data)?);
+        }
+        Ok(opt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientSubnet, Decoder, EdnsOption, Encoder, Error, Opt};
+
+    fn roundtrip(opt: &Opt) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        opt.encode(&mut enc).unwrap();
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(opt.clone()), Opt::decode(&mut dec));
+        buf
+    }
+
+    #[test]
+    fn test_opt_wire_format() {
+        let opt = Opt {
+            udp_payload_size: 1232,
+            dnssec_ok: true,
+            ..Opt::default()
+        };
+        assert_eq!(
+            vec![0, 0, 41, 0x04, 0xD0, 0, 0, 0x80, 0, 0, 0],
+            roundtrip(&opt)
+        );
+    }
+
+    #[test]
+    fn test_options_encode_decode() {
+        let opt = Opt {
+            udp_payload_size: 4096,
+            options: vec![
+                EdnsOption::Nsid(Vec::new()),
+                EdnsOption::Cookie {
+                    client: [1, 2, 3, 4, 5, 6, 7, 8],
+                    server: Some(vec![9; 16]),
+                },
+                EdnsOption::ClientSubnet(ClientSubnet {
+                    source_prefix: 24,
+                    scope_prefix: 0,
+                    address: "192.0.2.0".parse().unwrap(),
+                }),
+                EdnsOption::KeepAlive(Some(300)),
+                EdnsOption::Padding(7),
+                EdnsOption::ExtendedError(6, "bogus".into()),
+                EdnsOption::ExtendedError(10, String::new()),
+                EdnsOption::Unknown(65001, vec![1, 2]),
+            ],
+            ..Opt::default()
+        };
+        roundtrip(&opt);
+    }
+
+    #[test]
+    fn test_client_subnet_wire_format() {
+        let ecs = EdnsOption::ClientSubnet(ClientSubnet {
+            source_prefix: 20,
+            scope_prefix: 0,
+            address: "198.51.100.0".parse().unwrap(),
+        });
+        // address truncated to the 3 bytes covering the /20
+        assert_eq!(vec![0, 1, 20, 0, 198, 51, 100], ecs.data());
+        assert_eq!(Ok(ecs), EdnsOption::decode(8, &[0, 1, 20, 0, 198, 51, 100]));
+    }
+
+    #[test]
+    fn test_malformed_known_option() {
+        assert_eq!(
+            Err(Error::InvalidEdnsOption(10)),
+            EdnsOption::decode(10, &[1, 2, 3])
+        );
+        assert_eq!(
+            Err(Error::InvalidEdnsOption(8)),
+            EdnsOption::decode(8, &[0, 1, 24, 0, 192, 0])
+        );
+        assert_eq!(
+            Err(Error::InvalidEdnsOption(15)),
+            EdnsOption::decode(15, &[6])
+        );
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut opt = Opt::default();
+        opt.set_option(EdnsOption::Padding(4));
+        opt.set_option(EdnsOption::Padding(8));
+        assert_eq!(vec![EdnsOption::Padding(8)], opt.options);
+        assert_eq!(None, opt.client_subnet());
+    }
+}
@@ -1,7 +1,9 @@
-use std::str::Utf8Error;
+use std::{collections::HashMap, str::Utf8Error};
 
 use thiserror::Error;
 
+use crate::proto::Name;
+
 #[derive(Debug, Error, PartialEq)]
 pub enum Error {
     #[error("width must be between 1 and 8 (was {0})")]
@@ -24,21 +26,92 @@ pub enum Error {
 
     #[error("utf8 error")]
     Utf8(#[from] Utf8Error),
+
+    #[error("name exceeds 255 bytes (was {0})")]
+    NameTooLong(usize),
+
+    #[error("label exceeds 63 bytes (was {0})")]
+    LabelTooLong(usize),
+
+    #[error("too many compression pointers in name (limit {0})")]
+    TooManyPointers(usize),
+
+    #[error("invalid label type (length byte {0:#04x})")]
+    InvalidLabelType(u8),
+
+    #[error("invalid rdata (type {rtype}, length {len})")]
+    InvalidRdata { rtype: u16, len: usize },
+
+    #[error("svc params not in strictly increasing key order (key {0})")]
+    InvalidSvcParamOrder(u16),
+
+    #[error("invalid type bitmap length {0}")]
+    InvalidTypeBitmap(u8),
+
+    #[error("invalid presentation format: {0}")]
+    InvalidPresentation(String),
+
+    #[error("malformed edns option (code {0})")]
+    InvalidEdnsOption(u16),
+
+    #[error("message exceeds 65535 bytes (was {0})")]
+    MessageTooLong(usize),
+
+    #[error("length-prefixed data exceeds 65535 bytes (was {0})")]
+    LengthOverflow(usize),
+
+    #[error("section exceeds 65535 records (was {0})")]
+    CountOverflow(usize),
 }
 
+/// Maximum length of a domain name on the wire, RFC 1035 section 2.3.4.
+const MAX_NAME_LEN: usize = 255;
+
+/// Maximum length of a label, the two high bits of its length byte being
+/// zero (RFC 1035, section 4.1.4).
+const MAX_LABEL_LEN: usize = 63;
+
+/// Upper bound on compression pointers followed while reading a single name.
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// Compression pointers carry a 14 bit offset, names written past this point
+/// can still point backwards but can't be pointed to.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
 pub struct Encoder<'a> {
     offset: usize,
     buf: &'a mut Vec<u8>,
+    // where the message starts, compression pointers count from there
+    base: usize,
+    // name suffix, in any case -> offset of its first occurrence in the
+    // message
+    names: HashMap<Name, u16>,
+    compress: bool,
 }
 
 impl<'a> Encoder<'a> {
     pub fn new(buf: &'a mut Vec<u8>) -> Self {
-        Self { offset: 0, buf }
+        Self {
+            offset: 0,
+            buf,
+            base: 0,
+            names: HashMap::new(),
+            compress: true,
+        }
+    }
+
+    /// Encoder that writes every name in full, for the canonical wire format
+    /// DNSSEC signs (RFC 4034, section 6.2).
+    pub fn uncompressed(buf: &'a mut Vec<u8>) -> Self {
+        Self {
+            compress: false,
+            ..Self::new(buf)
+        }
     }
 
     pub fn set_offset(&mut self, pos: usize) {
         if pos > self.buf.len() {
-            self.buf.resize(pos - self.buf.len(), 0);
+            self.buf.resize(pos, 0);
         }
         self.offset = pos;
     }
@@ -47,6 +120,59 @@ impl<'a> Encoder<'a> {
         self.offset
     }
 
+    /// Starts the message at the current offset, past any framing written
+    /// before it such as a TCP length prefix.
+    pub fn start_message(&mut self) {
+        self.base = self.offset;
+        self.names.clear();
+    }
+
+    /// Writes a placeholder u16 to fill in with `patch_u16` once its value
+    /// is known, returns where it is.
+    pub fn reserve_u16(&mut self) -> usize {
+        let pos = self.offset;
+        self.write_u16(0);
+        pos
+    }
+
+    /// Overwrites the u16 at `pos`, which must have been written already,
+    /// leaving the offset as it is.
+    pub fn patch_u16(&mut self, pos: usize, v: u16) {
+        self.buf[pos..pos + 2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    /// Fills in the u16 reserved at `pos` with the number of records
+    /// written since, such as a header's ANCOUNT. Fails past 65535.
+    pub fn patch_count(&mut self, pos: usize, count: usize) -> Result<(), Error> {
+        let count = u16::try_from(count).map_err(|_| Error::CountOverflow(count))?;
+        self.patch_u16(pos, count);
+        Ok(())
+    }
+
+    /// Writes what `func` does preceded by its length as a u16, such as
+    /// RDLENGTH before RDATA. Fails if it wrote more than 65535 bytes.
+    pub fn with_length_prefix<F, R>(&mut self, func: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<R, Error>,
+    {
+        let pos = self.reserve_u16();
+        let result = func(self)?;
+        let len = self.offset - pos - 2;
+        self.patch_u16(
+            pos,
+            u16::try_from(len).map_err(|_| Error::LengthOverflow(len))?,
+        );
+        Ok(result)
+    }
+
+    /// Writes `data` preceded by its length as a u16.
+    pub fn write_length_prefixed(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.with_length_prefix(|enc| {
+            enc.write_slice(data);
+            Ok(())
+        })
+    }
+
     pub fn write_slice(&mut self, b: &[u8]) {
         if b.len() == 1 {
             self.write_u8(b[0])
@@ -74,10 +200,64 @@ impl<'a> Encoder<'a> {
         self.offset += 1;
     }
 
-    pub fn write_str<'s>(&mut self, s: &'s str) {
+    pub fn write_str(&mut self, s: &str) {
         self.write_slice(s.as_bytes())
     }
 
+    /// Writes a <character-string>: a length byte followed by up to 255
+    /// bytes of data (RFC 1035, section 3.3). Longer strings are truncated.
+    pub fn write_character_string(&mut self, s: &str) {
+        let b = &s.as_bytes()[..s.len().min(255)];
+        self.write_u8(b.len() as u8);
+        self.write_slice(b);
+    }
+
+    /// Writes a domain name, replacing any suffix that was already written
+    /// anywhere in the message, in a question, an owner name or RDATA, with
+    /// a compression pointer to it (RFC 1035, section 4.1.4).
+    pub fn write_name(&mut self, name: &Name) -> Result<(), Error> {
+        self.write_labels(name.labels(), self.compress)
+    }
+
+    /// Writes a domain name in full, for the places where compression is
+    /// forbidden (e.g. the SVCB TargetName, RFC 9460 section 2.2). Later
+    /// names may still point into it.
+    pub fn write_uncompressed_name(&mut self, name: &Name) -> Result<(), Error> {
+        self.write_labels(name.labels(), false)
+    }
+
+    /// Writes `labels`, ending in a pointer to the longest suffix already
+    /// written in any case if `point`. Suffixes written out become targets
+    /// for later pointers, the first occurrence of each. Fails, writing
+    /// nothing, for labels or names too long for the wire format.
+    fn write_labels(&mut self, labels: &[Vec<u8>], point: bool) -> Result<(), Error> {
+        if let Some(label) = labels.iter().find(|l| l.len() > MAX_LABEL_LEN) {
+            return Err(Error::LabelTooLong(label.len()));
+        }
+        let len = labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1;
+        if len > MAX_NAME_LEN {
+            return Err(Error::NameTooLong(len));
+        }
+
+        for i in 0..labels.len() {
+            if self.compress {
+                let suffix = Name::from_labels(&labels[i..]);
+                if let Some(&ptr) = self.names.get(&suffix).filter(|_| point) {
+                    self.write_u16(0xC000 | ptr);
+                    return Ok(());
+                }
+                let at = self.offset - self.base;
+                if at <= MAX_POINTER_OFFSET {
+                    self.names.entry(suffix).or_insert(at as u16);
+                }
+            }
+            self.write_u8(labels[i].len() as u8);
+            self.write_slice(&labels[i]);
+        }
+        self.write_u8(0);
+        Ok(())
+    }
+
     pub fn write_u16(&mut self, v: u16) {
         self.write_slice(&v.to_be_bytes())
     }
@@ -99,6 +279,17 @@ impl<'a> Encoder<'a> {
     }
 }
 
+/// An encoded `message` framed for TCP and other streams, after its length
+/// (RFC 1035, section 4.2.2).
+pub fn length_prefixed(message: &[u8]) -> Result<Vec<u8>, Error> {
+    if message.len() > u16::MAX as usize {
+        return Err(Error::MessageTooLong(message.len()));
+    }
+    let mut buf = Vec::with_capacity(message.len() + 2);
+    Encoder::new(&mut buf).write_length_prefixed(message)?;
+    Ok(buf)
+}
+
 pub struct BitEncoder<'a> {
     data: &'a mut u8,
     offset: u8,
@@ -184,6 +375,11 @@ impl<'a> Decoder<'a> {
         current
     }
 
+    /// The whole buffer being read, which compression pointers point into.
+    pub fn buf(&self) -> &'a [u8] {
+        self.buf
+    }
+
     pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], Error> {
         if self.offset + len > self.buf.len() {
             return Err(Error::ReadOutOfBounds {
@@ -222,35 +418,77 @@ impl<'a> Decoder<'a> {
         Ok(())
     }
 
+    /// Reads a length-prefixed <character-string> (RFC 1035, section 3.3).
+    pub fn read_character_string(&mut self) -> Result<String, Error> {
+        let len = self.read_u8()?;
+        let bytes = self.read_slice(len as usize)?;
+        Ok(std::str::from_utf8(bytes)?.into())
+    }
+
+    /// Reads a domain name, following compression pointers (RFC 1035,
+    /// section 4.1.4). Pointers may target any earlier offset, including the
+    /// middle of another name or another pointer, so the number of jumps is
+    /// capped to reject pointer loops.
     pub fn read_name(&mut self) -> Result<String, Error> {
-        let mut segments = Vec::new();
+        let mut labels = Vec::new();
+        self.walk_name(|label| labels.push(label))?;
+        Ok(Name::from_labels(labels).to_string())
+    }
+
+    /// Skips past a domain name, checking it the way `read_name` does but
+    /// without allocating, returns where it starts.
+    pub fn skip_name(&mut self) -> Result<usize, Error> {
+        let start = self.offset;
+        self.walk_name(|_| ())?;
+        Ok(start)
+    }
+
+    /// Hands each label of the name at the offset to `label`, leaving the
+    /// offset past the name.
+    pub fn walk_name<F>(&mut self, mut label: F) -> Result<(), Error>
+    where
+        F: FnMut(&'a [u8]),
+    {
+        let mut name_len = 1;
+        let mut jumps = 0;
+        // where to continue reading once the name is done, set on first jump
+        let mut resume_at = None;
 
         loop {
             let len = self.read_u8()?;
-            if len == 0 {
-                break;
-            }
-
-            if len & 0xC0 == 0xC0 {
-                let offset = u16::from_be_bytes([len & 0x3F, self.read_u8()?]) as usize;
-                let original_ffset = self.set_offset(offset);
-                let segment = self.read_name()?;
-                segments.push(segment);
-                self.set_offset(original_ffset);
-            } else {
-                let bytes = self.read_slice(len as usize)?;
-                let label = std::str::from_utf8(bytes)?;
-                segments.push(label.into());
+            match len & 0xC0 {
+                0x00 if len == 0 => break,
+                0x00 => {
+                    name_len += len as usize + 1;
+                    if name_len > MAX_NAME_LEN {
+                        return Err(Error::NameTooLong(name_len));
+                    }
+                    let bytes = self.read_slice(len as usize)?;
+                    label(bytes);
+                }
+                0xC0 => {
+                    let offset = u16::from_be_bytes([len & 0x3F, self.read_u8()?]) as usize;
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS {
+                        return Err(Error::TooManyPointers(MAX_POINTER_JUMPS));
+                    }
+                    let current = self.set_offset(offset);
+                    resume_at.get_or_insert(current);
+                }
+                _ => return Err(Error::InvalidLabelType(len)),
             }
         }
 
-        Ok(segments.join("."))
+        if let Some(offset) = resume_at {
+            self.set_offset(offset);
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{BitDecoder, BitEncoder, Decoder, Encoder, Error};
+    use super::{length_prefixed, BitDecoder, BitEncoder, Decoder, Encoder, Error, Name};
 
     #[derive(Debug, Default, PartialEq)]
     struct Header {
@@ -263,9 +501,9 @@ mod test {
     }
 
     impl Header {
-        fn from_bytes<'a>(buf: &'a [u8]) -> Result<Self, Error> {
+        fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
             let mut res = Header::default();
-            let mut dec = Decoder::new(&buf);
+            let mut dec = Decoder::new(buf);
 
             res.id = dec.read_u16()?;
             dec.read_bits(|br| {
@@ -332,4 +570,189 @@ mod test {
         assert_eq!(dec.read(1), Ok(0));
         assert_eq!(dec.read(1), Ok(1));
     }
+
+    #[test]
+    fn test_write_name_compression() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_name(&Name::from("codecrafters.io")).unwrap();
+        enc.write_name(&Name::from("api.codecrafters.io")).unwrap();
+        enc.write_name(&Name::from("CodeCrafters.IO")).unwrap();
+        enc.write_name(&Name::from("io")).unwrap();
+
+        #[rustfmt::skip]
+        let expect = vec![
+            12, b'c', b'o', b'd', b'e', b'c', b'r', b'a', b'f', b't', b'e', b'r', b's',
+            2, b'i', b'o', 0,
+            3, b'a', b'p', b'i', 0xC0, 0,
+            0xC0, 0,
+            0xC0, 13,
+        ];
+        assert_eq!(expect, buf);
+
+        let mut buf = Vec::new();
+        let mut enc = Encoder::uncompressed(&mut buf);
+        enc.write_name(&Name::from("io")).unwrap();
+        enc.write_name(&Name::from("io")).unwrap();
+        assert_eq!(vec![2, b'i', b'o', 0, 2, b'i', b'o', 0], buf);
+
+        // a label with a dot in it is no suffix of the split labels
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_name(&Name::from_labels(["a.b", "io"])).unwrap();
+        enc.write_name(&Name::from_labels(["a", "b", "io"]))
+            .unwrap();
+        #[rustfmt::skip]
+        let expect = vec![
+            3, b'a', b'.', b'b', 2, b'i', b'o', 0,
+            1, b'a', 1, b'b', 0xC0, 4,
+        ];
+        assert_eq!(expect, buf);
+    }
+
+    #[test]
+    fn test_reserve_and_patch() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        let count = enc.reserve_u16();
+        let written = enc
+            .with_length_prefix(|enc| {
+                enc.write_str("abc");
+                enc.write_length_prefixed(&[7])?;
+                Ok(enc.offset())
+            })
+            .unwrap();
+        enc.patch_count(count, 2).unwrap();
+        assert_eq!(10, written);
+        assert_eq!(10, enc.offset());
+        assert_eq!(vec![0, 2, 0, 6, b'a', b'b', b'c', 0, 1, 7], buf);
+
+        // pointers count from the start of the message, not the buffer
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.with_length_prefix(|enc| {
+            enc.start_message();
+            enc.write_name(&Name::from("io"))?;
+            enc.write_name(&Name::from("io"))?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(vec![0, 6, 2, b'i', b'o', 0, 0xC0, 0], buf);
+    }
+
+    #[test]
+    fn test_prefix_overflow() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        assert!(enc.write_length_prefixed(&[0; 65535]).is_ok());
+        assert!(matches!(
+            enc.write_length_prefixed(&[0; 65536]),
+            Err(Error::LengthOverflow(65536))
+        ));
+        let count = enc.reserve_u16();
+        assert!(matches!(
+            enc.patch_count(count, 65536),
+            Err(Error::CountOverflow(65536))
+        ));
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        assert_eq!(Ok(vec![0, 3, 1, 2, 3]), length_prefixed(&[1, 2, 3]));
+        assert_eq!(Ok(65537), length_prefixed(&[0; 65535]).map(|buf| buf.len()));
+        assert_eq!(
+            Err(Error::MessageTooLong(65536)),
+            length_prefixed(&[0; 65536])
+        );
+    }
+
+    #[test]
+    fn test_write_name_points_into_uncompressed_names() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_u8(0xAA);
+        enc.write_uncompressed_name(&Name::from("sip.example.com"))
+            .unwrap();
+        enc.write_uncompressed_name(&Name::from("example.com"))
+            .unwrap();
+        enc.write_name(&Name::from("www.example.com")).unwrap();
+        enc.write_name(&Name::from("sip.example.com")).unwrap();
+
+        #[rustfmt::skip]
+        let expect = vec![
+            0xAA,
+            3, b's', b'i', b'p', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            // written in full all the same
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            // pointing at the first occurrence
+            3, b'w', b'w', b'w', 0xC0, 5,
+            0xC0, 1,
+        ];
+        assert_eq!(expect, buf);
+    }
+
+    #[test]
+    fn test_write_root_name() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_name(&Name::root()).unwrap();
+        assert_eq!(vec![0], buf);
+    }
+
+    #[test]
+    fn test_read_name_pointer_into_middle_of_name() {
+        #[rustfmt::skip]
+        let buf = vec![
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            3, b'f', b'o', b'o', 0xC0, 4,
+            0xAA,
+        ];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok("www.example.com".into()), dec.read_name());
+        assert_eq!(Ok("foo.example.com".into()), dec.read_name());
+        // offset continues right after the pointer, not after the target
+        assert_eq!(Ok(0xAA), dec.read_u8());
+    }
+
+    #[test]
+    fn test_read_name_pointer_chain() {
+        #[rustfmt::skip]
+        let buf = vec![
+            3, b'c', b'o', b'm', 0,
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0xC0, 0,
+            3, b'w', b'w', b'w', 0xC0, 5,
+        ];
+        let mut dec = Decoder::new(&buf);
+        dec.set_offset(15);
+        assert_eq!(Ok("www.example.com".into()), dec.read_name());
+        assert_eq!(buf.len(), dec.offset());
+    }
+
+    #[test]
+    fn test_read_name_pointer_loop() {
+        let buf = vec![3, b'f', b'o', b'o', 0xC0, 0];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Err(Error::TooManyPointers(16)), dec.read_name());
+    }
+
+    #[test]
+    fn test_read_name_invalid_label_type() {
+        let buf = vec![0x40, 0];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Err(Error::InvalidLabelType(0x40)), dec.read_name());
+    }
+
+    #[test]
+    fn test_character_string() {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_character_string("v=spf1 -all");
+        enc.write_character_string("");
+        assert_eq!(b"\x0bv=spf1 -all\x00".to_vec(), buf);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok("v=spf1 -all".into()), dec.read_character_string());
+        assert_eq!(Ok("".into()), dec.read_character_string());
+        assert!(dec.read_character_string().is_err());
+    }
 }
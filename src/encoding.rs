@@ -0,0 +1,214 @@
+//! Text encodings used by the presentation format and HTTP transports:
+//! hex, base64 (RFC 4648 section 4 and the URL-safe alphabet of section 5),
+//! base32hex (RFC 4648 section 7, used by NSEC3) and the percent-encoding
+//! of URLs (RFC 3986).
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const BASE32_HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hex, ignoring whitespace between digits.
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(digits.chunks(2).map(|p| p[0] << 4 | p[1]).collect())
+}
+
+fn base64_encode_with(data: &[u8], alphabet: &[u8; 64], pad: bool) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(alphabet[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else if pad {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode_with(s: &str, alphabet: &[u8; 64]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let v = alphabet.iter().position(|a| *a == c)? as u32;
+        acc = acc << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    base64_encode_with(data, BASE64, true)
+}
+
+/// Decodes base64, ignoring whitespace (zone files split long keys).
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    base64_decode_with(s, BASE64)
+}
+
+/// Unpadded base64url, as used by the DoH GET `dns` parameter (RFC 8484).
+pub fn base64url_encode(data: &[u8]) -> String {
+    base64_encode_with(data, BASE64_URL, false)
+}
+
+pub fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    base64_decode_with(s, BASE64_URL)
+}
+
+/// Unpadded base32hex, as used for NSEC3 hashed owner names (RFC 5155).
+pub fn base32hex_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut acc = 0u64;
+    let mut bits = 0;
+    for b in data {
+        acc = acc << 8 | *b as u64;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_HEX[(acc >> bits & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_HEX[(acc << (5 - bits) & 0x1F) as usize] as char);
+    }
+    out
+}
+
+pub fn base32hex_decode(s: impl AsRef<[u8]>) -> Option<Vec<u8>> {
+    let s = s.as_ref();
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut acc = 0u64;
+    let mut bits = 0;
+    for &c in s.iter().filter(|c| **c != b'=') {
+        let v = BASE32_HEX
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())? as u64;
+        acc = acc << 5 | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Percent-encodes all but unreserved characters (RFC 3986, section 2.3).
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decodes the `%XX` escapes of a URL component, None if one is malformed
+/// or the result isn't UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        base32hex_decode, base32hex_encode, base64_decode, base64_encode, base64url_decode,
+        base64url_encode, hex_decode, hex_encode, percent_decode, percent_encode,
+    };
+
+    #[test]
+    fn test_hex() {
+        assert_eq!("0a00ff", hex_encode(&[10, 0, 255]));
+        assert_eq!(Some(vec![10, 0, 255]), hex_decode("0A 00ff"));
+        assert_eq!(None, hex_decode("0a0"));
+        assert_eq!(None, hex_decode("zz"));
+    }
+
+    #[test]
+    fn test_base64() {
+        // RFC 4648, section 10
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in cases {
+            assert_eq!(encoded, base64_encode(plain.as_bytes()));
+            assert_eq!(Some(plain.as_bytes().to_vec()), base64_decode(encoded));
+        }
+        assert_eq!("-_8", base64url_encode(&[0xFB, 0xFF]));
+        assert_eq!(Some(vec![0xFB, 0xFF]), base64url_decode("-_8"));
+    }
+
+    #[test]
+    fn test_base32hex() {
+        // RFC 4648, section 10, without padding
+        let cases = [
+            ("f", "CO"),
+            ("fo", "CPNG"),
+            ("foo", "CPNMU"),
+            ("foob", "CPNMUOG"),
+            ("fooba", "CPNMUOJ1"),
+            ("foobar", "CPNMUOJ1E8"),
+        ];
+        for (plain, encoded) in cases {
+            assert_eq!(encoded, base32hex_encode(plain.as_bytes()));
+            assert_eq!(Some(plain.as_bytes().to_vec()), base32hex_decode(encoded));
+        }
+    }
+
+    #[test]
+    fn test_percent() {
+        assert_eq!("%2Fdns-query", percent_encode("/dns-query"));
+        assert_eq!("a%20b%C3%A9~", percent_encode("a bé~"));
+        assert_eq!(Some("a bé~".to_string()), percent_decode("a%20b%c3%A9~"));
+        assert_eq!(Some("/dns-query".to_string()), percent_decode("/dns-query"));
+        assert_eq!(None, percent_decode("a%2"));
+        assert_eq!(None, percent_decode("a%zz"));
+        assert_eq!(None, percent_decode("%+1"));
+        assert_eq!(None, percent_decode("%ff"));
+    }
+}
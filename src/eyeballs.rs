@@ -0,0 +1,162 @@
+//! Happy Eyeballs (RFC 8305): when a server is reachable over both IPv6 and
+//! IPv4, attempts alternate between the families, IPv6 first, each given a
+//! short head start before the next one is started alongside it. Whichever
+//! succeeds first is used, so a broken path costs a fraction of a second
+//! instead of a whole timeout.
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+/// Head start of an attempt before the next one is started (section 5).
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// `addrs` in the order to try them: families alternating, starting with
+/// IPv6, each family in its original order (section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Runs `attempt` for each candidate in order, starting the next one when
+/// the running ones failed or `delay` passed without any of them
+/// succeeding. Returns the first success with its candidate, or the last
+/// failure once all failed. Attempts still running then are left to finish
+/// on their own, their outcome dropped.
+pub fn race<C, T, F>(candidates: Vec<C>, delay: Duration, attempt: F) -> Result<(C, T)>
+where
+    C: Clone + Send + 'static,
+    T: Send + 'static,
+    F: Fn(C) -> Result<T> + Send + Sync + 'static,
+{
+    let attempt = Arc::new(attempt);
+    let (tx, rx) = mpsc::channel();
+    let mut candidates = candidates.into_iter();
+    let mut running = 0;
+    let mut last = anyhow!("nothing to try");
+    let mut start_next = true;
+    loop {
+        if start_next {
+            if let Some(candidate) = candidates.next() {
+                let (attempt, tx) = (attempt.clone(), tx.clone());
+                thread::spawn(move || {
+                    let result = attempt(candidate.clone());
+                    let _ = tx.send((candidate, result));
+                });
+                running += 1;
+            }
+        }
+        if running == 0 {
+            return Err(last);
+        }
+        // once all are started there's nothing to wait for but them
+        let received = match candidates.len() {
+            0 => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            _ => rx.recv_timeout(delay),
+        };
+        match received {
+            Ok((candidate, Ok(value))) => return Ok((candidate, value)),
+            Ok((_, Err(e))) => {
+                running -= 1;
+                last = e;
+            }
+            Err(_) => {}
+        }
+        start_next = true;
+    }
+}
+
+/// TCP connection to the first of `addrs` that accepts one, `timeout`
+/// applying to each attempt.
+pub fn connect(addrs: Vec<SocketAddr>, timeout: Duration) -> Result<TcpStream> {
+    let (_, stream) = race(interleave(addrs), CONNECTION_ATTEMPT_DELAY, move |addr| {
+        TcpStream::connect_timeout(&addr, timeout).map_err(|e| anyhow!("{}: {}", addr, e))
+    })?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, interleave, race};
+    use anyhow::bail;
+    use std::{
+        net::{SocketAddr, TcpListener},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["192.0.2.1:53", "192.0.2.2:53", "[2001:db8::1]:53"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(
+            vec![addrs[2], addrs[0], addrs[1]],
+            interleave(addrs.clone())
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_race() {
+        let delay = Duration::from_millis(50);
+
+        // the first to answer wins, even if started later
+        let start = Instant::now();
+        let (winner, _) = race(vec![1000, 10], delay, |ms| {
+            thread::sleep(Duration::from_millis(ms));
+            Ok(ms)
+        })
+        .unwrap();
+        assert_eq!(10, winner);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // a failure starts the next one without waiting
+        let start = Instant::now();
+        let (winner, _) = race(vec![0, 1], Duration::from_secs(10), |n| match n {
+            0 => bail!("unreachable"),
+            n => Ok(n),
+        })
+        .unwrap();
+        assert_eq!(1, winner);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // the last failure when all fail
+        let err = race(vec![1, 2], delay, |n| -> anyhow::Result<()> {
+            thread::sleep(Duration::from_millis(n * 20));
+            bail!("failed {}", n)
+        })
+        .unwrap_err();
+        assert_eq!("failed 2", err.to_string());
+        assert!(race(Vec::<u8>::new(), delay, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_connect() {
+        let v6 = TcpListener::bind("[::1]:0").unwrap();
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        // nothing listens on the IPv6 port any more, IPv4 takes over
+        let closed = v6.local_addr().unwrap();
+        drop(v6);
+        let stream = connect(
+            vec![v4.local_addr().unwrap(), closed],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(v4.local_addr().unwrap(), stream.peer_addr().unwrap());
+    }
+}
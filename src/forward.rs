@@ -0,0 +1,836 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    balance::{Balancer, Strategy},
+    coalesce::Coalescer,
+    dnscrypt::{DnsCryptClient, Stamp},
+    dnstap,
+    doh::{self, DohClient},
+    doq::{self, DoqClient},
+    edns::ClientSubnet,
+    eyeballs,
+    odoh::{OdohClient, Target},
+    proto::{rcode, unresolved_cname, Class, Message, Name, Question, Type},
+    resolver::Resolver,
+    telemetry::{self, Outcome},
+    upstream::Upstream,
+    wire::WireMessage,
+    x509::{Certificate, HostPins},
+};
+
+/// Follow-up queries made for a CNAME chain the upstream left unresolved.
+const MAX_CNAME_CHASE: usize = 8;
+
+/// How long an upstream that failed is passed over.
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Identifies upstream lookups that can share one query: name, in any case,
+/// type, class, whether DNSSEC records were asked for and the client subnet
+/// sent along.
+type FlightKey = (Name, Type, Class, bool, Option<ClientSubnet>);
+
+/// Where an upstream resolver takes queries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Endpoint {
+    // over UDP, and TCP for truncated replies
+    Dns(SocketAddr),
+    // DNS over HTTPS
+    Https(doh::Url),
+    // DNS over QUIC
+    Quic(doq::Url),
+    // DNSCrypt, over UDP and TCP for truncated replies
+    DnsCrypt(Stamp),
+    // Oblivious DNS over HTTPS, through the relay
+    Oblivious(Target),
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Dns(addr)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns(addr) => write!(f, "{}", addr),
+            Self::Https(url) => write!(f, "{}", url),
+            Self::Quic(url) => write!(f, "{}", url),
+            Self::DnsCrypt(stamp) => write!(f, "{}", stamp),
+            Self::Oblivious(target) => write!(f, "{}", target),
+        }
+    }
+}
+
+/// Answers requests by forwarding each question to upstream resolvers, in
+/// the order the balancer picks. Names under a zone with a forward rule go to
+/// that zone's upstreams instead of the default ones. An upstream that times
+/// out or answers SERVFAIL is tried last for a while. Plain upstreams of
+/// both address families are raced, the next one of the other family
+/// asked too when the first is slow to answer (RFC 8305).
+pub struct Forwarder {
+    balancer: Balancer,
+    // conditional forwarding, most specific zone first
+    zones: Vec<(Name, Balancer)>,
+    // identical concurrent questions share one upstream query
+    flights: Coalescer<FlightKey, Result<WireMessage, String>>,
+    upstream: Upstream,
+    doh: DohClient,
+    doq: DoqClient,
+    dnscrypt: DnsCryptClient,
+    odoh: OdohClient,
+    // relay ODoH queries go through
+    odoh_relay: Option<doh::Url>,
+    // upstream -> end of its cooldown
+    failed: Mutex<HashMap<Endpoint, Instant>>,
+    // how long each attempt may take
+    timeout: Duration,
+    // further attempts after a timeout, each under a fresh transaction ID
+    retries: u32,
+}
+
+impl Forwarder {
+    pub fn new(
+        addrs: Vec<Endpoint>,
+        rules: &[(String, Vec<Endpoint>)],
+        strategy: Strategy,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<Self> {
+        let mut zones: Vec<_> = rules
+            .iter()
+            .map(|(zone, addrs)| {
+                Ok((
+                    zone.parse::<Name>().map_err(anyhow::Error::msg)?,
+                    Balancer::new(strategy, addrs.clone()),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        zones.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.num_labels()));
+        Ok(Self {
+            balancer: Balancer::new(strategy, addrs),
+            zones,
+            flights: Coalescer::default(),
+            upstream: Upstream::bind()?,
+            doh: DohClient::new(Vec::new(), HostPins::default()),
+            doq: DoqClient::new(Vec::new(), HostPins::default()),
+            dnscrypt: DnsCryptClient::default(),
+            odoh: OdohClient::default(),
+            odoh_relay: None,
+            failed: Mutex::new(HashMap::new()),
+            timeout,
+            retries,
+        })
+    }
+
+    /// Root certificates DoH and DoQ upstreams are authenticated with, and
+    /// the pins the certificates of some hosts must match one of.
+    pub fn with_tls(mut self, roots: Vec<Certificate>, pins: &HostPins) -> Self {
+        self.doh = DohClient::new(roots.clone(), pins.clone());
+        self.doq = DoqClient::new(roots, pins.clone());
+        self
+    }
+
+    /// Relay ODoH upstreams are reached through.
+    pub fn with_odoh_relay(mut self, relay: doh::Url) -> Self {
+        self.odoh_relay = Some(relay);
+        self
+    }
+
+    /// Upstreams of the default balancer and of all forward rules.
+    fn endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+        std::iter::once(&self.balancer)
+            .chain(self.zones.iter().map(|(_, balancer)| balancer))
+            .flat_map(|balancer| balancer.upstreams())
+    }
+
+    /// Whether any upstream is reached over HTTPS, directly or through the
+    /// ODoH relay, or over QUIC.
+    pub fn uses_tls(&self) -> bool {
+        self.endpoints().any(|addr| {
+            matches!(
+                addr,
+                Endpoint::Https(_) | Endpoint::Quic(_) | Endpoint::Oblivious(_)
+            )
+        })
+    }
+
+    /// Whether any upstream is an ODoH target.
+    pub fn uses_odoh(&self) -> bool {
+        self.endpoints()
+            .any(|addr| matches!(addr, Endpoint::Oblivious(_)))
+    }
+}
+
+impl Resolver for Forwarder {
+    fn resolve(&self, request: &Message) -> Result<Message> {
+        let mut reply = Message {
+            rcode: if request.opcode == 0 {
+                rcode::NOERROR
+            } else {
+                rcode::NOTIMP
+            },
+            ..request.reply()
+        };
+
+        for question in request.questions.iter() {
+            // the reply is decoded here, where its records get merged
+            let forwarded = self
+                .forward(request, question.clone())
+                .and_then(|fwd_reply| Ok(fwd_reply.to_message()?));
+            let mut fwd_reply = match forwarded {
+                Ok(fwd_reply) => fwd_reply,
+                Err(e) => {
+                    tracing::warn!(
+                        id = %request.id, qname = %question.name, error = %e,
+                        "Forwarding failed"
+                    );
+                    return Ok(request.error_reply(rcode::SERVFAIL));
+                }
+            };
+
+            // the upstream may stop at a CNAME, resolve the canonical name
+            // ourselves so the client gets the complete chain
+            for _ in 0..MAX_CNAME_CHASE {
+                if fwd_reply.rcode != rcode::NOERROR {
+                    break;
+                }
+                let Some(target) =
+                    unresolved_cname(&fwd_reply.answers, &question.name, question.qtype)
+                else {
+                    break;
+                };
+                tracing::debug!(id = %request.id, %target, "Chasing CNAME target");
+                let next = self.forward(
+                    request,
+                    Question {
+                        name: target,
+                        ..question.clone()
+                    },
+                );
+                let Ok(next) = next.and_then(|next| Ok(next.to_message()?)) else {
+                    return Ok(request.error_reply(rcode::SERVFAIL));
+                };
+                fwd_reply.rcode = next.rcode;
+                fwd_reply.answers.extend(next.answers);
+                fwd_reply.authorities = next.authorities;
+            }
+
+            for answer in fwd_reply.answers.into_iter() {
+                reply.answers.push(answer);
+            }
+            // keep NXDOMAIN and the SOA from the upstream so negative answers
+            // stay cacheable downstream
+            if fwd_reply.rcode != rcode::NOERROR {
+                reply.rcode = fwd_reply.rcode;
+            }
+            reply.authorities.extend(fwd_reply.authorities);
+        }
+        Ok(reply)
+    }
+}
+
+impl Forwarder {
+    /// Sends a single question of `request` to the upstreams, or waits for
+    /// the reply to the same question already sent for another client.
+    fn forward(&self, request: &Message, question: Question) -> Result<WireMessage> {
+        let key = (
+            question.name.clone(),
+            question.qtype,
+            question.class,
+            request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok),
+            request
+                .opt
+                .as_ref()
+                .and_then(|opt| opt.client_subnet())
+                .cloned(),
+        );
+        self.flights
+            .run(key, || {
+                self.forward_uncoalesced(request, question)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Sends a single question of `request` to the upstreams until one
+    /// answers it, returning the last failure if none does.
+    fn forward_uncoalesced(&self, request: &Message, question: Question) -> Result<WireMessage> {
+        let balancer = self.balancer(&question.name);
+        let fwd_request = Message {
+            questions: vec![question],
+            ..request.clone()
+        };
+
+        let mut last = Err(anyhow!("no upstream resolvers"));
+        let mut candidates = self.candidates(balancer);
+        while !candidates.is_empty() {
+            let addr = candidates.remove(0);
+            // the next upstream of the other address family races this one
+            let rival = candidates
+                .iter()
+                .position(|other| other_family(&addr, other))
+                .map(|i| candidates.remove(i));
+
+            let start = Instant::now();
+            let (tried, result) = match rival {
+                Some(rival) => match self.race(vec![addr.clone(), rival.clone()], &fwd_request) {
+                    Ok((winner, fwd_reply)) => (vec![winner], Ok(fwd_reply)),
+                    Err(e) => (vec![addr, rival], Err(e)),
+                },
+                None => {
+                    let result = self.query(&addr, &fwd_request);
+                    (vec![addr], result)
+                }
+            };
+            // a failed upstream counts as slow as the timeout
+            for addr in tried.iter() {
+                balancer.record(
+                    addr,
+                    match result {
+                        Ok(_) => start.elapsed(),
+                        Err(_) => self.timeout,
+                    },
+                );
+            }
+            let tried_names: Vec<String> = tried.iter().map(Endpoint::to_string).collect();
+            match result {
+                Ok(fwd_reply) if fwd_reply.view().rcode != rcode::SERVFAIL => {
+                    self.failed.lock().unwrap().remove(&tried[0]);
+                    return Ok(fwd_reply);
+                }
+                Ok(fwd_reply) => {
+                    tracing::warn!(upstream = %tried_names.join(","), "Upstream answered SERVFAIL");
+                    last = Ok(fwd_reply);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        upstream = %tried_names.join(","), error = %e,
+                        "Upstream failed"
+                    );
+                    last = Err(e);
+                }
+            }
+            let until = Instant::now() + FAILURE_COOLDOWN;
+            let mut failed = self.failed.lock().unwrap();
+            for addr in tried {
+                failed.insert(addr, until);
+            }
+        }
+        last
+    }
+
+    /// Sends `fwd_request` to plain upstreams of both address families, the
+    /// first given a head start before the second is asked as well (RFC
+    /// 8305), and returns the first to answer with anything but SERVFAIL.
+    fn race(&self, addrs: Vec<Endpoint>, fwd_request: &Message) -> Result<(Endpoint, WireMessage)> {
+        let upstream = self.upstream.clone();
+        let fwd_request = fwd_request.clone();
+        let timeout = self.timeout;
+        eyeballs::race(addrs, eyeballs::CONNECTION_ATTEMPT_DELAY, move |addr| {
+            let Endpoint::Dns(sock_addr) = addr else {
+                bail!("{} is not a plain DNS upstream", addr);
+            };
+            tracing::trace!(upstream = %addr, "Sending query {:?}", fwd_request);
+            let sent = SystemTime::now();
+            let result = upstream.query(sock_addr, &fwd_request, timeout);
+            exchanged(&addr, &fwd_request, sent, timeout, &result);
+            let fwd_reply = result?;
+            if fwd_reply.view().rcode == rcode::SERVFAIL {
+                bail!("upstream {} answered SERVFAIL", addr);
+            }
+            Ok(fwd_reply)
+        })
+    }
+
+    /// Upstreams responsible for `name`: those of the most specific forward
+    /// rule covering it, or the default ones.
+    fn balancer(&self, name: &Name) -> &Balancer {
+        self.zones
+            .iter()
+            .find(|(zone, _)| name.is_subdomain_of(zone))
+            .map_or(&self.balancer, |(_, balancer)| balancer)
+    }
+
+    /// Upstreams in the order to try them: healthy ones first, then the ones
+    /// still cooling down in case everything else fails too.
+    fn candidates(&self, balancer: &Balancer) -> Vec<Endpoint> {
+        let now = Instant::now();
+        let failed = self.failed.lock().unwrap();
+        let (healthy, cooling): (Vec<_>, Vec<_>) = balancer
+            .order()
+            .into_iter()
+            .partition(|addr| !matches!(failed.get(addr), Some(until) if *until > now));
+        healthy.into_iter().chain(cooling).collect()
+    }
+
+    /// Sends `fwd_request` to a single upstream, retrying on timeout.
+    fn query(&self, addr: &Endpoint, fwd_request: &Message) -> Result<WireMessage> {
+        let mut attempt = 0;
+        loop {
+            tracing::trace!(upstream = %addr, "Sending query {:?}", fwd_request);
+            let sent = SystemTime::now();
+            let result = match addr {
+                Endpoint::Dns(addr) => self.upstream.query(*addr, fwd_request, self.timeout),
+                Endpoint::Https(url) => self.doh.query(url, fwd_request, self.timeout),
+                Endpoint::Quic(url) => self.doq.query(url, fwd_request, self.timeout),
+                Endpoint::DnsCrypt(stamp) => self.dnscrypt.query(stamp, fwd_request, self.timeout),
+                Endpoint::Oblivious(target) => match &self.odoh_relay {
+                    Some(relay) => {
+                        self.odoh
+                            .query(&self.doh, relay, target, fwd_request, self.timeout)
+                    }
+                    None => Err(anyhow!("No ODoH relay for {}", target)),
+                },
+            };
+            exchanged(addr, fwd_request, sent, self.timeout, &result);
+            match result {
+                Ok(fwd_reply) => {
+                    tracing::trace!(upstream = %addr, "Reply {:?}", fwd_reply.view());
+                    return Ok(fwd_reply);
+                }
+                Err(e) if attempt < self.retries => {
+                    tracing::debug!(upstream = %addr, error = %e, "Retrying");
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reports a query sent to `addr` at `sent`, and how it went, to the
+/// upstream stats and dnstap.
+fn exchanged(
+    addr: &Endpoint,
+    fwd_request: &Message,
+    sent: SystemTime,
+    timeout: Duration,
+    result: &Result<WireMessage>,
+) {
+    let elapsed = sent.elapsed().unwrap_or_default();
+    telemetry::upstreams().record(addr, Outcome::of(result, elapsed, timeout));
+    dnstap::forwarder_exchange(addr, fwd_request, sent, result.as_ref().ok());
+}
+
+/// Whether `a` and `b` are plain upstreams of different address families.
+fn other_family(a: &Endpoint, b: &Endpoint) -> bool {
+    matches!((a, b), (Endpoint::Dns(a), Endpoint::Dns(b)) if a.is_ipv6() != b.is_ipv6())
+}
+
+/// Parses an upstream address, the port defaults to 53.
+pub fn parse_upstream(s: &str) -> Result<SocketAddr, String> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|e| format!("{}: {:?}", e, s))
+}
+
+/// Parses an upstream resolver: a DoH server's `https://` URL, a DoQ
+/// server's `quic://` URL, a DNSCrypt server's `sdns://` stamp, an ODoH
+/// target's `odoh://` URL, or an address whose port defaults to 53.
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, String> {
+    if s.starts_with("https://") {
+        s.parse().map(Endpoint::Https)
+    } else if s.starts_with("quic://") {
+        s.parse().map(Endpoint::Quic)
+    } else if s.starts_with("sdns://") {
+        s.parse().map(Endpoint::DnsCrypt)
+    } else if s.starts_with("odoh://") {
+        s.parse().map(Endpoint::Oblivious)
+    } else {
+        parse_upstream(s).map(Endpoint::Dns)
+    }
+}
+
+/// Parses a default upstream resolver: a DoH server's `https://` URL, a DoQ
+/// server's `quic://` URL, a DNSCrypt server's `sdns://` stamp, an ODoH
+/// target's `odoh://` URL, or an address with its port.
+pub fn parse_resolver(s: &str) -> Result<Endpoint, String> {
+    match ["https://", "quic://", "sdns://", "odoh://"]
+        .iter()
+        .any(|scheme| s.starts_with(scheme))
+    {
+        true => parse_endpoint(s),
+        false => s
+            .parse()
+            .map(Endpoint::Dns)
+            .map_err(|e| format!("{}: {:?}", e, s)),
+    }
+}
+
+/// Parses a `zone=upstream[,upstream...]` forward rule.
+pub fn parse_forward_rule(s: &str) -> Result<(String, Vec<Endpoint>), String> {
+    let (zone, addrs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ZONE=ADDRESS, got {:?}", s))?;
+    let addrs = addrs
+        .split(',')
+        .map(parse_endpoint)
+        .collect::<Result<_, _>>()?;
+    Ok((zone.into(), addrs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_endpoint, parse_forward_rule, Endpoint, Forwarder, Resolver};
+    use crate::{
+        balance::Strategy,
+        eyeballs,
+        proto::{rcode, Class, Message, Name, Question, Record, Type},
+        rdata::RData,
+    };
+    use std::{
+        net::UdpSocket,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: match rdata {
+                RData::CNAME(_) => Type::CNAME,
+                _ => Type::A,
+            },
+            class: Class::IN,
+            ttl: 60,
+            rdata,
+        }
+    }
+
+    #[test]
+    fn test_forward_chases_cname() {
+        // upstream that only knows the first link of the chain and the target
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            for _ in 0..2 {
+                let (size, source) = upstream.recv_from(&mut buf).unwrap();
+                let request = Message::from_bytes(&buf[..size]).unwrap();
+                let answer = match request.questions[0]
+                    .name
+                    .to_string()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "www.example.com" => record(
+                        "www.example.com",
+                        RData::CNAME(Name::from("edge.example.net")),
+                    ),
+                    _ => record("edge.example.net", RData::A([192, 0, 2, 1].into())),
+                };
+                let reply = Message {
+                    answers: vec![answer],
+                    ..request.reply()
+                };
+                upstream
+                    .send_to(&reply.to_bytes().unwrap(), source)
+                    .unwrap();
+            }
+        });
+
+        let request = Message {
+            id: 7,
+            rd: 1,
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let forwarder = Forwarder::new(
+            vec![addr.into()],
+            &[],
+            Strategy::Ordered,
+            Duration::from_secs(2),
+            0,
+        )
+        .unwrap();
+        let reply = forwarder.resolve(&request).unwrap();
+
+        assert_eq!(7, reply.id);
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(2, reply.answers.len());
+        assert_eq!(RData::A([192, 0, 2, 1].into()), reply.answers[1].rdata);
+    }
+
+    #[test]
+    fn test_retry_then_servfail() {
+        // upstream that never answers, counts the attempts it sees
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let counter = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let mut ids = Vec::new();
+            while let Ok((size, _)) = upstream.recv_from(&mut buf) {
+                ids.push(Message::from_bytes(&buf[..size]).unwrap().id);
+            }
+            ids
+        });
+
+        let request = Message {
+            id: 7,
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let forwarder = Forwarder::new(
+            vec![addr.into()],
+            &[],
+            Strategy::Ordered,
+            Duration::from_millis(50),
+            2,
+        )
+        .unwrap();
+        let reply = forwarder.resolve(&request).unwrap();
+        assert_eq!(7, reply.id);
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+
+        let ids = counter.join().unwrap();
+        assert_eq!(3, ids.len());
+        // every attempt goes out under its own transaction ID
+        assert!(ids[0] != ids[1] || ids[1] != ids[2]);
+    }
+
+    #[test]
+    fn test_failover() {
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let live = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addrs: Vec<Endpoint> = vec![
+            dead.local_addr().unwrap().into(),
+            live.local_addr().unwrap().into(),
+        ];
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            for _ in 0..2 {
+                let (size, source) = live.recv_from(&mut buf).unwrap();
+                let request = Message::from_bytes(&buf[..size]).unwrap();
+                let reply = Message {
+                    answers: vec![record("www.example.com", RData::A([192, 0, 2, 1].into()))],
+                    ..request.reply()
+                };
+                live.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+            }
+        });
+
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let forwarder = Forwarder::new(
+            addrs.clone(),
+            &[],
+            Strategy::Ordered,
+            Duration::from_millis(50),
+            0,
+        )
+        .unwrap();
+        assert_eq!(addrs, forwarder.candidates(&forwarder.balancer));
+
+        let reply = forwarder.resolve(&request).unwrap();
+        assert_eq!(1, reply.answers.len());
+
+        // the dead upstream is cooling down and tried last
+        assert_eq!(
+            vec![addrs[1].clone(), addrs[0].clone()],
+            forwarder.candidates(&forwarder.balancer)
+        );
+        let reply = forwarder.resolve(&request).unwrap();
+        assert_eq!(1, reply.answers.len());
+        drop(dead);
+    }
+
+    #[test]
+    fn test_dual_stack_race() {
+        // the preferred IPv6 upstream never answers
+        let silent = UdpSocket::bind("[::1]:0").unwrap();
+        let live = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addrs: Vec<Endpoint> = vec![
+            silent.local_addr().unwrap().into(),
+            live.local_addr().unwrap().into(),
+        ];
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = live.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..size]).unwrap();
+            let reply = Message {
+                answers: vec![record("www.example.com", RData::A([192, 0, 2, 1].into()))],
+                ..request.reply()
+            };
+            live.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+        });
+
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let forwarder = Forwarder::new(
+            addrs.clone(),
+            &[],
+            Strategy::Ordered,
+            Duration::from_secs(5),
+            0,
+        )
+        .unwrap();
+        let start = Instant::now();
+        let reply = forwarder.resolve(&request).unwrap();
+        assert_eq!(1, reply.answers.len());
+        // answered after the head start, long before the timeout
+        assert!(start.elapsed() >= eyeballs::CONNECTION_ATTEMPT_DELAY);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(silent);
+    }
+
+    #[test]
+    fn test_conditional_forwarding() {
+        let corp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let corp_addr: Endpoint = corp.local_addr().unwrap().into();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = corp.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..size]).unwrap();
+            let reply = Message {
+                answers: vec![record(
+                    "intra.corp.example.com",
+                    RData::A([10, 0, 0, 1].into()),
+                )],
+                ..request.reply()
+            };
+            corp.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+        });
+
+        // the default upstream never answers
+        let default = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let default_addr: Endpoint = default.local_addr().unwrap().into();
+        let rules = vec![
+            ("example.com".to_string(), vec![default_addr.clone()]),
+            ("corp.example.com.".to_string(), vec![corp_addr.clone()]),
+        ];
+        let forwarder = Forwarder::new(
+            vec![default_addr],
+            &rules,
+            Strategy::Ordered,
+            Duration::from_millis(50),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![corp_addr],
+            forwarder.candidates(forwarder.balancer(&Name::from("Intra.Corp.Example.com")))
+        );
+
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from("intra.corp.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let reply = forwarder.resolve(&request).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(1, reply.answers.len());
+    }
+
+    #[test]
+    fn test_parse_forward_rule() {
+        assert_eq!(
+            Ok((
+                "corp.example.com".to_string(),
+                vec![
+                    parse_endpoint("10.0.0.53:53").unwrap(),
+                    parse_endpoint("https://doh.corp.example.com/dns-query").unwrap(),
+                    Endpoint::Quic("quic://doq.corp.example.com:853".parse().unwrap()),
+                    Endpoint::Oblivious("odoh://odoh.corp.example.com".parse().unwrap()),
+                ]
+            )),
+            parse_forward_rule(
+                "corp.example.com=10.0.0.53,https://doh.corp.example.com,quic://doq.corp.example.com,\
+                 odoh://odoh.corp.example.com"
+            )
+        );
+        assert!(parse_forward_rule("corp.example.com").is_err());
+        assert!(parse_forward_rule("corp.example.com=nowhere").is_err());
+    }
+
+    #[test]
+    fn test_identical_queries_coalesced() {
+        // slow upstream counting the queries it gets
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let (count_tx, count_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let request = Message::from_bytes(&buf[..size]).unwrap();
+                count_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                let reply = Message {
+                    answers: vec![record("www.example.com", RData::A([192, 0, 2, 1].into()))],
+                    ..request.reply()
+                };
+                upstream
+                    .send_to(&reply.to_bytes().unwrap(), source)
+                    .unwrap();
+            }
+        });
+
+        let forwarder = std::sync::Arc::new(
+            Forwarder::new(
+                vec![addr.into()],
+                &[],
+                Strategy::Ordered,
+                Duration::from_secs(2),
+                0,
+            )
+            .unwrap(),
+        );
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let forwarder = forwarder.clone();
+                thread::spawn(move || {
+                    let request = Message {
+                        id,
+                        questions: vec![Question {
+                            name: Name::from("WWW.example.com"),
+                            qtype: Type::A,
+                            class: Class::IN,
+                        }],
+                        ..Message::default()
+                    };
+                    forwarder.resolve(&request).unwrap()
+                })
+            })
+            .collect();
+        for (id, handle) in handles.into_iter().enumerate() {
+            let reply = handle.join().unwrap();
+            assert_eq!(id as u16, reply.id);
+            assert_eq!(1, reply.answers.len());
+        }
+        assert_eq!(1, count_rx.try_iter().count());
+    }
+}
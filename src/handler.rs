@@ -0,0 +1,271 @@
+use std::{cell::Cell, fmt, net::SocketAddr, sync::Arc, time::Instant};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::{
+    edns::Opt,
+    privacy,
+    proto::{rcode, Class, Message, Name, Record, Type},
+    rdata::{RData, Soa},
+};
+
+/// Transport a request arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    // DNS over plain HTTP, the JSON API included
+    Http,
+    // DNS over HTTPS
+    Https,
+    // DNS over QUIC
+    Quic,
+    // DNSCrypt, over UDP or TCP
+    DnsCrypt,
+}
+
+/// Where a request came from, for handlers that treat clients differently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    pub source: SocketAddr,
+    pub transport: Transport,
+    // TSIG key the request was signed with, once verified
+    pub key: Option<Name>,
+}
+
+/// Where a reply came from, as the query log reports it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Origin {
+    // zones, hosts, policy and errors
+    #[default]
+    Local,
+    Cache,
+    // upstreams or recursion
+    Upstream,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Local => "local",
+            Self::Cache => "cached",
+            Self::Upstream => "forwarded",
+        })
+    }
+}
+
+thread_local! {
+    // origin of the reply to the request handled on this thread
+    static ORIGIN: Cell<Origin> = const { Cell::new(Origin::Local) };
+}
+
+/// Records where the reply to the request being handled comes from. A chain
+/// runs on a single thread, so handlers further out can tell.
+pub fn set_origin(origin: Origin) {
+    ORIGIN.with(|o| o.set(origin));
+}
+
+/// The origin recorded since the last call, `Local` if none was.
+pub fn take_origin() -> Origin {
+    ORIGIN.with(|o| o.replace(Origin::Local))
+}
+
+/// Error of handlers that want no reply sent at all: the request is
+/// dropped, and a TCP connection it came on closed.
+#[derive(Error, Debug)]
+#[error("request dropped")]
+pub struct Dropped;
+
+/// A step in answering requests. A handler either answers the request itself
+/// or passes it down the chain with `next.run`, possibly changing the request
+/// on the way in and the reply on the way out.
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message>;
+}
+
+/// A shared handler, for handlers something outside the chain keeps
+/// working with.
+impl<H: RequestHandler + ?Sized> RequestHandler for Arc<H> {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        (**self).handle(ctx, request, next)
+    }
+}
+
+/// The handlers after the current one.
+pub struct Next<'a>(&'a [Box<dyn RequestHandler>]);
+
+impl Next<'_> {
+    pub fn run(self, ctx: &Context, request: Message) -> Result<Message> {
+        match self.0.split_first() {
+            Some((handler, rest)) => handler.handle(ctx, request, Next(rest)),
+            // no handler answered the request
+            None => Ok(request.error_reply(rcode::REFUSED)),
+        }
+    }
+}
+
+/// Handlers in the order requests pass through them.
+#[derive(Default)]
+pub struct Chain {
+    handlers: Vec<Box<dyn RequestHandler>>,
+}
+
+impl Chain {
+    pub fn with(mut self, handler: impl RequestHandler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    pub fn handle(&self, ctx: &Context, request: Message) -> Result<Message> {
+        Next(&self.handlers).run(ctx, request)
+    }
+}
+
+/// Logs each request with its outcome, and in full at trace level.
+pub struct Logging;
+
+impl RequestHandler for Logging {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let id = request.id;
+        let (qname, qtype) = match request.questions.first() {
+            Some(q) => (q.name.to_string(), q.qtype.to_string()),
+            None => Default::default(),
+        };
+        let transport = format!("{:?}", ctx.transport).to_lowercase();
+        let client = privacy::client(ctx.source);
+        tracing::trace!(%id, %client, "Request {:?}", request);
+        let started = Instant::now();
+        let result = next.run(ctx, request);
+        let ms = started.elapsed().as_millis();
+        match &result {
+            Ok(reply) => {
+                tracing::info!(
+                    %id, %client, %transport, %qname, %qtype, outcome = %rcode::name(reply.rcode),
+                    answers = reply.answers.len(), %ms,
+                    "Answered"
+                );
+                tracing::trace!(%id, %client, "Reply {:?}", reply);
+            }
+            Err(e) if e.is::<Dropped>() => tracing::info!(
+                %id, %client, %transport, %qname, %qtype, outcome = "dropped", %ms,
+                "Dropped"
+            ),
+            Err(e) => tracing::warn!(
+                %id, %client, %transport, %qname, %qtype, outcome = "error", %ms, error = %e,
+                "Failed"
+            ),
+        }
+        result
+    }
+}
+
+/// Rejects EDNS versions other than 0 with BADVERS (RFC 6891, section 6.1.3).
+pub struct EdnsVersion;
+
+impl RequestHandler for EdnsVersion {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        match &request.opt {
+            Some(opt) if opt.version != 0 => Ok(Message {
+                rcode: (Opt::BADVERS & 0xF) as u8,
+                opt: Some(Opt {
+                    udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                    ext_rcode: (Opt::BADVERS >> 4) as u8,
+                    ..Opt::default()
+                }),
+                ..request.reply()
+            }),
+            _ => next.run(ctx, request),
+        }
+    }
+}
+
+/// SOA for locally answered names. The stub answers every name itself, so
+/// each queried name is treated as the apex of its own zone.
+pub fn local_soa(name: &Name) -> Record {
+    let minimum = 60;
+    Record {
+        name: name.clone(),
+        rtype: Type::SOA,
+        class: Class::IN,
+        ttl: minimum,
+        rdata: RData::SOA(Soa {
+            mname: Name::from("localhost"),
+            rname: Name::from("hostmaster.localhost"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Chain, Context, EdnsVersion, Next, RequestHandler, Transport};
+    use crate::{
+        edns::Opt,
+        proto::{rcode, Message},
+    };
+    use anyhow::Result;
+
+    fn ctx() -> Context {
+        Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        }
+    }
+
+    // sets a header bit on the way in and another on the way out
+    struct Mark;
+
+    impl RequestHandler for Mark {
+        fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+            let mut reply = next.run(ctx, Message { z: 1, ..request })?;
+            reply.ra = 1;
+            Ok(reply)
+        }
+    }
+
+    struct Answer;
+
+    impl RequestHandler for Answer {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            assert_eq!(1, request.z);
+            Ok(request.reply())
+        }
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let chain = Chain::default().with(Mark).with(Answer);
+        let reply = chain.handle(&ctx(), Message::default()).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(1, reply.ra);
+    }
+
+    #[test]
+    fn test_unanswered_request_refused() {
+        let chain = Chain::default().with(EdnsVersion);
+        let reply = chain.handle(&ctx(), Message::default()).unwrap();
+        assert_eq!(rcode::REFUSED, reply.rcode);
+    }
+
+    #[test]
+    fn test_edns_version() {
+        let chain = Chain::default().with(EdnsVersion).with(Answer);
+        let request = Message {
+            z: 1,
+            opt: Some(Opt {
+                version: 1,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let reply = chain.handle(&ctx(), request).unwrap();
+        assert_eq!(0, reply.rcode);
+        assert_eq!(1, reply.opt.unwrap().ext_rcode);
+    }
+}
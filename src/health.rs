@@ -0,0 +1,284 @@
+//! Liveness and readiness probes over plain HTTP, for orchestrators and
+//! container health checks:
+//!
+//! ```text
+//! GET /livez   200 while the server isn't stuck
+//! GET /readyz  200 once every listener serves and, if queries go
+//!              upstream, an upstream answers; 503 with the reason if not
+//! ```
+//!
+//! Upstreams are probed with a query for the root's NS records, bypassing
+//! the cache, at most once per `PROBE_INTERVAL`.
+
+use std::{
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    doh::{is_idle, read_request, Request},
+    proto::{rcode, Class, Message, Name, Question, Type},
+    resolver::Resolver,
+};
+
+/// How long the outcome of an upstream probe is reused.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Idle time after which a probe connection is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections served at once, further ones are closed.
+const MAX_CONNECTIONS: usize = 8;
+
+/// What the probes report on.
+pub struct Health {
+    // set once every listener serves
+    started: AtomicBool,
+    alive: Box<dyn Fn() -> bool + Send + Sync>,
+    // what forwarded and recursive queries go to, none if there are none
+    resolver: Box<dyn Fn() -> Option<Arc<dyn Resolver>> + Send + Sync>,
+    // when upstreams were last probed, and how that went
+    probed: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl Health {
+    pub fn new(
+        alive: impl Fn() -> bool + Send + Sync + 'static,
+        resolver: impl Fn() -> Option<Arc<dyn Resolver>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            started: AtomicBool::new(false),
+            alive: Box::new(alive),
+            resolver: Box::new(resolver),
+            probed: Mutex::new(None),
+        }
+    }
+
+    /// Marks the server as serving on every listener.
+    pub fn set_started(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    pub fn live(&self) -> Result<(), String> {
+        match (self.alive)() {
+            true => Ok(()),
+            false => Err("the server state is locked".into()),
+        }
+    }
+
+    pub fn ready(&self) -> Result<(), String> {
+        self.live()?;
+        if !self.started.load(Ordering::Relaxed) {
+            return Err("the listeners aren't serving yet".into());
+        }
+        let Some(resolver) = (self.resolver)() else {
+            return Ok(());
+        };
+        let mut probed = self.probed.lock().unwrap();
+        match &*probed {
+            Some((at, outcome)) if at.elapsed() < PROBE_INTERVAL => outcome.clone(),
+            _ => {
+                let outcome = probe(&*resolver);
+                *probed = Some((Instant::now(), outcome.clone()));
+                outcome
+            }
+        }
+    }
+
+    /// Status and body of the response to `request`.
+    fn respond(&self, request: &Request) -> (u16, String) {
+        let path = request
+            .target
+            .split_once('?')
+            .map_or(&request.target[..], |(path, _)| path);
+        let check = match path {
+            "/livez" => Self::live,
+            "/readyz" => Self::ready,
+            _ => return (404, "no such probe".into()),
+        };
+        if request.method != "GET" {
+            return (405, "only GET is allowed".into());
+        }
+        match check(self) {
+            Ok(()) => (200, "ok".into()),
+            Err(reason) => (503, reason),
+        }
+    }
+}
+
+/// Whether `resolver` gets an answer for the root's NS records.
+fn probe(resolver: &dyn Resolver) -> Result<(), String> {
+    let query = Message {
+        id: rand::random(),
+        rd: 1,
+        questions: vec![Question {
+            name: Name::root(),
+            qtype: Type::NS,
+            class: Class::IN,
+        }],
+        ..Message::default()
+    };
+    match resolver.resolve(&query) {
+        Ok(reply) if reply.rcode == rcode::SERVFAIL => Err("upstreams answer SERVFAIL".into()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("no upstream answers: {:#}", e)),
+    }
+}
+
+fn to_bytes(status: u16, body: &str, keep_alive: bool) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let connection = match keep_alive {
+        true => "keep-alive",
+        false => "close",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\nCache-Control: no-store\r\nConnection: {}\r\n\r\n{}\n",
+        status,
+        reason,
+        body.len() + 1,
+        connection,
+        body
+    )
+    .into_bytes()
+}
+
+/// Answers probes on `listener`, each connection on its own thread.
+pub fn spawn(listener: TcpListener, health: Arc<Health>) -> Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("health".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                }
+                let (health, connections) = (health.clone(), connections.clone());
+                thread::spawn(move || {
+                    if let Err(e) = serve_conn(stream, &health) {
+                        tracing::debug!(error = %e, "Health probe connection failed");
+                    }
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        })?;
+    Ok(())
+}
+
+fn serve_conn(mut stream: TcpStream, health: &Health) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    loop {
+        let request = match read_request(&mut stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if is_idle(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (status, body) = health.respond(&request);
+        stream.write_all(&to_bytes(status, &body, request.keep_alive))?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Health;
+    use crate::{
+        doh::Request,
+        proto::{rcode, Message},
+        resolver::Resolver,
+    };
+    use anyhow::{bail, Result};
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct Upstream {
+        up: Arc<AtomicBool>,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl Resolver for Upstream {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            if !self.up.load(Ordering::Relaxed) {
+                bail!("timed out");
+            }
+            Ok(Message {
+                rcode: rcode::NOERROR,
+                ..request.reply()
+            })
+        }
+    }
+
+    fn get(health: &Health, target: &str) -> (u16, String) {
+        let request = Request {
+            method: "GET".into(),
+            target: target.into(),
+            ..Request::default()
+        };
+        health.respond(&request)
+    }
+
+    #[test]
+    fn test_probes() {
+        let up = Arc::new(AtomicBool::new(false));
+        let queries = Arc::new(AtomicUsize::new(0));
+        let upstream: Arc<dyn Resolver> = Arc::new(Upstream {
+            up: up.clone(),
+            queries: queries.clone(),
+        });
+        let health = Health::new(|| true, move || Some(upstream.clone()));
+
+        assert_eq!((200, "ok".to_string()), get(&health, "/livez"));
+        let (status, reason) = get(&health, "/readyz");
+        assert_eq!(503, status);
+        assert_eq!("the listeners aren't serving yet", reason);
+
+        health.set_started();
+        let (status, reason) = get(&health, "/readyz");
+        assert_eq!(503, status);
+        assert!(reason.starts_with("no upstream answers"), "{}", reason);
+
+        // the outcome is reused for a while
+        up.store(true, Ordering::Relaxed);
+        assert_eq!(503, get(&health, "/readyz").0);
+        assert_eq!(1, queries.load(Ordering::Relaxed));
+        *health.probed.lock().unwrap() = None;
+        assert_eq!((200, "ok".to_string()), get(&health, "/readyz?verbose"));
+
+        assert_eq!(404, get(&health, "/metrics").0);
+        let post = Request {
+            method: "POST".into(),
+            target: "/livez".into(),
+            ..Request::default()
+        };
+        assert_eq!(405, health.respond(&post).0);
+
+        // authoritative only, nothing to probe
+        let health = Health::new(|| true, || None);
+        health.set_started();
+        assert_eq!(200, get(&health, "/readyz").0);
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::{
+    handler::{local_soa, Context, Next, RequestHandler},
+    proto::{Class, Message, Name, Question, Record, Type},
+    rdata::RData,
+};
+
+/// TTL of answers synthesized from local host entries.
+const HOSTS_TTL: u32 = 60;
+
+/// Locally configured host addresses. They are answered authoritatively for
+/// A/AAAA and, when reverse lookups are enabled, the matching in-addr.arpa /
+/// ip6.arpa PTR names are synthesized from the same entries.
+///
+/// Entries of hosts files always get their PTR names. Overrides, like
+/// dnsmasq's `address=/example.lan/192.168.1.10`, do the same for a domain
+/// and every name under it. Host entries win over overrides, and more
+/// specific overrides over the domains they are under.
+#[derive(Debug, Default, Clone)]
+pub struct Hosts {
+    // name -> addresses, in insertion order
+    names: HashMap<Name, Vec<IpAddr>>,
+    // address -> lowercased names, first one is the canonical PTR target
+    addrs: HashMap<IpAddr, Vec<Name>>,
+    // address -> names answered for its PTR name either way
+    ptrs: HashMap<IpAddr, Vec<Name>>,
+    // overridden domain -> addresses
+    domains: HashMap<Name, Vec<IpAddr>>,
+    reverse: bool,
+}
+
+impl Hosts {
+    pub fn new(reverse: bool) -> Self {
+        Self {
+            reverse,
+            ..Self::default()
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, addr: IpAddr) {
+        insert(&mut self.names, &mut self.addrs, name, addr);
+    }
+
+    /// Answers `addr` for `domain` and the names under it.
+    pub fn insert_override(&mut self, domain: &str, addr: IpAddr) {
+        insert(&mut self.domains, &mut self.ptrs, domain, addr);
+    }
+
+    /// Inserts the entries of a hosts file, first names of addresses first.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for (name, addr) in parse_hosts_file(&text) {
+            insert(&mut self.names, &mut self.ptrs, &name, addr);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.domains.is_empty()
+    }
+
+    /// Addresses of the closest override `name` is at or under.
+    fn overridden(&self, name: &Name) -> Option<&Vec<IpAddr>> {
+        let mut suffix = name.clone();
+        loop {
+            if let Some(addrs) = self.domains.get(&suffix) {
+                return Some(addrs);
+            }
+            suffix = suffix.parent()?;
+        }
+    }
+
+    /// Answers `q` from the local entries. Returns `None` when the name isn't
+    /// known locally, and an empty answer set (NODATA) when it is but has no
+    /// records of the requested type.
+    pub fn lookup(&self, q: &Question) -> Option<Vec<Record>> {
+        let record = |rtype, rdata| Record {
+            name: q.name.clone(),
+            rtype,
+            class: Class::IN,
+            ttl: HOSTS_TTL,
+            rdata,
+        };
+
+        if let Some(addrs) = self.names.get(&q.name).or_else(|| self.overridden(&q.name)) {
+            let answers = addrs
+                .iter()
+                .filter_map(|addr| match (q.qtype, addr) {
+                    (Type::A | Type::ANY, IpAddr::V4(v4)) => Some(record(Type::A, RData::A(*v4))),
+                    (Type::AAAA | Type::ANY, IpAddr::V6(v6)) => {
+                        Some(record(Type::AAAA, RData::AAAA(*v6)))
+                    }
+                    _ => None,
+                })
+                .collect();
+            return Some(answers);
+        }
+
+        let addr = parse_reverse_name(&q.name.to_string())?;
+        let names = match self.addrs.get(&addr) {
+            Some(names) if self.reverse => names,
+            _ => self.ptrs.get(&addr)?,
+        };
+        match q.qtype {
+            Type::PTR | Type::ANY => Some(vec![record(Type::PTR, RData::PTR(names[0].clone()))]),
+            _ => Some(Vec::new()),
+        }
+    }
+}
+
+fn insert(
+    names: &mut HashMap<Name, Vec<IpAddr>>,
+    addrs: &mut HashMap<IpAddr, Vec<Name>>,
+    name: &str,
+    addr: IpAddr,
+) {
+    let Ok(name) = name.parse::<Name>() else {
+        return;
+    };
+    let name = name.to_lowercase();
+
+    let name_addrs = names.entry(name.clone()).or_default();
+    if !name_addrs.contains(&addr) {
+        name_addrs.push(addr);
+    }
+    let addr_names = addrs.entry(addr).or_default();
+    if !addr_names.contains(&name) {
+        addr_names.push(name);
+    }
+}
+
+/// Answers requests whose questions are all about local names
+/// authoritatively, and passes the rest on.
+impl RequestHandler for Hosts {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        if request.questions.is_empty() {
+            return next.run(ctx, request);
+        }
+        let mut answers = Vec::new();
+        for q in request.questions.iter() {
+            match self.lookup(q) {
+                Some(records) => answers.extend(records),
+                None => return next.run(ctx, request),
+            }
+        }
+
+        // NODATA, point the client at the SOA for the negative TTL
+        let authorities = match request.questions.first() {
+            Some(q) if answers.is_empty() => vec![local_soa(&q.name)],
+            _ => Vec::new(),
+        };
+        Ok(Message {
+            aa: 1,
+            answers,
+            authorities,
+            ..request.reply()
+        })
+    }
+}
+
+/// Reverse lookup name of an address, e.g. `4.3.2.1.in-addr.arpa` for
+/// 1.2.3.4 (RFC 1035, section 3.5) or the nibble form under `ip6.arpa` for
+/// IPv6 (RFC 3596, section 2.5).
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for b in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0xF, b >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Inverse of [`reverse_name`], `None` if `name` isn't a complete reverse name.
+pub fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = rest
+            .split('.')
+            .map(|l| l.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+
+    let rest = name.strip_suffix(".ip6.arpa")?;
+    let nibbles = rest
+        .split('.')
+        .map(|l| match l.len() {
+            1 => u8::from_str_radix(l, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (i, pair) in nibbles.rchunks(2).enumerate() {
+        octets[i] = pair[1] << 4 | pair[0];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+/// Entries of a hosts file, e.g. `/etc/hosts`: an address, then its names.
+/// Comments start with `#`, lines with an address that doesn't parse, like
+/// a scoped IPv6 one, are skipped, as are invalid names.
+pub fn parse_hosts_file(text: &str) -> Vec<(String, IpAddr)> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(addr)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        entries.extend(
+            fields
+                .filter(|name| name.parse::<Name>().is_ok())
+                .map(|name| (name.to_string(), addr)),
+        );
+    }
+    entries
+}
+
+/// Parses a dnsmasq-style `/domain[/domain...]/address` override.
+pub fn parse_override(s: &str) -> Result<(Vec<String>, IpAddr), String> {
+    let err = || format!("expected /DOMAIN[/DOMAIN...]/ADDRESS, got {:?}", s);
+    let (domains, addr) = s
+        .strip_prefix('/')
+        .and_then(|s| s.rsplit_once('/'))
+        .ok_or_else(err)?;
+    let domains: Vec<String> = domains.split('/').map(String::from).collect();
+    if domains.iter().any(|domain| domain.is_empty()) {
+        return Err(err());
+    }
+    for domain in domains.iter() {
+        domain.parse::<Name>()?;
+    }
+    let addr = addr.parse().map_err(|e| format!("{}: {:?}", e, addr))?;
+    Ok((domains, addr))
+}
+
+/// Parses a `name=address` host entry.
+pub fn parse_host_entry(s: &str) -> Result<(String, IpAddr), String> {
+    let (name, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=ADDRESS, got {:?}", s))?;
+    name.parse::<Name>()?;
+    let addr = addr.parse().map_err(|e| format!("{}: {:?}", e, addr))?;
+    Ok((name.into(), addr))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_host_entry, parse_hosts_file, parse_override, parse_reverse_name, reverse_name, Hosts,
+    };
+    use crate::{
+        proto::{test::question, Name, Type},
+        rdata::RData,
+    };
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_reverse_name_roundtrip() {
+        let cases = [
+            ("192.0.2.10", "10.2.0.192.in-addr.arpa"),
+            (
+                "2001:db8::567:89ab",
+                "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            ),
+        ];
+        for (addr, name) in cases {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(name, reverse_name(addr));
+            assert_eq!(Some(addr), parse_reverse_name(name));
+        }
+        assert_eq!(None, parse_reverse_name("2.0.192.in-addr.arpa"));
+        assert_eq!(None, parse_reverse_name("10.2.0.192.in-addr.arpa.example"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut hosts = Hosts::new(true);
+        hosts.insert("nas.lan", "192.168.1.10".parse().unwrap());
+        hosts.insert("NAS.lan.", "fd00::10".parse().unwrap());
+
+        let a = hosts.lookup(&question("nas.LAN", Type::A)).unwrap();
+        assert_eq!(1, a.len());
+        assert_eq!(RData::A("192.168.1.10".parse().unwrap()), a[0].rdata);
+
+        let aaaa = hosts.lookup(&question("nas.lan", Type::AAAA)).unwrap();
+        assert_eq!(RData::AAAA("fd00::10".parse().unwrap()), aaaa[0].rdata);
+
+        assert_eq!(Some(vec![]), hosts.lookup(&question("nas.lan", Type::MX)));
+        assert_eq!(None, hosts.lookup(&question("printer.lan", Type::A)));
+
+        let ptr = hosts
+            .lookup(&question("10.1.168.192.in-addr.arpa", Type::PTR))
+            .unwrap();
+        assert_eq!(RData::PTR(Name::from("nas.lan")), ptr[0].rdata);
+    }
+
+    #[test]
+    fn test_lookup_without_reverse() {
+        let mut hosts = Hosts::new(false);
+        hosts.insert("nas.lan", "192.168.1.10".parse().unwrap());
+        assert_eq!(
+            None,
+            hosts.lookup(&question("10.1.168.192.in-addr.arpa", Type::PTR))
+        );
+    }
+
+    #[test]
+    fn test_lookup_override() {
+        let mut hosts = Hosts::new(false);
+        hosts.insert_override("example.lan", "192.168.1.10".parse().unwrap());
+        hosts.insert_override("dev.example.lan", "192.168.1.20".parse().unwrap());
+        hosts.insert("nas.example.lan", "192.168.1.30".parse().unwrap());
+
+        let a = |name| {
+            hosts.lookup(&question(name, Type::A)).unwrap()[0]
+                .rdata
+                .clone()
+        };
+        assert_eq!(RData::A("192.168.1.10".parse().unwrap()), a("example.lan"));
+        assert_eq!(
+            RData::A("192.168.1.10".parse().unwrap()),
+            a("www.Example.lan")
+        );
+        // the closest override, host entries before any
+        assert_eq!(
+            RData::A("192.168.1.20".parse().unwrap()),
+            a("api.dev.example.lan")
+        );
+        // a dot inside a label doesn't put the name under dev.example.lan
+        assert_eq!(
+            RData::A("192.168.1.10".parse().unwrap()),
+            a("www\\.dev.example.lan")
+        );
+        assert_eq!(
+            RData::A("192.168.1.30".parse().unwrap()),
+            a("nas.example.lan")
+        );
+        assert_eq!(
+            Some(vec![]),
+            hosts.lookup(&question("www.example.lan", Type::AAAA))
+        );
+        assert_eq!(None, hosts.lookup(&question("badexample.lan", Type::A)));
+
+        // PTR names of overrides even without reverse lookups
+        let ptr = hosts
+            .lookup(&question("10.1.168.192.in-addr.arpa", Type::PTR))
+            .unwrap();
+        assert_eq!(RData::PTR(Name::from("example.lan")), ptr[0].rdata);
+        assert_eq!(
+            None,
+            hosts.lookup(&question("30.1.168.192.in-addr.arpa", Type::PTR))
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# static entries\n\
+             127.0.0.1\tlocalhost\n\
+             192.168.1.10 nas.lan nas # the NAS\n\
+             fe80::1%lo0 scoped\n\
+             fd00::10 nas.lan\n",
+        )
+        .unwrap();
+        let mut hosts = Hosts::new(false);
+        hosts.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let a = hosts.lookup(&question("nas", Type::A)).unwrap();
+        assert_eq!(RData::A("192.168.1.10".parse().unwrap()), a[0].rdata);
+        let aaaa = hosts.lookup(&question("nas.lan", Type::AAAA)).unwrap();
+        assert_eq!(RData::AAAA("fd00::10".parse().unwrap()), aaaa[0].rdata);
+        assert_eq!(None, hosts.lookup(&question("scoped", Type::AAAA)));
+        // the first name, without reverse lookups enabled
+        let ptr = hosts
+            .lookup(&question("10.1.168.192.in-addr.arpa", Type::PTR))
+            .unwrap();
+        assert_eq!(RData::PTR(Name::from("nas.lan")), ptr[0].rdata);
+
+        assert!(hosts.load(&path).is_err());
+        assert_eq!(
+            vec![("printer".to_string(), "192.0.2.1".parse().unwrap())],
+            parse_hosts_file("192.0.2.1 printer bad..name\nprinter2\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_override() {
+        assert_eq!(
+            Ok((
+                vec!["example.lan".into(), "example.test".into()],
+                "fd00::10".parse().unwrap()
+            )),
+            parse_override("/example.lan/example.test/fd00::10")
+        );
+        assert!(parse_override("example.lan/192.168.1.10").is_err());
+        assert!(parse_override("//192.168.1.10").is_err());
+        assert!(parse_override("/example.lan/").is_err());
+        assert!(parse_override("/a..lan/192.168.1.10").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_entry() {
+        assert_eq!(
+            Ok(("nas.lan".into(), "192.168.1.10".parse().unwrap())),
+            parse_host_entry("nas.lan=192.168.1.10")
+        );
+        assert!(parse_host_entry("nas.lan").is_err());
+        assert!(parse_host_entry("nas.lan=not-an-ip").is_err());
+        assert!(parse_host_entry("nas..lan=192.168.1.10").is_err());
+    }
+}
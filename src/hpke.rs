@@ -0,0 +1,209 @@
+//! Hybrid Public Key Encryption (RFC 9180) in base mode, with the one
+//! suite ODoH targets publish: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and
+//! AES-128-GCM.
+
+use rand::Rng;
+
+use crate::{
+    aes::{self, Aes},
+    digest::{hkdf_expand, hkdf_extract, Hash},
+    x25519,
+};
+
+pub const KEM_X25519_SHA256: u16 = 0x0020;
+pub const KDF_HKDF_SHA256: u16 = 0x0001;
+pub const AEAD_AES_128_GCM: u16 = 0x0001;
+
+/// Key length of the AEAD.
+pub const NK: usize = 16;
+
+/// Nonce length of the AEAD.
+pub const NN: usize = aes::NONCE_LEN;
+
+/// Output length of the KDF.
+pub const NH: usize = 32;
+
+/// Length of encapsulated keys.
+pub const NENC: usize = 32;
+
+const MODE_BASE: u8 = 0x00;
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &str, ikm: &[u8]) -> Vec<u8> {
+    let ikm = [b"HPKE-v1", suite_id, label.as_bytes(), ikm].concat();
+    hkdf_extract(Hash::Sha256, salt, &ikm)
+}
+
+fn labeled_expand(suite_id: &[u8], prk: &[u8], label: &str, info: &[u8], len: usize) -> Vec<u8> {
+    let info = [
+        &(len as u16).to_be_bytes(),
+        b"HPKE-v1".as_slice(),
+        suite_id,
+        label.as_bytes(),
+        info,
+    ]
+    .concat();
+    hkdf_expand(Hash::Sha256, prk, &info, len)
+}
+
+/// The KEM shared secret of a Diffie-Hellman result (section 4.1).
+fn extract_and_expand(dh: &[u8; 32], enc: &[u8; NENC], pk_r: &[u8; 32]) -> Vec<u8> {
+    let suite_id = [b"KEM".as_slice(), &KEM_X25519_SHA256.to_be_bytes()].concat();
+    let eae_prk = labeled_extract(&suite_id, b"", "eae_prk", dh);
+    let kem_context = [enc.as_slice(), pk_r].concat();
+    labeled_expand(&suite_id, &eae_prk, "shared_secret", &kem_context, 32)
+}
+
+/// Identifies the KEM, KDF and AEAD in labels of the key schedule.
+fn suite_id() -> Vec<u8> {
+    [
+        b"HPKE".as_slice(),
+        &KEM_X25519_SHA256.to_be_bytes(),
+        &KDF_HKDF_SHA256.to_be_bytes(),
+        &AEAD_AES_128_GCM.to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// Encryption context shared by sender and recipient (section 5.2).
+pub struct Context {
+    aead: Aes,
+    base_nonce: [u8; NN],
+    seq: u64,
+    exporter_secret: Vec<u8>,
+}
+
+impl Context {
+    /// The key schedule of base mode, no PSK (section 5.1).
+    fn new(shared_secret: &[u8], info: &[u8]) -> Self {
+        let suite_id = suite_id();
+        let psk_id_hash = labeled_extract(&suite_id, b"", "psk_id_hash", b"");
+        let info_hash = labeled_extract(&suite_id, b"", "info_hash", info);
+        let context = [[MODE_BASE].as_slice(), &psk_id_hash, &info_hash].concat();
+        let secret = labeled_extract(&suite_id, shared_secret, "secret", b"");
+        let key = labeled_expand(&suite_id, &secret, "key", &context, NK);
+        let base_nonce = labeled_expand(&suite_id, &secret, "base_nonce", &context, NN);
+        Self {
+            aead: Aes::new(&key).unwrap(),
+            base_nonce: base_nonce.try_into().unwrap(),
+            seq: 0,
+            exporter_secret: labeled_expand(&suite_id, &secret, "exp", &context, NH),
+        }
+    }
+
+    /// Nonce of the next message, the sequence number XORed into the base
+    /// nonce.
+    fn nonce(&self) -> [u8; NN] {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[NN - 8..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let sealed = self.aead.seal(&self.nonce(), aad, plaintext);
+        self.seq += 1;
+        sealed
+    }
+
+    /// Opens the next message, `None` if it was altered.
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = self.aead.open(&self.nonce(), aad, ciphertext)?;
+        self.seq += 1;
+        Some(plaintext)
+    }
+
+    /// Secret of `len` bytes derived from the context (section 5.3).
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Vec<u8> {
+        let suite_id = suite_id();
+        labeled_expand(
+            &suite_id,
+            &self.exporter_secret,
+            "sec",
+            exporter_context,
+            len,
+        )
+    }
+}
+
+/// Sets up a context to encrypt to `pk_r`, returning the encapsulated key
+/// the recipient sets up its side with. `None` for small-order keys.
+pub fn setup_base_s(pk_r: &[u8; 32], info: &[u8]) -> Option<([u8; NENC], Context)> {
+    setup_base_s_with(&rand::thread_rng().gen(), pk_r, info)
+}
+
+fn setup_base_s_with(
+    sk_e: &[u8; 32],
+    pk_r: &[u8; 32],
+    info: &[u8],
+) -> Option<([u8; NENC], Context)> {
+    let dh = x25519::shared_secret(sk_e, pk_r);
+    if dh == [0; 32] {
+        return None;
+    }
+    let enc = x25519::public_key(sk_e);
+    let shared_secret = extract_and_expand(&dh, &enc, pk_r);
+    Some((enc, Context::new(&shared_secret, info)))
+}
+
+/// Sets up the recipient's context for the encapsulated key `enc`.
+pub fn setup_base_r(enc: &[u8; NENC], sk_r: &[u8; 32], info: &[u8]) -> Option<Context> {
+    let dh = x25519::shared_secret(sk_r, enc);
+    if dh == [0; 32] {
+        return None;
+    }
+    let shared_secret = extract_and_expand(&dh, enc, &x25519::public_key(sk_r));
+    Some(Context::new(&shared_secret, info))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{setup_base_r, setup_base_s_with};
+    use crate::{
+        encoding::{hex_decode, hex_encode},
+        x25519,
+    };
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        hex_decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_base_mode() {
+        // RFC 9180, appendix A.1.1
+        let info = b"Ode on a Grecian Urn";
+        let sk_e = bytes("52c4a758a802cd8b936eceea314432798d5baf2d7e9235dc084ab1b9cfa2f736");
+        let sk_r = bytes("4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8");
+        let pk_r = x25519::public_key(&sk_r);
+
+        let (enc, mut sender) = setup_base_s_with(&sk_e, &pk_r, info).unwrap();
+        assert_eq!(
+            "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431",
+            hex_encode(&enc)
+        );
+        let sealed = sender.seal(b"Count-0", b"Beauty is truth, truth beauty");
+        assert_eq!(
+            "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a9\
+             6d8770ac83d07bea87e13c512a",
+            hex_encode(&sealed)
+        );
+        assert_eq!(
+            "3853fe2b4035195a573ffc53856e77058e15d9ea064de3e59f4961d0095250ee",
+            hex_encode(&sender.export(b"", 32))
+        );
+        assert_eq!(
+            "e9e43065102c3836401bed8c3c3c75ae46be1639869391d62c61f1ec7af54931",
+            hex_encode(&sender.export(b"TestContext", 32))
+        );
+
+        let mut recipient = setup_base_r(&enc, &sk_r, info).unwrap();
+        assert_eq!(None, recipient.open(b"Count-1", &sealed));
+        assert_eq!(
+            Some(b"Beauty is truth, truth beauty".to_vec()),
+            recipient.open(b"Count-0", &sealed)
+        );
+        // the second message takes the next nonce
+        let sealed = sender.seal(b"Count-1", b"Beauty is truth, truth beauty");
+        assert!(recipient.open(b"Count-1", &sealed).is_some());
+    }
+}
@@ -0,0 +1,230 @@
+//! The JSON API of Google's and Cloudflare's public resolvers: queries as
+//! `GET /resolve?name=example.com&type=A`, replies as `application/dns-json`
+//! objects with the status, the header flags and each section's records in
+//! presentation format.
+
+use std::fmt::Write;
+
+use crate::{
+    edns::Opt,
+    encoding::percent_decode,
+    proto::{Class, Message, Question, Record, Type},
+};
+
+/// Media type of the replies.
+pub const DNS_JSON: &str = "application/dns-json";
+
+/// Path the server answers JSON queries on.
+pub const PATH: &str = "/resolve";
+
+/// The query of a request's parameters: `name`, `type` as a mnemonic or a
+/// number and A if not given, and the `cd` and `do` flags, set by `1` or
+/// `true`.
+pub fn query(params: &str) -> Result<Message, String> {
+    let mut name = None;
+    let mut qtype = Type::A;
+    let mut cd = false;
+    let mut dnssec_ok = false;
+    for param in params.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value =
+            percent_decode(value).ok_or_else(|| format!("invalid parameter {:?}", param))?;
+        let flag = || match value.as_str() {
+            "" | "0" | "false" => Ok(false),
+            "1" | "true" => Ok(true),
+            _ => Err(format!("invalid {} flag {:?}", key, value)),
+        };
+        match key {
+            "name" => name = Some(value.clone()),
+            "type" => {
+                qtype = match value.parse::<u16>() {
+                    Ok(n) => n.into(),
+                    Err(_) => value
+                        .parse()
+                        .map_err(|_| format!("unknown type {:?}", value))?,
+                }
+            }
+            "cd" => cd = flag()?,
+            "do" => dnssec_ok = flag()?,
+            _ => {}
+        }
+    }
+    let name = name
+        .filter(|name| !name.is_empty())
+        .ok_or("missing name parameter")?;
+    Ok(Message {
+        rd: 1,
+        cd: cd as u8,
+        questions: vec![Question {
+            name: name.parse()?,
+            qtype,
+            class: Class::IN,
+        }],
+        opt: dnssec_ok.then(|| Opt {
+            dnssec_ok,
+            ..Opt::default()
+        }),
+        ..Message::default()
+    })
+}
+
+/// The reply as a JSON object, sections without records left out.
+pub fn to_json(reply: &Message) -> String {
+    let mut out = format!(
+        "{{\"Status\":{},\"TC\":{},\"RD\":{},\"RA\":{},\"AD\":{},\"CD\":{}",
+        reply.rcode,
+        reply.tc == 1,
+        reply.rd == 1,
+        reply.ra == 1,
+        reply.ad == 1,
+        reply.cd == 1
+    );
+    let questions: Vec<String> = reply
+        .questions
+        .iter()
+        .map(|question| {
+            format!(
+                "{{\"name\":{},\"type\":{}}}",
+                string(&question.name.to_fqdn()),
+                u16::from(question.qtype)
+            )
+        })
+        .collect();
+    write!(out, ",\"Question\":[{}]", questions.join(",")).unwrap();
+    for (section, records) in [
+        ("Answer", &reply.answers),
+        ("Authority", &reply.authorities),
+        ("Additional", &reply.additionals),
+    ] {
+        if !records.is_empty() {
+            let records: Vec<String> = records.iter().map(record).collect();
+            write!(out, ",\"{}\":[{}]", section, records.join(",")).unwrap();
+        }
+    }
+    out.push('}');
+    out
+}
+
+fn record(record: &Record) -> String {
+    format!(
+        "{{\"name\":{},\"type\":{},\"TTL\":{},\"data\":{}}}",
+        string(&record.name.to_fqdn()),
+        u16::from(record.rtype),
+        record.ttl,
+        string(&record.rdata.to_string())
+    )
+}
+
+/// JSON string literal of `s` (RFC 8259, section 7).
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{query, string, to_json};
+    use crate::{
+        proto::{rcode, Class, Name, Question, Record, Type},
+        rdata::{RData, Soa},
+    };
+
+    #[test]
+    fn test_query() {
+        let request = query("name=example.com&type=AAAA").unwrap();
+        assert_eq!(1, request.rd);
+        assert_eq!(
+            vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::AAAA,
+                class: Class::IN,
+            }],
+            request.questions
+        );
+        assert_eq!(None, request.opt);
+
+        let request = query("name=%E2%98%83.example&type=15&do=1&cd=true&ct=x").unwrap();
+        assert_eq!(
+            ["☃".as_bytes(), b"example"],
+            request.questions[0].name.iter_labels().collect::<Vec<_>>()[..]
+        );
+        assert_eq!(Type::MX, request.questions[0].qtype);
+        assert_eq!(1, request.cd);
+        assert!(request.opt.unwrap().dnssec_ok);
+        assert_eq!(
+            Type::A,
+            query("name=example.com").unwrap().questions[0].qtype
+        );
+        assert_eq!(
+            Type::TXT,
+            query("type=txt&name=example.com").unwrap().questions[0].qtype
+        );
+
+        assert!(query("type=A").is_err());
+        assert!(query("name=").is_err());
+        assert!(query("name=example.com&type=BOGUS").is_err());
+        assert!(query("name=example.com&do=maybe").is_err());
+        assert!(query("name=%zz").is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut reply = query("name=example.com").unwrap().reply();
+        reply.ra = 1;
+        reply.answers.push(Record {
+            name: Name::from("example.com"),
+            rtype: Type::TXT,
+            class: Class::IN,
+            ttl: 300,
+            rdata: RData::TXT(vec!["v=spf1 -all".into()]),
+        });
+        assert_eq!(
+            "{\"Status\":0,\"TC\":false,\"RD\":true,\"RA\":true,\"AD\":false,\"CD\":false,\
+             \"Question\":[{\"name\":\"example.com.\",\"type\":1}],\
+             \"Answer\":[{\"name\":\"example.com.\",\"type\":16,\"TTL\":300,\
+             \"data\":\"\\\"v=spf1 -all\\\"\"}]}",
+            to_json(&reply)
+        );
+
+        let mut reply = query("name=missing.example.com").unwrap().reply();
+        reply.rcode = rcode::NXDOMAIN;
+        reply.authorities.push(Record {
+            name: Name::from("example.com"),
+            rtype: Type::SOA,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::SOA(Soa {
+                mname: Name::from("ns.example.com"),
+                rname: Name::from("admin.example.com"),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            }),
+        });
+        let json = to_json(&reply);
+        assert!(json.starts_with("{\"Status\":3,"));
+        assert!(!json.contains("\"Answer\""));
+        assert!(json.ends_with(
+            "\"Authority\":[{\"name\":\"example.com.\",\"type\":6,\"TTL\":60,\
+             \"data\":\"ns.example.com. admin.example.com. 1 2 3 4 5\"}]}"
+        ));
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!("\"a\\\"b\\\\c\\u000a\"", string("a\"b\\c\n"));
+    }
+}
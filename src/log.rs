@@ -0,0 +1,300 @@
+//! Leveled, structured `tracing` events, written by a `tracing-subscriber`
+//! fmt layer on stderr, a file or syslog, one line each:
+//!
+//! ```text
+//! 2026-10-16T08:30:00Z  INFO dns_starter_rust::handler: Answered id=4660 client=192.0.2.7:5353 qname=example.com. qtype=A outcome=NOERROR
+//! ```
+//!
+//! Which events are written is decided by `EnvFilter` directives like
+//! `info,dns_starter_rust::forward=debug,dns_starter_rust::cache=off`: a
+//! default level, then levels for the targets named.
+
+use std::{
+    env, fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{level_filters::LevelFilter, Level, Metadata};
+use tracing_subscriber::{
+    filter::EnvFilter, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, reload,
+    util::SubscriberInitExt, Layer, Registry,
+};
+
+use crate::rdata::format_timestamp;
+
+/// Environment variable filter directives are read from, on top of the
+/// `--log-level` default.
+pub const FILTER_ENV: &str = "DNS_LOG";
+
+/// Socket of the local syslog daemon.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Syslog facility of the events, daemon (RFC 5424, section 6.2.1).
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Syslog severity of an event level (RFC 5424, section 6.2.1).
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Parses comma-separated `EnvFilter` directives, either a level for every
+/// target or `target=level`.
+pub fn filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| e.to_string())
+}
+
+/// The filter in use and the directives it was made of, later ones win.
+struct Filter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: String,
+}
+
+static FILTER: OnceLock<Mutex<Filter>> = OnceLock::new();
+
+/// Installs the subscriber, writing events of `level` and below, or as the
+/// directives in `DNS_LOG` say, on stderr until `set_output` says otherwise.
+pub fn init(level: LevelFilter) -> Result<(), String> {
+    let mut directives = level.to_string();
+    if let Ok(more) = env::var(FILTER_ENV) {
+        directives = format!("{},{}", directives, more);
+    }
+    let env_filter = filter(&directives).map_err(|e| format!("{}: {}", FILTER_ENV, e))?;
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(layer(Writer))
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    let _ = FILTER.set(Mutex::new(Filter { handle, directives }));
+    Ok(())
+}
+
+/// Adds filter directives to the ones in use.
+pub fn add_directives(more: &str) -> Result<(), String> {
+    let Some(current) = FILTER.get() else {
+        return Err("logging is not set up".into());
+    };
+    let mut current = current.lock().unwrap();
+    let directives = format!("{},{}", current.directives, more);
+    current
+        .handle
+        .reload(filter(&directives)?)
+        .map_err(|e| e.to_string())?;
+    current.directives = directives;
+    Ok(())
+}
+
+/// The fmt layer writing event lines, without the timestamp: the sink adds
+/// it unless syslog stamps the time itself.
+fn layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .without_time()
+        .with_writer(writer)
+}
+
+/// Where events are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Stderr,
+    // appended to, reopened by `reopen` once rotated
+    File(PathBuf),
+    Syslog,
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stderr => f.write_str("stderr"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Syslog => write!(f, "syslog at {}", SYSLOG_SOCKET),
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(PathBuf, File),
+    Syslog(UnixDatagram),
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn connect_syslog() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SYSLOG_SOCKET)?;
+    Ok(socket)
+}
+
+impl Sink {
+    fn open(output: &Output) -> io::Result<Self> {
+        Ok(match output {
+            Output::Stderr => Self::Stderr,
+            Output::File(path) => Self::File(path.clone(), open_file(path)?),
+            Output::Syslog => Self::Syslog(connect_syslog()?),
+        })
+    }
+
+    fn write(&mut self, level: Level, line: &str) {
+        match self {
+            Self::Stderr => {
+                let _ = writeln!(io::stderr().lock(), "{} {}", timestamp(), line);
+            }
+            Self::File(_, file) => {
+                let _ = writeln!(file, "{} {}", timestamp(), line);
+            }
+            // syslog stamps the time itself
+            Self::Syslog(socket) => {
+                let message = format!(
+                    "<{}>{}[{}]: {}",
+                    SYSLOG_FACILITY * 8 + severity(level),
+                    env!("CARGO_PKG_NAME"),
+                    process::id(),
+                    line.trim_start()
+                );
+                if socket.send(message.as_bytes()).is_err() {
+                    // the daemon restarted, try its new socket once
+                    if let Ok(reconnected) = connect_syslog() {
+                        let _ = reconnected.send(message.as_bytes());
+                        *socket = reconnected;
+                    }
+                }
+            }
+        }
+    }
+}
+
+static SINK: Mutex<Sink> = Mutex::new(Sink::Stderr);
+
+/// Writes events to `output` from now on.
+pub fn set_output(output: &Output) -> io::Result<()> {
+    *SINK.lock().unwrap() = Sink::open(output)?;
+    Ok(())
+}
+
+/// Opens the log file again, after it was rotated away.
+pub fn reopen() -> io::Result<()> {
+    let mut sink = SINK.lock().unwrap();
+    if let Sink::File(path, file) = &mut *sink {
+        *file = open_file(path)?;
+    }
+    Ok(())
+}
+
+/// Hands the lines of the fmt layer to the sink.
+struct Writer;
+
+/// An event line on its way to the sink, the fmt layer writes it whole.
+struct EventWriter(Level);
+
+impl Write for EventWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        SINK.lock().unwrap().write(self.0, line.trim_end());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Writer {
+    type Writer = EventWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        EventWriter(*meta.level())
+    }
+}
+
+/// The time now, like `2026-10-16T08:30:00Z`.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+    let ts = format_timestamp(now);
+    format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &ts[0..4],
+        &ts[4..6],
+        &ts[6..8],
+        &ts[8..10],
+        &ts[10..12],
+        &ts[12..14]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{filter, layer};
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_filter_and_format() {
+        let lines = Lines::default();
+        let writer = lines.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                filter("warn,dns_starter_rust::forward=trace,dns_starter_rust::cache=off").unwrap(),
+            )
+            .with(layer(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "dns_starter_rust", "dropped");
+            tracing::error!(target: "dns_starter_rust::cache", "dropped");
+            tracing::debug!(
+                target: "dns_starter_rust::forward",
+                upstream = %"192.0.2.53:53", error = %"timed out",
+                "Upstream failed"
+            );
+            tracing::warn!(target: "dns_starter_rust", id = 4660, "Request timed out");
+        });
+        assert_eq!(
+            "DEBUG dns_starter_rust::forward: Upstream failed upstream=192.0.2.53:53 error=timed out\n\
+             \x20WARN dns_starter_rust: Request timed out id=4660\n",
+            String::from_utf8(lines.0.lock().unwrap().clone()).unwrap()
+        );
+
+        assert!(filter("dns_starter_rust::forward=loud").is_err());
+    }
+}
@@ -1,130 +1,1906 @@
 #[allow(dead_code)]
+mod acl;
+#[allow(dead_code)]
+mod aes;
+#[allow(dead_code)]
+mod anchors;
+#[allow(dead_code)]
+mod any;
+#[allow(dead_code)]
+mod api;
+#[allow(dead_code)]
+mod balance;
+#[allow(dead_code)]
+mod bignum;
+#[allow(dead_code)]
+mod blocklist;
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod chaos;
+#[allow(dead_code)]
+mod coalesce;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod connections;
+#[allow(dead_code)]
+mod control;
+#[allow(dead_code)]
+mod daemon;
+#[allow(dead_code)]
+mod digest;
+#[allow(dead_code)]
+mod dns64;
+#[allow(dead_code)]
+mod dnscrypt;
+#[allow(dead_code)]
+mod dnssec;
+#[allow(dead_code)]
+mod dnstap;
+#[allow(dead_code)]
+mod doh;
+#[allow(dead_code)]
+mod doq;
+#[allow(dead_code)]
+mod ecdsa;
+#[allow(dead_code)]
+mod ecs;
+#[allow(dead_code)]
+mod ed25519;
+#[allow(dead_code)]
+mod edns;
+#[allow(dead_code)]
 mod encoder;
 #[allow(dead_code)]
+mod encoding;
+#[allow(dead_code)]
+mod eyeballs;
+#[allow(dead_code)]
+mod forward;
+#[allow(dead_code)]
+mod handler;
+#[allow(dead_code)]
+mod health;
+#[allow(dead_code)]
+mod hosts;
+#[allow(dead_code)]
+mod hpke;
+#[allow(dead_code)]
+mod json;
+#[allow(dead_code)]
+mod log;
+#[allow(dead_code)]
+mod notify;
+#[allow(dead_code)]
+mod odoh;
+#[allow(dead_code)]
+mod overload;
+#[allow(dead_code)]
+mod policy;
+#[allow(dead_code)]
+mod pool;
+#[allow(dead_code)]
+mod privacy;
+#[allow(dead_code)]
+mod privileges;
+#[allow(dead_code)]
 mod proto;
+#[allow(dead_code)]
+mod query;
+#[allow(dead_code)]
+mod querylog;
+#[allow(dead_code)]
+mod quic;
+#[allow(dead_code)]
+mod rdata;
+#[allow(dead_code)]
+mod recursor;
+#[allow(dead_code)]
+mod regex;
+#[allow(dead_code)]
+mod resolver;
+#[allow(dead_code)]
+mod rotate;
+#[allow(dead_code)]
+mod rrl;
+#[allow(dead_code)]
+mod rsa;
+#[allow(dead_code)]
+mod salsa20;
+#[allow(dead_code)]
+mod secondary;
+#[allow(dead_code)]
+mod shutdown;
+#[allow(dead_code)]
+mod socket;
+#[allow(dead_code)]
+mod stub;
+#[allow(dead_code)]
+mod systemd;
+#[allow(dead_code)]
+mod telemetry;
+#[allow(dead_code)]
+mod tls;
+#[allow(dead_code)]
+mod traffic;
+#[allow(dead_code)]
+mod tsig;
+#[allow(dead_code)]
+mod upstream;
+#[allow(dead_code)]
+mod validator;
+#[allow(dead_code)]
+mod wire;
+#[allow(dead_code)]
+mod x25519;
+#[allow(dead_code)]
+mod x509;
+#[allow(dead_code)]
+mod zone;
 
 use crate::{
-    encoder::{Decoder, Encoder},
-    proto::{Class, Message, Question, Record, Type},
+    acl::{Acl, AclHandler, Capability, Network},
+    anchors::TrustAnchor,
+    any::{AnyHandler, AnyPolicy},
+    balance::Strategy,
+    blocklist::{BlockResponse, BlocklistHandler},
+    cache::{Cache, CacheHandler},
+    chaos::{Chaos, ChaosHandler},
+    config::Config,
+    connections::Connections,
+    control::Command,
+    daemon::PidFile,
+    dns64::Prefix,
+    dnscrypt::Provider,
+    dnstap::{Dnstap, DnstapHandler},
+    ecs::{EcsHandler, EcsMode},
+    edns::Opt,
+    encoder::{length_prefixed, Decoder},
+    forward::{parse_forward_rule, parse_resolver, Endpoint},
+    handler::{Chain, Context, Dropped, EdnsVersion, Logging, Transport},
+    health::Health,
+    hosts::{parse_host_entry, parse_override, Hosts},
+    overload::{OverloadPolicy, QueueStats},
+    policy::Policy,
+    privacy::{Anonymization, Anonymizer},
+    proto::{rcode, Message, Name, Record, Type},
+    querylog::QueryLog,
+    resolver::{Resolver, ResolverHandler},
+    rotate::{AnswerOrder, Rotation},
+    rrl::{Action, RateLimit, RateLimiter},
+    secondary::Secondary,
+    shutdown::{InFlight, Shutdown},
+    socket::SocketOptions,
+    tls::Identity,
+    traffic::{Traffic, TrafficHandler},
+    tsig::{Key, Signer},
+    x509::{parse_pin_rule, Pin},
+    zone::{split_transfer, Authoritative, Zone, ZoneConfig},
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context as _, Result};
 use clap::Parser;
-use std::net::{SocketAddr, UdpSocket};
+use signal_hook::{
+    consts::{SIGHUP, SIGUSR1},
+    iterator::Signals,
+};
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::Ordering, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    net::{TcpListener, TcpStream, UdpSocket},
+    signal::unix::{signal, SignalKind},
+    sync::Semaphore,
+    time::timeout,
+};
+use tracing::level_filters::LevelFilter;
 
-/// Simple program to greet a person
+/// Simple DNS server
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Name of the person to greet
-    #[arg(short, long, value_parser)]
-    resolver: Option<SocketAddr>,
+    /// Upstream resolver to forward queries to, as ip:port, an https:// DoH
+    /// URL, a quic:// DoQ URL, an sdns:// DNSCrypt stamp or an odoh:// ODoH
+    /// target URL (repeatable, in order of preference)
+    #[arg(short, long = "resolver", value_parser = parse_resolver)]
+    resolvers: Vec<Endpoint>,
+
+    /// Resolve names from the root servers down instead of asking upstream
+    /// resolvers
+    #[arg(long)]
+    recursive: bool,
+
+    /// Root hints file in master file format, naming the root servers
+    /// recursion is primed from instead of the built-in ones
+    #[arg(long, value_name = "FILE")]
+    root_hints: Option<PathBuf>,
+
+    /// Synthesize AAAA records from A records for names without any (DNS64)
+    #[arg(long)]
+    dns64: bool,
+
+    /// NAT64 prefix synthesized AAAA records embed IPv4 addresses in
+    #[arg(long, default_value_t = Prefix::WELL_KNOWN)]
+    dns64_prefix: Prefix,
+
+    /// Network of AAAA records treated as missing for DNS64, besides
+    /// IPv4-mapped addresses, as CIDR (repeatable)
+    #[arg(long = "dns64-exclude", value_parser = Network::from_str)]
+    dns64_exclude: Vec<Network>,
+
+    /// Forward names under ZONE to other upstreams, as ZONE=ADDRESS[,ADDRESS...]
+    /// (repeatable, the port defaults to 53)
+    #[arg(long = "forward", value_parser = parse_forward_rule)]
+    forward_rules: Vec<(String, Vec<Endpoint>)>,
+
+    /// PEM bundle of CA certificates DoH, DoQ and ODoH upstreams and ODoH
+    /// relays are checked against, instead of the system's
+    #[arg(long, value_name = "PATH")]
+    tls_roots: Option<PathBuf>,
+
+    /// Also require the certificate of an upstream or relay host to match a
+    /// pin, as HOST=PIN[,PIN...] with sha256/BASE64 for the digest of its
+    /// public key or cert-sha256/BASE64 for the whole certificate
+    /// (repeatable)
+    #[arg(long = "tls-pin", value_parser = parse_pin_rule)]
+    tls_pins: Vec<(String, Vec<Pin>)>,
+
+    /// HTTPS URL of the relay queries to ODoH targets go through
+    #[arg(long, value_name = "URL")]
+    odoh_relay: Option<doh::Url>,
+
+    /// How upstream resolvers are picked for each query
+    #[arg(long, value_enum, default_value_t = Strategy::Fastest)]
+    upstream_strategy: Strategy,
+
+    /// How the A and AAAA records of a name are ordered in each answer, to
+    /// spread clients across the addresses
+    #[arg(long, value_enum, default_value_t = AnswerOrder::Rotate)]
+    answer_order: AnswerOrder,
+
+    /// What forwarded queries tell upstreams about the client's subnet
+    /// (EDNS Client Subnet)
+    #[arg(long, value_enum, default_value_t = EcsMode::Pass)]
+    ecs: EcsMode,
+
+    /// Most bits of IPv4 client addresses sent upstream with --ecs add
+    #[arg(long, default_value_t = ecs::IPV4_PREFIX, value_parser = clap::value_parser!(u8).range(0..=32))]
+    ecs_ipv4_prefix: u8,
+
+    /// Most bits of IPv6 client addresses sent upstream with --ecs add
+    #[arg(long, default_value_t = ecs::IPV6_PREFIX, value_parser = clap::value_parser!(u8).range(0..=128))]
+    ecs_ipv6_prefix: u8,
+
+    /// How long to wait for each upstream attempt, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    upstream_timeout_ms: u64,
+
+    /// Further upstream attempts after a timeout before answering SERVFAIL
+    #[arg(long, default_value_t = 2)]
+    upstream_retries: u32,
+
+    /// Local host entry answered authoritatively, as NAME=ADDRESS (repeatable)
+    #[arg(long = "host", value_parser = parse_host_entry)]
+    hosts: Vec<(String, IpAddr)>,
+
+    /// Hosts file whose entries are answered like --host ones, PTR names
+    /// included, e.g. /etc/hosts (repeatable)
+    #[arg(long = "hosts-file", value_name = "PATH")]
+    hosts_files: Vec<PathBuf>,
+
+    /// Address answered for domains and every name under them, with the
+    /// matching PTR name, as /DOMAIN[/DOMAIN...]/ADDRESS like dnsmasq's
+    /// address= (repeatable)
+    #[arg(long = "address", value_parser = parse_override)]
+    overrides: Vec<(Vec<String>, IpAddr)>,
+
+    /// Answer PTR queries for the addresses of local host entries
+    #[arg(long)]
+    reverse: bool,
+
+    /// Hosts file or domain list, a path or an HTTPS URL, whose names and
+    /// the names under them are blocked (repeatable)
+    #[arg(long = "blocklist", value_name = "PATH|URL")]
+    blocklists: Vec<blocklist::Source>,
+
+    /// How blocked names are answered: nxdomain, null for 0.0.0.0 and ::,
+    /// or an address
+    #[arg(long, default_value_t = BlockResponse::Nxdomain)]
+    block_response: BlockResponse,
+
+    /// How often blocklists are loaded again, in seconds, never if zero
+    #[arg(long, default_value_t = 24 * 60 * 60)]
+    blocklist_refresh_secs: u64,
+
+    /// Validate forwarded answers with DNSSEC, answering SERVFAIL for bogus
+    /// ones and setting AD on secure ones
+    #[arg(long)]
+    dnssec_validation: bool,
+
+    /// Trust anchor validation starts from besides the root's keys, as a DS
+    /// or DNSKEY record in presentation format (repeatable)
+    #[arg(long = "trust-anchor", value_parser = TrustAnchor::from_str)]
+    trust_anchors: Vec<TrustAnchor>,
+
+    /// File of trust anchors, one record per line (repeatable)
+    #[arg(long = "trust-anchor-file")]
+    trust_anchor_files: Vec<PathBuf>,
+
+    /// File tracking key rollovers of the anchored zones (RFC 5011), kept
+    /// up to date while running
+    #[arg(long)]
+    trust_anchor_state: Option<PathBuf>,
+
+    /// Master file of a zone to answer authoritatively (repeatable). Zones
+    /// with further options go in the config file
+    #[arg(long = "zone")]
+    zones: Vec<PathBuf>,
+
+    /// How ANY queries received over UDP are answered (RFC 8482)
+    #[arg(long, value_enum, default_value_t = AnyPolicy::Hinfo)]
+    udp_any: AnyPolicy,
+
+    /// How ANY queries received over TCP, HTTPS, QUIC and DNSCrypt are
+    /// answered (RFC 8482)
+    #[arg(long, value_enum, default_value_t = AnyPolicy::Full)]
+    tcp_any: AnyPolicy,
+
+    /// Answer to version.bind and version.server TXT queries in class CH,
+    /// refused if not set
+    #[arg(long, value_name = "TEXT")]
+    chaos_version: Option<String>,
+
+    /// Answer to hostname.bind TXT queries in class CH, refused if not set
+    #[arg(long, value_name = "TEXT")]
+    chaos_hostname: Option<String>,
+
+    /// Answer to id.server TXT queries in class CH, refused if not set
+    #[arg(long, value_name = "TEXT")]
+    chaos_id: Option<String>,
+
+    /// Network allowed to query, as CIDR (repeatable, all clients if none)
+    #[arg(long = "allow-query", value_parser = Network::from_str)]
+    allow_query: Vec<Network>,
+
+    /// Network allowed to have names resolved that aren't answered locally,
+    /// as CIDR (repeatable, all clients if none)
+    #[arg(long = "allow-recursion", value_parser = Network::from_str)]
+    allow_recursion: Vec<Network>,
+
+    /// Identical UDP responses per second to a client network before they
+    /// are dropped or slipped, 0 for no limit (response rate limiting)
+    #[arg(long, default_value_t = 0)]
+    rate_limit: u32,
+
+    /// Every how many rate limited responses one is sent truncated instead,
+    /// so real clients retry over TCP (0 drops them all)
+    #[arg(long, default_value_t = 2)]
+    rate_limit_slip: u32,
+
+    /// Address to listen on for UDP and TCP queries, as ip:port
+    /// (repeatable). Ignored when systemd passes the sockets
+    #[arg(long = "listen", default_value = "127.0.0.1:2053")]
+    listen: Vec<SocketAddr>,
+
+    /// Address to serve DNS over HTTPS on, as ip:port (repeatable), on
+    /// /dns-query and as the JSON API on /resolve. Needs --tls-cert and
+    /// --tls-key
+    #[arg(long = "doh-listen")]
+    doh_listen: Vec<SocketAddr>,
+
+    /// Address to serve DNS over plain HTTP on, as ip:port (repeatable), with
+    /// the same /dns-query and /resolve endpoints as --doh-listen
+    #[arg(long = "http-listen")]
+    http_listen: Vec<SocketAddr>,
+
+    /// Address to serve DNS over QUIC on, as ip:port (repeatable). Needs
+    /// --tls-cert and --tls-key
+    #[arg(long = "doq-listen")]
+    doq_listen: Vec<SocketAddr>,
+
+    /// Address to serve DNSCrypt on over UDP and TCP, as ip:port
+    /// (repeatable). Needs --dnscrypt-provider and --dnscrypt-key
+    #[arg(long = "dnscrypt-listen")]
+    dnscrypt_listen: Vec<SocketAddr>,
+
+    /// Provider name DNSCrypt clients fetch certificates for, starting with
+    /// 2.dnscrypt-cert.
+    #[arg(long, value_name = "NAME")]
+    dnscrypt_provider: Option<String>,
+
+    /// PEM Ed25519 private key of the DNSCrypt provider, signing the
+    /// certificates of the short-lived resolver keys
+    #[arg(long, value_name = "PATH")]
+    dnscrypt_key: Option<PathBuf>,
+
+    /// Address to serve the HTTP API to the records of the primary zones
+    /// on, as ip:port. Over TLS if --tls-cert and --tls-key are given.
+    /// Needs --api-token-file
+    #[arg(long, value_name = "ADDR")]
+    api_listen: Option<SocketAddr>,
+
+    /// File holding the token API requests must carry as
+    /// `Authorization: Bearer TOKEN`
+    #[arg(long, value_name = "PATH")]
+    api_token_file: Option<PathBuf>,
+
+    /// Address to answer health probes on over HTTP, as ip:port: /livez
+    /// and /readyz, ready once every listener serves and an upstream
+    /// answers
+    #[arg(long, value_name = "ADDR")]
+    health_listen: Option<SocketAddr>,
+
+    /// User to switch to once the sockets are bound, a name or a number
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// Group to switch to once the sockets are bound, the user's primary
+    /// group if not given
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+
+    /// PEM certificate chain presented to DoH, DoQ and API clients, the
+    /// server's certificate first
+    #[arg(long, value_name = "PATH")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the server's certificate, ECDSA or Ed25519
+    #[arg(long, value_name = "PATH")]
+    tls_key: Option<PathBuf>,
+
+    /// Configuration file, re-read on SIGHUP. Its settings take precedence
+    /// over the flags above
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Serve with this many blocking worker threads instead of the async runtime
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// UDP sockets bound to each --listen address with SO_REUSEPORT, the
+    /// kernel spreading queries over them, each with a receiver of its own
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    reuseport: usize,
+
+    /// Receive buffer size of listener and upstream sockets in bytes
+    /// (SO_RCVBUF), for bursts of queries
+    #[arg(long, value_name = "BYTES")]
+    recv_buffer: Option<u32>,
+
+    /// Send buffer size of listener and upstream sockets in bytes
+    /// (SO_SNDBUF)
+    #[arg(long, value_name = "BYTES")]
+    send_buffer: Option<u32>,
+
+    /// DSCP codepoint packets sent by listener and upstream sockets are
+    /// marked with, like 46 for expedited forwarding
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..64))]
+    dscp: Option<u8>,
+
+    /// Whether IPv6 listener and upstream sockets on [::] leave IPv4 to
+    /// others (IPV6_V6ONLY), the system's default if not given
+    #[arg(long, value_name = "BOOL")]
+    ipv6_only: Option<bool>,
+
+    /// Pending UDP queries at most, further ones are rejected
+    #[arg(long, default_value_t = QUEUE_SIZE)]
+    queue_size: usize,
+
+    /// How UDP queries are rejected while the queue is full
+    #[arg(long, value_enum, default_value_t = OverloadPolicy::Drop)]
+    overload: OverloadPolicy,
+
+    /// TCP connections served at once, further ones are closed right away
+    /// (0 for no limit)
+    #[arg(long, default_value_t = TCP_CONNECTIONS)]
+    tcp_max_connections: usize,
+
+    /// TCP connections served at once from a single client address (0 for
+    /// no limit)
+    #[arg(long, default_value_t = TCP_CONNECTIONS_PER_IP)]
+    tcp_max_connections_per_ip: usize,
+
+    /// Seconds a TCP connection may wait for its next query before it's
+    /// closed
+    #[arg(long, default_value_t = TCP_IDLE_TIMEOUT.as_secs())]
+    tcp_idle_timeout_secs: u64,
+
+    /// Cached replies at most, the least recently used are evicted first (0
+    /// disables the cache)
+    #[arg(long, default_value_t = CACHE_SIZE)]
+    cache_size: usize,
+
+    /// Lowest TTL, in seconds, on replies served from the cache
+    #[arg(long, default_value_t = 0)]
+    cache_min_ttl: u32,
+
+    /// File the cache is saved to on shutdown and periodically, and loaded
+    /// from on startup
+    #[arg(long)]
+    cache_file: Option<PathBuf>,
+
+    /// File every query is logged to with its outcome, in the format of
+    /// BIND's query log, `-` for stdout. Opened again on reloads
+    #[arg(long, value_name = "PATH")]
+    query_log: Option<PathBuf>,
+
+    /// Size in bytes past which the query log is rotated, never if zero
+    #[arg(long, default_value_t = 0)]
+    query_log_size: u64,
+
+    /// Rotated query logs kept, as PATH.1, PATH.2 and so on
+    #[arg(long, default_value_t = 3)]
+    query_log_versions: u32,
+
+    /// Unix socket of a dnstap collector client and forwarder queries and
+    /// responses are sent to
+    #[arg(long, value_name = "PATH")]
+    dnstap: Option<PathBuf>,
+
+    /// Name of this server in dnstap messages
+    #[arg(long, value_name = "NAME")]
+    dnstap_identity: Option<String>,
+
+    /// Unix socket to accept control commands on (reload, zone-reload
+    /// [ZONE], set-log-level FILTER, flush, flush-name NAME, flush-tree NAME,
+    /// stats, dump-stats, top [N]), and `ctl` sends them to
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// Most verbose events logged (off, error, warn, info, debug or trace),
+    /// `EnvFilter` directives like `info,dns_starter_rust::forward=debug` can
+    /// be given in DNS_LOG
+    #[arg(long, default_value_t = LevelFilter::INFO)]
+    log_level: LevelFilter,
+
+    /// File to append log events to instead of stderr, reopened on SIGHUP
+    /// so it can be rotated
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Send log events to the local syslog daemon instead of stderr
+    #[arg(long, conflicts_with = "log_file")]
+    syslog: bool,
+
+    /// Detach from the terminal and run in the background once the sockets
+    /// are bound. Logs go to syslog unless --log-file is given
+    #[arg(long)]
+    daemon: bool,
+
+    /// File to write the server's PID to, locked while it runs so a second
+    /// server with the same file won't start
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// How client addresses appear in logs, the query log, dnstap and
+    /// statistics
+    #[arg(long, value_enum, default_value_t = Anonymization::Off)]
+    anonymize: Anonymization,
+
+    /// Prefix length IPv4 client addresses are truncated to, and hashed by
+    /// network at
+    #[arg(long, default_value_t = privacy::IPV4_PREFIX)]
+    anonymize_ipv4_prefix: u8,
+
+    /// Prefix length IPv6 client addresses are truncated to, and hashed by
+    /// network at
+    #[arg(long, default_value_t = privacy::IPV6_PREFIX)]
+    anonymize_ipv6_prefix: u8,
+
+    /// Key of the client address hashes, so they stay the same across
+    /// restarts. Random if not given
+    #[arg(long, value_name = "KEY")]
+    anonymize_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Look a name up with the configured resolver instead of serving, and
+    /// print the reply
+    Query {
+        /// Name to look up
+        name: String,
+
+        /// Type of the records asked for
+        #[arg(default_value_t = Type::A, value_parser = Type::from_str)]
+        qtype: Type,
+
+        /// Follow the delegations from the root servers down, printing each
+        /// server asked, its round trip time and the records it sent
+        #[arg(long)]
+        trace: bool,
+    },
+
+    /// Send a command to the control socket of a running server, and print
+    /// the reply
+    Ctl {
+        /// Command and its arguments, like `flush-name example.com`
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+}
+
+/// How long a query may take in total before the client gets SERVFAIL, a
+/// backstop above the upstream timeouts and retries.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the buffers UDP queries are received into: the payload size
+/// advertised, and a byte more so a larger query shows as not fitting.
+const UDP_BUFFER_SIZE: usize = Opt::UDP_PAYLOAD_SIZE as usize + 1;
+
+/// Idle time after which a TCP connection is closed (RFC 7766, section 6.2.3).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default bounds of the TCP connections served at once, in total and from
+/// a single client address.
+const TCP_CONNECTIONS: usize = 1024;
+const TCP_CONNECTIONS_PER_IP: usize = 64;
+
+/// How often connections waiting for a query check for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default bound of the inbound UDP queue.
+const QUEUE_SIZE: usize = 1024;
+
+/// Default bound of the reply cache.
+const CACHE_SIZE: usize = 10_000;
+
+/// How often the cache is saved to the cache file while running.
+const CACHE_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long shutdown waits for in-flight queries before exiting anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handlers built from the configuration. Replaced as a whole on reload,
+/// queries already being answered keep the one they started with.
+struct State {
+    chain: Chain,
+    // for verifying signed requests
+    keys: Vec<Key>,
+    // zone SOAs and the secondaries to notify once the state is in use
+    notifications: Vec<(Record, Vec<SocketAddr>)>,
+    // limits identical UDP responses
+    rate_limiter: RateLimiter,
+    // listen address -> clients it serves
+    listener_acls: Vec<(SocketAddr, Acl)>,
+    // zones served and what they were loaded from, for reloading them
+    authoritative: Option<Arc<Authoritative>>,
+    zones: Vec<ZoneConfig>,
+    // what other queries go to, none if they're refused
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl State {
+    fn new(config: &Config, cache: &Arc<Cache>, traffic: &Arc<Traffic>) -> Result<Self> {
+        // before any upstream socket is bound
+        socket::set_options(config.sockets);
+        let mut hosts = Hosts::new(config.reverse);
+        for (name, addr) in config.hosts.iter() {
+            hosts.insert(name, *addr);
+        }
+        for path in config.hosts_files.iter() {
+            hosts.load(path)?;
+        }
+        for (domain, addr) in config.overrides.iter() {
+            hosts.insert_override(domain, *addr);
+        }
+
+        let mut chain = Chain::default()
+            .with(Logging)
+            .with(TrafficHandler(traffic.clone()));
+        if dnstap::sink().is_some() {
+            chain = chain.with(DnstapHandler);
+        }
+        if let Some(path) = &config.query_log {
+            chain = chain.with(QueryLog::open(
+                path,
+                config.query_log_size,
+                config.query_log_versions,
+            )?);
+        }
+        chain = chain.with(EdnsVersion).with(AclHandler {
+            capability: Capability::Query,
+            acl: config.query_acl.clone(),
+        });
+        chain = chain.with(ChaosHandler(config.chaos.clone()));
+        if config.answer_order != AnswerOrder::Preserve {
+            chain = chain.with(Rotation::new(config.answer_order));
+        }
+        if !config.rules.is_empty() {
+            chain = chain.with(Policy::new(config.rules.clone(), |upstreams| {
+                resolver::upstreams(config, upstreams.to_vec())
+            })?);
+        }
+        chain = chain.with(AnyHandler {
+            udp: config.udp_any,
+            tcp: config.tcp_any,
+        });
+        if !hosts.is_empty() {
+            chain = chain.with(hosts);
+        }
+        // built ahead of the zones, ALIAS records are flattened with it
+        let resolver = resolver::from_config(config)?;
+        let forwarding =
+            config.recursive || !config.resolvers.is_empty() || !config.forward_rules.is_empty();
+        let mut notifications = Vec::new();
+        let mut served = None;
+        if !config.zones.is_empty() {
+            let mut zones = Vec::new();
+            let mut secondaries = Vec::new();
+            for zone in config.zones.iter() {
+                match (zone.primary, &zone.origin) {
+                    (Some(primary), Some(origin)) => secondaries.push(Secondary::start(
+                        origin.clone(),
+                        primary,
+                        zone.allow_transfer.clone(),
+                        zone.transfer_keys.clone(),
+                        config.upstream_timeout,
+                    )),
+                    _ => {
+                        let loaded = Zone::load(zone)
+                            .with_context(|| format!("Failed to load {}", zone.file.display()))?;
+                        if !zone.notify.is_empty() {
+                            notifications.push((loaded.soa().clone(), zone.notify.clone()));
+                        }
+                        zones.push(loaded);
+                    }
+                }
+            }
+            let mut authoritative = Authoritative::new(zones).with_secondaries(secondaries);
+            if forwarding {
+                authoritative = authoritative.with_alias_resolver(resolver.clone());
+            }
+            let authoritative = Arc::new(authoritative);
+            chain = chain
+                .with(AclHandler {
+                    capability: Capability::Transfer,
+                    acl: config.transfer_acl.clone(),
+                })
+                .with(authoritative.clone());
+            served = Some(authoritative);
+
+            // authoritative only, other names are refused
+            if !forwarding {
+                return Ok(Self {
+                    chain,
+                    keys: config.keys.clone(),
+                    notifications,
+                    rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+                    listener_acls: config.listener_acls.clone(),
+                    authoritative: served,
+                    zones: config.zones.clone(),
+                    resolver: None,
+                });
+            }
+        }
+        chain = chain.with(AclHandler {
+            capability: Capability::Recursion,
+            acl: config.recursion_acl.clone(),
+        });
+        if !config.blocklists.is_empty() {
+            chain = chain.with(BlocklistHandler(blocklist::from_config(config)?));
+        }
+        Ok(Self {
+            keys: config.keys.clone(),
+            notifications,
+            rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+            listener_acls: config.listener_acls.clone(),
+            authoritative: served,
+            zones: config.zones.clone(),
+            resolver: Some(resolver.clone()),
+            chain: chain
+                .with(EcsHandler::new(
+                    config.ecs,
+                    config.ecs_ipv4_prefix,
+                    config.ecs_ipv6_prefix,
+                ))
+                .with(CacheHandler {
+                    cache: cache.clone(),
+                    prefetch: Some(resolver.clone()),
+                })
+                .with(ResolverHandler(resolver)),
+        })
+    }
+}
+
+impl State {
+    /// Tells secondaries about the zones just loaded. Only once the state
+    /// answers queries, so they see the new serial when they ask.
+    fn notify(&self) {
+        for (soa, targets) in self.notifications.iter() {
+            notify::send(soa.clone(), targets.clone());
+        }
+    }
+}
+
+struct Server {
+    // settings from the command line, the config file is applied on top
+    base: Config,
+    config_path: Option<PathBuf>,
+    state: RwLock<Arc<State>>,
+    // outlive reloads
+    cache: Arc<Cache>,
+    traffic: Arc<Traffic>,
+    shutdown: Shutdown,
+    // pending UDP queries, at most
+    queue_size: usize,
+    overload: OverloadPolicy,
+    queue_stats: QueueStats,
+    // open TCP connections, and how long they may wait for a query
+    connections: Arc<Connections>,
+    tcp_idle_timeout: Duration,
+    started: Instant,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    log::init(args.log_level).map_err(|e| anyhow!(e))?;
+    if let Some(Subcommand::Ctl { command }) = &args.command {
+        let Some(path) = &args.control else {
+            bail!("ctl needs --control, the path of the server's control socket");
+        };
+        let reply = control::send(path, &command.join(" "))?;
+        println!("{}", reply);
+        if reply.starts_with("error:") {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    privacy::set_anonymizer(Anonymizer::new(
+        args.anonymize,
+        args.anonymize_ipv4_prefix,
+        args.anonymize_ipv6_prefix,
+        args.anonymize_key.as_deref(),
+    ));
+
+    let base = Config {
+        resolvers: args.resolvers,
+        recursive: args.recursive,
+        root_hints: args.root_hints,
+        dns64: args.dns64,
+        dns64_prefix: args.dns64_prefix,
+        dns64_exclude: args.dns64_exclude,
+        forward_rules: args.forward_rules,
+        upstream_strategy: args.upstream_strategy,
+        answer_order: args.answer_order,
+        ecs: args.ecs,
+        ecs_ipv4_prefix: args.ecs_ipv4_prefix,
+        ecs_ipv6_prefix: args.ecs_ipv6_prefix,
+        upstream_timeout: Duration::from_millis(args.upstream_timeout_ms.max(1)),
+        upstream_retries: args.upstream_retries,
+        tls_roots: args.tls_roots,
+        tls_pins: args.tls_pins.into_iter().collect(),
+        odoh_relay: args.odoh_relay,
+        hosts: args.hosts,
+        hosts_files: args.hosts_files,
+        overrides: args
+            .overrides
+            .into_iter()
+            .flat_map(|(domains, addr)| domains.into_iter().map(move |domain| (domain, addr)))
+            .collect(),
+        reverse: args.reverse,
+        blocklists: args.blocklists,
+        block_response: args.block_response,
+        blocklist_refresh: Duration::from_secs(args.blocklist_refresh_secs),
+        query_log: args.query_log,
+        query_log_size: args.query_log_size,
+        query_log_versions: args.query_log_versions,
+        zones: args
+            .zones
+            .into_iter()
+            .map(|file| ZoneConfig {
+                file,
+                ..ZoneConfig::default()
+            })
+            .collect(),
+        keys: Vec::new(),
+        dnssec_validation: args.dnssec_validation,
+        trust_anchors: args.trust_anchors,
+        trust_anchor_files: args.trust_anchor_files,
+        trust_anchor_state: args.trust_anchor_state,
+        udp_any: args.udp_any,
+        tcp_any: args.tcp_any,
+        chaos: Chaos {
+            version: args.chaos_version,
+            hostname: args.chaos_hostname,
+            id: args.chaos_id,
+        },
+        rate_limit: RateLimit {
+            responses_per_second: args.rate_limit,
+            slip: args.rate_limit_slip,
+            ..RateLimit::default()
+        },
+        query_acl: Acl {
+            allow: args.allow_query,
+            deny: Vec::new(),
+        },
+        recursion_acl: Acl {
+            allow: args.allow_recursion,
+            deny: Vec::new(),
+        },
+        transfer_acl: Acl::default(),
+        listener_acls: Vec::new(),
+        rules: Vec::new(),
+        sockets: SocketOptions {
+            recv_buffer: args.recv_buffer,
+            send_buffer: args.send_buffer,
+            dscp: args.dscp,
+            ipv6_only: args.ipv6_only,
+        },
+    };
+    if let Some(Subcommand::Query { name, qtype, trace }) = &args.command {
+        let config = match &args.config {
+            Some(path) => base.load(path)?,
+            None => base,
+        };
+        return query::run(&config, name, *qtype, *trace);
+    }
+
+    let output = match &args.log_file {
+        Some(path) => log::Output::File(path.clone()),
+        None if args.syslog || args.daemon => log::Output::Syslog,
+        None => log::Output::Stderr,
+    };
+    log::set_output(&output).with_context(|| format!("Failed to log to {}", output))?;
+    let mut pid_file = args.pid_file.as_deref().map(PidFile::lock).transpose()?;
+    // before any thread is spawned, the daemon would be without it
+    let detached = match args.daemon {
+        true => Some(daemon::detach()?),
+        false => None,
+    };
+    if let Some(pid_file) = &mut pid_file {
+        pid_file.write()?;
+    }
 
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    println!("Logs from your program will appear here!");
+    if let Some(path) = &args.dnstap {
+        dnstap::install(Dnstap::new(path, args.dnstap_identity)?);
+    }
+    let server = Arc::new(Server {
+        queue_size: args.queue_size.max(1),
+        overload: args.overload,
+        connections: Arc::new(Connections::new(
+            args.tcp_max_connections,
+            args.tcp_max_connections_per_ip,
+        )),
+        tcp_idle_timeout: Duration::from_secs(args.tcp_idle_timeout_secs.max(1)),
+        ..Server::new(
+            base,
+            args.config,
+            Cache::new(args.cache_size, args.cache_min_ttl),
+        )?
+    });
+    spawn_signal_handler(server.clone())?;
+    if let Some(path) = &args.cache_file {
+        if path.exists() {
+            match server.cache.load(path) {
+                Ok(count) => tracing::info!(
+                    entries = %count, path = %path.display(),
+                    "Loaded cache"
+                ),
+                Err(e) => {
+                    tracing::warn!(
+                        path = %path.display(), error = %format!("{:#}", e),
+                        "Failed to load cache"
+                    )
+                }
+            }
+        }
+        spawn_cache_snapshots(server.clone(), path.clone());
+    }
+    if let Some(path) = &args.control {
+        let server = server.clone();
+        control::spawn(path, move |command| server.execute(command))
+            .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    }
 
-    // Uncomment this block to pass the first stage
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
+    // listeners only start serving once privileges are dropped
+    let mut starts: Vec<Box<dyn FnOnce() -> Result<()>>> = Vec::new();
+    let mut udp_sockets = Vec::new();
+    let mut tcp_listeners = Vec::new();
+    let mut listen = &args.listen[..];
+    if let Some(sockets) = systemd::listen_fds()? {
+        for socket in sockets.udp.iter() {
+            tracing::info!(addr = %socket.local_addr()?, "Listening on udp socket from systemd");
+        }
+        for listener in sockets.tcp.iter() {
+            tracing::info!(addr = %listener.local_addr()?, "Listening on tcp socket from systemd");
+        }
+        udp_sockets = sockets.udp;
+        tcp_listeners = sockets.tcp;
+        listen = &[];
+    }
+    for addr in listen {
+        udp_sockets.extend(socket::bind_udp(addr, args.reuseport)?);
+        tcp_listeners.push(socket::bind_tcp(addr)?);
+        tracing::info!(%addr, "Listening");
+    }
+    if !args.doh_listen.is_empty() {
+        let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+            bail!("--doh-listen needs --tls-cert and --tls-key");
+        };
+        for addr in args.doh_listen.iter() {
+            let identity = Identity::load(cert, key)
+                .with_context(|| format!("Failed to load TLS identity {}", cert.display()))?;
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("Failed to bind doh listener to {}", addr))?;
+            let local = listener.local_addr()?;
+            let server = server.clone();
+            starts.push(Box::new(move || {
+                doh::spawn(listener, Some(identity), move |source, packet| {
+                    server.answer_wire(local, source, packet, Transport::Https)
+                })
+            }));
+            tracing::info!(%addr, "Listening for DNS over HTTPS");
+        }
+    }
+    for addr in args.http_listen.iter() {
+        let listener = std::net::TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind http listener to {}", addr))?;
+        let local = listener.local_addr()?;
+        let server = server.clone();
+        starts.push(Box::new(move || {
+            doh::spawn(listener, None, move |source, packet| {
+                server.answer_wire(local, source, packet, Transport::Http)
+            })
+        }));
+        tracing::info!(%addr, "Listening for DNS over HTTP");
+    }
+    if let Some(addr) = &args.api_listen {
+        let Some(path) = &args.api_token_file else {
+            bail!("--api-listen needs --api-token-file");
+        };
+        let token = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read API token {}", path.display()))?
+            .trim()
+            .to_string();
+        if token.is_empty() {
+            bail!("API token file {} is empty", path.display());
+        }
+        let identity = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                Identity::load(cert, key)
+                    .with_context(|| format!("Failed to load TLS identity {}", cert.display()))?,
+            ),
+            _ => None,
+        };
+        let listener = std::net::TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind api listener to {}", addr))?;
+        let server = server.clone();
+        starts.push(Box::new(move || {
+            api::spawn(listener, identity, token, move || {
+                let state = server.state();
+                Some(api::Zones {
+                    authoritative: state.authoritative.clone()?,
+                    configs: state.zones.clone(),
+                })
+            })
+        }));
+        tracing::info!(%addr, "Listening for API requests");
+    }
+    if !args.doq_listen.is_empty() {
+        let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+            bail!("--doq-listen needs --tls-cert and --tls-key");
+        };
+        for addr in args.doq_listen.iter() {
+            let identity = Identity::load(cert, key)
+                .with_context(|| format!("Failed to load TLS identity {}", cert.display()))?;
+            let socket = std::net::UdpSocket::bind(addr)
+                .with_context(|| format!("Failed to bind doq socket to {}", addr))?;
+            let local = socket.local_addr()?;
+            let server = server.clone();
+            starts.push(Box::new(move || {
+                doq::spawn(socket, identity, move |source, packet| {
+                    server.answer_wire(local, source, packet, Transport::Quic)
+                })
+            }));
+            tracing::info!(%addr, "Listening for DNS over QUIC");
+        }
+    }
+    if !args.dnscrypt_listen.is_empty() {
+        let (Some(name), Some(key)) = (&args.dnscrypt_provider, &args.dnscrypt_key) else {
+            bail!("--dnscrypt-listen needs --dnscrypt-provider and --dnscrypt-key");
+        };
+        if !name.starts_with(dnscrypt::PROVIDER_PREFIX) {
+            bail!(
+                "DNSCrypt provider name must start with {}",
+                dnscrypt::PROVIDER_PREFIX
+            );
+        }
+        let provider_name: Name = name.parse().map_err(anyhow::Error::msg)?;
+        let x509::PrivateKey::Ed25519(seed) = x509::load_private_key(key)
+            .with_context(|| format!("Failed to load DNSCrypt key {}", key.display()))?
+        else {
+            bail!(
+                "DNSCrypt provider key {} isn't an Ed25519 key",
+                key.display()
+            );
+        };
+        for addr in args.dnscrypt_listen.iter() {
+            let socket = std::net::UdpSocket::bind(addr)
+                .with_context(|| format!("Failed to bind dnscrypt socket to {}", addr))?;
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("Failed to bind dnscrypt listener to {}", addr))?;
+            let local = socket.local_addr()?;
+            let provider = Provider::new(provider_name.clone(), seed);
+            let stamp = provider.stamp(local);
+            let server = server.clone();
+            starts.push(Box::new(move || {
+                dnscrypt::spawn(socket, listener, provider, move |source, packet| {
+                    server.answer_wire(local, source, packet, Transport::DnsCrypt)
+                })
+            }));
+            tracing::info!(%addr, %stamp, "Listening for DNSCrypt");
+        }
+    }
+    let health = Arc::new(Health::new(
+        {
+            let server = server.clone();
+            move || server.state.try_read().is_ok()
+        },
+        {
+            let server = server.clone();
+            move || server.state().resolver.clone()
+        },
+    ));
+    if let Some(addr) = &args.health_listen {
+        let listener = std::net::TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind health listener to {}", addr))?;
+        let health = health.clone();
+        starts.push(Box::new(move || health::spawn(listener, health)));
+        tracing::info!(%addr, "Listening for health probes");
+    }
+    if args.user.is_some() || args.group.is_some() {
+        privileges::drop(args.user.as_deref(), args.group.as_deref())
+            .context("Failed to drop privileges")?;
+        tracing::info!(
+            user = %args.user.as_deref().unwrap_or("-"),
+            group = %args.group.as_deref().unwrap_or("-"),
+            "Dropped privileges"
+        );
+    }
+    for start in starts {
+        start()?;
+    }
+    health.set_started();
+    // the listeners are bound, secondaries can ask right away
+    server.state().notify();
+    systemd::notify("READY=1");
+    if let Some(detached) = detached {
+        detached.ready()?;
+    }
+    let watched = server.clone();
+    systemd::spawn_watchdog(move || watched.state.try_read().is_ok());
+
+    match args.workers {
+        Some(workers) => pool::serve(server.clone(), udp_sockets, tcp_listeners, workers.max(1))?,
+        None => {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(serve(server.clone(), udp_sockets, tcp_listeners))?;
+            // handlers still blocked on an upstream past the deadline are abandoned
+            runtime.shutdown_timeout(Duration::from_millis(100));
+        }
+    }
+    if let Some(path) = &args.cache_file {
+        save_cache(&server, path);
+    }
+    Ok(())
+}
+
+/// Saves the cache to `path` every `CACHE_SNAPSHOT_INTERVAL`, so a crash
+/// loses at most that much.
+fn spawn_cache_snapshots(server: Arc<Server>, path: PathBuf) {
+    thread::spawn(move || loop {
+        thread::sleep(CACHE_SNAPSHOT_INTERVAL);
+        if server.shutdown.is_requested() {
+            return;
+        }
+        save_cache(&server, &path);
+    });
+}
+
+fn save_cache(server: &Server, path: &Path) {
+    match server.cache.save(path) {
+        Ok(count) => tracing::info!(entries = %count, path = %path.display(), "Saved cache"),
+        Err(e) => {
+            tracing::warn!(
+                path = %path.display(), error = %format!("{:#}", e),
+                "Failed to save cache"
+            )
+        }
+    }
+}
+
+/// Listens for queries on every socket, answering each one on its own task
+/// so a slow upstream only delays the client that asked. Returns once a
+/// shutdown signal arrived and the queries in flight were answered.
+async fn serve(
+    server: Arc<Server>,
+    udp_sockets: Vec<std::net::UdpSocket>,
+    tcp_listeners: Vec<std::net::TcpListener>,
+) -> Result<()> {
+    let mut listeners = Vec::new();
+    for listener in tcp_listeners {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        listeners.push(tokio::spawn(serve_tcp(listener, server.clone())));
+    }
+    // shared by all UDP sockets, each query holds a permit until answered
+    let queue = Arc::new(Semaphore::new(server.queue_size));
+    for udp_socket in udp_sockets {
+        udp_socket.set_nonblocking(true)?;
+        let endpoint = UdpEndpoint::new(UdpSocket::from_std(udp_socket)?, server.queue_size)?;
+        listeners.push(tokio::spawn(serve_udp(
+            endpoint,
+            server.clone(),
+            queue.clone(),
+        )));
+    }
+
+    shutdown_signal().await?;
+    systemd::notify("STOPPING=1");
+    tracing::info!("Shutting down");
+    server.shutdown.request();
+    listeners.iter().for_each(|listener| listener.abort());
+
+    // connections close once they've answered the query they're on
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while (server.shutdown.in_flight() > 0 || server.connections.open() > 0)
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    if server.shutdown.in_flight() > 0 {
+        tracing::warn!(in_flight = %server.shutdown.in_flight(), "Exiting with queries in flight");
+    }
+    tracing::info!("{}", server.queue_stats.summary());
+    tracing::info!("{}", server.cache.summary());
+    Ok(())
+}
+
+/// Reloads the configuration on every SIGHUP, logs statistics on every
+/// SIGUSR1.
+fn spawn_signal_handler(server: Arc<Server>) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGUSR1])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGUSR1 {
+                server.dump_stats();
+                continue;
+            }
+            if let Err(e) = log::reopen() {
+                tracing::error!(error = %e, "Failed to reopen the log file");
+            }
+            match server.reload() {
+                Ok(()) => tracing::info!("Configuration reloaded"),
+                Err(e) => {
+                    tracing::error!(error = %format!("{:#}", e), "Failed to reload configuration")
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() -> Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    for kind in [SignalKind::interrupt(), SignalKind::terminate()] {
+        let mut signal = signal(kind)?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            signal.recv().await;
+            let _ = tx.send(()).await;
+        });
+    }
+    rx.recv().await;
+    Ok(())
+}
+
+/// A reply waiting to be sent, its query counted as in flight until it is.
+type Reply = (Vec<u8>, SocketAddr, InFlight);
+
+/// A UDP socket of the async server. Queries are received and replies sent
+/// in batches, with a system call per batch rather than per packet.
+struct UdpEndpoint {
+    udp_socket: Arc<UdpSocket>,
+    local: SocketAddr,
+    replies: tokio::sync::mpsc::Sender<Reply>,
+}
+
+impl UdpEndpoint {
+    /// Wraps `udp_socket`, sending up to `queue_size` replies queued on it
+    /// until it's dropped.
+    fn new(udp_socket: UdpSocket, queue_size: usize) -> Result<Arc<Self>> {
+        let udp_socket = Arc::new(udp_socket);
+        let (replies, queued) = tokio::sync::mpsc::channel(queue_size.max(1));
+        tokio::spawn(send_replies(udp_socket.clone(), queued));
+        Ok(Arc::new(Self {
+            local: udp_socket.local_addr()?,
+            udp_socket,
+            replies,
+        }))
+    }
+
+    /// Receives as many packets as are waiting, up to one per buffer, once
+    /// there is one.
+    async fn recv(
+        &self,
+        bufs: &mut [[u8; UDP_BUFFER_SIZE]],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+        self.udp_socket
+            .async_io(Interest::READABLE, || {
+                socket::recv_batch(&*self.udp_socket, bufs)
+            })
+            .await
+    }
+
+    /// Queues `reply` to be sent with the next batch.
+    async fn send(&self, reply: Reply) {
+        let _ = self.replies.send(reply).await;
+    }
+}
+
+/// Sends the replies queued for `udp_socket` in batches, until no one can
+/// queue any more.
+async fn send_replies(udp_socket: Arc<UdpSocket>, mut queued: tokio::sync::mpsc::Receiver<Reply>) {
+    let mut batch = Vec::with_capacity(socket::BATCH);
+    while queued.recv_many(&mut batch, socket::BATCH).await > 0 {
+        let packets: Vec<_> = batch
+            .iter()
+            .map(|(reply, dest, _)| (&reply[..], *dest))
+            .collect();
+        let mut unsent = &packets[..];
+        while let Some((_, dest)) = unsent.first() {
+            let sent = udp_socket
+                .async_io(Interest::WRITABLE, || {
+                    socket::send_batch(&*udp_socket, unsent)
+                })
+                .await;
+            match sent {
+                Ok(sent) => unsent = &unsent[sent..],
+                Err(e) => {
+                    tracing::warn!(
+                        client = %privacy::client(*dest), error = %e,
+                        "Failed to send reply"
+                    );
+                    unsent = &unsent[1..];
+                }
+            }
+        }
+        batch.clear();
+    }
+}
+
+/// Receives queries on a single UDP socket until it fails.
+async fn serve_udp(endpoint: Arc<UdpEndpoint>, server: Arc<Server>, queue: Arc<Semaphore>) {
+    let mut bufs = vec![[0; UDP_BUFFER_SIZE]; socket::BATCH];
 
     loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                let _received_data = String::from_utf8_lossy(&buf[0..size]);
-                println!("Received {} bytes from {}", size, source);
-
-                let mut dec = Decoder::new(&buf);
-                let request = Message::decode(&mut dec)?;
-                println!("---> Parsed request: {:?}", request);
-
-                let reply = if let Some(fwd_addr) = args.resolver {
-                    println!("Forward server address: {}", fwd_addr);
-
-                    let mut reply = Message {
-                        id: request.id,
-                        opcode: request.opcode,
-                        rd: request.rd,
-                        rcode: if request.opcode == 0 { 0 } else { 4 },
-                        qr: 1,
-                        questions: request.questions.clone(),
-                        ..Message::default()
-                    };
-
-                    let fwd_socket =
-                        UdpSocket::bind("0.0.0.0:0").expect("Failed to bin fwd socket");
-
-                    for question in request.questions.iter() {
-                        let fwd_request = Message {
-                            questions: vec![Question {
-                                qtype: Type::A,
-                                class: Class::IN,
-                                ..question.clone()
-                            }],
-                            ..request.clone()
-                        };
-                        println!("---> Sending query to fwd server: {:?}", fwd_request);
-                        // fwd_request.questions = vec![question.clone()];
-                        let mut buf = Vec::with_capacity(512);
-                        let mut enc = Encoder::new(&mut buf);
-                        fwd_request.encode(&mut enc)?;
-
-                        fwd_socket
-                            .send_to(&buf, fwd_addr.to_string())
-                            .expect("failed to send forward request");
-
-                        let mut response_buf = [0u8; 512];
-                        let (_, _) = fwd_socket.recv_from(&mut response_buf)?;
-                        let mut dec = Decoder::new(&mut response_buf);
-                        let fwd_reply = Message::decode(&mut dec)?;
-
-                        println!("<--- Parsed reply from fwd server: {:?}", fwd_reply);
-
-                        for answer in fwd_reply.answers.into_iter() {
-                            reply.answers.push(answer);
-                        }
+        let received = match endpoint.recv(&mut bufs).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to receive");
+                break;
+            }
+        };
+        for (i, size, source) in received {
+            tracing::trace!(bytes = %size, client = %privacy::client(source), "Received");
+
+            let packet = bufs[i][..size].to_vec();
+            let Ok(permit) = queue.clone().try_acquire_owned() else {
+                if let Some(reply) = server.queue_stats.reject(server.overload, &packet) {
+                    let _ = endpoint
+                        .replies
+                        .try_send((reply, source, server.shutdown.track()));
+                }
+                continue;
+            };
+            server.queue_stats.accept();
+
+            let endpoint = endpoint.clone();
+            let server = server.clone();
+            let in_flight = server.shutdown.track();
+            tokio::spawn(async move {
+                let _permit = permit;
+                match serve_udp_query(endpoint.local, server, &packet, source).await {
+                    Ok(Some(reply)) => endpoint.send((reply, source, in_flight)).await,
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!(
+                        client = %privacy::client(source), error = %e,
+                        "Failed to serve query"
+                    ),
+                }
+            });
+        }
+    }
+}
+
+/// Answers a single query received over UDP on `listener`, none if it's
+/// dropped.
+async fn serve_udp_query(
+    listener: SocketAddr,
+    server: Arc<Server>,
+    packet: &[u8],
+    source: SocketAddr,
+) -> Result<Option<Vec<u8>>> {
+    let mut dec = Decoder::new(packet);
+    let mut request = Message::decode(&mut dec)?;
+
+    let max_size = udp_payload_limit(&request);
+    let (reply, signer) = match server.verify(packet, &mut request) {
+        Ok(signer) if !server.admits(listener, source) => {
+            (Some(request.error_reply(rcode::REFUSED)), signer)
+        }
+        Ok(signer) => {
+            let ctx = Context {
+                source,
+                transport: Transport::Udp,
+                key: signer.as_ref().and_then(|s| s.key().cloned()),
+            };
+            (handle_query(server.clone(), ctx, request).await, signer)
+        }
+        Err(signer) => (Some(request.error_reply(rcode::NOTAUTH)), Some(*signer)),
+    };
+    let Some(reply) = reply.and_then(|reply| server.rate_limit(source, reply)) else {
+        return Ok(None);
+    };
+    Ok(Some(encode_udp_reply(reply, max_size, signer)?))
+}
+
+/// Runs the blocking request handler off the async workers, answering
+/// SERVFAIL if it fails or takes longer than `REQUEST_TIMEOUT`. None if the
+/// request is dropped.
+async fn handle_query(server: Arc<Server>, ctx: Context, request: Message) -> Option<Message> {
+    let id = request.id;
+    let servfail = request.error_reply(rcode::SERVFAIL);
+    let task = tokio::task::spawn_blocking(move || server.handle_request(ctx, request));
+
+    let reply = match timeout(REQUEST_TIMEOUT, task).await {
+        Ok(Ok(Ok(reply))) => reply,
+        Ok(Ok(Err(e))) if e.is::<Dropped>() => return None,
+        Ok(Ok(Err(e))) => {
+            tracing::warn!(%id, error = %e, "Failed to handle request");
+            servfail
+        }
+        Ok(Err(e)) => {
+            tracing::error!(%id, error = %e, "Request handler failed");
+            servfail
+        }
+        Err(_) => {
+            tracing::warn!(%id, "Request timed out");
+            servfail
+        }
+    };
+    Some(reply)
+}
+
+impl Server {
+    fn new(base: Config, config_path: Option<PathBuf>, cache: Cache) -> Result<Self> {
+        let config = match &config_path {
+            Some(path) => base
+                .load(path)
+                .with_context(|| format!("Failed to load {}", path.display()))?,
+            None => base.clone(),
+        };
+        let cache = Arc::new(cache);
+        let traffic = Arc::new(Traffic::default());
+        Ok(Self {
+            base,
+            config_path,
+            state: RwLock::new(Arc::new(State::new(&config, &cache, &traffic)?)),
+            cache,
+            traffic,
+            shutdown: Shutdown::default(),
+            queue_size: QUEUE_SIZE,
+            overload: OverloadPolicy::default(),
+            queue_stats: QueueStats::default(),
+            connections: Arc::new(Connections::new(TCP_CONNECTIONS, TCP_CONNECTIONS_PER_IP)),
+            tcp_idle_timeout: TCP_IDLE_TIMEOUT,
+            started: Instant::now(),
+        })
+    }
+
+    /// Current state, valid for as long as the caller holds it.
+    fn state(&self) -> Arc<State> {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Re-reads the config file and swaps in the new state. The old state is
+    /// kept if the file can't be loaded.
+    fn reload(&self) -> Result<()> {
+        let Some(path) = &self.config_path else {
+            return Ok(());
+        };
+        systemd::notify("RELOADING=1");
+        let loaded = self
+            .base
+            .load(path)
+            .with_context(|| format!("Failed to load {}", path.display()))
+            .and_then(|config| State::new(&config, &self.cache, &self.traffic));
+        systemd::notify("READY=1");
+        let state = Arc::new(loaded?);
+        *self.state.write().unwrap() = state.clone();
+        state.notify();
+        Ok(())
+    }
+
+    /// Runs a command from the control socket, returns the reply line.
+    fn execute(&self, command: Command) -> String {
+        match command {
+            Command::Reload if self.config_path.is_none() => "error: no config file".into(),
+            Command::Reload => match self.reload() {
+                Ok(()) => "reloaded".into(),
+                Err(e) => format!("error: {:#}", e),
+            },
+            Command::ZoneReload(origin) => match self.reload_zones(origin.as_ref()) {
+                Ok(reply) => reply,
+                Err(e) => format!("error: {:#}", e),
+            },
+            Command::SetLogLevel(directives) => match log::add_directives(&directives) {
+                Ok(()) => format!("log filter {} applied", directives),
+                Err(e) => format!("error: {}", e),
+            },
+            Command::Flush => format!("flushed {} entries", self.cache.flush()),
+            Command::FlushName(name) => {
+                format!("flushed {} entries", self.cache.flush_name(&name, false))
+            }
+            Command::FlushTree(name) => {
+                format!("flushed {} entries", self.cache.flush_name(&name, true))
+            }
+            Command::Stats => format!(
+                "{}; {}; {}; {}",
+                self.queue_stats.summary(),
+                self.cache.summary(),
+                self.state().rate_limiter.summary(),
+                telemetry::upstreams().summary()
+            ),
+            Command::Top(n) => self.traffic.stats(n).summary(),
+            Command::DumpStats => {
+                self.dump_stats();
+                "statistics logged".into()
+            }
+        }
+    }
+
+    /// Reads the files of the primary zones again, or of the one at `origin`,
+    /// and notifies their secondaries. A secondary zone at `origin` is
+    /// checked with its primary instead. Returns the reply line.
+    fn reload_zones(&self, origin: Option<&Name>) -> Result<String> {
+        let state = self.state();
+        let Some(authoritative) = &state.authoritative else {
+            bail!("no zones are served");
+        };
+        if let Some(origin) = origin {
+            if authoritative.refresh(origin) {
+                return Ok(format!("refreshing {} from its primary", origin));
+            }
+        }
+        let mut reloaded = Vec::new();
+        for config in state.zones.iter().filter(|zone| zone.primary.is_none()) {
+            if let (Some(origin), Some(configured)) = (origin, &config.origin) {
+                if origin != configured {
+                    continue;
+                }
+            }
+            let zone = Zone::load(config)
+                .with_context(|| format!("Failed to load {}", config.file.display()))?;
+            if origin.is_some_and(|origin| *origin != zone.origin) {
+                continue;
+            }
+            let soa = zone.soa().clone();
+            reloaded.push(zone.origin.to_string());
+            authoritative.replace(zone);
+            if !config.notify.is_empty() {
+                notify::send(soa, config.notify.clone());
+            }
+        }
+        if let (Some(origin), true) = (origin, reloaded.is_empty()) {
+            bail!("no zone {}", origin);
+        }
+        tracing::info!(zones = %reloaded.join(" "), "Reloaded zones");
+        Ok(format!("reloaded {}", reloaded.join(" ")))
+    }
+
+    /// Logs a snapshot of the counters, an event for the server, the cache
+    /// and each upstream.
+    fn dump_stats(&self) {
+        let queue = &self.queue_stats;
+        let rate_limiter = &self.state().rate_limiter;
+        tracing::info!(
+            uptime_secs = %self.started.elapsed().as_secs(),
+            accepted = %queue.accepted.load(Ordering::Relaxed),
+            dropped = %queue.dropped.load(Ordering::Relaxed),
+            refused = %queue.refused.load(Ordering::Relaxed),
+            rate_limited = %rate_limiter.dropped(), slipped = %rate_limiter.slipped(),
+            "Server statistics"
+        );
+        let cache = self.cache.stats();
+        tracing::info!(
+            entries = %cache.entries, bytes = %cache.bytes, hits = %cache.hits,
+            misses = %cache.misses, hit_ratio = %format!("{:.3}", cache.hit_ratio()),
+            evictions = %cache.evictions,
+            "Cache statistics"
+        );
+        for upstream in telemetry::upstreams().stats() {
+            tracing::info!(
+                upstream = %upstream.upstream, queries = %upstream.queries,
+                servfails = %upstream.servfails, timeouts = %upstream.timeouts,
+                failures = %upstream.failures, rtt_p50 = %upstream.rtt_quantile(0.5),
+                rtt_p90 = %upstream.rtt_quantile(0.9), rtt_p99 = %upstream.rtt_quantile(0.99),
+                "Upstream statistics"
+            );
+        }
+    }
+
+    /// Checks the TSIG of a request against the current keys and drops it
+    /// from the parsed `request`. Returns the signer for the reply, which is
+    /// NOTAUTH if the check failed (RFC 8945, section 5.2).
+    fn verify(&self, packet: &[u8], request: &mut Message) -> Result<Option<Signer>, Box<Signer>> {
+        request.additionals.retain(|r| r.rtype != Type::TSIG);
+        tsig::verify(packet, &self.state().keys, tsig::now()).inspect_err(|signer| {
+            tracing::info!(id = %request.id, error = %signer.error(), "TSIG verification failed")
+        })
+    }
+
+    /// Whether the listener on `listener` serves `source`.
+    fn admits(&self, listener: SocketAddr, source: SocketAddr) -> bool {
+        self.state()
+            .listener_acls
+            .iter()
+            .filter(|(addr, _)| *addr == listener)
+            .all(|(_, acl)| acl.permits(source.ip()))
+    }
+
+    /// Applies response rate limiting to a UDP reply: `None` if it's
+    /// dropped, an empty truncated reply if it slips.
+    fn rate_limit(&self, source: SocketAddr, reply: Message) -> Option<Message> {
+        match self
+            .state()
+            .rate_limiter
+            .check(source.ip(), &reply, Instant::now())
+        {
+            Action::Send => Some(reply),
+            Action::Drop => None,
+            Action::Slip => Some(Message {
+                tc: 1,
+                answers: Vec::new(),
+                authorities: Vec::new(),
+                additionals: Vec::new(),
+                ..reply
+            }),
+        }
+    }
+
+    /// Answers a query received over HTTP(S), QUIC or DNSCrypt on
+    /// `listener`, both in wire format. Replies over HTTPS and QUIC to EDNS
+    /// queries are padded to hide their size. None if the query doesn't
+    /// parse or is dropped.
+    fn answer_wire(
+        &self,
+        listener: SocketAddr,
+        source: SocketAddr,
+        packet: &[u8],
+        transport: Transport,
+    ) -> Option<Vec<u8>> {
+        let mut request = Message::from_bytes(packet).ok()?;
+        let _in_flight = self.shutdown.track();
+        let (reply, signer) = match self.verify(packet, &mut request) {
+            Ok(signer) if !self.admits(listener, source) => {
+                (request.error_reply(rcode::REFUSED), signer)
+            }
+            Ok(signer) => {
+                let ctx = Context {
+                    source,
+                    transport,
+                    key: signer.as_ref().and_then(|s| s.key().cloned()),
+                };
+                let (id, servfail) = (request.id, request.error_reply(rcode::SERVFAIL));
+                let reply = match self.handle_request(ctx, request) {
+                    Ok(reply) => reply,
+                    Err(e) if e.is::<Dropped>() => return None,
+                    Err(e) => {
+                        tracing::warn!(%id, error = %e, "Failed to handle request");
+                        servfail
                     }
-                    reply
-                } else {
-                    let answers = request
-                        .questions
-                        .iter()
-                        .map(|q| Record {
-                            name: q.name.clone(),
-                            rtype: q.qtype,
-                            class: q.class,
-                            ttl: 60,
-                            rdata: vec![8u8; 4],
-                        })
-                        .collect();
-
-                    Message {
-                        id: request.id,
-                        opcode: request.opcode,
-                        rd: request.rd,
-                        rcode: if request.opcode == 0 { 0 } else { 4 },
-                        qr: 1,
-                        questions: request.questions,
-                        answers,
-                        ..Message::default()
+                };
+                (reply, signer)
+            }
+            Err(signer) => (request.error_reply(rcode::NOTAUTH), Some(*signer)),
+        };
+        // DNSCrypt pads on its own, within the query's size over UDP
+        let encoded = match transport {
+            Transport::Https | Transport::Quic => signer
+                .as_ref()
+                .map_or(Ok(0), Signer::overhead)
+                .and_then(|overhead| reply.to_padded_bytes(Opt::RESPONSE_PADDING, overhead)),
+            _ => reply.to_bytes(),
+        };
+        let signed = encoded.and_then(|mut buf| {
+            if let Some(mut signer) = signer {
+                signer.sign(&mut buf)?;
+            }
+            Ok(buf)
+        });
+        match signed {
+            Ok(buf) => Some(buf),
+            Err(e) => {
+                tracing::error!(id = %reply.id, error = %e, "Failed to encode reply");
+                None
+            }
+        }
+    }
+
+    /// Builds the reply to a parsed request by passing it down the current
+    /// handler chain.
+    fn handle_request(&self, ctx: Context, request: Message) -> Result<Message> {
+        self.state().chain.handle(&ctx, request)
+    }
+}
+
+/// Largest UDP reply the client accepts: 512 bytes without EDNS0, otherwise
+/// its advertised payload size capped at our own.
+fn udp_payload_limit(request: &Message) -> usize {
+    match &request.opt {
+        Some(opt) => opt.udp_payload_size.clamp(512, Opt::UDP_PAYLOAD_SIZE) as usize,
+        None => 512,
+    }
+}
+
+/// Encodes a UDP reply, signed if the request was, setting TC and dropping
+/// the record sections when it doesn't fit in `max_size` so the client
+/// retries over TCP.
+fn encode_udp_reply(reply: Message, max_size: usize, signer: Option<Signer>) -> Result<Vec<u8>> {
+    let overhead = signer.as_ref().map_or(Ok(0), Signer::overhead)?;
+    let mut buf = fit_udp_reply(reply, max_size.saturating_sub(overhead))?;
+    if let Some(mut signer) = signer {
+        signer.sign(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Encodes a reply in at most `max_size` bytes. The additional section is
+/// dropped first, without TC (RFC 2181, section 9).
+fn fit_udp_reply(reply: Message, max_size: usize) -> Result<Vec<u8>> {
+    let buf = reply.to_bytes()?;
+    if buf.len() <= max_size {
+        return Ok(buf);
+    }
+    if !reply.additionals.is_empty() {
+        let trimmed = Message {
+            additionals: Vec::new(),
+            ..reply.clone()
+        };
+        let buf = trimmed.to_bytes()?;
+        if buf.len() <= max_size {
+            return Ok(buf);
+        }
+    }
+
+    let truncated = Message {
+        tc: 1,
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        ..reply
+    };
+    Ok(truncated.to_bytes()?)
+}
+
+/// Encodes a TCP reply with its length prefix. Zone transfers become a
+/// sequence of messages, each signed in turn if the request was. Messages
+/// too long for the prefix to count are cut down as UDP replies are.
+fn encode_tcp_reply(reply: Message, mut signer: Option<Signer>) -> Result<Vec<u8>> {
+    let overhead = signer.as_ref().map_or(Ok(0), Signer::overhead)?;
+    let mut out = Vec::new();
+    for message in split_transfer(reply)? {
+        let mut buf = fit_udp_reply(message, u16::MAX as usize - overhead)?;
+        if let Some(signer) = signer.as_mut() {
+            signer.sign(&mut buf)?;
+        }
+        out.extend(length_prefixed(&buf)?);
+    }
+    Ok(out)
+}
+
+/// Accepts DNS over TCP connections (RFC 7766), serving each one on its own
+/// task.
+async fn serve_tcp(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, source)) => {
+                let connection = match server.connections.admit(source.ip()) {
+                    Ok(connection) => connection,
+                    Err(refusal) => {
+                        tracing::debug!(
+                            client = %privacy::client(source), reason = %refusal,
+                            "Closed TCP connection"
+                        );
+                        continue;
                     }
                 };
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let _connection = connection;
+                    if let Err(e) = serve_tcp_conn(stream, source, server).await {
+                        tracing::debug!(error = %e, "TCP connection failed");
+                    }
+                });
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to accept TCP connection"),
+        }
+    }
+}
 
-                let mut buf = Vec::new();
-                let mut enc = Encoder::new(&mut buf);
-                reply.encode(&mut enc)?;
+/// Waits for the next query on `stream`, false if it stays idle for the
+/// idle timeout or shutdown is requested first.
+async fn wait_for_query(stream: &TcpStream, server: &Server) -> io::Result<bool> {
+    let deadline = Instant::now() + server.tcp_idle_timeout;
+    while !server.shutdown.is_requested() && Instant::now() < deadline {
+        if let Ok(ready) = timeout(POLL_INTERVAL, stream.readable()).await {
+            ready?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Answers length-prefixed queries on a single connection until the client
+/// closes it or leaves it idle, or shutdown is requested. A query being
+/// answered then still gets its reply.
+async fn serve_tcp_conn(
+    mut stream: TcpStream,
+    source: SocketAddr,
+    server: Arc<Server>,
+) -> Result<()> {
+    let listener = stream.local_addr()?;
+    let idle = server.tcp_idle_timeout;
+    // idle connections are closed, the client reconnects if it needs to
+    while wait_for_query(&stream, &server).await? {
+        let mut len_buf = [0u8; 2];
+        match timeout(idle, stream.read_exact(&mut len_buf)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Ok(()),
+        }
+
+        let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        timeout(idle, stream.read_exact(&mut buf)).await??;
+        tracing::trace!(bytes = buf.len(), client = %privacy::client(source), "Received over TCP");
 
-                udp_socket
-                    .send_to(&buf, source)
-                    .expect("Failed to send response");
+        let mut request = Message::from_bytes(&buf)?;
+
+        let _in_flight = server.shutdown.track();
+        let (reply, signer) = match server.verify(&buf, &mut request) {
+            Ok(signer) if !server.admits(listener, source) => {
+                (Some(request.error_reply(rcode::REFUSED)), signer)
             }
-            Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
+            Ok(signer) => {
+                let ctx = Context {
+                    source,
+                    transport: Transport::Tcp,
+                    key: signer.as_ref().and_then(|s| s.key().cloned()),
+                };
+                (handle_query(server.clone(), ctx, request).await, signer)
             }
-        }
+            Err(signer) => (Some(request.error_reply(rcode::NOTAUTH)), Some(*signer)),
+        };
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+        stream.write_all(&encode_tcp_reply(reply, signer)?).await?;
     }
     Ok(())
 }
@@ -0,0 +1,122 @@
+//! Outgoing zone change notifications (RFC 1996).
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+
+use crate::proto::{opcode, Class, Message, Question, Record, Type};
+
+/// How long to wait for each acknowledgement.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// NOTIFY messages sent to each target at most (RFC 1996, section 3.6).
+const NOTIFY_ATTEMPTS: usize = 5;
+
+/// Tells each of `targets` on a thread that the zone of `soa` changed, so
+/// it checks for a new serial right away. Every target is sent NOTIFY until
+/// it acknowledges or `NOTIFY_ATTEMPTS` are used up.
+pub fn send(soa: Record, targets: Vec<SocketAddr>) {
+    thread::spawn(move || {
+        for target in targets {
+            match notify(&soa, target) {
+                Ok(()) => tracing::info!(%target, zone = %soa.name, "Sent NOTIFY"),
+                Err(e) => {
+                    tracing::warn!(
+                        %target, zone = %soa.name, error = %format!("{:#}", e),
+                        "Failed to send NOTIFY"
+                    )
+                }
+            }
+        }
+    });
+}
+
+fn notify(soa: &Record, target: SocketAddr) -> Result<()> {
+    let bind: SocketAddr = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+
+    let id = rand::thread_rng().gen();
+    let request = Message {
+        id,
+        opcode: opcode::NOTIFY,
+        aa: 1,
+        questions: vec![Question {
+            name: soa.name.clone(),
+            qtype: Type::SOA,
+            class: Class::IN,
+        }],
+        // hint of the new serial (RFC 1996, section 3.7)
+        answers: vec![soa.clone()],
+        ..Message::default()
+    }
+    .to_bytes()?;
+
+    let mut buf = [0u8; 512];
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(&request)?;
+        let Ok(len) = socket.recv(&mut buf) else {
+            continue;
+        };
+        let Ok(reply) = Message::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        if reply.id == id && reply.qr == 1 && reply.opcode == opcode::NOTIFY {
+            return Ok(());
+        }
+    }
+    bail!("no acknowledgement after {} attempts", NOTIFY_ATTEMPTS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::notify;
+    use crate::{
+        proto::{opcode, Class, Message, Name, Record, Type},
+        rdata::{RData, Soa},
+    };
+    use std::{net::UdpSocket, thread};
+
+    #[test]
+    fn test_notify() {
+        let secondary = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = secondary.local_addr().unwrap();
+        let soa = Record {
+            name: Name::from("example.com"),
+            rtype: Type::SOA,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::SOA(Soa {
+                serial: 42,
+                ..Soa::default()
+            }),
+        };
+
+        let acknowledge = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            // the first one is lost
+            secondary.recv_from(&mut buf).unwrap();
+            let (len, source) = secondary.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..len]).unwrap();
+            secondary
+                .send_to(&request.reply().to_bytes().unwrap(), source)
+                .unwrap();
+            request
+        });
+        notify(&soa, addr).unwrap();
+
+        let request = acknowledge.join().unwrap();
+        assert_eq!(opcode::NOTIFY, request.opcode);
+        assert_eq!(Type::SOA, request.questions[0].qtype);
+        assert_eq!(vec![soa], request.answers);
+    }
+}
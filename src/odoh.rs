@@ -0,0 +1,457 @@
+//! Oblivious DNS over HTTPS (RFC 9230). Queries are encrypted with HPKE to
+//! the public key of the target resolver and POSTed to a relay, which
+//! passes them on to the target: the relay sees who asks but not what, the
+//! target what is asked but not by whom. Replies come back the same way,
+//! encrypted with a key derived from the query's HPKE context. Targets
+//! publish their keys at a well-known URL, fetched once a day.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+
+use crate::{
+    aes::Aes,
+    digest::{hkdf_expand, hkdf_extract, Hash},
+    doh::{self, DohClient},
+    encoder::{self, Decoder, Encoder},
+    encoding::percent_encode,
+    hpke::{self, Context},
+    proto::Message,
+    wire::WireMessage,
+};
+
+/// Media type of ODoH messages (section 7).
+const ODOH_MESSAGE: &str = "application/oblivious-dns-message";
+
+/// Where targets publish their configurations (section 6.3).
+const CONFIGS_PATH: &str = "/.well-known/odohconfigs";
+
+/// Configurations are accepted whatever their media type, which RFC 9230
+/// leaves open.
+const CONFIGS_TYPE: &str = "*/*";
+
+const VERSION: u16 = 0x0001;
+
+// message types (section 6.1)
+const QUERY: u8 = 0x01;
+const RESPONSE: u8 = 0x02;
+
+/// Queries are padded to a multiple of this many bytes (RFC 8467, section
+/// 4.1).
+const PADDING_BLOCK: usize = 128;
+
+/// How long a target's configuration is used before fetching it again.
+const CONFIG_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Length of the nonce responses are keyed with, max(Nn, Nk).
+const RESPONSE_NONCE_LEN: usize = 16;
+
+/// An ODoH target, `odoh://host[:port][/path]`, reached with HTTPS through
+/// the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Target(doh::Url);
+
+impl FromStr for Target {
+    type Err = String;
+
+    /// The path defaults to `/dns-query`, the port to 443.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("odoh://")
+            .ok_or_else(|| format!("expected an odoh:// URL, got {:?}", s))?;
+        format!("https://{}", rest).parse().map(Self)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = self.0.to_string();
+        write!(f, "odoh://{}", url.trim_start_matches("https://"))
+    }
+}
+
+/// A target's public key, from the one configuration of its
+/// `ObliviousDoHConfigs` with the HPKE suite we implement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    public_key: [u8; 32],
+    // the encoded ObliviousDoHConfigContents, the key ID is derived from
+    contents: Vec<u8>,
+}
+
+impl Config {
+    pub fn new(public_key: [u8; 32]) -> Self {
+        let mut contents = Vec::with_capacity(40);
+        let mut enc = Encoder::new(&mut contents);
+        enc.write_u16(hpke::KEM_X25519_SHA256);
+        enc.write_u16(hpke::KDF_HKDF_SHA256);
+        enc.write_u16(hpke::AEAD_AES_128_GCM);
+        enc.write_length_prefixed(&public_key)
+            .expect("a 32-byte key fits its length prefix");
+        Self {
+            public_key,
+            contents,
+        }
+    }
+
+    /// Picks the first configuration we support out of `ObliviousDoHConfigs`
+    /// (section 6.3), skipping other versions and suites.
+    pub fn from_configs(data: &[u8]) -> Result<Self> {
+        let mut dec = Decoder::new(data);
+        let len = dec.read_u16()? as usize;
+        let mut configs = Decoder::new(dec.read_slice(len)?);
+        while configs.offset() < len {
+            let version = configs.read_u16()?;
+            let len = configs.read_u16()?;
+            let contents = configs.read_slice(len as usize)?;
+            if version != VERSION {
+                continue;
+            }
+            let mut dec = Decoder::new(contents);
+            let suite = (dec.read_u16()?, dec.read_u16()?, dec.read_u16()?);
+            let key_len = dec.read_u16()?;
+            let key = dec.read_slice(key_len as usize)?;
+            if suite
+                == (
+                    hpke::KEM_X25519_SHA256,
+                    hpke::KDF_HKDF_SHA256,
+                    hpke::AEAD_AES_128_GCM,
+                )
+            {
+                if let Ok(key) = key.try_into() {
+                    return Ok(Self::new(key));
+                }
+            }
+        }
+        bail!("no supported ODoH configuration")
+    }
+
+    /// Encodes the configuration as `ObliviousDoHConfigs`.
+    pub fn to_configs(&self) -> Result<Vec<u8>, encoder::Error> {
+        let mut data = Vec::with_capacity(self.contents.len() + 6);
+        Encoder::new(&mut data).with_length_prefix(|enc| {
+            enc.write_u16(VERSION);
+            enc.write_length_prefixed(&self.contents)
+        })?;
+        Ok(data)
+    }
+
+    /// Identifies the key in queries (section 6.2).
+    fn key_id(&self) -> Vec<u8> {
+        let prk = hkdf_extract(Hash::Sha256, b"", &self.contents);
+        hkdf_expand(Hash::Sha256, &prk, b"odoh key id", hpke::NH)
+    }
+}
+
+/// `ObliviousDoHMessagePlaintext`, a DNS message with zero padding.
+fn plaintext(message: &[u8], padding: usize) -> Result<Vec<u8>, encoder::Error> {
+    let mut data = Vec::with_capacity(message.len() + padding + 4);
+    let mut enc = Encoder::new(&mut data);
+    enc.write_length_prefixed(message)?;
+    enc.write_length_prefixed(&vec![0; padding])?;
+    Ok(data)
+}
+
+/// The DNS message of an `ObliviousDoHMessagePlaintext`, `None` if it
+/// doesn't parse or the padding isn't all zeros.
+fn from_plaintext(data: &[u8]) -> Option<Vec<u8>> {
+    let mut dec = Decoder::new(data);
+    let len = dec.read_u16().ok()?;
+    let message = dec.read_slice(len as usize).ok()?;
+    let len = dec.read_u16().ok()?;
+    let padding = dec.read_slice(len as usize).ok()?;
+    (dec.offset() == data.len() && padding.iter().all(|b| *b == 0)).then(|| message.to_vec())
+}
+
+/// `ObliviousDoHMessage`: type, key ID and encrypted message (section 6.1).
+fn encode_message(kind: u8, key_id: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, encoder::Error> {
+    let mut data = Vec::with_capacity(key_id.len() + encrypted.len() + 5);
+    let mut enc = Encoder::new(&mut data);
+    enc.write_u8(kind);
+    enc.write_length_prefixed(key_id)?;
+    enc.write_length_prefixed(encrypted)?;
+    Ok(data)
+}
+
+/// Splits an `ObliviousDoHMessage` of type `kind` into key ID and encrypted
+/// message.
+fn decode_message(data: &[u8], kind: u8) -> Option<(&[u8], &[u8])> {
+    let mut dec = Decoder::new(data);
+    if dec.read_u8().ok()? != kind {
+        return None;
+    }
+    let len = dec.read_u16().ok()?;
+    let key_id = dec.read_slice(len as usize).ok()?;
+    let len = dec.read_u16().ok()?;
+    let encrypted = dec.read_slice(len as usize).ok()?;
+    (dec.offset() == data.len()).then_some((key_id, encrypted))
+}
+
+/// Associated data of a message: its type and length-prefixed key ID.
+fn aad(kind: u8, key_id: &[u8]) -> Result<Vec<u8>, encoder::Error> {
+    let mut data = Vec::with_capacity(key_id.len() + 3);
+    let mut enc = Encoder::new(&mut data);
+    enc.write_u8(kind);
+    enc.write_length_prefixed(key_id)?;
+    Ok(data)
+}
+
+/// What the response to a query is keyed with: the HPKE context of the
+/// query and its plaintext.
+pub struct Exchange {
+    context: Context,
+    query: Vec<u8>,
+}
+
+impl Exchange {
+    /// AEAD key and nonce of the response with `nonce` (section 6.4).
+    fn response_key(
+        &self,
+        nonce: &[u8],
+    ) -> Result<([u8; hpke::NK], [u8; hpke::NN]), encoder::Error> {
+        let secret = self.context.export(b"odoh response", hpke::NK);
+        let mut salt = self.query.clone();
+        let end = salt.len();
+        let mut enc = Encoder::new(&mut salt);
+        enc.set_offset(end);
+        enc.write_length_prefixed(nonce)?;
+        let prk = hkdf_extract(Hash::Sha256, &salt, &secret);
+        let key = hkdf_expand(Hash::Sha256, &prk, b"odoh key", hpke::NK);
+        let nonce = hkdf_expand(Hash::Sha256, &prk, b"odoh nonce", hpke::NN);
+        Ok((key.try_into().unwrap(), nonce.try_into().unwrap()))
+    }
+}
+
+/// Encrypts the DNS message `query` to the target of `config` (section
+/// 6.3), padded to a multiple of `PADDING_BLOCK`.
+pub fn encrypt_query(config: &Config, query: &[u8]) -> Result<(Vec<u8>, Exchange)> {
+    let padding = (query.len() + 4).next_multiple_of(PADDING_BLOCK) - query.len() - 4;
+    let plain = plaintext(query, padding)?;
+    let (enc, mut context) = hpke::setup_base_s(&config.public_key, b"odoh query")
+        .ok_or_else(|| anyhow!("invalid ODoH target key"))?;
+    let key_id = config.key_id();
+    let mut encrypted = enc.to_vec();
+    encrypted.extend_from_slice(&context.seal(&aad(QUERY, &key_id)?, &plain));
+    let message = encode_message(QUERY, &key_id, &encrypted)?;
+    Ok((
+        message,
+        Exchange {
+            context,
+            query: plain,
+        },
+    ))
+}
+
+/// Decrypts the target's response to the query of `exchange` (section
+/// 6.5), returning the DNS message.
+pub fn decrypt_response(exchange: &Exchange, message: &[u8]) -> Result<Vec<u8>> {
+    let (nonce, encrypted) =
+        decode_message(message, RESPONSE).ok_or_else(|| anyhow!("invalid ODoH response"))?;
+    let (key, aead_nonce) = exchange.response_key(nonce)?;
+    let plain = Aes::new(&key)
+        .unwrap()
+        .open(&aead_nonce, &aad(RESPONSE, nonce)?, encrypted)
+        .ok_or_else(|| anyhow!("undecryptable ODoH response"))?;
+    from_plaintext(&plain).ok_or_else(|| anyhow!("invalid ODoH response plaintext"))
+}
+
+/// Target side: decrypts a query for the key pair of `secret` and
+/// `config`, returning the DNS message.
+pub fn decrypt_query(
+    secret: &[u8; 32],
+    config: &Config,
+    message: &[u8],
+) -> Option<(Vec<u8>, Exchange)> {
+    let (key_id, encrypted) = decode_message(message, QUERY)?;
+    if key_id != config.key_id() || encrypted.len() < hpke::NENC {
+        return None;
+    }
+    let (enc, ciphertext) = encrypted.split_at(hpke::NENC);
+    let mut context = hpke::setup_base_r(enc.try_into().unwrap(), secret, b"odoh query")?;
+    let plain = context.open(&aad(QUERY, key_id).ok()?, ciphertext)?;
+    let query = from_plaintext(&plain)?;
+    Some((
+        query,
+        Exchange {
+            context,
+            query: plain,
+        },
+    ))
+}
+
+/// Target side: encrypts the DNS message `response` to the query of
+/// `exchange`.
+pub fn encrypt_response(exchange: &Exchange, response: &[u8]) -> Result<Vec<u8>> {
+    let nonce: [u8; RESPONSE_NONCE_LEN] = rand::thread_rng().gen();
+    let (key, aead_nonce) = exchange.response_key(&nonce)?;
+    let encrypted = Aes::new(&key).unwrap().seal(
+        &aead_nonce,
+        &aad(RESPONSE, &nonce)?,
+        &plaintext(response, 0)?,
+    );
+    Ok(encode_message(RESPONSE, &nonce, &encrypted)?)
+}
+
+/// Client for ODoH targets, caching their configurations.
+#[derive(Default)]
+pub struct OdohClient {
+    // target -> its configuration, when it was fetched
+    configs: Mutex<HashMap<Target, (Config, Instant)>>,
+}
+
+impl OdohClient {
+    /// Sends `request` to `target` through `relay` over `doh`'s
+    /// connections, waiting up to `timeout` for each step. Like DoH, the
+    /// message goes out with ID 0. A failure drops the target's
+    /// configuration, in case its key changed.
+    pub fn query(
+        &self,
+        doh: &DohClient,
+        relay: &doh::Url,
+        target: &Target,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<WireMessage> {
+        let result = self.exchange(doh, relay, target, request, timeout);
+        if result.is_err() {
+            self.configs.lock().unwrap().remove(target);
+        }
+        result
+    }
+
+    fn exchange(
+        &self,
+        doh: &DohClient,
+        relay: &doh::Url,
+        target: &Target,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<WireMessage> {
+        let config = self.config(doh, target, timeout)?;
+        let query = Message {
+            id: 0,
+            ..request.clone()
+        }
+        .to_bytes()?;
+        let (message, exchange) = encrypt_query(&config, &query)?;
+        let separator = if relay.path().contains('?') { '&' } else { '?' };
+        let path = format!(
+            "{}{}targethost={}&targetpath={}",
+            relay.path(),
+            separator,
+            percent_encode(&target.0.host()),
+            percent_encode(target.0.path())
+        );
+        let body = Some((ODOH_MESSAGE, message.as_slice()));
+        let response = doh.fetch(relay, &path, body, ODOH_MESSAGE, timeout)?;
+        let mut reply = WireMessage::new(decrypt_response(&exchange, &response)?)?;
+        if !reply.view().is_reply_to(&request.questions) {
+            bail!("unexpected reply from {}", target);
+        }
+        reply.set_id(request.id);
+        Ok(reply)
+    }
+
+    /// The target's configuration, fetched straight from the target when
+    /// missing or `CONFIG_REFRESH` old.
+    fn config(&self, doh: &DohClient, target: &Target, timeout: Duration) -> Result<Config> {
+        if let Some((config, fetched)) = self.configs.lock().unwrap().get(target) {
+            if fetched.elapsed() < CONFIG_REFRESH {
+                return Ok(config.clone());
+            }
+        }
+        let url = target.0.with_path(CONFIGS_PATH);
+        let data = doh.fetch(&url, CONFIGS_PATH, None, CONFIGS_TYPE, timeout)?;
+        let config = Config::from_configs(&data)?;
+        self.configs
+            .lock()
+            .unwrap()
+            .insert(target.clone(), (config.clone(), Instant::now()));
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decrypt_query, decrypt_response, encrypt_query, encrypt_response, from_plaintext,
+        plaintext, Config, Target, PADDING_BLOCK,
+    };
+    use crate::x25519;
+
+    #[test]
+    fn test_target() {
+        let target: Target = "odoh://odoh.example".parse().unwrap();
+        assert_eq!("odoh://odoh.example/dns-query", target.to_string());
+        assert_eq!("odoh.example", target.0.host());
+        let target: Target = "odoh://odoh.example:8443/q".parse().unwrap();
+        assert_eq!("odoh://odoh.example:8443/q", target.to_string());
+        assert!("https://odoh.example".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_configs() {
+        let config = Config::new([7; 32]);
+        let data = config.to_configs().unwrap();
+        assert_eq!(Config::new([7; 32]), Config::from_configs(&data).unwrap());
+
+        // an unknown version first, then a config with another AEAD
+        let mut other = vec![0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB];
+        other.extend_from_slice(&[0x00, 0x01, 0x00, 0x28, 0x00, 0x20, 0x00, 0x01, 0x00, 0x03]);
+        other.extend_from_slice(&[0x00, 0x20]);
+        other.extend_from_slice(&[9; 32]);
+        let mut configs = ((other.len() + data.len() - 2) as u16)
+            .to_be_bytes()
+            .to_vec();
+        configs.extend_from_slice(&other);
+        configs.extend_from_slice(&data[2..]);
+        assert_eq!(config, Config::from_configs(&configs).unwrap());
+        assert!(Config::from_configs(&[0, 6, 0, 2, 0, 2, 0xAA, 0xBB]).is_err());
+    }
+
+    #[test]
+    fn test_plaintext() {
+        let plain = plaintext(b"query", 3).unwrap();
+        assert_eq!(
+            vec![0, 5, b'q', b'u', b'e', b'r', b'y', 0, 3, 0, 0, 0],
+            plain
+        );
+        assert_eq!(Some(b"query".to_vec()), from_plaintext(&plain));
+        assert_eq!(None, from_plaintext(&[0, 1, 1, 0, 1, 1]));
+        assert_eq!(None, from_plaintext(&[0, 1, 1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let secret = [3; 32];
+        let config = Config::new(x25519::public_key(&secret));
+
+        let (message, client) = encrypt_query(&config, b"query").unwrap();
+        assert_eq!(1, message[0]);
+        let (query, target) = decrypt_query(&secret, &config, &message).unwrap();
+        assert_eq!(b"query".to_vec(), query);
+        assert_eq!(0, client.query.len() % PADDING_BLOCK);
+
+        let response = encrypt_response(&target, b"response").unwrap();
+        assert_eq!(
+            b"response".to_vec(),
+            decrypt_response(&client, &response).unwrap()
+        );
+
+        // another key, a tampered query or a response to another query
+        let other = Config::new(x25519::public_key(&[4; 32]));
+        assert!(decrypt_query(&[4; 32], &other, &message).is_none());
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt_query(&secret, &config, &tampered).is_none());
+        let (_, unrelated) = encrypt_query(&config, b"query").unwrap();
+        assert!(decrypt_response(&unrelated, &response).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+
+use crate::proto::{rcode, Message};
+
+/// What happens to a UDP query that arrives while the inbound queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OverloadPolicy {
+    /// Drop the query silently, the client retries after its timeout
+    #[default]
+    Drop,
+    /// Answer REFUSED right away so the client moves on to another server
+    Refused,
+}
+
+/// Counters of the inbound queue.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub accepted: AtomicU64,
+    pub dropped: AtomicU64,
+    pub refused: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn accept(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a query turned away under `policy`, returning the reply to
+    /// send if any.
+    pub fn reject(&self, policy: OverloadPolicy, packet: &[u8]) -> Option<Vec<u8>> {
+        match policy {
+            OverloadPolicy::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            OverloadPolicy::Refused => {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                refused_reply(packet)
+            }
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} queries accepted, {} dropped, {} refused",
+            self.accepted.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// REFUSED reply to a raw query, `None` if it can't be parsed.
+fn refused_reply(packet: &[u8]) -> Option<Vec<u8>> {
+    let request = Message::from_bytes(packet).ok()?;
+    request.error_reply(rcode::REFUSED).to_bytes().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OverloadPolicy, QueueStats};
+    use crate::proto::{rcode, Message};
+
+    #[test]
+    fn test_reject() {
+        let stats = QueueStats::default();
+        let query = Message {
+            id: 99,
+            ..Message::default()
+        }
+        .to_bytes()
+        .unwrap();
+
+        assert_eq!(None, stats.reject(OverloadPolicy::Drop, &query));
+
+        let reply = stats.reject(OverloadPolicy::Refused, &query).unwrap();
+        let reply = Message::from_bytes(&reply).unwrap();
+        assert_eq!(99, reply.id);
+        assert_eq!(rcode::REFUSED, reply.rcode);
+
+        assert_eq!(None, stats.reject(OverloadPolicy::Refused, &[0, 1, 2]));
+        stats.accept();
+        assert_eq!("1 queries accepted, 1 dropped, 2 refused", stats.summary());
+    }
+}
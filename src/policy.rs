@@ -0,0 +1,471 @@
+//! Query policy: rules tried in order for each request, the first whose
+//! conditions all hold deciding what happens to it. Conditions are on the
+//! queried name, by glob or regular expression, its type, the client's
+//! address and the time of day, and requests are let through, refused,
+//! dropped without a reply, asked about another name instead, or forwarded
+//! to upstreams of their own. Requests no rule matches are let through.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    acl::Network,
+    forward::{parse_endpoint, Endpoint},
+    handler::{set_origin, Context, Dropped, Next, Origin, RequestHandler},
+    privacy,
+    proto::{rcode, Message, Name, Question, Type},
+    regex::Regex,
+    resolver::Resolver,
+};
+
+/// Pattern queried names are matched against, ignoring ASCII case.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamePattern {
+    // `*` for any run of characters, dots included, `?` for one
+    Glob(String),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    /// A glob's dots only match between labels, a regular expression sees
+    /// the name in presentation form, dots inside labels escaped.
+    pub fn matches(&self, name: &Name) -> bool {
+        match self {
+            Self::Glob(glob) => {
+                // the bytes of the labels, none between them
+                let mut bytes = Vec::new();
+                for (i, label) in name.iter_labels().enumerate() {
+                    if i > 0 {
+                        bytes.push(None);
+                    }
+                    bytes.extend(label.iter().copied().map(Some));
+                }
+                glob_matches(glob.as_bytes(), &bytes)
+            }
+            Self::Regex(regex) => regex.is_match(&name.to_string()),
+        }
+    }
+}
+
+fn glob_matches(glob: &[u8], name: &[Option<u8>]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((c, rest)) => match name.split_first() {
+            Some((n, name)) if *c == b'?' || byte_matches(*c, *n) => glob_matches(rest, name),
+            _ => false,
+        },
+    }
+}
+
+/// Whether glob character `c` matches `n`, a byte of a label or none
+/// between labels. A dot inside a label is only matched by `?`.
+fn byte_matches(c: u8, n: Option<u8>) -> bool {
+    match n {
+        Some(n) => c != b'.' && c.eq_ignore_ascii_case(&n),
+        None => c == b'.',
+    }
+}
+
+/// Minutes of the day from the start, included, to the end, excluded,
+/// across midnight if the end comes first. In UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    start: u16,
+    end: u16,
+}
+
+impl TimeRange {
+    pub fn contains(&self, minute: u16) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&minute),
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    /// `HH:MM-HH:MM`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let minute = |hhmm: &str| {
+            let (h, m) = hhmm.trim().split_once(':')?;
+            let (h, m): (u16, u16) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        s.split_once('-')
+            .and_then(|(start, end)| {
+                Some(Self {
+                    start: minute(start)?,
+                    end: minute(end)?,
+                })
+            })
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", s))
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// What happens to a request a rule matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    // answered like no rule matched
+    Allow,
+    Refuse,
+    // no reply at all
+    Drop,
+    // answered for another name, the records of that name renamed back
+    Rewrite(Name),
+    // answered by these upstreams alone, bypassing local data and the cache
+    Forward(Vec<Endpoint>),
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    /// `allow`, `refuse`, `drop`, `rewrite NAME` or `forward UPSTREAM...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let action = match (words.next(), words.next()) {
+            (Some("allow"), None) => Self::Allow,
+            (Some("refuse"), None) => Self::Refuse,
+            (Some("drop"), None) => Self::Drop,
+            (Some("rewrite"), Some(name)) if words.next().is_none() => Self::Rewrite(name.parse()?),
+            (Some("forward"), Some(first)) => Self::Forward(
+                [first]
+                    .into_iter()
+                    .chain(words)
+                    .map(parse_endpoint)
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => {
+                return Err(format!(
+                    "expected allow, refuse, drop, rewrite NAME or forward UPSTREAM..., got {:?}",
+                    s
+                ))
+            }
+        };
+        Ok(action)
+    }
+}
+
+/// Conditions on requests, each holding when unset, and what happens to
+/// the requests they all hold for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    // for the log
+    pub name: String,
+    // any of them matches the queried name
+    pub patterns: Vec<NamePattern>,
+    pub qtypes: Vec<Type>,
+    pub clients: Vec<Network>,
+    pub time: Option<TimeRange>,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            patterns: Vec::new(),
+            qtypes: Vec::new(),
+            clients: Vec::new(),
+            time: None,
+            action: Action::Allow,
+        }
+    }
+
+    fn matches(&self, ctx: &Context, q: &Question, minute: u16) -> bool {
+        (self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(&q.name)))
+            && (self.qtypes.is_empty() || self.qtypes.contains(&q.qtype))
+            && (self.clients.is_empty()
+                || self.clients.iter().any(|net| net.contains(ctx.source.ip())))
+            && self.time.is_none_or(|time| time.contains(minute))
+    }
+}
+
+/// Applies the first matching rule to each request, by its first question.
+pub struct Policy {
+    rules: Vec<Rule>,
+    // resolver of each rule forwarding to upstreams
+    upstreams: Vec<Option<Arc<dyn Resolver>>>,
+    // minute of the day now
+    clock: fn() -> u16,
+}
+
+impl Policy {
+    /// `connect` gives the resolver for the upstreams of forwarding rules.
+    pub fn new(
+        rules: Vec<Rule>,
+        connect: impl Fn(&[Endpoint]) -> Result<Arc<dyn Resolver>>,
+    ) -> Result<Self> {
+        let upstreams = rules
+            .iter()
+            .map(|rule| match &rule.action {
+                Action::Forward(endpoints) => connect(endpoints).map(Some),
+                _ => Ok(None),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            rules,
+            upstreams,
+            clock: minute_of_day,
+        })
+    }
+
+    /// Index of the rule deciding about `q`.
+    fn rule(&self, ctx: &Context, q: &Question) -> Option<usize> {
+        let minute = (self.clock)();
+        self.rules
+            .iter()
+            .position(|rule| rule.matches(ctx, q, minute))
+    }
+}
+
+impl RequestHandler for Policy {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let Some(q) = request.questions.first() else {
+            return next.run(ctx, request);
+        };
+        let Some(i) = self.rule(ctx, q) else {
+            return next.run(ctx, request);
+        };
+        let rule = &self.rules[i];
+        tracing::debug!(
+            rule = %rule.name, id = %request.id, client = %privacy::client(ctx.source),
+            qname = %q.name, qtype = %q.qtype, action = ?rule.action,
+            "Policy rule matched"
+        );
+        match &rule.action {
+            Action::Allow => next.run(ctx, request),
+            Action::Refuse => Ok(request.error_reply(rcode::REFUSED)),
+            Action::Drop => Err(Dropped.into()),
+            Action::Rewrite(target) => {
+                let original = q.name.clone();
+                let mut rewritten = request.clone();
+                rewritten.questions[0].name = target.clone();
+                let mut reply = next.run(ctx, rewritten)?;
+                for record in reply
+                    .answers
+                    .iter_mut()
+                    .chain(reply.authorities.iter_mut())
+                    .chain(reply.additionals.iter_mut())
+                    .filter(|r| r.name == *target)
+                {
+                    record.name = original.clone();
+                }
+                reply.questions = request.questions;
+                Ok(reply)
+            }
+            Action::Forward(_) => {
+                let reply = self.upstreams[i].as_ref().unwrap().resolve(&request)?;
+                set_origin(Origin::Upstream);
+                Ok(reply)
+            }
+        }
+    }
+}
+
+/// Minute of the day in UTC.
+fn minute_of_day() -> u16 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / 60 % (24 * 60)) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, NamePattern, Policy, Rule, TimeRange};
+    use crate::{
+        forward::parse_endpoint,
+        handler::{Chain, Context, Dropped, Next, RequestHandler, Transport},
+        proto::{rcode, test::request, Class, Message, Name, Record, Type},
+        rdata::RData,
+        resolver::Resolver,
+    };
+    use anyhow::Result;
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    #[test]
+    fn test_name_pattern() {
+        let glob = NamePattern::Glob("*.ads.example".into());
+        assert!(glob.matches(&Name::from("cdn.ADS.example.")));
+        assert!(glob.matches(&Name::from("a.b.ads.example")));
+        assert!(!glob.matches(&Name::from("ads.example")));
+        assert!(!glob.matches(&Name::from_labels(["cdn.ads", "example"])));
+        let glob = NamePattern::Glob("host?.lan".into());
+        assert!(glob.matches(&Name::from("host1.lan")));
+        assert!(!glob.matches(&Name::from("host10.lan")));
+        let regex = NamePattern::Regex("^ad[0-9]+\\.".parse().unwrap());
+        assert!(regex.matches(&Name::from("ad42.example")));
+        assert!(!regex.matches(&Name::from("bad42.example")));
+    }
+
+    #[test]
+    fn test_parse() {
+        let night: TimeRange = "22:00-06:30".parse().unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(6 * 60 + 29));
+        assert!(!night.contains(6 * 60 + 30));
+        assert!(!night.contains(12 * 60));
+        let day: TimeRange = "08:00-18:00".parse().unwrap();
+        assert!(day.contains(12 * 60));
+        assert!(!day.contains(22 * 60));
+        assert_eq!("22:00-06:30", night.to_string());
+        assert!("24:00-06:00".parse::<TimeRange>().is_err());
+        assert!("22:00".parse::<TimeRange>().is_err());
+
+        assert_eq!(Ok(Action::Drop), "drop".parse());
+        assert_eq!(
+            Ok(Action::Rewrite(Name::from("safe.example"))),
+            "rewrite safe.example.".parse()
+        );
+        assert!(matches!(
+            "forward 9.9.9.9:53 https://dns.example".parse(),
+            Ok(Action::Forward(endpoints)) if endpoints.len() == 2
+        ));
+        assert!("rewrite".parse::<Action>().is_err());
+        assert!("forward".parse::<Action>().is_err());
+        assert!("block".parse::<Action>().is_err());
+    }
+
+    fn a(name: &str, ip: Ipv4Addr) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: Type::A,
+            class: Class::IN,
+            ttl: 300,
+            rdata: RData::A(ip),
+        }
+    }
+
+    /// Answers every name with 192.0.2.1 at the end of the chain.
+    struct Answer;
+
+    impl RequestHandler for Answer {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            let name = request.questions[0].name.to_string();
+            Ok(Message {
+                answers: vec![a(&name, Ipv4Addr::new(192, 0, 2, 1))],
+                ..request.reply()
+            })
+        }
+    }
+
+    /// The forced upstream, answering 198.51.100.1.
+    struct Upstream;
+
+    impl Resolver for Upstream {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            let name = request.questions[0].name.to_string();
+            Ok(Message {
+                answers: vec![a(&name, Ipv4Addr::new(198, 51, 100, 1))],
+                ..request.reply()
+            })
+        }
+    }
+
+    fn ctx(source: &str) -> Context {
+        Context {
+            source: source.parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        }
+    }
+
+    enum Minute {
+        Night,
+        Noon,
+    }
+    use Minute::*;
+
+    #[test]
+    fn test_apply() {
+        let rules = vec![
+            Rule {
+                qtypes: vec![Type::ANY],
+                action: Action::Refuse,
+                ..Rule::new("no-any")
+            },
+            Rule {
+                patterns: vec![NamePattern::Glob("*.games.example".into())],
+                clients: vec!["192.168.1.0/24".parse().unwrap()],
+                time: Some("22:00-06:00".parse().unwrap()),
+                action: Action::Drop,
+                ..Rule::new("bedtime")
+            },
+            Rule {
+                patterns: vec![NamePattern::Glob("search.example".into())],
+                action: Action::Rewrite(Name::from("safe.search.example")),
+                ..Rule::new("safe-search")
+            },
+            Rule {
+                patterns: vec![NamePattern::Regex("\\.corp$".parse().unwrap())],
+                action: Action::Forward(vec![parse_endpoint("10.0.0.53:53").unwrap()]),
+                ..Rule::new("corp")
+            },
+        ];
+        let policy = |minute| {
+            let policy = Policy::new(rules.clone(), |endpoints| {
+                assert_eq!(1, endpoints.len());
+                Ok(Arc::new(Upstream))
+            })
+            .unwrap();
+            let clock: fn() -> u16 = match minute {
+                Night => || 23 * 60,
+                Noon => || 12 * 60,
+            };
+            Chain::default()
+                .with(Policy { clock, ..policy })
+                .with(Answer)
+        };
+        let night = policy(Night);
+        let apply = |ctx: &Context, request, chain: &Chain| chain.handle(ctx, request);
+        let kid = ctx("192.168.1.20:5353");
+
+        let reply = apply(&kid, request("example.com", Type::ANY), &night).unwrap();
+        assert_eq!(rcode::REFUSED, reply.rcode);
+
+        let err = apply(&kid, request("play.games.example", Type::A), &night).unwrap_err();
+        assert!(err.is::<Dropped>());
+        // another time, or another client
+        let reply = apply(&kid, request("play.games.example", Type::A), &policy(Noon)).unwrap();
+        assert_eq!(
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            reply.answers[0].rdata
+        );
+        let parent = ctx("192.168.2.20:5353");
+        assert!(apply(&parent, request("play.games.example", Type::A), &night).is_ok());
+
+        // asked about the other name, answered about the original one
+        let reply = apply(&kid, request("Search.example", Type::A), &night).unwrap();
+        assert_eq!("Search.example", reply.questions[0].name.to_string());
+        assert_eq!("Search.example", reply.answers[0].name.to_string());
+
+        let reply = apply(&kid, request("wiki.corp", Type::A), &night).unwrap();
+        assert_eq!(
+            RData::A(Ipv4Addr::new(198, 51, 100, 1)),
+            reply.answers[0].rdata
+        );
+    }
+}
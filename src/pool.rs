@@ -0,0 +1,345 @@
+//! Blocking worker-pool mode. A receiver thread per socket reads UDP packets
+//! and queues them on a bounded channel for a fixed set of worker threads,
+//! which decode, resolve and queue replies for a sender thread per socket.
+//! Receivers and senders handle packets in batches. Each worker forwards
+//! through its own upstream socket.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::{
+    encode_tcp_reply, encode_udp_reply,
+    handler::{Context, Dropped, Transport},
+    privacy,
+    proto::{rcode, Message},
+    shutdown::InFlight,
+    socket::{self, BATCH},
+    systemd, udp_payload_limit, Server, SHUTDOWN_TIMEOUT, UDP_BUFFER_SIZE,
+};
+
+/// How often blocked receivers check for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// packet, source and the socket it arrived on, counted as in flight while
+// queued
+type Job = (Vec<u8>, SocketAddr, Arc<Endpoint>, InFlight);
+
+// reply and destination, its query counted as in flight until it's sent
+type Reply = (Vec<u8>, SocketAddr, InFlight);
+
+/// A UDP socket as seen by the workers.
+struct Endpoint {
+    local: SocketAddr,
+    // replies for its sender thread
+    replies: mpsc::SyncSender<Reply>,
+}
+
+/// Serves UDP queries from all sockets with `workers` threads, and TCP
+/// connections with a thread each, until SIGINT or SIGTERM. Queued queries
+/// are still answered before returning.
+pub fn serve(
+    server: Arc<Server>,
+    udp_sockets: Vec<UdpSocket>,
+    tcp_listeners: Vec<TcpListener>,
+    workers: usize,
+) -> Result<()> {
+    signal_hook::flag::register(SIGINT, server.shutdown.flag())?;
+    signal_hook::flag::register(SIGTERM, server.shutdown.flag())?;
+
+    for listener in tcp_listeners {
+        let server = server.clone();
+        thread::spawn(move || serve_tcp(listener, server));
+    }
+
+    let (tx, rx) = mpsc::sync_channel::<Job>(server.queue_size);
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..workers {
+        let rx = rx.clone();
+        let server = server.clone();
+        thread::Builder::new()
+            .name(format!("worker-{}", i))
+            .spawn(move || work(&rx, &server))?;
+    }
+
+    let mut receivers = Vec::new();
+    for udp_socket in udp_sockets {
+        let (replies, queued) = mpsc::sync_channel(server.queue_size.max(1));
+        let endpoint = Arc::new(Endpoint {
+            local: udp_socket.local_addr()?,
+            replies,
+        });
+        let udp_socket = Arc::new(udp_socket);
+        let sending = udp_socket.clone();
+        thread::spawn(move || send(&sending, queued));
+        let (tx, server) = (tx.clone(), server.clone());
+        receivers.push(thread::spawn(move || {
+            receive(&udp_socket, endpoint, &server, tx)
+        }));
+    }
+    for receiver in receivers {
+        let _ = receiver.join();
+    }
+
+    // workers exit once the queue is drained
+    drop(tx);
+    systemd::notify("STOPPING=1");
+    tracing::info!("Shutting down");
+    if !server.shutdown.wait_idle(SHUTDOWN_TIMEOUT) {
+        tracing::warn!(in_flight = %server.shutdown.in_flight(), "Exiting with queries in flight");
+    }
+    // connections close once they've answered the query they're on
+    server.connections.wait_closed(POLL_INTERVAL * 2);
+    tracing::info!("{}", server.queue_stats.summary());
+    tracing::info!("{}", server.cache.summary());
+    Ok(())
+}
+
+/// Receiver loop of a single socket, queues packets for the workers until
+/// shutdown is requested.
+fn receive(
+    udp_socket: &UdpSocket,
+    endpoint: Arc<Endpoint>,
+    server: &Server,
+    tx: mpsc::SyncSender<Job>,
+) {
+    if let Err(e) = udp_socket.set_read_timeout(Some(POLL_INTERVAL)) {
+        tracing::error!(error = %e, "Failed to set socket timeout");
+        return;
+    }
+
+    let mut bufs = vec![[0; UDP_BUFFER_SIZE]; BATCH];
+    while !server.shutdown.is_requested() {
+        let received = match socket::recv_batch(udp_socket, &mut bufs) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to receive");
+                return;
+            }
+        };
+        for (i, size, source) in received {
+            tracing::trace!(bytes = %size, client = %privacy::client(source), "Received");
+            let job = (
+                bufs[i][..size].to_vec(),
+                source,
+                endpoint.clone(),
+                server.shutdown.track(),
+            );
+            match tx.try_send(job) {
+                Ok(()) => server.queue_stats.accept(),
+                Err(mpsc::TrySendError::Full((packet, _, _, in_flight))) => {
+                    if let Some(reply) = server.queue_stats.reject(server.overload, &packet) {
+                        let _ = endpoint.replies.try_send((reply, source, in_flight));
+                    }
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => return,
+            }
+        }
+    }
+}
+
+/// Sender loop of a single socket, sends queued replies in batches until
+/// no one can queue any more.
+fn send(udp_socket: &UdpSocket, queued: mpsc::Receiver<Reply>) {
+    while let Ok(reply) = queued.recv() {
+        let mut batch = vec![reply];
+        batch.extend(queued.try_iter().take(BATCH - 1));
+        let packets: Vec<_> = batch
+            .iter()
+            .map(|(reply, dest, _)| (&reply[..], *dest))
+            .collect();
+        let mut unsent = &packets[..];
+        while let Some((_, dest)) = unsent.first() {
+            match socket::send_batch(udp_socket, unsent) {
+                Ok(sent) => unsent = &unsent[sent..],
+                Err(e) => {
+                    tracing::warn!(
+                        client = %privacy::client(*dest), error = %e,
+                        "Failed to send reply"
+                    );
+                    unsent = &unsent[1..];
+                }
+            }
+        }
+    }
+}
+
+/// Worker loop, answers queued packets until the receivers go away.
+fn work(rx: &Mutex<mpsc::Receiver<Job>>, server: &Server) {
+    loop {
+        // the lock is only held while waiting, not while resolving
+        let job = rx.lock().unwrap().recv();
+        let Ok((packet, source, endpoint, in_flight)) = job else {
+            return;
+        };
+        match serve_udp_query(server, endpoint.local, &packet, source) {
+            Ok(Some(reply)) => {
+                let _ = endpoint.replies.send((reply, source, in_flight));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!(
+                client = %privacy::client(source), error = %e,
+                "Failed to serve query"
+            ),
+        }
+    }
+}
+
+/// Answers a single query received on `listener`, none if it's dropped.
+fn serve_udp_query(
+    server: &Server,
+    listener: SocketAddr,
+    packet: &[u8],
+    source: SocketAddr,
+) -> Result<Option<Vec<u8>>> {
+    let mut request = Message::from_bytes(packet)?;
+
+    let max_size = udp_payload_limit(&request);
+    let (reply, signer) = match server.verify(packet, &mut request) {
+        Ok(signer) if !server.admits(listener, source) => {
+            (Some(request.error_reply(rcode::REFUSED)), signer)
+        }
+        Ok(signer) => {
+            let ctx = Context {
+                source,
+                transport: Transport::Udp,
+                key: signer.as_ref().and_then(|s| s.key().cloned()),
+            };
+            (handle_query(server, ctx, request), signer)
+        }
+        Err(signer) => (Some(request.error_reply(rcode::NOTAUTH)), Some(*signer)),
+    };
+    let Some(reply) = reply.and_then(|reply| server.rate_limit(source, reply)) else {
+        return Ok(None);
+    };
+    Ok(Some(encode_udp_reply(reply, max_size, signer)?))
+}
+
+/// Runs the request handler, answering SERVFAIL if it fails. None if the
+/// request is dropped.
+fn handle_query(server: &Server, ctx: Context, request: Message) -> Option<Message> {
+    let id = request.id;
+    let servfail = request.error_reply(rcode::SERVFAIL);
+    match server.handle_request(ctx, request) {
+        Ok(reply) => Some(reply),
+        Err(e) if e.is::<Dropped>() => None,
+        Err(e) => {
+            tracing::warn!(%id, error = %e, "Failed to handle request");
+            Some(servfail)
+        }
+    }
+}
+
+/// Accepts DNS over TCP connections (RFC 7766), serving each one on its own
+/// thread.
+fn serve_tcp(listener: TcpListener, server: Arc<Server>) {
+    for stream in listener.incoming() {
+        if server.shutdown.is_requested() {
+            return;
+        }
+        match stream {
+            Ok(stream) => {
+                let source = match stream.peer_addr() {
+                    Ok(source) => source,
+                    Err(e) => {
+                        tracing::debug!(error = %e, "TCP connection failed");
+                        continue;
+                    }
+                };
+                let connection = match server.connections.admit(source.ip()) {
+                    Ok(connection) => connection,
+                    Err(refusal) => {
+                        tracing::debug!(
+                            client = %privacy::client(source), reason = %refusal,
+                            "Closed TCP connection"
+                        );
+                        continue;
+                    }
+                };
+                let server = server.clone();
+                thread::spawn(move || {
+                    let _connection = connection;
+                    if let Err(e) = serve_tcp_conn(stream, &server) {
+                        tracing::debug!(error = %e, "TCP connection failed");
+                    }
+                });
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to accept TCP connection"),
+        }
+    }
+}
+
+/// Waits for the next query on `stream`, false if it stays idle for the
+/// idle timeout or shutdown is requested first.
+fn wait_for_query(stream: &TcpStream, server: &Server) -> io::Result<bool> {
+    let deadline = Instant::now() + server.tcp_idle_timeout;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    while !server.shutdown.is_requested() && Instant::now() < deadline {
+        match stream.peek(&mut [0]) {
+            // closed by the client, read as such below
+            Ok(_) => {
+                stream.set_read_timeout(Some(server.tcp_idle_timeout))?;
+                return Ok(true);
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Answers length-prefixed queries on a single connection until the client
+/// closes it or leaves it idle, or shutdown is requested. A query being
+/// answered then still gets its reply.
+fn serve_tcp_conn(mut stream: TcpStream, server: &Server) -> Result<()> {
+    let source = stream.peer_addr()?;
+    let listener = stream.local_addr()?;
+
+    // idle connections are closed, the client reconnects if it needs to
+    while wait_for_query(&stream, server)? {
+        let mut len_buf = [0u8; 2];
+        match stream.read_exact(&mut len_buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut buf)?;
+        tracing::trace!(bytes = buf.len(), client = %privacy::client(source), "Received over TCP");
+
+        let mut request = Message::from_bytes(&buf)?;
+
+        let _in_flight = server.shutdown.track();
+        let (reply, signer) = match server.verify(&buf, &mut request) {
+            Ok(signer) if !server.admits(listener, source) => {
+                (Some(request.error_reply(rcode::REFUSED)), signer)
+            }
+            Ok(signer) => {
+                let ctx = Context {
+                    source,
+                    transport: Transport::Tcp,
+                    key: signer.as_ref().and_then(|s| s.key().cloned()),
+                };
+                (handle_query(server, ctx, request), signer)
+            }
+            Err(signer) => (Some(request.error_reply(rcode::NOTAUTH)), Some(*signer)),
+        };
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+        stream.write_all(&encode_tcp_reply(reply, signer)?)?;
+    }
+    Ok(())
+}
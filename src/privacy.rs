@@ -0,0 +1,209 @@
+//! Client addresses as they appear in logs, the query log, dnstap and
+//! statistics: as is, truncated to their network, or replaced by keyed
+//! hashes. Hashes are of the network and of the whole address, so queries
+//! from one network can still be told apart from another's:
+//!
+//! ```text
+//! 192.0.2.7   -> 192.0.2.0          (truncate, /24)
+//! 192.0.2.7   -> 5f0c6e1a.93b27d40  (hash)
+//! 192.0.2.201 -> 5f0c6e1a.c1e8a274  (hash)
+//! ```
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    digest::{hmac, Hash},
+    ecs::truncate,
+    encoding::hex_encode,
+};
+
+/// Prefix lengths client addresses are truncated to by default.
+pub const IPV4_PREFIX: u8 = 24;
+pub const IPV6_PREFIX: u8 = 48;
+
+/// How client addresses are recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Anonymization {
+    /// As they are
+    #[default]
+    Off,
+    /// Truncated to their network
+    Truncate,
+    /// Replaced by keyed hashes of their network and of themselves
+    Hash,
+}
+
+impl FromStr for Anonymization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            _ => Err(format!("unknown anonymization {:?}", s)),
+        }
+    }
+}
+
+/// Rewrites client addresses by an anonymization mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anonymizer {
+    mode: Anonymization,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    // HMAC-SHA256 key of the hashes
+    key: Vec<u8>,
+}
+
+impl Anonymizer {
+    pub const fn off() -> Self {
+        Self {
+            mode: Anonymization::Off,
+            ipv4_prefix: IPV4_PREFIX,
+            ipv6_prefix: IPV6_PREFIX,
+            key: Vec::new(),
+        }
+    }
+
+    /// Anonymizer in `mode`, with networks of `ipv4_prefix` and
+    /// `ipv6_prefix` bits, hashing with `key`, a random one if not given so
+    /// hashes can't be matched across restarts.
+    pub fn new(mode: Anonymization, ipv4_prefix: u8, ipv6_prefix: u8, key: Option<&str>) -> Self {
+        Self {
+            mode,
+            ipv4_prefix: ipv4_prefix.min(32),
+            ipv6_prefix: ipv6_prefix.min(128),
+            key: match key {
+                Some(key) => key.as_bytes().to_vec(),
+                None => rand::random::<[u8; 32]>().to_vec(),
+            },
+        }
+    }
+
+    pub fn mode(&self) -> Anonymization {
+        self.mode
+    }
+
+    fn network(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => truncate(ip, self.ipv4_prefix),
+            IpAddr::V6(_) => truncate(ip, self.ipv6_prefix),
+        }
+    }
+
+    fn hash(&self, ip: IpAddr) -> String {
+        let bytes = match ip {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        };
+        hex_encode(&hmac(Hash::Sha256, &self.key, &bytes)[..4])
+    }
+
+    /// `ip` as it may be recorded.
+    pub fn ip(&self, ip: IpAddr) -> String {
+        let ip = ip.to_canonical();
+        match self.mode {
+            Anonymization::Off => ip.to_string(),
+            Anonymization::Truncate => self.network(ip).to_string(),
+            Anonymization::Hash => format!("{}.{}", self.hash(self.network(ip)), self.hash(ip)),
+        }
+    }
+
+    /// `addr` as it may be recorded, without the port unless addresses are
+    /// recorded as they are.
+    pub fn client(&self, addr: SocketAddr) -> String {
+        match self.mode {
+            Anonymization::Off => addr.to_string(),
+            _ => self.ip(addr.ip()),
+        }
+    }
+
+    /// `ip` as it may be recorded where an address is needed, `None` if it
+    /// may not be.
+    pub fn address(&self, ip: IpAddr) -> Option<IpAddr> {
+        match self.mode {
+            Anonymization::Off => Some(ip),
+            Anonymization::Truncate => Some(self.network(ip.to_canonical())),
+            Anonymization::Hash => None,
+        }
+    }
+}
+
+static ANONYMIZER: RwLock<Anonymizer> = RwLock::new(Anonymizer::off());
+
+/// Replaces the anonymizer client addresses are recorded through.
+pub fn set_anonymizer(anonymizer: Anonymizer) {
+    *ANONYMIZER.write().unwrap() = anonymizer;
+}
+
+/// `addr` of a client as it may be recorded.
+pub fn client(addr: SocketAddr) -> String {
+    ANONYMIZER.read().unwrap().client(addr)
+}
+
+/// The IP address of a client as it may be recorded.
+pub fn ip(ip: IpAddr) -> String {
+    ANONYMIZER.read().unwrap().ip(ip)
+}
+
+/// `addr` of a client as it may be recorded where an address is needed,
+/// `None` if it may not be.
+pub fn address(addr: SocketAddr) -> Option<SocketAddr> {
+    let ip = ANONYMIZER.read().unwrap().address(addr.ip())?;
+    Some(SocketAddr::new(ip, addr.port()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Anonymization, Anonymizer};
+    use std::net::{IpAddr, SocketAddr};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_modes() {
+        let off = Anonymizer::off();
+        assert_eq!("192.0.2.7:5353", off.client(addr("192.0.2.7:5353")));
+        assert_eq!(
+            Some(addr("192.0.2.7:0").ip()),
+            off.address(addr("192.0.2.7:0").ip())
+        );
+
+        let truncate = Anonymizer::new(Anonymization::Truncate, 24, 48, None);
+        assert_eq!("192.0.2.0", truncate.client(addr("192.0.2.7:5353")));
+        assert_eq!("192.0.2.0", truncate.client(addr("[::ffff:192.0.2.7]:53")));
+        assert_eq!(
+            "2001:db8:aa::",
+            truncate.client(addr("[2001:db8:aa:bb::1]:5353"))
+        );
+        assert_eq!(
+            Some("192.0.2.0".parse::<IpAddr>().unwrap()),
+            truncate.address("192.0.2.7".parse().unwrap())
+        );
+
+        let hash = Anonymizer::new(Anonymization::Hash, 24, 48, Some("secret"));
+        let a = hash.client(addr("192.0.2.7:5353"));
+        let b = hash.client(addr("192.0.2.201:53"));
+        let c = hash.client(addr("198.51.100.7:5353"));
+        assert_eq!(17, a.len());
+        // same network, different hosts
+        assert_eq!(a[..8], b[..8]);
+        assert_ne!(a[9..], b[9..]);
+        assert_ne!(a[..8], c[..8]);
+        assert_eq!(a, hash.client(addr("192.0.2.7:1")));
+        assert_eq!(None, hash.address("192.0.2.7".parse().unwrap()));
+
+        // another key, other hashes
+        let other = Anonymizer::new(Anonymization::Hash, 24, 48, Some("other"));
+        assert_ne!(a, other.client(addr("192.0.2.7:5353")));
+    }
+}
@@ -0,0 +1,129 @@
+//! Giving up root once the sockets are bound: the server switches to an
+//! unprivileged user and group, named in /etc/passwd and /etc/group or
+//! given by number, before it serves anything.
+
+use std::{fs, io};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+extern "C" {
+    fn getuid() -> u32;
+    fn geteuid() -> u32;
+    fn getgid() -> u32;
+    fn getegid() -> u32;
+    fn setuid(uid: u32) -> i32;
+    fn setgid(gid: u32) -> i32;
+    fn setgroups(size: usize, list: *const u32) -> i32;
+}
+
+/// The ID and primary group of user `name` in the passwd database `text`.
+fn parse_passwd(text: &str, name: &str) -> Option<(u32, u32)> {
+    text.lines().find_map(|line| {
+        let fields: Vec<_> = line.split(':').collect();
+        match fields.as_slice() {
+            [user, _, uid, gid, ..] if *user == name => {
+                Some((uid.parse().ok()?, gid.parse().ok()?))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// The ID of group `name` in the group database `text`.
+fn parse_group(text: &str, name: &str) -> Option<u32> {
+    text.lines().find_map(|line| {
+        let fields: Vec<_> = line.split(':').collect();
+        match fields.as_slice() {
+            [group, _, gid, ..] if *group == name => gid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The ID and primary group of `user`, a name or a number. A number
+/// without an entry has no group.
+fn user_ids(user: &str) -> Result<(u32, Option<u32>)> {
+    let passwd = fs::read_to_string("/etc/passwd");
+    if let Some((uid, gid)) = passwd
+        .as_ref()
+        .ok()
+        .and_then(|text| parse_passwd(text, user))
+    {
+        return Ok((uid, Some(gid)));
+    }
+    let uid = user.parse().map_err(|_| anyhow!("no user {}", user))?;
+    Ok((uid, None))
+}
+
+fn group_id(group: &str) -> Result<u32> {
+    let groups = fs::read_to_string("/etc/group");
+    if let Some(gid) = groups
+        .as_ref()
+        .ok()
+        .and_then(|text| parse_group(text, group))
+    {
+        return Ok(gid);
+    }
+    group.parse().map_err(|_| anyhow!("no group {}", group))
+}
+
+fn check(result: i32, call: &str) -> Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()).with_context(|| format!("{} failed", call)),
+    }
+}
+
+/// Switches the whole process, every thread of it, to `user` and `group`,
+/// the user's primary group if not given, with no supplementary groups.
+/// Fails unless root can't be regained afterwards.
+pub fn drop(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let user = user.map(user_ids).transpose()?;
+    let gid = match (group, user) {
+        (Some(group), _) => Some(group_id(group)?),
+        (None, Some((_, Some(gid)))) => Some(gid),
+        // root's group isn't kept by accident
+        (None, Some((uid, None))) => bail!("user {} has no group, one must be given", uid),
+        (None, None) => None,
+    };
+    // SAFETY: plain system calls, glibc applies them to every thread
+    unsafe {
+        if let Some(gid) = gid {
+            check(setgroups(1, &gid), "setgroups")?;
+            check(setgid(gid), "setgid")?;
+            if getgid() != gid || getegid() != gid {
+                bail!("still in group {} after setgid", getegid());
+            }
+        }
+        if let Some((uid, _)) = user {
+            check(setuid(uid), "setuid")?;
+            if getuid() != uid || geteuid() != uid {
+                bail!("still user {} after setuid", geteuid());
+            }
+            if uid != 0 && setuid(0) == 0 {
+                bail!("root privileges could be regained after setuid");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_group, parse_passwd};
+
+    #[test]
+    fn test_databases() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      dns:x:953:953:DNS server:/var/lib/dns:/usr/sbin/nologin\n";
+        assert_eq!(Some((953, 953)), parse_passwd(passwd, "dns"));
+        assert_eq!(Some((0, 0)), parse_passwd(passwd, "root"));
+        assert_eq!(None, parse_passwd(passwd, "nobody"));
+        assert_eq!(None, parse_passwd("broken:x:abc:1::/:/bin/sh", "broken"));
+
+        let group = "root:x:0:\nnogroup:x:65534:\ndns:x:953:alice,bob\n";
+        assert_eq!(Some(953), parse_group(group, "dns"));
+        assert_eq!(Some(65534), parse_group(group, "nogroup"));
+        assert_eq!(None, parse_group(group, "wheel"));
+    }
+}
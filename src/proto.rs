@@ -1,24 +1,286 @@
-use crate::encoder::{Decoder, Encoder, Error};
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
 
-#[derive(Debug, Default, PartialEq, Clone)]
-pub struct Name(pub String);
+use crate::{
+    edns::{EdnsOption, Opt},
+    encoder::{Decoder, Encoder, Error},
+    rdata::RData,
+};
+
+/// Operation codes (OPCODE), RFC 1035 section 4.1.1, NOTIFY (RFC 1996) and
+/// UPDATE (RFC 2136).
+pub mod opcode {
+    pub const QUERY: u8 = 0;
+    pub const IQUERY: u8 = 1;
+    pub const STATUS: u8 = 2;
+    pub const NOTIFY: u8 = 4;
+    pub const UPDATE: u8 = 5;
+}
+
+/// Response codes (RCODE), RFC 1035 section 4.1.1.
+pub mod rcode {
+    pub const NOERROR: u8 = 0;
+    pub const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const NOTIMP: u8 = 4;
+    pub const REFUSED: u8 = 5;
+    pub const NOTAUTH: u8 = 9;
+
+    /// Mnemonic of `rcode`, its number if it has none here.
+    pub fn name(rcode: u8) -> String {
+        match rcode {
+            NOERROR => "NOERROR".into(),
+            FORMERR => "FORMERR".into(),
+            SERVFAIL => "SERVFAIL".into(),
+            NXDOMAIN => "NXDOMAIN".into(),
+            NOTIMP => "NOTIMP".into(),
+            REFUSED => "REFUSED".into(),
+            NOTAUTH => "NOTAUTH".into(),
+            _ => format!("RCODE{}", rcode),
+        }
+    }
+}
+
+/// A domain name, its labels in the case they were written. Labels are
+/// arbitrary bytes (RFC 2181, section 11), not necessarily UTF-8. Names
+/// compare, hash and order ignoring ASCII case, ordered the canonical DNS
+/// way (RFC 4034, section 6.1).
+#[derive(Debug, Default, Clone)]
+pub struct Name {
+    // leftmost first, without the empty root label
+    labels: Vec<Vec<u8>>,
+}
 
 impl Name {
-    pub fn encode(&self, enc: &mut Encoder) {
-        for label in self.0.split('.') {
-            enc.write_u8(label.len() as u8);
-            enc.write_str(label);
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The name made of `labels`, leftmost first, taken as they are.
+    pub fn from_labels<L: AsRef<[u8]>>(labels: impl IntoIterator<Item = L>) -> Self {
+        Self {
+            labels: labels.into_iter().map(|l| l.as_ref().to_vec()).collect(),
         }
-        enc.write_u8(0);
     }
 
-    fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let name = dec.read_name()?;
-        Ok(Self(name))
+    pub fn labels(&self) -> &[Vec<u8>] {
+        &self.labels
+    }
+
+    pub fn iter_labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(Vec::as_slice)
+    }
+
+    pub fn num_labels(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// The name with its leftmost label removed, none for the root.
+    pub fn parent(&self) -> Option<Name> {
+        let (_, rest) = self.labels.split_first()?;
+        Some(Self::from_labels(rest))
+    }
+
+    /// Length of the name on the wire, its lengths and root label included.
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1
+    }
+
+    /// The relative name followed by `origin`. Fails if the result is longer
+    /// than 255 bytes.
+    pub fn append(&self, origin: &Name) -> Result<Name, String> {
+        let name = Self::from_labels(self.iter_labels().chain(origin.iter_labels()));
+        if name.wire_len() > 255 {
+            return Err(format!("name {} is longer than 255 bytes", name));
+        }
+        Ok(name)
+    }
+
+    /// The name in presentation form with its trailing dot, `.` for the
+    /// root.
+    pub fn to_fqdn(&self) -> String {
+        format!("{}.", self)
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_name(self)
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        let mut labels = Vec::new();
+        dec.walk_name(|label| labels.push(label.to_vec()))?;
+        Ok(Self { labels })
+    }
+
+    /// Whether the names are the same, in the exact same case.
+    pub fn eq_exact(&self, other: &Name) -> bool {
+        self.labels == other.labels
+    }
+
+    /// The name with every letter in lower case, its canonical form (RFC
+    /// 4034, section 6.2).
+    pub fn to_lowercase(&self) -> Name {
+        Self {
+            labels: self.labels.iter().map(|l| l.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Whether the name is `zone` itself or below it.
+    pub fn is_subdomain_of(&self, zone: &Name) -> bool {
+        self.labels.len() >= zone.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(zone.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+/// Reads a name in presentation form as `FromStr` does, for names known to
+/// be valid like literals. Panics on strings it rejects, input from
+/// elsewhere goes through `parse`.
+impl From<&str> for Name {
+    fn from(s: &str) -> Self {
+        s.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl From<String> for Name {
+    fn from(s: String) -> Self {
+        Self::from(&s[..])
+    }
+}
+
+/// Parses a name in presentation form, a trailing dot or not: `\.` is a dot
+/// inside a label, `\DDD` the byte with decimal value DDD and `\` before
+/// any other character that character (RFC 1035, section 5.1).
+impl FromStr for Name {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid name {:?}: {}", s, reason);
+        if s == "." {
+            return Ok(Self::root());
+        }
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(invalid("empty label"));
+                    }
+                    labels.push(std::mem::take(&mut label));
+                }
+                b'\\' => match bytes.next() {
+                    Some(d) if d.is_ascii_digit() => {
+                        let digits = [d, bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                        let value = std::str::from_utf8(&digits)
+                            .ok()
+                            .filter(|v| v.bytes().all(|d| d.is_ascii_digit()))
+                            .and_then(|v| v.parse::<u8>().ok())
+                            .ok_or_else(|| invalid("bad \\DDD escape"))?;
+                        label.push(value);
+                    }
+                    Some(c) => label.push(c),
+                    None => return Err(invalid("ends in a backslash")),
+                },
+                b => label.push(b),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        }
+
+        if labels.iter().any(|l| l.len() > 63) {
+            return Err(invalid("label longer than 63 bytes"));
+        }
+        let name = Self { labels };
+        if name.wire_len() > 255 {
+            return Err(invalid("longer than 255 bytes"));
+        }
+        Ok(name)
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// The presentation form without the trailing dot, so empty for the root.
+/// Dots and backslashes in labels are escaped with a backslash, bytes other
+/// than printable ASCII as `\DDD`.
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &b in label {
+                match b {
+                    b'.' | b'\\' => write!(f, "\\{}", b as char)?,
+                    0x21..=0x7E => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for b in label {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+impl Ord for Name {
+    // label by label from the right, a name before those below it
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lower = |b: u8| b.to_ascii_lowercase();
+        for (a, b) in self.labels.iter().rev().zip(other.labels.iter().rev()) {
+            let order = a.iter().map(|&b| lower(b)).cmp(b.iter().map(|&b| lower(b)));
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        self.labels.len().cmp(&other.labels.len())
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 #[repr(u16)]
 #[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum Type {
@@ -40,6 +302,25 @@ pub enum Type {
     MX,    // 15 mail exchange
     TXT,   // 16 text strings
 
+    AAAA = 28, // 28 an IPv6 host address (RFC 3596)
+    SRV = 33,  // 33 server selection (RFC 2782)
+
+    DS = 43,     // 43 delegation signer (RFC 4034)
+    RRSIG = 46,  // 46 RRset signature (RFC 4034)
+    NSEC = 47,   // 47 next secure (RFC 4034)
+    DNSKEY = 48, // 48 DNS public key (RFC 4034)
+    NSEC3 = 50,  // 50 hashed next secure (RFC 5155)
+    NSEC3PARAM,  // 51 NSEC3 parameters of a zone (RFC 5155)
+
+    SVCB = 64,  // 64 general purpose service binding (RFC 9460)
+    HTTPS = 65, // 65 service binding for HTTPS (RFC 9460)
+
+    ALIAS = 65401, // 65401 apex alias, flattened when answering (private use)
+
+    // Pseudo RR
+    OPT = 41,   // 41 EDNS0 option record (RFC 6891)
+    TSIG = 250, // 250 transaction signature (RFC 8945)
+
     // Qtype
     AXFR = 252,
     MAILB,
@@ -49,64 +330,169 @@ pub enum Type {
     UNKNOWN(u16),
 }
 
+impl From<Type> for u16 {
+    fn from(t: Type) -> Self {
+        match t {
+            Type::A => 1,
+            Type::NS => 2,
+            Type::MD => 3,
+            Type::MF => 4,
+            Type::CNAME => 5,
+            Type::SOA => 6,
+            Type::MB => 7,
+            Type::MG => 8,
+            Type::MR => 9,
+            Type::NULL => 10,
+            Type::WKS => 11,
+            Type::PTR => 12,
+            Type::HINFO => 13,
+            Type::MINFO => 14,
+            Type::MX => 15,
+            Type::TXT => 16,
+            Type::AAAA => 28,
+            Type::SRV => 33,
+            Type::OPT => 41,
+            Type::DS => 43,
+            Type::RRSIG => 46,
+            Type::NSEC => 47,
+            Type::DNSKEY => 48,
+            Type::NSEC3 => 50,
+            Type::NSEC3PARAM => 51,
+            Type::SVCB => 64,
+            Type::HTTPS => 65,
+            Type::ALIAS => 65401,
+            Type::TSIG => 250,
+            Type::AXFR => 252,
+            Type::MAILB => 253,
+            Type::MAILA => 254,
+            Type::ANY => 255,
+            Type::UNKNOWN(v) => v,
+        }
+    }
+}
+
+impl From<u16> for Type {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::A,
+            2 => Self::NS,
+            3 => Self::MD,
+            4 => Self::MF,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            7 => Self::MB,
+            8 => Self::MG,
+            9 => Self::MR,
+            10 => Self::NULL,
+            11 => Self::WKS,
+            12 => Self::PTR,
+            13 => Self::HINFO,
+            14 => Self::MINFO,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
+            43 => Self::DS,
+            46 => Self::RRSIG,
+            47 => Self::NSEC,
+            48 => Self::DNSKEY,
+            50 => Self::NSEC3,
+            51 => Self::NSEC3PARAM,
+            64 => Self::SVCB,
+            65 => Self::HTTPS,
+            65401 => Self::ALIAS,
+            250 => Self::TSIG,
+            // QType
+            252 => Self::AXFR,
+            253 => Self::MAILB,
+            254 => Self::MAILA,
+            255 => Self::ANY,
+            _ => Self::UNKNOWN(value),
+        }
+    }
+}
+
 impl Type {
+    /// Mnemonic used in the presentation format, `None` for unknown types.
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        let m = match self {
+            Self::A => "A",
+            Self::NS => "NS",
+            Self::MD => "MD",
+            Self::MF => "MF",
+            Self::CNAME => "CNAME",
+            Self::SOA => "SOA",
+            Self::MB => "MB",
+            Self::MG => "MG",
+            Self::MR => "MR",
+            Self::NULL => "NULL",
+            Self::WKS => "WKS",
+            Self::PTR => "PTR",
+            Self::HINFO => "HINFO",
+            Self::MINFO => "MINFO",
+            Self::MX => "MX",
+            Self::TXT => "TXT",
+            Self::AAAA => "AAAA",
+            Self::SRV => "SRV",
+            Self::OPT => "OPT",
+            Self::DS => "DS",
+            Self::RRSIG => "RRSIG",
+            Self::NSEC => "NSEC",
+            Self::DNSKEY => "DNSKEY",
+            Self::NSEC3 => "NSEC3",
+            Self::NSEC3PARAM => "NSEC3PARAM",
+            Self::SVCB => "SVCB",
+            Self::HTTPS => "HTTPS",
+            Self::ALIAS => "ALIAS",
+            Self::TSIG => "TSIG",
+            Self::AXFR => "AXFR",
+            Self::MAILB => "MAILB",
+            Self::MAILA => "MAILA",
+            Self::ANY => "ANY",
+            Self::UNKNOWN(_) => return None,
+        };
+        Some(m)
+    }
+
     pub fn encode(&self, enc: &mut Encoder) {
-        match self {
-            Self::A => enc.write_u16(1),
-            Self::NS => enc.write_u16(2),
-            Self::MD => enc.write_u16(3),
-            Self::MF => enc.write_u16(4),
-            Self::CNAME => enc.write_u16(5),
-            Self::SOA => enc.write_u16(6),
-            Self::MB => enc.write_u16(7),
-            Self::MG => enc.write_u16(8),
-            Self::MR => enc.write_u16(9),
-            Self::NULL => enc.write_u16(10),
-            Self::WKS => enc.write_u16(11),
-            Self::PTR => enc.write_u16(12),
-            Self::HINFO => enc.write_u16(13),
-            Self::MINFO => enc.write_u16(14),
-            Self::MX => enc.write_u16(15),
-            Self::TXT => enc.write_u16(16),
-            Self::AXFR => enc.write_u16(252),
-            Self::MAILB => enc.write_u16(253),
-            Self::MAILA => enc.write_u16(254),
-            Self::ANY => enc.write_u16(255),
-            Self::UNKNOWN(v) => enc.write_u16(*v),
-        }
-        // enc.write_u16(*self as u16)
+        enc.write_u16((*self).into())
     }
 
     pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let value = dec.read_u16()?;
-        match value {
-            1 => Ok(Self::A),
-            2 => Ok(Self::NS),
-            3 => Ok(Self::MD),
-            4 => Ok(Self::MF),
-            5 => Ok(Self::CNAME),
-            6 => Ok(Self::SOA),
-            7 => Ok(Self::MB),
-            8 => Ok(Self::MG),
-            9 => Ok(Self::MR),
-            10 => Ok(Self::NULL),
-            11 => Ok(Self::WKS),
-            12 => Ok(Self::PTR),
-            13 => Ok(Self::HINFO),
-            14 => Ok(Self::MINFO),
-            15 => Ok(Self::MX),
-            16 => Ok(Self::TXT),
-            // QType
-            252 => Ok(Self::AXFR),
-            253 => Ok(Self::MAILB),
-            254 => Ok(Self::MAILA),
-            255 => Ok(Self::ANY),
-            _ => Ok(Self::UNKNOWN(value)),
+        Ok(dec.read_u16()?.into())
+    }
+}
+
+/// Mnemonic, or `TYPE<n>` for unknown types (RFC 3597, section 5).
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mnemonic() {
+            Some(m) => f.write_str(m),
+            None => write!(f, "TYPE{}", u16::from(*self)),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq)]
+impl FromStr for Type {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(n) = upper
+            .strip_prefix("TYPE")
+            .and_then(|n| n.parse::<u16>().ok())
+        {
+            return Ok(n.into());
+        }
+        (1..=u16::MAX)
+            .map(Type::from)
+            .find(|t| t.mnemonic() == Some(upper.as_str()))
+            .ok_or_else(|| Error::InvalidPresentation(format!("unknown type {}", s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 #[repr(u16)]
 #[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum Class {
@@ -115,29 +501,84 @@ pub enum Class {
     CS, // 2 the CSNET class (Obsolete - used only for examples in some obsolete RFCs)
     CH, // 3 the CHAOS class
     HS, // 4 Hesiod [Dyer 87]
+
+    // Qclass
+    ANY = 255, // 255 any class, also the class of TSIG records
     UNKNOWN(u16),
 }
 
+impl From<Class> for u16 {
+    fn from(c: Class) -> Self {
+        match c {
+            Class::IN => 1,
+            Class::CS => 2,
+            Class::CH => 3,
+            Class::HS => 4,
+            Class::ANY => 255,
+            Class::UNKNOWN(v) => v,
+        }
+    }
+}
+
+impl From<u16> for Class {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::IN,
+            2 => Self::CS,
+            3 => Self::CH,
+            4 => Self::HS,
+            255 => Self::ANY,
+            _ => Self::UNKNOWN(value),
+        }
+    }
+}
+
 impl Class {
-    pub fn encode(&self, enc: &mut Encoder) {
-        match &self {
-            Self::IN => enc.write_u16(1),
-            Self::CS => enc.write_u16(2),
-            Self::CH => enc.write_u16(3),
-            Self::HS => enc.write_u16(4),
-            Self::UNKNOWN(v) => enc.write_u16(*v),
+    /// Mnemonic used in the presentation format, `None` for unknown classes.
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        match self {
+            Self::IN => Some("IN"),
+            Self::CS => Some("CS"),
+            Self::CH => Some("CH"),
+            Self::HS => Some("HS"),
+            Self::ANY => Some("ANY"),
+            Self::UNKNOWN(_) => None,
         }
-        // enc.write_u16(*self as u16)
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_u16((*self).into())
     }
 
     pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let value = dec.read_u16()?;
-        match value {
-            1 => Ok(Self::IN),
-            2 => Ok(Self::CS),
-            3 => Ok(Self::CH),
-            4 => Ok(Self::HS),
-            _ => Ok(Self::UNKNOWN(value)),
+        Ok(dec.read_u16()?.into())
+    }
+}
+
+/// Mnemonic, or `CLASS<n>` for unknown classes (RFC 3597, section 5).
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mnemonic() {
+            Some(m) => f.write_str(m),
+            None => write!(f, "CLASS{}", u16::from(*self)),
+        }
+    }
+}
+
+impl FromStr for Class {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "IN" => Ok(Self::IN),
+            "CS" => Ok(Self::CS),
+            "CH" => Ok(Self::CH),
+            "HS" => Ok(Self::HS),
+            upper => upper
+                .strip_prefix("CLASS")
+                .and_then(|n| n.parse::<u16>().ok())
+                .map(Class::from)
+                .ok_or_else(|| Error::InvalidPresentation(format!("unknown class {}", s))),
         }
     }
 }
@@ -150,18 +591,19 @@ pub struct Question {
 }
 
 impl Question {
-    fn encode(&self, enc: &mut Encoder) {
-        self.name.encode(enc);
+    fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        self.name.encode(enc)?;
         self.qtype.encode(enc);
         self.class.encode(enc);
+        Ok(())
     }
 
     fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let mut q = Self::default();
-        q.name = Name::decode(dec)?;
-        q.qtype = Type::decode(dec)?;
-        q.class = Class::decode(dec)?;
-        Ok(q)
+        Ok(Self {
+            name: Name::decode(dec)?,
+            qtype: Type::decode(dec)?,
+            class: Class::decode(dec)?,
+        })
     }
 }
 
@@ -172,29 +614,80 @@ pub struct Record {
     pub class: Class,
     pub ttl: u32,
     // rdlength: u16, taken from rdata
-    pub rdata: Vec<u8>,
+    pub rdata: RData,
 }
 
 impl Record {
-    pub fn encode(&self, enc: &mut Encoder) {
-        self.name.encode(enc);
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        self.name.encode(enc)?;
         self.rtype.encode(enc);
         self.class.encode(enc);
         enc.write_u32(self.ttl);
-        enc.write_u16(self.rdata.len() as u16);
-        enc.write_slice(&self.rdata)
+
+        enc.with_length_prefix(|enc| self.rdata.encode(enc))
     }
 
     pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let mut rec = Record::default();
-
-        rec.name = Name::decode(dec)?;
-        rec.rtype = Type::decode(dec)?;
-        rec.class = Class::decode(dec)?;
-        rec.ttl = dec.read_u32()?;
+        let name = Name::decode(dec)?;
+        let rtype = Type::decode(dec)?;
+        let class = Class::decode(dec)?;
+        let ttl = dec.read_u32()?;
         let rdlength = dec.read_u16()?;
-        rec.rdata = dec.read_slice(rdlength as usize)?.to_vec();
-        Ok(rec)
+        let rdata = RData::decode(rtype, rdlength as usize, dec)?;
+        Ok(Self {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// A line of a master file, the owner fully qualified.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.name.to_fqdn(),
+            self.ttl,
+            self.class,
+            self.rtype,
+            self.rdata
+        )
+    }
+}
+
+/// Follows the CNAME chain for `qname` through `answers` and returns the name
+/// it ends at if `answers` has no `qtype` records for it, i.e. the chain still
+/// needs resolving. Returns `None` for complete answers, answers without a
+/// CNAME for `qname` and looping chains.
+pub fn unresolved_cname(answers: &[Record], qname: &Name, qtype: Type) -> Option<Name> {
+    if matches!(qtype, Type::CNAME | Type::ANY) {
+        return None;
+    }
+
+    let mut name = qname;
+    let mut seen = Vec::new();
+    loop {
+        let owned = answers.iter().filter(|r| r.name == *name);
+        if owned.clone().any(|r| r.rtype == qtype) {
+            return None;
+        }
+        let target = owned.into_iter().find_map(|r| match &r.rdata {
+            RData::CNAME(target) => Some(target),
+            _ => None,
+        });
+        match target {
+            Some(target) if seen.contains(&target) => return None,
+            Some(target) => {
+                seen.push(name);
+                name = target;
+            }
+            None if seen.is_empty() => return None,
+            None => return Some(name.clone()),
+        }
     }
 }
 
@@ -230,9 +723,16 @@ pub struct Message {
     // Server sets this to 1 to indicate that recursion is available.
     pub ra: u8,
 
-    // Reserved (Z), 3 bits
-    // Used by DNSSEC queries. At inception, it was reserved for future use.
+    // Reserved (Z), 1 bit
     pub z: u8,
+
+    // Authentic Data (AD), 1 bit (RFC 4035, section 3.2.3)
+    // Server sets this to 1 if it validated all the data in the reply.
+    pub ad: u8,
+
+    // Checking Disabled (CD), 1 bit (RFC 4035, section 3.2.2)
+    // Sender sets this to 1 to get replies the server hasn't validated.
+    pub cd: u8,
     // Response Code (RCODE), 4 bits
     // Response code indicating the status of the response.
     pub rcode: u8,
@@ -241,18 +741,24 @@ pub struct Message {
 
     // pub ancount: u16,
 
-    // Authority Record Count (NSCOUNT), 16 bits
-    // Number of records in the Authority section.
-    pub nscount: u16,
-    // Additional Record Count (ARCOUNT), 16 bits
-    // Number of records in the Additional section.
-    pub arcount: u16,
+    // pub nscount: u16,
+
+    // pub arcount: u16,
 
     // questions
     pub questions: Vec<Question>,
 
     // answers
     pub answers: Vec<Record>,
+
+    // authority records
+    pub authorities: Vec<Record>,
+
+    // additional records, not including the OPT pseudo-record
+    pub additionals: Vec<Record>,
+
+    // EDNS0 OPT pseudo-record, carried in the additional section
+    pub opt: Option<Opt>,
 }
 
 impl Message {
@@ -267,23 +773,48 @@ impl Message {
         })?;
         enc.write_bits(|b| {
             b.write(self.ra, 1)?;
-            b.write(self.z, 3)?;
+            b.write(self.z, 1)?;
+            b.write(self.ad, 1)?;
+            b.write(self.cd, 1)?;
             b.write(self.rcode, 4)
         })?;
-        enc.write_u16(self.questions.len() as u16);
-        enc.write_u16(self.answers.len() as u16);
-        enc.write_u16(self.nscount);
-        enc.write_u16(self.arcount);
+        // filled in once the sections are written
+        let qdcount = enc.reserve_u16();
+        let ancount = enc.reserve_u16();
+        let nscount = enc.reserve_u16();
+        let arcount = enc.reserve_u16();
 
-        self.questions.iter().for_each(|q| q.encode(enc));
-        self.answers.iter().for_each(|a| a.encode(enc));
-        Ok(())
+        for question in self.questions.iter() {
+            question.encode(enc)?;
+        }
+        for record in self.answers.iter() {
+            record.encode(enc)?;
+        }
+        for record in self.authorities.iter() {
+            record.encode(enc)?;
+        }
+        for record in self.additionals.iter() {
+            record.encode(enc)?;
+        }
+        if let Some(opt) = &self.opt {
+            opt.encode(enc)?;
+        }
+
+        enc.patch_count(qdcount, self.questions.len())?;
+        enc.patch_count(ancount, self.answers.len())?;
+        enc.patch_count(nscount, self.authorities.len())?;
+        enc.patch_count(
+            arcount,
+            self.additionals.len() + self.opt.is_some() as usize,
+        )
     }
 
     pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
-        let mut msg = Message::default();
+        let mut msg = Message {
+            id: dec.read_u16()?,
+            ..Message::default()
+        };
 
-        msg.id = dec.read_u16()?;
         dec.read_bits(|b| {
             msg.qr = b.read(1)?;
             msg.opcode = b.read(4)?;
@@ -294,28 +825,58 @@ impl Message {
         })?;
         dec.read_bits(|b| {
             msg.ra = b.read(1)?;
-            msg.z = b.read(3)?;
+            msg.z = b.read(1)?;
+            msg.ad = b.read(1)?;
+            msg.cd = b.read(1)?;
             msg.rcode = b.read(4)?;
             Ok(())
         })?;
 
         let qdcount = dec.read_u16()?;
         let ancount = dec.read_u16()?;
-        msg.nscount = dec.read_u16()?;
-        msg.arcount = dec.read_u16()?;
+        let nscount = dec.read_u16()?;
+        let arcount = dec.read_u16()?;
 
         // now we read questions based on qdcount from header
         msg.questions = (0..qdcount)
-            .into_iter()
             .map(|_| Question::decode(dec))
             .collect::<Result<Vec<_>, _>>()?;
 
-        msg.answers = (0..ancount)
-            .into_iter()
+        msg.decode_records(dec, ancount, nscount, arcount)?;
+        Ok(msg)
+    }
+
+    /// Decodes the answer, authority and additional records at the offset,
+    /// as many as the header counts, into this message.
+    pub fn decode_records(
+        &mut self,
+        dec: &mut Decoder,
+        ancount: u16,
+        nscount: u16,
+        arcount: u16,
+    ) -> Result<(), Error> {
+        self.answers = (0..ancount)
+            .map(|_| Record::decode(dec))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.authorities = (0..nscount)
             .map(|_| Record::decode(dec))
             .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(msg)
+        for _ in 0..arcount {
+            // peek at the type to pick out the OPT pseudo-record
+            let start = dec.skip_name()?;
+            let rtype = Type::decode(dec)?;
+            dec.set_offset(start);
+
+            if rtype == Type::OPT {
+                self.opt = Some(Opt::decode(dec)?);
+            } else {
+                self.additionals.push(Record::decode(dec)?);
+            }
+        }
+
+        Ok(())
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
@@ -325,16 +886,108 @@ impl Message {
         Ok(buf)
     }
 
+    /// Encodes the message framed for TCP, after its length (RFC 1035,
+    /// section 4.2.2). Fails if it's too long for the length to count.
+    pub fn to_tcp_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::with_capacity(514);
+        let mut enc = Encoder::new(&mut buf);
+        let pos = enc.reserve_u16();
+        enc.start_message();
+        self.encode(&mut enc)?;
+        let len = enc.offset() - pos - 2;
+        enc.patch_u16(
+            pos,
+            u16::try_from(len).map_err(|_| Error::MessageTooLong(len))?,
+        );
+        Ok(buf)
+    }
+
+    /// Encodes the message padded with the EDNS Padding option (RFC 7830)
+    /// to a multiple of `block` bytes, counting `overhead` bytes appended
+    /// afterwards, such as a TSIG record. Messages without an OPT record,
+    /// and those padding would take past 65535 bytes, go unpadded.
+    pub fn to_padded_bytes(&self, block: usize, overhead: usize) -> Result<Vec<u8>, Error> {
+        let mut padded = self.clone();
+        let Some(opt) = padded.opt.as_mut() else {
+            return self.to_bytes();
+        };
+        opt.set_option(EdnsOption::Padding(0));
+        let len = padded.to_bytes()?.len() + overhead;
+        let padding = len.next_multiple_of(block) - len;
+        if len + padding > u16::MAX as usize {
+            return self.to_bytes();
+        }
+        if let Some(opt) = padded.opt.as_mut() {
+            opt.set_option(EdnsOption::Padding(padding));
+        }
+        padded.to_bytes()
+    }
+
     pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
         let mut dec = Decoder::new(buf);
         let msg = Self::decode(&mut dec)?;
         Ok(msg)
     }
+
+    /// Empty reply to this request, echoing its ID, opcode, RD, CD and
+    /// questions. Carries an OPT record only if the request had one (RFC
+    /// 6891, section 7).
+    pub fn reply(&self) -> Message {
+        Message {
+            id: self.id,
+            opcode: self.opcode,
+            rd: self.rd,
+            cd: self.cd,
+            qr: 1,
+            questions: self.questions.clone(),
+            opt: self.opt.as_ref().map(|opt| Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                dnssec_ok: opt.dnssec_ok,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    /// Reply carrying only the questions and an error `rcode`.
+    pub fn error_reply(&self, rcode: u8) -> Message {
+        Message {
+            rcode,
+            ..self.reply()
+        }
+    }
 }
 
 #[cfg(test)]
-mod test {
-    use super::{Class, Decoder, Encoder, Message, Name, Question, Record, Type};
+pub(crate) mod test {
+    use super::{
+        rcode, unresolved_cname, Class, Decoder, Encoder, Error, Message, Name, Opt, Question,
+        RData, Record, Type,
+    };
+    use crate::{
+        edns::EdnsOption,
+        rdata::{Mx, Srv},
+    };
+    use std::net::Ipv4Addr;
+
+    /// A question for `name` and `qtype` in class IN.
+    pub(crate) fn question(name: &str, qtype: Type) -> Question {
+        Question {
+            name: Name::from(name),
+            qtype,
+            class: Class::IN,
+        }
+    }
+
+    /// A client's query with ID 7 and RD set, asking the one question.
+    pub(crate) fn request(name: &str, qtype: Type) -> Message {
+        Message {
+            id: 7,
+            rd: 1,
+            questions: vec![question(name, qtype)],
+            ..Message::default()
+        }
+    }
 
     fn test_cases() -> Vec<(&'static str, Vec<u8>)> {
         vec![
@@ -358,10 +1011,10 @@ mod test {
     #[test]
     fn test_name_encode() {
         for (input, expect) in test_cases() {
-            let name = Name(input.into());
+            let name = Name::from(input);
             let mut buf = Vec::new();
             let mut encoder = Encoder::new(&mut buf);
-            name.encode(&mut encoder);
+            name.encode(&mut encoder).unwrap();
             assert_eq!(expect, buf);
         }
     }
@@ -371,7 +1024,7 @@ mod test {
         for (expect, input) in test_cases() {
             let mut decoder = Decoder::new(&input);
             let name = Name::decode(&mut decoder);
-            assert_eq!(Ok(Name(expect.into())), name);
+            assert_eq!(Ok(Name::from(expect)), name);
         }
     }
 
@@ -380,17 +1033,19 @@ mod test {
         let orig_msg = Message {
             id: 1,
             aa: 1,
+            ad: 1,
+            cd: 1,
             questions: vec![Question {
-                name: Name("codecrafters.io".into()),
+                name: Name::from("codecrafters.io"),
                 qtype: Type::A,
                 class: Class::IN,
             }],
             answers: vec![Record {
-                name: Name("codecrafters.io".into()),
+                name: Name::from("codecrafters.io"),
                 rtype: Type::A,
                 class: Class::IN,
                 ttl: 60,
-                rdata: vec![8u8; 4],
+                rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
             }],
             ..Message::default()
         };
@@ -401,8 +1056,387 @@ mod test {
 
         assert!(res.is_ok());
 
-        let mut dec = Decoder::new(&mut buf);
+        let mut dec = Decoder::new(&buf);
         let res = Message::decode(&mut dec);
         assert_eq!(Ok(orig_msg), res);
     }
+
+    #[test]
+    fn test_msg_encode_compresses_names() {
+        let msg = Message {
+            id: 1,
+            questions: vec![Question {
+                name: Name::from("codecrafters.io"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            answers: vec![Record {
+                name: Name::from("codecrafters.io"),
+                rtype: Type::A,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
+            }],
+            ..Message::default()
+        };
+
+        let buf = msg.to_bytes().unwrap();
+        // header (12) + question name (17) + qtype/class (4), answer name is
+        // a pointer back to the question name at offset 12
+        assert_eq!(&buf[33..35], &[0xC0, 12]);
+        assert_eq!(Ok(msg), Message::from_bytes(&buf));
+    }
+
+    #[test]
+    fn test_msg_encode_compresses_rdata_names() {
+        let record = |name: &str, rtype, rdata| Record {
+            name: Name::from(name),
+            rtype,
+            class: Class::IN,
+            ttl: 60,
+            rdata,
+        };
+        let msg = Message {
+            id: 1,
+            qr: 1,
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            answers: vec![
+                record(
+                    "www.example.com",
+                    Type::CNAME,
+                    RData::CNAME(Name::from("web.example.com")),
+                ),
+                record(
+                    "example.com",
+                    Type::SRV,
+                    RData::SRV(Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 53,
+                        target: Name::from("ns.example.com"),
+                    }),
+                ),
+                record(
+                    "example.com",
+                    Type::MX,
+                    RData::MX(Mx {
+                        preference: 10,
+                        exchange: Name::from("ns.example.com"),
+                    }),
+                ),
+            ],
+            ..Message::default()
+        };
+
+        #[rustfmt::skip]
+        let expect = vec![
+            0, 1, 0x80, 0, 0, 1, 0, 3, 0, 0, 0, 0,
+            // 12: www.example.com A IN
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 1, 0, 1,
+            // 33: owner -> 12, rdata web -> example.com at 16
+            0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6,
+            3, b'w', b'e', b'b', 0xC0, 16,
+            // 51: owner -> 16, the SRV target written in full
+            0xC0, 16, 0, 33, 0, 1, 0, 0, 0, 60, 0, 22,
+            0, 0, 0, 0, 0, 53,
+            2, b'n', b's', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            // 85: owner -> 16, the MX exchange -> ns.example.com at 69
+            0xC0, 16, 0, 15, 0, 1, 0, 0, 0, 60, 0, 4,
+            0, 10, 0xC0, 69,
+        ];
+        let buf = msg.to_bytes().unwrap();
+        assert_eq!(expect, buf);
+        assert_eq!(Ok(msg.clone()), Message::from_bytes(&buf));
+
+        // framed for TCP, the pointers are the same
+        let framed = msg.to_tcp_bytes().unwrap();
+        assert_eq!(&[0, 101], &framed[..2]);
+        assert_eq!(expect, framed[2..]);
+    }
+
+    #[test]
+    fn test_msg_opt_encode_decode() {
+        let orig_msg = Message {
+            id: 7,
+            rd: 1,
+            questions: vec![Question {
+                name: Name::from("codecrafters.io"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            additionals: vec![Record {
+                name: Name::from("ns.codecrafters.io"),
+                rtype: Type::A,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            }],
+            opt: Some(Opt {
+                udp_payload_size: 4096,
+                ext_rcode: 1,
+                version: 0,
+                dnssec_ok: true,
+                options: vec![EdnsOption::Cookie {
+                    client: [1, 2, 3, 4, 5, 6, 7, 8],
+                    server: None,
+                }],
+            }),
+            ..Message::default()
+        };
+
+        let buf = orig_msg.to_bytes().unwrap();
+        // arcount includes the OPT record
+        assert_eq!(&buf[10..12], &[0, 2]);
+        assert_eq!(Ok(orig_msg), Message::from_bytes(&buf));
+    }
+
+    fn cname(name: &str, target: &str) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: Type::CNAME,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::CNAME(Name::from(target)),
+        }
+    }
+
+    #[test]
+    fn test_reply() {
+        let request = Message {
+            id: 1234,
+            rd: 1,
+            cd: 1,
+            questions: vec![Question {
+                name: Name::from("codecrafters.io"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            opt: Some(Opt {
+                udp_payload_size: 4096,
+                dnssec_ok: true,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+
+        let reply = request.error_reply(rcode::SERVFAIL);
+        assert_eq!(1234, reply.id);
+        assert_eq!(1, reply.qr);
+        assert_eq!((1, 1, 0), (reply.rd, reply.cd, reply.ad));
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert_eq!(request.questions, reply.questions);
+        assert_eq!(
+            Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                dnssec_ok: true,
+                ..Opt::default()
+            }),
+            reply.opt
+        );
+
+        let plain = Message {
+            opt: None,
+            ..request
+        };
+        assert_eq!(None, plain.reply().opt);
+    }
+
+    #[test]
+    fn test_padded_bytes() {
+        let request = Message {
+            id: 1234,
+            questions: vec![Question {
+                name: Name::from("codecrafters.io"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            opt: Some(Opt {
+                options: vec![EdnsOption::Padding(100)],
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let mut reply = request.reply();
+        let buf = reply.to_padded_bytes(468, 0).unwrap();
+        assert_eq!(468, buf.len());
+        let decoded = Message::from_bytes(&buf).unwrap();
+        assert!(matches!(
+            decoded.opt.unwrap().option(EdnsOption::PADDING),
+            Some(EdnsOption::Padding(_))
+        ));
+        assert_eq!(468 - 61, reply.to_padded_bytes(468, 61).unwrap().len());
+
+        // the message itself stays as it was
+        assert!(reply.opt.as_ref().unwrap().options.is_empty());
+        reply.opt = None;
+        assert_eq!(reply.to_bytes(), reply.to_padded_bytes(468, 0));
+    }
+
+    #[test]
+    fn test_is_subdomain_of() {
+        let zone = Name::from("corp.example.com.");
+        assert!(Name::from("corp.example.com").is_subdomain_of(&zone));
+        assert!(Name::from("WWW.Corp.example.com").is_subdomain_of(&zone));
+        assert!(!Name::from("notcorp.example.com").is_subdomain_of(&zone));
+        assert!(!Name::from("example.com").is_subdomain_of(&zone));
+        assert!(Name::from("example.com").is_subdomain_of(&Name::root()));
+    }
+
+    #[test]
+    fn test_name_parse_display() {
+        let name: Name = "a\\.b.c\\\\d.\\009x.example.".parse().unwrap();
+        assert_eq!(
+            vec![&b"a.b"[..], b"c\\d", b"\tx", b"example"],
+            name.labels()
+        );
+        assert_eq!("a\\.b.c\\\\d.\\009x.example", name.to_string());
+        assert_eq!(Ok(name.clone()), name.to_string().parse());
+        assert_eq!("a\\.b.c\\\\d.\\009x.example.", name.to_fqdn());
+        assert_eq!(Ok(Name::root()), ".".parse());
+        assert_eq!(".", Name::root().to_fqdn());
+
+        assert!("a..example".parse::<Name>().is_err());
+        assert!(".example".parse::<Name>().is_err());
+        assert!("a\\25".parse::<Name>().is_err());
+        assert!("a\\256".parse::<Name>().is_err());
+        assert!(format!("{}.example", "a".repeat(64))
+            .parse::<Name>()
+            .is_err());
+        assert!(vec!["a".repeat(63); 4].join(".").parse::<Name>().is_err());
+
+        // converting honors escapes too
+        assert_eq!(
+            vec![&b"a.b"[..], b"example"],
+            Name::from("a\\.b.example").labels()
+        );
+
+        // names built from parts are checked when joined or encoded
+        let long = Name::from(vec!["a".repeat(63); 3].join(".").as_str());
+        let origin = Name::from("a".repeat(61).as_str());
+        assert_eq!(255, long.append(&origin).unwrap().wire_len());
+        let origin = Name::from("a".repeat(62).as_str());
+        assert!(long.append(&origin).is_err());
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        let overlong = Name::from_labels(["a".repeat(64), "example".into()]);
+        assert_eq!(Err(Error::LabelTooLong(64)), enc.write_name(&overlong));
+        let overlong = Name::from_labels(vec!["a".repeat(63); 4]);
+        assert_eq!(Err(Error::NameTooLong(257)), enc.write_name(&overlong));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_name_binary_labels() {
+        let name = Name::from_labels([&[0xFF, b'A'][..], b"example"]);
+        assert_eq!("\\255A.example", name.to_string());
+        assert!(name.eq_exact(&name.to_string().parse().unwrap()));
+        assert_eq!(Name::from("\\255a.EXAMPLE"), name);
+
+        // anywhere in a message, RDATA too
+        let message = Message {
+            questions: vec![Question {
+                name: name.clone(),
+                qtype: Type::CNAME,
+                class: Class::IN,
+            }],
+            answers: vec![Record {
+                name: name.clone(),
+                rtype: Type::CNAME,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::CNAME(name.clone()),
+            }],
+            ..Message::default()
+        };
+        let decoded = Message::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        assert!(decoded.questions[0].name.eq_exact(&name));
+        assert_eq!(RData::CNAME(name), decoded.answers[0].rdata);
+    }
+
+    #[test]
+    fn test_name_labels() {
+        let name = Name::from_labels(["www", "Example", "com"]);
+        assert_eq!(3, name.num_labels());
+        assert_eq!(
+            vec![&b"com"[..], b"Example", b"www"],
+            name.iter_labels().rev().collect::<Vec<_>>()
+        );
+        assert!(name.parent().unwrap().eq_exact(&Name::from("Example.com")));
+        assert_eq!(Some(Name::root()), Name::from("com").parent());
+        assert_eq!(None, Name::root().parent());
+    }
+
+    #[test]
+    fn test_name_compare() {
+        let (a, b) = (
+            Name::from("WWW.Example.com."),
+            Name::from("www.example.COM"),
+        );
+        assert_eq!(a, b);
+        assert!(!a.eq_exact(&b) && a.eq_exact(&a.clone()));
+        assert!(a.to_lowercase().eq_exact(&b.to_lowercase()));
+        let set: std::collections::HashSet<_> = [a, b].into_iter().collect();
+        assert_eq!(1, set.len());
+
+        // RFC 4034, section 6.1, without the escaped labels
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        for pair in names.windows(2) {
+            let (a, b) = (Name::from(pair[0]), Name::from(pair[1]));
+            assert!(a < b, "{} < {}", a, b);
+        }
+        assert!(Name::root() < Name::from("example"));
+    }
+
+    #[test]
+    fn test_record_display() {
+        assert_eq!(
+            "www.example.com.\t60\tIN\tCNAME\tweb.example.com.",
+            cname("www.example.com", "web.example.com").to_string()
+        );
+    }
+
+    #[test]
+    fn test_unresolved_cname() {
+        let www = Name::from("www.example.com");
+        let a = Record {
+            name: Name::from("Edge.CDN.net"),
+            rtype: Type::A,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let chain = vec![
+            cname("www.example.com", "web.example.com"),
+            cname("web.example.com", "edge.cdn.net."),
+        ];
+
+        assert_eq!(
+            Some(Name::from("edge.cdn.net.")),
+            unresolved_cname(&chain, &www, Type::A)
+        );
+        let mut complete = chain.clone();
+        complete.push(a);
+        assert_eq!(None, unresolved_cname(&complete, &www, Type::A));
+        assert_eq!(None, unresolved_cname(&chain, &www, Type::CNAME));
+        assert_eq!(None, unresolved_cname(&[], &www, Type::A));
+
+        let looping = vec![
+            cname("www.example.com", "web.example.com"),
+            cname("web.example.com", "www.example.com"),
+        ];
+        assert_eq!(None, unresolved_cname(&looping, &www, Type::A));
+    }
 }
@@ -0,0 +1,123 @@
+//! The `query` subcommand: looks a name up with the resolver of the
+//! configuration and prints the reply like dig does, or with `--trace`
+//! follows the delegations from the root servers down itself, printing
+//! every server asked along the way.
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+use crate::{
+    config::Config,
+    proto::{rcode, Class, Message, Question, Type},
+    recursor::{self, Recursor},
+    resolver,
+};
+
+/// Resolves `name` and `qtype` and prints the outcome to stdout.
+pub fn run(config: &Config, name: &str, qtype: Type, trace: bool) -> Result<()> {
+    let question = Question {
+        name: name.parse().map_err(anyhow::Error::msg)?,
+        qtype,
+        class: Class::IN,
+    };
+    let mut out = io::stdout().lock();
+    if trace {
+        let mut recursor = Recursor::new(config.upstream_timeout)?;
+        if let Some(path) = &config.root_hints {
+            recursor = recursor.with_hints(recursor::load_hints(path)?);
+        }
+        recursor.trace(&question, &mut out)?;
+        return Ok(());
+    }
+
+    let request = Message {
+        rd: 1,
+        questions: vec![question],
+        ..Message::default()
+    };
+    let reply = resolver::from_config(config)?.resolve(&request)?;
+    print(&reply, &mut out)?;
+    Ok(())
+}
+
+/// The reply's status, flags and records, a section after another.
+fn print(reply: &Message, out: &mut dyn Write) -> io::Result<()> {
+    let flags: Vec<&str> = [
+        ("qr", reply.qr),
+        ("aa", reply.aa),
+        ("tc", reply.tc),
+        ("rd", reply.rd),
+        ("ra", reply.ra),
+        ("ad", reply.ad),
+        ("cd", reply.cd),
+    ]
+    .into_iter()
+    .filter(|(_, bit)| *bit == 1)
+    .map(|(flag, _)| flag)
+    .collect();
+    writeln!(
+        out,
+        ";; status: {}, flags: {}",
+        rcode::name(reply.rcode),
+        flags.join(" ")
+    )?;
+    for (section, records) in [
+        ("ANSWER", &reply.answers),
+        ("AUTHORITY", &reply.authorities),
+        ("ADDITIONAL", &reply.additionals),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        writeln!(out, "\n;; {} SECTION:", section)?;
+        for record in records {
+            writeln!(out, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::print;
+    use crate::{
+        proto::{rcode, Class, Message, Name, Question, Record, Type},
+        rdata::RData,
+    };
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_print() {
+        let request = Message {
+            rd: 1,
+            questions: vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let reply = Message {
+            ra: 1,
+            rcode: rcode::NOERROR,
+            answers: vec![Record {
+                name: Name::from("example.com"),
+                rtype: Type::A,
+                class: Class::IN,
+                ttl: 300,
+                rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            }],
+            ..request.reply()
+        };
+        let mut out = Vec::new();
+        print(&reply, &mut out).unwrap();
+        assert_eq!(
+            ";; status: NOERROR, flags: qr rd ra\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             example.com.\t300\tIN\tA\t192.0.2.1\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
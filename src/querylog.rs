@@ -0,0 +1,276 @@
+//! Query log, a line per query in the format of BIND's `queries` category
+//! with the outcome appended:
+//!
+//! ```text
+//! 16-Oct-2026 09:31:07.123 client 192.0.2.7#5353 (example.com): query: example.com IN A +E(0) udp NOERROR 1 12ms forwarded
+//! ```
+//!
+//! The flags are BIND's: `+` if recursion was desired, `-` if not, then `E(v)`
+//! for EDNS version v, `T` for TCP and `D` for DNSSEC OK.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+
+use crate::{
+    handler::{take_origin, Context, Dropped, Next, RequestHandler, Transport},
+    privacy,
+    proto::{rcode, Message},
+    rdata::format_timestamp,
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Where query log lines go.
+enum Sink {
+    Stdout,
+    // rotated to `path.1`, `path.2`, ... once `max_size` bytes are written,
+    // never if zero, keeping `versions` old files
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_size: u64,
+        versions: u32,
+    },
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Self::File {
+                path,
+                file,
+                size,
+                max_size,
+                versions,
+            } => {
+                if *max_size > 0 && *size > 0 && *size + line.len() as u64 > *max_size {
+                    rotate(path, *versions)?;
+                    *file = open(path)?;
+                    *size = 0;
+                }
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Shifts `path` to `path.1`, `path.1` to `path.2` and so on, dropping the
+/// oldest past `versions`. With no versions kept the file is just emptied.
+fn rotate(path: &Path, versions: u32) -> io::Result<()> {
+    let version = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    if versions == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..versions).rev() {
+        if version(n).exists() {
+            fs::rename(version(n), version(n + 1))?;
+        }
+    }
+    fs::rename(path, version(1))
+}
+
+/// Logs every request with how it was answered. The file is opened again on
+/// configuration reloads, so it can be moved away and a reload sent.
+pub struct QueryLog {
+    sink: Mutex<Sink>,
+}
+
+impl QueryLog {
+    /// Log to `path`, `-` for stdout, rotated as it reaches `max_size` bytes
+    /// if that isn't zero, keeping `versions` old files.
+    pub fn open(path: &Path, max_size: u64, versions: u32) -> Result<Self> {
+        let sink = if path == Path::new("-") {
+            Sink::Stdout
+        } else {
+            let file = open(path)
+                .with_context(|| format!("Failed to open query log {}", path.display()))?;
+            Sink::File {
+                path: path.to_path_buf(),
+                size: file.metadata()?.len(),
+                file,
+                max_size,
+                versions,
+            }
+        };
+        Ok(Self {
+            sink: Mutex::new(sink),
+        })
+    }
+}
+
+/// Time in the format of BIND logs, e.g. `16-Oct-2026 09:31:07.123`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ts = format_timestamp(since_epoch.as_secs() as u32);
+    let month: usize = ts[4..6].parse().unwrap_or(1);
+    format!(
+        "{}-{}-{} {}:{}:{}.{:03}",
+        &ts[6..8],
+        MONTHS[month - 1],
+        &ts[0..4],
+        &ts[8..10],
+        &ts[10..12],
+        &ts[12..14],
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The part of the line about `request` itself, up to the transport.
+fn describe(ctx: &Context, request: &Message) -> String {
+    let client = privacy::ip(ctx.source.ip());
+    let mut line = format!("client {}#{}", client, ctx.source.port());
+    let question = request.questions.first();
+    let qname = question.map_or(".".into(), |q| q.name.to_string());
+    let _ = write!(line, " ({}): query: {}", qname, qname);
+    if let Some(q) = question {
+        let _ = write!(line, " {} {}", q.class, q.qtype);
+    }
+    line.push(' ');
+    line.push(if request.rd == 1 { '+' } else { '-' });
+    if let Some(opt) = &request.opt {
+        let _ = write!(line, "E({})", opt.version);
+    }
+    if ctx.transport == Transport::Tcp {
+        line.push('T');
+    }
+    if request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok) {
+        line.push('D');
+    }
+    let _ = write!(line, " {}", format!("{:?}", ctx.transport).to_lowercase());
+    line
+}
+
+impl RequestHandler for QueryLog {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let started = Instant::now();
+        take_origin();
+        let query = describe(ctx, &request);
+        let result = next.run(ctx, request);
+        let ms = started.elapsed().as_millis();
+        let (outcome, answers) = match &result {
+            Ok(reply) => (rcode::name(reply.rcode), reply.answers.len()),
+            Err(e) if e.is::<Dropped>() => ("DROPPED".to_string(), 0),
+            Err(_) => ("SERVFAIL".to_string(), 0),
+        };
+        let line = format!(
+            "{} {} {} {} {}ms {}\n",
+            timestamp(SystemTime::now()),
+            query,
+            outcome,
+            answers,
+            ms,
+            take_origin()
+        );
+        if let Err(e) = self.sink.lock().unwrap().write_line(&line) {
+            tracing::warn!(error = %e, "Failed to write query log");
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe, rotate, timestamp, QueryLog};
+    use crate::{
+        edns::Opt,
+        handler::{set_origin, Chain, Context, Next, Origin, RequestHandler, Transport},
+        proto::{test::request, Message, Type},
+    };
+    use anyhow::Result;
+    use std::{
+        env, fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn ctx(transport: Transport) -> Context {
+        Context {
+            source: "192.0.2.7:5353".parse().unwrap(),
+            transport,
+            key: None,
+        }
+    }
+
+    struct Cached;
+
+    impl RequestHandler for Cached {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            set_origin(Origin::Cache);
+            Ok(request.reply())
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_792_143_067_123);
+        assert_eq!("16-Oct-2026 09:31:07.123", timestamp(time));
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            "client 192.0.2.7#5353 (example.com): query: example.com IN AAAA + udp",
+            describe(&ctx(Transport::Udp), &request("example.com", Type::AAAA))
+        );
+
+        let request = Message {
+            rd: 0,
+            opt: Some(Opt {
+                dnssec_ok: true,
+                ..Opt::default()
+            }),
+            ..request("example.com", Type::AAAA)
+        };
+        let line = describe(&ctx(Transport::Tcp), &request);
+        assert!(line.ends_with("AAAA -E(0)TD tcp"), "{}", line);
+    }
+
+    #[test]
+    fn test_log_and_rotate() {
+        let dir = env::temp_dir().join(format!("querylog-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+
+        // each line is about 120 bytes, two fit
+        let chain = Chain::default()
+            .with(QueryLog::open(&path, 250, 2).unwrap())
+            .with(Cached);
+        for _ in 0..7 {
+            chain
+                .handle(&ctx(Transport::Udp), request("example.com", Type::AAAA))
+                .unwrap();
+        }
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(1, current.lines().count());
+        assert!(
+            current.trim_end().ends_with("NOERROR 0 0ms cached"),
+            "{}",
+            current
+        );
+        let rotated = fs::read_to_string(dir.join("queries.log.1")).unwrap();
+        assert_eq!(2, rotated.lines().count());
+        assert!(dir.join("queries.log.2").exists());
+        assert!(!dir.join("queries.log.3").exists());
+
+        rotate(&path, 0).unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
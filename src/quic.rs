@@ -0,0 +1,2037 @@
+//! QUIC version 1 (RFC 9000) secured with TLS 1.3 (RFC 9001), as much of it
+//! as DNS over QUIC needs: client-initiated bidirectional streams, 1200-byte
+//! datagrams, and loss recovery by acknowledgements and probe timeouts
+//! (RFC 9002, simplified). No Retry, 0-RTT, migration or key updates.
+//!
+//! A `Connection` is driven over a `Link` that carries its datagrams: a
+//! connected socket for clients, the server's demultiplexer for servers.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, ErrorKind},
+    mem,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+
+use crate::{
+    aes::{self, Aes},
+    digest::{self, hkdf_extract},
+    encoder::Decoder,
+    tls::{self, expand_label, Handshake, Identity, Negotiated},
+    x509::{Certificate, Pin},
+};
+
+pub const VERSION: u32 = 1;
+
+/// Salt of the Initial secrets of version 1 (RFC 9001, section 5.2).
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Size of the datagrams sent, which every path must carry. Datagrams with
+/// Initial packets are padded to it (section 14.1).
+pub const MAX_DATAGRAM: usize = 1200;
+
+/// Length of the connection IDs this side picks.
+pub const CID_LEN: usize = 8;
+
+/// Packet numbers are always sent in full 4 bytes.
+const PN_LEN: usize = 4;
+
+// long header packet types
+const INITIAL: u8 = 0;
+const HANDSHAKE: u8 = 2;
+
+// packet number spaces, also the encryption levels
+const INITIAL_SPACE: usize = 0;
+const HANDSHAKE_SPACE: usize = 1;
+const APPLICATION_SPACE: usize = 2;
+
+// transport error codes (section 20.1)
+pub const NO_ERROR: u64 = 0x0;
+const FLOW_CONTROL_ERROR: u64 = 0x3;
+const STREAM_LIMIT_ERROR: u64 = 0x4;
+const STREAM_STATE_ERROR: u64 = 0x5;
+const FINAL_SIZE_ERROR: u64 = 0x6;
+const FRAME_ENCODING_ERROR: u64 = 0x7;
+const TRANSPORT_PARAMETER_ERROR: u64 = 0x8;
+const PROTOCOL_VIOLATION: u64 = 0xa;
+const APPLICATION_ERROR: u64 = 0xc;
+const CRYPTO_BUFFER_EXCEEDED: u64 = 0xd;
+// TLS alerts, offset by 0x100
+const HANDSHAKE_FAILURE: u64 = 0x128;
+const NO_APPLICATION_PROTOCOL: u64 = 0x178;
+
+/// Idle time after which a connection is dropped, advertised to the peer.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Flow control window of the whole connection, moved ahead as data arrives.
+const CONNECTION_WINDOW: u64 = 1 << 20;
+
+/// Bytes the peer may send on a stream, enough for one DNS message.
+const STREAM_WINDOW: u64 = 1 << 17;
+
+/// Streams the client may have open at once.
+const MAX_STREAMS: u64 = 100;
+
+/// CRYPTO bytes buffered ahead of the TLS handshake.
+const MAX_CRYPTO_BUFFER: u64 = 1 << 18;
+
+/// Packets kept until their keys are in place.
+const MAX_EARLY: usize = 16;
+
+/// Ranges of received packet numbers remembered for ACK frames.
+const MAX_ACK_RANGES: usize = 32;
+
+/// Datagrams sent at once.
+const MAX_BURST: usize = 32;
+
+/// RTT assumed before the first sample (RFC 9002, section 6.2.2).
+const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// Delay the peer may hold back acknowledgements for, the default.
+const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// A transport error: its code and why.
+type Violation = (u64, &'static str);
+
+const MALFORMED: Violation = (FRAME_ENCODING_ERROR, "malformed frame");
+
+/// Carries a connection's datagrams.
+pub trait Link {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()>;
+
+    /// Waits until `deadline` for a datagram, None if none came. May return
+    /// None early, for the caller to look at other things.
+    fn recv(&mut self, deadline: Instant) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// A socket connected to the peer.
+impl Link for UdpSocket {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        UdpSocket::send(self, datagram).map(|_| ())
+    }
+
+    fn recv(&mut self, deadline: Instant) -> io::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        if deadline <= now {
+            return Ok(None);
+        }
+        self.set_read_timeout(Some(deadline - now))?;
+        let mut buf = vec![0; u16::MAX as usize];
+        match UdpSocket::recv(self, &mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                Ok(Some(buf))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Keys protecting the packets of one direction at one encryption level
+/// (RFC 9001, section 5.1).
+struct PacketKeys {
+    aead: Aes,
+    iv: [u8; aes::NONCE_LEN],
+    // header protection
+    hp: Aes,
+}
+
+impl PacketKeys {
+    fn new(secret: &[u8]) -> Self {
+        let key = expand_label(secret, "quic key", b"", 16);
+        let iv = expand_label(secret, "quic iv", b"", aes::NONCE_LEN);
+        let hp = expand_label(secret, "quic hp", b"", 16);
+        Self {
+            aead: Aes::new(&key).unwrap(),
+            iv: iv.try_into().unwrap(),
+            hp: Aes::new(&hp).unwrap(),
+        }
+    }
+
+    /// Nonce of a packet: the IV XORed with its packet number.
+    fn nonce(&self, pn: u64) -> [u8; aes::NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (n, p) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+            *n ^= p;
+        }
+        nonce
+    }
+
+    /// Header protection mask for a sample of the ciphertext (section 5.4).
+    fn mask(&self, sample: &[u8]) -> [u8; 16] {
+        self.hp.encrypt_block(sample.try_into().unwrap())
+    }
+}
+
+/// Client and server Initial secrets, derived from the Destination
+/// Connection ID of the client's first packet (RFC 9001, section 5.2).
+fn initial_secrets(dcid: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let initial = hkdf_extract(digest::Hash::Sha256, &INITIAL_SALT, dcid);
+    (
+        expand_label(&initial, "client in", b"", 32),
+        expand_label(&initial, "server in", b"", 32),
+    )
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend((value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend((value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend((value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Reads a variable-length integer (section 16).
+pub fn read_varint(decoder: &mut Decoder) -> Result<u64> {
+    let first = decoder.read_u8()?;
+    let rest = decoder.read_slice((1 << (first >> 6)) - 1)?;
+    Ok(rest
+        .iter()
+        .fold((first & 0x3f) as u64, |value, b| value << 8 | *b as u64))
+}
+
+fn varint(decoder: &mut Decoder) -> Result<u64, Violation> {
+    read_varint(decoder).map_err(|_| MALFORMED)
+}
+
+fn varint_len(value: u64) -> usize {
+    match value {
+        0..=0x3f => 1,
+        0x40..=0x3fff => 2,
+        0x4000..=0x3fff_ffff => 4,
+        _ => 8,
+    }
+}
+
+/// The parts of a packet header readable without keys.
+#[derive(Debug)]
+pub struct Header<'a> {
+    // long header packet type, None for short headers
+    pub kind: Option<u8>,
+    pub version: u32,
+    pub dcid: &'a [u8],
+    pub scid: &'a [u8],
+    // where the protected packet number starts
+    pn_offset: usize,
+    // length of the whole packet, the rest of the datagram for short headers
+    len: usize,
+}
+
+impl Header<'_> {
+    pub fn is_initial(&self) -> bool {
+        self.kind == Some(INITIAL) && self.version == VERSION
+    }
+}
+
+/// Parses the header of the first packet in `datagram`. Short headers carry
+/// connection IDs of `cid_len` bytes.
+pub fn parse_header(datagram: &[u8], cid_len: usize) -> Option<Header<'_>> {
+    let first = *datagram.first()?;
+    if first & 0x80 == 0 {
+        return Some(Header {
+            kind: None,
+            version: VERSION,
+            dcid: datagram.get(1..1 + cid_len)?,
+            scid: &[],
+            pn_offset: 1 + cid_len,
+            len: datagram.len(),
+        });
+    }
+    let mut decoder = Decoder::new(datagram);
+    decoder.read_u8().ok()?;
+    let version = decoder.read_u32().ok()?;
+    let mut cid = || {
+        let len = decoder.read_u8().ok()? as usize;
+        (len <= 20).then(|| decoder.read_slice(len).ok()).flatten()
+    };
+    let (dcid, scid) = (cid()?, cid()?);
+    let kind = (first >> 4) & 3;
+    let mut header = Header {
+        kind: Some(kind),
+        version,
+        dcid,
+        scid,
+        pn_offset: 0,
+        len: datagram.len(),
+    };
+    if version != VERSION || !matches!(kind, INITIAL | HANDSHAKE) {
+        return Some(header);
+    }
+    if kind == INITIAL {
+        let token = read_varint(&mut decoder).ok()?;
+        decoder.read_slice(token as usize).ok()?;
+    }
+    let len = read_varint(&mut decoder).ok()? as usize;
+    header.pn_offset = decoder.offset();
+    header.len = header.pn_offset.checked_add(len)?;
+    (header.len <= datagram.len()).then_some(header)
+}
+
+/// Version Negotiation packet answering a packet of an unknown version
+/// (section 17.2.1).
+pub fn version_negotiation(header: &Header) -> Vec<u8> {
+    let mut packet = vec![0x80 | rand::thread_rng().gen::<u8>(), 0, 0, 0, 0];
+    packet.push(header.scid.len() as u8);
+    packet.extend(header.scid);
+    packet.push(header.dcid.len() as u8);
+    packet.extend(header.dcid);
+    packet.extend(VERSION.to_be_bytes());
+    packet
+}
+
+/// The full packet number closest to the next expected one (appendix A.3).
+fn decode_pn(largest: Option<u64>, truncated: u64, bits: usize) -> u64 {
+    let expected = largest.map_or(0, |largest| largest + 1);
+    let window = 1u64 << bits;
+    let candidate = (expected & !(window - 1)) | truncated;
+    if candidate + window / 2 <= expected && candidate < (1 << 62) - window {
+        candidate + window
+    } else if candidate > expected + window / 2 && candidate >= window {
+        candidate - window
+    } else {
+        candidate
+    }
+}
+
+/// Transport parameters (section 18), those this implementation uses.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TransportParameters {
+    original_dcid: Option<Vec<u8>>,
+    initial_scid: Option<Vec<u8>>,
+    // milliseconds, 0 for none
+    idle_timeout: u64,
+    max_data: u64,
+    stream_data_bidi_local: u64,
+    stream_data_bidi_remote: u64,
+    streams_bidi: u64,
+}
+
+impl TransportParameters {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut bytes = |id: u64, value: &[u8]| {
+            write_varint(&mut buf, id);
+            write_varint(&mut buf, value.len() as u64);
+            buf.extend(value);
+        };
+        if let Some(cid) = &self.original_dcid {
+            bytes(0x00, cid);
+        }
+        if let Some(cid) = &self.initial_scid {
+            bytes(0x0f, cid);
+        }
+        for (id, value) in [
+            (0x01, self.idle_timeout),
+            (0x04, self.max_data),
+            (0x05, self.stream_data_bidi_local),
+            (0x06, self.stream_data_bidi_remote),
+            (0x08, self.streams_bidi),
+        ] {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, value);
+            bytes(id, &encoded);
+        }
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut params = Self::default();
+        let mut decoder = Decoder::new(data);
+        while decoder.offset() < data.len() {
+            let id = read_varint(&mut decoder)?;
+            let len = read_varint(&mut decoder)?;
+            let value = decoder.read_slice(len as usize)?;
+            let integer = || -> Result<u64> {
+                let mut decoder = Decoder::new(value);
+                let integer = read_varint(&mut decoder)?;
+                match decoder.offset() == value.len() {
+                    true => Ok(integer),
+                    false => bail!("malformed transport parameter {:#x}", id),
+                }
+            };
+            match id {
+                0x00 => params.original_dcid = Some(value.to_vec()),
+                0x01 => params.idle_timeout = integer()?,
+                0x04 => params.max_data = integer()?,
+                0x05 => params.stream_data_bidi_local = integer()?,
+                0x06 => params.stream_data_bidi_remote = integer()?,
+                0x08 => params.streams_bidi = integer()?,
+                0x0f => params.initial_scid = Some(value.to_vec()),
+                _ => {}
+            }
+        }
+        Ok(params)
+    }
+}
+
+/// Frames that are sent again when the packet carrying them is lost.
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Ping,
+    Crypto {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Stream {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        fin: bool,
+    },
+    ResetStream {
+        id: u64,
+        code: u64,
+        final_size: u64,
+    },
+    StopSending {
+        id: u64,
+        code: u64,
+    },
+    MaxData(u64),
+    MaxStreams(u64),
+    PathResponse([u8; 8]),
+    HandshakeDone,
+}
+
+impl Frame {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Ping => buf.push(0x01),
+            Self::Crypto { offset, data } => {
+                buf.push(0x06);
+                write_varint(buf, *offset);
+                write_varint(buf, data.len() as u64);
+                buf.extend(data);
+            }
+            // always with offset and length
+            Self::Stream {
+                id,
+                offset,
+                data,
+                fin,
+            } => {
+                buf.push(0x0e | *fin as u8);
+                write_varint(buf, *id);
+                write_varint(buf, *offset);
+                write_varint(buf, data.len() as u64);
+                buf.extend(data);
+            }
+            Self::ResetStream {
+                id,
+                code,
+                final_size,
+            } => {
+                buf.push(0x04);
+                for value in [*id, *code, *final_size] {
+                    write_varint(buf, value);
+                }
+            }
+            Self::StopSending { id, code } => {
+                buf.push(0x05);
+                write_varint(buf, *id);
+                write_varint(buf, *code);
+            }
+            Self::MaxData(max) => {
+                buf.push(0x10);
+                write_varint(buf, *max);
+            }
+            Self::MaxStreams(max) => {
+                buf.push(0x12);
+                write_varint(buf, *max);
+            }
+            Self::PathResponse(data) => {
+                buf.push(0x1b);
+                buf.extend(data);
+            }
+            Self::HandshakeDone => buf.push(0x1e),
+        }
+    }
+
+    /// Splits a CRYPTO or STREAM frame into a head that encodes in `room`
+    /// bytes and the rest.
+    fn split(self, room: usize) -> Option<(Frame, Frame)> {
+        match self {
+            Self::Crypto { offset, mut data } => {
+                let overhead = 1 + varint_len(offset) + varint_len(data.len() as u64);
+                let len = room.checked_sub(overhead).filter(|len| *len > 0)?;
+                let rest = data.split_off(len.min(data.len()));
+                let next = offset + data.len() as u64;
+                Some((
+                    Self::Crypto { offset, data },
+                    Self::Crypto {
+                        offset: next,
+                        data: rest,
+                    },
+                ))
+            }
+            Self::Stream {
+                id,
+                offset,
+                mut data,
+                fin,
+            } => {
+                let overhead =
+                    1 + varint_len(id) + varint_len(offset) + varint_len(data.len() as u64);
+                let len = room.checked_sub(overhead).filter(|len| *len > 0)?;
+                let rest = data.split_off(len.min(data.len()));
+                let next = offset + data.len() as u64;
+                Some((
+                    Self::Stream {
+                        id,
+                        offset,
+                        data,
+                        fin: false,
+                    },
+                    Self::Stream {
+                        id,
+                        offset: next,
+                        data: rest,
+                        fin,
+                    },
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Bytes received at arbitrary offsets, read in order.
+#[derive(Debug, Default)]
+struct Reassembly {
+    // contiguous bytes from `start`
+    data: Vec<u8>,
+    start: u64,
+    // bytes past a gap, by offset
+    segments: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Reassembly {
+    /// Offset right after the contiguous bytes.
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    fn insert(&mut self, offset: u64, bytes: &[u8]) {
+        if offset > self.end() {
+            let segment = self.segments.entry(offset).or_default();
+            if segment.len() < bytes.len() {
+                *segment = bytes.to_vec();
+            }
+            return;
+        }
+        self.append(offset, bytes);
+        while let Some((&offset, _)) = self.segments.first_key_value() {
+            if offset > self.end() {
+                break;
+            }
+            let bytes = self.segments.remove(&offset).unwrap();
+            self.append(offset, &bytes);
+        }
+    }
+
+    /// Appends what `bytes`, starting at or before the end, add.
+    fn append(&mut self, offset: u64, bytes: &[u8]) {
+        let skip = (self.end() - offset) as usize;
+        if skip < bytes.len() {
+            self.data.extend(&bytes[skip..]);
+        }
+    }
+
+    fn consume(&mut self, len: usize) -> Vec<u8> {
+        self.start += len as u64;
+        self.data.drain(..len).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.segments.is_empty()
+    }
+}
+
+/// An ack-eliciting packet awaiting acknowledgement.
+#[derive(Debug)]
+struct SentPacket {
+    time: Instant,
+    frames: Vec<Frame>,
+}
+
+/// State of one packet number space.
+#[derive(Default)]
+struct Space {
+    read: Option<PacketKeys>,
+    write: Option<PacketKeys>,
+    // the keys are gone for good
+    discarded: bool,
+    next_pn: u64,
+    // received packet numbers as inclusive ranges, highest first
+    received: Vec<(u64, u64)>,
+    ack_needed: bool,
+    crypto: Reassembly,
+    // offset of the next CRYPTO byte sent
+    crypto_offset: u64,
+    // frames waiting to be sent
+    pending: VecDeque<Frame>,
+    sent: BTreeMap<u64, SentPacket>,
+    // packets that came before their keys
+    early: Vec<Vec<u8>>,
+}
+
+impl Space {
+    /// Records a received packet number, false for duplicates.
+    fn record(&mut self, pn: u64) -> bool {
+        let ranges = &mut self.received;
+        for i in 0..ranges.len() {
+            let (low, high) = ranges[i];
+            if (low..=high).contains(&pn) {
+                return false;
+            }
+            if pn == high + 1 {
+                ranges[i].1 = pn;
+                return true;
+            }
+            if pn + 1 == low {
+                ranges[i].0 = pn;
+                if ranges.get(i + 1).is_some_and(|next| next.1 + 1 == pn) {
+                    ranges[i].0 = ranges.remove(i + 1).0;
+                }
+                return true;
+            }
+            if pn > high {
+                ranges.insert(i, (pn, pn));
+                ranges.truncate(MAX_ACK_RANGES);
+                return true;
+            }
+        }
+        ranges.push((pn, pn));
+        ranges.truncate(MAX_ACK_RANGES);
+        true
+    }
+
+    /// ACK frame for the packets received (section 19.3).
+    fn ack_frame(&self) -> Vec<u8> {
+        let mut buf = vec![0x02];
+        let (low, high) = self.received[0];
+        write_varint(&mut buf, high);
+        write_varint(&mut buf, 0);
+        write_varint(&mut buf, self.received.len() as u64 - 1);
+        write_varint(&mut buf, high - low);
+        let mut previous = low;
+        for (low, high) in &self.received[1..] {
+            write_varint(&mut buf, previous - high - 2);
+            write_varint(&mut buf, high - low);
+            previous = *low;
+        }
+        buf
+    }
+
+    /// Sends the frames of `packets` again.
+    fn requeue(&mut self, packets: &[u64]) {
+        for pn in packets.iter().rev() {
+            if let Some(packet) = self.sent.remove(pn) {
+                for frame in packet.frames.into_iter().rev() {
+                    self.pending.push_front(frame);
+                }
+            }
+        }
+    }
+
+    fn discard(&mut self) {
+        *self = Self {
+            discarded: true,
+            ..Self::default()
+        };
+    }
+}
+
+/// A bidirectional stream.
+#[derive(Debug, Default)]
+struct Stream {
+    recv: Reassembly,
+    final_size: Option<u64>,
+    // highest offset received, counted against flow control
+    received: u64,
+    // error code the peer reset its side with
+    reset: Option<u64>,
+    // the application has the received data
+    delivered: bool,
+    // data waiting for flow control credit
+    send: Vec<u8>,
+    send_offset: u64,
+    // the peer's limit on `send_offset`
+    send_limit: u64,
+    // the application wrote its last byte
+    fin: bool,
+    // every byte and the end went into frames, or the stream was reset
+    send_done: bool,
+}
+
+impl Stream {
+    fn is_done(&self) -> bool {
+        self.delivered && self.send_done
+    }
+}
+
+/// CONNECTION_CLOSE waiting to be sent.
+#[derive(Debug)]
+struct Close {
+    code: u64,
+    application: bool,
+    reason: String,
+}
+
+/// A QUIC connection, either side.
+pub struct Connection {
+    is_client: bool,
+    scid: Vec<u8>,
+    dcid: Vec<u8>,
+    // Destination Connection ID of the client's first packet
+    original_dcid: Vec<u8>,
+    // whether the client switched to the connection ID the server chose
+    dcid_settled: bool,
+    spaces: [Space; 3],
+    params: TransportParameters,
+    streams: BTreeMap<u64, Stream>,
+    // streams opened so far by this side and by the peer
+    opened: u64,
+    peer_opened: u64,
+    // streams this side may open, and the peer may open
+    peer_max_streams: u64,
+    max_streams: u64,
+    // connection flow control, both ways
+    peer_max_data: u64,
+    data_sent: u64,
+    max_data: u64,
+    data_received: u64,
+    // stream data the peer accepts on streams it opens and this side opens
+    peer_stream_data_local: u64,
+    peer_stream_data_remote: u64,
+    srtt: Duration,
+    rttvar: Duration,
+    has_rtt_sample: bool,
+    // consecutive probe timeouts
+    pto_count: u32,
+    idle_timeout: Duration,
+    last_activity: Instant,
+    // the server may send 3 times what it received until the client proves
+    // its address (section 8.1)
+    validated: bool,
+    bytes_received: usize,
+    bytes_sent: usize,
+    close: Option<Close>,
+    // why the connection ended
+    closed: Option<String>,
+}
+
+impl Connection {
+    fn new(is_client: bool, scid: Vec<u8>, dcid: Vec<u8>, original_dcid: Vec<u8>) -> Self {
+        let (client_secret, server_secret) = initial_secrets(&original_dcid);
+        let (write, read) = match is_client {
+            true => (client_secret, server_secret),
+            false => (server_secret, client_secret),
+        };
+        let mut spaces: [Space; 3] = Default::default();
+        spaces[INITIAL_SPACE].read = Some(PacketKeys::new(&read));
+        spaces[INITIAL_SPACE].write = Some(PacketKeys::new(&write));
+        let params = TransportParameters {
+            original_dcid: (!is_client).then(|| original_dcid.clone()),
+            initial_scid: Some(scid.clone()),
+            idle_timeout: IDLE_TIMEOUT.as_millis() as u64,
+            max_data: CONNECTION_WINDOW,
+            // a client takes replies on its streams, a server queries on
+            // the client's
+            stream_data_bidi_local: if is_client { STREAM_WINDOW } else { 0 },
+            stream_data_bidi_remote: if is_client { 0 } else { STREAM_WINDOW },
+            streams_bidi: if is_client { 0 } else { MAX_STREAMS },
+        };
+        Self {
+            is_client,
+            scid,
+            dcid,
+            original_dcid,
+            dcid_settled: !is_client,
+            spaces,
+            max_streams: params.streams_bidi,
+            max_data: params.max_data,
+            params,
+            streams: BTreeMap::new(),
+            opened: 0,
+            peer_opened: 0,
+            peer_max_streams: 0,
+            peer_max_data: 0,
+            data_sent: 0,
+            data_received: 0,
+            peer_stream_data_local: 0,
+            peer_stream_data_remote: 0,
+            srtt: INITIAL_RTT,
+            rttvar: INITIAL_RTT / 2,
+            has_rtt_sample: false,
+            pto_count: 0,
+            idle_timeout: IDLE_TIMEOUT,
+            last_activity: Instant::now(),
+            validated: is_client,
+            bytes_received: 0,
+            bytes_sent: 0,
+            close: None,
+            closed: None,
+        }
+    }
+
+    /// A client connection, with fresh connection IDs.
+    pub fn client() -> Self {
+        let mut rng = rand::thread_rng();
+        let scid = rng.gen::<[u8; CID_LEN]>().to_vec();
+        let dcid = rng.gen::<[u8; CID_LEN]>().to_vec();
+        Self::new(true, scid, dcid.clone(), dcid)
+    }
+
+    /// A server connection for a client whose first packet has `header`.
+    pub fn server(header: &Header) -> Self {
+        let scid = rand::thread_rng().gen::<[u8; CID_LEN]>().to_vec();
+        Self::new(false, scid, header.scid.to_vec(), header.dcid.to_vec())
+    }
+
+    /// Connection ID this side picked, the peer addresses packets with it.
+    pub fn scid(&self) -> &[u8] {
+        &self.scid
+    }
+
+    /// Destination Connection ID of the client's first packet.
+    pub fn original_dcid(&self) -> &[u8] {
+        &self.original_dcid
+    }
+
+    /// Why the connection ended, None while it's open.
+    pub fn closed(&self) -> Option<&str> {
+        self.closed.as_deref()
+    }
+
+    /// Performs the handshake as a client of `host`, authenticating the
+    /// server with a certificate chain up to one of `roots` and, if any, one
+    /// of `pins`. The server must agree to `protocol` with ALPN.
+    pub fn connect(
+        &mut self,
+        link: &mut impl Link,
+        host: &str,
+        roots: &[Certificate],
+        pins: &[Pin],
+        protocol: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let params = self.params.encode();
+        let mut driver = Driver::new(self, link, Instant::now() + timeout);
+        let negotiated = tls::connect(&mut driver, host, roots, pins, &[protocol], Some(&params));
+        self.finish_handshake(link, negotiated, protocol)
+    }
+
+    /// Performs the handshake as a server authenticated by `identity`,
+    /// starting with the client's first datagram. The client must offer
+    /// `protocol` with ALPN.
+    pub fn accept(
+        &mut self,
+        link: &mut impl Link,
+        first: &[u8],
+        identity: &Identity,
+        protocol: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        self.receive(first, Instant::now());
+        let params = self.params.encode();
+        let mut driver = Driver::new(self, link, Instant::now() + timeout);
+        let negotiated = tls::accept(&mut driver, identity, &[protocol], Some(&params));
+        self.finish_handshake(link, negotiated, protocol)
+    }
+
+    fn finish_handshake(
+        &mut self,
+        link: &mut impl Link,
+        negotiated: Result<Negotiated>,
+        protocol: &str,
+    ) -> Result<()> {
+        let result = match negotiated {
+            Err(e) => {
+                self.close_with(HANDSHAKE_FAILURE, false, "TLS handshake failed");
+                Err(e)
+            }
+            // ALPN is mandatory (RFC 9001, section 8.1)
+            Ok(negotiated) if negotiated.protocol.as_deref() != Some(protocol) => {
+                self.close_with(NO_APPLICATION_PROTOCOL, false, "no application protocol");
+                Err(anyhow!("peer doesn't speak {}", protocol))
+            }
+            Ok(negotiated) => self
+                .apply_peer_parameters(negotiated.transport_parameters)
+                .map_err(|(code, reason)| {
+                    self.close_with(code, false, reason);
+                    anyhow!("{}", reason)
+                }),
+        };
+        // the handshake is confirmed for the server once complete
+        if result.is_ok() && !self.is_client {
+            self.spaces[APPLICATION_SPACE]
+                .pending
+                .push_back(Frame::HandshakeDone);
+            self.spaces[HANDSHAKE_SPACE].discard();
+        }
+        self.flush(link)?;
+        result
+    }
+
+    fn apply_peer_parameters(&mut self, raw: Option<Vec<u8>>) -> Result<(), Violation> {
+        const INVALID: Violation = (TRANSPORT_PARAMETER_ERROR, "invalid transport parameters");
+        let peer = raw
+            .and_then(|raw| TransportParameters::decode(&raw).ok())
+            .ok_or(INVALID)?;
+        let original_dcid = self.is_client.then(|| self.original_dcid.clone());
+        if peer.initial_scid.as_ref() != Some(&self.dcid) || peer.original_dcid != original_dcid {
+            return Err(INVALID);
+        }
+        self.peer_max_data = peer.max_data;
+        self.peer_max_streams = peer.streams_bidi;
+        self.peer_stream_data_local = peer.stream_data_bidi_local;
+        self.peer_stream_data_remote = peer.stream_data_bidi_remote;
+        if peer.idle_timeout > 0 {
+            self.idle_timeout = self
+                .idle_timeout
+                .min(Duration::from_millis(peer.idle_timeout));
+        }
+        Ok(())
+    }
+
+    /// Opens a stream, None once the peer allows no more.
+    pub fn open_stream(&mut self) -> Option<u64> {
+        if self.opened >= self.peer_max_streams || self.closed.is_some() {
+            return None;
+        }
+        let id = self.opened << 2 | !self.is_client as u64;
+        self.opened += 1;
+        let stream = Stream {
+            send_limit: self.peer_stream_data_remote,
+            ..Stream::default()
+        };
+        self.streams.insert(id, stream);
+        Some(id)
+    }
+
+    /// Queues `data` on a stream, the last of it with `fin`.
+    pub fn write_stream(&mut self, id: u64, data: &[u8], fin: bool) {
+        if let Some(stream) = self.streams.get_mut(&id).filter(|s| !s.fin && !s.send_done) {
+            stream.send.extend(data);
+            stream.fin = fin;
+        }
+    }
+
+    /// All data of a stream once the peer finished it, or the error code it
+    /// reset the stream with.
+    pub fn read_stream(&mut self, id: u64) -> Option<Result<Vec<u8>, u64>> {
+        let stream = self.streams.get_mut(&id).filter(|s| !s.delivered)?;
+        let result = match stream.reset {
+            Some(code) => Err(code),
+            None if stream.final_size == Some(stream.recv.end()) => {
+                Ok(mem::take(&mut stream.recv.data))
+            }
+            None => return None,
+        };
+        stream.delivered = true;
+        self.collect();
+        Some(result)
+    }
+
+    /// Streams the peer opened with all their data in, or reset, that
+    /// weren't read yet.
+    pub fn incoming(&self) -> Vec<u64> {
+        self.streams
+            .iter()
+            .filter(|(id, stream)| {
+                self.is_peer_stream(**id)
+                    && !stream.delivered
+                    && (stream.reset.is_some() || stream.final_size == Some(stream.recv.end()))
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Abandons a stream both ways, telling the peer with `code`.
+    pub fn cancel_stream(&mut self, id: u64, code: u64) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        let pending = &mut self.spaces[APPLICATION_SPACE].pending;
+        if !stream.send_done {
+            pending.push_back(Frame::ResetStream {
+                id,
+                code,
+                final_size: stream.send_offset,
+            });
+            stream.send_done = true;
+        }
+        if !stream.delivered {
+            pending.push_back(Frame::StopSending { id, code });
+            stream.delivered = true;
+        }
+        self.collect();
+    }
+
+    /// Closes the connection with an application error `code`.
+    pub fn close(&mut self, code: u64, reason: &str) {
+        self.close_with(code, true, reason);
+    }
+
+    fn close_with(&mut self, code: u64, application: bool, reason: &str) {
+        if self.closed.is_none() && self.close.is_none() {
+            self.close = Some(Close {
+                code,
+                application,
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    fn is_peer_stream(&self, id: u64) -> bool {
+        (id & 1 == 0) != self.is_client
+    }
+
+    /// Drops streams done both ways. Each one the peer opened lets it open
+    /// another.
+    fn collect(&mut self) {
+        let done: Vec<u64> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.is_done())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in done {
+            self.streams.remove(&id);
+            if self.is_peer_stream(id) {
+                self.max_streams += 1;
+                self.spaces[APPLICATION_SPACE]
+                    .pending
+                    .push_back(Frame::MaxStreams(self.max_streams));
+            }
+        }
+    }
+
+    /// Sends the datagrams that are due.
+    pub fn flush(&mut self, link: &mut impl Link) -> io::Result<()> {
+        for datagram in self.poll_transmit(Instant::now()) {
+            link.send(&datagram)?;
+        }
+        Ok(())
+    }
+
+    /// Sends what's due, then waits for a datagram or a timer, until
+    /// `deadline` at most.
+    pub fn step(&mut self, link: &mut impl Link, deadline: Instant) -> Result<()> {
+        self.flush(link)?;
+        if let Some(reason) = &self.closed {
+            bail!("{}", reason);
+        }
+        if Instant::now() >= deadline {
+            bail!("QUIC connection timed out");
+        }
+        let wake = self.next_timeout().min(deadline);
+        match link.recv(wake)? {
+            Some(datagram) => self.receive(&datagram, Instant::now()),
+            None => self.on_timeout(Instant::now()),
+        }
+        Ok(())
+    }
+
+    /// When a timer of the connection expires: a probe timeout or the idle
+    /// timeout.
+    pub fn next_timeout(&self) -> Instant {
+        (0..3)
+            .filter_map(|space| self.pto_deadline(space))
+            .fold(self.idle_deadline(), Instant::min)
+    }
+
+    /// The idle timeout, no less than 3 probe timeouts (section 10.1).
+    fn idle_deadline(&self) -> Instant {
+        self.last_activity + self.idle_timeout.max(3 * self.pto(APPLICATION_SPACE))
+    }
+
+    /// Probe timeout of a space, backed off exponentially (RFC 9002, section
+    /// 6.2.1).
+    fn pto(&self, space: usize) -> Duration {
+        let mut pto = self.srtt + (4 * self.rttvar).max(Duration::from_millis(1));
+        if space == APPLICATION_SPACE {
+            pto += MAX_ACK_DELAY;
+        }
+        pto * 2u32.pow(self.pto_count.min(16))
+    }
+
+    fn pto_deadline(&self, space: usize) -> Option<Instant> {
+        let (_, last) = self.spaces[space].sent.last_key_value()?;
+        Some(last.time + self.pto(space))
+    }
+
+    fn on_timeout(&mut self, now: Instant) {
+        if self.closed.is_some() {
+            return;
+        }
+        if now >= self.idle_deadline() {
+            self.closed = Some("QUIC connection idle".into());
+            return;
+        }
+        // everything in flight is presumed lost
+        let mut expired = false;
+        for space in 0..3 {
+            if self
+                .pto_deadline(space)
+                .is_some_and(|deadline| now >= deadline)
+            {
+                let space = &mut self.spaces[space];
+                let packets: Vec<u64> = space.sent.keys().copied().collect();
+                space.requeue(&packets);
+                expired = true;
+            }
+        }
+        if expired {
+            self.pto_count += 1;
+        }
+    }
+
+    /// Processes a received datagram.
+    pub fn receive(&mut self, datagram: &[u8], now: Instant) {
+        if self.closed.is_some() {
+            return;
+        }
+        self.bytes_received += datagram.len();
+        let mut rest = datagram;
+        while let Some(header) = parse_header(rest, self.scid.len()) {
+            let packet = &rest[..header.len];
+            rest = &rest[header.len..];
+            if header.version != VERSION || header.pn_offset == 0 {
+                continue;
+            }
+            let space = match header.kind {
+                Some(INITIAL) => INITIAL_SPACE,
+                Some(_) => HANDSHAKE_SPACE,
+                None => APPLICATION_SPACE,
+            };
+            // the client's first packets go to the ID it made up
+            let to_us = header.dcid == self.scid
+                || !self.is_client
+                    && space != APPLICATION_SPACE
+                    && header.dcid == self.original_dcid;
+            if to_us {
+                self.receive_packet(space, packet, header.pn_offset, now);
+            }
+            if self.closed.is_some() {
+                return;
+            }
+        }
+    }
+
+    fn receive_packet(&mut self, space: usize, packet: &[u8], pn_offset: usize, now: Instant) {
+        let Some(keys) = &self.spaces[space].read else {
+            let early = &mut self.spaces[space].early;
+            if !self.spaces[space].discarded && early.len() < MAX_EARLY {
+                early.push(packet.to_vec());
+            }
+            return;
+        };
+        if packet.len() < pn_offset + 4 + 16 {
+            return;
+        }
+        let mut packet = packet.to_vec();
+        let long = packet[0] & 0x80 != 0;
+        let mask = keys.mask(&packet[pn_offset + 4..pn_offset + 20]);
+        packet[0] ^= mask[0] & if long { 0x0f } else { 0x1f };
+        let pn_len = (packet[0] & 3) as usize + 1;
+        let mut truncated = 0;
+        for i in 0..pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+            truncated = truncated << 8 | packet[pn_offset + i] as u64;
+        }
+        let largest = self.spaces[space].received.first().map(|(_, high)| *high);
+        let pn = decode_pn(largest, truncated, pn_len * 8);
+        let header_len = pn_offset + pn_len;
+        let Some(payload) = keys.aead.open(
+            &keys.nonce(pn),
+            &packet[..header_len],
+            &packet[header_len..],
+        ) else {
+            return;
+        };
+        if packet[0] & if long { 0x0c } else { 0x18 } != 0 {
+            self.close_with(PROTOCOL_VIOLATION, false, "reserved header bits set");
+            return;
+        }
+        if !self.spaces[space].record(pn) {
+            return;
+        }
+        self.last_activity = now;
+
+        if self.is_client && !self.dcid_settled && long {
+            if let Some(header) = parse_header(&packet, CID_LEN) {
+                self.dcid = header.scid.to_vec();
+                self.dcid_settled = true;
+            }
+        }
+        // a Handshake packet proves the client's address, and that it no
+        // longer needs Initial packets (RFC 9001, section 4.9.1)
+        if !self.is_client && space == HANDSHAKE_SPACE {
+            self.validated = true;
+            self.spaces[INITIAL_SPACE].discard();
+        }
+        match self.process_frames(space, &payload, now) {
+            Ok(true) => self.spaces[space].ack_needed = true,
+            Ok(false) => {}
+            Err((code, reason)) => self.close_with(code, false, reason),
+        }
+    }
+
+    /// Processes packets that came before the keys of `space`.
+    fn receive_early(&mut self, space: usize, now: Instant) {
+        for packet in mem::take(&mut self.spaces[space].early) {
+            if let Some(header) = parse_header(&packet, self.scid.len()) {
+                self.receive_packet(space, &packet, header.pn_offset, now);
+            }
+        }
+    }
+
+    /// Processes the frames of a packet, returning whether it needs an
+    /// acknowledgement.
+    fn process_frames(
+        &mut self,
+        space: usize,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<bool, Violation> {
+        let mut decoder = Decoder::new(payload);
+        let d = &mut decoder;
+        let mut ack_eliciting = false;
+        while d.offset() < payload.len() {
+            let kind = varint(d)?;
+            ack_eliciting |= !matches!(kind, 0x00 | 0x02 | 0x03 | 0x1c | 0x1d);
+            if space != APPLICATION_SPACE && !matches!(kind, 0x00..=0x03 | 0x06 | 0x1c) {
+                return Err((PROTOCOL_VIOLATION, "frame not allowed before the handshake"));
+            }
+            match kind {
+                // PADDING, PING
+                0x00 | 0x01 => {}
+                0x02 | 0x03 => {
+                    let largest = varint(d)?;
+                    let _delay = varint(d)?;
+                    let count = varint(d)?;
+                    let first = varint(d)?;
+                    let mut low = largest.checked_sub(first).ok_or(MALFORMED)?;
+                    let mut ranges = vec![(low, largest)];
+                    for _ in 0..count {
+                        let gap = varint(d)?;
+                        let len = varint(d)?;
+                        let high = low.checked_sub(gap + 2).ok_or(MALFORMED)?;
+                        low = high.checked_sub(len).ok_or(MALFORMED)?;
+                        ranges.push((low, high));
+                    }
+                    // ECN counts
+                    if kind == 0x03 {
+                        for _ in 0..3 {
+                            varint(d)?;
+                        }
+                    }
+                    self.on_ack(space, &ranges, now)?;
+                }
+                0x04 => {
+                    let (id, code, final_size) = (varint(d)?, varint(d)?, varint(d)?);
+                    if let Some(stream) = self.stream_for_frame(id)? {
+                        if stream.final_size.is_some_and(|size| size != final_size)
+                            || final_size < stream.received
+                        {
+                            return Err((FINAL_SIZE_ERROR, "stream reset with another size"));
+                        }
+                        let more = final_size - stream.received;
+                        stream.received = final_size;
+                        stream.final_size = Some(final_size);
+                        stream.reset = Some(code);
+                        self.receive_data(more)?;
+                    }
+                }
+                0x05 => {
+                    let (id, code) = (varint(d)?, varint(d)?);
+                    if let Some(stream) = self.stream_for_frame(id)? {
+                        if !stream.send_done {
+                            stream.send.clear();
+                            stream.send_done = true;
+                            let final_size = stream.send_offset;
+                            self.spaces[APPLICATION_SPACE]
+                                .pending
+                                .push_back(Frame::ResetStream {
+                                    id,
+                                    code,
+                                    final_size,
+                                });
+                        }
+                        self.collect();
+                    }
+                }
+                0x06 => {
+                    let offset = varint(d)?;
+                    let len = varint(d)?;
+                    let data = d.read_slice(len as usize).map_err(|_| MALFORMED)?;
+                    let crypto = &mut self.spaces[space].crypto;
+                    if offset + len > crypto.start + MAX_CRYPTO_BUFFER {
+                        return Err((CRYPTO_BUFFER_EXCEEDED, "too much handshake data"));
+                    }
+                    // post-handshake messages such as session tickets are
+                    // of no use
+                    if space != APPLICATION_SPACE {
+                        crypto.insert(offset, data);
+                    }
+                }
+                // NEW_TOKEN
+                0x07 => {
+                    let len = varint(d)?;
+                    d.read_slice(len as usize).map_err(|_| MALFORMED)?;
+                }
+                0x08..=0x0f => {
+                    let id = varint(d)?;
+                    let offset = if kind & 0x04 != 0 { varint(d)? } else { 0 };
+                    let len = match kind & 0x02 != 0 {
+                        true => varint(d)?,
+                        false => (payload.len() - d.offset()) as u64,
+                    };
+                    let data = d.read_slice(len as usize).map_err(|_| MALFORMED)?;
+                    self.on_stream(id, offset, data, kind & 0x01 != 0)?;
+                }
+                0x10 => self.peer_max_data = self.peer_max_data.max(varint(d)?),
+                0x11 => {
+                    let (id, max) = (varint(d)?, varint(d)?);
+                    if let Some(stream) = self.stream_for_frame(id)? {
+                        stream.send_limit = stream.send_limit.max(max);
+                    }
+                }
+                0x12 => self.peer_max_streams = self.peer_max_streams.max(varint(d)?),
+                // MAX_STREAMS (unidirectional), DATA_BLOCKED, STREAMS_BLOCKED,
+                // RETIRE_CONNECTION_ID
+                0x13 | 0x14 | 0x16 | 0x17 | 0x19 => {
+                    varint(d)?;
+                }
+                // STREAM_DATA_BLOCKED
+                0x15 => {
+                    varint(d)?;
+                    varint(d)?;
+                }
+                // NEW_CONNECTION_ID, the current one is kept
+                0x18 => {
+                    varint(d)?;
+                    varint(d)?;
+                    let len = d.read_u8().map_err(|_| MALFORMED)?;
+                    d.read_slice(len as usize + 16).map_err(|_| MALFORMED)?;
+                }
+                0x1a => {
+                    let data = d.read_slice(8).map_err(|_| MALFORMED)?;
+                    self.spaces[APPLICATION_SPACE]
+                        .pending
+                        .push_back(Frame::PathResponse(data.try_into().unwrap()));
+                }
+                // PATH_RESPONSE
+                0x1b => {
+                    d.read_slice(8).map_err(|_| MALFORMED)?;
+                }
+                0x1c | 0x1d => {
+                    let code = varint(d)?;
+                    if kind == 0x1c {
+                        varint(d)?;
+                    }
+                    let len = varint(d)?;
+                    let reason = d.read_slice(len as usize).map_err(|_| MALFORMED)?;
+                    let reason = String::from_utf8_lossy(reason);
+                    self.closed = Some(match kind {
+                        0x1c => format!("peer closed the connection: error {:#x} {}", code, reason),
+                        _ => format!(
+                            "peer closed the connection: application error {:#x} {}",
+                            code, reason
+                        ),
+                    });
+                    return Ok(false);
+                }
+                0x1e if self.is_client => self.spaces[HANDSHAKE_SPACE].discard(),
+                0x1e => return Err((PROTOCOL_VIOLATION, "HANDSHAKE_DONE from a client")),
+                _ => return Err((FRAME_ENCODING_ERROR, "unknown frame type")),
+            }
+        }
+        Ok(ack_eliciting)
+    }
+
+    fn on_ack(
+        &mut self,
+        space: usize,
+        ranges: &[(u64, u64)],
+        now: Instant,
+    ) -> Result<(), Violation> {
+        let largest = ranges[0].1;
+        let sp = &mut self.spaces[space];
+        if largest >= sp.next_pn {
+            return Err((PROTOCOL_VIOLATION, "acknowledged an unsent packet"));
+        }
+        // an RTT sample when the largest is newly acknowledged (RFC 9002,
+        // section 5)
+        if let Some(packet) = sp.sent.get(&largest) {
+            let sample = now - packet.time;
+            if self.has_rtt_sample {
+                let diff = self.srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = (self.srtt * 7 + sample) / 8;
+            } else {
+                self.srtt = sample;
+                self.rttvar = sample / 2;
+                self.has_rtt_sample = true;
+            }
+        }
+        let acked: Vec<u64> = sp
+            .sent
+            .keys()
+            .filter(|pn| {
+                ranges
+                    .iter()
+                    .any(|(low, high)| (*low..=*high).contains(*pn))
+            })
+            .copied()
+            .collect();
+        for pn in &acked {
+            sp.sent.remove(pn);
+        }
+        if !acked.is_empty() {
+            self.pto_count = 0;
+        }
+        // lost: 3 packets or 9/8 RTT older than an acknowledged one
+        // (section 6.1)
+        let threshold = self.srtt.max(Duration::from_millis(1)) * 9 / 8;
+        let lost: Vec<u64> = sp
+            .sent
+            .iter()
+            .filter(|(pn, packet)| {
+                **pn + 3 <= largest || **pn < largest && now - packet.time > threshold
+            })
+            .map(|(pn, _)| *pn)
+            .collect();
+        sp.requeue(&lost);
+        Ok(())
+    }
+
+    /// The stream a frame is about, opening the streams the peer may open up
+    /// to it. None for streams already gone.
+    fn stream_for_frame(&mut self, id: u64) -> Result<Option<&mut Stream>, Violation> {
+        if id & 2 != 0 {
+            return Err((STREAM_LIMIT_ERROR, "unidirectional streams aren't allowed"));
+        }
+        let index = id >> 2;
+        if self.is_peer_stream(id) {
+            if index >= self.max_streams {
+                return Err((STREAM_LIMIT_ERROR, "too many streams"));
+            }
+            while self.peer_opened <= index {
+                let opened = self.peer_opened << 2 | (id & 1);
+                let stream = Stream {
+                    send_limit: self.peer_stream_data_local,
+                    ..Stream::default()
+                };
+                self.streams.insert(opened, stream);
+                self.peer_opened += 1;
+            }
+        } else if index >= self.opened {
+            return Err((STREAM_STATE_ERROR, "stream wasn't opened"));
+        }
+        Ok(self.streams.get_mut(&id))
+    }
+
+    fn on_stream(&mut self, id: u64, offset: u64, data: &[u8], fin: bool) -> Result<(), Violation> {
+        let Some(stream) = self.stream_for_frame(id)? else {
+            return Ok(());
+        };
+        let end = offset + data.len() as u64;
+        if end > STREAM_WINDOW {
+            return Err((FLOW_CONTROL_ERROR, "stream data past the limit"));
+        }
+        if stream
+            .final_size
+            .is_some_and(|size| end > size || fin && end != size)
+            || fin && end < stream.received
+        {
+            return Err((FINAL_SIZE_ERROR, "stream data past its end"));
+        }
+        if fin {
+            stream.final_size = Some(end);
+        }
+        let more = end.saturating_sub(stream.received);
+        stream.received = stream.received.max(end);
+        if stream.reset.is_none() && !stream.delivered {
+            stream.recv.insert(offset, data);
+        }
+        self.receive_data(more)
+    }
+
+    /// Counts `len` more bytes against the connection's flow control, moving
+    /// the window ahead once half of it is used.
+    fn receive_data(&mut self, len: u64) -> Result<(), Violation> {
+        self.data_received += len;
+        if self.data_received > self.max_data {
+            return Err((FLOW_CONTROL_ERROR, "data past the connection limit"));
+        }
+        if self.data_received + CONNECTION_WINDOW / 2 > self.max_data {
+            self.max_data = self.data_received + CONNECTION_WINDOW;
+            self.spaces[APPLICATION_SPACE]
+                .pending
+                .push_back(Frame::MaxData(self.max_data));
+        }
+        Ok(())
+    }
+
+    /// Turns stream data into frames, as far as flow control allows.
+    fn flush_streams(&mut self) {
+        let pending = &mut self.spaces[APPLICATION_SPACE].pending;
+        for (id, stream) in self.streams.iter_mut() {
+            if stream.send_done || stream.send.is_empty() && !stream.fin {
+                continue;
+            }
+            let credit =
+                (stream.send_limit - stream.send_offset).min(self.peer_max_data - self.data_sent);
+            let len = (credit as usize).min(stream.send.len());
+            let fin = stream.fin && len == stream.send.len();
+            if len == 0 && !fin {
+                continue;
+            }
+            pending.push_back(Frame::Stream {
+                id: *id,
+                offset: stream.send_offset,
+                data: stream.send.drain(..len).collect(),
+                fin,
+            });
+            stream.send_offset += len as u64;
+            self.data_sent += len as u64;
+            stream.send_done = fin;
+        }
+        self.collect();
+    }
+
+    /// Datagrams due to be sent.
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self.spaces[APPLICATION_SPACE].write.is_some() {
+            self.flush_streams();
+        }
+        let mut datagrams = Vec::new();
+        while datagrams.len() < MAX_BURST {
+            let Some(datagram) = self.build_datagram(now) else {
+                break;
+            };
+            self.bytes_sent += datagram.len();
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    fn build_datagram(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.closed.is_some()
+            || !self.validated && self.bytes_sent + MAX_DATAGRAM > 3 * self.bytes_received
+        {
+            return None;
+        }
+        if let Some(close) = self.close.take() {
+            return self.close_datagram(close);
+        }
+
+        let mut packets = Vec::new();
+        let mut room = MAX_DATAGRAM;
+        for space in 0..3 {
+            let sp = &self.spaces[space];
+            if sp.write.is_none() || !sp.ack_needed && sp.pending.is_empty() {
+                continue;
+            }
+            let overhead = self.header_len(space) + aes::TAG_LEN;
+            if room < overhead + 32 {
+                break;
+            }
+            let (payload, frames) = self.fill(space, room - overhead);
+            if !payload.is_empty() {
+                room -= overhead + payload.len();
+                packets.push((space, payload, frames));
+            }
+        }
+        // padding fills the last packet
+        if packets.iter().any(|(space, _, _)| *space == INITIAL_SPACE) {
+            let (_, payload, _) = packets.last_mut()?;
+            payload.resize(payload.len() + room, 0);
+        }
+        let mut datagram = Vec::new();
+        let mut sent_handshake = false;
+        for (space, payload, frames) in packets {
+            sent_handshake |= space == HANDSHAKE_SPACE;
+            datagram.extend(self.protect(space, &payload));
+            let sp = &mut self.spaces[space];
+            if !frames.is_empty() {
+                sp.sent.insert(sp.next_pn, SentPacket { time: now, frames });
+            }
+            sp.next_pn += 1;
+        }
+        // the client is done with Initial packets once it sends a Handshake
+        // one (RFC 9001, section 4.9.1)
+        if self.is_client && sent_handshake {
+            self.spaces[INITIAL_SPACE].discard();
+        }
+        (!datagram.is_empty()).then_some(datagram)
+    }
+
+    /// Frames for a packet of `space`, at most `room` bytes: an ACK first,
+    /// then what's pending. Returns the payload and the frames to resend if
+    /// it's lost.
+    fn fill(&mut self, space: usize, room: usize) -> (Vec<u8>, Vec<Frame>) {
+        let sp = &mut self.spaces[space];
+        let mut payload = Vec::new();
+        let mut frames = Vec::new();
+        if sp.ack_needed {
+            let ack = sp.ack_frame();
+            if ack.len() <= room {
+                payload.extend(ack);
+                sp.ack_needed = false;
+            }
+        }
+        while let Some(frame) = sp.pending.pop_front() {
+            let mut encoded = Vec::new();
+            frame.encode(&mut encoded);
+            let left = room - payload.len();
+            if encoded.len() <= left {
+                payload.extend(encoded);
+                frames.push(frame);
+                continue;
+            }
+            match frame.clone().split(left) {
+                Some((head, rest)) => {
+                    head.encode(&mut payload);
+                    frames.push(head);
+                    sp.pending.push_front(rest);
+                }
+                None => sp.pending.push_front(frame),
+            }
+            break;
+        }
+        (payload, frames)
+    }
+
+    /// A datagram with CONNECTION_CLOSE, at the highest level there are keys
+    /// for. The connection is closed after it.
+    fn close_datagram(&mut self, close: Close) -> Option<Vec<u8>> {
+        self.closed = Some(format!("connection closed: {}", close.reason));
+        let space = (0..3)
+            .rev()
+            .find(|space| self.spaces[*space].write.is_some())?;
+        let mut payload = Vec::new();
+        // application errors are hidden until the handshake is done
+        // (section 10.2.3)
+        if close.application && space == APPLICATION_SPACE {
+            payload.push(0x1d);
+            write_varint(&mut payload, close.code);
+        } else {
+            payload.push(0x1c);
+            let code = if close.application {
+                APPLICATION_ERROR
+            } else {
+                close.code
+            };
+            write_varint(&mut payload, code);
+            write_varint(&mut payload, 0);
+        }
+        write_varint(&mut payload, close.reason.len() as u64);
+        payload.extend(close.reason.as_bytes());
+        if space == INITIAL_SPACE {
+            let size = self.header_len(space) + aes::TAG_LEN + payload.len();
+            payload.resize(payload.len() + MAX_DATAGRAM.saturating_sub(size), 0);
+        }
+        let datagram = self.protect(space, &payload);
+        self.spaces[space].next_pn += 1;
+        Some(datagram)
+    }
+
+    fn header_len(&self, space: usize) -> usize {
+        match space {
+            APPLICATION_SPACE => 1 + self.dcid.len() + PN_LEN,
+            // version, connection IDs, a 2-byte length, and an empty token
+            // for Initial packets
+            _ => {
+                let token = (space == INITIAL_SPACE) as usize;
+                1 + 4 + 1 + self.dcid.len() + 1 + self.scid.len() + token + 2 + PN_LEN
+            }
+        }
+    }
+
+    /// Builds and protects the next packet of `space` (RFC 9001, section 5).
+    fn protect(&self, space: usize, payload: &[u8]) -> Vec<u8> {
+        let sp = &self.spaces[space];
+        let keys = sp.write.as_ref().unwrap();
+        let pn = sp.next_pn;
+        let mut packet = Vec::new();
+        if space == APPLICATION_SPACE {
+            packet.push(0x40 | (PN_LEN - 1) as u8);
+            packet.extend(&self.dcid);
+        } else {
+            let kind = if space == INITIAL_SPACE {
+                INITIAL
+            } else {
+                HANDSHAKE
+            };
+            packet.push(0xc0 | kind << 4 | (PN_LEN - 1) as u8);
+            packet.extend(VERSION.to_be_bytes());
+            packet.push(self.dcid.len() as u8);
+            packet.extend(&self.dcid);
+            packet.push(self.scid.len() as u8);
+            packet.extend(&self.scid);
+            if space == INITIAL_SPACE {
+                packet.push(0);
+            }
+            let len = PN_LEN + payload.len() + aes::TAG_LEN;
+            packet.extend((len as u16 | 0x4000).to_be_bytes());
+        }
+        let pn_offset = packet.len();
+        packet.extend((pn as u32).to_be_bytes());
+        let sealed = keys.aead.seal(&keys.nonce(pn), &packet, payload);
+        packet.extend(sealed);
+
+        let mask = keys.mask(&packet[pn_offset + 4..pn_offset + 20]);
+        packet[0] ^= mask[0]
+            & if space == APPLICATION_SPACE {
+                0x1f
+            } else {
+                0x0f
+            };
+        for i in 0..PN_LEN {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+        packet
+    }
+
+    /// The next handshake message received at `space`, when complete.
+    fn take_message(&mut self, space: usize) -> Result<Option<Vec<u8>>> {
+        let crypto = &mut self.spaces[space].crypto;
+        let Some(header) = crypto.data.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if len as u64 > MAX_CRYPTO_BUFFER {
+            bail!("handshake message too long");
+        }
+        match crypto.data.len() >= 4 + len {
+            true => Ok(Some(crypto.consume(4 + len))),
+            false => Ok(None),
+        }
+    }
+}
+
+/// Carries a TLS handshake in CRYPTO frames, exchanging datagrams over a
+/// link until `deadline`.
+struct Driver<'a, L> {
+    conn: &'a mut Connection,
+    link: &'a mut L,
+    deadline: Instant,
+    read_space: usize,
+    write_space: usize,
+}
+
+impl<'a, L: Link> Driver<'a, L> {
+    fn new(conn: &'a mut Connection, link: &'a mut L, deadline: Instant) -> Self {
+        Self {
+            conn,
+            link,
+            deadline,
+            read_space: INITIAL_SPACE,
+            write_space: INITIAL_SPACE,
+        }
+    }
+}
+
+impl<L: Link> Handshake for Driver<'_, L> {
+    fn send(&mut self, messages: &[u8]) -> Result<()> {
+        let space = &mut self.conn.spaces[self.write_space];
+        space.pending.push_back(Frame::Crypto {
+            offset: space.crypto_offset,
+            data: messages.to_vec(),
+        });
+        space.crypto_offset += messages.len() as u64;
+        Ok(())
+    }
+
+    fn expect(&mut self, kind: u8) -> Result<Vec<u8>> {
+        loop {
+            if let Some(message) = self.conn.take_message(self.read_space)? {
+                if message[0] != kind {
+                    bail!("expected handshake message {}, got {}", kind, message[0]);
+                }
+                return Ok(message);
+            }
+            self.conn.step(self.link, self.deadline)?;
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.conn.spaces[self.read_space].crypto.is_empty()
+    }
+
+    fn set_read_secret(&mut self, secret: Vec<u8>) -> Result<()> {
+        self.read_space += 1;
+        self.conn.spaces[self.read_space].read = Some(PacketKeys::new(&secret));
+        self.conn.receive_early(self.read_space, Instant::now());
+        Ok(())
+    }
+
+    fn set_write_secret(&mut self, secret: Vec<u8>) -> Result<()> {
+        self.write_space += 1;
+        self.conn.spaces[self.write_space].write = Some(PacketKeys::new(&secret));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode_pn, initial_secrets, parse_header, read_varint, write_varint, Connection, Frame,
+        Link, PacketKeys, Reassembly, Space, TransportParameters, CID_LEN, MAX_DATAGRAM,
+    };
+    use crate::{
+        encoder::Decoder,
+        tls::{test::identity_files, Identity},
+        x509,
+    };
+    use std::{
+        io,
+        sync::mpsc::{channel, Receiver, Sender},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_varint() {
+        for (value, encoded) in [
+            (37, "25"),
+            (15293, "7bbd"),
+            (494878333, "9d7f3e7d"),
+            (151288809941952652, "c2197c5eff14e88c"),
+        ] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(buf, hex(encoded));
+            assert_eq!(read_varint(&mut Decoder::new(&buf)).unwrap(), value);
+        }
+    }
+
+    // RFC 9001, appendix A
+    #[test]
+    fn test_initial_keys() {
+        let (client, server) = initial_secrets(&hex("8394c8f03e515708"));
+        assert_eq!(
+            client,
+            hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea")
+        );
+        assert_eq!(
+            server,
+            hex("3c199828fd139efd216c155ad844cc81fb82fa8d7446fa7d78be803acdda951b")
+        );
+        let keys = PacketKeys::new(&client);
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        let mask = keys.mask(&hex("d1b1c98dd7689fb8ec11d242b123dc9b"));
+        assert_eq!(mask[..5], hex("437b9aec36"));
+        let keys = PacketKeys::new(&server);
+        assert_eq!(keys.iv.to_vec(), hex("0ac1493ca1905853b0bba03e"));
+        let mask = keys.mask(&hex("2cd0991cd25b0aac406a5816b6394100"));
+        assert_eq!(mask[..5], hex("2ec0d8356a"));
+    }
+
+    // RFC 9000, appendix A.3
+    #[test]
+    fn test_decode_pn() {
+        assert_eq!(decode_pn(Some(0xa82f30ea), 0x9b32, 16), 0xa82f9b32);
+        assert_eq!(decode_pn(None, 0, 32), 0);
+        assert_eq!(decode_pn(Some(0xff), 0x01, 8), 0x101);
+    }
+
+    #[test]
+    fn test_transport_parameters() {
+        let params = TransportParameters {
+            original_dcid: Some(vec![1; 8]),
+            initial_scid: Some(vec![2; 8]),
+            idle_timeout: 30000,
+            max_data: 1 << 20,
+            stream_data_bidi_local: 0,
+            stream_data_bidi_remote: 1 << 17,
+            streams_bidi: 100,
+        };
+        assert_eq!(
+            TransportParameters::decode(&params.encode()).unwrap(),
+            params
+        );
+        // unknown parameters are skipped, malformed ones rejected
+        let mut raw = vec![0x40, 0x2a, 1, 0];
+        raw.extend(params.encode());
+        assert_eq!(TransportParameters::decode(&raw).unwrap(), params);
+        assert!(TransportParameters::decode(&[0x04, 2, 0x01, 0]).is_err());
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut buf = Reassembly::default();
+        buf.insert(6, b"world");
+        buf.insert(0, b"hel");
+        assert_eq!(buf.data, b"hel");
+        buf.insert(2, b"llo ");
+        assert_eq!(buf.data, b"hello world");
+        assert!(buf.segments.is_empty());
+        assert_eq!(buf.consume(6), b"hello ");
+        buf.insert(0, b"hello");
+        assert_eq!(buf.data, b"world");
+    }
+
+    #[test]
+    fn test_ack_ranges() {
+        let mut space = Space::default();
+        for pn in [0, 1, 2, 5, 7, 6, 9] {
+            assert!(space.record(pn));
+        }
+        assert!(!space.record(6));
+        assert_eq!(space.received, [(9, 9), (5, 7), (0, 2)]);
+        assert!(space.record(8));
+        assert_eq!(space.received, [(5, 9), (0, 2)]);
+        // largest 9, first range 4, one gap of 1 and a range of 2
+        assert_eq!(space.ack_frame(), [0x02, 9, 0, 1, 4, 1, 2]);
+    }
+
+    #[test]
+    fn test_split() {
+        let frame = Frame::Stream {
+            id: 4,
+            offset: 0,
+            data: vec![7; 100],
+            fin: true,
+        };
+        let (head, rest) = frame.split(20).unwrap();
+        let mut encoded = Vec::new();
+        head.encode(&mut encoded);
+        assert!(encoded.len() <= 20);
+        let Frame::Stream { data, fin, .. } = &head else {
+            panic!()
+        };
+        assert!(!fin);
+        assert_eq!(
+            rest,
+            Frame::Stream {
+                id: 4,
+                offset: data.len() as u64,
+                data: vec![7; 100 - data.len()],
+                fin: true,
+            }
+        );
+        assert!(Frame::Ping.split(20).is_none());
+    }
+
+    /// One end of an in-memory path that loses every `drop`th datagram.
+    struct Pipe {
+        sender: Sender<Vec<u8>>,
+        receiver: Receiver<Vec<u8>>,
+        drop: usize,
+        sent: usize,
+    }
+
+    impl Link for Pipe {
+        fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+            assert!(datagram.len() <= MAX_DATAGRAM);
+            self.sent += 1;
+            if !self.sent.is_multiple_of(self.drop) {
+                let _ = self.sender.send(datagram.to_vec());
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self, deadline: Instant) -> io::Result<Option<Vec<u8>>> {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            Ok(self.receiver.recv_timeout(timeout).ok())
+        }
+    }
+
+    fn pipes(drop: usize) -> (Pipe, Pipe) {
+        let (a, b) = (channel(), channel());
+        let pipe = |sender, receiver| Pipe {
+            sender,
+            receiver,
+            drop,
+            sent: 0,
+        };
+        (pipe(a.0, b.1), pipe(b.0, a.1))
+    }
+
+    #[test]
+    fn test_connection() {
+        let (cert, key) = identity_files("quic");
+        let identity = Identity::load(&cert, &key).unwrap();
+        let roots = x509::load_pem(&cert).unwrap();
+        let timeout = Duration::from_secs(10);
+        // every third datagram is lost both ways
+        let (mut client_link, mut server_link) = pipes(3);
+
+        let mut client = Connection::client();
+        let server = thread::spawn(move || {
+            let first = server_link.receiver.recv().unwrap();
+            assert_eq!(first.len(), MAX_DATAGRAM);
+            let header = parse_header(&first, CID_LEN).unwrap();
+            assert!(header.is_initial());
+            let mut conn = Connection::server(&header);
+            conn.accept(&mut server_link, &first, &identity, "doq", timeout)
+                .unwrap();
+            // echoes each stream back, reversed
+            let deadline = Instant::now() + timeout;
+            let mut echoed = 0;
+            while echoed < 2 {
+                for id in conn.incoming() {
+                    let mut data = conn.read_stream(id).unwrap().unwrap();
+                    data.reverse();
+                    conn.write_stream(id, &data, true);
+                    echoed += 1;
+                }
+                conn.step(&mut server_link, deadline).unwrap();
+            }
+            while conn.step(&mut server_link, deadline).is_ok() {}
+            conn.closed().map(str::to_string)
+        });
+
+        client
+            .connect(&mut client_link, "dns.example", &roots, &[], "doq", timeout)
+            .unwrap();
+        let deadline = Instant::now() + timeout;
+        let long: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let streams: Vec<(u64, Vec<u8>)> = [b"query".to_vec(), long]
+            .into_iter()
+            .map(|data| {
+                let id = client.open_stream().unwrap();
+                client.write_stream(id, &data, true);
+                (id, data)
+            })
+            .collect();
+        assert_eq!(streams[0].0, 0);
+        assert_eq!(streams[1].0, 4);
+        for (id, mut data) in streams {
+            let reply = loop {
+                if let Some(reply) = client.read_stream(id) {
+                    break reply.unwrap();
+                }
+                client.step(&mut client_link, deadline).unwrap();
+            };
+            data.reverse();
+            assert_eq!(reply, data);
+        }
+        client.close(0x2, "done");
+        client.flush(&mut client_link).unwrap();
+        assert!(client.closed().is_some());
+        let closed = server.join().unwrap().unwrap();
+        assert!(closed.contains("application error 0x2"), "{}", closed);
+    }
+}
@@ -0,0 +1,1373 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    encoder::{Decoder, Encoder, Error},
+    encoding::{base32hex_encode, base64_encode, hex_decode, hex_encode},
+    proto::{Name, Type},
+};
+
+/// Start of authority (RFC 1035, section 3.3.13).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Soa {
+    // primary name server for the zone
+    pub mname: Name,
+    // mailbox of the person responsible for the zone
+    pub rname: Name,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    // TTL for negative responses (RFC 2308)
+    pub minimum: u32,
+}
+
+impl Soa {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        self.mname.encode(enc)?;
+        self.rname.encode(enc)?;
+        enc.write_u32(self.serial);
+        enc.write_u32(self.refresh);
+        enc.write_u32(self.retry);
+        enc.write_u32(self.expire);
+        enc.write_u32(self.minimum);
+        Ok(())
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        Ok(Self {
+            mname: Name::decode(dec)?,
+            rname: Name::decode(dec)?,
+            serial: dec.read_u32()?,
+            refresh: dec.read_u32()?,
+            retry: dec.read_u32()?,
+            expire: dec.read_u32()?,
+            minimum: dec.read_u32()?,
+        })
+    }
+}
+
+/// Host information (RFC 1035, section 3.3.2), also the synthetic answer to
+/// ANY queries (RFC 8482, section 4.2).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Hinfo {
+    pub cpu: String,
+    pub os: String,
+}
+
+impl Hinfo {
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_character_string(&self.cpu);
+        enc.write_character_string(&self.os);
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        Ok(Self {
+            cpu: dec.read_character_string()?,
+            os: dec.read_character_string()?,
+        })
+    }
+}
+
+/// Mail exchange (RFC 1035, section 3.3.9).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Mx {
+    // lower values are preferred
+    pub preference: u16,
+    pub exchange: Name,
+}
+
+impl Mx {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_u16(self.preference);
+        self.exchange.encode(enc)
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        Ok(Self {
+            preference: dec.read_u16()?,
+            exchange: Name::decode(dec)?,
+        })
+    }
+}
+
+/// Server selection (RFC 2782). The target is never compressed.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Srv {
+    // lower values are preferred
+    pub priority: u16,
+    // relative share among targets of the same priority
+    pub weight: u16,
+    pub port: u16,
+    pub target: Name,
+}
+
+impl Srv {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_u16(self.priority);
+        enc.write_u16(self.weight);
+        enc.write_u16(self.port);
+        enc.write_uncompressed_name(&self.target)
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        Ok(Self {
+            priority: dec.read_u16()?,
+            weight: dec.read_u16()?,
+            port: dec.read_u16()?,
+            target: Name::decode(dec)?,
+        })
+    }
+}
+
+/// A single SvcParam, a key from the SvcParamKeys registry and its wire
+/// format value.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SvcParam {
+    pub key: u16,
+    pub value: Vec<u8>,
+}
+
+impl SvcParam {
+    pub const MANDATORY: u16 = 0;
+    pub const ALPN: u16 = 1;
+    pub const NO_DEFAULT_ALPN: u16 = 2;
+    pub const PORT: u16 = 3;
+    pub const IPV4HINT: u16 = 4;
+    pub const ECH: u16 = 5;
+    pub const IPV6HINT: u16 = 6;
+}
+
+/// Service binding, the RDATA of both SVCB and HTTPS (RFC 9460, section 2.2).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Svcb {
+    // 0 for AliasMode, ServiceMode otherwise
+    pub priority: u16,
+    pub target: Name,
+    // sorted by key, at most once each
+    pub params: Vec<SvcParam>,
+}
+
+impl Svcb {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_u16(self.priority);
+        enc.write_uncompressed_name(&self.target)?;
+        for param in self.params.iter() {
+            enc.write_u16(param.key);
+            enc.write_length_prefixed(&param.value)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes SVCB RDATA that ends at offset `end` of the message.
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        let mut svcb = Self {
+            priority: dec.read_u16()?,
+            target: Name::decode(dec)?,
+            params: Vec::new(),
+        };
+
+        while dec.offset() < end {
+            let key = dec.read_u16()?;
+            if svcb.params.last().is_some_and(|p| p.key >= key) {
+                return Err(Error::InvalidSvcParamOrder(key));
+            }
+            let len = dec.read_u16()?;
+            let value = dec.read_slice(len as usize)?.to_vec();
+            svcb.params.push(SvcParam { key, value });
+        }
+        Ok(svcb)
+    }
+}
+
+/// Reads the rest of the RDATA that ends at offset `end` of the message.
+fn read_to_end(dec: &mut Decoder, end: usize) -> Result<Vec<u8>, Error> {
+    let len = end
+        .checked_sub(dec.offset())
+        .ok_or(Error::ReadOutOfBounds {
+            offset: dec.offset(),
+            read_len: 0,
+            buf_len: end,
+        })?;
+    Ok(dec.read_slice(len)?.to_vec())
+}
+
+/// Writes the NSEC/NSEC3 type bit maps field (RFC 4034, section 4.1.2):
+/// one block per 256-type window holding a bitmap of the present types.
+pub fn encode_type_bitmap(types: &[Type], enc: &mut Encoder) {
+    let mut types: Vec<u16> = types.iter().map(|t| (*t).into()).collect();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        for t in window {
+            let bit = (t & 0xFF) as usize;
+            bitmap[bit / 8] |= 0x80 >> (bit % 8);
+        }
+        let len = (window[window.len() - 1] & 0xFF) as usize / 8 + 1;
+        enc.write_u8((window[0] >> 8) as u8);
+        enc.write_u8(len as u8);
+        enc.write_slice(&bitmap[..len]);
+    }
+}
+
+/// Reads type bit maps up to offset `end` of the message.
+pub fn decode_type_bitmap(dec: &mut Decoder, end: usize) -> Result<Vec<Type>, Error> {
+    let mut types = Vec::new();
+    while dec.offset() < end {
+        let window = dec.read_u8()? as u16;
+        let len = dec.read_u8()?;
+        if len == 0 || len > 32 {
+            return Err(Error::InvalidTypeBitmap(len));
+        }
+        for (i, byte) in dec.read_slice(len as usize)?.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((window << 8 | (i * 8 + bit) as u16).into());
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// DNS public key (RFC 4034, section 2.1).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Dnskey {
+    // bit 7 zone key, bit 15 secure entry point
+    pub flags: u16,
+    // always 3
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_u16(self.flags);
+        enc.write_u8(self.protocol);
+        enc.write_u8(self.algorithm);
+        enc.write_slice(&self.public_key);
+    }
+
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        Ok(Self {
+            flags: dec.read_u16()?,
+            protocol: dec.read_u8()?,
+            algorithm: dec.read_u8()?,
+            public_key: read_to_end(dec, end)?,
+        })
+    }
+}
+
+/// Delegation signer (RFC 4034, section 5.1).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_u16(self.key_tag);
+        enc.write_u8(self.algorithm);
+        enc.write_u8(self.digest_type);
+        enc.write_slice(&self.digest);
+    }
+
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        Ok(Self {
+            key_tag: dec.read_u16()?,
+            algorithm: dec.read_u8()?,
+            digest_type: dec.read_u8()?,
+            digest: read_to_end(dec, end)?,
+        })
+    }
+}
+
+/// RRset signature (RFC 4034, section 3.1). The signer name is never
+/// compressed.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Rrsig {
+    pub type_covered: Type,
+    pub algorithm: u8,
+    // number of labels in the original owner name, without wildcard
+    pub labels: u8,
+    pub original_ttl: u32,
+    // seconds since epoch, serial number arithmetic
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer_name: Name,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        self.type_covered.encode(enc);
+        enc.write_u8(self.algorithm);
+        enc.write_u8(self.labels);
+        enc.write_u32(self.original_ttl);
+        enc.write_u32(self.expiration);
+        enc.write_u32(self.inception);
+        enc.write_u16(self.key_tag);
+        enc.write_uncompressed_name(&self.signer_name)?;
+        enc.write_slice(&self.signature);
+        Ok(())
+    }
+
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        Ok(Self {
+            type_covered: Type::decode(dec)?,
+            algorithm: dec.read_u8()?,
+            labels: dec.read_u8()?,
+            original_ttl: dec.read_u32()?,
+            expiration: dec.read_u32()?,
+            inception: dec.read_u32()?,
+            key_tag: dec.read_u16()?,
+            signer_name: Name::decode(dec)?,
+            signature: read_to_end(dec, end)?,
+        })
+    }
+}
+
+/// Next secure record (RFC 4034, section 4.1). The next domain name is never
+/// compressed.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Nsec {
+    pub next_domain_name: Name,
+    pub types: Vec<Type>,
+}
+
+impl Nsec {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_uncompressed_name(&self.next_domain_name)?;
+        encode_type_bitmap(&self.types, enc);
+        Ok(())
+    }
+
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        Ok(Self {
+            next_domain_name: Name::decode(dec)?,
+            types: decode_type_bitmap(dec, end)?,
+        })
+    }
+}
+
+/// Hashed next secure record (RFC 5155, section 3.2).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    // bit 0 is opt-out
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed_owner: Vec<u8>,
+    pub types: Vec<Type>,
+}
+
+impl Nsec3 {
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_u8(self.hash_algorithm);
+        enc.write_u8(self.flags);
+        enc.write_u16(self.iterations);
+        enc.write_u8(self.salt.len() as u8);
+        enc.write_slice(&self.salt);
+        enc.write_u8(self.next_hashed_owner.len() as u8);
+        enc.write_slice(&self.next_hashed_owner);
+        encode_type_bitmap(&self.types, enc);
+    }
+
+    pub fn decode(dec: &mut Decoder, end: usize) -> Result<Self, Error> {
+        let hash_algorithm = dec.read_u8()?;
+        let flags = dec.read_u8()?;
+        let iterations = dec.read_u16()?;
+        let salt_len = dec.read_u8()?;
+        let salt = dec.read_slice(salt_len as usize)?.to_vec();
+        let hash_len = dec.read_u8()?;
+        let next_hashed_owner = dec.read_slice(hash_len as usize)?.to_vec();
+        Ok(Self {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next_hashed_owner,
+            types: decode_type_bitmap(dec, end)?,
+        })
+    }
+}
+
+/// NSEC3 parameters of a zone, at its apex (RFC 5155, section 4.2).
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Nsec3param {
+    pub hash_algorithm: u8,
+    // always zero
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+}
+
+impl Nsec3param {
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.write_u8(self.hash_algorithm);
+        enc.write_u8(self.flags);
+        enc.write_u16(self.iterations);
+        enc.write_u8(self.salt.len() as u8);
+        enc.write_slice(&self.salt);
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        let hash_algorithm = dec.read_u8()?;
+        let flags = dec.read_u8()?;
+        let iterations = dec.read_u16()?;
+        let salt_len = dec.read_u8()?;
+        Ok(Self {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt: dec.read_slice(salt_len as usize)?.to_vec(),
+        })
+    }
+}
+
+/// Transaction signature (RFC 8945, section 4.2). Only ever the last
+/// additional record of a message, the algorithm name is never compressed.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Tsig {
+    pub algorithm: Name,
+    // seconds since epoch, 48 bits on the wire
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl Tsig {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        enc.write_uncompressed_name(&self.algorithm)?;
+        enc.write_u16((self.time_signed >> 32) as u16);
+        enc.write_u32(self.time_signed as u32);
+        enc.write_u16(self.fudge);
+        enc.write_length_prefixed(&self.mac)?;
+        enc.write_u16(self.original_id);
+        enc.write_u16(self.error);
+        enc.write_length_prefixed(&self.other)
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<Self, Error> {
+        let algorithm = Name::decode(dec)?;
+        let time_signed = (dec.read_u16()? as u64) << 32 | dec.read_u32()? as u64;
+        let fudge = dec.read_u16()?;
+        let mac_len = dec.read_u16()?;
+        let mac = dec.read_slice(mac_len as usize)?.to_vec();
+        let original_id = dec.read_u16()?;
+        let error = dec.read_u16()?;
+        let other_len = dec.read_u16()?;
+        let other = dec.read_slice(other_len as usize)?.to_vec();
+        Ok(Self {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+}
+
+/// Typed RDATA of a resource record. Types without a dedicated variant are
+/// carried as raw wire bytes.
+#[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(Name),
+    CNAME(Name),
+    SOA(Soa),
+    PTR(Name),
+    HINFO(Hinfo),
+    MX(Mx),
+    // one or more <character-string>s
+    TXT(Vec<String>),
+    SRV(Srv),
+    DS(Ds),
+    RRSIG(Rrsig),
+    NSEC(Nsec),
+    DNSKEY(Dnskey),
+    NSEC3(Nsec3),
+    NSEC3PARAM(Nsec3param),
+    SVCB(Svcb),
+    HTTPS(Svcb),
+    // target whose addresses are served at the owner
+    ALIAS(Name),
+    TSIG(Tsig),
+    Unknown(Vec<u8>),
+}
+
+impl Default for RData {
+    fn default() -> Self {
+        Self::Unknown(Vec::new())
+    }
+}
+
+impl RData {
+    pub fn encode(&self, enc: &mut Encoder) -> Result<(), Error> {
+        match self {
+            Self::A(addr) => enc.write_slice(&addr.octets()),
+            Self::AAAA(addr) => enc.write_slice(&addr.octets()),
+            Self::NS(name) | Self::CNAME(name) => name.encode(enc)?,
+            Self::SOA(soa) => soa.encode(enc)?,
+            Self::PTR(name) => name.encode(enc)?,
+            Self::HINFO(hinfo) => hinfo.encode(enc),
+            Self::MX(mx) => mx.encode(enc)?,
+            Self::TXT(strings) => strings.iter().for_each(|s| enc.write_character_string(s)),
+            Self::SRV(srv) => srv.encode(enc)?,
+            Self::DS(ds) => ds.encode(enc),
+            Self::RRSIG(rrsig) => rrsig.encode(enc)?,
+            Self::NSEC(nsec) => nsec.encode(enc)?,
+            Self::DNSKEY(dnskey) => dnskey.encode(enc),
+            Self::NSEC3(nsec3) => nsec3.encode(enc),
+            Self::NSEC3PARAM(param) => param.encode(enc),
+            Self::SVCB(svcb) | Self::HTTPS(svcb) => svcb.encode(enc)?,
+            // not a well-known type, its names aren't compressed (RFC 3597)
+            Self::ALIAS(name) => enc.write_uncompressed_name(name)?,
+            Self::TSIG(tsig) => tsig.encode(enc)?,
+            Self::Unknown(data) => enc.write_slice(data),
+        }
+        Ok(())
+    }
+
+    /// Decodes `len` bytes of RDATA for a record of type `rtype`. The decoder
+    /// must be positioned inside the full message so compressed names can be
+    /// resolved.
+    pub fn decode(rtype: Type, len: usize, dec: &mut Decoder) -> Result<Self, Error> {
+        let invalid = || Error::InvalidRdata {
+            rtype: rtype.into(),
+            len,
+        };
+
+        let start = dec.offset();
+        let rdata = match rtype {
+            Type::A => {
+                let b: [u8; 4] = dec.read_slice(len)?.try_into().map_err(|_| invalid())?;
+                Self::A(b.into())
+            }
+            Type::AAAA => {
+                let b: [u8; 16] = dec.read_slice(len)?.try_into().map_err(|_| invalid())?;
+                Self::AAAA(b.into())
+            }
+            Type::NS => Self::NS(Name::decode(dec)?),
+            Type::CNAME => Self::CNAME(Name::decode(dec)?),
+            Type::SOA => Self::SOA(Soa::decode(dec)?),
+            Type::PTR => Self::PTR(Name::decode(dec)?),
+            Type::HINFO => Self::HINFO(Hinfo::decode(dec)?),
+            Type::MX => Self::MX(Mx::decode(dec)?),
+            Type::TXT => {
+                let mut rdata = Decoder::new(dec.read_slice(len)?);
+                let mut strings = Vec::new();
+                while rdata.offset() < len {
+                    strings.push(rdata.read_character_string()?);
+                }
+                Self::TXT(strings)
+            }
+            Type::SRV => Self::SRV(Srv::decode(dec)?),
+            Type::DS => Self::DS(Ds::decode(dec, start + len)?),
+            Type::RRSIG => Self::RRSIG(Rrsig::decode(dec, start + len)?),
+            Type::NSEC => Self::NSEC(Nsec::decode(dec, start + len)?),
+            Type::DNSKEY => Self::DNSKEY(Dnskey::decode(dec, start + len)?),
+            Type::NSEC3 => Self::NSEC3(Nsec3::decode(dec, start + len)?),
+            Type::NSEC3PARAM => Self::NSEC3PARAM(Nsec3param::decode(dec)?),
+            Type::SVCB => Self::SVCB(Svcb::decode(dec, start + len)?),
+            Type::HTTPS => Self::HTTPS(Svcb::decode(dec, start + len)?),
+            Type::ALIAS => Self::ALIAS(Name::decode(dec)?),
+            Type::TSIG => Self::TSIG(Tsig::decode(dec)?),
+            _ => Self::Unknown(dec.read_slice(len)?.to_vec()),
+        };
+
+        // names inside rdata are variable length, make sure they didn't run
+        // past (or stop short of) rdlength
+        if dec.offset() - start != len {
+            return Err(invalid());
+        }
+        Ok(rdata)
+    }
+
+    /// Parses the RFC 3597 generic presentation `\# <len> <hex>`, which is
+    /// valid for any type. Known types are decoded into their typed variant,
+    /// as long as the wire data doesn't contain compression pointers.
+    pub fn parse_generic(rtype: Type, s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidPresentation(format!("generic rdata {:?}", s));
+
+        let mut parts = s.trim().splitn(3, char::is_whitespace);
+        if parts.next() != Some("\\#") {
+            return Err(invalid());
+        }
+        let len: usize = parts
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(invalid)?;
+        let data = hex_decode(parts.next().unwrap_or("")).ok_or_else(invalid)?;
+        if data.len() != len {
+            return Err(invalid());
+        }
+
+        let mut dec = Decoder::new(&data);
+        Self::decode(rtype, len, &mut dec)
+    }
+}
+
+/// Quoted <character-string> with `"`, `\` and non-printable bytes escaped.
+fn quote(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in s {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(*b as char);
+            }
+            0x20..=0x7E => out.push(*b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Seconds since epoch as YYYYMMDDHHmmSS (RFC 4034, section 3.2).
+pub fn format_timestamp(ts: u32) -> String {
+    let days = (ts / 86400) as i64;
+    let secs = ts % 86400;
+
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Inverse of [`format_timestamp`]. A plain decimal number of seconds is
+/// accepted too (RFC 4034, section 3.2).
+pub fn parse_timestamp(s: &str) -> Option<u32> {
+    if s.len() != 14 {
+        return s.parse().ok();
+    }
+    // serial number arithmetic, later dates wrap around
+    parse_datetime(s).map(|secs| secs as u32)
+}
+
+/// Seconds since the epoch of a YYYYMMDDHHmmSS UTC date, without wrapping.
+pub fn parse_datetime(s: &str) -> Option<i64> {
+    if s.len() != 14 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, min, sec) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 59 {
+        return None;
+    }
+
+    // civil date to days, inverse of the conversion above
+    let y = year - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+fn format_types(types: &[Type]) -> String {
+    types
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = &self.value;
+        let list = |size: usize| v.len().is_multiple_of(size) && !v.is_empty();
+        match self.key {
+            Self::MANDATORY if list(2) => {
+                let keys: Vec<String> = v
+                    .chunks(2)
+                    .map(|k| svc_key_name(u16::from_be_bytes([k[0], k[1]])))
+                    .collect();
+                write!(f, "mandatory={}", keys.join(","))
+            }
+            Self::ALPN if !v.is_empty() => {
+                let mut dec = Decoder::new(v);
+                let mut ids = Vec::new();
+                while dec.offset() < v.len() {
+                    match dec.read_character_string() {
+                        Ok(id) => ids.push(id),
+                        Err(_) => return write!(f, "key1={}", quote(v)),
+                    }
+                }
+                write!(f, "alpn={}", ids.join(","))
+            }
+            Self::NO_DEFAULT_ALPN if v.is_empty() => f.write_str("no-default-alpn"),
+            Self::PORT if v.len() == 2 => write!(f, "port={}", u16::from_be_bytes([v[0], v[1]])),
+            Self::IPV4HINT if list(4) => {
+                let addrs: Vec<String> = v
+                    .chunks(4)
+                    .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]).to_string())
+                    .collect();
+                write!(f, "ipv4hint={}", addrs.join(","))
+            }
+            Self::ECH => write!(f, "ech={}", base64_encode(v)),
+            Self::IPV6HINT if list(16) => {
+                let addrs: Vec<String> = v
+                    .chunks(16)
+                    .map(|a| Ipv6Addr::from(<[u8; 16]>::try_from(a).unwrap()).to_string())
+                    .collect();
+                write!(f, "ipv6hint={}", addrs.join(","))
+            }
+            key if v.is_empty() => write!(f, "key{}", key),
+            key => write!(f, "key{}={}", key, quote(v)),
+        }
+    }
+}
+
+/// Presentation name of a SvcParamKey (RFC 9460, section 14.3.2).
+pub fn svc_key_name(key: u16) -> String {
+    match key {
+        SvcParam::MANDATORY => "mandatory".into(),
+        SvcParam::ALPN => "alpn".into(),
+        SvcParam::NO_DEFAULT_ALPN => "no-default-alpn".into(),
+        SvcParam::PORT => "port".into(),
+        SvcParam::IPV4HINT => "ipv4hint".into(),
+        SvcParam::ECH => "ech".into(),
+        SvcParam::IPV6HINT => "ipv6hint".into(),
+        key => format!("key{}", key),
+    }
+}
+
+/// Presentation format of the RDATA, as used in zone files. Unknown types
+/// use the generic `\# <len> <hex>` form (RFC 3597, section 5).
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(addr) => write!(f, "{}", addr),
+            Self::AAAA(addr) => write!(f, "{}", addr),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) | Self::ALIAS(name) => {
+                f.write_str(&name.to_fqdn())
+            }
+            Self::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname.to_fqdn(),
+                soa.rname.to_fqdn(),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            Self::HINFO(hinfo) => write!(
+                f,
+                "{} {}",
+                quote(hinfo.cpu.as_bytes()),
+                quote(hinfo.os.as_bytes())
+            ),
+            Self::MX(mx) => write!(f, "{} {}", mx.preference, mx.exchange.to_fqdn()),
+            Self::TXT(strings) => {
+                let quoted: Vec<String> = strings.iter().map(|s| quote(s.as_bytes())).collect();
+                f.write_str(&quoted.join(" "))
+            }
+            Self::SRV(srv) => write!(
+                f,
+                "{} {} {} {}",
+                srv.priority,
+                srv.weight,
+                srv.port,
+                srv.target.to_fqdn()
+            ),
+            Self::DS(ds) => write!(
+                f,
+                "{} {} {} {}",
+                ds.key_tag,
+                ds.algorithm,
+                ds.digest_type,
+                hex_encode(&ds.digest).to_ascii_uppercase()
+            ),
+            Self::RRSIG(sig) => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                sig.type_covered,
+                sig.algorithm,
+                sig.labels,
+                sig.original_ttl,
+                format_timestamp(sig.expiration),
+                format_timestamp(sig.inception),
+                sig.key_tag,
+                sig.signer_name.to_fqdn(),
+                base64_encode(&sig.signature)
+            ),
+            Self::NSEC(nsec) => write!(
+                f,
+                "{} {}",
+                nsec.next_domain_name.to_fqdn(),
+                format_types(&nsec.types)
+            ),
+            Self::DNSKEY(key) => write!(
+                f,
+                "{} {} {} {}",
+                key.flags,
+                key.protocol,
+                key.algorithm,
+                base64_encode(&key.public_key)
+            ),
+            Self::NSEC3(nsec3) => write!(
+                f,
+                "{} {} {} {} {} {}",
+                nsec3.hash_algorithm,
+                nsec3.flags,
+                nsec3.iterations,
+                match nsec3.salt.is_empty() {
+                    true => "-".into(),
+                    false => hex_encode(&nsec3.salt).to_ascii_uppercase(),
+                },
+                base32hex_encode(&nsec3.next_hashed_owner),
+                format_types(&nsec3.types)
+            ),
+            Self::NSEC3PARAM(param) => write!(
+                f,
+                "{} {} {} {}",
+                param.hash_algorithm,
+                param.flags,
+                param.iterations,
+                match param.salt.is_empty() {
+                    true => "-".into(),
+                    false => hex_encode(&param.salt).to_ascii_uppercase(),
+                }
+            ),
+            Self::SVCB(svcb) | Self::HTTPS(svcb) => {
+                write!(f, "{} {}", svcb.priority, svcb.target.to_fqdn())?;
+                for param in svcb.params.iter() {
+                    write!(f, " {}", param)?;
+                }
+                Ok(())
+            }
+            Self::TSIG(tsig) => write!(
+                f,
+                "{} {} {} {} {} {} {} {}",
+                tsig.algorithm.to_fqdn(),
+                tsig.time_signed,
+                tsig.fudge,
+                tsig.mac.len(),
+                base64_encode(&tsig.mac),
+                tsig.original_id,
+                tsig.error,
+                tsig.other.len()
+            ),
+            Self::Unknown(data) if data.is_empty() => f.write_str("\\# 0"),
+            Self::Unknown(data) => write!(f, "\\# {} {}", data.len(), hex_encode(data)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode_type_bitmap, encode_type_bitmap, format_timestamp, parse_timestamp, Decoder, Dnskey,
+        Ds, Encoder, Error, Hinfo, Mx, Name, Nsec, Nsec3, Nsec3param, RData, Rrsig, Soa, Srv,
+        SvcParam, Svcb, Type,
+    };
+    use crate::proto::{Class, Message, Record};
+
+    #[test]
+    fn test_aaaa_encode_decode() {
+        let rdata = RData::AAAA("2001:db8::1".parse().unwrap());
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+        assert_eq!(16, buf.len());
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(rdata), RData::decode(Type::AAAA, 16, &mut dec));
+    }
+
+    #[test]
+    fn test_a_invalid_length() {
+        let buf = vec![8u8; 5];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Err(Error::InvalidRdata { rtype: 1, len: 5 }),
+            RData::decode(Type::A, 5, &mut dec)
+        );
+    }
+
+    #[test]
+    fn test_soa_encode_decode() {
+        let msg = Message {
+            authorities: vec![Record {
+                name: Name::from("codecrafters.io"),
+                rtype: Type::SOA,
+                class: Class::IN,
+                ttl: 300,
+                rdata: RData::SOA(Soa {
+                    mname: Name::from("ns1.codecrafters.io"),
+                    rname: Name::from("hostmaster.codecrafters.io"),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 900,
+                    expire: 1209600,
+                    minimum: 300,
+                }),
+            }],
+            ..Message::default()
+        };
+
+        let buf = msg.to_bytes().unwrap();
+        // header (12) + owner (17) + type/class/ttl/rdlength (10) + "ns1" label
+        // (4) and a pointer back to the owner name
+        assert_eq!(&buf[43..45], &[0xC0, 12]);
+        assert_eq!(Ok(msg), Message::from_bytes(&buf));
+    }
+
+    #[test]
+    fn test_soa_rdlength_mismatch() {
+        #[rustfmt::skip]
+        let buf = vec![
+            0, 0,
+            0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5,
+        ];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Err(Error::InvalidRdata { rtype: 6, len: 21 }),
+            RData::decode(Type::SOA, 21, &mut dec)
+        );
+    }
+
+    #[test]
+    fn test_txt_encode_decode() {
+        let rdata = RData::TXT(vec!["v=spf1".into(), "include:_spf.codecrafters.io".into()]);
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+        assert_eq!(6, buf[0]);
+        assert_eq!(28, buf[7]);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(rdata), RData::decode(Type::TXT, buf.len(), &mut dec));
+    }
+
+    #[test]
+    fn test_hinfo_encode_decode() {
+        let rdata = RData::HINFO(Hinfo {
+            cpu: "RFC8482".into(),
+            os: "".into(),
+        });
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+        assert_eq!(b"\x07RFC8482\x00".to_vec(), buf);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Ok(rdata.clone()),
+            RData::decode(Type::HINFO, buf.len(), &mut dec)
+        );
+        assert_eq!("\"RFC8482\" \"\"", rdata.to_string());
+    }
+
+    #[test]
+    fn test_srv_encode_decode() {
+        let rdata = RData::SRV(Srv {
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            target: Name::from("sip.example.com"),
+        });
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+        assert_eq!(6 + 17, buf.len());
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Ok(rdata.clone()),
+            RData::decode(Type::SRV, buf.len(), &mut dec)
+        );
+        assert_eq!("10 60 5060 sip.example.com.", rdata.to_string());
+    }
+
+    #[test]
+    fn test_txt_overrun() {
+        // character-string claims more bytes than the rdata holds
+        let buf = vec![5, b'a', b'b', 0, 0, 0];
+        let mut dec = Decoder::new(&buf);
+        assert!(RData::decode(Type::TXT, 3, &mut dec).is_err());
+    }
+
+    #[test]
+    fn test_mx_reencoded_against_own_message() {
+        // upstream reply where the exchange is compressed against the question
+        #[rustfmt::skip]
+        let upstream = vec![
+            0, 1, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0,
+            12, b'c', b'o', b'd', b'e', b'c', b'r', b'a', b'f', b't', b'e', b'r', b's',
+            2, b'i', b'o', 0, 0, 15, 0, 1,
+            0xC0, 12, 0, 15, 0, 1, 0, 0, 0, 60, 0, 7,
+            0, 10, 2, b'm', b'x', 0xC0, 12,
+        ];
+        let msg = Message::from_bytes(&upstream).unwrap();
+        let mx = Mx {
+            preference: 10,
+            exchange: Name::from("mx.codecrafters.io"),
+        };
+        assert_eq!(RData::MX(mx.clone()), msg.answers[0].rdata);
+
+        // the same answer in a message without the question must not carry
+        // the upstream's pointer
+        let reply = Message {
+            answers: vec![Record {
+                name: Name::from("codecrafters.io"),
+                rtype: Type::MX,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::MX(mx),
+            }],
+            ..Message::default()
+        };
+        let buf = reply.to_bytes().unwrap();
+        assert_eq!(Ok(reply), Message::from_bytes(&buf));
+    }
+
+    #[test]
+    fn test_mx_rdlength_mismatch() {
+        let buf = vec![0, 10, 2, b'm', b'x', 0, 0xFF];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Err(Error::InvalidRdata { rtype: 15, len: 7 }),
+            RData::decode(Type::MX, 7, &mut dec)
+        );
+    }
+
+    #[test]
+    fn test_https_encode_decode() {
+        let rdata = RData::HTTPS(Svcb {
+            priority: 1,
+            target: Name::root(),
+            params: vec![
+                SvcParam {
+                    key: SvcParam::ALPN,
+                    value: b"\x02h2\x02h3".to_vec(),
+                },
+                SvcParam {
+                    key: SvcParam::PORT,
+                    value: vec![0x01, 0xBB],
+                },
+            ],
+        });
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+
+        #[rustfmt::skip]
+        let expect = vec![
+            0, 1, 0,
+            0, 1, 0, 6, 2, b'h', b'2', 2, b'h', b'3',
+            0, 3, 0, 2, 0x01, 0xBB,
+        ];
+        assert_eq!(expect, buf);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(rdata), RData::decode(Type::HTTPS, buf.len(), &mut dec));
+    }
+
+    #[test]
+    fn test_svcb_target_not_compressed() {
+        let svcb = RData::SVCB(Svcb {
+            priority: 1,
+            target: Name::from("svc.codecrafters.io"),
+            params: Vec::new(),
+        });
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_name(&Name::from("svc.codecrafters.io")).unwrap();
+        svcb.encode(&mut enc).unwrap();
+        // 21 bytes of name, then priority and the full name again
+        assert_eq!(&buf[21..23], &[0, 1]);
+        assert_eq!(&buf[..21], &buf[23..]);
+    }
+
+    #[test]
+    fn test_alias_encode_decode() {
+        let alias = RData::ALIAS(Name::from("lb.codecrafters.io"));
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        enc.write_name(&Name::from("lb.codecrafters.io")).unwrap();
+        alias.encode(&mut enc).unwrap();
+        // not compressed against the name before it
+        assert_eq!(&buf[..20], &buf[20..]);
+
+        let mut dec = Decoder::new(&buf);
+        dec.read_slice(20).unwrap();
+        assert_eq!(alias, RData::decode(Type::ALIAS, 20, &mut dec).unwrap());
+        assert_eq!("lb.codecrafters.io.", alias.to_string());
+        assert_eq!(Type::ALIAS, "alias".parse().unwrap());
+        assert_eq!(65401, u16::from(Type::ALIAS));
+    }
+
+    #[test]
+    fn test_svcb_params_out_of_order() {
+        let buf = vec![0, 1, 0, 0, 3, 0, 0, 0, 1, 0, 0];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Err(Error::InvalidSvcParamOrder(1)),
+            RData::decode(Type::SVCB, buf.len(), &mut dec)
+        );
+    }
+
+    fn roundtrip(rdata: RData, rtype: Type) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        rdata.encode(&mut enc).unwrap();
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(rdata), RData::decode(rtype, buf.len(), &mut dec));
+        buf
+    }
+
+    #[test]
+    fn test_type_bitmap() {
+        // RFC 4034, section 4.3
+        let types = vec![
+            Type::A,
+            Type::MX,
+            Type::RRSIG,
+            Type::NSEC,
+            Type::UNKNOWN(1234),
+        ];
+        let mut buf = Vec::new();
+        let mut enc = Encoder::new(&mut buf);
+        encode_type_bitmap(&types, &mut enc);
+
+        let mut expect = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03];
+        expect.extend_from_slice(&[0x04, 0x1b]);
+        expect.extend_from_slice(&[0u8; 26]);
+        expect.push(0x20);
+        assert_eq!(expect, buf);
+
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(Ok(types), decode_type_bitmap(&mut dec, buf.len()));
+    }
+
+    #[test]
+    fn test_type_bitmap_invalid_length() {
+        let buf = vec![0, 0];
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(
+            Err(Error::InvalidTypeBitmap(0)),
+            decode_type_bitmap(&mut dec, buf.len())
+        );
+    }
+
+    #[test]
+    fn test_dnssec_encode_decode() {
+        roundtrip(
+            RData::DNSKEY(Dnskey {
+                flags: 257,
+                protocol: 3,
+                algorithm: 13,
+                public_key: vec![0xAB; 64],
+            }),
+            Type::DNSKEY,
+        );
+        roundtrip(
+            RData::DS(Ds {
+                key_tag: 20326,
+                algorithm: 8,
+                digest_type: 2,
+                digest: vec![0xE0; 32],
+            }),
+            Type::DS,
+        );
+        roundtrip(
+            RData::NSEC3(Nsec3 {
+                hash_algorithm: 1,
+                flags: 1,
+                iterations: 0,
+                salt: vec![0xAA, 0xBB],
+                next_hashed_owner: vec![0x11; 20],
+                types: vec![Type::A, Type::RRSIG],
+            }),
+            Type::NSEC3,
+        );
+        roundtrip(
+            RData::NSEC3PARAM(Nsec3param {
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 10,
+                salt: vec![0xAA, 0xBB],
+            }),
+            Type::NSEC3PARAM,
+        );
+    }
+
+    #[test]
+    fn test_rrsig_signer_not_compressed() {
+        let rrsig = RData::RRSIG(Rrsig {
+            type_covered: Type::A,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: 300,
+            expiration: 1700000000,
+            inception: 1690000000,
+            key_tag: 12345,
+            signer_name: Name::from("codecrafters.io"),
+            signature: vec![0x55; 64],
+        });
+        let buf = roundtrip(rrsig.clone(), Type::RRSIG);
+
+        let mut msg_buf = Vec::new();
+        let mut enc = Encoder::new(&mut msg_buf);
+        enc.write_name(&Name::from("codecrafters.io")).unwrap();
+        rrsig.encode(&mut enc).unwrap();
+        assert_eq!(&msg_buf[17..], &buf[..]);
+    }
+
+    #[test]
+    fn test_nsec_encode_decode() {
+        let buf = roundtrip(
+            RData::NSEC(Nsec {
+                next_domain_name: Name::from("host.codecrafters.io"),
+                types: vec![Type::A, Type::AAAA, Type::RRSIG, Type::NSEC],
+            }),
+            Type::NSEC,
+        );
+        assert_eq!(22 + 2 + 6, buf.len());
+    }
+
+    #[test]
+    fn test_unknown_generic_presentation() {
+        let rdata = RData::Unknown(vec![0x0A, 0, 0, 1]);
+        assert_eq!(r"\# 4 0a000001", rdata.to_string());
+        assert_eq!(
+            Ok(rdata),
+            RData::parse_generic(Type::UNKNOWN(731), r"\# 4 0A 00 00 01")
+        );
+        assert_eq!(r"\# 0", RData::Unknown(vec![]).to_string());
+        assert_eq!(
+            Ok(RData::Unknown(vec![])),
+            RData::parse_generic(Type::UNKNOWN(731), r"\# 0")
+        );
+    }
+
+    #[test]
+    fn test_generic_presentation_of_known_type() {
+        assert_eq!(
+            Ok(RData::A("10.0.0.1".parse().unwrap())),
+            RData::parse_generic(Type::A, r"\# 4 0a000001")
+        );
+        assert!(RData::parse_generic(Type::A, r"\# 3 0a000001").is_err());
+        assert!(RData::parse_generic(Type::A, "10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_unknown_not_recompressed() {
+        // a name-shaped blob in an unknown type stays byte-for-byte intact
+        let blob = vec![3, b'w', b'w', b'w', 0xC0, 12];
+        let msg = Message {
+            answers: vec![Record {
+                name: Name::from("codecrafters.io"),
+                rtype: Type::UNKNOWN(65280),
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::Unknown(blob.clone()),
+            }],
+            ..Message::default()
+        };
+        let buf = msg.to_bytes().unwrap();
+        assert_eq!(&buf[buf.len() - blob.len()..], &blob[..]);
+        assert_eq!(Ok(msg), Message::from_bytes(&buf));
+    }
+
+    #[test]
+    fn test_presentation_format() {
+        let cases = [
+            (
+                RData::MX(Mx {
+                    preference: 10,
+                    exchange: Name::from("mx.codecrafters.io"),
+                }),
+                "10 mx.codecrafters.io.",
+            ),
+            (
+                RData::TXT(vec!["v=spf1 -all".into(), "say \"hi\"".into()]),
+                r#""v=spf1 -all" "say \"hi\"""#,
+            ),
+            (
+                RData::HTTPS(Svcb {
+                    priority: 1,
+                    target: Name::root(),
+                    params: vec![
+                        SvcParam {
+                            key: SvcParam::ALPN,
+                            value: b"\x02h2\x02h3".to_vec(),
+                        },
+                        SvcParam {
+                            key: SvcParam::IPV4HINT,
+                            value: vec![192, 0, 2, 1],
+                        },
+                    ],
+                }),
+                "1 . alpn=h2,h3 ipv4hint=192.0.2.1",
+            ),
+            (
+                RData::NSEC(Nsec {
+                    next_domain_name: Name::from("host.codecrafters.io"),
+                    types: vec![Type::A, Type::RRSIG, Type::UNKNOWN(1234)],
+                }),
+                "host.codecrafters.io. A RRSIG TYPE1234",
+            ),
+        ];
+        for (rdata, expect) in cases {
+            assert_eq!(expect, rdata.to_string());
+        }
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!("19700101000000", format_timestamp(0));
+        assert_eq!("20231114221320", format_timestamp(1700000000));
+        assert_eq!("21060207062815", format_timestamp(u32::MAX));
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(Some(0), parse_timestamp("19700101000000"));
+        assert_eq!(Some(1700000000), parse_timestamp("20231114221320"));
+        assert_eq!(Some(u32::MAX), parse_timestamp("21060207062815"));
+        assert_eq!(Some(1700000000), parse_timestamp("1700000000"));
+        assert_eq!(None, parse_timestamp("20231314221320"));
+        assert_eq!(None, parse_timestamp("soon"));
+    }
+}
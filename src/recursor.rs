@@ -0,0 +1,1189 @@
+//! Iterative resolution (RFC 1034, section 5.3.3): each question starts at
+//! the root servers and follows referrals down to the zone that answers it,
+//! no upstream resolver involved. Only records within the zone of the
+//! server that sent them are believed. Delegations, glue and answers are
+//! cached by RRset for their TTL, so later questions start from the closest
+//! zone already known.
+//!
+//! Servers are only told as much of the name as they need (RFC 9156): one
+//! label more than the zone they serve, asked for A records, until a
+//! referral or the full name. A server that fails such a query or denies the
+//! name exists, which some do for empty non-terminals, is asked the full
+//! name instead.
+//!
+//! The root servers come from hints, built in or loaded from a file, which
+//! only serve to ask one of them for the current root NS set (RFC 8109).
+//! That priming is repeated before the set expires.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rand::seq::SliceRandom;
+
+use crate::{
+    edns::Opt,
+    proto::{rcode, unresolved_cname, Class, Message, Name, Question, Record, Type},
+    rdata::RData,
+    resolver::Resolver,
+    upstream::Upstream,
+    zone::parse_records,
+};
+
+/// Built-in root hints, the IPv4 addresses of the root servers a to m.
+pub const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Referrals followed for a single name before giving up.
+const MAX_REFERRALS: usize = 16;
+
+/// Lookups nested for CNAME targets and the addresses of name servers
+/// without glue.
+const MAX_DEPTH: usize = 8;
+
+/// Links of a CNAME chain followed.
+const MAX_CNAME_CHASE: usize = 8;
+
+/// Minimized queries sent for a single name, the full name is sent after.
+const MAX_MINIMIZED: usize = 10;
+
+/// Servers of a zone tried before giving up on it.
+const MAX_ATTEMPTS: usize = 4;
+
+/// RRsets cached at most, expired ones are dropped when it fills up.
+const MAX_RRSETS: usize = 65536;
+
+/// Longest an RRset is cached, whatever its TTL.
+const MAX_TTL: u32 = 86400;
+
+/// How long to wait before priming again after it failed, in seconds.
+const PRIME_RETRY: u64 = 60;
+
+/// Identifies a cached RRset: owner name, in any case, and type, the type
+/// an RRSIG covers for signatures.
+type RrsetKey = (Name, Type);
+
+fn rrset_key(name: &Name, rtype: Type) -> RrsetKey {
+    (name.clone(), rtype)
+}
+
+/// Records of an RRset, when they were cached and for how long.
+type Entry = (Vec<Record>, Instant, u32);
+
+/// Cached RRsets, each with its signatures.
+#[derive(Default)]
+struct Rrsets {
+    entries: Mutex<HashMap<RrsetKey, Entry>>,
+}
+
+impl Rrsets {
+    /// Records of `name` and `rtype` still fresh, their TTLs counted down.
+    fn get(&self, name: &Name, rtype: Type) -> Option<Vec<Record>> {
+        let entries = self.entries.lock().unwrap();
+        let (records, cached, ttl) = entries.get(&rrset_key(name, rtype))?;
+        let left = ttl
+            .checked_sub(cached.elapsed().as_secs() as u32)
+            .filter(|left| *left > 0)?;
+        Some(
+            records
+                .iter()
+                .map(|record| Record {
+                    ttl: left,
+                    ..record.clone()
+                })
+                .collect(),
+        )
+    }
+
+    /// Caches `records` by RRset, each for the lowest TTL in it.
+    fn insert(&self, records: &[Record]) {
+        let mut rrsets: HashMap<RrsetKey, Vec<Record>> = HashMap::new();
+        for record in records {
+            let rtype = match &record.rdata {
+                RData::RRSIG(rrsig) => rrsig.type_covered,
+                _ => record.rtype,
+            };
+            rrsets
+                .entry(rrset_key(&record.name, rtype))
+                .or_default()
+                .push(record.clone());
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() + rrsets.len() > MAX_RRSETS {
+            entries.retain(|_, (_, cached, ttl)| cached.elapsed().as_secs() < *ttl as u64);
+            if entries.len() + rrsets.len() > MAX_RRSETS {
+                return;
+            }
+        }
+        let now = Instant::now();
+        for (key, records) in rrsets {
+            let ttl = records.iter().map(|r| r.ttl).min().unwrap_or(0);
+            entries.insert(key, (records, now, ttl.min(MAX_TTL)));
+        }
+    }
+}
+
+/// Servers of a zone: their names, and the addresses of those known.
+#[derive(Debug, Clone, PartialEq)]
+struct Delegation {
+    zone: Name,
+    names: Vec<Name>,
+    addrs: Vec<SocketAddr>,
+}
+
+/// What a server's reply tells about a question.
+enum Step {
+    // the final word on the name: its records, NODATA or NXDOMAIN
+    Answer(Resolution),
+    // servers closer to the name
+    Referral(Delegation),
+}
+
+/// Outcome of resolving a question.
+#[derive(Debug, Clone, PartialEq)]
+struct Resolution {
+    rcode: u8,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+}
+
+/// Resolves requests itself, iterating from the root servers.
+pub struct Recursor {
+    upstream: Upstream,
+    rrsets: Rrsets,
+    roots: Vec<SocketAddr>,
+    // port servers are queried on, 53 but in tests
+    port: u16,
+    // how long each query may take
+    timeout: Duration,
+}
+
+impl Recursor {
+    pub fn new(timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            upstream: Upstream::bind()?,
+            rrsets: Rrsets::default(),
+            roots: ROOT_SERVERS
+                .iter()
+                .map(|ip| SocketAddr::new((*ip).into(), 53))
+                .collect(),
+            port: 53,
+            timeout,
+        })
+    }
+
+    /// Primes from the root servers of `hints` instead of the built-in ones.
+    pub fn with_hints(self, hints: Vec<Ipv4Addr>) -> Self {
+        Self {
+            roots: hints
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip.into(), self.port))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// Primes the root NS set now and again whenever it's about to expire,
+    /// in the background. Stops once the recursor is dropped.
+    pub fn prime_periodically(recursor: &Arc<Self>) {
+        let weak = Arc::downgrade(recursor);
+        thread::spawn(move || prime_loop(weak));
+    }
+
+    /// Asks a root server of the hints for the root NS set and caches it
+    /// with the addresses of the servers, returning its TTL.
+    fn prime(&self) -> Result<u32> {
+        let question = Question {
+            name: Name::root(),
+            qtype: Type::NS,
+            class: Class::IN,
+        };
+        let request = Message {
+            questions: vec![question.clone()],
+            opt: Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let mut roots = self.roots.clone();
+        roots.shuffle(&mut rand::thread_rng());
+        for addr in roots.into_iter().take(MAX_ATTEMPTS) {
+            let reply = match self.exchange(addr, &request) {
+                Ok(reply) if reply.rcode == rcode::NOERROR => reply,
+                Ok(reply) => {
+                    tracing::warn!(
+                        server = %addr, outcome = %rcode::name(reply.rcode),
+                        "Root server failed priming"
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(server = %addr, error = %e, "Root server failed priming");
+                    continue;
+                }
+            };
+            let ns: Vec<Record> = reply
+                .answers
+                .into_iter()
+                .filter(|record| record.name == question.name && record.rtype == Type::NS)
+                .collect();
+            let names: Vec<&Name> = ns
+                .iter()
+                .filter_map(|record| match &record.rdata {
+                    RData::NS(name) => Some(name),
+                    _ => None,
+                })
+                .collect();
+            let glue: Vec<Record> = reply
+                .additionals
+                .iter()
+                .filter(|record| {
+                    record.rtype == Type::A && names.iter().any(|name| **name == record.name)
+                })
+                .cloned()
+                .collect();
+            if glue.is_empty() {
+                tracing::warn!(server = %addr, "Root server gave no root server addresses");
+                continue;
+            }
+            let ttl = ns.iter().map(|record| record.ttl).min().unwrap_or(0);
+            self.rrsets.insert(&ns);
+            self.rrsets.insert(&glue);
+            tracing::info!(servers = names.len(), server = %addr, "Primed root servers");
+            return Ok(ttl.min(MAX_TTL));
+        }
+        bail!("no root server answered the priming query")
+    }
+}
+
+fn prime_loop(recursor: Weak<Recursor>) {
+    loop {
+        let Some(strong) = recursor.upgrade() else {
+            return;
+        };
+        // again once 90% of the TTL has passed
+        let wait = match strong.prime() {
+            Ok(ttl) => (ttl as u64 * 9 / 10).max(PRIME_RETRY),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to prime the root servers");
+                PRIME_RETRY
+            }
+        };
+        drop(strong);
+        thread::sleep(Duration::from_secs(wait));
+    }
+}
+
+/// Root hints in master file format, as published by IANA: the addresses of
+/// the servers the root NS records name. Only IPv4 ones are used.
+pub fn parse_hints(text: &str) -> Result<Vec<Ipv4Addr>, String> {
+    let records = parse_records(text, None, None).map_err(|e| e.to_string())?;
+    let names: Vec<&Name> = records
+        .iter()
+        .filter_map(|record| match &record.rdata {
+            RData::NS(name) if record.name.is_root() => Some(name),
+            _ => None,
+        })
+        .collect();
+    let hints: Vec<Ipv4Addr> = records
+        .iter()
+        .filter_map(|record| match record.rdata {
+            RData::A(ip) if names.iter().any(|name| **name == record.name) => Some(ip),
+            _ => None,
+        })
+        .collect();
+    if hints.is_empty() {
+        return Err("no IPv4 address of a root server".into());
+    }
+    Ok(hints)
+}
+
+/// Reads a root hints file, see `parse_hints`.
+pub fn load_hints(path: &Path) -> Result<Vec<Ipv4Addr>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read root hints from {}", path.display()))?;
+    parse_hints(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+impl Resolver for Recursor {
+    fn resolve(&self, request: &Message) -> Result<Message> {
+        let mut reply = Message {
+            ra: 1,
+            rcode: if request.opcode == 0 {
+                rcode::NOERROR
+            } else {
+                rcode::NOTIMP
+            },
+            ..request.reply()
+        };
+        if request.opcode != 0 {
+            return Ok(reply);
+        }
+        let dnssec_ok = request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok);
+        for question in request.questions.iter() {
+            let resolution = match self.lookup(question, dnssec_ok, 0) {
+                Ok(resolution) => resolution,
+                Err(e) => {
+                    tracing::warn!(
+                        id = %request.id, qname = %question.name, error = %e,
+                        "Resolution failed"
+                    );
+                    return Ok(request.error_reply(rcode::SERVFAIL));
+                }
+            };
+            if resolution.rcode != rcode::NOERROR {
+                reply.rcode = resolution.rcode;
+            }
+            reply.answers.extend(resolution.answers);
+            reply.authorities.extend(resolution.authorities);
+        }
+        if !dnssec_ok {
+            for section in [&mut reply.answers, &mut reply.authorities] {
+                section.retain(|record| record.rtype != Type::RRSIG);
+            }
+        }
+        Ok(reply)
+    }
+}
+
+impl Recursor {
+    /// Resolves `question`, following the CNAME chain from its name.
+    fn lookup(&self, question: &Question, dnssec_ok: bool, depth: usize) -> Result<Resolution> {
+        if depth > MAX_DEPTH {
+            bail!("too many nested lookups for {}", question.name);
+        }
+        let mut answers = Vec::new();
+        let mut name = question.name.clone();
+        for _ in 0..=MAX_CNAME_CHASE {
+            let next = Question {
+                name,
+                ..question.clone()
+            };
+            let resolution = self.lookup_name(&next, dnssec_ok, depth)?;
+            answers.extend(resolution.answers);
+            if resolution.rcode != rcode::NOERROR {
+                return Ok(Resolution {
+                    answers,
+                    ..resolution
+                });
+            }
+            match unresolved_cname(&answers, &question.name, question.qtype) {
+                Some(target) => name = target,
+                None => {
+                    return Ok(Resolution {
+                        answers,
+                        ..resolution
+                    })
+                }
+            }
+        }
+        bail!("CNAME chain of {} is too long", question.name)
+    }
+
+    /// Resolves `question` for its name alone, a CNAME there being an
+    /// answer too.
+    fn lookup_name(
+        &self,
+        question: &Question,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<Resolution> {
+        for rtype in [question.qtype, Type::CNAME] {
+            if let Some(answers) = self.rrsets.get(&question.name, rtype) {
+                return Ok(Resolution {
+                    rcode: rcode::NOERROR,
+                    answers,
+                    authorities: Vec::new(),
+                });
+            }
+        }
+
+        let mut delegation = self.closest_delegation(question);
+        // labels added to the zone for the minimized name, 0 for the full one
+        let mut extra = 1;
+        let mut minimized = 0;
+        let mut referrals = 0;
+        while referrals < MAX_REFERRALS {
+            let name = (extra > 0 && minimized < MAX_MINIMIZED)
+                .then(|| minimize(&question.name, &delegation.zone, extra))
+                .flatten();
+            let step = match name {
+                Some(name) => {
+                    minimized += 1;
+                    let probe = Question {
+                        name,
+                        qtype: Type::A,
+                        class: Class::IN,
+                    };
+                    match self.query_zone(&delegation, &probe, dnssec_ok, depth) {
+                        Ok(Step::Referral(next)) => Step::Referral(next),
+                        Ok(Step::Answer(resolution)) if resolution.rcode == rcode::NOERROR => {
+                            extra += 1;
+                            continue;
+                        }
+                        _ => {
+                            extra = 0;
+                            continue;
+                        }
+                    }
+                }
+                None => self.query_zone(&delegation, question, dnssec_ok, depth)?,
+            };
+            match step {
+                Step::Answer(resolution) => return Ok(resolution),
+                Step::Referral(next) => {
+                    tracing::debug!(zone = %next.zone, qname = %question.name, "Referred");
+                    delegation = next;
+                    referrals += 1;
+                    extra = extra.min(1);
+                }
+            }
+        }
+        bail!("too many referrals for {}", question.name)
+    }
+
+    /// Servers of the closest zone above the name known from the cache, the
+    /// root servers of the hints if none is, not even the root. Zones whose
+    /// servers can't be reached without their expired glue are skipped. DS
+    /// records are asked of the parent.
+    fn closest_delegation(&self, question: &Question) -> Delegation {
+        let mut zone = match question.qtype {
+            Type::DS => question.name.parent(),
+            _ => Some(question.name.clone()),
+        };
+        while let Some(name) = zone {
+            let delegation = self.cached_delegation(&name);
+            let reachable = |delegation: &Delegation| {
+                !delegation.addrs.is_empty()
+                    || delegation
+                        .names
+                        .iter()
+                        .any(|name| !name.is_subdomain_of(&delegation.zone))
+            };
+            if let Some(delegation) = delegation.filter(reachable) {
+                return delegation;
+            }
+            zone = name.parent();
+        }
+        Delegation {
+            zone: Name::root(),
+            names: Vec::new(),
+            addrs: self.roots.clone(),
+        }
+    }
+
+    /// Servers of `zone` from its cached NS records and their addresses.
+    fn cached_delegation(&self, zone: &Name) -> Option<Delegation> {
+        let names: Vec<Name> = self
+            .rrsets
+            .get(zone, Type::NS)?
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                RData::NS(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let addrs = names
+            .iter()
+            .flat_map(|name| self.cached_addrs(name))
+            .collect();
+        Some(Delegation {
+            zone: zone.clone(),
+            names,
+            addrs,
+        })
+    }
+
+    fn cached_addrs(&self, name: &Name) -> Vec<SocketAddr> {
+        self.rrsets
+            .get(name, Type::A)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                RData::A(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Asks the servers of `delegation` until one gives a usable reply, then
+    /// those without glue once their addresses are looked up.
+    fn query_zone(
+        &self,
+        delegation: &Delegation,
+        question: &Question,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<Step> {
+        let mut rng = rand::thread_rng();
+        let mut addrs = delegation.addrs.clone();
+        addrs.shuffle(&mut rng);
+        let mut attempts = 0;
+        for addr in addrs.into_iter().take(MAX_ATTEMPTS) {
+            attempts += 1;
+            if let Some(step) = self.ask(addr, &delegation.zone, question, dnssec_ok) {
+                return Ok(step);
+            }
+        }
+
+        // glueless delegation, servers in the zone itself would need glue
+        let mut names: Vec<&Name> = delegation
+            .names
+            .iter()
+            .filter(|name| !name.is_subdomain_of(&delegation.zone))
+            .collect();
+        names.shuffle(&mut rng);
+        for name in names {
+            if attempts >= MAX_ATTEMPTS {
+                break;
+            }
+            let Ok(resolution) = self.lookup(
+                &Question {
+                    name: name.clone(),
+                    qtype: Type::A,
+                    class: Class::IN,
+                },
+                false,
+                depth + 1,
+            ) else {
+                continue;
+            };
+            let addrs = resolution
+                .answers
+                .iter()
+                .filter_map(|record| match record.rdata {
+                    RData::A(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+                    _ => None,
+                });
+            for addr in addrs.take(MAX_ATTEMPTS - attempts) {
+                attempts += 1;
+                if let Some(step) = self.ask(addr, &delegation.zone, question, dnssec_ok) {
+                    return Ok(step);
+                }
+            }
+        }
+        Err(anyhow!(
+            "no server of {} answered for {}",
+            delegation.zone.to_fqdn(),
+            question.name
+        ))
+    }
+
+    /// Sends `request` to the server at `addr`, its reply decoded in full
+    /// to go through the referrals.
+    fn exchange(&self, addr: SocketAddr, request: &Message) -> Result<Message> {
+        Ok(self
+            .upstream
+            .query(addr, request, self.timeout)?
+            .to_message()?)
+    }
+
+    /// Sends `question` to a server of `zone`, None if it fails or gives a
+    /// reply that's of no use.
+    fn ask(
+        &self,
+        addr: SocketAddr,
+        zone: &Name,
+        question: &Question,
+        dnssec_ok: bool,
+    ) -> Option<Step> {
+        let request = Message {
+            questions: vec![question.clone()],
+            opt: Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                dnssec_ok,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        tracing::debug!(server = %addr, qname = %question.name, "Asking");
+        match self.exchange(addr, &request) {
+            Ok(reply) => {
+                let step = self.classify(zone, question, reply);
+                if step.is_none() {
+                    tracing::debug!(server = %addr, "No usable reply");
+                }
+                step
+            }
+            Err(e) => {
+                tracing::debug!(server = %addr, error = %e, "Server failed");
+                None
+            }
+        }
+    }
+
+    /// What the reply of a server of `zone` tells about `question`, caching
+    /// the records in it. Records outside the zone are ignored.
+    fn classify(&self, zone: &Name, question: &Question, reply: Message) -> Option<Step> {
+        let in_zone = |record: &Record| record.name.is_subdomain_of(zone);
+        let authorities: Vec<Record> = reply.authorities.into_iter().filter(in_zone).collect();
+        match reply.rcode {
+            rcode::NXDOMAIN => {
+                return Some(Step::Answer(Resolution {
+                    rcode: rcode::NXDOMAIN,
+                    answers: Vec::new(),
+                    authorities,
+                }))
+            }
+            rcode::NOERROR => {}
+            _ => return None,
+        }
+
+        let answers: Vec<Record> = reply.answers.into_iter().filter(in_zone).collect();
+        let answered = answers.iter().any(|record| {
+            record.name == question.name
+                && (record.rtype == question.qtype
+                    || record.rtype == Type::CNAME
+                    || question.qtype == Type::ANY)
+        });
+        if answered {
+            self.rrsets.insert(&answers);
+            return Some(Step::Answer(Resolution {
+                rcode: rcode::NOERROR,
+                answers,
+                authorities: Vec::new(),
+            }));
+        }
+
+        // a referral to a zone below this one, on the way to the name
+        let child = authorities
+            .iter()
+            .find(|record| {
+                record.rtype == Type::NS
+                    && record.name != *zone
+                    && question.name.is_subdomain_of(&record.name)
+                    // DS records are in the parent, not below the cut
+                    && !(question.qtype == Type::DS && record.name == question.name)
+            })
+            .map(|record| record.name.clone());
+        if let Some(child) = child {
+            let referral: Vec<Record> = authorities
+                .into_iter()
+                .filter(|record| {
+                    record.name == child
+                        && matches!(record.rtype, Type::NS | Type::DS | Type::RRSIG)
+                })
+                .collect();
+            let names: Vec<Name> = referral
+                .iter()
+                .filter_map(|record| match &record.rdata {
+                    RData::NS(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            let glue: Vec<Record> = reply
+                .additionals
+                .into_iter()
+                .filter(|record| {
+                    record.rtype == Type::A && in_zone(record) && names.contains(&record.name)
+                })
+                .collect();
+            self.rrsets.insert(&referral);
+            self.rrsets.insert(&glue);
+            let addrs = glue
+                .iter()
+                .filter_map(|record| match record.rdata {
+                    RData::A(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+                    _ => None,
+                })
+                .collect();
+            return Some(Step::Referral(Delegation {
+                zone: child,
+                names,
+                addrs,
+            }));
+        }
+
+        // NODATA, from a server that knows the zone
+        let soa = authorities.iter().any(|record| record.rtype == Type::SOA);
+        (reply.aa == 1 || soa).then_some(Step::Answer(Resolution {
+            rcode: rcode::NOERROR,
+            answers: Vec::new(),
+            authorities,
+        }))
+    }
+}
+
+impl Recursor {
+    /// Resolves `question` from the root servers of the hints down, the
+    /// full name asked at each zone, writing to `out` every server asked,
+    /// its round trip time and the records it sent, for debugging
+    /// delegations. Returns the reply that answered, CNAMEs in it not
+    /// followed.
+    pub fn trace(&self, question: &Question, out: &mut dyn Write) -> Result<Message> {
+        let request = Message {
+            questions: vec![question.clone()],
+            opt: Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let mut delegation = Delegation {
+            zone: Name::root(),
+            names: Vec::new(),
+            addrs: self.roots.clone(),
+        };
+        'referrals: for _ in 0..MAX_REFERRALS {
+            let mut addrs = delegation.addrs.clone();
+            if addrs.is_empty() {
+                // glueless, the servers' addresses are looked up first
+                for name in delegation.names.iter() {
+                    let a = Question {
+                        name: name.clone(),
+                        qtype: Type::A,
+                        class: Class::IN,
+                    };
+                    let Ok(resolution) = self.lookup(&a, false, 1) else {
+                        continue;
+                    };
+                    addrs.extend(resolution.answers.iter().filter_map(|r| match r.rdata {
+                        RData::A(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+                        _ => None,
+                    }));
+                }
+            }
+            addrs.shuffle(&mut rand::thread_rng());
+
+            for addr in addrs.into_iter().take(MAX_ATTEMPTS) {
+                let start = Instant::now();
+                let result = self.exchange(addr, &request);
+                let rtt = start.elapsed().as_millis();
+                let reply = match result {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        writeln!(
+                            out,
+                            ";; {} of {} failed after {} ms: {}",
+                            addr,
+                            delegation.zone.to_fqdn(),
+                            rtt,
+                            e
+                        )?;
+                        continue;
+                    }
+                };
+                let records = reply
+                    .answers
+                    .iter()
+                    .chain(reply.authorities.iter())
+                    .chain(reply.additionals.iter());
+                for record in records {
+                    writeln!(out, "{}", record)?;
+                }
+                writeln!(
+                    out,
+                    ";; Received {} from {} of {} in {} ms\n",
+                    rcode::name(reply.rcode),
+                    addr,
+                    delegation.zone.to_fqdn(),
+                    rtt
+                )?;
+                match self.classify(&delegation.zone, question, reply.clone()) {
+                    Some(Step::Answer(_)) => return Ok(reply),
+                    Some(Step::Referral(next)) => {
+                        delegation = next;
+                        continue 'referrals;
+                    }
+                    None => writeln!(out, ";; No usable reply from {}\n", addr)?,
+                }
+            }
+            bail!(
+                "no server of {} answered for {}",
+                delegation.zone.to_fqdn(),
+                question.name
+            );
+        }
+        bail!("too many referrals for {}", question.name)
+    }
+}
+
+/// `name` cut down to `extra` labels more than `zone`, None if that's all of
+/// it.
+fn minimize(name: &Name, zone: &Name, extra: usize) -> Option<Name> {
+    let labels = name.labels();
+    let keep = zone.num_labels() + extra;
+    (keep < labels.len()).then(|| Name::from_labels(&labels[labels.len() - keep..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{minimize, parse_hints, Recursor, Rrsets};
+    use crate::{
+        proto::{rcode, test::request, Class, Message, Name, Question, Record, Type},
+        rdata::{RData, Soa},
+        resolver::Resolver,
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: match rdata {
+                RData::A(_) => Type::A,
+                RData::NS(_) => Type::NS,
+                RData::CNAME(_) => Type::CNAME,
+                _ => Type::SOA,
+            },
+            class: Class::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn ns(zone: &str, server: &str) -> Record {
+        record(zone, RData::NS(Name::from(server)))
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Record {
+        record(name, RData::A(Ipv4Addr::from(ip)))
+    }
+
+    /// Authoritative server on `ip` and `port` answering with `answer`,
+    /// counting the queries it gets.
+    fn server(
+        ip: [u8; 4],
+        port: u16,
+        answer: impl Fn(&Message, &mut Message) + Send + 'static,
+    ) -> Arc<AtomicUsize> {
+        let socket = UdpSocket::bind(SocketAddr::from((ip, port))).unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 1232];
+            loop {
+                let (size, source) = socket.recv_from(&mut buf).unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let request = Message::from_bytes(&buf[..size]).unwrap();
+                assert_eq!(0, request.rd);
+                let mut reply = request.reply();
+                answer(&request, &mut reply);
+                socket.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+            }
+        });
+        queries
+    }
+
+    /// A port free on 127.0.0.1, and likely on the other loopback addresses.
+    fn unused_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let port = unused_port();
+        // the root refers to com and net, with glue
+        let root = server([127, 0, 0, 1], port, |request, reply| {
+            let name = request.questions[0].name.to_string().to_ascii_lowercase();
+            let (zone, server, ip) = match name.ends_with("com") {
+                true => ("com", "ns1.com", [127, 0, 0, 2]),
+                false => ("net", "ns1.net", [127, 0, 0, 3]),
+            };
+            reply.authorities.push(ns(zone, server));
+            reply.additionals.push(a(server, ip));
+            // out of bailiwick, ignored
+            reply.additionals.push(a("ns.example.net", [192, 0, 2, 99]));
+        });
+        // com refers to example.com, whose server is under net
+        server([127, 0, 0, 2], port, |_, reply| {
+            reply.authorities.push(ns("example.com", "ns.example.net"));
+        });
+        server([127, 0, 0, 3], port, |request, reply| {
+            reply.aa = 1;
+            reply
+                .answers
+                .push(a(&request.questions[0].name.to_string(), [127, 0, 0, 4]));
+        });
+        let example = server([127, 0, 0, 4], port, |request, reply| {
+            reply.aa = 1;
+            let question = &request.questions[0];
+            match question.name.to_string().to_ascii_lowercase().as_str() {
+                "www.example.com" => reply.answers.push(record(
+                    &question.name.to_string(),
+                    RData::CNAME(Name::from("web.example.com")),
+                )),
+                "web.example.com" if question.qtype == Type::A => reply
+                    .answers
+                    .push(a(&question.name.to_string(), [192, 0, 2, 1])),
+                "web.example.com" => {}
+                _ => reply.rcode = rcode::NXDOMAIN,
+            }
+            reply.authorities.push(record(
+                "example.com",
+                RData::SOA(Soa {
+                    mname: Name::from("ns.example.net"),
+                    rname: Name::from("admin.example.com"),
+                    serial: 1,
+                    refresh: 2,
+                    retry: 3,
+                    expire: 4,
+                    minimum: 5,
+                }),
+            ));
+        });
+
+        let mut recursor = Recursor::new(Duration::from_secs(2)).unwrap();
+        recursor.roots = vec![SocketAddr::from(([127, 0, 0, 1], port))];
+        recursor.port = port;
+
+        let reply = recursor
+            .resolve(&request("www.example.com", Type::A))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!((1, 1, 7), (reply.qr, reply.ra, reply.id));
+        assert_eq!(
+            vec![
+                RData::CNAME(Name::from("web.example.com")),
+                RData::A(Ipv4Addr::new(192, 0, 2, 1))
+            ],
+            reply
+                .answers
+                .into_iter()
+                .map(|r| r.rdata)
+                .collect::<Vec<_>>()
+        );
+        // the root was asked for example.com and ns.example.net
+        assert_eq!(2, root.load(Ordering::Relaxed));
+
+        // straight to the example.com server this time
+        let reply = recursor
+            .resolve(&request("missing.example.com", Type::A))
+            .unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        assert_eq!(Type::SOA, reply.authorities[0].rtype);
+        let reply = recursor
+            .resolve(&request("web.example.com", Type::MX))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert!(reply.answers.is_empty());
+        assert_eq!(1, reply.authorities.len());
+        assert_eq!(2, root.load(Ordering::Relaxed));
+        assert_eq!(4, example.load(Ordering::Relaxed));
+
+        // answers come from the cache
+        let reply = recursor
+            .resolve(&request("WWW.example.com", Type::A))
+            .unwrap();
+        assert_eq!(2, reply.answers.len());
+        assert_eq!(4, example.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_rrsets() {
+        let rrsets = Rrsets::default();
+        let mut short = a("ns1.com", [192, 0, 2, 2]);
+        short.ttl = 0;
+        rrsets.insert(&[a("ns1.com", [192, 0, 2, 1]), ns("com", "ns1.com"), short]);
+        // the RRset lives as long as its shortest TTL
+        assert_eq!(None, rrsets.get(&Name::from("NS1.com."), Type::A));
+        assert_eq!(
+            Some(vec![ns("com", "ns1.com")]),
+            rrsets.get(&Name::from("COM"), Type::NS)
+        );
+        assert_eq!(None, rrsets.get(&Name::from("com"), Type::A));
+    }
+
+    #[test]
+    fn test_prime() {
+        let port = unused_port();
+        let hint = server([127, 0, 0, 6], port, |request, reply| {
+            assert_eq!(Type::NS, request.questions[0].qtype);
+            reply.aa = 1;
+            reply.answers.push(ns("", "a.root-servers.net"));
+            reply
+                .additionals
+                .push(a("a.root-servers.net", [127, 0, 0, 7]));
+        });
+        let root = server([127, 0, 0, 7], port, |request, reply| {
+            reply.aa = 1;
+            reply
+                .answers
+                .push(a(&request.questions[0].name.to_string(), [192, 0, 2, 7]));
+        });
+
+        let mut recursor = Recursor::new(Duration::from_secs(2)).unwrap();
+        recursor.port = port;
+        let recursor = recursor.with_hints(vec![Ipv4Addr::new(127, 0, 0, 6)]);
+        assert_eq!(300, recursor.prime().unwrap());
+        let reply = recursor.resolve(&request("example.org", Type::A)).unwrap();
+        assert_eq!(
+            RData::A(Ipv4Addr::new(192, 0, 2, 7)),
+            reply.answers[0].rdata
+        );
+        // the hints served for priming only, the root was asked for org
+        // first
+        assert_eq!(1, hint.load(Ordering::Relaxed));
+        assert_eq!(2, root.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_qname_minimization() {
+        let port = unused_port();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let log = asked.clone();
+        server([127, 0, 0, 8], port, move |request, reply| {
+            let question = &request.questions[0];
+            log.lock().unwrap().push((
+                question.name.to_string().to_ascii_lowercase(),
+                question.qtype,
+            ));
+            reply.authorities.push(ns("org", "ns1.org"));
+            reply.additionals.push(a("ns1.org", [127, 0, 0, 9]));
+        });
+        // denies the empty non-terminal example.org exists
+        let log = asked.clone();
+        server([127, 0, 0, 9], port, move |request, reply| {
+            let question = &request.questions[0];
+            log.lock().unwrap().push((
+                question.name.to_string().to_ascii_lowercase(),
+                question.qtype,
+            ));
+            reply.aa = 1;
+            match (
+                question.name.to_string().to_ascii_lowercase().as_str(),
+                question.qtype,
+            ) {
+                ("a.b.example.org", Type::A) => reply
+                    .answers
+                    .push(a(&question.name.to_string(), [192, 0, 2, 9])),
+                ("a.b.example.org", _) => {}
+                _ => reply.rcode = rcode::NXDOMAIN,
+            }
+        });
+
+        let mut recursor = Recursor::new(Duration::from_secs(2)).unwrap();
+        recursor.roots = vec![SocketAddr::from(([127, 0, 0, 8], port))];
+        recursor.port = port;
+        let reply = recursor
+            .resolve(&request("a.b.example.org", Type::MX))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert!(reply.answers.is_empty());
+        let reply = recursor
+            .resolve(&request("a.b.example.org", Type::A))
+            .unwrap();
+        assert_eq!(1, reply.answers.len());
+        assert_eq!(
+            vec![
+                ("org".to_string(), Type::A),
+                ("example.org".to_string(), Type::A),
+                ("a.b.example.org".to_string(), Type::MX),
+                ("example.org".to_string(), Type::A),
+                ("a.b.example.org".to_string(), Type::A),
+            ],
+            *asked.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let port = unused_port();
+        server([127, 0, 0, 10], port, |_, reply| {
+            reply.authorities.push(ns("net", "ns1.net"));
+            reply.additionals.push(a("ns1.net", [127, 0, 0, 11]));
+        });
+        server([127, 0, 0, 11], port, |request, reply| {
+            reply.aa = 1;
+            let name = request.questions[0].name.to_string().to_ascii_lowercase();
+            reply.answers.push(a(&name, [192, 0, 2, 11]));
+        });
+
+        let mut recursor = Recursor::new(Duration::from_secs(2)).unwrap();
+        recursor.roots = vec![SocketAddr::from(([127, 0, 0, 10], port))];
+        recursor.port = port;
+        let mut out = Vec::new();
+        let question = Question {
+            name: Name::from("www.example.net"),
+            qtype: Type::A,
+            class: Class::IN,
+        };
+        let reply = recursor.trace(&question, &mut out).unwrap();
+        assert_eq!(1, reply.answers.len());
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(5, lines.len(), "{}", out);
+        // names compressed against the question take its random case
+        assert_eq!("net.\t300\tin\tns\tns1.net.", lines[0].to_ascii_lowercase());
+        assert_eq!(
+            "ns1.net.\t300\tin\ta\t127.0.0.11",
+            lines[1].to_ascii_lowercase()
+        );
+        assert!(lines[2].starts_with(&format!(
+            ";; Received NOERROR from 127.0.0.10:{} of . in ",
+            port
+        )));
+        assert_eq!("www.example.net.\t300\tIN\tA\t192.0.2.11", lines[3]);
+        assert!(lines[4].to_ascii_lowercase().contains(" of net. in "));
+    }
+
+    #[test]
+    fn test_minimize() {
+        let name = Name::from("www.Example.com.");
+        let root = Name::root();
+        assert_eq!(Some(Name::from("com")), minimize(&name, &root, 1));
+        assert_eq!(
+            Some(Name::from("Example.com")),
+            minimize(&name, &Name::from("com"), 1)
+        );
+        assert_eq!(None, minimize(&name, &Name::from("com"), 2));
+        assert_eq!(None, minimize(&name, &Name::from("example.com"), 1));
+    }
+
+    #[test]
+    fn test_parse_hints() {
+        let text = "\
+; formerly NS.INTERNIC.NET
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2
+; not a root server
+NS.EXAMPLE.              3600000      A     192.0.2.1
+";
+        assert_eq!(
+            vec![
+                Ipv4Addr::new(198, 41, 0, 4),
+                Ipv4Addr::new(170, 247, 170, 2)
+            ],
+            parse_hints(text).unwrap()
+        );
+        assert!(parse_hints(". 3600 NS a.root-servers.net.").is_err());
+        assert!(parse_hints(". 3600 BOGUS x").is_err());
+    }
+}
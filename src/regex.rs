@@ -0,0 +1,314 @@
+//! Regular expressions for matching names: literals, `.`, classes like
+//! `[a-z0-9-]` and `[^.]`, the escapes `\d`, `\w`, `\s` and their negations,
+//! anchors, groups, alternation and the quantifiers `*`, `+`, `?` and
+//! `{n,m}`, all greedy. Matching backtracks, ignores ASCII case and finds
+//! the pattern anywhere in the text unless anchored.
+
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    // any byte a class matches
+    Class(Class),
+    Start,
+    End,
+    // alternatives, each a sequence
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    // inclusive byte ranges
+    ranges: Vec<(u8, u8)>,
+    negated: bool,
+}
+
+impl Class {
+    fn of(ranges: &[(u8, u8)], negated: bool) -> Self {
+        Self {
+            ranges: ranges.to_vec(),
+            negated,
+        }
+    }
+
+    fn matches(&self, b: u8) -> bool {
+        let within = |b: u8| self.ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&b));
+        let found = within(b) || within(b.to_ascii_lowercase()) || within(b.to_ascii_uppercase());
+        found != self.negated
+    }
+}
+
+const DIGIT: &[(u8, u8)] = &[(b'0', b'9')];
+const WORD: &[(u8, u8)] = &[(b'a', b'z'), (b'A', b'Z'), (b'0', b'9'), (b'_', b'_')];
+const SPACE: &[(u8, u8)] = &[(b' ', b' '), (b'\t', b'\r')];
+
+/// A compiled regular expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regex {
+    source: String,
+    root: Vec<Vec<Node>>,
+}
+
+impl Regex {
+    pub fn is_match(&self, text: &str) -> bool {
+        let text = text.as_bytes();
+        let root = [Node::Group(self.root.clone())];
+        (0..=text.len()).any(|start| matches(&root, text, start, &|_| true))
+    }
+}
+
+/// Whether `nodes` match `text` from `at` on, with `then` accepting where
+/// they end.
+fn matches(nodes: &[Node], text: &[u8], at: usize, then: &dyn Fn(usize) -> bool) -> bool {
+    let Some((node, rest)) = nodes.split_first() else {
+        return then(at);
+    };
+    match node {
+        Node::Class(class) => {
+            at < text.len() && class.matches(text[at]) && matches(rest, text, at + 1, then)
+        }
+        Node::Start => at == 0 && matches(rest, text, at, then),
+        Node::End => at == text.len() && matches(rest, text, at, then),
+        Node::Group(alternatives) => alternatives.iter().any(|alternative| {
+            matches(alternative, text, at, &|end| matches(rest, text, end, then))
+        }),
+        Node::Repeat(node, min, max) => repeat(node, *min, *max, 0, rest, text, at, then),
+    }
+}
+
+/// Matches of `node` repeated from `count` times on, the most first.
+#[allow(clippy::too_many_arguments)]
+fn repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    rest: &[Node],
+    text: &[u8],
+    at: usize,
+    then: &dyn Fn(usize) -> bool,
+) -> bool {
+    let more = max.is_none_or(|max| count < max)
+        && matches(std::slice::from_ref(node), text, at, &|end| {
+            // an empty match repeated doesn't get anywhere
+            (end != at || count < min) && repeat(node, min, max, count + 1, rest, text, end, then)
+        });
+    more || (count >= min && matches(rest, text, at, then))
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.at += 1;
+        Some(b)
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some(b'|') {
+            self.at += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(b) = self.peek() {
+            if b == b'|' || b == b')' {
+                break;
+            }
+            let node = self.atom()?;
+            nodes.push(self.quantified(node)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let b = self.next().ok_or("unexpected end")?;
+        Ok(match b {
+            b'.' => Node::Class(Class::of(&[], true)),
+            b'^' => Node::Start,
+            b'$' => Node::End,
+            b'(' => {
+                // non-capturing groups are the only kind anyway
+                if self.pattern[self.at..].starts_with(b"?:") {
+                    self.at += 2;
+                }
+                let alternatives = self.alternatives()?;
+                if self.next() != Some(b')') {
+                    return Err("unclosed group".into());
+                }
+                Node::Group(alternatives)
+            }
+            b'[' => Node::Class(self.class()?),
+            b'\\' => Node::Class(self.escape()?),
+            b'*' | b'+' | b'?' | b'{' | b')' => {
+                return Err(format!("unexpected {:?} at {}", b as char, self.at - 1))
+            }
+            b => Node::Class(Class::of(&[(b, b)], false)),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Class, String> {
+        let b = self.next().ok_or("trailing backslash")?;
+        Ok(match b {
+            b'd' => Class::of(DIGIT, false),
+            b'D' => Class::of(DIGIT, true),
+            b'w' => Class::of(WORD, false),
+            b'W' => Class::of(WORD, true),
+            b's' => Class::of(SPACE, false),
+            b'S' => Class::of(SPACE, true),
+            b if b.is_ascii_alphanumeric() => {
+                return Err(format!("unknown escape \\{}", b as char))
+            }
+            b => Class::of(&[(b, b)], false),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.at += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let b = self.next().ok_or("unclosed class")?;
+            let lo = match b {
+                b']' if !first => return Ok(Class { ranges, negated }),
+                b'\\' => {
+                    let escaped = self.escape()?;
+                    if escaped.negated {
+                        return Err("negated escape in a class".into());
+                    }
+                    ranges.extend(escaped.ranges);
+                    first = false;
+                    continue;
+                }
+                b => b,
+            };
+            first = false;
+            let range = self.pattern.get(self.at..self.at + 2);
+            match range {
+                Some([b'-', hi]) if *hi != b']' => {
+                    self.at += 2;
+                    if *hi < lo {
+                        return Err(format!("invalid range {}-{}", lo as char, *hi as char));
+                    }
+                    ranges.push((lo, *hi));
+                }
+                _ => ranges.push((lo, lo)),
+            }
+        }
+    }
+
+    fn quantified(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            Some(b'{') => {
+                let end = self.pattern[self.at..]
+                    .iter()
+                    .position(|b| *b == b'}')
+                    .ok_or("unclosed repetition")?;
+                let bounds = std::str::from_utf8(&self.pattern[self.at + 1..self.at + end])
+                    .map_err(|e| e.to_string())?;
+                let number = |s: &str| {
+                    s.parse::<usize>()
+                        .map_err(|_| format!("invalid repetition {{{}}}", bounds))
+                };
+                let (min, max) = match bounds.split_once(',') {
+                    None => (number(bounds)?, Some(number(bounds)?)),
+                    Some((min, "")) => (number(min)?, None),
+                    Some((min, max)) => (number(min)?, Some(number(max)?)),
+                };
+                if max.is_some_and(|max| max < min) {
+                    return Err(format!("invalid repetition {{{}}}", bounds));
+                }
+                self.at += end;
+                (min, max)
+            }
+            _ => return Ok(node),
+        };
+        self.at += 1;
+        if matches!(node, Node::Start | Node::End) {
+            return Err("nothing to repeat".into());
+        }
+        Ok(Node::Repeat(Box::new(node), min, max))
+    }
+}
+
+impl FromStr for Regex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            pattern: s.as_bytes(),
+            at: 0,
+        };
+        let root = parser
+            .alternatives()
+            .map_err(|e| format!("{}: {:?}", e, s))?;
+        if parser.at != s.len() {
+            return Err(format!("unmatched ) in {:?}", s));
+        }
+        Ok(Self {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Regex;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        pattern.parse::<Regex>().unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_is_match() {
+        assert!(is_match("ads", "cdn.ads.example"));
+        assert!(is_match("^ads?[0-9]*\\.", "ad3.example"));
+        assert!(!is_match("^ads?[0-9]*\\.", "cdn.ads.example"));
+        assert!(is_match("(^|\\.)tracker\\.example$", "Tracker.EXAMPLE"));
+        assert!(is_match("(^|\\.)tracker\\.example$", "a.tracker.example"));
+        assert!(!is_match("(^|\\.)tracker\\.example$", "mytracker.example"));
+        assert!(is_match("^(?:www|api)\\d{1,2}\\.", "api42.example"));
+        assert!(!is_match("^(?:www|api)\\d{1,2}\\.", "api123.example"));
+        assert!(is_match("^[^.]+\\.lan$", "nas.lan"));
+        assert!(!is_match("^[^.]+\\.lan$", "a.nas.lan"));
+        assert!(is_match("^x{2,}$", "xxxx"));
+        assert!(is_match("^(a*)*b$", "aaab"));
+        assert!(is_match("^$", ""));
+        assert!(is_match("^\\w+-\\w+$", "home-lab"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for pattern in [
+            "(ads", "ads)", "[a-", "*ads", "a{3,1}", "^*", "\\q", "[z-a]",
+        ] {
+            assert!(pattern.parse::<Regex>().is_err(), "{}", pattern);
+        }
+    }
+}
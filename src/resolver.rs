@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context as _, Result};
+
+use crate::{
+    anchors::{self, Tracker},
+    config::Config,
+    dns64::Dns64,
+    forward::{Endpoint, Forwarder},
+    handler::{set_origin, Context, Next, Origin, RequestHandler},
+    proto::Message,
+    recursor::{self, Recursor},
+    stub::Stub,
+    validator::Validator,
+    x509,
+};
+
+/// Strategy for answering requests that reach the end of the handler chain.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, request: &Message) -> Result<Message>;
+}
+
+/// Resolver for the configuration: iterating from the root servers in
+/// recursive mode, primed from the configured or built-in root hints,
+/// forwarding when upstreams or forward rules are set, otherwise the
+/// built-in stub. With rules but no default upstreams, names outside the
+/// rules' zones get SERVFAIL. Recursive and forwarded answers are validated
+/// with DNSSEC when enabled, stub ones never are, starting from the root's
+/// keys and the configured trust anchors, and then get AAAA records
+/// synthesized with DNS64 when enabled. DoH, DoQ and ODoH upstreams are
+/// authenticated against the configured or system TLS roots and the pins of
+/// their hosts, ODoH ones are reached through the configured relay.
+pub fn from_config(config: &Config) -> Result<Arc<dyn Resolver>> {
+    let mut resolver: Arc<dyn Resolver> = if config.recursive {
+        let mut recursor = Recursor::new(config.upstream_timeout)?;
+        if let Some(path) = &config.root_hints {
+            recursor = recursor.with_hints(recursor::load_hints(path)?);
+        }
+        let recursor = Arc::new(recursor);
+        Recursor::prime_periodically(&recursor);
+        recursor
+    } else if config.resolvers.is_empty() && config.forward_rules.is_empty() {
+        return Ok(Arc::new(Stub));
+    } else {
+        Arc::new(forwarder(
+            config,
+            config.resolvers.clone(),
+            &config.forward_rules,
+        )?)
+    };
+    if config.dnssec_validation {
+        let mut trust_anchors = anchors::root();
+        trust_anchors.extend(config.trust_anchors.iter().cloned());
+        for file in config.trust_anchor_files.iter() {
+            let loaded = anchors::load(file)
+                .with_context(|| format!("Failed to load trust anchors from {}", file.display()))?;
+            trust_anchors.extend(loaded);
+        }
+        let mut validator = Validator::new(resolver, trust_anchors);
+        if let Some(path) = &config.trust_anchor_state {
+            validator = validator.with_tracker(Tracker::load(path)?);
+        }
+        let validator = Arc::new(validator);
+        Validator::track(&validator);
+        resolver = validator;
+    }
+    if config.dns64 {
+        resolver = Arc::new(Dns64::new(
+            resolver,
+            config.dns64_prefix,
+            config.dns64_exclude.clone(),
+        ));
+    }
+    Ok(resolver)
+}
+
+/// Resolver forwarding every request to `upstreams`, with the upstream
+/// settings of the configuration but neither validation nor DNS64.
+pub fn upstreams(config: &Config, upstreams: Vec<Endpoint>) -> Result<Arc<dyn Resolver>> {
+    Ok(Arc::new(forwarder(config, upstreams, &[])?))
+}
+
+fn forwarder(
+    config: &Config,
+    resolvers: Vec<Endpoint>,
+    rules: &[(String, Vec<Endpoint>)],
+) -> Result<Forwarder> {
+    let mut forwarder = Forwarder::new(
+        resolvers,
+        rules,
+        config.upstream_strategy,
+        config.upstream_timeout,
+        config.upstream_retries,
+    )?;
+    if forwarder.uses_tls() {
+        let path = config
+            .tls_roots
+            .clone()
+            .unwrap_or_else(|| x509::DEFAULT_ROOTS.into());
+        let roots = x509::load_pem(&path)
+            .with_context(|| format!("Failed to load TLS roots from {}", path.display()))?;
+        forwarder = forwarder.with_tls(roots, &config.tls_pins);
+    }
+    if forwarder.uses_odoh() {
+        let relay = config
+            .odoh_relay
+            .clone()
+            .ok_or_else(|| anyhow!("odoh:// upstreams need an ODoH relay"))?;
+        forwarder = forwarder.with_odoh_relay(relay);
+    }
+    Ok(forwarder)
+}
+
+/// Last handler of a chain, answers every request with its resolver.
+pub struct ResolverHandler(pub Arc<dyn Resolver>);
+
+impl RequestHandler for ResolverHandler {
+    fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+        let reply = self.0.resolve(&request)?;
+        set_origin(Origin::Upstream);
+        Ok(reply)
+    }
+}
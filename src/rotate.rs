@@ -0,0 +1,178 @@
+//! Order of the addresses in answers. Clients mostly use the first address
+//! they get, so answering a name's A or AAAA records in the same order every
+//! time sends all of them to the same target.
+
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+
+use crate::{
+    handler::{Context, Next, RequestHandler},
+    proto::{Message, Record, Type},
+};
+
+/// How the address records of each RRset in an answer are ordered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnswerOrder {
+    /// Start one further into the RRset on every response
+    #[default]
+    Rotate,
+    /// Shuffle the RRset on every response
+    Shuffle,
+    /// As upstreams, zone files and host entries have them
+    Preserve,
+}
+
+impl FromStr for AnswerOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rotate" => Ok(Self::Rotate),
+            "shuffle" => Ok(Self::Shuffle),
+            "preserve" => Ok(Self::Preserve),
+            _ => Err(format!("unknown answer order {:?}", s)),
+        }
+    }
+}
+
+/// Reorders the A and AAAA RRsets of replies on their way out, each
+/// staying where it was among the other answers.
+pub struct Rotation {
+    order: AnswerOrder,
+    // responses so far, how far to rotate the next one
+    next: AtomicUsize,
+}
+
+impl Rotation {
+    pub fn new(order: AnswerOrder) -> Self {
+        Self {
+            order,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn reorder(&self, answers: &mut [Record]) {
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut done = vec![false; answers.len()];
+        for i in 0..answers.len() {
+            if done[i] || !matches!(answers[i].rtype, Type::A | Type::AAAA) {
+                continue;
+            }
+            // the RRset's positions
+            let positions: Vec<usize> = (i..answers.len())
+                .filter(|&j| {
+                    answers[j].rtype == answers[i].rtype
+                        && answers[j].class == answers[i].class
+                        && answers[j].name == answers[i].name
+                })
+                .collect();
+            for &j in positions.iter() {
+                done[j] = true;
+            }
+            if positions.len() < 2 {
+                continue;
+            }
+            let mut rrset: Vec<Record> = positions.iter().map(|&j| answers[j].clone()).collect();
+            let len = rrset.len();
+            match self.order {
+                AnswerOrder::Rotate => rrset.rotate_left(offset % len),
+                AnswerOrder::Shuffle => rrset.shuffle(&mut rand::thread_rng()),
+                AnswerOrder::Preserve => {}
+            }
+            for (j, record) in positions.into_iter().zip(rrset) {
+                answers[j] = record;
+            }
+        }
+    }
+}
+
+impl RequestHandler for Rotation {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let mut reply = next.run(ctx, request)?;
+        self.reorder(&mut reply.answers);
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AnswerOrder, Rotation};
+    use crate::{
+        proto::{Class, Name, Record, Type},
+        rdata::RData,
+    };
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record {
+            name: Name::from(name),
+            rtype: match rdata {
+                RData::A(_) => Type::A,
+                _ => Type::CNAME,
+            },
+            class: Class::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn answers() -> Vec<Record> {
+        let a = |last| record("lb.example", RData::A(Ipv4Addr::new(192, 0, 2, last)));
+        vec![
+            record("www.example", RData::CNAME(Name::from("lb.example"))),
+            a(1),
+            a(2),
+            a(3),
+        ]
+    }
+
+    fn addresses(answers: &[Record]) -> Vec<String> {
+        answers.iter().map(|r| r.rdata.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rotate() {
+        let rotation = Rotation::new(AnswerOrder::Rotate);
+        let orders: Vec<_> = (0..4)
+            .map(|_| {
+                let mut answers = answers();
+                rotation.reorder(&mut answers);
+                addresses(&answers)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                vec!["lb.example.", "192.0.2.1", "192.0.2.2", "192.0.2.3"],
+                vec!["lb.example.", "192.0.2.2", "192.0.2.3", "192.0.2.1"],
+                vec!["lb.example.", "192.0.2.3", "192.0.2.1", "192.0.2.2"],
+                vec!["lb.example.", "192.0.2.1", "192.0.2.2", "192.0.2.3"],
+            ],
+            orders
+        );
+    }
+
+    #[test]
+    fn test_shuffle_and_preserve() {
+        let rotation = Rotation::new(AnswerOrder::Shuffle);
+        let mut answers = answers();
+        rotation.reorder(&mut answers);
+        // the CNAME stays first, the addresses are all still there
+        assert_eq!(Type::CNAME, answers[0].rtype);
+        let mut shuffled = addresses(&answers[1..]);
+        shuffled.sort();
+        assert_eq!(addresses(&self::answers()[1..]), shuffled);
+
+        let rotation = Rotation::new(AnswerOrder::Preserve);
+        for _ in 0..3 {
+            let mut answers = self::answers();
+            rotation.reorder(&mut answers);
+            assert_eq!(addresses(&self::answers()), addresses(&answers));
+        }
+    }
+}
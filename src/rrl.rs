@@ -0,0 +1,340 @@
+//! Response rate limiting, after BIND's RRL. Forged-source queries make a
+//! server reflect its answers at a victim, so identical responses to one
+//! network are limited: past the rate they are dropped, or every few
+//! "slip" as empty truncated replies that send real clients to TCP.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    acl::Network,
+    proto::{rcode, Message, Name, Type},
+};
+
+/// Buckets kept before idle ones are cleaned up.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Settings of the rate limiter, in the `[rate_limit]` section of the config
+/// file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    // identical responses per second to a network, 0 disables limiting
+    pub responses_per_second: u32,
+    // seconds over which the rate is averaged
+    pub window: u32,
+    // every how many limited responses one is sent truncated, 0 for never
+    pub slip: u32,
+    // prefix lengths clients are grouped by
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    // clients that are never limited
+    pub exempt: Vec<Network>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 0,
+            window: 15,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// What to do with a UDP response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Send,
+    Drop,
+    // send an empty reply with TC set instead
+    Slip,
+}
+
+/// Kind of response, each counted on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Answer,
+    // NXDOMAIN, NODATA or a referral, counted per zone
+    Empty,
+    // counted per client network only
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Bucket {
+    network: IpAddr,
+    kind: Kind,
+    // the root for errors
+    name: Name,
+    rtype: Option<Type>,
+}
+
+#[derive(Debug)]
+struct Account {
+    // responses left, negative while limited
+    balance: f64,
+    updated: Instant,
+    limited: u64,
+}
+
+/// Accounts of the responses sent per bucket.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimit,
+    accounts: Mutex<HashMap<Bucket, Account>>,
+    dropped: AtomicU64,
+    slipped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimit) -> Self {
+        Self {
+            config,
+            accounts: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            slipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Charges `reply` to `client`'s bucket for it. Each bucket earns the
+    /// rate every second, up to one second's worth, and owes at most a
+    /// window's worth.
+    pub fn check(&self, client: IpAddr, reply: &Message, now: Instant) -> Action {
+        let rate = self.config.responses_per_second as f64;
+        if rate == 0.0 || self.config.exempt.iter().any(|net| net.contains(client)) {
+            return Action::Send;
+        }
+        let bucket = self.bucket(client, reply);
+
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.len() >= MAX_BUCKETS {
+            let window = self.config.window as f64;
+            accounts
+                .retain(|_, account| now.duration_since(account.updated).as_secs_f64() < window);
+        }
+        let account = accounts.entry(bucket).or_insert(Account {
+            balance: rate,
+            updated: now,
+            limited: 0,
+        });
+        let elapsed = now.duration_since(account.updated).as_secs_f64();
+        account.balance = (account.balance + elapsed * rate).min(rate) - 1.0;
+        account.balance = account.balance.max(-rate * self.config.window as f64);
+        account.updated = now;
+        if account.balance >= 0.0 {
+            return Action::Send;
+        }
+
+        account.limited += 1;
+        if self.config.slip > 0 && account.limited.is_multiple_of(self.config.slip as u64) {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            Action::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            Action::Drop
+        }
+    }
+
+    /// Bucket a reply is counted in: answers per name and type, negative
+    /// answers and referrals per zone, errors per client network.
+    fn bucket(&self, client: IpAddr, reply: &Message) -> Bucket {
+        let question = reply.questions.first();
+        let qname = || question.map_or_else(Name::root, |q| q.name.clone());
+        let (kind, name, rtype) = match reply.rcode {
+            rcode::NOERROR if !reply.answers.is_empty() => {
+                (Kind::Answer, qname(), question.map(|q| q.qtype))
+            }
+            rcode::NOERROR | rcode::NXDOMAIN => {
+                let zone = reply
+                    .authorities
+                    .iter()
+                    .find(|r| matches!(r.rtype, Type::SOA | Type::NS))
+                    .map_or_else(qname, |r| r.name.clone());
+                (Kind::Empty, zone, None)
+            }
+            _ => (Kind::Error, Name::root(), None),
+        };
+        Bucket {
+            network: self.network(client),
+            kind,
+            name,
+            rtype,
+        }
+    }
+
+    /// `client` with the bits past the configured prefix cleared.
+    fn network(&self, client: IpAddr) -> IpAddr {
+        match client.to_canonical() {
+            IpAddr::V4(addr) => {
+                let bits = u32::from(addr);
+                let mask = u32::MAX.checked_shl(32 - self.config.ipv4_prefix as u32);
+                IpAddr::V4((bits & mask.unwrap_or(0)).into())
+            }
+            IpAddr::V6(addr) => {
+                let bits = u128::from(addr);
+                let mask = u128::MAX.checked_shl(128 - self.config.ipv6_prefix as u32);
+                IpAddr::V6((bits & mask.unwrap_or(0)).into())
+            }
+        }
+    }
+
+    /// Responses dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Responses answered truncated instead of dropped so far.
+    pub fn slipped(&self) -> u64 {
+        self.slipped.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} responses dropped and {} slipped by rate limiting",
+            self.dropped(),
+            self.slipped()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, RateLimit, RateLimiter};
+    use crate::{
+        handler::local_soa,
+        proto::{rcode, Class, Message, Name, Question, Record, Type},
+        rdata::RData,
+    };
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    fn reply(name: &str, rcode: u8) -> Message {
+        let name = Name::from(name);
+        let mut reply = Message {
+            qr: 1,
+            rcode,
+            questions: vec![Question {
+                name: name.clone(),
+                qtype: Type::A,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        match rcode {
+            rcode::NOERROR => reply.answers.push(Record {
+                name,
+                rtype: Type::A,
+                class: Class::IN,
+                ttl: 60,
+                rdata: RData::A("192.0.2.1".parse().unwrap()),
+            }),
+            rcode::NXDOMAIN => reply
+                .authorities
+                .push(local_soa(&Name::from("example.com"))),
+            _ => {}
+        }
+        reply
+    }
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            responses_per_second: 2,
+            window: 5,
+            slip,
+            exempt: vec!["192.0.2.53".parse().unwrap()],
+            ..RateLimit::default()
+        })
+    }
+
+    fn client(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = limiter(0);
+        let start = Instant::now();
+        let www = reply("www.example.com", rcode::NOERROR);
+        let check = |addr: &str, reply: &Message, secs: u64| {
+            limiter.check(client(addr), reply, start + Duration::from_secs(secs))
+        };
+
+        assert_eq!(Action::Send, check("198.51.100.1", &www, 0));
+        assert_eq!(Action::Send, check("198.51.100.1", &www, 0));
+        assert_eq!(Action::Drop, check("198.51.100.1", &www, 0));
+        // the same network shares the bucket, other names and networks don't
+        assert_eq!(Action::Drop, check("198.51.100.200", &www, 0));
+        assert_eq!(Action::Send, check("198.51.101.1", &www, 0));
+        assert_eq!(
+            Action::Send,
+            check(
+                "198.51.100.1",
+                &reply("mail.example.com", rcode::NOERROR),
+                0
+            )
+        );
+        assert_eq!(Action::Send, check("192.0.2.53", &www, 0));
+
+        // a second earns the rate back, once out of debt
+        assert_eq!(Action::Drop, check("198.51.100.1", &www, 1));
+        assert_eq!(Action::Send, check("198.51.100.1", &www, 3));
+        assert_eq!(Action::Send, check("198.51.100.1", &www, 3));
+        assert_eq!(Action::Drop, check("198.51.100.1", &www, 3));
+
+        // negative answers count per zone, errors per network
+        let nope = reply("nope.example.com", rcode::NXDOMAIN);
+        let other = reply("other.example.com", rcode::NXDOMAIN);
+        assert_eq!(Action::Send, check("203.0.113.1", &nope, 0));
+        assert_eq!(Action::Send, check("203.0.113.1", &other, 0));
+        assert_eq!(Action::Drop, check("203.0.113.1", &nope, 0));
+        let refused = reply("a.example.org", rcode::REFUSED);
+        assert_eq!(Action::Send, check("2001:db8::1", &refused, 0));
+        assert_eq!(
+            Action::Send,
+            check("2001:db8::2", &reply("b.example.org", rcode::SERVFAIL), 0)
+        );
+        assert_eq!(Action::Drop, check("2001:db8:0:ff::1", &refused, 0));
+        assert_eq!(Action::Send, check("2001:db8:0:100::1", &refused, 0));
+    }
+
+    #[test]
+    fn test_slip() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let www = reply("www.example.com", rcode::NOERROR);
+        let actions: Vec<Action> = (0..6)
+            .map(|_| limiter.check(client("198.51.100.1"), &www, now))
+            .collect();
+        assert_eq!(
+            vec![
+                Action::Send,
+                Action::Send,
+                Action::Drop,
+                Action::Slip,
+                Action::Drop,
+                Action::Slip
+            ],
+            actions
+        );
+        assert_eq!(
+            "2 responses dropped and 2 slipped by rate limiting",
+            limiter.summary()
+        );
+
+        // disabled
+        let limiter = RateLimiter::new(RateLimit::default());
+        assert!((0..10).all(|_| limiter.check(client("198.51.100.1"), &www, now) == Action::Send));
+    }
+}
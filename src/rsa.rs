@@ -0,0 +1,239 @@
+//! RSA signature verification, PKCS #1 v1.5 (RFC 8017, section 8.2.2), for
+//! DNSSEC algorithms 5, 7, 8 and 10 (RFC 3110, RFC 5702) and certificates,
+//! and RSASSA-PSS (section 8.1.2) for TLS 1.3 handshakes.
+
+use crate::{
+    bignum::BigUint,
+    digest::{sha1, sha256, sha384, sha512},
+};
+
+/// Hash a signature was made over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hash {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Hash {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => sha1(data).to_vec(),
+            Self::Sha256 => sha256(data).to_vec(),
+            Self::Sha384 => sha384(data).to_vec(),
+            Self::Sha512 => sha512(data).to_vec(),
+        }
+    }
+
+    /// DER encoded DigestInfo, up to the digest itself (RFC 8017, section
+    /// 9.2, note 1).
+    fn digest_info(&self, data: &[u8]) -> Vec<u8> {
+        let prefix: &[u8] = match self {
+            Self::Sha1 => &[
+                0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04,
+                0x14,
+            ],
+            Self::Sha256 => &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            Self::Sha384 => &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+            Self::Sha512 => &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+        };
+        [prefix, &self.digest(data)].concat()
+    }
+
+    /// Mask generation function MGF1 (RFC 8017, appendix B.2.1).
+    fn mgf1(&self, seed: &[u8], len: usize) -> Vec<u8> {
+        let mut mask = Vec::with_capacity(len);
+        let mut counter = 0u32;
+        while mask.len() < len {
+            mask.extend(self.digest(&[seed, &counter.to_be_bytes()].concat()));
+            counter += 1;
+        }
+        mask.truncate(len);
+        mask
+    }
+}
+
+/// RSA public key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    modulus: BigUint,
+    exponent: BigUint,
+}
+
+impl PublicKey {
+    /// Key from the big-endian modulus and exponent, as in certificates.
+    pub fn new(modulus: &[u8], exponent: &[u8]) -> Self {
+        Self {
+            modulus: BigUint::from_be_bytes(modulus),
+            exponent: BigUint::from_be_bytes(exponent),
+        }
+    }
+
+    /// Splits a public key in the DNSKEY format of RFC 3110, section 2: the
+    /// exponent length in one byte, or in three starting with a zero, then
+    /// the exponent and the modulus.
+    pub fn from_dnskey(key: &[u8]) -> Option<Self> {
+        let (len, rest) = match key.split_first()? {
+            (0, rest) => {
+                let (len, rest) = rest.split_at_checked(2)?;
+                (u16::from_be_bytes([len[0], len[1]]) as usize, rest)
+            }
+            (len, rest) => (*len as usize, rest),
+        };
+        let (exponent, modulus) = rest.split_at_checked(len)?;
+        Some(Self::new(modulus, exponent))
+    }
+
+    /// Bits of the modulus.
+    pub fn bits(&self) -> usize {
+        self.modulus.bits()
+    }
+
+    /// The signature raised to the exponent as `len` bytes, the length of
+    /// the modulus, or `None` for keys and signatures out of range.
+    fn open(&self, signature: &[u8]) -> Option<Vec<u8>> {
+        let len = self.modulus.bits().div_ceil(8);
+        let s = BigUint::from_be_bytes(signature);
+        if self.exponent.is_zero() || len < 64 || signature.len() != len || s >= self.modulus {
+            return None;
+        }
+        Some(s.mod_pow(&self.exponent, &self.modulus).to_be_bytes(len))
+    }
+
+    /// Whether `signature` is a valid PKCS #1 v1.5 signature of `data`.
+    pub fn verify_pkcs1(&self, hash: Hash, data: &[u8], signature: &[u8]) -> bool {
+        let Some(em) = self.open(signature) else {
+            return false;
+        };
+        // EM = 0x00 || 0x01 || 0xff... || 0x00 || DigestInfo
+        let digest_info = hash.digest_info(data);
+        let Some(padding) = em.len().checked_sub(digest_info.len() + 3) else {
+            return false;
+        };
+        let mut expected = vec![0x00, 0x01];
+        expected.extend(std::iter::repeat_n(0xff, padding));
+        expected.push(0x00);
+        expected.extend(digest_info);
+        em == expected
+    }
+
+    /// Whether `signature` is a valid RSASSA-PSS signature of `data`, with
+    /// MGF1 over the same hash and a salt as long as the digest, the only
+    /// parameters TLS 1.3 allows (RFC 8446, section 4.2.3).
+    pub fn verify_pss(&self, hash: Hash, data: &[u8], signature: &[u8]) -> bool {
+        let Some(em) = self.open(signature) else {
+            return false;
+        };
+        // EMSA-PSS-VERIFY (RFC 8017, section 9.1.2), emBits = modBits - 1
+        let em_bits = self.modulus.bits() - 1;
+        let (high, em) = em.split_at(em.len() - em_bits.div_ceil(8));
+        if high.iter().any(|b| *b != 0) {
+            return false;
+        }
+        let digest = hash.digest(data);
+        let h_len = digest.len();
+        let Some(db_len) = em.len().checked_sub(h_len + 1) else {
+            return false;
+        };
+        if db_len < h_len + 1 || em[em.len() - 1] != 0xbc {
+            return false;
+        }
+        let (masked_db, h) = (&em[..db_len], &em[db_len..em.len() - 1]);
+        let top = 0xffu8 >> (8 * em.len() - em_bits);
+        if masked_db[0] & !top != 0 {
+            return false;
+        }
+        let mut db: Vec<u8> = masked_db
+            .iter()
+            .zip(hash.mgf1(h, db_len))
+            .map(|(a, b)| a ^ b)
+            .collect();
+        db[0] &= top;
+        let (padding, salt) = db.split_at(db_len - h_len);
+        let (one, zeros) = padding.split_last().unwrap();
+        if *one != 0x01 || zeros.iter().any(|b| *b != 0) {
+            return false;
+        }
+        hash.digest(&[&[0; 8], &digest[..], salt].concat()) == h
+    }
+}
+
+/// Whether `signature` is a valid signature of `data` under the DNSKEY
+/// public key `key`.
+pub fn verify(hash: Hash, key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    PublicKey::from_dnskey(key).is_some_and(|key| key.verify_pkcs1(hash, data, signature))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify, Hash, PublicKey};
+    use crate::encoding::hex_decode;
+
+    // 1024 bit key and signatures of "test data" made with OpenSSL
+    const MODULUS: &str = "\
+        cc1c8ec912ceb151a76a7842dbba2ad14f93ebecfcd5522ef682e310434fad619a3aeb8f23c3d71c3f46bf\
+        c5624c91434796b27807c79b64055b4137df513267590f8052f1299184935b7e632a755b79c0531ef38d77\
+        c56c962a9788bed88206fb63cdd94ef0bfcbfa94cb9e35ab628e7093586659da3179652b2c6c2ef33ca3";
+    const SIG_SHA256: &str = "\
+        635492fe202fb8c17d9cc892477adf81c8d0c8d8a9aadfa3d4f0609af231b8bb833679f92d395731788c51\
+        ef0eb0bdaa96e520acc7c5f2398583aa73c8af66f2ee7993e93e88a849448b3094ecef136a0e8d1428fc8f\
+        6ef2992947cca69e5cfeba8b3b2f1e495856a7f53fdac5b95c9fc79c5b51325472b5e4a362e8b9182c17";
+    const SIG_SHA1: &str = "\
+        60fe3fa0e4f48e6e70d8a4def69d1bbb15bc3664434403b796deca0a0bf8b59946f9633583f1e065798fa0\
+        e7e7b8ee929de8b5db65bdc1f5475a8062e747e1da486335961087c75cf261aad35f9a0b9fc439c4bded64\
+        7af36fedc002fbe740cfa907f77638b0eb968f4bde43eea5cfb07bda0002f5be6c578c442a1a5bf4f56e";
+    // PSS signature of "test data" with SHA-256 and a 32 byte salt
+    const PSS_MODULUS: &str = "\
+        b61257d3f79e6093fef497d61596256b89faf5d45eabaa169b7232ef8851078bfa6e9907124e6f3cf1e8b3\
+        4bb7fd425ae6fc949e3d4dc379c686090397cc066a8b75d0544fd77f00074d5e5d7a86a228a430d657e806\
+        49053f44907d23d5df70a6a08df6e0d58a142c953c6d4ba7792722c6cb34a9ffb8801610e1d2ad2505f9";
+    const SIG_PSS: &str = "\
+        00ed5ffe9c52f5924df64fad175c69d752fb2d0c556bbaed8c3737285e7bf994282ef8f0974273268a7187\
+        8d45c409d6c24b9887e2a4421e57a2bb5e3eebe2655f1c83d783376b80a6aef14090aad42173ff2bfe5746\
+        68c5f08e4e5c9b0048d457a586270f9ca7c19b146686f980de7c21153bd8789e9ef5a5c7cc81cf243d3c";
+
+    fn key() -> Vec<u8> {
+        let mut key = vec![3, 0x01, 0x00, 0x01];
+        key.extend(hex_decode(MODULUS).unwrap());
+        key
+    }
+
+    #[test]
+    fn test_verify() {
+        let sig = hex_decode(SIG_SHA256).unwrap();
+        assert!(verify(Hash::Sha256, &key(), b"test data", &sig));
+        assert!(!verify(Hash::Sha256, &key(), b"test date", &sig));
+        assert!(!verify(Hash::Sha512, &key(), b"test data", &sig));
+        let mut bad = sig.clone();
+        bad[10] ^= 1;
+        assert!(!verify(Hash::Sha256, &key(), b"test data", &bad));
+        assert!(!verify(Hash::Sha256, &key()[..40], b"test data", &sig));
+
+        let sig = hex_decode(SIG_SHA1).unwrap();
+        assert!(verify(Hash::Sha1, &key(), b"test data", &sig));
+        assert!(!verify(Hash::Sha256, &key(), b"test data", &sig));
+    }
+
+    #[test]
+    fn test_verify_pss() {
+        let key = PublicKey::new(&hex_decode(PSS_MODULUS).unwrap(), &[1, 0, 1]);
+        let sig = hex_decode(SIG_PSS).unwrap();
+        assert!(key.verify_pss(Hash::Sha256, b"test data", &sig));
+        assert!(!key.verify_pss(Hash::Sha256, b"test date", &sig));
+        assert!(!key.verify_pss(Hash::Sha384, b"test data", &sig));
+        assert!(!key.verify_pkcs1(Hash::Sha256, b"test data", &sig));
+        let mut bad = sig.clone();
+        bad[100] ^= 1;
+        assert!(!key.verify_pss(Hash::Sha256, b"test data", &bad));
+    }
+}
@@ -0,0 +1,280 @@
+//! XSalsa20-Poly1305, the `crypto_box` construction of NaCl that DNSCrypt
+//! encrypts queries and replies with: the Salsa20 stream cipher (extended to
+//! 24-byte nonces through HSalsa20) and the Poly1305 one-time authenticator
+//! (RFC 8439, section 2.5), keyed with an X25519 shared secret.
+
+use crate::x25519;
+
+/// Length of the authentication tag prepended to ciphertexts.
+pub const TAG_LEN: usize = 16;
+
+/// Length of XSalsa20 nonces.
+pub const NONCE_LEN: usize = 24;
+
+// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().unwrap())
+}
+
+/// The 20 rounds of the Salsa20 core on `input`, without the final addition.
+fn rounds(input: &[u32; 16]) -> [u32; 16] {
+    fn quarter(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+    let mut x = *input;
+    for _ in 0..10 {
+        // columns
+        quarter(&mut x, 0, 4, 8, 12);
+        quarter(&mut x, 5, 9, 13, 1);
+        quarter(&mut x, 10, 14, 2, 6);
+        quarter(&mut x, 15, 3, 7, 11);
+        // rows
+        quarter(&mut x, 0, 1, 2, 3);
+        quarter(&mut x, 5, 6, 7, 4);
+        quarter(&mut x, 10, 11, 8, 9);
+        quarter(&mut x, 15, 12, 13, 14);
+    }
+    x
+}
+
+/// Initial state for `key` and the 16 bytes of nonce and counter.
+fn state(key: &[u8; 32], input: &[u8; 16]) -> [u32; 16] {
+    let mut s = [0; 16];
+    s[0] = SIGMA[0];
+    s[5] = SIGMA[1];
+    s[10] = SIGMA[2];
+    s[15] = SIGMA[3];
+    for i in 0..4 {
+        s[1 + i] = le32(&key[4 * i..]);
+        s[11 + i] = le32(&key[16 + 4 * i..]);
+        s[6 + i] = le32(&input[4 * i..]);
+    }
+    s
+}
+
+/// HSalsa20, derives a key from `key` and a 16-byte `input` (section 2 of
+/// "Extending the Salsa20 nonce").
+pub fn hsalsa20(key: &[u8; 32], input: &[u8; 16]) -> [u8; 32] {
+    let x = rounds(&state(key, input));
+    let mut out = [0; 32];
+    for (chunk, i) in out.chunks_mut(4).zip([0, 5, 10, 15, 6, 7, 8, 9]) {
+        chunk.copy_from_slice(&x[i].to_le_bytes());
+    }
+    out
+}
+
+/// XORs `data` with the XSalsa20 keystream of `key` and `nonce`.
+fn xsalsa20_xor(key: &[u8; 32], nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+    let subkey = hsalsa20(key, nonce[..16].try_into().unwrap());
+    let mut input = [0; 16];
+    input[..8].copy_from_slice(&nonce[16..]);
+    for (counter, chunk) in data.chunks_mut(64).enumerate() {
+        input[8..].copy_from_slice(&(counter as u64).to_le_bytes());
+        let s = state(&subkey, &input);
+        let x = rounds(&s);
+        let block: Vec<u8> = x
+            .iter()
+            .zip(s)
+            .flat_map(|(x, s)| x.wrapping_add(s).to_le_bytes())
+            .collect();
+        chunk.iter_mut().zip(block).for_each(|(b, k)| *b ^= k);
+    }
+}
+
+/// Poly1305 tag of `message` under the one-time `key`, in 26-bit limbs.
+pub fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; TAG_LEN] {
+    const MASK: u32 = 0x3ffffff;
+    let r = [
+        le32(&key[0..]) & 0x3ffffff,
+        (le32(&key[3..]) >> 2) & 0x3ffff03,
+        (le32(&key[6..]) >> 4) & 0x3ffc0ff,
+        (le32(&key[9..]) >> 6) & 0x3f03fff,
+        (le32(&key[12..]) >> 8) & 0x00fffff,
+    ];
+    let s = [r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+    let mut h = [0u32; 5];
+
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+        h[0] += le32(&block[0..]) & MASK;
+        h[1] += (le32(&block[3..]) >> 2) & MASK;
+        h[2] += (le32(&block[6..]) >> 4) & MASK;
+        h[3] += (le32(&block[9..]) >> 6) & MASK;
+        h[4] += (le32(&block[12..]) >> 8) | (block[16] as u32) << 24;
+
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m(h[0], r[0]) + m(h[1], s[3]) + m(h[2], s[2]) + m(h[3], s[1]) + m(h[4], s[0]);
+        let d1 = m(h[0], r[1]) + m(h[1], r[0]) + m(h[2], s[3]) + m(h[3], s[2]) + m(h[4], s[1]);
+        let d2 = m(h[0], r[2]) + m(h[1], r[1]) + m(h[2], r[0]) + m(h[3], s[3]) + m(h[4], s[2]);
+        let d3 = m(h[0], r[3]) + m(h[1], r[2]) + m(h[2], r[1]) + m(h[3], r[0]) + m(h[4], s[3]);
+        let d4 = m(h[0], r[4]) + m(h[1], r[3]) + m(h[2], r[2]) + m(h[3], r[1]) + m(h[4], r[0]);
+
+        let mut carry = 0;
+        for (h, d) in h.iter_mut().zip([d0, d1, d2, d3, d4]) {
+            let d = d + carry;
+            *h = d as u32 & MASK;
+            carry = d >> 26;
+        }
+        h[0] += carry as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= MASK;
+    }
+
+    // fully reduce h modulo 2^130 - 5
+    let mut carry = 0;
+    for limb in h.iter_mut().skip(1) {
+        *limb += carry;
+        carry = *limb >> 26;
+        *limb &= MASK;
+    }
+    h[0] += carry * 5;
+    h[1] += h[0] >> 26;
+    h[0] &= MASK;
+
+    // h - p, taken if it doesn't underflow
+    let mut g = [0u32; 5];
+    let mut carry = 5;
+    for i in 0..4 {
+        let sum = h[i] + carry;
+        g[i] = sum & MASK;
+        carry = sum >> 26;
+    }
+    g[4] = (h[4] + carry).wrapping_sub(1 << 26);
+    let take_g = (g[4] >> 31).wrapping_sub(1);
+    for (h, g) in h.iter_mut().zip(g) {
+        *h = (*h & !take_g) | (g & take_g);
+    }
+
+    let words = [
+        h[0] | h[1] << 26,
+        h[1] >> 6 | h[2] << 20,
+        h[2] >> 12 | h[3] << 14,
+        h[3] >> 18 | h[4] << 8,
+    ];
+    let mut tag = [0; TAG_LEN];
+    let mut f = 0u64;
+    for i in 0..4 {
+        f = words[i] as u64 + le32(&key[16 + 4 * i..]) as u64 + (f >> 32);
+        tag[4 * i..4 * i + 4].copy_from_slice(&(f as u32).to_le_bytes());
+    }
+    tag
+}
+
+/// Encrypts and authenticates `plaintext` (`crypto_secretbox`), returning
+/// the tag followed by the ciphertext.
+pub fn seal(key: &[u8; 32], nonce: &[u8; NONCE_LEN], plaintext: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 32];
+    buf.extend_from_slice(plaintext);
+    xsalsa20_xor(key, nonce, &mut buf);
+    let poly_key: [u8; 32] = buf[..32].try_into().unwrap();
+    let mut sealed = poly1305(&poly_key, &buf[32..]).to_vec();
+    sealed.extend_from_slice(&buf[32..]);
+    sealed
+}
+
+/// Verifies and decrypts the output of `seal`, `None` if it was altered.
+pub fn open(key: &[u8; 32], nonce: &[u8; NONCE_LEN], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < TAG_LEN {
+        return None;
+    }
+    let (tag, ciphertext) = sealed.split_at(TAG_LEN);
+    let mut poly_key = [0; 32];
+    xsalsa20_xor(key, nonce, &mut poly_key);
+    if !crate::digest::constant_time_eq(&poly1305(&poly_key, ciphertext), tag) {
+        return None;
+    }
+    let mut buf = vec![0; 32];
+    buf.extend_from_slice(ciphertext);
+    xsalsa20_xor(key, nonce, &mut buf);
+    buf.drain(..32);
+    Some(buf)
+}
+
+/// Key shared by the holders of `private` and of the private key of
+/// `public` (`crypto_box_beforenm`). `None` for small-order points.
+pub fn box_key(private: &[u8; 32], public: &[u8; 32]) -> Option<[u8; 32]> {
+    let shared = x25519::shared_secret(private, public);
+    if shared == [0; 32] {
+        return None;
+    }
+    Some(hsalsa20(&shared, &[0; 16]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{box_key, hsalsa20, open, poly1305, seal};
+    use crate::encoding::{hex_decode, hex_encode};
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        hex_decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_poly1305() {
+        // RFC 8439, section 2.5.2
+        let key = bytes("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        assert_eq!(
+            "a8061dc1305136c6c22b8baf0c0127a9",
+            hex_encode(&poly1305(&key, b"Cryptographic Forum Research Group"))
+        );
+    }
+
+    #[test]
+    fn test_hsalsa20() {
+        // NaCl's core1 and core2 tests: the X25519 secret of RFC 7748,
+        // section 6.1 hashed into the box key, then into an XSalsa20 subkey
+        let shared = bytes("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        let first = hsalsa20(&shared, &[0; 16]);
+        assert_eq!(
+            "1b27556473e985d462cd51197a9a46c76009549eac6474f206c4ee0844f68389",
+            hex_encode(&first)
+        );
+        assert_eq!(
+            "dc908dda0b9344a953629b733820778880f3ceb421bb61b91cbd4c3e66256ce4",
+            hex_encode(&hsalsa20(
+                &first,
+                &bytes("69696ee955b62b73cd62bda875fc73d6")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_box() {
+        // NaCl's box test
+        let alice = bytes("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = bytes("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let nonce = bytes("69696ee955b62b73cd62bda875fc73d68219e0036b7a0b37");
+        let message = hex_decode(
+            "be075fc53c81f2d5cf141316ebeb0c7b5228c52a4c62cbd44b66849b64244ffc\
+             e5ecbaaf33bd751a1ac728d45e6c61296cdc3c01233561f41db66cce314adb31\
+             0e3be8250c46f06dceea3a7fa1348057e2f6556ad6b1318a024a838f21af1fde\
+             048977eb48f59ffd4924ca1c60902e52f0a089bc76897040e082f93776384864\
+             5e0705",
+        )
+        .unwrap();
+        let key = box_key(&alice, &bob).unwrap();
+        let sealed = seal(&key, &nonce, &message);
+        assert_eq!(
+            "f3ffc7703f9400e52a7dfb4b3d3305d98e993b9f48681273c29650ba32fc76ce\
+             48332ea7164d96a4476fb8c531a1186ac0dfc17c98dce87b4da7f011ec48c972\
+             71d2c20f9b928fe2270d6fb863d51738b48eeee314a7cc8ab932164548e526ae\
+             90224368517acfeabd6bb3732bc0e9da99832b61ca01b6de56244a9e88d5f9b3\
+             7973f622a43d14a6599b1f654cb45a74e355a5",
+            hex_encode(&sealed)
+        );
+        assert_eq!(Some(message), open(&key, &nonce, &sealed));
+
+        let mut tampered = sealed.clone();
+        tampered[40] ^= 1;
+        assert_eq!(None, open(&key, &nonce, &tampered));
+        assert_eq!(None, open(&key, &nonce, &sealed[..10]));
+        assert!(box_key(&alice, &[0; 32]).is_none());
+    }
+}
@@ -0,0 +1,423 @@
+//! Secondary zones: copies of zones served by a primary server, pulled with
+//! AXFR (RFC 5936) and kept current with the SOA timers (RFC 1035, section
+//! 4.3.5).
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex, RwLock, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+
+use crate::{
+    acl::Network,
+    proto::{rcode, Class, Message, Name, Question, Record, Type},
+    rdata::RData,
+    zone::Zone,
+};
+
+/// How long to wait before trying again when the zone was never
+/// transferred, so there is no SOA retry timer yet.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// Limit for each read from the primary during a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A zone transferred from its primary. Until the first transfer succeeds,
+/// and after the data expired, there is no zone to answer from.
+pub struct Secondary {
+    pub origin: Name,
+    primary: SocketAddr,
+    // clients allowed to transfer the zone on from here
+    allow_transfer: Vec<Network>,
+    transfer_keys: Vec<Name>,
+    // for connecting to the primary
+    timeout: Duration,
+    zone: RwLock<Option<Arc<Zone>>>,
+    // the data is dropped at this point unless the primary was reached
+    expires: Mutex<Option<Instant>>,
+    // set to refresh before the timer is up
+    wake: Mutex<bool>,
+    woken: Condvar,
+}
+
+impl Secondary {
+    /// Transfers the zone once, then keeps refreshing it on a thread for as
+    /// long as the returned handle is alive.
+    pub fn start(
+        origin: Name,
+        primary: SocketAddr,
+        allow_transfer: Vec<Network>,
+        transfer_keys: Vec<Name>,
+        timeout: Duration,
+    ) -> Arc<Self> {
+        let secondary = Arc::new(Self {
+            origin,
+            primary,
+            allow_transfer,
+            transfer_keys,
+            timeout,
+            zone: RwLock::new(None),
+            expires: Mutex::new(None),
+            wake: Mutex::new(false),
+            woken: Condvar::new(),
+        });
+        let delay = secondary.refresh();
+
+        let weak = Arc::downgrade(&secondary);
+        thread::spawn(move || refresh_loop(weak, delay));
+        secondary
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Current data, `None` before the first transfer and once expired.
+    pub fn zone(&self) -> Option<Arc<Zone>> {
+        self.zone.read().unwrap().clone()
+    }
+
+    /// Checks the primary right away instead of waiting for the refresh
+    /// timer.
+    pub fn refresh_now(&self) {
+        *self.wake.lock().unwrap() = true;
+        self.woken.notify_all();
+    }
+
+    /// Transfers the zone if the primary has a newer serial. Returns how
+    /// long to wait before the next check.
+    fn refresh(&self) -> Duration {
+        let current = self.zone();
+        match self.try_refresh(current.as_ref()) {
+            Ok(zone) => {
+                let timers = Timers::of(&zone);
+                *self.expires.lock().unwrap() = Some(Instant::now() + timers.expire);
+                *self.zone.write().unwrap() = Some(zone);
+                timers.refresh
+            }
+            Err(e) => {
+                tracing::warn!(
+                    zone = %self.origin, primary = %self.primary, error = %format!("{:#}", e),
+                    "Failed to refresh zone"
+                );
+                let Some(zone) = current else {
+                    return INITIAL_RETRY;
+                };
+                let expired = self
+                    .expires
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|at| at <= Instant::now());
+                if expired {
+                    tracing::error!(zone = %self.origin, "Zone expired");
+                    *self.zone.write().unwrap() = None;
+                    return INITIAL_RETRY;
+                }
+                Timers::of(&zone).retry
+            }
+        }
+    }
+
+    fn try_refresh(&self, current: Option<&Arc<Zone>>) -> Result<Arc<Zone>> {
+        if let Some(current) = current {
+            let serial = query_serial(self.primary, &self.origin, self.timeout)?;
+            if !serial_newer(serial, zone_serial(current)) {
+                return Ok(current.clone());
+            }
+        }
+        let records = fetch(self.primary, &self.origin, self.timeout)?;
+        let mut zone = Zone::from_transfer(&self.origin, records, self.allow_transfer.clone())
+            .map_err(anyhow::Error::msg)?;
+        zone.transfer_keys = self.transfer_keys.clone();
+        tracing::info!(
+            zone = %self.origin, serial = %zone_serial(&zone), primary = %self.primary,
+            "Transferred zone"
+        );
+        Ok(Arc::new(zone))
+    }
+
+    /// Waits for `delay` or until `refresh_now` is called.
+    fn wait(&self, delay: Duration) {
+        let wake = self.wake.lock().unwrap();
+        let (mut wake, _) = self
+            .woken
+            .wait_timeout_while(wake, delay, |wake| !*wake)
+            .unwrap();
+        *wake = false;
+    }
+}
+
+fn refresh_loop(secondary: Weak<Secondary>, mut delay: Duration) {
+    loop {
+        let Some(secondary) = secondary.upgrade() else {
+            return;
+        };
+        secondary.wait(delay);
+        // replaced by a reload while waiting
+        if Arc::strong_count(&secondary) == 1 {
+            return;
+        }
+        delay = secondary.refresh();
+    }
+}
+
+/// SOA timers of a zone.
+struct Timers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+impl Timers {
+    fn of(zone: &Zone) -> Self {
+        let secs = |s: u32| Duration::from_secs(s.max(1) as u64);
+        match &zone.soa().rdata {
+            RData::SOA(soa) => Self {
+                refresh: secs(soa.refresh),
+                retry: secs(soa.retry),
+                expire: secs(soa.expire),
+            },
+            _ => Self {
+                refresh: INITIAL_RETRY,
+                retry: INITIAL_RETRY,
+                expire: INITIAL_RETRY,
+            },
+        }
+    }
+}
+
+fn zone_serial(zone: &Zone) -> u32 {
+    match &zone.soa().rdata {
+        RData::SOA(soa) => soa.serial,
+        _ => 0,
+    }
+}
+
+/// Whether serial `a` is later than `b` in serial number arithmetic
+/// (RFC 1982, section 3.2).
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Asks `primary` for the SOA serial of the zone at `origin`.
+fn query_serial(primary: SocketAddr, origin: &Name, timeout: Duration) -> Result<u32> {
+    let mut stream = connect(primary, timeout)?;
+    let id = send_query(&mut stream, origin, Type::SOA)?;
+    let reply = read_reply(&mut stream, id, primary)?;
+    match reply.answers.iter().find(|r| r.rtype == Type::SOA) {
+        Some(Record {
+            rdata: RData::SOA(soa),
+            ..
+        }) => Ok(soa.serial),
+        _ => bail!("no SOA for {} from {}", origin, primary),
+    }
+}
+
+/// Transfers the zone at `origin` from `primary`, returning its records in
+/// the order received: the SOA first and last (RFC 5936, section 2.2).
+pub fn fetch(primary: SocketAddr, origin: &Name, timeout: Duration) -> Result<Vec<Record>> {
+    let mut stream = connect(primary, timeout)?;
+    stream.set_read_timeout(Some(TRANSFER_TIMEOUT))?;
+    let id = send_query(&mut stream, origin, Type::AXFR)?;
+
+    let mut records: Vec<Record> = Vec::new();
+    loop {
+        let reply = read_reply(&mut stream, id, primary)?;
+        if reply.answers.is_empty() {
+            bail!("empty transfer message from {}", primary);
+        }
+        records.extend(reply.answers);
+        if records[0].rtype != Type::SOA {
+            bail!("transfer from {} doesn't start with the SOA", primary);
+        }
+        if records.len() > 1 && records[records.len() - 1].rtype == Type::SOA {
+            return Ok(records);
+        }
+    }
+}
+
+fn connect(addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+fn send_query(stream: &mut TcpStream, name: &Name, qtype: Type) -> Result<u16> {
+    let id = rand::thread_rng().gen();
+    let framed = Message {
+        id,
+        questions: vec![Question {
+            name: name.clone(),
+            qtype,
+            class: Class::IN,
+        }],
+        ..Message::default()
+    }
+    .to_tcp_bytes()?;
+    stream.write_all(&framed)?;
+    Ok(id)
+}
+
+fn read_reply(stream: &mut TcpStream, id: u16, primary: SocketAddr) -> Result<Message> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+    let reply = Message::from_bytes(&buf)?;
+    if reply.id != id || reply.qr != 1 {
+        bail!("unexpected reply from {}", primary);
+    }
+    if reply.rcode != rcode::NOERROR {
+        bail!("{} answered with rcode {}", primary, reply.rcode);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fetch, serial_newer, Secondary};
+    use crate::{
+        encode_tcp_reply,
+        handler::{Chain, Context, Transport},
+        proto::{opcode, rcode, Message, Name, Question, Type},
+        zone::{Authoritative, Zone},
+    };
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener},
+        thread,
+        time::Duration,
+    };
+
+    /// Serves `zone` over TCP to anyone, returns its address.
+    fn primary(zone: &str) -> SocketAddr {
+        let mut zone = Zone::parse(zone, None, None).unwrap();
+        zone.allow_transfer = vec!["127.0.0.1".parse().unwrap()];
+        let chain = Chain::default().with(Authoritative::new(vec![zone]));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).unwrap();
+                let ctx = Context {
+                    source: stream.peer_addr().unwrap(),
+                    transport: Transport::Tcp,
+                    key: None,
+                };
+                let reply = chain
+                    .handle(&ctx, Message::from_bytes(&buf).unwrap())
+                    .unwrap();
+                stream
+                    .write_all(&encode_tcp_reply(reply, None).unwrap())
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn zone_text(hosts: usize) -> String {
+        let mut text = String::from("$ORIGIN example.com.\n@ 60 SOA ns1 hm 7 3600 600 86400 60\n");
+        for i in 0..hosts {
+            text.push_str(&format!("host{} 60 A 192.0.2.{}\n", i, i % 256));
+        }
+        text
+    }
+
+    #[test]
+    fn test_serial_newer() {
+        assert!(serial_newer(2, 1));
+        assert!(!serial_newer(1, 1));
+        assert!(!serial_newer(1, 2));
+        // wraps around
+        assert!(serial_newer(1, u32::MAX));
+    }
+
+    #[test]
+    fn test_fetch() {
+        let addr = primary(&zone_text(2000));
+        let origin = Name::from("example.com");
+        let records = fetch(addr, &origin, Duration::from_secs(1)).unwrap();
+        assert_eq!(2002, records.len());
+
+        let secondary =
+            Secondary::start(origin, addr, Vec::new(), Vec::new(), Duration::from_secs(1));
+        let zone = secondary.zone().unwrap();
+        let lookup = zone.lookup(&Question {
+            name: Name::from("host42.example.com"),
+            qtype: Type::A,
+            ..Question::default()
+        });
+        assert_eq!(1, lookup.answers.len());
+        // nobody may transfer the copy unless configured
+        assert!(!zone.allows_transfer("127.0.0.1".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn test_unreachable_primary() {
+        // bound but not accepting, then closed
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let secondary = Secondary::start(
+            Name::from("example.com"),
+            addr,
+            Vec::new(),
+            Vec::new(),
+            Duration::from_millis(100),
+        );
+        assert!(secondary.zone().is_none());
+
+        // names in the zone fail rather than fall through
+        let chain =
+            Chain::default().with(Authoritative::new(Vec::new()).with_secondaries(vec![secondary]));
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from("www.example.com"),
+                ..Question::default()
+            }],
+            ..Message::default()
+        };
+        assert_eq!(
+            rcode::SERVFAIL,
+            chain.handle(&ctx, request.clone()).unwrap().rcode
+        );
+
+        // NOTIFY is only accepted from the primary
+        let notify = Message {
+            opcode: opcode::NOTIFY,
+            questions: vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::SOA,
+                ..Question::default()
+            }],
+            ..request
+        };
+        let reply = chain.handle(&ctx, notify.clone()).unwrap();
+        assert_eq!(
+            (1, opcode::NOTIFY, rcode::NOERROR),
+            (reply.qr, reply.opcode, reply.rcode)
+        );
+        let stranger = Context {
+            source: "192.0.2.1:5353".parse().unwrap(),
+            ..ctx
+        };
+        let reply = chain.handle(&stranger, notify).unwrap();
+        assert_eq!(rcode::REFUSED, reply.rcode);
+    }
+}
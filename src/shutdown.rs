@@ -0,0 +1,94 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Shutdown state shared by the listeners. Once shutdown is requested they
+/// stop taking new queries, and the queries already being answered are
+/// tracked so the process can wait for them to finish.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    // set from signal handlers, hence shared
+    requested: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Guard counting a query as in flight until it is dropped.
+#[derive(Debug)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    /// Flag to hand to signal handlers that request shutdown.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.requested.clone()
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn track(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.in_flight.clone())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until no queries are in flight or `timeout` passes, returns
+    /// whether everything finished.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shutdown;
+    use std::time::Duration;
+
+    #[test]
+    fn test_in_flight_tracking() {
+        let shutdown = Shutdown::default();
+        let first = shutdown.track();
+        let second = shutdown.track();
+        assert_eq!(2, shutdown.in_flight());
+        assert!(!shutdown.wait_idle(Duration::from_millis(20)));
+
+        drop(first);
+        drop(second);
+        assert_eq!(0, shutdown.in_flight());
+        assert!(shutdown.wait_idle(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_request() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_requested());
+        shutdown.request();
+        assert!(shutdown.is_requested());
+        assert!(shutdown.flag().load(std::sync::atomic::Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,631 @@
+//! Sockets bound with options the standard library can't set before
+//! binding. With SO_REUSEPORT several UDP sockets share one address, and
+//! Linux spreads the packets arriving on it over them by flow, so each can
+//! have a receiver of its own. Listener and upstream sockets get the buffer
+//! sizes, DSCP marking and IPv6-only setting configured. Elsewhere than on
+//! Linux, sockets are bound by the standard library without options.
+//!
+//! Packets are received and sent in batches on Linux, with a single
+//! recvmmsg(2) or sendmmsg(2) each, or one packet at a time where those
+//! aren't available.
+
+use std::{
+    io::{self, ErrorKind},
+    mem::ManuallyDrop,
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    sync::RwLock,
+};
+#[cfg(target_os = "linux")]
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+#[cfg(target_os = "linux")]
+const AF_INET: i32 = 2;
+#[cfg(target_os = "linux")]
+const AF_INET6: i32 = 10;
+const SOCK_STREAM: i32 = 1;
+const SOCK_DGRAM: i32 = 2;
+#[cfg(target_os = "linux")]
+const SOCK_CLOEXEC: i32 = 0o2000000;
+#[cfg(target_os = "linux")]
+const SOL_SOCKET: i32 = 1;
+#[cfg(target_os = "linux")]
+const SO_REUSEADDR: i32 = 2;
+#[cfg(target_os = "linux")]
+const SO_SNDBUF: i32 = 7;
+#[cfg(target_os = "linux")]
+const SO_RCVBUF: i32 = 8;
+#[cfg(target_os = "linux")]
+const SO_REUSEPORT: i32 = 15;
+#[cfg(target_os = "linux")]
+const IPPROTO_IP: i32 = 0;
+#[cfg(target_os = "linux")]
+const IP_TOS: i32 = 1;
+#[cfg(target_os = "linux")]
+const IPPROTO_IPV6: i32 = 41;
+#[cfg(target_os = "linux")]
+const IPV6_V6ONLY: i32 = 26;
+#[cfg(target_os = "linux")]
+const IPV6_TCLASS: i32 = 67;
+#[cfg(target_os = "linux")]
+const MSG_TRUNC: i32 = 0x20;
+#[cfg(target_os = "linux")]
+const MSG_WAITFORONE: i32 = 0x10000;
+
+/// Pending connections a TCP listener queues, as the standard library's.
+#[cfg(target_os = "linux")]
+const BACKLOG: i32 = 128;
+
+/// Packets received or sent with one system call at most.
+pub const BATCH: usize = 32;
+
+/// Size of a sockaddr_storage, room for any address.
+#[cfg(target_os = "linux")]
+const SOCKADDR_SIZE: usize = 128;
+
+/// Set once the kernel turns out not to have recvmmsg or sendmmsg.
+#[cfg(target_os = "linux")]
+static UNBATCHED: AtomicBool = AtomicBool::new(false);
+
+/// Tuning of listener and upstream sockets, the system's defaults where
+/// not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SocketOptions {
+    // SO_RCVBUF and SO_SNDBUF in bytes, doubled by Linux and capped at
+    // net.core.rmem_max and wmem_max
+    pub recv_buffer: Option<u32>,
+    pub send_buffer: Option<u32>,
+    // DSCP codepoint outgoing packets are marked with, 0 to 63
+    pub dscp: Option<u8>,
+    // whether IPv6 sockets on [::] leave IPv4 to other sockets
+    pub ipv6_only: Option<bool>,
+}
+
+static OPTIONS: RwLock<SocketOptions> = RwLock::new(SocketOptions {
+    recv_buffer: None,
+    send_buffer: None,
+    dscp: None,
+    ipv6_only: None,
+});
+
+/// Replaces the options sockets bound from now on get.
+pub fn set_options(options: SocketOptions) {
+    *OPTIONS.write().unwrap() = options;
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn socket(domain: i32, kind: i32, protocol: i32) -> RawFd;
+    fn setsockopt(fd: RawFd, level: i32, name: i32, value: *const u8, len: u32) -> i32;
+    fn bind(fd: RawFd, addr: *const u8, len: u32) -> i32;
+    fn listen(fd: RawFd, backlog: i32) -> i32;
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn recvmmsg(fd: RawFd, msgs: *mut MmsgHdr, len: u32, flags: i32, timeout: *mut u8) -> i32;
+    fn sendmmsg(fd: RawFd, msgs: *mut MmsgHdr, len: u32, flags: i32) -> i32;
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct IoVec {
+    base: *mut u8,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct MsgHdr {
+    name: *mut u8,
+    name_len: u32,
+    iov: *mut IoVec,
+    iov_len: usize,
+    control: *mut u8,
+    control_len: usize,
+    flags: i32,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct MmsgHdr {
+    hdr: MsgHdr,
+    // bytes received or sent
+    len: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl MmsgHdr {
+    fn new(name: &mut [u8], iov: &mut IoVec) -> Self {
+        Self {
+            hdr: MsgHdr {
+                name: name.as_mut_ptr(),
+                name_len: name.len() as u32,
+                iov,
+                iov_len: 1,
+                control: std::ptr::null_mut(),
+                control_len: 0,
+                flags: 0,
+            },
+            len: 0,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check(result: i32) -> io::Result<i32> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+/// `addr` as a sockaddr_in or sockaddr_in6.
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: &SocketAddr) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(28);
+    match addr {
+        SocketAddr::V4(addr) => {
+            raw.extend((AF_INET as u16).to_ne_bytes());
+            raw.extend(addr.port().to_be_bytes());
+            raw.extend(addr.ip().octets());
+            raw.extend([0; 8]);
+        }
+        SocketAddr::V6(addr) => {
+            raw.extend((AF_INET6 as u16).to_ne_bytes());
+            raw.extend(addr.port().to_be_bytes());
+            raw.extend(addr.flowinfo().to_be_bytes());
+            raw.extend(addr.ip().octets());
+            raw.extend(addr.scope_id().to_ne_bytes());
+        }
+    }
+    raw
+}
+
+/// The address in sockaddr `raw`, none if it isn't IPv4 or IPv6.
+#[cfg(target_os = "linux")]
+fn parse_sockaddr(raw: &[u8]) -> Option<SocketAddr> {
+    let family = u16::from_ne_bytes([raw[0], raw[1]]) as i32;
+    let port = u16::from_be_bytes([raw[2], raw[3]]);
+    match family {
+        AF_INET => {
+            let ip: [u8; 4] = raw[4..8].try_into().ok()?;
+            Some(SocketAddrV4::new(Ipv4Addr::from(ip), port).into())
+        }
+        AF_INET6 => {
+            let flowinfo = u32::from_be_bytes(raw[4..8].try_into().ok()?);
+            let ip: [u8; 16] = raw[8..24].try_into().ok()?;
+            let scope_id = u32::from_ne_bytes(raw[24..28].try_into().ok()?);
+            Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id).into())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_option(fd: &impl AsFd, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let value = value.to_ne_bytes();
+    let fd = fd.as_fd().as_raw_fd();
+    // SAFETY: the pointer and length describe `value`, alive for the call
+    check(unsafe { setsockopt(fd, level, name, value.as_ptr(), 4) })?;
+    Ok(())
+}
+
+/// Applies `options` to `fd`, an IPv6 socket if `ipv6`.
+#[cfg(target_os = "linux")]
+fn tune(fd: &impl AsFd, ipv6: bool, options: &SocketOptions) -> io::Result<()> {
+    if let Some(size) = options.recv_buffer {
+        set_option(fd, SOL_SOCKET, SO_RCVBUF, size.min(i32::MAX as u32) as i32)?;
+    }
+    if let Some(size) = options.send_buffer {
+        set_option(fd, SOL_SOCKET, SO_SNDBUF, size.min(i32::MAX as u32) as i32)?;
+    }
+    if let Some(dscp) = options.dscp {
+        let tos = (dscp as i32 & 0x3f) << 2;
+        match ipv6 {
+            true => {
+                set_option(fd, IPPROTO_IPV6, IPV6_TCLASS, tos)?;
+                // for IPv4 clients of a dual-stack socket, where it's allowed
+                let _ = set_option(fd, IPPROTO_IP, IP_TOS, tos);
+            }
+            false => set_option(fd, IPPROTO_IP, IP_TOS, tos)?,
+        }
+    }
+    if let (true, Some(only)) = (ipv6, options.ipv6_only) {
+        set_option(fd, IPPROTO_IPV6, IPV6_V6ONLY, only as i32)?;
+    }
+    Ok(())
+}
+
+/// A socket of `kind` bound to `addr` with the configured options, and
+/// SO_REUSEPORT if `reuseport`. Streams are listened on.
+#[cfg(target_os = "linux")]
+fn bind_with_options(addr: &SocketAddr, kind: i32, reuseport: bool) -> io::Result<OwnedFd> {
+    let domain = match addr {
+        SocketAddr::V4(_) => AF_INET,
+        SocketAddr::V6(_) => AF_INET6,
+    };
+    // SAFETY: a plain system call, the descriptor is owned right away
+    let fd = unsafe { OwnedFd::from_raw_fd(check(socket(domain, kind | SOCK_CLOEXEC, 0))?) };
+    if kind == SOCK_STREAM {
+        // as the standard library does, so restarts don't wait for TIME_WAIT
+        set_option(&fd, SOL_SOCKET, SO_REUSEADDR, 1)?;
+    }
+    if reuseport {
+        set_option(&fd, SOL_SOCKET, SO_REUSEPORT, 1)?;
+    }
+    tune(&fd, addr.is_ipv6(), &OPTIONS.read().unwrap())?;
+    let raw = sockaddr(addr);
+    // SAFETY: the pointer and length describe `raw`, alive for the call
+    check(unsafe { bind(fd.as_raw_fd(), raw.as_ptr(), raw.len() as u32) })?;
+    if kind == SOCK_STREAM {
+        // SAFETY: a plain system call on a descriptor owned here
+        check(unsafe { listen(fd.as_raw_fd(), BACKLOG) })?;
+    }
+    Ok(fd)
+}
+
+/// Elsewhere, options are only checked to be unset, as their values
+/// differ between systems.
+#[cfg(not(target_os = "linux"))]
+fn tune(_: &impl AsFd, _: bool, options: &SocketOptions) -> io::Result<()> {
+    match *options == SocketOptions::default() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "socket options are only set on Linux",
+        )),
+    }
+}
+
+/// A socket of `kind` bound to `addr` by the standard library, which
+/// can't share the address, so not if `reuseport`.
+#[cfg(not(target_os = "linux"))]
+fn bind_with_options(addr: &SocketAddr, kind: i32, reuseport: bool) -> io::Result<OwnedFd> {
+    if reuseport {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "SO_REUSEPORT is only set on Linux",
+        ));
+    }
+    let fd = match kind {
+        SOCK_STREAM => OwnedFd::from(TcpListener::bind(addr)?),
+        _ => OwnedFd::from(UdpSocket::bind(addr)?),
+    };
+    tune(&fd, addr.is_ipv6(), &OPTIONS.read().unwrap())?;
+    Ok(fd)
+}
+
+/// A UDP socket bound to `addr` with the configured options, for upstream
+/// queries.
+pub fn udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    Ok(UdpSocket::from(bind_with_options(addr, SOCK_DGRAM, false)?))
+}
+
+/// Applies the configured buffer sizes and DSCP marking to a connected
+/// `stream`.
+pub fn tune_stream(stream: &TcpStream) -> io::Result<()> {
+    let options = SocketOptions {
+        ipv6_only: None,
+        ..*OPTIONS.read().unwrap()
+    };
+    tune(stream, stream.local_addr()?.is_ipv6(), &options)
+}
+
+/// `count` UDP sockets bound to `addr`, sharing it with SO_REUSEPORT if
+/// there's more than one. A port of 0 is picked for the first and reused
+/// for the others.
+pub fn bind_udp(addr: &SocketAddr, count: usize) -> Result<Vec<UdpSocket>> {
+    let mut addr = *addr;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count.max(1) {
+        let fd = bind_with_options(&addr, SOCK_DGRAM, count > 1)
+            .with_context(|| format!("Failed to bind udp socket to {}", addr))?;
+        let socket = UdpSocket::from(fd);
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+/// A TCP listener on `addr`.
+pub fn bind_tcp(addr: &SocketAddr) -> Result<TcpListener> {
+    let listener = bind_with_options(addr, SOCK_STREAM, false)
+        .with_context(|| format!("Failed to bind tcp listener to {}", addr))?;
+    Ok(TcpListener::from(listener))
+}
+
+/// `socket` as a standard one, for the calls it has.
+fn borrow(socket: &impl AsFd) -> ManuallyDrop<UdpSocket> {
+    // SAFETY: the descriptor stays open while `socket` is borrowed, and
+    // isn't closed when this is dropped
+    ManuallyDrop::new(unsafe { UdpSocket::from_raw_fd(socket.as_fd().as_raw_fd()) })
+}
+
+/// Whether `result` failed for lack of the system call, falling back to
+/// one packet at a time from now on if so.
+#[cfg(target_os = "linux")]
+fn unsupported(result: &io::Result<usize>) -> bool {
+    let unsupported = matches!(result, Err(e) if e.kind() == ErrorKind::Unsupported);
+    if unsupported {
+        UNBATCHED.store(true, Ordering::Relaxed);
+    }
+    unsupported
+}
+
+/// Receives packets into `bufs` with a single recvmmsg(2), none if the
+/// system call isn't available.
+#[cfg(target_os = "linux")]
+fn recv_mmsg(
+    socket: &impl AsFd,
+    bufs: &mut [impl AsMut<[u8]>],
+) -> Option<io::Result<Vec<(usize, usize, SocketAddr)>>> {
+    if UNBATCHED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut names = vec![[0; SOCKADDR_SIZE]; bufs.len()];
+    let mut iovs: Vec<_> = bufs
+        .iter_mut()
+        .map(|buf| {
+            let buf = buf.as_mut();
+            IoVec {
+                base: buf.as_mut_ptr(),
+                len: buf.len(),
+            }
+        })
+        .collect();
+    let mut msgs: Vec<_> = names
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .map(|(name, iov)| MmsgHdr::new(name, iov))
+        .collect();
+    let fd = socket.as_fd().as_raw_fd();
+    let len = msgs.len() as u32;
+    // SAFETY: every header points into `names`, `iovs` and `bufs`, all
+    // alive and unmoved for the call
+    let received = check(unsafe {
+        recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            len,
+            MSG_WAITFORONE,
+            std::ptr::null_mut(),
+        )
+    })
+    .map(|n| n as usize);
+    if unsupported(&received) {
+        return None;
+    }
+    Some(received.map(|received| {
+        msgs[..received]
+            .iter()
+            .zip(names.iter())
+            .enumerate()
+            .filter(|(_, (msg, _))| msg.hdr.flags & MSG_TRUNC == 0)
+            .filter_map(|(i, (msg, name))| {
+                let name = &name[..msg.hdr.name_len as usize];
+                Some((i, msg.len as usize, parse_sockaddr(name)?))
+            })
+            .collect()
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_mmsg(
+    _: &impl AsFd,
+    _: &mut [impl AsMut<[u8]>],
+) -> Option<io::Result<Vec<(usize, usize, SocketAddr)>>> {
+    None
+}
+
+/// Sends `packets` with a single sendmmsg(2), none if the system call
+/// isn't available.
+#[cfg(target_os = "linux")]
+fn send_mmsg(socket: &impl AsFd, packets: &[(&[u8], SocketAddr)]) -> Option<io::Result<usize>> {
+    if UNBATCHED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut names: Vec<_> = packets.iter().map(|(_, dest)| sockaddr(dest)).collect();
+    let mut iovs: Vec<_> = packets
+        .iter()
+        .map(|(packet, _)| IoVec {
+            base: packet.as_ptr() as *mut u8,
+            len: packet.len(),
+        })
+        .collect();
+    let mut msgs: Vec<_> = names
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .map(|(name, iov)| MmsgHdr::new(name, iov))
+        .collect();
+    let fd = socket.as_fd().as_raw_fd();
+    let len = msgs.len() as u32;
+    // SAFETY: as above, the packets are only read from
+    let sent = check(unsafe { sendmmsg(fd, msgs.as_mut_ptr(), len, 0) }).map(|n| n as usize);
+    (!unsupported(&sent)).then_some(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_mmsg(_: &impl AsFd, _: &[(&[u8], SocketAddr)]) -> Option<io::Result<usize>> {
+    None
+}
+
+/// Receives packets into `bufs`, one per buffer, waiting for the first
+/// only as long as `socket` waits for any. Returns the buffer, size and
+/// source of each packet received. Packets that didn't fit their buffer
+/// are dropped: those flagged MSG_TRUNC, or when received one at a time,
+/// those filling it, as there's no telling them apart then.
+pub fn recv_batch(
+    socket: &impl AsFd,
+    bufs: &mut [impl AsMut<[u8]>],
+) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
+    if let Some(received) = recv_mmsg(socket, bufs) {
+        return received;
+    }
+    let Some(buf) = bufs.first_mut() else {
+        return Ok(Vec::new());
+    };
+    let buf = buf.as_mut();
+    let (size, source) = borrow(socket).recv_from(buf)?;
+    match size < buf.len() {
+        true => Ok(vec![(0, size, source)]),
+        false => Ok(Vec::new()),
+    }
+}
+
+/// Sends `packets` in order until one fails, which is never the first if
+/// any were sent. Returns how many were.
+pub fn send_batch(socket: &impl AsFd, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+    if let Some(sent) = send_mmsg(socket, packets) {
+        return sent;
+    }
+    let socket = borrow(socket);
+    for (i, (packet, dest)) in packets.iter().enumerate() {
+        if let Err(e) = socket.send_to(packet, dest) {
+            return match i {
+                0 => Err(e),
+                i => Ok(i),
+            };
+        }
+    }
+    Ok(packets.len())
+}
+
+// the options, batches and SO_REUSEPORT tested are Linux's
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{
+        bind_udp, recv_batch, send_batch, socket, tune, SocketOptions, AF_INET6, IPPROTO_IPV6,
+        IPV6_TCLASS, IPV6_V6ONLY, SOCK_DGRAM, SOL_SOCKET, SO_RCVBUF, UNBATCHED,
+    };
+    use std::{
+        collections::HashSet,
+        net::UdpSocket,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        sync::atomic::Ordering,
+        time::Duration,
+    };
+
+    extern "C" {
+        fn getsockopt(fd: RawFd, level: i32, name: i32, value: *mut u8, len: *mut u32) -> i32;
+    }
+
+    fn get_option(socket: &impl AsRawFd, level: i32, name: i32) -> i32 {
+        let (mut value, mut len) = ([0; 4], 4);
+        // SAFETY: the pointers describe `value` and `len`, alive for the call
+        let result = unsafe {
+            getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                value.as_mut_ptr(),
+                &mut len,
+            )
+        };
+        assert_eq!(0, result);
+        i32::from_ne_bytes(value)
+    }
+
+    #[test]
+    fn test_tune() {
+        // left unbound, IPV6_V6ONLY can't change after
+        // SAFETY: a plain system call, the descriptor is owned right away
+        let socket = unsafe { OwnedFd::from_raw_fd(socket(AF_INET6, SOCK_DGRAM, 0)) };
+        let options = SocketOptions {
+            recv_buffer: Some(32768),
+            send_buffer: None,
+            dscp: Some(46),
+            ipv6_only: Some(true),
+        };
+        tune(&socket, true, &options).unwrap();
+        // Linux doubles it for its bookkeeping
+        assert_eq!(65536, get_option(&socket, SOL_SOCKET, SO_RCVBUF));
+        assert_eq!(46 << 2, get_option(&socket, IPPROTO_IPV6, IPV6_TCLASS));
+        assert_eq!(1, get_option(&socket, IPPROTO_IPV6, IPV6_V6ONLY));
+    }
+
+    #[test]
+    fn test_batches() {
+        for unbatched in [false, true] {
+            // the fallback behaves the same, other tests don't notice
+            UNBATCHED.store(unbatched, Ordering::Relaxed);
+            let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let dest = server.local_addr().unwrap();
+            let packets: Vec<_> = (1..=5).map(|n| vec![n; n as usize]).collect();
+            let batch: Vec<_> = packets.iter().map(|p| (&p[..], dest)).collect();
+            let mut sent = 0;
+            while sent < batch.len() {
+                sent += send_batch(&client, &batch[sent..]).unwrap();
+            }
+
+            server
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let mut bufs = [[0; 16]; 8];
+            let (mut received, mut calls) = (Vec::new(), 0);
+            while received.len() < packets.len() {
+                let batch = recv_batch(&server, &mut bufs).unwrap();
+                calls += 1;
+                for (i, size, source) in batch {
+                    assert_eq!(client.local_addr().unwrap(), source);
+                    received.push(bufs[i][..size].to_vec());
+                }
+            }
+            assert_eq!(packets, received);
+            assert_eq!(if unbatched { 5 } else { 1 }, calls);
+
+            // too large for its buffer, dropped rather than cut short
+            client.send_to(&[9; 17], dest).unwrap();
+            client.send_to(&[3; 3], dest).unwrap();
+            let mut received = Vec::new();
+            while received.is_empty() {
+                received = recv_batch(&server, &mut bufs).unwrap();
+            }
+            let (i, size, _) = received[0];
+            assert_eq!(vec![3; 3], bufs[i][..size]);
+        }
+        UNBATCHED.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_bind_udp() {
+        for addr in ["127.0.0.1:0", "[::1]:0"] {
+            let sockets = bind_udp(&addr.parse().unwrap(), 4).unwrap();
+            let local = sockets[0].local_addr().unwrap();
+            assert_ne!(0, local.port());
+            assert!(sockets.iter().all(|s| s.local_addr().unwrap() == local));
+            // others can't take the address without the option
+            assert!(UdpSocket::bind(local).is_err());
+
+            // flows from different sources are spread over the sockets
+            for socket in sockets.iter() {
+                socket
+                    .set_read_timeout(Some(Duration::from_millis(20)))
+                    .unwrap();
+            }
+            let clients: Vec<_> = (0..32)
+                .map(|_| UdpSocket::bind((local.ip(), 0)).unwrap())
+                .collect();
+            for client in clients.iter() {
+                client.send_to(b"query", local).unwrap();
+            }
+            let mut receiving = HashSet::new();
+            let mut received = 0;
+            for (i, socket) in sockets.iter().enumerate() {
+                while socket.recv(&mut [0; 16]).is_ok() {
+                    receiving.insert(i);
+                    received += 1;
+                }
+            }
+            assert_eq!(32, received);
+            assert!(receiving.len() > 1, "{:?}", receiving);
+        }
+    }
+}
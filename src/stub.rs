@@ -0,0 +1,92 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::Result;
+
+use crate::{
+    handler::local_soa,
+    proto::{rcode, Message, Record, Type},
+    rdata::RData,
+    resolver::Resolver,
+};
+
+/// Answers every A and AAAA question with a fixed address, used when no
+/// upstream resolver is configured.
+pub struct Stub;
+
+impl Resolver for Stub {
+    fn resolve(&self, request: &Message) -> Result<Message> {
+        let answers = request
+            .questions
+            .iter()
+            .filter_map(|q| {
+                let rdata = match q.qtype {
+                    Type::A => RData::A(Ipv4Addr::new(8, 8, 8, 8)),
+                    Type::AAAA => {
+                        RData::AAAA(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888))
+                    }
+                    _ => return None,
+                };
+                Some(Record {
+                    name: q.name.clone(),
+                    rtype: q.qtype,
+                    class: q.class,
+                    ttl: 60,
+                    rdata,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // NODATA, point the client at the SOA for the negative TTL
+        let authorities = match request.questions.first() {
+            Some(q) if answers.is_empty() => vec![local_soa(&q.name)],
+            _ => Vec::new(),
+        };
+
+        Ok(Message {
+            rcode: if request.opcode == 0 {
+                rcode::NOERROR
+            } else {
+                rcode::NOTIMP
+            },
+            answers,
+            authorities,
+            ..request.reply()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Resolver, Stub};
+    use crate::{
+        proto::{rcode, test::request, Message, Type},
+        rdata::RData,
+    };
+
+    #[test]
+    fn test_stub_answers_addresses() {
+        let reply = Stub.resolve(&request("codecrafters.io", Type::A)).unwrap();
+        assert_eq!(7, reply.id);
+        assert_eq!(RData::A([8, 8, 8, 8].into()), reply.answers[0].rdata);
+        assert!(reply.authorities.is_empty());
+    }
+
+    #[test]
+    fn test_stub_nodata() {
+        let reply = Stub.resolve(&request("codecrafters.io", Type::MX)).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert!(reply.answers.is_empty());
+        assert_eq!(Type::SOA, reply.authorities[0].rtype);
+    }
+
+    #[test]
+    fn test_stub_unsupported_opcode() {
+        let reply = Stub
+            .resolve(&Message {
+                opcode: 2,
+                ..request("codecrafters.io", Type::A)
+            })
+            .unwrap();
+        assert_eq!(rcode::NOTIMP, reply.rcode);
+    }
+}
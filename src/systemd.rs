@@ -0,0 +1,174 @@
+//! Running as a systemd service: sockets bound by systemd and passed on
+//! start (socket activation, sd_listen_fds(3)), so binding port 53 needs no
+//! privilege, and the state notifications and watchdog pings of
+//! sd_notify(3) for `Type=notify` units.
+
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, ErrorKind},
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process, thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+/// First descriptor passed, the others follow it.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd.
+#[derive(Debug, Default)]
+pub struct Sockets {
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+}
+
+/// The sockets systemd passed to this process, `None` if it passed none.
+/// The variables passing them are removed, so they're taken only once.
+pub fn listen_fds() -> Result<Option<Sockets>> {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string());
+    let count = env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let (true, Some(count)) = (for_us, count) else {
+        return Ok(None);
+    };
+    let count: RawFd = count
+        .parse()
+        .with_context(|| format!("invalid LISTEN_FDS {:?}", count))?;
+    let mut sockets = Sockets::default();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes these descriptors open to this process,
+        // and the variables are gone so nothing else takes them
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let socket = UdpSocket::from(fd);
+        match is_datagram(&socket).with_context(|| format!("passed descriptor {:?}", socket))? {
+            true => sockets.udp.push(socket),
+            false => sockets.tcp.push(TcpListener::from(OwnedFd::from(socket))),
+        }
+    }
+    Ok(Some(sockets))
+}
+
+/// Whether `socket` is a datagram socket rather than a listening stream
+/// one. The standard library can't ask for its type, but peeking without
+/// blocking fails with ENOTCONN only on a stream socket.
+fn is_datagram(socket: &UdpSocket) -> io::Result<bool> {
+    socket.set_nonblocking(true)?;
+    let peeked = socket.peek_from(&mut [0; 1]);
+    socket.set_nonblocking(false)?;
+    match peeked {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tells systemd about the service's `state`, like `READY=1`, if it
+/// supervises this process. Does nothing otherwise.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        tracing::warn!(%state, error = %e, "Failed to notify systemd");
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => abstract_addr(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// The address of the socket with abstract `name`, only there on Linux.
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &[u8]) -> io::Result<SocketAddr> {
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_: &[u8]) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "abstract socket names are only on Linux",
+    ))
+}
+
+/// How often systemd expects a watchdog ping from this process, if at all.
+fn watchdog_interval() -> Option<Duration> {
+    if env::var("WATCHDOG_PID").is_ok_and(|pid| pid != process::id().to_string()) {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the systemd watchdog twice per interval, as long as `healthy` says
+/// the server is. Does nothing unless systemd asked for pings.
+pub fn spawn_watchdog(healthy: impl Fn() -> bool + Send + 'static) {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    thread::spawn(move || loop {
+        if healthy() {
+            notify("WATCHDOG=1");
+        }
+        thread::sleep(interval / 2);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_datagram, notify};
+    use std::{
+        net::{TcpListener, UdpSocket},
+        os::{fd::OwnedFd, unix::net::UnixDatagram},
+    };
+
+    #[test]
+    fn test_is_datagram() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(is_datagram(&udp).unwrap());
+        // a waiting datagram is left for the server
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"query", udp.local_addr().unwrap()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(is_datagram(&udp).unwrap());
+        let mut buf = [0; 16];
+        assert_eq!(5, udp.recv(&mut buf).unwrap());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = UdpSocket::from(OwnedFd::from(tcp));
+        assert!(!is_datagram(&tcp).unwrap());
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("notify-test-{}.sock", std::process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..n]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
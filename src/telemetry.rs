@@ -0,0 +1,336 @@
+//! Health of each upstream the forwarder sends queries to: counts of
+//! queries, SERVFAIL answers, timeouts and other failures since startup, and
+//! a histogram of the round-trip times of the last few minutes. Kept for the
+//! life of the process, across configuration reloads.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{forward::Endpoint, proto::rcode, wire::WireMessage};
+
+/// Upper bounds of the round-trip time buckets, in milliseconds. Slower
+/// replies fall in one more bucket past the last.
+const BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// Round-trip times are kept in a histogram per minute, for this many of
+/// the latest minutes.
+const WINDOW_SECS: u64 = 60;
+const WINDOWS: usize = 5;
+
+/// Round-trip time counts by bucket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+}
+
+impl Histogram {
+    pub fn record(&mut self, rtt: Duration) {
+        let ms = rtt.as_millis() as u64;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket the `q` quantile falls in, `None` for an
+    /// empty histogram or past the last bound.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = (self.count() as f64 * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKETS_MS.get(bucket).map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+}
+
+/// How a query sent upstream went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Answered(Duration),
+    ServFail(Duration),
+    Timeout,
+    Failed,
+}
+
+impl Outcome {
+    /// Outcome of a query that gave `result` after `elapsed`, a failure
+    /// taking the whole `timeout` being a timeout.
+    pub fn of(result: &Result<WireMessage>, elapsed: Duration, timeout: Duration) -> Self {
+        match result {
+            Ok(reply) if reply.view().rcode == rcode::SERVFAIL => Self::ServFail(elapsed),
+            Ok(_) => Self::Answered(elapsed),
+            Err(_) if elapsed >= timeout => Self::Timeout,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queries: u64,
+    servfails: u64,
+    timeouts: u64,
+    failures: u64,
+    // window number -> round-trip times, the latest last
+    windows: VecDeque<(u64, Histogram)>,
+}
+
+/// Snapshot of the counters of an upstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamStats {
+    pub upstream: String,
+    pub queries: u64,
+    pub servfails: u64,
+    pub timeouts: u64,
+    pub failures: u64,
+    // of the latest minutes only
+    pub rtt: Histogram,
+}
+
+impl UpstreamStats {
+    /// Bound of the `q` quantile of the latest round-trip times, like
+    /// `<=20ms`, `-` if there are none.
+    pub fn rtt_quantile(&self, q: f64) -> String {
+        if self.rtt.count() == 0 {
+            return "-".into();
+        }
+        match self.rtt.quantile(q) {
+            Some(bound) => format!("<={}ms", bound.as_millis()),
+            None => format!(">{}ms", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "upstream {}: {} queries, {} SERVFAIL, {} timed out, {} failed",
+            self.upstream, self.queries, self.servfails, self.timeouts, self.failures
+        )?;
+        if self.rtt.count() > 0 {
+            write!(
+                f,
+                ", rtt p50 {} p90 {} p99 {}",
+                self.rtt_quantile(0.5),
+                self.rtt_quantile(0.9),
+                self.rtt_quantile(0.99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of every upstream queried, by name.
+#[derive(Debug, Default)]
+pub struct Registry {
+    upstreams: Mutex<BTreeMap<String, Counters>>,
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Self {
+            upstreams: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counts a query to `upstream`, at `now` seconds since the epoch.
+    fn record_at(&self, upstream: &Endpoint, outcome: Outcome, now: u64) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let counters = upstreams.entry(upstream.to_string()).or_default();
+        counters.queries += 1;
+        let rtt = match outcome {
+            Outcome::Answered(rtt) => rtt,
+            Outcome::ServFail(rtt) => {
+                counters.servfails += 1;
+                rtt
+            }
+            Outcome::Timeout => {
+                counters.timeouts += 1;
+                return;
+            }
+            Outcome::Failed => {
+                counters.failures += 1;
+                return;
+            }
+        };
+        let window = now / WINDOW_SECS;
+        if counters.windows.back().is_none_or(|(w, _)| *w != window) {
+            counters.windows.push_back((window, Histogram::default()));
+            if counters.windows.len() > WINDOWS {
+                counters.windows.pop_front();
+            }
+        }
+        if let Some((_, histogram)) = counters.windows.back_mut() {
+            histogram.record(rtt);
+        }
+    }
+
+    pub fn record(&self, upstream: &Endpoint, outcome: Outcome) {
+        self.record_at(upstream, outcome, now());
+    }
+
+    /// Counters of the upstreams queried so far, by name, as of `now`
+    /// seconds since the epoch.
+    fn stats_at(&self, now: u64) -> Vec<UpstreamStats> {
+        let oldest = (now / WINDOW_SECS).saturating_sub(WINDOWS as u64 - 1);
+        self.upstreams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(upstream, counters)| {
+                let mut rtt = Histogram::default();
+                for (_, histogram) in counters.windows.iter().filter(|(w, _)| *w >= oldest) {
+                    rtt.merge(histogram);
+                }
+                UpstreamStats {
+                    upstream: upstream.clone(),
+                    queries: counters.queries,
+                    servfails: counters.servfails,
+                    timeouts: counters.timeouts,
+                    failures: counters.failures,
+                    rtt,
+                }
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.stats_at(now())
+    }
+
+    pub fn summary(&self) -> String {
+        let stats = self.stats();
+        if stats.is_empty() {
+            return "no upstream queries".into();
+        }
+        let stats: Vec<_> = stats.iter().map(UpstreamStats::to_string).collect();
+        stats.join("; ")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+static UPSTREAMS: Registry = Registry::new();
+
+/// The counters of the upstreams queried by this process.
+pub fn upstreams() -> &'static Registry {
+    &UPSTREAMS
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Histogram, Outcome, Registry, UpstreamStats};
+    use crate::{
+        forward::Endpoint,
+        proto::{rcode, Message},
+        wire::WireMessage,
+    };
+    use anyhow::anyhow;
+    use std::{net::SocketAddr, time::Duration};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(None, histogram.quantile(0.5));
+        for rtt in [0, 3, 4, 8, 15, 15, 40, 90, 180, 9000] {
+            histogram.record(ms(rtt));
+        }
+        assert_eq!(10, histogram.count());
+        assert_eq!(Some(ms(20)), histogram.quantile(0.5));
+        assert_eq!(Some(ms(200)), histogram.quantile(0.9));
+        assert_eq!(None, histogram.quantile(0.99));
+        assert_eq!(Some(ms(1)), histogram.quantile(0.0));
+    }
+
+    #[test]
+    fn test_outcome() {
+        let reply = WireMessage::new(Message::default().to_bytes().unwrap()).unwrap();
+        let servfail = Message {
+            rcode: rcode::SERVFAIL,
+            ..Message::default()
+        };
+        let servfail = WireMessage::new(servfail.to_bytes().unwrap()).unwrap();
+        let timeout = ms(1000);
+        assert_eq!(
+            Outcome::Answered(ms(30)),
+            Outcome::of(&Ok(reply), ms(30), timeout)
+        );
+        assert_eq!(
+            Outcome::ServFail(ms(30)),
+            Outcome::of(&Ok(servfail), ms(30), timeout)
+        );
+        assert_eq!(
+            Outcome::Timeout,
+            Outcome::of(&Err(anyhow!("timed out")), ms(1000), timeout)
+        );
+        assert_eq!(
+            Outcome::Failed,
+            Outcome::of(&Err(anyhow!("refused")), ms(2), timeout)
+        );
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let upstream = Endpoint::Dns("192.0.2.53:53".parse::<SocketAddr>().unwrap());
+        let registry = Registry::new();
+        let start = 1_792_143_000;
+        registry.record_at(&upstream, Outcome::Answered(ms(8)), start);
+        registry.record_at(&upstream, Outcome::ServFail(ms(300)), start + 30);
+        registry.record_at(&upstream, Outcome::Timeout, start + 60);
+        registry.record_at(&upstream, Outcome::Failed, start + 120);
+        registry.record_at(&upstream, Outcome::Answered(ms(40)), start + 240);
+
+        let stats = registry.stats_at(start + 240);
+        assert_eq!(1, stats.len());
+        let UpstreamStats {
+            queries,
+            servfails,
+            timeouts,
+            failures,
+            rtt,
+            ..
+        } = &stats[0];
+        assert_eq!((5, 1, 1, 1), (*queries, *servfails, *timeouts, *failures));
+        assert_eq!(3, rtt.count());
+        assert_eq!(
+            "upstream 192.0.2.53:53: 5 queries, 1 SERVFAIL, 1 timed out, 1 failed, \
+             rtt p50 <=50ms p90 <=500ms p99 <=500ms",
+            stats[0].to_string()
+        );
+
+        // the first minute's times have rolled out, the counts stay
+        let stats = registry.stats_at(start + 300);
+        assert_eq!(1, stats[0].rtt.count());
+        assert_eq!(5, stats[0].queries);
+    }
+}
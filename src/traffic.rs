@@ -0,0 +1,382 @@
+//! What the server is asked and by whom: the most queried names and the
+//! busiest clients of the last few minutes, and how many queries of each
+//! type and answers of each RCODE were seen since startup.
+//!
+//! Names and clients are counted in count-min sketches (Cormode and
+//! Muthukrishnan), fixed-size tables that never undercount and overcount
+//! little, with only the heaviest hitters remembered by key. Memory stays
+//! the same however many distinct names and clients there are.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    handler::{Context, Next, RequestHandler},
+    privacy,
+    proto::{rcode, Message, Type},
+};
+
+/// Counters per row of a sketch, and rows.
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+
+/// Heavy hitters remembered by key, and so the most a top list can show.
+pub const TOP_KEYS: usize = 100;
+
+/// How long names and clients are counted before the counts start over.
+/// The previous window's counts are added in, so a fresh window isn't
+/// empty.
+const WINDOW: Duration = Duration::from_secs(300);
+
+/// Count-min sketch: each key adds to one counter per row, picked by the
+/// row's own hash, and its estimate is the lowest of them.
+struct Sketch {
+    rows: Vec<(RandomState, Vec<u64>)>,
+}
+
+impl Sketch {
+    fn new() -> Self {
+        Self {
+            rows: (0..SKETCH_DEPTH)
+                .map(|_| (RandomState::new(), vec![0; SKETCH_WIDTH]))
+                .collect(),
+        }
+    }
+
+    /// Counts `key` once more, returns its estimate.
+    fn add(&mut self, key: &str) -> u64 {
+        self.rows
+            .iter_mut()
+            .map(|(hasher, counters)| {
+                let counter = &mut counters[hasher.hash_one(key) as usize % SKETCH_WIDTH];
+                *counter += 1;
+                *counter
+            })
+            .min()
+            .unwrap_or_default()
+    }
+
+    fn estimate(&self, key: &str) -> u64 {
+        self.rows
+            .iter()
+            .map(|(hasher, counters)| counters[hasher.hash_one(key) as usize % SKETCH_WIDTH])
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+/// Estimated counts of keys in a sketch, and the heaviest keys seen.
+struct HeavyHitters {
+    sketch: Sketch,
+    // key -> estimate when last seen, at most `TOP_KEYS` of them
+    top: HashMap<String, u64>,
+}
+
+impl HeavyHitters {
+    fn new() -> Self {
+        Self {
+            sketch: Sketch::new(),
+            top: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        let estimate = self.sketch.add(key);
+        if let Some(count) = self.top.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        if self.top.len() < TOP_KEYS {
+            self.top.insert(key.to_string(), estimate);
+            return;
+        }
+        let lightest = self
+            .top
+            .iter()
+            .min_by_key(|(_, count)| **count)
+            .map(|(key, count)| (key.clone(), *count));
+        if let Some((lightest, count)) = lightest {
+            if estimate > count {
+                self.top.remove(&lightest);
+                self.top.insert(key.to_string(), estimate);
+            }
+        }
+    }
+}
+
+/// Heavy hitters of the current window and of the one before.
+struct Rolling {
+    current: HeavyHitters,
+    previous: Option<HeavyHitters>,
+}
+
+impl Rolling {
+    fn new() -> Self {
+        Self {
+            current: HeavyHitters::new(),
+            previous: None,
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        self.current.add(key);
+    }
+
+    fn roll(&mut self) {
+        let current = std::mem::replace(&mut self.current, HeavyHitters::new());
+        self.previous = Some(current);
+    }
+
+    /// The `n` keys counted most over both windows, the most counted first.
+    fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut keys: Vec<&String> = self.current.top.keys().collect();
+        if let Some(previous) = &self.previous {
+            keys.extend(
+                previous
+                    .top
+                    .keys()
+                    .filter(|k| !self.current.top.contains_key(*k)),
+            );
+        }
+        let mut top: Vec<(String, u64)> = keys
+            .into_iter()
+            .map(|key| {
+                let previous = self
+                    .previous
+                    .as_ref()
+                    .map_or(0, |previous| previous.sketch.estimate(key));
+                (key.clone(), self.current.sketch.estimate(key) + previous)
+            })
+            .collect();
+        top.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        top.truncate(n);
+        top
+    }
+}
+
+struct Counts {
+    names: Rolling,
+    clients: Rolling,
+    window_start: Instant,
+    qtypes: HashMap<Type, u64>,
+    rcodes: HashMap<u8, u64>,
+}
+
+/// Snapshot of the traffic counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrafficStats {
+    // most counted first
+    pub names: Vec<(String, u64)>,
+    pub clients: Vec<(String, u64)>,
+    pub qtypes: Vec<(Type, u64)>,
+    pub rcodes: Vec<(u8, u64)>,
+}
+
+/// `key=count` pairs, space separated, `-` if there are none.
+fn pairs<K: std::fmt::Display>(pairs: &[(K, u64)]) -> String {
+    if pairs.is_empty() {
+        return "-".into();
+    }
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|(key, count)| format!("{}={}", key, count))
+        .collect();
+    pairs.join(" ")
+}
+
+impl TrafficStats {
+    pub fn summary(&self) -> String {
+        let rcodes: Vec<_> = self
+            .rcodes
+            .iter()
+            .map(|(rcode, count)| (rcode::name(*rcode), *count))
+            .collect();
+        format!(
+            "top names: {}; top clients: {}; query types: {}; rcodes: {}",
+            pairs(&self.names),
+            pairs(&self.clients),
+            pairs(&self.qtypes),
+            pairs(&rcodes)
+        )
+    }
+}
+
+/// Traffic counters, kept across configuration reloads.
+pub struct Traffic {
+    counts: Mutex<Counts>,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self {
+            counts: Mutex::new(Counts {
+                names: Rolling::new(),
+                clients: Rolling::new(),
+                window_start: Instant::now(),
+                qtypes: HashMap::new(),
+                rcodes: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl Traffic {
+    /// Counts a query for `name` of `qtype` from `client`, at `now`.
+    fn record_at(&self, name: &str, qtype: Type, client: &str, now: Instant) {
+        let mut counts = self.counts.lock().unwrap();
+        let elapsed = now.duration_since(counts.window_start);
+        if elapsed >= WINDOW {
+            // after a quiet window, there's no previous one worth keeping
+            let rolls = if elapsed >= WINDOW * 2 { 2 } else { 1 };
+            for _ in 0..rolls {
+                counts.names.roll();
+                counts.clients.roll();
+            }
+            counts.window_start = now;
+        }
+        counts.names.add(name);
+        counts.clients.add(client);
+        *counts.qtypes.entry(qtype).or_default() += 1;
+    }
+
+    pub fn record_query(&self, name: &str, qtype: Type, client: &str) {
+        self.record_at(name, qtype, client, Instant::now());
+    }
+
+    pub fn record_rcode(&self, rcode: u8) {
+        *self.counts.lock().unwrap().rcodes.entry(rcode).or_default() += 1;
+    }
+
+    /// The `n` most queried names and busiest clients, at most `TOP_KEYS`,
+    /// and the query type and RCODE counts.
+    pub fn stats(&self, n: usize) -> TrafficStats {
+        let counts = self.counts.lock().unwrap();
+        let mut qtypes: Vec<_> = counts.qtypes.iter().map(|(t, n)| (*t, *n)).collect();
+        qtypes.sort_by_key(|(qtype, n)| (std::cmp::Reverse(*n), u16::from(*qtype)));
+        let mut rcodes: Vec<_> = counts.rcodes.iter().map(|(r, n)| (*r, *n)).collect();
+        rcodes.sort_by_key(|(rcode, n)| (std::cmp::Reverse(*n), *rcode));
+        TrafficStats {
+            names: counts.names.top(n),
+            clients: counts.clients.top(n),
+            qtypes,
+            rcodes,
+        }
+    }
+}
+
+/// Counts every request and the RCODE of its reply.
+pub struct TrafficHandler(pub Arc<Traffic>);
+
+impl RequestHandler for TrafficHandler {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        if let Some(q) = request.questions.first() {
+            let name = q.name.to_string().to_ascii_lowercase();
+            let name = if name.is_empty() { "." } else { &name };
+            self.0
+                .record_query(name, q.qtype, &privacy::ip(ctx.source.ip()));
+        }
+        let result = next.run(ctx, request);
+        if let Ok(reply) = &result {
+            self.0.record_rcode(reply.rcode);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Sketch, Traffic, TrafficHandler, SKETCH_WIDTH, TOP_KEYS, WINDOW};
+    use crate::{
+        handler::{Chain, Context, Next, RequestHandler, Transport},
+        proto::{rcode, Class, Message, Name, Question, Type},
+    };
+    use anyhow::Result;
+    use std::{sync::Arc, time::Instant};
+
+    #[test]
+    fn test_sketch() {
+        let mut sketch = Sketch::new();
+        for i in 0..SKETCH_WIDTH * 4 {
+            sketch.add(&format!("name{}.example", i));
+        }
+        for _ in 0..1000 {
+            sketch.add("heavy.example");
+        }
+        // never under, rarely much over
+        let estimate = sketch.estimate("heavy.example");
+        assert!((1000..1020).contains(&estimate), "{}", estimate);
+        assert!(sketch.estimate("name1.example") >= 1);
+        assert!(sketch.estimate("never.example") < 20);
+    }
+
+    #[test]
+    fn test_top_and_windows() {
+        let traffic = Traffic::default();
+        let start = Instant::now();
+        for i in 0..TOP_KEYS * 3 {
+            traffic.record_at(&format!("n{}.example", i), Type::A, "192.0.2.1", start);
+        }
+        for _ in 0..50 {
+            traffic.record_at("busy.example", Type::AAAA, "192.0.2.9", start);
+        }
+        for _ in 0..20 {
+            traffic.record_at("second.example", Type::A, "192.0.2.9", start);
+        }
+        let stats = traffic.stats(2);
+        assert_eq!("busy.example", stats.names[0].0);
+        assert!(stats.names[0].1 >= 50);
+        assert_eq!("second.example", stats.names[1].0);
+        assert_eq!(("192.0.2.1".to_string(), 300), stats.clients[0]);
+        assert_eq!(vec![(Type::A, 320), (Type::AAAA, 50)], stats.qtypes);
+
+        // the previous window still counts, the one before doesn't
+        traffic.record_at("new.example", Type::A, "192.0.2.1", start + WINDOW);
+        assert_eq!("busy.example", traffic.stats(1).names[0].0);
+        traffic.record_at("new.example", Type::A, "192.0.2.1", start + WINDOW * 2);
+        assert_eq!(vec![("new.example".to_string(), 2)], traffic.stats(1).names);
+    }
+
+    struct Nxdomain;
+
+    impl RequestHandler for Nxdomain {
+        fn handle(&self, _: &Context, request: Message, _: Next<'_>) -> Result<Message> {
+            Ok(Message {
+                rcode: rcode::NXDOMAIN,
+                ..request.reply()
+            })
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        let traffic = Arc::new(Traffic::default());
+        let chain = Chain::default()
+            .with(TrafficHandler(traffic.clone()))
+            .with(Nxdomain);
+        let ctx = Context {
+            source: "192.0.2.7:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = Message {
+            questions: vec![Question {
+                name: Name::from("WWW.Example."),
+                qtype: Type::MX,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        chain.handle(&ctx, request).unwrap();
+        assert_eq!(
+            "top names: www.example=1; top clients: 192.0.2.7=1; query types: MX=1; \
+             rcodes: NXDOMAIN=1",
+            traffic.stats(10).summary()
+        );
+    }
+}
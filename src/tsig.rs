@@ -0,0 +1,434 @@
+//! Transaction signatures (RFC 8945). Requests signed with a shared key are
+//! verified, and every message of the reply is signed with the same key.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    digest::{constant_time_eq, hmac, Hash},
+    encoder::{Encoder, Error},
+    encoding::base64_decode,
+    proto::{Class, Name, Record, Type},
+    rdata::{RData, Tsig},
+    wire::MessageRef,
+};
+
+/// TSIG errors, carried in the TSIG record of a NOTAUTH reply (RFC 8945,
+/// section 3).
+pub mod error {
+    pub const BADSIG: u16 = 16;
+    pub const BADKEY: u16 = 17;
+    pub const BADTIME: u16 = 18;
+}
+
+/// Seconds of clock skew allowed either way (RFC 8945, section 10).
+pub const FUDGE: u16 = 300;
+
+/// Shared secret for signing messages, known by the same name on both ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub name: Name,
+    pub hash: Hash,
+    pub secret: Vec<u8>,
+}
+
+impl Key {
+    /// Parses the key `name` from `ALGORITHM:SECRET`, with the secret in
+    /// base64, e.g. `hmac-sha256:c2VjcmV0`.
+    pub fn parse(name: &str, s: &str) -> Result<Self, String> {
+        let (algorithm, secret) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected algorithm:secret, got {:?}", s))?;
+        let hash = algorithm
+            .parse()
+            .ok()
+            .and_then(|algorithm| hash_of(&algorithm))
+            .ok_or_else(|| format!("unsupported algorithm {:?}", algorithm))?;
+        let secret = base64_decode(secret)
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| format!("invalid secret for key {}", name))?;
+        Ok(Self {
+            name: name.parse()?,
+            hash,
+            secret,
+        })
+    }
+
+    /// Algorithm name in TSIG records (RFC 8945, section 6).
+    pub fn algorithm(&self) -> Name {
+        let name = match self.hash {
+            Hash::Sha1 => "hmac-sha1",
+            Hash::Sha256 => "hmac-sha256",
+        };
+        Name::from(name)
+    }
+}
+
+fn hash_of(algorithm: &Name) -> Option<Hash> {
+    match algorithm.to_string().to_ascii_lowercase().as_str() {
+        "hmac-sha1" => Some(Hash::Sha1),
+        "hmac-sha256" => Some(Hash::Sha256),
+        _ => None,
+    }
+}
+
+/// Seconds since the epoch, the clock TSIG timestamps use.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Checks the TSIG of a request, the last additional record of `packet` if
+/// it has one (RFC 8945, section 5.2). Unsigned requests give `Ok(None)`,
+/// verified ones the signer for the reply. Otherwise the error is returned
+/// as the signer of the NOTAUTH reply that reports it. Truncated MACs are
+/// not accepted.
+pub fn verify(packet: &[u8], keys: &[Key], now: u64) -> Result<Option<Signer>, Box<Signer>> {
+    let Some((start, key_name, tsig)) = find(packet) else {
+        return Ok(None);
+    };
+    let mut signer = Signer {
+        key_name,
+        algorithm: tsig.algorithm.clone(),
+        key: None,
+        error: 0,
+        time_signed: now,
+        other: Vec::new(),
+        prior_mac: None,
+        replies: 0,
+    };
+
+    let Some(key) = keys
+        .iter()
+        .find(|k| k.name == signer.key_name && k.algorithm() == tsig.algorithm)
+    else {
+        signer.error = error::BADKEY;
+        return Err(Box::new(signer));
+    };
+    let mac = signer.mac(key, &unsigned(packet, start, tsig.original_id), &tsig);
+    if !mac.is_ok_and(|mac| constant_time_eq(&mac, &tsig.mac)) {
+        signer.error = error::BADSIG;
+        return Err(Box::new(signer));
+    }
+
+    signer.key = Some(key.clone());
+    signer.prior_mac = Some(tsig.mac);
+    if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        // signed, with the request's time and ours in the other data
+        // (RFC 8945, section 5.2.3)
+        signer.error = error::BADTIME;
+        signer.time_signed = tsig.time_signed;
+        signer.other = now.to_be_bytes()[2..].to_vec();
+        return Err(Box::new(signer));
+    }
+    Ok(Some(signer))
+}
+
+/// Signs a request and the messages of its reply, each chained to the MAC
+/// of the one before (RFC 8945, section 5.3.1). Also checks the messages
+/// of a reply to a request it signed.
+#[derive(Debug, Clone)]
+pub struct Signer {
+    key_name: Name,
+    algorithm: Name,
+    // none if the request's key is unknown or its MAC wrong, the error goes
+    // out unsigned
+    key: Option<Key>,
+    error: u16,
+    time_signed: u64,
+    other: Vec<u8>,
+    // MAC of the request, then of the last reply message. None while the
+    // request isn't signed yet
+    prior_mac: Option<Vec<u8>>,
+    // reply messages so far, all but the first only cover the timers
+    replies: usize,
+}
+
+impl Signer {
+    /// Signer for a new request with `key`.
+    pub fn new(key: Key, now: u64) -> Self {
+        Self {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm(),
+            key: Some(key),
+            error: 0,
+            time_signed: now,
+            other: Vec::new(),
+            prior_mac: None,
+            replies: 0,
+        }
+    }
+
+    /// Name of the key the request was signed with, once verified.
+    pub fn key(&self) -> Option<&Name> {
+        self.key
+            .as_ref()
+            .filter(|_| self.error == 0)
+            .map(|key| &key.name)
+    }
+
+    /// TSIG error reported to the client, 0 if none.
+    pub fn error(&self) -> u16 {
+        self.error
+    }
+
+    /// Bytes `sign` adds to a message.
+    pub fn overhead(&self) -> Result<usize, Error> {
+        let mac_size = self.key.as_ref().map_or(0, |key| key.hash.size());
+        let record = self.record(Tsig {
+            mac: vec![0; mac_size],
+            ..self.tsig(0)
+        });
+        let mut buf = Vec::new();
+        record.encode(&mut Encoder::new(&mut buf))?;
+        Ok(buf.len())
+    }
+
+    /// Appends the TSIG record to the encoded message in `buf` and counts it
+    /// in ARCOUNT.
+    pub fn sign(&mut self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let mut tsig = self.tsig(u16::from_be_bytes([buf[0], buf[1]]));
+        if let Some(key) = &self.key {
+            tsig.mac = self.mac(key, buf, &tsig)?;
+            self.advance(tsig.mac.clone());
+        }
+
+        let arcount = u16::from_be_bytes([buf[10], buf[11]]) as usize + 1;
+        let end = buf.len();
+        let mut enc = Encoder::new(buf);
+        enc.patch_count(10, arcount)?;
+        enc.set_offset(end);
+        self.record(tsig).encode(&mut enc)
+    }
+
+    /// Checks the TSIG of the next reply message in `packet`, which must be
+    /// signed without error under the same key.
+    pub fn check(&mut self, packet: &[u8]) -> bool {
+        let (Some(key), Some((start, key_name, tsig))) = (&self.key, find(packet)) else {
+            return false;
+        };
+        if key_name != self.key_name || tsig.error != 0 {
+            return false;
+        }
+        match self.mac(key, &unsigned(packet, start, tsig.original_id), &tsig) {
+            Ok(mac) if constant_time_eq(&mac, &tsig.mac) => {
+                self.advance(mac);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn tsig(&self, original_id: u16) -> Tsig {
+        Tsig {
+            algorithm: self.algorithm.clone(),
+            time_signed: self.time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id,
+            error: self.error,
+            other: self.other.clone(),
+        }
+    }
+
+    fn record(&self, tsig: Tsig) -> Record {
+        Record {
+            name: self.key_name.clone(),
+            rtype: Type::TSIG,
+            class: Class::ANY,
+            ttl: 0,
+            rdata: RData::TSIG(tsig),
+        }
+    }
+
+    /// MAC of `message` without its TSIG record: the prior MAC, then the
+    /// message, then all TSIG variables for requests and the first reply
+    /// message and only the timers after that (RFC 8945, section 4.3).
+    fn mac(&self, key: &Key, message: &[u8], tsig: &Tsig) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        let mut enc = Encoder::new(&mut data);
+        if let Some(prior) = &self.prior_mac {
+            enc.write_length_prefixed(prior)?;
+        }
+        enc.write_slice(message);
+
+        let mut variables = Vec::new();
+        let mut enc = Encoder::new(&mut variables);
+        if self.prior_mac.is_none() || self.replies == 0 {
+            enc.write_uncompressed_name(&self.key_name.to_lowercase())?;
+            Class::ANY.encode(&mut enc);
+            enc.write_u32(0);
+            enc.write_uncompressed_name(&tsig.algorithm.to_lowercase())?;
+            write_timers(&mut enc, tsig);
+            enc.write_u16(tsig.error);
+            enc.write_length_prefixed(&tsig.other)?;
+        } else {
+            write_timers(&mut enc, tsig);
+        }
+        data.extend_from_slice(&variables);
+        Ok(hmac(key.hash, &key.secret, &data))
+    }
+
+    fn advance(&mut self, mac: Vec<u8>) {
+        if self.prior_mac.is_some() {
+            self.replies += 1;
+        }
+        self.prior_mac = Some(mac);
+    }
+}
+
+fn write_timers(enc: &mut Encoder, tsig: &Tsig) {
+    enc.write_u16((tsig.time_signed >> 32) as u16);
+    enc.write_u32(tsig.time_signed as u32);
+    enc.write_u16(tsig.fudge);
+}
+
+/// Offset, owner and contents of the TSIG record ending `packet`, if any.
+fn find(packet: &[u8]) -> Option<(usize, Name, Tsig)> {
+    let message = MessageRef::decode(packet).ok()?;
+    let last = message.records().last()?.ok()?;
+    if last.rtype != Type::TSIG || last.end() != packet.len() {
+        return None;
+    }
+    let record = last.to_record().ok()?;
+    match record.rdata {
+        RData::TSIG(tsig) => Some((last.start(), record.name, tsig)),
+        _ => None,
+    }
+}
+
+/// The message as it was before the TSIG record at `start` was added.
+fn unsigned(packet: &[u8], start: usize, original_id: u16) -> Vec<u8> {
+    let mut message = packet[..start].to_vec();
+    message[..2].copy_from_slice(&original_id.to_be_bytes());
+    let arcount = u16::from_be_bytes([message[10], message[11]]).saturating_sub(1);
+    message[10..12].copy_from_slice(&arcount.to_be_bytes());
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::{error, verify, Key, Signer, FUDGE};
+    use crate::{
+        digest::Hash,
+        proto::{Class, Message, Name, Question, Type},
+        rdata::RData,
+    };
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key(name: &str) -> Key {
+        Key::parse(name, "hmac-sha256:c2VjcmV0IGZvciB0ZXN0aW5nIG9ubHk=").unwrap()
+    }
+
+    fn request() -> Vec<u8> {
+        Message {
+            id: 1234,
+            questions: vec![Question {
+                name: Name::from("example.com"),
+                qtype: Type::AXFR,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    fn tsig_error(packet: &[u8]) -> (u16, usize) {
+        let message = Message::from_bytes(packet).unwrap();
+        match &message.additionals.last().unwrap().rdata {
+            RData::TSIG(tsig) => (tsig.error, tsig.mac.len()),
+            other => panic!("expected TSIG, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = key("xfr.example.com");
+        assert_eq!(Hash::Sha256, key.hash);
+        assert_eq!("hmac-sha256", key.algorithm().to_string());
+        assert!(Key::parse("k", "hmac-md5:c2VjcmV0").is_err());
+        assert!(Key::parse("k", "hmac-sha1:!!").is_err());
+        assert!(Key::parse("k", "c2VjcmV0").is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let keys = vec![key("xfr.example.com")];
+        assert!(verify(&request(), &keys, NOW).unwrap().is_none());
+
+        let mut signed = request();
+        Signer::new(keys[0].clone(), NOW).sign(&mut signed).unwrap();
+        let message = Message::from_bytes(&signed).unwrap();
+        assert_eq!(Type::TSIG, message.additionals[0].rtype);
+        let signer = verify(&signed, &keys, NOW + 10).unwrap().unwrap();
+        assert_eq!(Some(&keys[0].name), signer.key());
+
+        let mut tampered = signed.clone();
+        tampered[2] ^= 1;
+        let signer = verify(&tampered, &keys, NOW).unwrap_err();
+        assert_eq!(error::BADSIG, signer.error());
+        assert_eq!(None, signer.key());
+
+        let other = vec![key("other.example.com")];
+        assert_eq!(
+            error::BADKEY,
+            verify(&signed, &other, NOW).unwrap_err().error()
+        );
+        let late = NOW + FUDGE as u64 + 1;
+        assert_eq!(
+            error::BADTIME,
+            verify(&signed, &keys, late).unwrap_err().error()
+        );
+    }
+
+    #[test]
+    fn test_reply_chain() {
+        let key = key("xfr.example.com");
+        let mut client = Signer::new(key.clone(), NOW);
+        let mut signed = request();
+        client.sign(&mut signed).unwrap();
+
+        let mut server = verify(&signed, &[key], NOW).unwrap().unwrap();
+        let messages: Vec<Vec<u8>> = (0..3)
+            .map(|_| {
+                let mut reply = Message::from_bytes(&request()).unwrap().reply();
+                reply.qr = 1;
+                let mut buf = reply.to_bytes().unwrap();
+                server.sign(&mut buf).unwrap();
+                buf
+            })
+            .collect();
+        assert_eq!(
+            messages[0].len() - request().len(),
+            server.clone().overhead().unwrap()
+        );
+
+        // out of order breaks the chain
+        assert!(!client.clone().check(&messages[1]));
+        for message in messages.iter() {
+            assert!(client.check(message));
+        }
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let keys = vec![key("xfr.example.com")];
+        let mut signed = request();
+        Signer::new(keys[0].clone(), NOW).sign(&mut signed).unwrap();
+
+        // unknown keys are reported unsigned
+        let mut signer = verify(&signed, &[key("other.example.com")], NOW).unwrap_err();
+        let mut reply = request();
+        signer.sign(&mut reply).unwrap();
+        assert_eq!((error::BADKEY, 0), tsig_error(&reply));
+
+        // bad times are signed, so the client can trust our clock
+        let mut signer = verify(&signed, &keys, NOW + 3600).unwrap_err();
+        let mut reply = request();
+        signer.sign(&mut reply).unwrap();
+        assert_eq!((error::BADTIME, 32), tsig_error(&reply));
+    }
+}
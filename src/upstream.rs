@@ -0,0 +1,482 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{mpsc, Arc, Mutex, Weak},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+
+use crate::{
+    proto::{Message, Name, Question},
+    socket,
+    wire::{MessageRef, WireMessage},
+};
+
+/// How often the receiver checks whether the socket is still in use.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Socket shared by all queries to upstream resolvers. Outstanding queries
+/// are keyed by transaction ID, a receiver thread hands each reply to the
+/// query waiting for it. Anything that isn't a reply to an outstanding query
+/// from the server it went to, echoing its question, is discarded and the
+/// query keeps waiting.
+///
+/// Query names are sent in random case (DNS 0x20, draft-vixie-dnsext-dns0x20)
+/// and replies must echo that exact case, making spoofed replies harder to
+/// get accepted.
+///
+/// IPv6 servers are queried from a socket of their own, if the host has
+/// IPv6 at all. Clones share the sockets.
+#[derive(Clone)]
+pub struct Upstream {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    socket6: Option<UdpSocket>,
+    pending: Mutex<HashMap<u16, Pending>>,
+}
+
+struct Pending {
+    // replies are only accepted from the server the query went to
+    addr: SocketAddr,
+    // and must echo the question, in the exact case sent
+    questions: Vec<Question>,
+    tx: mpsc::Sender<WireMessage>,
+}
+
+impl Pending {
+    fn accepts(&self, source: SocketAddr, reply: &MessageRef) -> bool {
+        source == self.addr && reply.is_reply_to(&self.questions)
+    }
+}
+
+/// Removes an outstanding query once its caller stops waiting.
+struct PendingGuard<'a>(&'a Inner, u16);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.pending.lock().unwrap().remove(&self.1);
+    }
+}
+
+impl Upstream {
+    pub fn bind() -> Result<Self> {
+        let socket = socket::udp_socket(&(Ipv4Addr::UNSPECIFIED, 0).into())?;
+        let socket6 = socket::udp_socket(&(Ipv6Addr::UNSPECIFIED, 0).into()).ok();
+        let mut recv_sockets = vec![socket.try_clone()?];
+        if let Some(socket6) = &socket6 {
+            recv_sockets.push(socket6.try_clone()?);
+        }
+
+        let inner = Arc::new(Inner {
+            socket,
+            socket6,
+            pending: Mutex::new(HashMap::new()),
+        });
+        for recv_socket in recv_sockets {
+            recv_socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let weak = Arc::downgrade(&inner);
+            thread::Builder::new()
+                .name("upstream".into())
+                .spawn(move || receive(recv_socket, weak))?;
+        }
+        Ok(Self { inner })
+    }
+
+    /// Sends `request` to `addr` under a fresh transaction ID and waits up to
+    /// `timeout` for the reply. IDs come from a CSPRNG so off-path attackers
+    /// can't guess them; the reply gets the ID and the name case of `request`
+    /// back, patched in place. A truncated reply is retried over TCP (RFC
+    /// 7766, section 5).
+    pub fn query(
+        &self,
+        addr: SocketAddr,
+        request: &Message,
+        timeout: Duration,
+    ) -> Result<WireMessage> {
+        let questions: Vec<_> = request
+            .questions
+            .iter()
+            .map(|q| Question {
+                name: randomize_case(&q.name),
+                ..q.clone()
+            })
+            .collect();
+
+        let mut reply = self.query_udp(addr, request, &questions, timeout)?;
+        if reply.view().tc == 1 {
+            tracing::debug!(
+                id = %request.id, upstream = %addr,
+                "Truncated reply, retrying over TCP"
+            );
+            reply = query_tcp(addr, request, &questions, timeout)?;
+        }
+
+        reply.set_id(request.id);
+        reply.restore_case(&request.questions);
+        Ok(reply)
+    }
+
+    fn query_udp(
+        &self,
+        addr: SocketAddr,
+        request: &Message,
+        questions: &[Question],
+        timeout: Duration,
+    ) -> Result<WireMessage> {
+        let (tx, rx) = mpsc::channel();
+        let id = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            if pending.len() > u16::MAX as usize / 2 {
+                bail!("too many outstanding upstream queries");
+            }
+            let id = loop {
+                let id = rand::thread_rng().gen();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(
+                id,
+                Pending {
+                    addr,
+                    questions: questions.to_vec(),
+                    tx,
+                },
+            );
+            id
+        };
+        let _pending = PendingGuard(&self.inner, id);
+
+        let buf = Message {
+            id,
+            questions: questions.to_vec(),
+            ..request.clone()
+        }
+        .to_bytes()?;
+        let socket = match addr {
+            SocketAddr::V4(_) => &self.inner.socket,
+            SocketAddr::V6(_) => self
+                .inner
+                .socket6
+                .as_ref()
+                .ok_or_else(|| anyhow!("no IPv6 to reach upstream {}", addr))?,
+        };
+        socket.send_to(&buf, addr)?;
+
+        rx.recv_timeout(timeout)
+            .map_err(|_| anyhow!("upstream {} timed out", addr))
+    }
+}
+
+/// Sends `request` to `addr` over a fresh TCP connection, `timeout` applies
+/// to connecting and to each read and write.
+fn query_tcp(
+    addr: SocketAddr,
+    request: &Message,
+    questions: &[Question],
+    timeout: Duration,
+) -> Result<WireMessage> {
+    let id = rand::thread_rng().gen();
+    let framed = Message {
+        id,
+        questions: questions.to_vec(),
+        ..request.clone()
+    }
+    .to_tcp_bytes()?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    socket::tune_stream(&stream)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(&framed)?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+    let reply = WireMessage::new(buf)?;
+    let view = reply.view();
+    if view.id != id || !view.is_reply_to(questions) {
+        bail!("unexpected TCP reply from upstream {}", addr);
+    }
+    Ok(reply)
+}
+
+/// Flips the case of each letter in `name` at random.
+fn randomize_case(name: &Name) -> Name {
+    let mut rng = rand::thread_rng();
+    Name::from_labels(name.iter_labels().map(|label| {
+        label
+            .iter()
+            .map(|b| {
+                if rng.gen() {
+                    b.to_ascii_uppercase()
+                } else {
+                    b.to_ascii_lowercase()
+                }
+            })
+            .collect::<Vec<u8>>()
+    }))
+}
+
+/// Receiver loop, runs until the `Upstream` is dropped.
+fn receive(socket: UdpSocket, inner: Weak<Inner>) {
+    let mut buf = [0u8; 4096];
+    loop {
+        let result = socket.recv_from(&mut buf);
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let (size, source) = match result {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to receive upstream reply");
+                continue;
+            }
+        };
+
+        // replies are only matched here, whoever needs their records decodes
+        // them
+        let parsed = MessageRef::decode(&buf[..size]);
+        let mut pending = inner.pending.lock().unwrap();
+        match parsed.map(|view| (pending.get(&view.id), view)) {
+            Ok((Some(p), view)) if p.accepts(source, &view) => {
+                let p = pending.remove(&view.id).unwrap();
+                let _ = p.tx.send(view.to_wire());
+            }
+            Err(e) => tracing::warn!(
+                upstream = %source, error = %e,
+                "Failed to parse upstream reply"
+            ),
+            // late reply to a query that timed out, a mismatched question, or
+            // a spoofing attempt
+            _ => tracing::debug!(upstream = %source, "Ignoring unexpected reply"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{randomize_case, Upstream};
+    use crate::proto::{
+        test::{question, request},
+        Message, Name, Type,
+    };
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, UdpSocket},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_replies_matched_by_id() {
+        // answers two queries in reverse order
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let (size, source) = server.recv_from(&mut buf).unwrap();
+                received.push((Message::from_bytes(&buf[..size]).unwrap(), source));
+            }
+            for (request, source) in received.into_iter().rev() {
+                let reply = request.reply().to_bytes().unwrap();
+                server.send_to(&reply, source).unwrap();
+            }
+        });
+
+        let upstream = Arc::new(Upstream::bind().unwrap());
+        let handles = ["one.example", "two.example"].map(|name| {
+            let upstream = upstream.clone();
+            thread::spawn(move || {
+                upstream
+                    .query(addr, &request(name, Type::A), Duration::from_secs(2))
+                    .unwrap()
+                    .to_message()
+                    .unwrap()
+            })
+        });
+        for (handle, name) in handles.into_iter().zip(["one.example", "two.example"]) {
+            let reply = handle.join().unwrap();
+            assert_eq!(name, reply.questions[0].name.to_string());
+            assert_eq!(7, reply.id);
+        }
+        assert!(upstream.inner.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let server = UdpSocket::bind("[::1]:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = server.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..size]).unwrap();
+            server
+                .send_to(&request.reply().to_bytes().unwrap(), source)
+                .unwrap();
+        });
+
+        let upstream = Upstream::bind().unwrap();
+        let reply = upstream
+            .query(
+                addr,
+                &request("example.com", Type::A),
+                Duration::from_secs(2),
+            )
+            .unwrap()
+            .to_message()
+            .unwrap();
+        assert_eq!(1, reply.qr);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // never answers
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = Upstream::bind().unwrap();
+        let result = upstream.query(
+            server.local_addr().unwrap(),
+            &request("example.com", Type::A),
+            Duration::from_millis(50),
+        );
+        assert!(result.is_err());
+        assert!(upstream.inner.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unexpected_replies_discarded() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = server.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..size]).unwrap();
+
+            // from another address
+            let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let spoofed = request.reply().to_bytes().unwrap();
+            spoofer.send_to(&spoofed, source).unwrap();
+            // for another question
+            let other = Message {
+                questions: vec![question("evil.example", Type::A)],
+                ..request.reply()
+            };
+            server.send_to(&other.to_bytes().unwrap(), source).unwrap();
+            // not a reply
+            server
+                .send_to(&request.to_bytes().unwrap(), source)
+                .unwrap();
+
+            // only the real reply is authoritative
+            let reply = Message {
+                aa: 1,
+                ..request.reply()
+            };
+            thread::sleep(Duration::from_millis(20));
+            server.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+        });
+
+        let upstream = Upstream::bind().unwrap();
+        let reply = upstream
+            .query(
+                addr,
+                &request("Example.COM", Type::A),
+                Duration::from_secs(2),
+            )
+            .unwrap()
+            .to_message()
+            .unwrap();
+        assert_eq!(1, reply.aa);
+        assert_eq!("Example.COM", reply.questions[0].name.to_string());
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let name = Name::from("a-long-name.with-many-letters.example");
+        let randomized = randomize_case(&name);
+        assert_eq!(name, randomized);
+        assert!(!randomized.eq_exact(&name));
+    }
+
+    #[test]
+    fn test_case_mismatch_discarded() {
+        // answers with the query name lowercased, like a spoofer that had to
+        // guess it
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = server.recv_from(&mut buf).unwrap();
+            let mut reply = Message::from_bytes(&buf[..size]).unwrap().reply();
+            reply.questions[0].name = reply.questions[0].name.to_lowercase();
+            server.send_to(&reply.to_bytes().unwrap(), source).unwrap();
+        });
+
+        let upstream = Upstream::bind().unwrap();
+        let result = upstream.query(
+            addr,
+            &request("a-long-name.with-many-letters.example", Type::A),
+            Duration::from_millis(200),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_truncated_reply_retried_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = UdpSocket::bind(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = server.recv_from(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf[..size]).unwrap();
+            let truncated = Message {
+                tc: 1,
+                ..request.reply()
+            };
+            server
+                .send_to(&truncated.to_bytes().unwrap(), source)
+                .unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let request = Message::from_bytes(&buf).unwrap();
+            let complete = Message {
+                aa: 1,
+                ..request.reply()
+            }
+            .to_tcp_bytes()
+            .unwrap();
+            stream.write_all(&complete).unwrap();
+        });
+
+        let upstream = Upstream::bind().unwrap();
+        let reply = upstream
+            .query(
+                addr,
+                &request("example.com", Type::A),
+                Duration::from_secs(2),
+            )
+            .unwrap()
+            .to_message()
+            .unwrap();
+        assert_eq!(0, reply.tc);
+        assert_eq!(1, reply.aa);
+        assert_eq!(7, reply.id);
+    }
+}
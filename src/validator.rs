@@ -0,0 +1,1509 @@
+//! DNSSEC validation of forwarded answers (RFC 4035, section 5). Upstreams
+//! are asked for DNSSEC records with checking disabled, and every RRset is
+//! verified along a chain of trust from a configured trust anchor down to
+//! the zone that signed it.
+//!
+//! Validated NSEC and NSEC3 records are kept for their TTL and used
+//! aggressively (RFC 8198): names and types they prove don't exist are
+//! answered NXDOMAIN or NODATA without asking upstream.
+
+use std::{
+    collections::HashMap,
+    fmt, iter,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    anchors::{Tracker, TrustAnchor},
+    dnssec::{self, label_count, nsec3_hash, ZONE_KEY},
+    edns::{ede, EdnsOption, Opt},
+    encoding::base32hex_decode,
+    proto::{rcode, Class, Message, Name, Question, Record, Type},
+    rdata::{Dnskey, Ds, Nsec, Nsec3, RData, Rrsig},
+    resolver::Resolver,
+};
+
+/// Longest a validated DNSKEY RRset or insecure delegation is reused, in
+/// seconds.
+const MAX_KEY_TTL: u32 = 3600;
+
+/// Most NSEC and NSEC3 RRsets kept for aggressive use, over all zones.
+const MAX_DENIAL_RRSETS: usize = 10000;
+
+/// NSEC3 chains with more iterations don't authenticate anything (RFC 9276,
+/// section 3.2).
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// Why data failed validation, as an extended DNS error (RFC 8914).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bogus {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Bogus {
+    fn new(code: u16, reason: String) -> Self {
+        Self { code, reason }
+    }
+}
+
+impl fmt::Display for Bogus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (EDE {})", self.reason, self.code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Secure,
+    // provably unsigned, or signed with algorithms we can't verify
+    Insecure,
+}
+
+impl Status {
+    fn and(self, other: Status) -> Status {
+        match (self, other) {
+            (Status::Secure, Status::Secure) => Status::Secure,
+            _ => Status::Insecure,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum KeyState {
+    // zone keys of a zone the chain of trust reaches
+    Secure(Vec<Dnskey>),
+    Insecure,
+}
+
+/// What a denial has to prove.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Denial {
+    NxDomain,
+    NoData,
+    // no exact match for a name answered from a wildcard with this many
+    // labels (RFC 4035, section 5.3.4)
+    Wildcard(u8),
+}
+
+/// Validated denial records of a zone, each RRset with its RRSIGs and when
+/// it expires.
+#[derive(Default)]
+struct ZoneDenials {
+    soa: Option<(Vec<Record>, Instant)>,
+    // owner -> its NSEC or NSEC3 RRset
+    nsecs: HashMap<Name, (Vec<Record>, Instant)>,
+}
+
+/// Resolver validating the answers of another one. Validated answers get the
+/// AD bit if the client asked for it with AD or DO (RFC 6840, section 5.7),
+/// bogus ones become SERVFAIL with an extended DNS error. Requests with CD
+/// set are passed through unvalidated.
+pub struct Validator {
+    resolver: Arc<dyn Resolver>,
+    anchors: Vec<TrustAnchor>,
+    // keys of the anchored zones followed across rollovers (RFC 5011)
+    tracker: Option<Mutex<Tracker>>,
+    // zone -> its key state, until it expires
+    keys: Mutex<HashMap<Name, (KeyState, Instant)>>,
+    // zone -> its validated denial records
+    denials: Mutex<HashMap<Name, ZoneDenials>>,
+}
+
+impl Validator {
+    pub fn new(resolver: Arc<dyn Resolver>, anchors: Vec<TrustAnchor>) -> Self {
+        Self {
+            resolver,
+            anchors,
+            tracker: None,
+            keys: Mutex::new(HashMap::new()),
+            denials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Follows key rollovers of the anchored zones, trusting the keys the
+    /// tracker does instead of the configured ones once it knows a zone.
+    pub fn with_tracker(self, tracker: Tracker) -> Self {
+        Self {
+            tracker: Some(Mutex::new(tracker)),
+            ..self
+        }
+    }
+
+    /// Re-validates the keys of the anchored zones every `MAX_KEY_TTL`
+    /// seconds, so the tracker sees rollovers even without queries for
+    /// them. Stops once the validator is dropped.
+    pub fn track(validator: &Arc<Self>) {
+        if validator.tracker.is_none() {
+            return;
+        }
+        let weak = Arc::downgrade(validator);
+        thread::spawn(move || refresh_loop(weak));
+    }
+
+    fn refresh_anchors(&self) {
+        let mut zones: Vec<&Name> = self.anchors.iter().map(|anchor| &anchor.zone).collect();
+        zones.dedup();
+        for zone in zones {
+            self.keys.lock().unwrap().remove(zone);
+            if let Err(bogus) = self.key_state(zone, now()) {
+                tracing::warn!(%zone, error = %bogus, "Failed to refresh trust anchors");
+            }
+        }
+    }
+}
+
+fn refresh_loop(validator: Weak<Validator>) {
+    loop {
+        thread::sleep(Duration::from_secs(MAX_KEY_TTL.into()));
+        let Some(validator) = validator.upgrade() else {
+            return;
+        };
+        validator.refresh_anchors();
+    }
+}
+
+impl Resolver for Validator {
+    fn resolve(&self, request: &Message) -> Result<Message> {
+        let dnssec_ok = request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok);
+        if request.cd == 0 {
+            if let Some(mut reply) = self.synthesize(request) {
+                reply.ad = (dnssec_ok || request.ad == 1) as u8;
+                if !dnssec_ok {
+                    strip_dnssec(&mut reply);
+                }
+                return Ok(reply);
+            }
+        }
+        let upstream_request = Message {
+            cd: 1,
+            opt: Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                dnssec_ok: true,
+                ..request.opt.clone().unwrap_or_default()
+            }),
+            ..request.clone()
+        };
+        let mut reply = self.resolver.resolve(&upstream_request)?;
+        reply.opt = request.reply().opt;
+        reply.cd = request.cd;
+        reply.ad = 0;
+
+        if request.cd == 0 && matches!(reply.rcode, rcode::NOERROR | rcode::NXDOMAIN) {
+            match self.validate(&reply, now()) {
+                Ok(Status::Secure) => reply.ad = (dnssec_ok || request.ad == 1) as u8,
+                Ok(Status::Insecure) => {}
+                Err(bogus) => {
+                    tracing::info!(id = %request.id, error = %bogus, "DNSSEC validation failed");
+                    let mut reply = request.error_reply(rcode::SERVFAIL);
+                    if let Some(opt) = reply.opt.as_mut() {
+                        opt.set_option(EdnsOption::ExtendedError(bogus.code, bogus.reason));
+                    }
+                    return Ok(reply);
+                }
+            }
+        }
+        if !dnssec_ok {
+            strip_dnssec(&mut reply);
+        }
+        Ok(reply)
+    }
+}
+
+impl Validator {
+    /// Validates every RRset in the answer section, and the denial in the
+    /// authority section of a negative reply.
+    fn validate(&self, reply: &Message, now: u32) -> Result<Status, Bogus> {
+        let Some(question) = reply.questions.first() else {
+            return Ok(Status::Insecure);
+        };
+        let mut status = Status::Secure;
+        for rrset in rrsets(&reply.answers) {
+            let (rrset_status, wildcard) = self.verify_rrset(&rrset, &reply.answers, now)?;
+            status = status.and(rrset_status);
+            if let Some(labels) = wildcard {
+                let denial = Denial::Wildcard(labels);
+                status = status.and(self.verify_denial(
+                    &rrset[0].name,
+                    question.qtype,
+                    denial,
+                    &reply.authorities,
+                    now,
+                )?);
+            }
+        }
+
+        // a CNAME chain ends at the name the denial is about
+        let mut target = question.name.clone();
+        for _ in 0..reply.answers.len() {
+            let next = reply.answers.iter().find_map(|r| match &r.rdata {
+                RData::CNAME(next) if r.name == target => Some(next.clone()),
+                _ => None,
+            });
+            match next {
+                Some(next) if question.qtype != Type::CNAME => target = next,
+                _ => break,
+            }
+        }
+        let answered = reply.answers.iter().any(|r| {
+            r.name == target && (r.rtype == question.qtype || question.qtype == Type::ANY)
+        });
+        if reply.rcode == rcode::NXDOMAIN || !answered {
+            let denial = match reply.rcode {
+                rcode::NXDOMAIN => Denial::NxDomain,
+                _ => Denial::NoData,
+            };
+            status = status.and(self.verify_denial(
+                &target,
+                question.qtype,
+                denial,
+                &reply.authorities,
+                now,
+            )?);
+        }
+        Ok(status)
+    }
+
+    /// Verifies an RRset against the RRSIGs for it in `section`. Also
+    /// returns the label count of the wildcard it was expanded from, if any.
+    fn verify_rrset(
+        &self,
+        rrset: &[Record],
+        section: &[Record],
+        now: u32,
+    ) -> Result<(Status, Option<u8>), Bogus> {
+        let owner = &rrset[0].name;
+        let rtype = rrset[0].rtype;
+        let sigs: Vec<&Rrsig> = section
+            .iter()
+            .filter(|r| r.name == *owner)
+            .filter_map(|r| match &r.rdata {
+                RData::RRSIG(sig) if sig.type_covered == rtype => Some(sig),
+                _ => None,
+            })
+            // a DS is signed by the parent, never by the zone it is for
+            .filter(|sig| {
+                owner.is_subdomain_of(&sig.signer_name)
+                    && !(rtype == Type::DS && sig.signer_name == *owner)
+            })
+            .collect();
+
+        if sigs.is_empty() {
+            if !self.covered(owner) {
+                return Ok((Status::Insecure, None));
+            }
+            let name = match rtype {
+                Type::DS => parent(owner),
+                _ => owner.clone(),
+            };
+            let zone = self.find_zone(&name)?;
+            return match self.key_state(&zone, now)? {
+                KeyState::Insecure => Ok((Status::Insecure, None)),
+                KeyState::Secure(_) => Err(Bogus::new(
+                    ede::RRSIGS_MISSING,
+                    format!("no signature for {} {}", owner, rtype),
+                )),
+            };
+        }
+
+        let mut error = None;
+        for sig in sigs {
+            match self.key_state(&sig.signer_name, now)? {
+                KeyState::Insecure => return Ok((Status::Insecure, None)),
+                KeyState::Secure(keys) => match check_rrsig(sig, &keys, rrset, now) {
+                    Ok(()) => {
+                        let wildcard = (sig.labels < label_count(owner)).then_some(sig.labels);
+                        return Ok((Status::Secure, wildcard));
+                    }
+                    Err(e) => error = Some(e),
+                },
+            }
+        }
+        Err(error.unwrap())
+    }
+
+    /// Verifies the NSEC or NSEC3 records in `authorities` prove `denial`
+    /// for `name` and `qtype` (RFC 4035, section 5.4, RFC 5155, section 8).
+    fn verify_denial(
+        &self,
+        name: &Name,
+        qtype: Type,
+        denial: Denial,
+        authorities: &[Record],
+        now: u32,
+    ) -> Result<Status, Bogus> {
+        if !self.covered(name) {
+            return Ok(Status::Insecure);
+        }
+        // the zone the denial comes from, the parent's for a DS
+        let acceptable =
+            |zone: &Name| name.is_subdomain_of(zone) && !(qtype == Type::DS && zone == name);
+        let claimed = authorities.iter().find_map(|r| match &r.rdata {
+            RData::SOA(_) => Some(r.name.clone()),
+            RData::RRSIG(sig) if matches!(sig.type_covered, Type::NSEC | Type::NSEC3) => {
+                Some(sig.signer_name.clone())
+            }
+            _ => None,
+        });
+        let zone = match claimed.filter(acceptable) {
+            Some(zone) => zone,
+            None if qtype == Type::DS => self.find_zone(&parent(name))?,
+            None => self.find_zone(name)?,
+        };
+        if let KeyState::Insecure = self.key_state(&zone, now)? {
+            return Ok(Status::Insecure);
+        }
+
+        let mut status = Status::Secure;
+        for rrset in rrsets(authorities) {
+            if matches!(rrset[0].rtype, Type::SOA | Type::NSEC | Type::NSEC3) {
+                status = status.and(self.verify_rrset(&rrset, authorities, now)?.0);
+            }
+        }
+        if status == Status::Insecure {
+            return Ok(status);
+        }
+
+        let nsecs: Vec<(&Name, &Nsec)> = authorities
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::NSEC(nsec) if r.name.is_subdomain_of(&zone) => Some((&r.name, nsec)),
+                _ => None,
+            })
+            .collect();
+        let nsec3s: Vec<(&Name, &Nsec3)> = authorities
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::NSEC3(nsec3) if r.name.is_subdomain_of(&zone) => Some((&r.name, nsec3)),
+                _ => None,
+            })
+            .collect();
+        let proof = match (nsecs.is_empty(), nsec3s.is_empty()) {
+            (false, _) => nsec_proof(name, qtype, denial, &nsecs).then_some(Status::Secure),
+            (true, false) => nsec3_proof(&zone, name, qtype, denial, &nsec3s),
+            (true, true) => None,
+        };
+        let proof = proof.ok_or_else(|| {
+            Bogus::new(
+                ede::NSEC_MISSING,
+                format!("no proof that {} {} doesn't exist", name, qtype),
+            )
+        })?;
+        if proof == Status::Secure {
+            self.remember_denial(&zone, authorities);
+        }
+        Ok(proof)
+    }
+
+    /// Keeps the SOA, NSEC and NSEC3 records of a secure denial from `zone`
+    /// for aggressive use. They live as long as the negative TTL at most
+    /// (RFC 9077).
+    fn remember_denial(&self, zone: &Name, authorities: &[Record]) {
+        let negative_ttl = authorities.iter().find_map(|r| match &r.rdata {
+            RData::SOA(soa) if r.name == *zone => Some(r.ttl.min(soa.minimum)),
+            _ => None,
+        });
+        let Some(negative_ttl) = negative_ttl else {
+            return;
+        };
+        let now = Instant::now();
+        let mut denials = self.denials.lock().unwrap();
+        let mut kept: usize = denials.values().map(|zone| zone.nsecs.len()).sum();
+        if kept >= MAX_DENIAL_RRSETS {
+            for zone in denials.values_mut() {
+                zone.nsecs.retain(|_, (_, expires)| *expires > now);
+            }
+            kept = denials.values().map(|zone| zone.nsecs.len()).sum();
+        }
+        let entry = denials.entry(zone.clone()).or_default();
+        for rrset in rrsets(authorities) {
+            let owner = &rrset[0].name;
+            let rtype = rrset[0].rtype;
+            let ttl = rrset.iter().map(|r| r.ttl).min().unwrap_or(0);
+            let expires = now + Duration::from_secs(ttl.min(negative_ttl).into());
+            let mut records = rrset.clone();
+            records.extend(
+                authorities
+                    .iter()
+                    .filter(|r| {
+                        r.name == *owner
+                            && matches!(&r.rdata, RData::RRSIG(sig) if sig.type_covered == rtype)
+                    })
+                    .cloned(),
+            );
+            match rtype {
+                Type::SOA if owner == zone => entry.soa = Some((records, expires)),
+                Type::NSEC | Type::NSEC3
+                    if owner.is_subdomain_of(zone)
+                        && (kept < MAX_DENIAL_RRSETS || entry.nsecs.contains_key(owner)) =>
+                {
+                    kept += 1;
+                    entry.nsecs.insert(owner.clone(), (records, expires));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Answers `request` from the kept denial records of the closest zone
+    /// that has some, when they prove the name or type doesn't exist. Names
+    /// at or below a delegation of that zone aren't answered.
+    fn synthesize(&self, request: &Message) -> Option<Message> {
+        let [question] = request.questions.as_slice() else {
+            return None;
+        };
+        if question.class != Class::IN || request.opcode != 0 {
+            return None;
+        }
+        let name = &question.name;
+        let qtype = question.qtype;
+        let now = Instant::now();
+        let denials = self.denials.lock().unwrap();
+        let mut zone = match qtype {
+            Type::DS => parent(name),
+            _ => name.clone(),
+        };
+        let denial = loop {
+            if let Some(denial) = denials.get(&zone) {
+                break denial;
+            }
+            if zone.is_root() {
+                return None;
+            }
+            zone = parent(&zone);
+        };
+        let (soa, soa_expires) = denial.soa.as_ref().filter(|(_, expires)| *expires > now)?;
+        let entries: Vec<(&Vec<Record>, &Instant)> = denial
+            .nsecs
+            .values()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(records, expires)| (records, expires))
+            .collect();
+
+        // the names a proof involves: the name, its ancestors in the zone and
+        // their wildcards
+        let labels = labels(name);
+        let ancestors: Vec<Name> = (0..=labels.len() - label_count(&zone) as usize)
+            .map(|i| Name::from_labels(&labels[i..]))
+            .collect();
+        let involved: Vec<Name> = ancestors
+            .iter()
+            .flat_map(|ancestor| [ancestor.clone(), wildcard(ancestor)])
+            .collect();
+        // a delegation above the name, or at it unless for a DS
+        let cut = |owner: &Name, types: &[Type]| {
+            let below = owner != name || qtype != Type::DS;
+            below && types.contains(&Type::NS) && !types.contains(&Type::SOA)
+        };
+
+        let nsecs: Vec<(&Name, &Nsec, &Vec<Record>, &Instant)> = entries
+            .iter()
+            .filter_map(|(records, expires)| match &records[0].rdata {
+                RData::NSEC(nsec) => Some((&records[0].name, nsec, *records, *expires)),
+                _ => None,
+            })
+            .filter(|(owner, nsec, _, _)| {
+                involved
+                    .iter()
+                    .any(|n| *owner == n || covers(owner, &nsec.next_domain_name, n))
+            })
+            .collect();
+        let nsec3s: Vec<(&Name, &Nsec3, &Vec<Record>, &Instant)> = entries
+            .iter()
+            .filter_map(|(records, expires)| match &records[0].rdata {
+                RData::NSEC3(nsec3) => Some((&records[0].name, nsec3, *records, *expires)),
+                _ => None,
+            })
+            .collect();
+
+        let (rcode, used) = if !nsecs.is_empty() {
+            let ancestor_cut = nsecs.iter().any(|(owner, nsec, _, _)| {
+                ancestors.iter().any(|a| a == *owner) && cut(owner, &nsec.types)
+            });
+            if ancestor_cut {
+                return None;
+            }
+            let proof: Vec<(&Name, &Nsec)> = nsecs
+                .iter()
+                .map(|(owner, nsec, _, _)| (*owner, *nsec))
+                .collect();
+            let rcode = if nsec_proof(name, qtype, Denial::NxDomain, &proof) {
+                rcode::NXDOMAIN
+            } else if nsec_proof(name, qtype, Denial::NoData, &proof) {
+                rcode::NOERROR
+            } else {
+                return None;
+            };
+            let used = nsecs
+                .iter()
+                .map(|(_, _, records, expires)| (*records, *expires));
+            (rcode, used.collect::<Vec<_>>())
+        } else if !nsec3s.is_empty() {
+            let params = nsec3s[0].1;
+            let hashed = |n: &Name| nsec3_hash(n, &params.salt, params.iterations).ok();
+            let hashes: Vec<Vec<u8>> = involved.iter().map(hashed).collect::<Option<_>>()?;
+            let ancestor_hashes: Vec<Vec<u8>> =
+                ancestors.iter().map(hashed).collect::<Option<_>>()?;
+            let owner_hash = |owner: &Name| base32hex_decode(owner.labels().first()?);
+            let mut relevant = Vec::new();
+            for (owner, nsec3, records, expires) in nsec3s.iter() {
+                let Some(owner_hash) = owner_hash(owner) else {
+                    continue;
+                };
+                let next = &nsec3.next_hashed_owner;
+                let covers = |h: &Vec<u8>| match owner_hash < *next {
+                    true => owner_hash < *h && h < next,
+                    false => owner_hash < *h || h < next,
+                };
+                if let Some(i) = ancestor_hashes.iter().position(|h| *h == owner_hash) {
+                    if cut(&ancestors[i], &nsec3.types) {
+                        return None;
+                    }
+                }
+                if hashes.iter().any(|h| *h == owner_hash || covers(h)) {
+                    relevant.push((*owner, *nsec3, *records, *expires));
+                }
+            }
+            let proof: Vec<(&Name, &Nsec3)> = relevant
+                .iter()
+                .map(|(owner, nsec3, _, _)| (*owner, *nsec3))
+                .collect();
+            let secure =
+                |denial| nsec3_proof(&zone, name, qtype, denial, &proof) == Some(Status::Secure);
+            let rcode = if secure(Denial::NxDomain) {
+                rcode::NXDOMAIN
+            } else if secure(Denial::NoData) {
+                rcode::NOERROR
+            } else {
+                return None;
+            };
+            let used = relevant
+                .iter()
+                .map(|(_, _, records, expires)| (*records, *expires));
+            (rcode, used.collect::<Vec<_>>())
+        } else {
+            return None;
+        };
+
+        // TTLs count down to the expiry of each RRset
+        let mut authorities = Vec::new();
+        for (records, expires) in [(soa, soa_expires)].into_iter().chain(used) {
+            let left = expires.saturating_duration_since(now).as_secs() as u32;
+            authorities.extend(records.iter().map(|r| Record {
+                ttl: r.ttl.min(left),
+                ..r.clone()
+            }));
+        }
+        Some(Message {
+            rcode,
+            ra: 1,
+            authorities,
+            ..request.reply()
+        })
+    }
+
+    /// Keys of `zone`, validated along the chain of trust, cached for the
+    /// TTL of the records that got us there.
+    fn key_state(&self, zone: &Name, now: u32) -> Result<KeyState, Bogus> {
+        if let Some((state, expires)) = self.keys.lock().unwrap().get(zone) {
+            if *expires > Instant::now() {
+                return Ok(state.clone());
+            }
+        }
+        let (state, ttl) = self.fetch_key_state(zone, now)?;
+        let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_KEY_TTL).into());
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(zone.clone(), (state.clone(), expires));
+        Ok(state)
+    }
+
+    /// Follows the chain of trust into `zone`: its DS RRset from a trust
+    /// anchor or validated in the parent, then a DNSKEY matching one of them
+    /// signing the zone's DNSKEY RRset (RFC 4035, section 5.2).
+    fn fetch_key_state(&self, zone: &Name, now: u32) -> Result<(KeyState, u32), Bogus> {
+        let anchored = self.anchored(zone);
+        let (ds_set, mut ttl) = if let Some(anchored) = anchored.clone() {
+            if anchored.is_empty() {
+                return Err(Bogus::new(
+                    ede::DNSKEY_MISSING,
+                    format!("no trusted key of {} left", zone),
+                ));
+            }
+            (anchored, MAX_KEY_TTL)
+        } else {
+            if !self.covered(zone) {
+                return Ok((KeyState::Insecure, MAX_KEY_TTL));
+            }
+            let reply = self.query(zone, Type::DS)?;
+            let records = of_type(&reply.answers, zone, Type::DS);
+            if records.is_empty() {
+                // an insecure delegation, if the parent proves there's no DS
+                let denial = match reply.rcode {
+                    rcode::NXDOMAIN => Denial::NxDomain,
+                    _ => Denial::NoData,
+                };
+                self.verify_denial(zone, Type::DS, denial, &reply.authorities, now)?;
+                let ttl = reply.authorities.iter().map(|r| r.ttl).min();
+                return Ok((KeyState::Insecure, ttl.unwrap_or(MAX_KEY_TTL)));
+            }
+            let ttl = records.iter().map(|r| r.ttl).min().unwrap_or(MAX_KEY_TTL);
+            if self.verify_rrset(&records, &reply.answers, now)?.0 == Status::Insecure {
+                return Ok((KeyState::Insecure, ttl));
+            }
+            let ds_set = records
+                .into_iter()
+                .filter_map(|r| match r.rdata {
+                    RData::DS(ds) => Some(ds),
+                    _ => None,
+                })
+                .collect();
+            (ds_set, ttl)
+        };
+
+        // only DS records we can use count (RFC 4035, section 5.2)
+        let usable: Vec<&Ds> = ds_set
+            .iter()
+            .filter(|ds| {
+                dnssec::supported_algorithm(ds.algorithm)
+                    && dnssec::supported_digest(ds.digest_type)
+            })
+            .collect();
+        if usable.is_empty() {
+            return Ok((KeyState::Insecure, ttl));
+        }
+
+        let reply = self.query(zone, Type::DNSKEY)?;
+        let records = of_type(&reply.answers, zone, Type::DNSKEY);
+        let keys: Vec<Dnskey> = records
+            .iter()
+            .filter_map(|r| match &r.rdata {
+                RData::DNSKEY(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let sigs: Vec<&Rrsig> = reply
+            .answers
+            .iter()
+            .filter(|r| r.name == *zone)
+            .filter_map(|r| match &r.rdata {
+                RData::RRSIG(sig) if sig.type_covered == Type::DNSKEY => Some(sig),
+                _ => None,
+            })
+            .collect();
+        ttl = records.iter().map(|r| r.ttl).fold(ttl, u32::min);
+
+        let mut error = Bogus::new(
+            ede::DNSKEY_MISSING,
+            format!("no DNSKEY of {} matches its DS", zone),
+        );
+        for ds in usable {
+            let entry_keys = keys
+                .iter()
+                .filter(|key| dnssec::ds(zone, key, ds.digest_type).as_ref() == Some(ds));
+            for key in entry_keys {
+                for sig in sigs.iter().filter(|sig| sig.key_tag == ds.key_tag) {
+                    match check_rrsig(sig, std::slice::from_ref(key), &records, now) {
+                        Ok(()) => {
+                            if let Some(trusted) = &anchored {
+                                self.track_keys(zone, trusted, &records, &sigs, now);
+                            }
+                            let zone_keys = keys
+                                .into_iter()
+                                .filter(|key| key.flags & ZONE_KEY != 0)
+                                .collect();
+                            return Ok((KeyState::Secure(zone_keys), ttl));
+                        }
+                        Err(e) => error = e,
+                    }
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// DS records trusted for `zone` without its parent: the tracked keys,
+    /// or the configured anchors while it has none.
+    fn anchored(&self, zone: &Name) -> Option<Vec<Ds>> {
+        if let Some(tracker) = &self.tracker {
+            if let Some(ds_set) = tracker.lock().unwrap().anchors(zone) {
+                return Some(ds_set);
+            }
+        }
+        let configured: Vec<Ds> = self
+            .anchors
+            .iter()
+            .filter(|anchor| anchor.zone == *zone)
+            .map(|anchor| anchor.ds.clone())
+            .collect();
+        (!configured.is_empty()).then_some(configured)
+    }
+
+    /// Hands a validated DNSKEY RRset of an anchored zone to the tracker,
+    /// saving its state if any key moved on.
+    fn track_keys(&self, zone: &Name, trusted: &[Ds], rrset: &[Record], sigs: &[&Rrsig], now: u32) {
+        let Some(tracker) = &self.tracker else {
+            return;
+        };
+        let mut tracker = tracker.lock().unwrap();
+        if tracker.update(zone, trusted, rrset, sigs, now.into()) {
+            if let Err(e) = tracker.save() {
+                tracing::warn!(error = %format!("{:#}", e), "Failed to save trust anchor state");
+            }
+        }
+    }
+
+    /// Apex of the zone `name` is in, from the SOA an upstream returns.
+    fn find_zone(&self, name: &Name) -> Result<Name, Bogus> {
+        let reply = self.query(name, Type::SOA)?;
+        reply
+            .answers
+            .iter()
+            .chain(&reply.authorities)
+            .find(|r| r.rtype == Type::SOA && name.is_subdomain_of(&r.name))
+            .map(|r| r.name.clone())
+            .ok_or_else(|| Bogus::new(ede::DNSSEC_BOGUS, format!("no zone found for {}", name)))
+    }
+
+    /// Looks up DNSSEC records the validation needs, unvalidated.
+    fn query(&self, name: &Name, qtype: Type) -> Result<Message, Bogus> {
+        let request = Message {
+            rd: 1,
+            cd: 1,
+            questions: vec![Question {
+                name: name.clone(),
+                qtype,
+                class: Class::IN,
+            }],
+            opt: Some(Opt {
+                udp_payload_size: Opt::UDP_PAYLOAD_SIZE,
+                dnssec_ok: true,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let failed = |e: String| {
+            Bogus::new(
+                ede::DNSSEC_BOGUS,
+                format!("looking up {} {}: {}", name, qtype, e),
+            )
+        };
+        match self.resolver.resolve(&request) {
+            Ok(reply) if matches!(reply.rcode, rcode::NOERROR | rcode::NXDOMAIN) => Ok(reply),
+            Ok(reply) => Err(failed(format!("rcode {}", reply.rcode))),
+            Err(e) => Err(failed(e.to_string())),
+        }
+    }
+
+    /// Whether a trust anchor is at or above `name`, otherwise nothing
+    /// about it can be validated.
+    fn covered(&self, name: &Name) -> bool {
+        self.anchors
+            .iter()
+            .any(|anchor| name.is_subdomain_of(&anchor.zone))
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Checks the validity period and label count of `sig`, and that one of
+/// `keys` made it over `rrset` (RFC 4035, section 5.3.1).
+fn check_rrsig(sig: &Rrsig, keys: &[Dnskey], rrset: &[Record], now: u32) -> Result<(), Bogus> {
+    let owner = &rrset[0].name;
+    let what = format!("signature of {} {}", owner, sig.type_covered);
+    // serial number arithmetic, the timestamps wrap (RFC 4034, section 3.1.5)
+    let before = |a: u32, b: u32| (a.wrapping_sub(b) as i32) < 0;
+    if before(now, sig.inception) {
+        return Err(Bogus::new(
+            ede::SIGNATURE_NOT_YET_VALID,
+            format!("{} not yet valid", what),
+        ));
+    }
+    if before(sig.expiration, now) {
+        return Err(Bogus::new(
+            ede::SIGNATURE_EXPIRED,
+            format!("{} expired", what),
+        ));
+    }
+    if sig.labels > label_count(owner) {
+        return Err(Bogus::new(
+            ede::DNSSEC_BOGUS,
+            format!("{} has too many labels", what),
+        ));
+    }
+    let verified = keys
+        .iter()
+        .filter(|key| key.flags & ZONE_KEY != 0 && dnssec::key_tag(key) == sig.key_tag)
+        .any(|key| dnssec::verify(sig, key, rrset));
+    match verified {
+        true => Ok(()),
+        false => Err(Bogus::new(
+            ede::DNSSEC_BOGUS,
+            format!("{} by {} doesn't verify", what, sig.signer_name),
+        )),
+    }
+}
+
+/// Whether NSEC records prove `denial` (RFC 4035, section 5.4).
+fn nsec_proof(name: &Name, qtype: Type, denial: Denial, nsecs: &[(&Name, &Nsec)]) -> bool {
+    let covering = |name: &Name| {
+        nsecs
+            .iter()
+            .find(|(owner, nsec)| covers(owner, &nsec.next_domain_name, name))
+    };
+    let lacks_type = |name: &Name| {
+        nsecs
+            .iter()
+            .any(|(owner, nsec)| *owner == name && lacks(&nsec.types, qtype))
+    };
+    // the closest encloser is the longest ancestor the covering NSEC's owner
+    // or next name share with the name
+    let closest_encloser = |owner: &Name, next: &Name| {
+        let (a, b) = (common_ancestor(name, owner), common_ancestor(name, next));
+        match label_count(&a) >= label_count(&b) {
+            true => a,
+            false => b,
+        }
+    };
+    match denial {
+        Denial::NoData => {
+            lacks_type(name)
+                || covering(name).is_some_and(|(owner, nsec)| {
+                    let next = &nsec.next_domain_name;
+                    // an empty non-terminal, the next name is below it
+                    (next.is_subdomain_of(name) && next != name)
+                        || lacks_type(&wildcard(&closest_encloser(owner, next)))
+                })
+        }
+        Denial::NxDomain => covering(name).is_some_and(|(owner, nsec)| {
+            let next = &nsec.next_domain_name;
+            // names below it exist, an empty non-terminal
+            !next.is_subdomain_of(name)
+                && covering(&wildcard(&closest_encloser(owner, next))).is_some()
+        }),
+        Denial::Wildcard(_) => covering(name).is_some(),
+    }
+}
+
+/// What NSEC3 records prove about `denial` (RFC 5155, section 8): secure,
+/// insecure when an opt-out span or too many iterations are involved, or
+/// nothing.
+fn nsec3_proof(
+    zone: &Name,
+    name: &Name,
+    qtype: Type,
+    denial: Denial,
+    nsec3s: &[(&Name, &Nsec3)],
+) -> Option<Status> {
+    let params = nsec3s[0].1;
+    if params.hash_algorithm != 1 || params.iterations > MAX_NSEC3_ITERATIONS {
+        return Some(Status::Insecure);
+    }
+    let hashes: Vec<(Vec<u8>, &Nsec3)> = nsec3s
+        .iter()
+        .filter_map(|(owner, nsec3)| {
+            let label = owner.labels().first()?;
+            Some((base32hex_decode(label)?, *nsec3))
+        })
+        .collect();
+    let hash = |name: &Name| nsec3_hash(name, &params.salt, params.iterations).ok();
+    let matching = |name: &Name| {
+        let h = hash(name)?;
+        hashes
+            .iter()
+            .find(|(owner, _)| *owner == h)
+            .map(|(_, n)| *n)
+    };
+    let covering = |name: &Name| {
+        let h = hash(name)?;
+        hashes
+            .iter()
+            .find(|(owner, nsec3)| {
+                let next = &nsec3.next_hashed_owner;
+                match owner < next {
+                    true => *owner < h && h < *next,
+                    // the last NSEC3 wraps around to the first
+                    false => *owner < h || h < *next,
+                }
+            })
+            .map(|(_, n)| *n)
+    };
+    let lacks_type = |nsec3: &Nsec3| lacks(&nsec3.types, qtype);
+    // closest encloser proof: the longest existing ancestor, and an NSEC3
+    // covering the name one label below it (RFC 5155, section 8.3)
+    let closest_encloser = || {
+        let labels = labels(name);
+        let zone_labels = label_count(zone) as usize;
+        (1..=labels.len().saturating_sub(zone_labels)).find_map(|i| {
+            let encloser = Name::from_labels(&labels[i..]);
+            matching(&encloser)?;
+            let next_closer = Name::from_labels(&labels[i - 1..]);
+            Some((encloser, covering(&next_closer)))
+        })
+    };
+    let opt_out = |nsec3: &Nsec3| match nsec3.flags & 1 {
+        1 => Status::Insecure,
+        _ => Status::Secure,
+    };
+
+    match denial {
+        Denial::NoData => {
+            if let Some(nsec3) = matching(name) {
+                return lacks_type(nsec3).then_some(Status::Secure);
+            }
+            let (encloser, next_closer) = closest_encloser()?;
+            let next_closer = next_closer?;
+            // a delegation without DS in an opt-out span (section 8.6)
+            if qtype == Type::DS && next_closer.flags & 1 == 1 {
+                return Some(Status::Insecure);
+            }
+            // or a wildcard without the type (section 8.7)
+            matching(&wildcard(&encloser))
+                .filter(|nsec3| lacks_type(nsec3))
+                .map(|_| Status::Secure)
+        }
+        Denial::NxDomain => {
+            let (encloser, next_closer) = closest_encloser()?;
+            let next_closer = next_closer?;
+            covering(&wildcard(&encloser))?;
+            Some(opt_out(next_closer))
+        }
+        Denial::Wildcard(encloser_labels) => {
+            // the name one label below the wildcard's parent doesn't exist
+            let labels = labels(name);
+            let start = labels.len().checked_sub(encloser_labels as usize + 1)?;
+            covering(&Name::from_labels(&labels[start..])).map(opt_out)
+        }
+    }
+}
+
+/// Whether a type bitmap proves there's no `qtype` RRset, nor a CNAME. For
+/// a DS it must be the parent's bitmap, without SOA.
+fn lacks(types: &[Type], qtype: Type) -> bool {
+    let child_apex = qtype == Type::DS && types.contains(&Type::SOA);
+    !types.contains(&qtype) && !types.contains(&Type::CNAME) && !child_apex
+}
+
+/// Whether the NSEC from `owner` to `next` covers `name`, the last NSEC of
+/// a zone wrapping around to the apex.
+fn covers(owner: &Name, next: &Name, name: &Name) -> bool {
+    owner < name && (name < next || next <= owner)
+}
+
+/// Lowercased labels of a name, leftmost first.
+fn labels(name: &Name) -> Vec<Vec<u8>> {
+    name.to_lowercase().labels().to_vec()
+}
+
+fn parent(name: &Name) -> Name {
+    name.parent().unwrap_or_default()
+}
+
+fn wildcard(name: &Name) -> Name {
+    Name::from_labels(iter::once(&b"*"[..]).chain(name.iter_labels()))
+}
+
+/// Longest common ancestor of two names.
+fn common_ancestor(a: &Name, b: &Name) -> Name {
+    let (a, b) = (labels(a), labels(b));
+    let common = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    Name::from_labels(&a[a.len() - common..])
+}
+
+/// Records grouped into RRsets by owner and type, RRSIGs left out.
+fn rrsets(records: &[Record]) -> Vec<Vec<Record>> {
+    let mut rrsets: Vec<Vec<Record>> = Vec::new();
+    for record in records.iter().filter(|r| r.rtype != Type::RRSIG) {
+        match rrsets
+            .iter_mut()
+            .find(|set| set[0].rtype == record.rtype && set[0].name == record.name)
+        {
+            Some(set) => set.push(record.clone()),
+            None => rrsets.push(vec![record.clone()]),
+        }
+    }
+    rrsets
+}
+
+fn of_type(records: &[Record], name: &Name, rtype: Type) -> Vec<Record> {
+    records
+        .iter()
+        .filter(|r| r.rtype == rtype && r.name == *name)
+        .cloned()
+        .collect()
+}
+
+/// Drops the DNSSEC records a client that didn't set DO didn't ask for
+/// (RFC 4035, section 3.2.1).
+pub fn strip_dnssec(reply: &mut Message) {
+    let qtype = reply.questions.first().map(|q| q.qtype);
+    let asked = |r: &Record| Some(r.rtype) == qtype;
+    reply.answers.retain(|r| r.rtype != Type::RRSIG || asked(r));
+    reply
+        .authorities
+        .retain(|r| !matches!(r.rtype, Type::RRSIG | Type::NSEC | Type::NSEC3));
+    reply.additionals.retain(|r| r.rtype != Type::RRSIG);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{now, Validator};
+    use crate::{
+        anchors::{Tracker, TrustAnchor},
+        dnssec::{self, sign_zone, DenialChain, SigningKey},
+        edns::{ede, EdnsOption, Opt},
+        proto::{rcode, Class, Message, Name, Record, Type},
+        rdata::{Ds, RData},
+        resolver::Resolver,
+        zone::Zone,
+    };
+    use anyhow::Result;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const COM: &str = "\
+$ORIGIN com.
+$TTL 3600
+@            SOA ns1.example.com. hostmaster.example.com. 1 7200 3600 1209600 300
+@            NS  ns1.example.com.
+example      NS  ns1.example.com.
+insecure     NS  ns1.insecure.com.
+";
+
+    const EXAMPLE: &str = "\
+$ORIGIN example.com.
+$TTL 3600
+@            SOA ns1 hostmaster 1 7200 3600 1209600 300
+@            NS  ns1
+*.wild       TXT \"any\"
+www          A   192.0.2.1
+";
+
+    const INSECURE: &str = "\
+$ORIGIN insecure.com.
+$TTL 3600
+@            SOA ns1 hostmaster 1 7200 3600 1209600 300
+www          A   192.0.2.2
+";
+
+    const ORG: &str = "\
+$ORIGIN example.org.
+$TTL 3600
+@            SOA ns1 hostmaster 1 7200 3600 1209600 300
+www          A   192.0.2.3
+";
+
+    /// Authoritative data of several zones answering like a recursive
+    /// resolver with checking disabled would.
+    struct Upstream(Vec<(Name, Vec<Record>)>);
+
+    impl Upstream {
+        fn records(&mut self, zone: &str) -> &mut Vec<Record> {
+            let zone = Name::from(zone);
+            &mut self.0.iter_mut().find(|(z, _)| *z == zone).unwrap().1
+        }
+    }
+
+    impl Resolver for Upstream {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            let question = &request.questions[0];
+            let name = &question.name;
+            // the deepest zone, the parent's for a DS
+            let (_, records) = self
+                .0
+                .iter()
+                .filter(|(zone, _)| {
+                    name.is_subdomain_of(zone) && !(question.qtype == Type::DS && zone == name)
+                })
+                .max_by_key(|(zone, _)| zone.num_labels())
+                .unwrap();
+            let answer = |owner: &Name| -> Vec<Record> {
+                records
+                    .iter()
+                    .filter(|r| r.name == *owner)
+                    .filter(|r| match &r.rdata {
+                        RData::RRSIG(sig) => sig.type_covered == question.qtype,
+                        _ => r.rtype == question.qtype,
+                    })
+                    .map(|r| Record {
+                        name: name.clone(),
+                        ..r.clone()
+                    })
+                    .collect()
+            };
+            let denial = || -> Vec<Record> {
+                records
+                    .iter()
+                    .filter(|r| match &r.rdata {
+                        RData::RRSIG(sig) => {
+                            matches!(sig.type_covered, Type::SOA | Type::NSEC | Type::NSEC3)
+                        }
+                        _ => matches!(r.rtype, Type::SOA | Type::NSEC | Type::NSEC3),
+                    })
+                    .cloned()
+                    .collect()
+            };
+
+            let mut reply = request.reply();
+            reply.answers = answer(name);
+            if reply.answers.is_empty() {
+                let wildcard = super::wildcard(&name.parent().unwrap());
+                reply.answers = answer(&wildcard);
+                if reply.answers.is_empty() {
+                    if !records.iter().any(|r| r.name == *name) {
+                        reply.rcode = rcode::NXDOMAIN;
+                    }
+                } else {
+                    reply.authorities = denial();
+                }
+            }
+            if reply.answers.is_empty() {
+                reply.authorities = denial();
+            }
+            Ok(reply)
+        }
+    }
+
+    fn com_key() -> SigningKey {
+        SigningKey::from_seed([1; 32])
+    }
+
+    fn example_key() -> SigningKey {
+        SigningKey::from_seed([2; 32])
+    }
+
+    fn ds(zone: &str, key: &SigningKey) -> Ds {
+        let zone = Name::from(zone);
+        dnssec::ds(&zone, &key.dnskey(), dnssec::DIGEST_SHA256).unwrap()
+    }
+
+    /// The zones above, com and example.com signed at `signed` with
+    /// example.com's DS in com, insecure.com delegated without one.
+    fn upstream(signed: u32) -> Upstream {
+        let parse = |text| {
+            Zone::parse(text, None, None)
+                .unwrap()
+                .records()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let example = Name::from("example.com");
+        let nsec = DenialChain::Nsec;
+        let mut com = parse(COM);
+        com.push(Record {
+            name: example.clone(),
+            rtype: Type::DS,
+            class: Class::IN,
+            ttl: 3600,
+            rdata: RData::DS(ds("example.com", &example_key())),
+        });
+        Upstream(vec![
+            (
+                Name::from("com"),
+                sign_zone(&Name::from("com"), com, &com_key(), &nsec, signed).unwrap(),
+            ),
+            (
+                example.clone(),
+                sign_zone(&example, parse(EXAMPLE), &example_key(), &nsec, signed).unwrap(),
+            ),
+            (Name::from("insecure.com"), parse(INSECURE)),
+            (Name::from("example.org"), parse(ORG)),
+        ])
+    }
+
+    fn validator(upstream: Upstream) -> Validator {
+        counting_validator(upstream).0
+    }
+
+    /// Counts the requests passed on to the upstream.
+    struct Counting(Upstream, Arc<AtomicUsize>);
+
+    impl Resolver for Counting {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.resolve(request)
+        }
+    }
+
+    fn counting_validator(upstream: Upstream) -> (Validator, Arc<AtomicUsize>) {
+        let anchor = TrustAnchor {
+            zone: Name::from("com"),
+            ds: ds("com", &com_key()),
+        };
+        let count = Arc::new(AtomicUsize::new(0));
+        let upstream = Counting(upstream, count.clone());
+        (Validator::new(Arc::new(upstream), vec![anchor]), count)
+    }
+
+    fn request(name: &str, qtype: Type, dnssec_ok: bool) -> Message {
+        Message {
+            opt: Some(Opt {
+                dnssec_ok,
+                ..Opt::default()
+            }),
+            ..crate::proto::test::request(name, qtype)
+        }
+    }
+
+    fn types(records: &[Record]) -> Vec<Type> {
+        records.iter().map(|r| r.rtype).collect()
+    }
+
+    fn extended_error(reply: &Message) -> Option<u16> {
+        match reply.opt.as_ref()?.option(EdnsOption::EXTENDED_ERROR)? {
+            EdnsOption::ExtendedError(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_tracker() {
+        let path = std::env::temp_dir().join(format!("validator-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let tracked = |path| validator(upstream(now())).with_tracker(Tracker::load(path).unwrap());
+
+        // com's key is trusted from its anchor, and remembered
+        let reply = tracked(&path)
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(1, reply.ad);
+        let state = std::fs::read_to_string(&path).unwrap();
+        assert!(state.contains("com. DNSKEY 257 3 15 "), "{}", state);
+        assert!(state.contains("; VALID "), "{}", state);
+
+        // once tracked, the state decides which keys are trusted
+        let other = SigningKey::from_seed([3; 32]).dnskey();
+        let state = state.replace(
+            &crate::encoding::base64_encode(&com_key().dnskey().public_key),
+            &crate::encoding::base64_encode(&other.public_key),
+        );
+        std::fs::write(&path, state).unwrap();
+        let reply = tracked(&path)
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert_eq!(Some(ede::DNSKEY_MISSING), extended_error(&reply));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_secure() {
+        let validator = validator(upstream(now()));
+
+        let reply = validator
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert_eq!(1, reply.ad);
+        assert_eq!(vec![Type::A, Type::RRSIG], types(&reply.answers));
+
+        // proven by NSEC records
+        let reply = validator
+            .resolve(&request("nope.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NXDOMAIN, 1), (reply.rcode, reply.ad));
+        let reply = validator
+            .resolve(&request("www.example.com", Type::TXT, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 1), (reply.rcode, reply.ad));
+        assert!(reply.answers.is_empty());
+
+        // expanded from a wildcard, with proof the name itself doesn't exist
+        let reply = validator
+            .resolve(&request("a.wild.example.com", Type::TXT, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 1), (reply.rcode, reply.ad));
+        assert_eq!(vec![Type::TXT, Type::RRSIG], types(&reply.answers));
+
+        // without DO, DNSSEC records are left out and AD only set if asked
+        let reply = validator
+            .resolve(&request("www.example.com", Type::A, false))
+            .unwrap();
+        assert_eq!(vec![Type::A], types(&reply.answers));
+        assert_eq!(0, reply.ad);
+        let reply = validator
+            .resolve(&Message {
+                ad: 1,
+                ..request("www.example.com", Type::A, false)
+            })
+            .unwrap();
+        assert_eq!(1, reply.ad);
+    }
+
+    #[test]
+    fn test_aggressive_nsec() {
+        let (validator, count) = counting_validator(upstream(now()));
+        let reply = validator
+            .resolve(&request("nope.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NXDOMAIN, 1), (reply.rcode, reply.ad));
+        let forwarded = count.load(Ordering::Relaxed);
+
+        // another name and a missing type, proven by the NSEC records kept
+        let reply = validator
+            .resolve(&request("other.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NXDOMAIN, 1), (reply.rcode, reply.ad));
+        assert!(types(&reply.authorities).contains(&Type::NSEC));
+        let reply = validator
+            .resolve(&request("www.example.com", Type::MX, false))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 0), (reply.rcode, reply.ad));
+        assert_eq!(vec![Type::SOA], types(&reply.authorities));
+        assert_eq!(forwarded, count.load(Ordering::Relaxed));
+
+        // a wildcard answers the name, and a delegation hides what's below
+        let reply = validator
+            .resolve(&request("b.wild.example.com", Type::TXT, true))
+            .unwrap();
+        assert_eq!(vec![Type::TXT, Type::RRSIG], types(&reply.answers));
+        validator
+            .resolve(&request("nope.com", Type::A, true))
+            .unwrap();
+        let forwarded = count.load(Ordering::Relaxed);
+        let reply = validator
+            .resolve(&request("www.insecure.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 0), (reply.rcode, reply.ad));
+        assert!(count.load(Ordering::Relaxed) > forwarded);
+
+        // nor with checking disabled
+        let forwarded = count.load(Ordering::Relaxed);
+        validator
+            .resolve(&Message {
+                cd: 1,
+                ..request("other.example.com", Type::A, true)
+            })
+            .unwrap();
+        assert_eq!(forwarded + 1, count.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_aggressive_nsec3() {
+        let mut upstream = upstream(now());
+        let example = Name::from("example.com");
+        let zone = Zone::parse(EXAMPLE, None, None).unwrap();
+        let chain = DenialChain::Nsec3 {
+            salt: vec![0xaa, 0xbb],
+            iterations: 1,
+        };
+        *upstream.records("example.com") = sign_zone(
+            &example,
+            zone.records().cloned().collect(),
+            &example_key(),
+            &chain,
+            now(),
+        )
+        .unwrap();
+        let (validator, count) = counting_validator(upstream);
+        let reply = validator
+            .resolve(&request("nope.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NXDOMAIN, 1), (reply.rcode, reply.ad));
+        let forwarded = count.load(Ordering::Relaxed);
+
+        let reply = validator
+            .resolve(&request("other.example.com", Type::AAAA, true))
+            .unwrap();
+        assert_eq!((rcode::NXDOMAIN, 1), (reply.rcode, reply.ad));
+        assert!(types(&reply.authorities).contains(&Type::NSEC3));
+        let reply = validator
+            .resolve(&request("www.example.com", Type::TXT, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 1), (reply.rcode, reply.ad));
+        assert_eq!(forwarded, count.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_insecure() {
+        let validator = validator(upstream(now()));
+
+        // delegated without DS, as the parent's NSEC proves
+        let reply = validator
+            .resolve(&request("www.insecure.com", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 0), (reply.rcode, reply.ad));
+        assert_eq!(vec![Type::A], types(&reply.answers));
+
+        // no trust anchor above it
+        let reply = validator
+            .resolve(&request("www.example.org", Type::A, true))
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 0), (reply.rcode, reply.ad));
+        assert_eq!(vec![Type::A], types(&reply.answers));
+    }
+
+    #[test]
+    fn test_bogus() {
+        let mut tampered = upstream(now());
+        for record in tampered.records("example.com") {
+            if record.rtype == Type::A {
+                record.rdata = RData::A("192.0.2.99".parse().unwrap());
+            }
+        }
+        let tampered = validator(tampered);
+        let reply = tampered
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert!(reply.answers.is_empty());
+        assert_eq!(Some(ede::DNSSEC_BOGUS), extended_error(&reply));
+
+        // checking disabled, the answer is passed along as is
+        let reply = tampered
+            .resolve(&Message {
+                cd: 1,
+                ..request("www.example.com", Type::A, true)
+            })
+            .unwrap();
+        assert_eq!((rcode::NOERROR, 0, 1), (reply.rcode, reply.ad, reply.cd));
+        assert_eq!(vec![Type::A, Type::RRSIG], types(&reply.answers));
+
+        // signatures of a secure zone can't just go missing
+        let mut stripped = upstream(now());
+        stripped.records("example.com").retain(|r| match &r.rdata {
+            RData::RRSIG(sig) => sig.type_covered != Type::A,
+            _ => true,
+        });
+        let reply = validator(stripped)
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert_eq!(Some(ede::RRSIGS_MISSING), extended_error(&reply));
+
+        // nor can the denial of a name
+        let mut undenied = upstream(now());
+        undenied
+            .records("example.com")
+            .retain(|r| r.rtype != Type::NSEC);
+        let reply = validator(undenied)
+            .resolve(&request("nope.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert_eq!(Some(ede::NSEC_MISSING), extended_error(&reply));
+
+        // signed two months ago, the signatures are valid for 30 days
+        let reply = validator(upstream(now() - 60 * 86400))
+            .resolve(&request("www.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+        assert_eq!(Some(ede::SIGNATURE_EXPIRED), extended_error(&reply));
+    }
+}
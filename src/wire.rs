@@ -0,0 +1,475 @@
+//! Borrowed views of messages on the wire. Decoding one checks the parts it
+//! covers without allocating, so a reply can be matched against the query
+//! it answers, or its records picked through by type, before paying for an
+//! owned `Message`. Each view converts to its owned counterpart, a message
+//! decoding only what the view left undecoded, so nothing is parsed twice.
+//! A `WireMessage` keeps the bytes of a whole message for views to read,
+//! for replies passed along before anything needs to change them.
+
+use crate::{
+    encoder::{Decoder, Error},
+    proto::{Class, Message, Name, Question, Record, Type},
+};
+
+/// A domain name inside a message.
+#[derive(Debug, Clone, Copy)]
+pub struct NameRef<'a> {
+    msg: &'a [u8],
+    // where the name starts, its labels may continue elsewhere
+    at: usize,
+}
+
+impl<'a> NameRef<'a> {
+    pub fn decode(dec: &mut Decoder<'a>) -> Result<Self, Error> {
+        let at = dec.skip_name()?;
+        Ok(Self { msg: dec.buf(), at })
+    }
+
+    /// The labels, following compression pointers.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            msg: self.msg,
+            at: self.at,
+        }
+    }
+
+    pub fn to_name(self) -> Name {
+        Name::from_labels(self.labels())
+    }
+
+    /// Whether this is `name`, in the exact same case.
+    pub fn is(&self, name: &Name) -> bool {
+        self.labels().eq(name.iter_labels())
+    }
+
+    /// Whether this is `name`, in any case.
+    pub fn matches(&self, name: &Name) -> bool {
+        let mut labels = self.labels();
+        let mut other = name.iter_labels();
+        loop {
+            match (labels.next(), other.next()) {
+                (None, None) => return true,
+                (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// Where each label's bytes start in the message, following pointers.
+    fn label_offsets(&self) -> impl Iterator<Item = usize> + 'a {
+        let mut labels = self.labels();
+        std::iter::from_fn(move || labels.next_at())
+    }
+}
+
+/// Iterator over the labels of a `NameRef`.
+pub struct Labels<'a> {
+    msg: &'a [u8],
+    at: usize,
+}
+
+impl Labels<'_> {
+    /// Where the next label's bytes start, past its length.
+    fn next_at(&mut self) -> Option<usize> {
+        // the name was checked when decoded, there are no loops or bad labels
+        loop {
+            let len = *self.msg.get(self.at)?;
+            match len & 0xC0 {
+                0x00 if len == 0 => return None,
+                0x00 => {
+                    let at = self.at + 1;
+                    self.at += 1 + len as usize;
+                    return Some(at);
+                }
+                _ => {
+                    let low = *self.msg.get(self.at + 1)?;
+                    self.at = u16::from_be_bytes([len & 0x3F, low]) as usize;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let at = self.next_at()?;
+        self.msg.get(at..at + self.msg[at - 1] as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub qtype: Type,
+    pub class: Class,
+}
+
+impl<'a> QuestionRef<'a> {
+    pub fn decode(dec: &mut Decoder<'a>) -> Result<Self, Error> {
+        Ok(Self {
+            name: NameRef::decode(dec)?,
+            qtype: Type::decode(dec)?,
+            class: Class::decode(dec)?,
+        })
+    }
+
+    pub fn to_question(self) -> Question {
+        Question {
+            name: self.name.to_name(),
+            qtype: self.qtype,
+            class: self.class,
+        }
+    }
+}
+
+/// A resource record with its RDATA left undecoded. Names in the RDATA may
+/// still point elsewhere in the message.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    pub name: NameRef<'a>,
+    pub rtype: Type,
+    pub class: Class,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+    // where the RDATA starts in the message
+    rdata_at: usize,
+}
+
+impl<'a> RecordRef<'a> {
+    pub fn decode(dec: &mut Decoder<'a>) -> Result<Self, Error> {
+        let name = NameRef::decode(dec)?;
+        let rtype = Type::decode(dec)?;
+        let class = Class::decode(dec)?;
+        let ttl = dec.read_u32()?;
+        let rdlength = dec.read_u16()?;
+        let rdata_at = dec.offset();
+        Ok(Self {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata: dec.read_slice(rdlength as usize)?,
+            rdata_at,
+        })
+    }
+
+    /// Where the record starts in the message.
+    pub fn start(&self) -> usize {
+        self.name.at
+    }
+
+    /// Where the record ends in the message.
+    pub fn end(&self) -> usize {
+        self.rdata_at + self.rdata.len()
+    }
+
+    /// Decodes the record in full, RDATA included.
+    pub fn to_record(self) -> Result<Record, Error> {
+        let mut dec = Decoder::new(self.name.msg);
+        dec.set_offset(self.start());
+        Record::decode(&mut dec)
+    }
+}
+
+/// A message with its header and questions decoded, its records read on
+/// demand.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    msg: &'a [u8],
+    pub id: u16,
+    pub qr: u8,
+    pub opcode: u8,
+    pub aa: u8,
+    pub tc: u8,
+    pub rd: u8,
+    pub ra: u8,
+    pub z: u8,
+    pub ad: u8,
+    pub cd: u8,
+    pub rcode: u8,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+    // where the answers start, past the questions
+    records_at: usize,
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn decode(msg: &'a [u8]) -> Result<Self, Error> {
+        let mut dec = Decoder::new(msg);
+        let id = dec.read_u16()?;
+        let (mut qr, mut opcode, mut aa, mut tc, mut rd) = (0, 0, 0, 0, 0);
+        dec.read_bits(|b| {
+            qr = b.read(1)?;
+            opcode = b.read(4)?;
+            aa = b.read(1)?;
+            tc = b.read(1)?;
+            rd = b.read(1)?;
+            Ok(())
+        })?;
+        let (mut ra, mut z, mut ad, mut cd, mut rcode) = (0, 0, 0, 0, 0);
+        dec.read_bits(|b| {
+            ra = b.read(1)?;
+            z = b.read(1)?;
+            ad = b.read(1)?;
+            cd = b.read(1)?;
+            rcode = b.read(4)?;
+            Ok(())
+        })?;
+        let qdcount = dec.read_u16()?;
+        let ancount = dec.read_u16()?;
+        let nscount = dec.read_u16()?;
+        let arcount = dec.read_u16()?;
+        for _ in 0..qdcount {
+            QuestionRef::decode(&mut dec)?;
+        }
+
+        Ok(Self {
+            msg,
+            id,
+            qr,
+            opcode,
+            aa,
+            tc,
+            rd,
+            ra,
+            z,
+            ad,
+            cd,
+            rcode,
+            qdcount,
+            ancount,
+            nscount,
+            arcount,
+            records_at: dec.offset(),
+        })
+    }
+
+    pub fn questions(&self) -> impl Iterator<Item = QuestionRef<'a>> + 'a {
+        let mut dec = Decoder::new(self.msg);
+        dec.set_offset(12);
+        // checked when decoded, none of them fails
+        (0..self.qdcount).map_while(move |_| QuestionRef::decode(&mut dec).ok())
+    }
+
+    /// The answer, authority and additional records in turn, OPT included.
+    /// Stops after the first that fails to decode.
+    pub fn records(&self) -> impl Iterator<Item = Result<RecordRef<'a>, Error>> + 'a {
+        let mut dec = Decoder::new(self.msg);
+        dec.set_offset(self.records_at);
+        let count = self.ancount as usize + self.nscount as usize + self.arcount as usize;
+        let mut failed = false;
+        (0..count).map_while(move |_| {
+            if failed {
+                return None;
+            }
+            let record = RecordRef::decode(&mut dec);
+            failed = record.is_err();
+            Some(record)
+        })
+    }
+
+    /// Whether this is a reply echoing `questions`, in the exact case sent.
+    pub fn is_reply_to(&self, questions: &[Question]) -> bool {
+        self.qr == 1
+            && self.qdcount as usize == questions.len()
+            && self
+                .questions()
+                .zip(questions)
+                .all(|(r, q)| r.name.is(&q.name) && r.qtype == q.qtype && r.class == q.class)
+    }
+
+    /// Copies the message for later, undecoded.
+    pub fn to_wire(self) -> WireMessage {
+        WireMessage {
+            buf: self.msg.to_vec(),
+        }
+    }
+
+    /// Converts to an owned `Message`. The header and questions are taken
+    /// as decoded, only the records are read past them.
+    pub fn to_message(self) -> Result<Message, Error> {
+        let mut msg = Message {
+            id: self.id,
+            qr: self.qr,
+            opcode: self.opcode,
+            aa: self.aa,
+            tc: self.tc,
+            rd: self.rd,
+            ra: self.ra,
+            z: self.z,
+            ad: self.ad,
+            cd: self.cd,
+            rcode: self.rcode,
+            questions: self.questions().map(QuestionRef::to_question).collect(),
+            ..Message::default()
+        };
+        let mut dec = Decoder::new(self.msg);
+        dec.set_offset(self.records_at);
+        msg.decode_records(&mut dec, self.ancount, self.nscount, self.arcount)?;
+        Ok(msg)
+    }
+}
+
+/// A message in wire format, its header and questions checked. Read through
+/// `view`, decoded in full with `to_message` once something needs to change
+/// more than its ID or the case of its names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireMessage {
+    buf: Vec<u8>,
+}
+
+impl WireMessage {
+    pub fn new(buf: Vec<u8>) -> Result<Self, Error> {
+        MessageRef::decode(&buf)?;
+        Ok(Self { buf })
+    }
+
+    pub fn view(&self) -> MessageRef<'_> {
+        // checked when made, patches keep the structure
+        MessageRef::decode(&self.buf).unwrap()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        self.buf[..2].copy_from_slice(&id.to_be_bytes());
+    }
+
+    /// Gives the questions, and the owners of records named like them, the
+    /// case of `questions` back. Names pointing into them follow along.
+    pub fn restore_case(&mut self, questions: &[Question]) {
+        let view = self.view();
+        let records = view.records().map_while(Result::ok).map(|r| r.name);
+        let mut patches = Vec::new();
+        for name in view.questions().map(|q| q.name).chain(records) {
+            if let Some(q) = questions.iter().find(|q| name.matches(&q.name)) {
+                patches.extend(name.label_offsets().zip(q.name.iter_labels()));
+            }
+        }
+        for (at, label) in patches {
+            self.buf[at..at + label.len()].copy_from_slice(label);
+        }
+    }
+
+    pub fn to_message(&self) -> Result<Message, Error> {
+        self.view().to_message()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MessageRef, WireMessage};
+    use crate::{
+        encoder::Error,
+        proto::{Class, Message, Name, Question, Record, Type},
+        rdata::{Mx, RData},
+    };
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_views() {
+        let question = Question {
+            name: Name::from("Www.example.com"),
+            qtype: Type::MX,
+            class: Class::IN,
+        };
+        let mx = Record {
+            name: Name::from("www.example.com"),
+            rtype: Type::MX,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::MX(Mx {
+                preference: 10,
+                exchange: Name::from("mail.example.com"),
+            }),
+        };
+        let a = Record {
+            name: Name::from("mail.example.com"),
+            rtype: Type::A,
+            class: Class::IN,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let msg = Message {
+            id: 7,
+            qr: 1,
+            aa: 1,
+            rd: 1,
+            cd: 1,
+            questions: vec![question.clone()],
+            answers: vec![mx.clone()],
+            additionals: vec![a.clone()],
+            ..Message::default()
+        };
+        let buf = msg.to_bytes().unwrap();
+
+        let view = MessageRef::decode(&buf).unwrap();
+        assert_eq!(
+            (7, 1, 1, 0, 1),
+            (view.id, view.qr, view.qdcount, view.nscount, view.arcount)
+        );
+        assert!(view.is_reply_to(std::slice::from_ref(&question)));
+        let lower = Question {
+            name: Name::from("www.example.com"),
+            ..question.clone()
+        };
+        assert!(!view.is_reply_to(&[lower]));
+        assert_eq!(
+            vec![question.clone()],
+            view.questions()
+                .map(|q| q.to_question())
+                .collect::<Vec<_>>()
+        );
+
+        let records: Vec<_> = view.records().map(Result::unwrap).collect();
+        assert_eq!(2, records.len());
+        // the exchange is compressed, the view leaves it as it is
+        assert_eq!(
+            &[0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 16],
+            records[0].rdata
+        );
+        assert_eq!(
+            vec![&b"mail"[..], b"example", b"com"],
+            records[1].name.labels().collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(mx), records[0].to_record());
+        assert_eq!(Ok(a), records[1].to_record());
+        assert_eq!(buf.len(), records[1].end());
+        assert_eq!(Ok(msg), view.to_message());
+
+        // patched in place, names compressed against the question follow it
+        let mut wire = WireMessage::new(buf.clone()).unwrap();
+        wire.set_id(9);
+        let upper = Question {
+            name: Name::from("WWW.Example.com"),
+            ..question.clone()
+        };
+        wire.restore_case(std::slice::from_ref(&upper));
+        let msg = wire.to_message().unwrap();
+        assert_eq!(9, msg.id);
+        assert_eq!(vec![upper], msg.questions);
+        assert_eq!("WWW.Example.com", msg.answers[0].name.to_string());
+        assert_eq!("mail.Example.com", msg.additionals[0].name.to_string());
+        assert!(WireMessage::new(buf[..11].to_vec()).is_err());
+
+        // a record cut short ends the iteration with its error
+        let view = MessageRef::decode(&buf[..buf.len() - 1]).unwrap();
+        let records: Vec<_> = view.records().collect();
+        assert_eq!(2, records.len());
+        assert!(matches!(records[1], Err(Error::ReadOutOfBounds { .. })));
+
+        // a question pointing at itself
+        let mut looped = buf[..12].to_vec();
+        looped.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(
+            Err(Error::TooManyPointers(16)),
+            MessageRef::decode(&looped).map(|_| ())
+        );
+    }
+}
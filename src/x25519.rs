@@ -0,0 +1,88 @@
+//! X25519 Diffie-Hellman (RFC 7748), the key exchange offered in TLS 1.3
+//! handshakes. Shares the field arithmetic of the `ed25519` module.
+
+use crate::ed25519::{fadd, finv, fmul, fsquare, fsub, pack_gf, select, unpack_gf, Gf, GF0, GF1};
+
+// (486662 - 2) / 4
+const A24: Gf = [0xdb41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const BASE: [u8; 32] = {
+    let mut base = [0; 32];
+    base[0] = 9;
+    base
+};
+
+/// The public key of `private`.
+pub fn public_key(private: &[u8; 32]) -> [u8; 32] {
+    shared_secret(private, &BASE)
+}
+
+/// `private` times the point `public`, with the Montgomery ladder (RFC 7748,
+/// section 5). Callers check for the all-zero result of small-order points.
+pub fn shared_secret(private: &[u8; 32], public: &[u8; 32]) -> [u8; 32] {
+    let mut k = *private;
+    k[0] &= 248;
+    k[31] = (k[31] & 127) | 64;
+    let x = unpack_gf(public);
+    let (mut a, mut b, mut c, mut d) = (GF1, x, GF0, GF1);
+    for i in (0..255).rev() {
+        let bit = ((k[i >> 3] >> (i & 7)) & 1) as i64;
+        select(&mut a, &mut b, bit);
+        select(&mut c, &mut d, bit);
+        let e = fadd(&a, &c);
+        a = fsub(&a, &c);
+        c = fadd(&b, &d);
+        b = fsub(&b, &d);
+        d = fsquare(&e);
+        let f = fsquare(&a);
+        a = fmul(&c, &a);
+        c = fmul(&b, &e);
+        let e = fadd(&a, &c);
+        a = fsub(&a, &c);
+        b = fsquare(&a);
+        c = fsub(&d, &f);
+        a = fmul(&c, &A24);
+        a = fadd(&a, &d);
+        c = fmul(&c, &a);
+        a = fmul(&d, &f);
+        d = fmul(&b, &x);
+        b = fsquare(&e);
+        select(&mut a, &mut b, bit);
+        select(&mut c, &mut d, bit);
+    }
+    pack_gf(&fmul(&a, &finv(&c)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{public_key, shared_secret};
+    use crate::encoding::{hex_decode, hex_encode};
+
+    fn key(hex: &str) -> [u8; 32] {
+        hex_decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        // RFC 7748, section 6.1
+        let alice = key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        assert_eq!(
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+            hex_encode(&public_key(&alice))
+        );
+        assert_eq!(
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+            hex_encode(&public_key(&bob))
+        );
+        let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+        assert_eq!(
+            shared,
+            hex_encode(&shared_secret(&alice, &public_key(&bob)))
+        );
+        assert_eq!(
+            shared,
+            hex_encode(&shared_secret(&bob, &public_key(&alice)))
+        );
+    }
+}
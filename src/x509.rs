@@ -0,0 +1,1064 @@
+//! X.509 certificates (RFC 5280), just enough to authenticate TLS servers:
+//! DER parsing, chains checked up to a root from a PEM bundle, and the
+//! host name or address matched against the subject alternative names.
+//! Names are compared as their DER encodings. Also the private keys our own
+//! TLS server signs with, and the pins servers can be held to.
+
+use std::{collections::HashMap, fmt, fs, net::IpAddr, path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    digest::{sha256, sha384, sha512},
+    ecdsa::{self, Curve},
+    ed25519,
+    encoding::{base64_decode, base64_encode},
+    rdata::parse_datetime,
+    rsa,
+};
+
+/// Root certificates of most Linux distributions.
+pub const DEFAULT_ROOTS: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Intermediates allowed between a server certificate and a root.
+const MAX_DEPTH: usize = 8;
+
+// object identifiers, as DER contents
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+// DER tags
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+
+/// Reader of DER encoded values with single byte tags.
+pub struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Tag of the next value, if any.
+    pub fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// The next value's tag, contents and whole encoding.
+    fn next_raw(&mut self) -> Result<(u8, &'a [u8], &'a [u8])> {
+        let data = self.data;
+        let malformed = || anyhow!("malformed DER");
+        let (&tag, rest) = data.split_first().ok_or_else(malformed)?;
+        let (&first, mut rest) = rest.split_first().ok_or_else(malformed)?;
+        let len = match first {
+            0..=0x7f => first as usize,
+            0x81..=0x84 => {
+                let (bytes, tail) = rest
+                    .split_at_checked((first & 0x7f) as usize)
+                    .ok_or_else(malformed)?;
+                rest = tail;
+                bytes.iter().fold(0, |len, b| (len << 8) | *b as usize)
+            }
+            _ => bail!("unsupported DER length {:#04x}", first),
+        };
+        let (contents, tail) = rest.split_at_checked(len).ok_or_else(malformed)?;
+        self.data = tail;
+        Ok((tag, contents, &data[..data.len() - tail.len()]))
+    }
+
+    /// Contents of the next value, which must have tag `tag`.
+    pub fn read(&mut self, tag: u8) -> Result<&'a [u8]> {
+        match self.next_raw()? {
+            (found, contents, _) if found == tag => Ok(contents),
+            (found, _, _) => bail!("expected DER tag {:#04x}, found {:#04x}", tag, found),
+        }
+    }
+
+    /// Contents of the next value if it has tag `tag`.
+    pub fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>> {
+        match self.peek() == Some(tag) {
+            true => self.read(tag).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Reader over the contents of the next value.
+    pub fn nested(&mut self, tag: u8) -> Result<Der<'a>> {
+        self.read(tag).map(Der::new)
+    }
+
+    /// Bytes of the next BIT STRING, which must have no unused bits.
+    pub fn bits(&mut self) -> Result<&'a [u8]> {
+        match self.read(BIT_STRING)?.split_first() {
+            Some((0, bits)) => Ok(bits),
+            _ => bail!("unsupported BIT STRING"),
+        }
+    }
+
+    /// Magnitude of the next non-negative INTEGER, without leading zeros.
+    pub fn unsigned(&mut self) -> Result<&'a [u8]> {
+        let int = self.read(INTEGER)?;
+        if int.first().is_some_and(|b| b & 0x80 != 0) {
+            bail!("negative INTEGER");
+        }
+        let zeros = int.iter().take_while(|b| **b == 0).count();
+        Ok(&int[zeros..])
+    }
+}
+
+/// Public key of a certificate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublicKey {
+    Rsa(rsa::PublicKey),
+    // curve and the uncompressed point's x and y
+    Ecdsa(&'static Curve, Vec<u8>),
+    Ed25519([u8; 32]),
+}
+
+/// Algorithm a signature was made with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureScheme {
+    RsaPkcs1(rsa::Hash),
+    RsaPss(rsa::Hash),
+    // ECDSA over the key's curve with the given hash
+    Ecdsa(rsa::Hash),
+    Ed25519,
+}
+
+impl PublicKey {
+    /// Whether `signature` is a valid `scheme` signature of `data`. ECDSA
+    /// signatures are DER encoded, as in certificates and TLS.
+    pub fn verify(&self, scheme: SignatureScheme, data: &[u8], signature: &[u8]) -> bool {
+        match (self, scheme) {
+            (Self::Rsa(key), SignatureScheme::RsaPkcs1(hash)) => {
+                key.verify_pkcs1(hash, data, signature)
+            }
+            (Self::Rsa(key), SignatureScheme::RsaPss(hash)) => {
+                key.verify_pss(hash, data, signature)
+            }
+            (Self::Ecdsa(curve, point), SignatureScheme::Ecdsa(hash)) => {
+                let digest = match hash {
+                    rsa::Hash::Sha1 => return false,
+                    rsa::Hash::Sha256 => sha256(data).to_vec(),
+                    rsa::Hash::Sha384 => sha384(data).to_vec(),
+                    rsa::Hash::Sha512 => sha512(data).to_vec(),
+                };
+                match ecdsa_signature(signature, curve.size()) {
+                    Some(signature) => ecdsa::verify_digest(curve, point, &digest, &signature),
+                    None => false,
+                }
+            }
+            (Self::Ed25519(key), SignatureScheme::Ed25519) => ed25519::verify(key, data, signature),
+            _ => false,
+        }
+    }
+}
+
+/// r and s of a DER encoded ECDSA-Sig-Value (RFC 5480, section 2.2), each
+/// padded to `len` bytes.
+fn ecdsa_signature(der: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut outer = Der::new(der);
+    let mut seq = outer.nested(SEQUENCE).ok()?;
+    let mut out = Vec::with_capacity(2 * len);
+    for _ in 0..2 {
+        let int = seq.unsigned().ok()?;
+        out.extend(std::iter::repeat_n(0, len.checked_sub(int.len())?));
+        out.extend_from_slice(int);
+    }
+    (outer.is_empty() && seq.is_empty()).then_some(out)
+}
+
+/// DER encoded ECDSA-Sig-Value of r and s, each `raw.len() / 2` bytes.
+fn encode_ecdsa_signature(raw: &[u8]) -> Vec<u8> {
+    let mut ints = Vec::new();
+    for int in raw.chunks(raw.len() / 2) {
+        let zeros = int.iter().take_while(|b| **b == 0).count();
+        let mut int = int[zeros..].to_vec();
+        if int.first().is_none_or(|b| b & 0x80 != 0) {
+            int.insert(0, 0);
+        }
+        ints.push(INTEGER);
+        ints.push(int.len() as u8);
+        ints.extend(int);
+    }
+    // at most 2 * (2 + 1 + 66) bytes
+    match ints.len() {
+        0..=0x7f => [&[SEQUENCE, ints.len() as u8][..], &ints].concat(),
+        _ => [&[SEQUENCE, 0x81, ints.len() as u8][..], &ints].concat(),
+    }
+}
+
+/// Private key of a TLS server certificate.
+#[derive(Clone)]
+pub enum PrivateKey {
+    // curve and the private scalar
+    Ecdsa(&'static Curve, Vec<u8>),
+    // the seed
+    Ed25519([u8; 32]),
+}
+
+impl PrivateKey {
+    /// Parses a PKCS #8 PrivateKeyInfo (RFC 5208) or an ECPrivateKey
+    /// (RFC 5915), `Ok(None)` for algorithms that aren't supported.
+    fn from_der(der: &[u8], label: &str) -> Result<Option<Self>> {
+        let mut outer = Der::new(der);
+        let mut info = outer.nested(SEQUENCE)?;
+        if label == "EC PRIVATE KEY" {
+            return parse_ec_private_key(&mut info, None);
+        }
+        info.read(INTEGER)?;
+        let mut algorithm = info.nested(SEQUENCE)?;
+        let mut key = info.nested(OCTET_STRING)?;
+        match algorithm.read(OID)? {
+            ED25519 => Ok(key
+                .read(OCTET_STRING)?
+                .try_into()
+                .ok()
+                .map(PrivateKey::Ed25519)),
+            EC_PUBLIC_KEY => {
+                let curve = algorithm.read(OID)?;
+                parse_ec_private_key(&mut key.nested(SEQUENCE)?, Some(curve))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Scheme of the signatures `sign` makes.
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Self::Ecdsa(curve, _) if **curve == ecdsa::P384 => {
+                SignatureScheme::Ecdsa(rsa::Hash::Sha384)
+            }
+            Self::Ecdsa(_, _) => SignatureScheme::Ecdsa(rsa::Hash::Sha256),
+            Self::Ed25519(_) => SignatureScheme::Ed25519,
+        }
+    }
+
+    pub fn public_key(&self) -> Option<PublicKey> {
+        match self {
+            Self::Ecdsa(curve, scalar) => {
+                ecdsa::public_key(curve, scalar).map(|point| PublicKey::Ecdsa(curve, point))
+            }
+            Self::Ed25519(seed) => Some(PublicKey::Ed25519(ed25519::public_key(seed))),
+        }
+    }
+
+    /// Signature of `data`, DER encoded for ECDSA as `PublicKey::verify`
+    /// expects it.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Ecdsa(curve, scalar) => {
+                let digest = match curve.size() {
+                    32 => sha256(data).to_vec(),
+                    _ => sha384(data).to_vec(),
+                };
+                encode_ecdsa_signature(&ecdsa::sign_digest(curve, scalar, &digest))
+            }
+            Self::Ed25519(seed) => ed25519::sign(seed, data).to_vec(),
+        }
+    }
+}
+
+/// The contents of an ECPrivateKey, whose curve is given either by `curve`
+/// or by its own parameters.
+fn parse_ec_private_key(key: &mut Der, curve: Option<&[u8]>) -> Result<Option<PrivateKey>> {
+    key.read(INTEGER)?;
+    let scalar = key.read(OCTET_STRING)?;
+    let curve = match (curve, key.optional(0xa0)?) {
+        (Some(curve), _) => curve,
+        (None, Some(parameters)) => Der::new(parameters).read(OID)?,
+        (None, None) => bail!("EC private key without a curve"),
+    };
+    let curve = match curve {
+        PRIME256V1 => &ecdsa::P256,
+        SECP384R1 => &ecdsa::P384,
+        _ => return Ok(None),
+    };
+    let Some(padding) = curve.size().checked_sub(scalar.len()) else {
+        bail!("EC private key too long");
+    };
+    let scalar = [&vec![0; padding][..], scalar].concat();
+    Ok(Some(PrivateKey::Ecdsa(curve, scalar)))
+}
+
+/// Parsed certificate.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    // as received or loaded, for sending it on
+    der: Vec<u8>,
+    // the signed part, TBSCertificate
+    tbs: Vec<u8>,
+    scheme: Option<SignatureScheme>,
+    signature: Vec<u8>,
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    // validity, in seconds since the epoch
+    not_before: i64,
+    not_after: i64,
+    // SubjectPublicKeyInfo, as encoded
+    spki: Vec<u8>,
+    pub public_key: Option<PublicKey>,
+    // basicConstraints cA
+    is_ca: bool,
+    // basicConstraints pathLenConstraint, how many CA certificates may
+    // follow this one down to a server's
+    path_len: Option<usize>,
+    // keyUsage keyCertSign, or no keyUsage at all
+    signs_certificates: bool,
+    dns_names: Vec<String>,
+    ip_addrs: Vec<IpAddr>,
+}
+
+impl Certificate {
+    /// Parses a DER encoded certificate (RFC 5280, section 4.1). Keys and
+    /// signature algorithms that aren't supported parse as `None`.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let mut outer = Der::new(der);
+        let mut cert = outer.nested(SEQUENCE)?;
+        let (tag, contents, tbs) = cert.next_raw()?;
+        if tag != SEQUENCE {
+            bail!("malformed certificate");
+        }
+        let mut tbs_der = Der::new(contents);
+        let scheme = signature_scheme(&mut cert.nested(SEQUENCE)?)?;
+        let signature = cert.bits()?.to_vec();
+        if !cert.is_empty() || !outer.is_empty() {
+            bail!("trailing data after certificate");
+        }
+
+        // version [0], serial number, signature algorithm
+        tbs_der.optional(0xa0)?;
+        tbs_der.read(INTEGER)?;
+        tbs_der.read(SEQUENCE)?;
+        let issuer = tbs_der.next_raw()?.2.to_vec();
+        let mut validity = tbs_der.nested(SEQUENCE)?;
+        let not_before = parse_time(&mut validity)?;
+        let not_after = parse_time(&mut validity)?;
+        let subject = tbs_der.next_raw()?.2.to_vec();
+        let (tag, contents, spki) = tbs_der.next_raw()?;
+        if tag != SEQUENCE {
+            bail!("malformed certificate");
+        }
+        let public_key = parse_public_key(&mut Der::new(contents))?;
+
+        let mut certificate = Self {
+            der: der.to_vec(),
+            tbs: tbs.to_vec(),
+            scheme,
+            signature,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            spki: spki.to_vec(),
+            public_key,
+            is_ca: false,
+            path_len: None,
+            signs_certificates: true,
+            dns_names: Vec::new(),
+            ip_addrs: Vec::new(),
+        };
+        // issuer and subject unique IDs [1] and [2], extensions [3]
+        tbs_der.optional(0x81)?;
+        tbs_der.optional(0x82)?;
+        if let Some(extensions) = tbs_der.optional(0xa3)? {
+            let mut extensions = Der::new(extensions).nested(SEQUENCE)?;
+            while !extensions.is_empty() {
+                certificate.parse_extension(&mut extensions.nested(SEQUENCE)?)?;
+            }
+        }
+        Ok(certificate)
+    }
+
+    /// Reads an extension, failing on a critical one that isn't known, as
+    /// what it restricts would go unchecked (RFC 5280, section 4.2).
+    fn parse_extension(&mut self, extension: &mut Der) -> Result<()> {
+        let oid = extension.read(OID)?;
+        let critical = extension.optional(BOOLEAN)? == Some(&[0xff]);
+        let mut value = extension.nested(OCTET_STRING)?;
+        match oid {
+            BASIC_CONSTRAINTS => {
+                let mut constraints = value.nested(SEQUENCE)?;
+                self.is_ca = constraints.optional(BOOLEAN)? == Some(&[0xff]);
+                if constraints.peek() == Some(INTEGER) {
+                    self.path_len = Some(match constraints.unsigned()? {
+                        [] => 0,
+                        [len] => *len as usize,
+                        _ => usize::MAX,
+                    });
+                }
+            }
+            KEY_USAGE => {
+                // after the count of unused bits, keyCertSign is bit 5
+                let usage = value.read(BIT_STRING)?;
+                self.signs_certificates = usage.get(1).is_some_and(|bits| bits & 0x04 != 0);
+            }
+            SUBJECT_ALT_NAME => {
+                let mut names = value.nested(SEQUENCE)?;
+                while !names.is_empty() {
+                    let (tag, name, _) = names.next_raw()?;
+                    match (tag, name.len()) {
+                        // dNSName [2]
+                        (0x82, _) => self
+                            .dns_names
+                            .push(String::from_utf8_lossy(name).to_ascii_lowercase()),
+                        // iPAddress [7]
+                        (0x87, 4) => self
+                            .ip_addrs
+                            .push(<[u8; 4]>::try_from(name).unwrap().into()),
+                        (0x87, 16) => self
+                            .ip_addrs
+                            .push(<[u8; 16]>::try_from(name).unwrap().into()),
+                        _ => {}
+                    }
+                }
+            }
+            _ if critical => bail!("unsupported critical extension {:02x?}", oid),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Whether `now` is within the validity period.
+    pub fn is_valid_at(&self, now: i64) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+
+    /// Whether the certificate is for `host`, a name or an address, with
+    /// wildcards only as a whole leftmost label (RFC 6125, section 6.4.3).
+    pub fn matches(&self, host: &str) -> bool {
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return self.ip_addrs.contains(&addr);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.dns_names
+            .iter()
+            .any(|name| match name.strip_prefix("*.") {
+                Some(parent) => host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == parent),
+                None => *name == host,
+            })
+    }
+
+    /// Whether this is a CA certificate that may issue others, with `below`
+    /// CA certificates between it and a server's.
+    fn can_issue(&self, below: usize) -> bool {
+        self.is_ca && self.signs_certificates && self.path_len.is_none_or(|len| below <= len)
+    }
+
+    /// Whether `issuer` issued and signed this certificate.
+    fn issued_by(&self, issuer: &Certificate) -> bool {
+        match (&issuer.public_key, self.scheme) {
+            (Some(key), Some(scheme)) => {
+                self.issuer == issuer.subject && key.verify(scheme, &self.tbs, &self.signature)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn signature_scheme(algorithm: &mut Der) -> Result<Option<SignatureScheme>> {
+    Ok(match algorithm.read(OID)? {
+        SHA256_WITH_RSA => Some(SignatureScheme::RsaPkcs1(rsa::Hash::Sha256)),
+        SHA384_WITH_RSA => Some(SignatureScheme::RsaPkcs1(rsa::Hash::Sha384)),
+        SHA512_WITH_RSA => Some(SignatureScheme::RsaPkcs1(rsa::Hash::Sha512)),
+        ECDSA_WITH_SHA256 => Some(SignatureScheme::Ecdsa(rsa::Hash::Sha256)),
+        ECDSA_WITH_SHA384 => Some(SignatureScheme::Ecdsa(rsa::Hash::Sha384)),
+        ECDSA_WITH_SHA512 => Some(SignatureScheme::Ecdsa(rsa::Hash::Sha512)),
+        ED25519 => Some(SignatureScheme::Ed25519),
+        _ => None,
+    })
+}
+
+/// SubjectPublicKeyInfo (RFC 5280, section 4.1.2.7).
+fn parse_public_key(info: &mut Der) -> Result<Option<PublicKey>> {
+    let mut algorithm = info.nested(SEQUENCE)?;
+    let oid = algorithm.read(OID)?;
+    let key = info.bits()?;
+    Ok(match oid {
+        RSA_ENCRYPTION => {
+            let mut rsa_key = Der::new(key).nested(SEQUENCE)?;
+            let modulus = rsa_key.unsigned()?;
+            let exponent = rsa_key.unsigned()?;
+            Some(PublicKey::Rsa(rsa::PublicKey::new(modulus, exponent)))
+        }
+        EC_PUBLIC_KEY => {
+            let curve = match algorithm.read(OID)? {
+                PRIME256V1 => &ecdsa::P256,
+                SECP384R1 => &ecdsa::P384,
+                _ => return Ok(None),
+            };
+            match key.split_first() {
+                Some((4, point)) if point.len() == 2 * curve.size() => {
+                    Some(PublicKey::Ecdsa(curve, point.to_vec()))
+                }
+                _ => None,
+            }
+        }
+        ED25519 => key.try_into().ok().map(PublicKey::Ed25519),
+        _ => None,
+    })
+}
+
+/// UTCTime or GeneralizedTime, in seconds since the epoch (RFC 5280,
+/// section 4.1.2.5).
+fn parse_time(der: &mut Der) -> Result<i64> {
+    let (tag, value, _) = der.next_raw()?;
+    let value = std::str::from_utf8(value)?;
+    let digits = match (tag, value.strip_suffix('Z')) {
+        (UTC_TIME, Some(digits)) if digits.len() == 12 => {
+            let century = match digits < "50" {
+                true => "20",
+                false => "19",
+            };
+            format!("{}{}", century, digits)
+        }
+        (GENERALIZED_TIME, Some(digits)) => digits.to_string(),
+        _ => bail!("unsupported time {:?}", value),
+    };
+    parse_datetime(&digits).ok_or_else(|| anyhow!("invalid time {:?}", value))
+}
+
+/// Decoded contents of the PEM blocks of a file whose label `accepts`,
+/// with their labels.
+fn load_pem_blocks(path: &Path, accepts: impl Fn(&str) -> bool) -> Result<Vec<(String, Vec<u8>)>> {
+    let pem = fs::read_to_string(path)?;
+    let mut blocks = Vec::new();
+    let mut rest = pem.as_str();
+    while let Some(start) = rest.find("-----BEGIN ") {
+        rest = &rest[start + 11..];
+        let (label, tail) = rest
+            .split_once("-----")
+            .ok_or_else(|| anyhow!("{}: malformed PEM", path.display()))?;
+        let end = tail
+            .find(&format!("-----END {}-----", label))
+            .ok_or_else(|| anyhow!("{}: unterminated {}", path.display(), label))?;
+        if accepts(label) {
+            let base64: String = tail[..end].split_whitespace().collect();
+            let der = base64_decode(&base64)
+                .ok_or_else(|| anyhow!("{}: invalid base64 in {}", path.display(), label))?;
+            blocks.push((label.to_string(), der));
+        }
+        rest = &tail[end..];
+    }
+    Ok(blocks)
+}
+
+/// Certificates of a PEM file, those that don't parse are skipped.
+pub fn load_pem(path: &Path) -> Result<Vec<Certificate>> {
+    Ok(load_pem_blocks(path, |label| label == "CERTIFICATE")?
+        .into_iter()
+        .filter_map(|(_, der)| Certificate::from_der(&der).ok())
+        .collect())
+}
+
+/// The first private key of a PEM file, PKCS #8 or SEC1. Only ECDSA P-256
+/// and P-384 and Ed25519 keys are supported.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let blocks = load_pem_blocks(path, |label| label.ends_with("PRIVATE KEY"))?;
+    let Some((label, der)) = blocks.first() else {
+        bail!("{}: no private key", path.display());
+    };
+    PrivateKey::from_der(der, label)?.ok_or_else(|| {
+        anyhow!(
+            "{}: only ECDSA and Ed25519 keys are supported",
+            path.display()
+        )
+    })
+}
+
+/// What a server's certificate is expected to be, beyond a valid chain:
+/// the SHA-256 digest of its SubjectPublicKeyInfo, surviving renewals with
+/// the same key, or of the whole certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin {
+    Spki([u8; 32]),
+    Certificate([u8; 32]),
+}
+
+impl Pin {
+    pub fn matches(&self, cert: &Certificate) -> bool {
+        match self {
+            Self::Spki(digest) => sha256(&cert.spki) == *digest,
+            Self::Certificate(digest) => sha256(&cert.der) == *digest,
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    /// Parses `sha256/BASE64`, a key's pin as in HPKP (RFC 7469, section
+    /// 2.4), or `cert-sha256/BASE64` for a whole certificate.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, digest) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected sha256/BASE64, got {:?}", s))?;
+        let digest = base64_decode(digest)
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .ok_or_else(|| format!("invalid SHA-256 digest in {:?}", s))?;
+        match kind {
+            "sha256" => Ok(Self::Spki(digest)),
+            "cert-sha256" => Ok(Self::Certificate(digest)),
+            _ => Err(format!("unknown pin type {:?}", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spki(digest) => write!(f, "sha256/{}", base64_encode(digest)),
+            Self::Certificate(digest) => write!(f, "cert-sha256/{}", base64_encode(digest)),
+        }
+    }
+}
+
+/// Parses a `host=pin[,pin...]` rule.
+pub fn parse_pin_rule(s: &str) -> Result<(String, Vec<Pin>), String> {
+    let (host, pins) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected HOST=PIN, got {:?}", s))?;
+    let pins = pins.split(',').map(str::parse).collect::<Result<_, _>>()?;
+    Ok((host.into(), pins))
+}
+
+/// The pins servers are held to, by host: a pinned host's certificate must
+/// match one of its pins besides being valid. Hosts are compared ignoring
+/// ASCII case.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostPins(HashMap<String, Vec<Pin>>);
+
+impl HostPins {
+    /// Adds `pins` to those of `host`.
+    pub fn insert(&mut self, host: &str, pins: Vec<Pin>) {
+        self.0
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .extend(pins);
+    }
+
+    /// Pins of `host`, none if it isn't pinned.
+    pub fn get(&self, host: &str) -> &[Pin] {
+        self.0
+            .get(&host.to_ascii_lowercase())
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+impl FromIterator<(String, Vec<Pin>)> for HostPins {
+    fn from_iter<I: IntoIterator<Item = (String, Vec<Pin>)>>(rules: I) -> Self {
+        let mut pins = Self::default();
+        for (host, host_pins) in rules {
+            pins.insert(&host, host_pins);
+        }
+        pins
+    }
+}
+
+/// Checks that `chain`, the server's certificate followed by intermediates
+/// in any order, leads up to one of `roots` and is valid for `host` at
+/// `now`, in seconds since the epoch. Roots are trusted as they are, only
+/// their keys are used.
+pub fn verify_chain(
+    chain: &[Certificate],
+    roots: &[Certificate],
+    host: &str,
+    now: i64,
+) -> Result<()> {
+    let Some(leaf) = chain.first() else {
+        bail!("no server certificate");
+    };
+    if !leaf.matches(host) {
+        bail!("certificate is not valid for {}", host);
+    }
+    let mut current = leaf;
+    // CA certificates between the current one and the server's
+    for below in 0..=MAX_DEPTH {
+        if !current.is_valid_at(now) {
+            bail!("certificate has expired or is not yet valid");
+        }
+        if roots.iter().any(|root| current.issued_by(root)) {
+            return Ok(());
+        }
+        current = chain[1..]
+            .iter()
+            .find(|cert| cert.can_issue(below) && current.issued_by(cert))
+            .ok_or_else(|| anyhow!("certificate is not issued by a trusted root"))?;
+    }
+    bail!("certificate chain is too long")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ecdsa_signature, encode_ecdsa_signature, parse_pin_rule, verify_chain, Certificate, Der,
+        HostPins, Pin, PrivateKey, PublicKey, SignatureScheme, BASIC_CONSTRAINTS, BIT_STRING,
+        BOOLEAN, ED25519, INTEGER, KEY_USAGE, OCTET_STRING, OID, SEQUENCE, SUBJECT_ALT_NAME,
+        UTC_TIME,
+    };
+    use crate::{ecdsa, ed25519, encoding::base64_decode, rdata::parse_datetime, rsa};
+    use anyhow::Result;
+
+    // made with the Python cryptography package: a P-384 root, an RSA
+    // intermediate signed by it with SHA-384, an Ed25519 server certificate
+    // signed by that with SHA-256 for dns.example, *.example.net and
+    // 192.0.2.1
+    const ROOT: &str = "\
+        MIIBezCCAQCgAwIBAgIUGyhMoA6rOp1W7JeUQ+rqou4DIQUwCgYIKoZIzj0EAwMw\
+        FDESMBAGA1UEAwwJVGVzdCBSb290MB4XDTIwMDEwMTAwMDAwMFoXDTQ1MDEwMTAw\
+        MDAwMFowFDESMBAGA1UEAwwJVGVzdCBSb290MHYwEAYHKoZIzj0CAQYFK4EEACID\
+        YgAEpQ54RQV2pZIFPVDKIR7s8HU71r5ixw2JS3mYfxQtbi3p6HoJk5jt4uNKizkk\
+        RV9WHBGvLfN1vzjNwWrAvYd+xWfcyIBCaSezipvs8owCI4vfC7YzfBFbmPuF8WsQ\
+        Z3RIoxMwETAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMDA2kAMGYCMQCDlQiE\
+        AShTsN/qqu3oZ0o4LNWvc/HjH+TM9dnpagZp7NogkK+gV8A8+8aj9OhY+f0CMQDm\
+        5g0GP9BHS4PKHkrXDSgVRCf2GgtE7QbEHd5ENoHxUOd5VW44bci3kZ79W/bFuOE=";
+    const INTERMEDIATE: &str = "\
+        MIIBrTCCATKgAwIBAgIUTaervlJR2MivOVRjYULx0smTQdQwCgYIKoZIzj0EAwMw\
+        FDESMBAGA1UEAwwJVGVzdCBSb290MB4XDTIwMDEwMTAwMDAwMFoXDTQ1MDEwMTAw\
+        MDAwMFowHDEaMBgGA1UEAwwRVGVzdCBJbnRlcm1lZGlhdGUwgZ8wDQYJKoZIhvcN\
+        AQEBBQADgY0AMIGJAoGBAL5JxZiowQIHQruV5Omd5IJB/4jfDXZCRm95aLMXkMQQ\
+        BhKIMv9LDK5I0ttaWABoUgU8TZZqNiqv4g3FDPljXMoQRv3rfswX0ULAY9HyZtrq\
+        5iWmIVMtCH0fDoml5M444AcuURG+YD+DwDvd/P+b48ACth7C5epMdMo78S6Gb3K1\
+        AgMBAAGjEzARMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwMDaQAwZgIxAMFz\
+        EAiZbYiMv+1OQuFGfczMr09wxS1bAesGc5jW0bAR1wcE/o2Z+T67biUMA+JmywIx\
+        AIO/3MPf8EYgwMe5jHx5kyZNDxehkx158wpn/1LnsZS/rYIROEz0m1Zkmr+7Lek/\
+        zg==";
+    const LEAF: &str = "\
+        MIIBgzCB7aADAgECAhQ+jRVa5O3+YA2nZRSwyucjFOt7EjANBgkqhkiG9w0BAQsF\
+        ADAcMRowGAYDVQQDDBFUZXN0IEludGVybWVkaWF0ZTAgFw0yNDAxMDEwMDAwMDBa\
+        GA8yMDYwMDEwMTAwMDAwMFowFjEUMBIGA1UEAwwLZG5zLmV4YW1wbGUwKjAFBgMr\
+        ZXADIQBf1KmsDn6rLNS1cIEg8vw0YFIuBYRO9JoI2c9X1aoiF6M9MDswDAYDVR0T\
+        AQH/BAIwADArBgNVHREEJDAiggtkbnMuZXhhbXBsZYINKi5leGFtcGxlLm5ldIcE\
+        wAACATANBgkqhkiG9w0BAQsFAAOBgQCdalAlNbmOJrynEHM5T+kLASsKDqUqu4Ae\
+        XRwLVMbAxli9mZNJREuVJrwzBgTsQeAewJnvSx9FaruzjvFS4H4x7lsuqoQ8rrGx\
+        D/Uc4jYgphZZ0oAPE+ywmlbFqqFAw0PpSPP/svvlVOdxUdqLUh1TYT/3vM3UTl/1\
+        rDBCNebZvA==";
+
+    fn cert(pem: &str) -> Certificate {
+        let base64: String = pem.split_whitespace().collect();
+        Certificate::from_der(&base64_decode(&base64).unwrap()).unwrap()
+    }
+
+    /// DER value of `tag` with `contents`, shorter than 64 KiB.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let len = contents.len();
+        let header = match len {
+            0..=0x7f => vec![tag, len as u8],
+            0x80..=0xff => vec![tag, 0x81, len as u8],
+            _ => vec![tag, 0x82, (len >> 8) as u8, len as u8],
+        };
+        [header, contents.to_vec()].concat()
+    }
+
+    fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+        let critical = match critical {
+            true => der(BOOLEAN, &[0xff]),
+            false => Vec::new(),
+        };
+        der(
+            SEQUENCE,
+            &[der(OID, oid), critical, der(OCTET_STRING, value)].concat(),
+        )
+    }
+
+    /// basicConstraints of a CA certificate, with `path_len` if any.
+    fn ca(path_len: Option<u8>) -> Vec<u8> {
+        let path_len = path_len.map_or(Vec::new(), |len| der(INTEGER, &[len]));
+        let constraints = der(SEQUENCE, &[der(BOOLEAN, &[0xff]), path_len].concat());
+        extension(BASIC_CONSTRAINTS, true, &constraints)
+    }
+
+    /// A certificate for common name `subject` with the Ed25519 key of
+    /// `seed`, issued by `issuer` and signed with the key of `issuer_seed`.
+    fn issue(
+        subject: &str,
+        seed: u8,
+        (issuer, issuer_seed): (&str, u8),
+        extensions: &[Vec<u8>],
+    ) -> Result<Certificate> {
+        let name = |cn: &str| {
+            let attribute = [der(OID, &[0x55, 0x04, 0x03]), der(0x0c, cn.as_bytes())].concat();
+            der(SEQUENCE, &der(0x31, &der(SEQUENCE, &attribute)))
+        };
+        let algorithm = der(SEQUENCE, &der(OID, ED25519));
+        let public_key = ed25519::public_key(&[seed; 32]);
+        let spki = [
+            algorithm.clone(),
+            der(BIT_STRING, &[&[0][..], &public_key].concat()),
+        ]
+        .concat();
+        let validity = [
+            der(UTC_TIME, b"200101000000Z"),
+            der(UTC_TIME, b"450101000000Z"),
+        ]
+        .concat();
+        let tbs = der(
+            SEQUENCE,
+            &[
+                der(0xa0, &der(INTEGER, &[2])),
+                der(INTEGER, &[seed]),
+                algorithm.clone(),
+                name(issuer),
+                der(SEQUENCE, &validity),
+                name(subject),
+                der(SEQUENCE, &spki),
+                der(0xa3, &der(SEQUENCE, &extensions.concat())),
+            ]
+            .concat(),
+        );
+        let signature = PrivateKey::Ed25519([issuer_seed; 32]).sign(&tbs);
+        let signature = der(BIT_STRING, &[&[0][..], &signature].concat());
+        Certificate::from_der(&der(SEQUENCE, &[tbs, algorithm, signature].concat()))
+    }
+
+    #[test]
+    fn test_der() {
+        let mut der = Der::new(&[0x30, 0x06, 0x02, 0x01, 0x05, 0x02, 0x01, 0x00]);
+        let mut seq = der.nested(0x30).unwrap();
+        assert!(der.is_empty());
+        assert_eq!(&[5], seq.unsigned().unwrap());
+        assert_eq!(None, seq.optional(0x04).unwrap());
+        assert!(seq.unsigned().unwrap().is_empty());
+        assert!(seq.read(0x02).is_err());
+
+        // long form length
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend([7; 0x80]);
+        assert_eq!(0x80, Der::new(&long).read(0x04).unwrap().len());
+        assert!(Der::new(&long[..100]).read(0x04).is_err());
+
+        assert_eq!(
+            Some(vec![0, 1, 0, 2]),
+            ecdsa_signature(&[0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00, 0x02], 2)
+        );
+        assert_eq!(
+            None,
+            ecdsa_signature(&[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x82], 2)
+        );
+    }
+
+    #[test]
+    fn test_certificate() {
+        let leaf = cert(LEAF);
+        assert!(leaf.matches("dns.example"));
+        assert!(leaf.matches("DNS.example."));
+        assert!(leaf.matches("a.example.net"));
+        assert!(!leaf.matches("example.net"));
+        assert!(!leaf.matches("a.b.example.net"));
+        assert!(!leaf.matches("other.example"));
+        assert!(leaf.matches("192.0.2.1"));
+        assert!(!leaf.matches("192.0.2.2"));
+        assert!(matches!(leaf.public_key, Some(PublicKey::Ed25519(_))));
+        assert!(!leaf.is_ca);
+
+        let start = parse_datetime("20240101000000").unwrap();
+        let end = parse_datetime("20600101000000").unwrap();
+        assert!(leaf.is_valid_at(start) && leaf.is_valid_at(end));
+        assert!(!leaf.is_valid_at(start - 1) && !leaf.is_valid_at(end + 1));
+
+        let intermediate = cert(INTERMEDIATE);
+        assert!(intermediate.is_ca);
+        assert!(matches!(intermediate.public_key, Some(PublicKey::Rsa(_))));
+        assert_eq!(
+            Some(SignatureScheme::RsaPkcs1(rsa::Hash::Sha256)),
+            leaf.scheme
+        );
+        assert!(leaf.issued_by(&intermediate));
+        assert!(!leaf.issued_by(&cert(ROOT)));
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let (root, intermediate, leaf) = (cert(ROOT), cert(INTERMEDIATE), cert(LEAF));
+        let roots = [root.clone()];
+        let now = parse_datetime("20250101000000").unwrap();
+        let chain = [leaf.clone(), intermediate.clone()];
+        assert!(verify_chain(&chain, &roots, "dns.example", now).is_ok());
+        assert!(verify_chain(&chain, &roots, "www.example.net", now).is_ok());
+        // with the root sent along
+        let full = [leaf.clone(), root.clone(), intermediate.clone()];
+        assert!(verify_chain(&full, &roots, "dns.example", now).is_ok());
+
+        assert!(verify_chain(&chain, &roots, "dns.example.org", now).is_err());
+        assert!(verify_chain(&chain, &roots, "dns.example", now + 40 * 365 * 86400).is_err());
+        assert!(verify_chain(std::slice::from_ref(&leaf), &roots, "dns.example", now).is_err());
+        assert!(verify_chain(
+            &chain,
+            std::slice::from_ref(&intermediate),
+            "dns.example",
+            now
+        )
+        .is_ok());
+        assert!(verify_chain(&chain, std::slice::from_ref(&leaf), "dns.example", now).is_err());
+        assert!(verify_chain(&[], &roots, "dns.example", now).is_err());
+        // a server certificate can't issue others
+        let forged = [leaf.clone(), leaf];
+        assert!(verify_chain(&forged, &roots, "dns.example", now).is_err());
+    }
+
+    #[test]
+    fn test_critical_extensions() {
+        let root = ("Root", 1);
+        let san = extension(
+            SUBJECT_ALT_NAME,
+            false,
+            &der(SEQUENCE, &der(0x82, b"dns.example")),
+        );
+        // 1.2.3.4, unknown
+        let unknown = |critical| extension(&[0x2a, 0x03, 0x04], critical, &[0x05, 0x00]);
+        assert!(issue("Leaf", 2, root, &[san.clone(), unknown(false)]).is_ok());
+        assert!(issue("Leaf", 2, root, &[san.clone(), unknown(true)]).is_err());
+        // keyUsage digitalSignature, known and left to the peer's checks
+        let usage = extension(KEY_USAGE, true, &der(BIT_STRING, &[0x07, 0x80]));
+        assert!(issue("Leaf", 2, root, &[san, usage]).is_ok());
+    }
+
+    #[test]
+    fn test_verify_chain_path_len() {
+        let now = parse_datetime("20250101000000").unwrap();
+        let roots = [issue("Root", 1, ("Root", 1), &[ca(None)]).unwrap()];
+        let san = extension(
+            SUBJECT_ALT_NAME,
+            false,
+            &der(SEQUENCE, &der(0x82, b"dns.example")),
+        );
+        let leaf = issue("Leaf", 4, ("Second", 3), &[san]).unwrap();
+        let second = issue("Second", 3, ("First", 2), &[ca(None)]).unwrap();
+        let verify = |first: &[Vec<u8>], second: &Certificate| {
+            let first = issue("First", 2, ("Root", 1), first).unwrap();
+            let chain = [leaf.clone(), second.clone(), first];
+            verify_chain(&chain, &roots, "dns.example", now)
+        };
+        assert!(verify(&[ca(None)], &second).is_ok());
+        assert!(verify(&[ca(Some(1))], &second).is_ok());
+        // one CA below the first where it allows none
+        assert!(verify(&[ca(Some(0))], &second).is_err());
+
+        // a CA whose key isn't for signing certificates
+        let usage = extension(KEY_USAGE, true, &der(BIT_STRING, &[0x07, 0x80]));
+        let signing = issue("Second", 3, ("First", 2), &[ca(None), usage]).unwrap();
+        assert!(verify(&[ca(None)], &signing).is_err());
+    }
+
+    #[test]
+    fn test_pin() {
+        // digests from OpenSSL
+        let spki: Pin = "sha256/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="
+            .parse()
+            .unwrap();
+        let whole: Pin = "cert-sha256/47oe/karkVnBrLU/+QnKDLUzGIVUlJHN1jCdXJLU//0="
+            .parse()
+            .unwrap();
+        let (leaf, intermediate) = (cert(LEAF), cert(INTERMEDIATE));
+        assert!(spki.matches(&leaf) && whole.matches(&leaf));
+        assert!(!spki.matches(&intermediate) && !whole.matches(&intermediate));
+        assert!(matches!(whole, Pin::Certificate(_)));
+        assert_eq!(Ok(spki), spki.to_string().parse());
+        assert_eq!(Ok(whole), whole.to_string().parse());
+
+        assert!("l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="
+            .parse::<Pin>()
+            .is_err());
+        assert!("sha1/l81Xw7eCQs86YTjstjrE/nrru8Ez1MmO6NLi9gG6Htg="
+            .parse::<Pin>()
+            .is_err());
+        assert!("sha256/c2VjcmV0".parse::<Pin>().is_err());
+
+        let rule = format!("dns.example={},{}", spki, whole);
+        assert_eq!(
+            Ok(("dns.example".into(), vec![spki, whole])),
+            parse_pin_rule(&rule)
+        );
+        assert!(parse_pin_rule(&spki.to_string()).is_err());
+
+        let pins = HostPins::from_iter([
+            ("DNS.example".into(), vec![spki]),
+            ("dns.example".into(), vec![whole]),
+        ]);
+        assert_eq!(&[spki, whole], pins.get("dns.Example"));
+        assert!(pins.get("other.example").is_empty());
+    }
+
+    #[test]
+    fn test_private_key() {
+        let key = |label: &str, pem: &str| {
+            let base64: String = pem.split_whitespace().collect();
+            PrivateKey::from_der(&base64_decode(&base64).unwrap(), label)
+                .unwrap()
+                .unwrap()
+        };
+        // made with OpenSSL: a P-256 key in PKCS #8 and SEC1, and an Ed25519
+        // key
+        let pkcs8 = key(
+            "PRIVATE KEY",
+            "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgtMgcrcIOfDOp3a6P\
+             /5Oo4B4QhqUXiUvv4OVFxaxX/12hRANCAAT26W3SGvPuyieumgUCUZyvW+Mjtaxi\
+             cfIXV8ewbn9ncUrFiVgBMnQHWtqSmktGi8rbcykFTrejinmOqZz2h9vE",
+        );
+        let sec1 = key(
+            "EC PRIVATE KEY",
+            "MHcCAQEEILTIHK3CDnwzqd2uj/+TqOAeEIalF4lL7+DlRcWsV/9doAoGCCqGSM49\
+             AwEHoUQDQgAE9ult0hrz7sonrpoFAlGcr1vjI7WsYnHyF1fHsG5/Z3FKxYlYATJ0\
+             B1rakppLRovK23MpBU63o4p5jqmc9ofbxA==",
+        );
+        let point = crate::encoding::hex_decode(
+            "f6e96dd21af3eeca27ae9a0502519caf5be323b5ac6271f21757c7b06e7f6771\
+             4ac58958013274075ada929a4b468bcadb7329054eb7a38a798ea99cf687dbc4",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(PublicKey::Ecdsa(&ecdsa::P256, point)),
+            pkcs8.public_key()
+        );
+        assert_eq!(pkcs8.public_key(), sec1.public_key());
+        let ed25519 = key(
+            "PRIVATE KEY",
+            "MC4CAQAwBQYDK2VwBCIEIDrP//rppSWdxOaZN2qf94lfRUauTAKmpOaYVBQagpPe",
+        );
+        assert_eq!(SignatureScheme::Ed25519, ed25519.scheme());
+
+        for key in [pkcs8, ed25519] {
+            let signature = key.sign(b"data");
+            let public = key.public_key().unwrap();
+            assert!(public.verify(key.scheme(), b"data", &signature));
+            assert!(!public.verify(key.scheme(), b"date", &signature));
+        }
+
+        // r with its high bit set gets a leading zero
+        let mut raw = vec![0x80; 32];
+        raw.extend([0x01; 32]);
+        let der = encode_ecdsa_signature(&raw);
+        assert_eq!(&[0x30, 0x45, 0x02, 0x21, 0x00, 0x80], &der[..6]);
+        assert_eq!(Some(raw), ecdsa_signature(&der, 32));
+    }
+}
@@ -0,0 +1,2180 @@
+use std::{
+    collections::HashMap,
+    fs, iter,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use thiserror::Error;
+
+use crate::{
+    acl::Network,
+    dnssec::{self, nsec3_hash, DenialChain, SigningKey},
+    encoder,
+    encoding::{base32hex_decode, base64_decode, hex_decode},
+    handler::{Context, Next, RequestHandler, Transport},
+    privacy,
+    proto::{opcode, rcode, Class, Message, Name, Question, Record, Type},
+    rdata::{
+        parse_timestamp, Dnskey, Ds, Hinfo, Mx, Nsec, Nsec3, Nsec3param, RData, Rrsig, Soa, Srv,
+        SvcParam, Svcb,
+    },
+    resolver::Resolver,
+    secondary::Secondary,
+};
+
+/// Longest CNAME chain followed inside a zone.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Size zone transfers are split into, well below the 64k TCP message limit.
+const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// Error in a zone file, with the 1-based line it was found on.
+#[derive(Error, Debug, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct ZoneError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Where a zone is read from and how it is served.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ZoneConfig {
+    // the owner of the SOA record when not set
+    pub origin: Option<Name>,
+    // master file, unless transferred from a primary
+    pub file: PathBuf,
+    // primary server to transfer the zone from, as a secondary
+    pub primary: Option<SocketAddr>,
+    // TTL of records without one, unless the file sets $TTL
+    pub default_ttl: Option<u32>,
+    // clients allowed to transfer the zone
+    pub allow_transfer: Vec<Network>,
+    // TSIG keys that may transfer the zone from any address
+    pub transfer_keys: Vec<Name>,
+    // secondaries sent NOTIFY whenever the zone is loaded
+    pub notify: Vec<SocketAddr>,
+    // private key the zone is signed with on load, generated if missing
+    pub dnssec_key: Option<PathBuf>,
+    // how the signed zone proves names don't exist
+    pub denial: DenialChain,
+}
+
+/// Authoritative data for the names at and below `origin`, read from a
+/// master file (RFC 1035, section 5).
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: Name,
+    pub allow_transfer: Vec<Network>,
+    pub transfer_keys: Vec<Name>,
+    soa: Record,
+    // owner name -> records, in file order. Empty non-terminals have an
+    // empty entry
+    names: HashMap<Name, Vec<Record>>,
+    // NSEC3 records and their RRSIGs, their hashed owner names don't exist
+    // as names of the zone (RFC 5155, section 7.2.8)
+    nsec3: Vec<Record>,
+    // names below the apex delegated to child zones
+    cuts: Vec<Name>,
+}
+
+/// Authoritative answer to a question, or a referral to a child zone.
+#[derive(Debug, Default, PartialEq)]
+pub struct Lookup {
+    pub rcode: u8,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+    // the name is delegated, the authorities are the cut's NS records
+    pub referral: bool,
+}
+
+impl Zone {
+    /// Reads the master file of a configured zone, and signs it if it has
+    /// a DNSSEC key.
+    pub fn load(config: &ZoneConfig) -> Result<Self> {
+        let text = fs::read_to_string(&config.file)?;
+        let mut zone = Self {
+            allow_transfer: config.allow_transfer.clone(),
+            transfer_keys: config.transfer_keys.clone(),
+            ..Self::parse(&text, config.origin.as_ref(), config.default_ttl)?
+        };
+        if let Some(path) = &config.dnssec_key {
+            let key = SigningKey::load_or_generate(path)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            zone.sign(&key, &config.denial, now as u32)?;
+        }
+        Ok(zone)
+    }
+
+    /// Replaces the zone's DNSSEC records with the key `key`, a fresh
+    /// `chain` of denial records and signatures of every RRset. Left as it
+    /// was if an RRset can't be encoded.
+    pub fn sign(
+        &mut self,
+        key: &SigningKey,
+        chain: &DenialChain,
+        now: u32,
+    ) -> Result<(), encoder::Error> {
+        let records: Vec<Record> = self.records().cloned().collect();
+        let signed = dnssec::sign_zone(&self.origin, records, key, chain, now)?;
+        self.names.clear();
+        self.nsec3.clear();
+        for record in signed {
+            self.insert(record);
+        }
+        Ok(())
+    }
+
+    /// Parses a master file. Relative names need `origin` or an earlier
+    /// `$ORIGIN`, records without a TTL need `default_ttl` or `$TTL`. The
+    /// zone's own origin is the owner of its SOA record, which must be
+    /// `origin` if set, and every record must be at or below it.
+    pub fn parse(
+        text: &str,
+        origin: Option<&Name>,
+        default_ttl: Option<u32>,
+    ) -> Result<Self, ZoneError> {
+        let records = Parser::new(origin, default_ttl).parse(text)?;
+
+        let err = |line, message| ZoneError { line, message };
+        let mut soas = records.iter().filter(|(_, r)| r.rtype == Type::SOA);
+        let (_, soa) = soas
+            .next()
+            .ok_or_else(|| err(0, "no SOA record".into()))?
+            .clone();
+        if let Some((line, _)) = soas.next() {
+            return Err(err(*line, "more than one SOA record".into()));
+        }
+        if let Some(origin) = origin.filter(|o| **o != soa.name) {
+            return Err(err(
+                0,
+                format!("SOA owner {} isn't the zone {}", soa.name, origin),
+            ));
+        }
+
+        let mut zone = Self {
+            origin: soa.name.clone(),
+            allow_transfer: Vec::new(),
+            transfer_keys: Vec::new(),
+            soa,
+            names: HashMap::new(),
+            nsec3: Vec::new(),
+            cuts: Vec::new(),
+        };
+        for (line, record) in records {
+            if !record.name.is_subdomain_of(&zone.origin) {
+                return Err(err(
+                    line,
+                    format!("{} is outside the zone {}", record.name, zone.origin),
+                ));
+            }
+            zone.insert(record);
+        }
+        zone.cuts = dnssec::delegations(&zone.origin, zone.records());
+        Ok(zone)
+    }
+
+    /// Builds a zone from the records of a transfer, SOA first and last.
+    pub fn from_transfer(
+        origin: &Name,
+        records: Vec<Record>,
+        allow_transfer: Vec<Network>,
+    ) -> Result<Self, String> {
+        let mut records = records.into_iter();
+        let soa = records
+            .next()
+            .filter(|r| r.rtype == Type::SOA && r.name == *origin)
+            .ok_or_else(|| format!("transfer of {} doesn't start with its SOA", origin))?;
+
+        let mut zone = Self {
+            origin: soa.name.clone(),
+            allow_transfer,
+            transfer_keys: Vec::new(),
+            soa: soa.clone(),
+            names: HashMap::new(),
+            nsec3: Vec::new(),
+            cuts: Vec::new(),
+        };
+        zone.insert(soa);
+        // the closing SOA
+        for record in records.filter(|r| r.rtype != Type::SOA) {
+            if !zone.contains(&record.name) {
+                return Err(format!(
+                    "{} is outside the zone {}",
+                    record.name, zone.origin
+                ));
+            }
+            zone.insert(record);
+        }
+        zone.cuts = dnssec::delegations(&zone.origin, zone.records());
+        Ok(zone)
+    }
+
+    fn insert(&mut self, record: Record) {
+        let hashed = match &record.rdata {
+            RData::NSEC3(_) => true,
+            RData::RRSIG(sig) => sig.type_covered == Type::NSEC3,
+            _ => false,
+        };
+        if hashed {
+            self.nsec3.push(record);
+            return;
+        }
+        let mut name = record.name.clone();
+        self.names.entry(name.clone()).or_default().push(record);
+
+        // the names between the owner and the origin exist too
+        while name.num_labels() > self.origin.num_labels() {
+            name = name.parent().unwrap_or_default();
+            self.names.entry(name.clone()).or_default();
+        }
+    }
+
+    /// Whether `name` is at or below the origin.
+    pub fn contains(&self, name: &Name) -> bool {
+        name.is_subdomain_of(&self.origin)
+    }
+
+    pub fn soa(&self) -> &Record {
+        &self.soa
+    }
+
+    /// All records of the zone.
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.names.values().flatten().chain(&self.nsec3)
+    }
+
+    /// The records of a full zone transfer: the SOA, every other record and
+    /// the SOA again (RFC 5936, section 2.2).
+    pub fn transfer(&self) -> Vec<Record> {
+        let mut records = vec![self.soa.clone()];
+        records.extend(self.records().filter(|r| r.rtype != Type::SOA).cloned());
+        records.push(self.soa.clone());
+        records
+    }
+
+    /// Whether a client at `addr`, with a request signed by `key` if any,
+    /// may transfer the zone. Nobody may unless allowed.
+    pub fn allows_transfer(&self, addr: IpAddr, key: Option<&Name>) -> bool {
+        self.allow_transfer.iter().any(|net| net.contains(addr))
+            || key.is_some_and(|key| self.transfer_keys.iter().any(|k| k == key))
+    }
+
+    /// Answers `q`, following CNAMEs as long as they stay inside the zone
+    /// and out of its delegations. Negative answers carry the SOA with the
+    /// negative TTL (RFC 2308, section 3). Names at or below a delegation
+    /// get a referral, except for the DS records of the cut itself, which
+    /// the zone has (RFC 4035, section 3.1.4.1).
+    pub fn lookup(&self, q: &Question) -> Lookup {
+        let mut lookup = Lookup::default();
+        let mut name = q.name.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.cut(&name) {
+                if !(q.qtype == Type::DS && name == *cut) {
+                    // a CNAME into a child zone is answered as far as it goes
+                    if lookup.answers.is_empty() {
+                        lookup = self.referral(cut);
+                    }
+                    return lookup;
+                }
+            }
+            let Some(records) = self.records_at(&name) else {
+                lookup.rcode = rcode::NXDOMAIN;
+                lookup.authorities.push(self.negative_soa());
+                return lookup;
+            };
+            let at_name = |r: &Record| Record {
+                name: name.clone(),
+                ..r.clone()
+            };
+
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|r| q.qtype == Type::ANY || r.rtype == q.qtype)
+                .map(at_name)
+                .collect();
+            if !matching.is_empty() {
+                lookup.answers.extend(matching);
+                return lookup;
+            }
+
+            let cname = records.iter().find(|r| r.rtype == Type::CNAME);
+            match cname.map(|r| &r.rdata) {
+                Some(RData::CNAME(target)) => {
+                    lookup.answers.push(at_name(cname.unwrap()));
+                    if !self.contains(target) {
+                        return lookup;
+                    }
+                    name = target.clone();
+                }
+                _ => {
+                    // NODATA
+                    lookup.authorities.push(self.negative_soa());
+                    return lookup;
+                }
+            }
+        }
+        lookup
+    }
+
+    /// The delegation `name` is at or below, the one closest to the apex as
+    /// any below it is occluded.
+    fn cut(&self, name: &Name) -> Option<&Name> {
+        self.cuts
+            .iter()
+            .filter(|cut| name.is_subdomain_of(cut))
+            .min_by_key(|cut| cut.num_labels())
+    }
+
+    /// Referral to the child zone at `cut`: its NS records, and the
+    /// addresses of the servers the zone has, glue included (RFC 1034,
+    /// section 4.3.2).
+    fn referral(&self, cut: &Name) -> Lookup {
+        let ns: Vec<Record> = self.names[cut]
+            .iter()
+            .filter(|r| r.rtype == Type::NS)
+            .cloned()
+            .collect();
+        let mut additionals: Vec<Record> = Vec::new();
+        for record in &ns {
+            let RData::NS(target) = &record.rdata else {
+                continue;
+            };
+            let glue = self.names.get(target).into_iter().flatten();
+            for record in glue.filter(|r| matches!(r.rtype, Type::A | Type::AAAA)) {
+                if !additionals.contains(record) {
+                    additionals.push(record.clone());
+                }
+            }
+        }
+        Lookup {
+            authorities: ns,
+            additionals,
+            referral: true,
+            ..Lookup::default()
+        }
+    }
+
+    /// For clients that set the DO bit, the DS records at `cut` when the
+    /// child zone is signed, otherwise the NSEC or NSEC3 record proving it
+    /// has none (RFC 4035, section 3.1.4).
+    pub fn delegation_signer(&self, cut: &Name) -> Vec<Record> {
+        let ds: Vec<Record> = self
+            .names
+            .get(cut)
+            .into_iter()
+            .flatten()
+            .filter(|r| r.rtype == Type::DS)
+            .cloned()
+            .collect();
+        match ds.is_empty() {
+            true => self.proof(cut, false),
+            false => ds,
+        }
+    }
+
+    /// RRSIGs of the RRsets in `records`, for clients that set the DO bit
+    /// (RFC 4035, section 3.1.1). Signatures of a wildcard are given the
+    /// name it was expanded to, their label count tells validators so.
+    pub fn signatures(&self, records: &[Record]) -> Vec<Record> {
+        let mut covered: Vec<(&Name, Type)> = Vec::new();
+        let mut signatures = Vec::new();
+        for record in records.iter().filter(|r| r.rtype != Type::RRSIG) {
+            let rrset = (&record.name, record.rtype);
+            if covered.contains(&rrset) {
+                continue;
+            }
+            covered.push(rrset);
+            let at_name = self.records_at(&record.name).into_iter().flatten();
+            let hashed = self.nsec3.iter().filter(|r| r.name == record.name);
+            for sig in at_name.chain(hashed) {
+                if matches!(&sig.rdata, RData::RRSIG(s) if s.type_covered == record.rtype) {
+                    signatures.push(Record {
+                        name: record.name.clone(),
+                        ..sig.clone()
+                    });
+                }
+            }
+        }
+        signatures
+    }
+
+    /// NSEC or NSEC3 records proving what `lookup` says doesn't exist, for
+    /// clients that set the DO bit: the name of a negative answer or of an
+    /// answer expanded from a wildcard, and the type for NODATA (RFC 4035,
+    /// section 3.1.3; RFC 5155, section 7.2). Empty for unsigned zones.
+    pub fn denial(&self, q: &Question, lookup: &Lookup) -> Vec<Record> {
+        let mut proof: Vec<Record> = Vec::new();
+        let mut name = q.name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if !self.contains(&name) {
+                break;
+            }
+            let at_name: Vec<&Record> = lookup.answers.iter().filter(|r| r.name == name).collect();
+            let answered = at_name
+                .iter()
+                .any(|r| q.qtype == Type::ANY || r.rtype == q.qtype);
+            for record in self.proof(&name, !at_name.is_empty()) {
+                if !proof.contains(&record) {
+                    proof.push(record);
+                }
+            }
+            let cname = at_name.iter().find_map(|r| match &r.rdata {
+                RData::CNAME(target) if !answered => Some(target.clone()),
+                _ => None,
+            });
+            match cname {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+        proof
+    }
+
+    /// Denial records for one name of an answer: that the name doesn't
+    /// exist when it `has_records` from a wildcard, otherwise that the type
+    /// doesn't, or neither the name nor a wildcard.
+    fn proof(&self, name: &Name, has_records: bool) -> Vec<Record> {
+        let exists = self.names.contains_key(name);
+        if exists {
+            // NODATA, an empty non-terminal has the NSEC before it
+            return match has_records {
+                true => Vec::new(),
+                false => self.denial_record(name).into_iter().collect(),
+            };
+        }
+
+        // the closest encloser is the longest existing ancestor
+        let labels = name.labels();
+        let depth = (1..labels.len())
+            .find(|i| self.names.contains_key(&Name::from_labels(&labels[*i..])))
+            .unwrap_or(labels.len());
+        let encloser = Name::from_labels(&labels[depth..]);
+        let wildcard = Name::from_labels(iter::once(&b"*"[..]).chain(encloser.iter_labels()));
+        let mut names = match self.nsec3.is_empty() {
+            true => vec![name.clone()],
+            // closest encloser proof (RFC 5155, section 7.2.1)
+            false => vec![Name::from_labels(&labels[depth - 1..])],
+        };
+        if !has_records {
+            if !self.nsec3.is_empty() {
+                names.push(encloser);
+            }
+            names.push(wildcard);
+        }
+        names
+            .iter()
+            .filter_map(|name| self.denial_record(name))
+            .collect()
+    }
+
+    /// The NSEC or NSEC3 record matching `name` or covering it.
+    fn denial_record(&self, name: &Name) -> Option<Record> {
+        if self.nsec3.is_empty() {
+            return self
+                .names
+                .values()
+                .flatten()
+                .find(|r| match &r.rdata {
+                    RData::NSEC(nsec) => {
+                        let next = &nsec.next_domain_name;
+                        &r.name <= name && (name < next || next <= &r.name)
+                    }
+                    _ => false,
+                })
+                .cloned();
+        }
+
+        let params = self.nsec3.iter().find_map(|r| match &r.rdata {
+            RData::NSEC3(nsec3) => Some(nsec3),
+            _ => None,
+        })?;
+        let hash = nsec3_hash(name, &params.salt, params.iterations).ok()?;
+        self.nsec3
+            .iter()
+            .find(|r| {
+                let RData::NSEC3(nsec3) = &r.rdata else {
+                    return false;
+                };
+                let Some(owner) = r.name.labels().first().and_then(base32hex_decode) else {
+                    return false;
+                };
+                let next = &nsec3.next_hashed_owner;
+                match owner < *next {
+                    true => owner <= hash && hash < *next,
+                    // the last one wraps around to the first
+                    false => owner <= hash || hash < *next,
+                }
+            })
+            .cloned()
+    }
+
+    /// Addresses of `name` in the zone, no CNAMEs followed.
+    pub fn addresses(&self, name: &Name) -> Vec<Record> {
+        self.records_at(name)
+            .into_iter()
+            .flatten()
+            .filter(|r| r.rtype == Type::A || r.rtype == Type::AAAA)
+            .map(|r| Record {
+                name: name.clone(),
+                ..r.clone()
+            })
+            .collect()
+    }
+
+    /// Records at `name`, synthesized from the wildcard at its closest
+    /// encloser when the name doesn't exist (RFC 4592, section 3.3.1).
+    /// `None` if neither does.
+    fn records_at(&self, name: &Name) -> Option<&Vec<Record>> {
+        if let Some(records) = self.names.get(name) {
+            return Some(records);
+        }
+
+        // the zone has an entry for every existing name, so the first
+        // ancestor found is the closest encloser. Wildcards don't reach
+        // across a delegation
+        let mut encloser = name.parent()?;
+        while !self.names.contains_key(&encloser) {
+            encloser = encloser.parent()?;
+        }
+        if self.cut(&encloser).is_some() {
+            return None;
+        }
+        let wildcard = Name::from_labels(iter::once(&b"*"[..]).chain(encloser.iter_labels()));
+        self.names.get(&wildcard)
+    }
+
+    fn negative_soa(&self) -> Record {
+        let ttl = match &self.soa.rdata {
+            RData::SOA(soa) => self.soa.ttl.min(soa.minimum),
+            _ => self.soa.ttl,
+        };
+        Record {
+            ttl,
+            ..self.soa.clone()
+        }
+    }
+}
+
+/// Addresses of an ALIAS target, when they were resolved and how long they
+/// are good for.
+type Flattened = (Vec<Record>, Instant, u32);
+
+/// Answers requests for names inside the zones authoritatively, and passes
+/// the rest on.
+pub struct Authoritative {
+    // replaced one by one as their files are reloaded
+    zones: RwLock<Vec<Arc<Zone>>>,
+    secondaries: Vec<Arc<Secondary>>,
+    // resolves the targets of ALIAS records, which are left unanswered
+    // without one
+    alias_resolver: Option<Arc<dyn Resolver>>,
+    // (target, A or AAAA) -> its addresses
+    flattened: Mutex<HashMap<(Name, Type), Flattened>>,
+}
+
+impl Authoritative {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self {
+            zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+            secondaries: Vec::new(),
+            alias_resolver: None,
+            flattened: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_secondaries(self, secondaries: Vec<Arc<Secondary>>) -> Self {
+        Self {
+            secondaries,
+            ..self
+        }
+    }
+
+    pub fn with_alias_resolver(self, resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            alias_resolver: Some(resolver),
+            ..self
+        }
+    }
+
+    /// Addresses of the names the answers point at (MX exchanges, NS and
+    /// SRV targets) that any of the zones knows, so the client doesn't have
+    /// to ask for them (RFC 1035, section 3.3.9; RFC 2782).
+    pub fn additionals(&self, answers: &[Record]) -> Vec<Record> {
+        let mut additionals: Vec<Record> = Vec::new();
+        for answer in answers {
+            let target = match &answer.rdata {
+                RData::MX(mx) => &mx.exchange,
+                RData::NS(name) => name,
+                RData::SRV(srv) => &srv.target,
+                _ => continue,
+            };
+            let Some(Some(zone)) = self.find(target) else {
+                continue;
+            };
+            for record in zone.addresses(target) {
+                if !answers.contains(&record) && !additionals.contains(&record) {
+                    additionals.push(record);
+                }
+            }
+        }
+        additionals
+    }
+
+    /// Serves `zone` instead of the zone with the same origin. Returns
+    /// whether there was one, `zone` isn't served otherwise.
+    pub fn replace(&self, zone: Zone) -> bool {
+        let mut zones = self.zones.write().unwrap();
+        match zones.iter_mut().find(|z| z.origin == zone.origin) {
+            Some(old) => {
+                *old = Arc::new(zone);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Has the secondary zone at `origin` checked with its primary right
+    /// away. Returns whether there is such a zone.
+    pub fn refresh(&self, origin: &Name) -> bool {
+        let secondary = self.secondaries.iter().find(|s| s.origin == *origin);
+        secondary.inspect(|s| s.refresh_now()).is_some()
+    }
+
+    /// The zone `name` belongs to, the one with the longest origin when
+    /// zones are nested. `Some(None)` for a secondary zone without data,
+    /// not transferred yet or expired.
+    pub fn find(&self, name: &Name) -> Option<Option<Arc<Zone>>> {
+        let zones = self.zones.read().unwrap();
+        let zones = zones.iter().map(|zone| (&zone.origin, Some(zone.clone())));
+        let secondaries = self.secondaries.iter().map(|s| (&s.origin, s.zone()));
+        zones
+            .chain(secondaries)
+            .filter(|(origin, _)| name.is_subdomain_of(origin))
+            .max_by_key(|(origin, _)| origin.num_labels())
+            .map(|(_, zone)| zone)
+    }
+}
+
+impl RequestHandler for Authoritative {
+    fn handle(&self, ctx: &Context, request: Message, next: Next<'_>) -> Result<Message> {
+        let [q] = request.questions.as_slice() else {
+            return next.run(ctx, request);
+        };
+        match request.opcode {
+            opcode::QUERY => {}
+            opcode::NOTIFY => return Ok(self.notified(ctx, &request)),
+            _ => return next.run(ctx, request),
+        }
+        if q.qtype == Type::AXFR {
+            return Ok(self.transfer(ctx, &request));
+        }
+        let zone = match self.find(&q.name) {
+            Some(Some(zone)) => zone,
+            Some(None) => return Ok(request.error_reply(rcode::SERVFAIL)),
+            None => return next.run(ctx, request),
+        };
+        let mut lookup = zone.lookup(q);
+        match self.flatten(&zone, q, &lookup) {
+            // flattened answers can't be signed, so no DNSSEC records either
+            Some(Ok(answers)) => {
+                return Ok(Message {
+                    aa: 1,
+                    answers,
+                    ..request.reply()
+                })
+            }
+            Some(Err(e)) => {
+                tracing::warn!(qname = %q.name, error = %e, "Failed to flatten ALIAS");
+                return Ok(request.error_reply(rcode::SERVFAIL));
+            }
+            None => {}
+        }
+        if request.opt.as_ref().is_some_and(|opt| opt.dnssec_ok) {
+            let denial = match lookup.referral {
+                true => zone.delegation_signer(&lookup.authorities[0].name),
+                false => zone.denial(q, &lookup),
+            };
+            lookup.authorities.extend(denial);
+            lookup.answers.extend(zone.signatures(&lookup.answers));
+            lookup
+                .authorities
+                .extend(zone.signatures(&lookup.authorities));
+        }
+        let mut additionals = lookup.additionals;
+        for record in self.additionals(&lookup.answers) {
+            if !additionals.contains(&record) {
+                additionals.push(record);
+            }
+        }
+        Ok(Message {
+            aa: !lookup.referral as u8,
+            rcode: lookup.rcode,
+            additionals,
+            answers: lookup.answers,
+            authorities: lookup.authorities,
+            ..request.reply()
+        })
+    }
+}
+
+impl Authoritative {
+    /// Answer to an A or AAAA question at a name with an ALIAS record and
+    /// no addresses of that type: the target's addresses at the name, no
+    /// longer than the ALIAS record's TTL. `None` when there is nothing to
+    /// flatten, or no resolver to do it with.
+    fn flatten(&self, zone: &Zone, q: &Question, lookup: &Lookup) -> Option<Result<Vec<Record>>> {
+        if !matches!(q.qtype, Type::A | Type::AAAA)
+            || lookup.referral
+            || lookup.rcode != rcode::NOERROR
+            || !lookup.answers.is_empty()
+        {
+            return None;
+        }
+        let resolver = self.alias_resolver.as_ref()?;
+        let alias = zone
+            .records_at(&q.name)?
+            .iter()
+            .find(|r| r.rtype == Type::ALIAS)?;
+        let RData::ALIAS(target) = &alias.rdata else {
+            return None;
+        };
+        let answers = self.resolve_alias(resolver.as_ref(), target, q.qtype);
+        Some(answers.map(|records| {
+            records
+                .into_iter()
+                .map(|r| Record {
+                    name: q.name.clone(),
+                    ttl: r.ttl.min(alias.ttl),
+                    ..r
+                })
+                .collect()
+        }))
+    }
+
+    /// Addresses of type `qtype` of `target`, from the cache while their
+    /// TTL lasts. A target without any is cached for the negative TTL of
+    /// the reply.
+    fn resolve_alias(
+        &self,
+        resolver: &dyn Resolver,
+        target: &Name,
+        qtype: Type,
+    ) -> Result<Vec<Record>> {
+        let cache_key = (target.clone(), qtype);
+        if let Some((records, resolved, ttl)) = self.flattened.lock().unwrap().get(&cache_key) {
+            let elapsed = resolved.elapsed().as_secs().min(u32::MAX as u64) as u32;
+            if elapsed < *ttl {
+                let remaining = ttl - elapsed;
+                return Ok(records
+                    .iter()
+                    .map(|r| Record {
+                        ttl: remaining,
+                        ..r.clone()
+                    })
+                    .collect());
+            }
+        }
+
+        let request = Message {
+            rd: 1,
+            questions: vec![Question {
+                name: target.clone(),
+                qtype,
+                class: Class::IN,
+            }],
+            ..Message::default()
+        };
+        let reply = resolver.resolve(&request)?;
+        if reply.rcode != rcode::NOERROR && reply.rcode != rcode::NXDOMAIN {
+            bail!("{} {} resolved with rcode {}", target, qtype, reply.rcode);
+        }
+        // the addresses at the end of any CNAME chain
+        let records: Vec<Record> = reply
+            .answers
+            .into_iter()
+            .filter(|r| r.rtype == qtype)
+            .collect();
+        let ttl = match records.iter().map(|r| r.ttl).min() {
+            Some(ttl) => ttl,
+            None => reply
+                .authorities
+                .iter()
+                .find_map(|r| match &r.rdata {
+                    RData::SOA(soa) => Some(r.ttl.min(soa.minimum)),
+                    _ => None,
+                })
+                .unwrap_or(0),
+        };
+        if ttl > 0 {
+            self.flattened
+                .lock()
+                .unwrap()
+                .insert(cache_key, (records.clone(), Instant::now(), ttl));
+        }
+        Ok(records)
+    }
+}
+
+impl Authoritative {
+    /// Answers an AXFR request with the whole zone in a single message, to
+    /// be split with [`split_transfer`]. Transfers are TCP only (RFC 5936,
+    /// section 4.2) and need the zone's ACL to allow the client, or the
+    /// request to be signed with one of its transfer keys.
+    fn transfer(&self, ctx: &Context, request: &Message) -> Message {
+        let q = &request.questions[0];
+        let zone = match self.find(&q.name) {
+            Some(Some(zone)) if zone.origin == q.name => zone,
+            Some(None) => return request.error_reply(rcode::SERVFAIL),
+            _ => return request.error_reply(rcode::NOTAUTH),
+        };
+        if ctx.transport != Transport::Tcp
+            || !zone.allows_transfer(ctx.source.ip(), ctx.key.as_ref())
+        {
+            return request.error_reply(rcode::REFUSED);
+        }
+        Message {
+            aa: 1,
+            answers: zone.transfer(),
+            ..request.reply()
+        }
+    }
+}
+
+impl Authoritative {
+    /// Acknowledges a NOTIFY from the primary of a secondary zone and has
+    /// the zone checked right away (RFC 1996, section 3.7).
+    fn notified(&self, ctx: &Context, request: &Message) -> Message {
+        let q = &request.questions[0];
+        let Some(secondary) = self.secondaries.iter().find(|s| s.origin == q.name) else {
+            return request.error_reply(rcode::NOTAUTH);
+        };
+        if secondary.primary().ip() != ctx.source.ip() {
+            return request.error_reply(rcode::REFUSED);
+        }
+        tracing::info!(zone = %q.name, client = %privacy::client(ctx.source), "NOTIFY received");
+        secondary.refresh_now();
+        Message {
+            aa: 1,
+            ..request.reply()
+        }
+    }
+}
+
+/// Splits a zone transfer reply into messages of about
+/// `TRANSFER_MESSAGE_SIZE` bytes each, all carrying the question (RFC 5936,
+/// section 2.2). Other replies are returned as they are.
+pub fn split_transfer(reply: Message) -> Result<Vec<Message>> {
+    let is_transfer = matches!(reply.questions.as_slice(), [q] if q.qtype == Type::AXFR);
+    if !is_transfer || reply.answers.len() <= 1 {
+        return Ok(vec![reply]);
+    }
+
+    let empty = Message {
+        answers: Vec::new(),
+        ..reply.clone()
+    };
+    let base = empty.to_bytes()?.len();
+    let mut messages = Vec::new();
+    let mut answers: Vec<Record> = Vec::new();
+    let mut size = base;
+    for record in reply.answers {
+        // alone in a message the record compresses at most as well as it
+        // does next to others, so this overestimates
+        let with_record = Message {
+            answers: vec![record.clone()],
+            ..empty.clone()
+        };
+        let len = with_record.to_bytes()?.len() - base;
+        if !answers.is_empty() && size + len > TRANSFER_MESSAGE_SIZE {
+            messages.push(Message {
+                answers: std::mem::take(&mut answers),
+                ..empty.clone()
+            });
+            size = base;
+        }
+        size += len;
+        answers.push(record);
+    }
+    messages.push(Message { answers, ..empty });
+    Ok(messages)
+}
+
+/// Line of a master file after joining parenthesized continuations.
+struct Entry {
+    line: usize,
+    // the owner is left out and taken from the previous entry
+    blank_owner: bool,
+    tokens: Vec<String>,
+}
+
+/// Splits a master file into entries: handles `;` comments, quoted strings
+/// with `\X` and `\DDD` escapes, and parentheses spanning lines.
+fn tokenize(text: &str) -> Result<Vec<Entry>, ZoneError> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut token: Option<Vec<u8>> = None;
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut line = 1;
+    let mut at_line_start = true;
+
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i <= bytes.len() {
+        let b = bytes.get(i).copied();
+        i += 1;
+
+        if quoted {
+            match b {
+                None => {
+                    return Err(ZoneError {
+                        line,
+                        message: "unterminated string".into(),
+                    })
+                }
+                Some(b'"') => quoted = false,
+                Some(b'\\') => {
+                    let byte = unescape(bytes, &mut i).ok_or_else(|| ZoneError {
+                        line,
+                        message: "invalid escape".into(),
+                    })?;
+                    token.get_or_insert_with(Vec::new).push(byte);
+                }
+                Some(b) => {
+                    if b == b'\n' {
+                        line += 1;
+                    }
+                    token.get_or_insert_with(Vec::new).push(b);
+                }
+            }
+            continue;
+        }
+
+        let starts_line = at_line_start;
+        at_line_start = false;
+        match b {
+            Some(b' ' | b'\t' | b'\r') | Some(b'\n') | Some(b';') | Some(b'(' | b')') | None => {
+                if let Some(t) = token.take() {
+                    let entry = entry.get_or_insert_with(|| Entry {
+                        line,
+                        blank_owner: false,
+                        tokens: Vec::new(),
+                    });
+                    entry.tokens.push(String::from_utf8_lossy(&t).into_owned());
+                }
+                match b {
+                    Some(b' ' | b'\t') if starts_line && depth == 0 && entry.is_none() => {
+                        entry = Some(Entry {
+                            line,
+                            blank_owner: true,
+                            tokens: Vec::new(),
+                        });
+                    }
+                    Some(b';') => {
+                        while i < bytes.len() && bytes[i] != b'\n' {
+                            i += 1;
+                        }
+                    }
+                    Some(b'(') => depth += 1,
+                    Some(b')') if depth == 0 => {
+                        return Err(ZoneError {
+                            line,
+                            message: "unbalanced parenthesis".into(),
+                        })
+                    }
+                    Some(b')') => depth -= 1,
+                    Some(b'\n') | None => {
+                        if depth > 0 && b.is_some() {
+                            line += 1;
+                            continue;
+                        }
+                        if depth > 0 {
+                            return Err(ZoneError {
+                                line,
+                                message: "unbalanced parenthesis".into(),
+                            });
+                        }
+                        if let Some(e) = entry.take().filter(|e| !e.tokens.is_empty()) {
+                            entries.push(e);
+                        }
+                        line += 1;
+                        at_line_start = true;
+                    }
+                    _ => {}
+                }
+            }
+            Some(b'"') => {
+                quoted = true;
+                token.get_or_insert_with(Vec::new);
+            }
+            Some(b'\\') => {
+                let t = token.get_or_insert_with(Vec::new);
+                if t.is_empty() && bytes.get(i) == Some(&b'#') {
+                    // `\#` introduces generic RDATA, kept as is
+                    t.extend_from_slice(b"\\#");
+                    i += 1;
+                    continue;
+                }
+                let byte = unescape(bytes, &mut i).ok_or_else(|| ZoneError {
+                    line,
+                    message: "invalid escape".into(),
+                })?;
+                t.push(byte);
+            }
+            Some(b) => token.get_or_insert_with(Vec::new).push(b),
+        }
+    }
+    Ok(entries)
+}
+
+/// Byte escaped by `\X` or `\DDD`, with `i` just past the backslash.
+fn unescape(bytes: &[u8], i: &mut usize) -> Option<u8> {
+    match bytes.get(*i..*i + 3) {
+        Some(digits) if digits.iter().all(u8::is_ascii_digit) => {
+            *i += 3;
+            std::str::from_utf8(digits).ok()?.parse().ok()
+        }
+        _ => {
+            *i += 1;
+            bytes.get(*i - 1).copied()
+        }
+    }
+}
+
+/// Parses the records of a master file that isn't a zone, such as root
+/// hints, with the same rules as `Zone::parse` for relative names and TTLs.
+pub fn parse_records(
+    text: &str,
+    origin: Option<&Name>,
+    default_ttl: Option<u32>,
+) -> Result<Vec<Record>, ZoneError> {
+    let records = Parser::new(origin, default_ttl).parse(text)?;
+    Ok(records.into_iter().map(|(_, record)| record).collect())
+}
+
+/// State carried from one entry of a master file to the next.
+struct Parser {
+    origin: Option<Name>,
+    default_ttl: Option<u32>,
+    last_owner: Option<Name>,
+    last_ttl: Option<u32>,
+    last_class: Class,
+}
+
+impl Parser {
+    fn new(origin: Option<&Name>, default_ttl: Option<u32>) -> Self {
+        Self {
+            origin: origin.cloned(),
+            default_ttl,
+            last_owner: None,
+            last_ttl: None,
+            last_class: Class::IN,
+        }
+    }
+
+    /// Records in file order, with the line each one started on.
+    fn parse(mut self, text: &str) -> Result<Vec<(usize, Record)>, ZoneError> {
+        let mut records = Vec::new();
+        for entry in tokenize(text)? {
+            let err = |message| ZoneError {
+                line: entry.line,
+                message,
+            };
+            match entry.tokens[0].to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let [_, origin] = entry.tokens.as_slice() else {
+                        return Err(err("expected $ORIGIN <name>".into()));
+                    };
+                    self.origin = Some(self.name(origin).map_err(err)?);
+                }
+                "$TTL" => {
+                    let ttl = match entry.tokens.as_slice() {
+                        [_, ttl] => parse_ttl(ttl),
+                        _ => None,
+                    };
+                    self.default_ttl = Some(ttl.ok_or_else(|| err("expected $TTL <ttl>".into()))?);
+                }
+                directive if directive.starts_with('$') => {
+                    return Err(err(format!("unsupported directive {}", entry.tokens[0])));
+                }
+                _ => records.push((entry.line, self.record(&entry).map_err(err)?)),
+            }
+        }
+        Ok(records)
+    }
+
+    /// `[<owner>] [<ttl>] [<class>] <type> <rdata>`, TTL and class in either
+    /// order.
+    fn record(&mut self, entry: &Entry) -> Result<Record, String> {
+        let mut tokens = entry.tokens.iter();
+        let name = match entry.blank_owner {
+            true => self.last_owner.clone().ok_or("no previous owner name")?,
+            false => self.name(tokens.next().ok_or("missing owner name")?)?,
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or("missing type")?;
+            if ttl.is_none() {
+                if let Some(t) = parse_ttl(token) {
+                    ttl = Some(t);
+                    continue;
+                }
+            }
+            if class.is_none() {
+                if let Ok(c) = Class::from_str(token) {
+                    class = Some(c);
+                    continue;
+                }
+            }
+            break Type::from_str(token).map_err(|e| e.to_string())?;
+        };
+
+        let fields: Vec<&str> = tokens.map(String::as_str).collect();
+        let rdata = self.rdata(rtype, &fields)?;
+        let ttl = match (ttl.or(self.default_ttl).or(self.last_ttl), &rdata) {
+            (Some(ttl), _) => ttl,
+            (None, RData::SOA(soa)) => soa.minimum,
+            (None, _) => return Err("no TTL and no $TTL".into()),
+        };
+        let class = class.unwrap_or(self.last_class);
+
+        self.last_owner = Some(name.clone());
+        self.last_ttl = Some(ttl);
+        self.last_class = class;
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+
+    /// Makes a name absolute: `@` is the origin, names without a trailing
+    /// dot are relative to it.
+    fn name(&self, s: &str) -> Result<Name, String> {
+        if let Some(absolute) = s.strip_suffix('.') {
+            return absolute.parse();
+        }
+        let origin = self
+            .origin
+            .as_ref()
+            .ok_or_else(|| format!("relative name {} without $ORIGIN", s))?;
+        if s == "@" {
+            return Ok(origin.clone());
+        }
+        s.parse::<Name>()?.append(origin)
+    }
+
+    /// RDATA in presentation format, or the generic `\# <len> <hex>`
+    /// (RFC 3597, section 5) for any type.
+    fn rdata(&self, rtype: Type, fields: &[&str]) -> Result<RData, String> {
+        if fields.first() == Some(&"\\#") {
+            return RData::parse_generic(rtype, &fields.join(" ")).map_err(|e| e.to_string());
+        }
+
+        let mut f = Fields {
+            rtype,
+            fields: fields.iter(),
+        };
+        let rdata = match rtype {
+            Type::A => RData::A(f.parse::<Ipv4Addr>()?),
+            Type::AAAA => RData::AAAA(f.parse::<Ipv6Addr>()?),
+            Type::NS => RData::NS(self.name(f.next()?)?),
+            Type::CNAME => RData::CNAME(self.name(f.next()?)?),
+            Type::ALIAS => RData::ALIAS(self.name(f.next()?)?),
+            Type::PTR => RData::PTR(self.name(f.next()?)?),
+            Type::SOA => RData::SOA(Soa {
+                mname: self.name(f.next()?)?,
+                rname: self.name(f.next()?)?,
+                serial: f.parse()?,
+                refresh: f.ttl()?,
+                retry: f.ttl()?,
+                expire: f.ttl()?,
+                minimum: f.ttl()?,
+            }),
+            Type::HINFO => RData::HINFO(Hinfo {
+                cpu: f.next()?.to_string(),
+                os: f.next()?.to_string(),
+            }),
+            Type::MX => RData::MX(Mx {
+                preference: f.parse()?,
+                exchange: self.name(f.next()?)?,
+            }),
+            Type::TXT => {
+                let strings: Vec<String> = f.rest().map(str::to_string).collect();
+                if strings.is_empty() {
+                    return Err(f.invalid());
+                }
+                RData::TXT(strings)
+            }
+            Type::SRV => RData::SRV(Srv {
+                priority: f.parse()?,
+                weight: f.parse()?,
+                port: f.parse()?,
+                target: self.name(f.next()?)?,
+            }),
+            Type::DS => RData::DS(Ds {
+                key_tag: f.parse()?,
+                algorithm: f.parse()?,
+                digest_type: f.parse()?,
+                digest: hex_decode(&f.joined()).ok_or_else(|| f.invalid())?,
+            }),
+            Type::DNSKEY => RData::DNSKEY(Dnskey {
+                flags: f.parse()?,
+                protocol: f.parse()?,
+                algorithm: f.parse()?,
+                public_key: base64_decode(&f.joined()).ok_or_else(|| f.invalid())?,
+            }),
+            Type::RRSIG => RData::RRSIG(Rrsig {
+                type_covered: f.parse()?,
+                algorithm: f.parse()?,
+                labels: f.parse()?,
+                original_ttl: f.ttl()?,
+                expiration: parse_timestamp(f.next()?).ok_or_else(|| f.invalid())?,
+                inception: parse_timestamp(f.next()?).ok_or_else(|| f.invalid())?,
+                key_tag: f.parse()?,
+                signer_name: self.name(f.next()?)?,
+                signature: base64_decode(&f.joined()).ok_or_else(|| f.invalid())?,
+            }),
+            Type::NSEC => RData::NSEC(Nsec {
+                next_domain_name: self.name(f.next()?)?,
+                types: f.types()?,
+            }),
+            Type::NSEC3 => RData::NSEC3(Nsec3 {
+                hash_algorithm: f.parse()?,
+                flags: f.parse()?,
+                iterations: f.parse()?,
+                salt: match f.next()? {
+                    "-" => Vec::new(),
+                    salt => hex_decode(salt).ok_or_else(|| f.invalid())?,
+                },
+                next_hashed_owner: base32hex_decode(f.next()?).ok_or_else(|| f.invalid())?,
+                types: f.types()?,
+            }),
+            Type::NSEC3PARAM => RData::NSEC3PARAM(Nsec3param {
+                hash_algorithm: f.parse()?,
+                flags: f.parse()?,
+                iterations: f.parse()?,
+                salt: match f.next()? {
+                    "-" => Vec::new(),
+                    salt => hex_decode(salt).ok_or_else(|| f.invalid())?,
+                },
+            }),
+            Type::SVCB => RData::SVCB(self.svcb(&mut f)?),
+            Type::HTTPS => RData::HTTPS(self.svcb(&mut f)?),
+            _ => {
+                return Err(format!(
+                    "no presentation format for {}, use \\# <len> <hex>",
+                    rtype
+                ))
+            }
+        };
+        if f.fields.next().is_some() {
+            return Err(format!("trailing data in {} record", rtype));
+        }
+        Ok(rdata)
+    }
+
+    /// `<priority> <target> [<key>[=<value>]...]` (RFC 9460, section 2.1).
+    fn svcb(&self, f: &mut Fields) -> Result<Svcb, String> {
+        let mut svcb = Svcb {
+            priority: f.parse()?,
+            target: self.name(f.next()?)?,
+            params: Vec::new(),
+        };
+        for param in f.rest() {
+            let param = parse_svc_param(param)?;
+            if svcb.params.iter().any(|p| p.key == param.key) {
+                return Err(format!("duplicate SvcParam {:?}", param.to_string()));
+            }
+            svcb.params.push(param);
+        }
+        svcb.params.sort_by_key(|p| p.key);
+        Ok(svcb)
+    }
+}
+
+/// RDATA fields of one record.
+struct Fields<'a> {
+    rtype: Type,
+    fields: std::slice::Iter<'a, &'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn invalid(&self) -> String {
+        format!("invalid {} record", self.rtype)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.fields.next().copied().ok_or_else(|| self.invalid())
+    }
+
+    fn parse<T: FromStr>(&mut self) -> Result<T, String> {
+        let field = self.next()?;
+        field
+            .parse()
+            .map_err(|_| format!("{}: {:?}", self.invalid(), field))
+    }
+
+    fn ttl(&mut self) -> Result<u32, String> {
+        let field = self.next()?;
+        parse_ttl(field).ok_or_else(|| format!("{}: {:?}", self.invalid(), field))
+    }
+
+    fn rest(&mut self) -> impl Iterator<Item = &'a str> + '_ {
+        self.fields.by_ref().copied()
+    }
+
+    // base64 and hex may be split by whitespace
+    fn joined(&mut self) -> String {
+        self.rest().collect()
+    }
+
+    fn types(&mut self) -> Result<Vec<Type>, String> {
+        self.rest()
+            .map(|t| Type::from_str(t).map_err(|e| e.to_string()))
+            .collect()
+    }
+}
+
+/// TTL as seconds, or with BIND style units like `1h30m` (s, m, h, d, w).
+fn parse_ttl(s: &str) -> Option<u32> {
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut n: Option<u32> = None;
+    for c in s.chars() {
+        match c.to_digit(10) {
+            Some(d) => n = Some(n.unwrap_or(0).checked_mul(10)?.checked_add(d)?),
+            None => {
+                let unit = match c.to_ascii_lowercase() {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604800,
+                    _ => return None,
+                };
+                total = total.checked_add(n.take()?.checked_mul(unit)?)?;
+            }
+        }
+    }
+    match n {
+        Some(_) => None,
+        None => Some(total),
+    }
+}
+
+/// `key=value` or `key` in presentation format (RFC 9460, section 2.1).
+fn parse_svc_param(s: &str) -> Result<SvcParam, String> {
+    let invalid = || format!("invalid SvcParam {:?}", s);
+    let (name, value) = match s.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (s, None),
+    };
+    let key = parse_svc_key(name).ok_or_else(invalid)?;
+
+    let list = || value.into_iter().flat_map(|v| v.split(','));
+    let value = match key {
+        SvcParam::MANDATORY => {
+            let mut keys = list()
+                .map(parse_svc_key)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+            keys.sort_unstable();
+            keys.iter().flat_map(|k| k.to_be_bytes()).collect()
+        }
+        SvcParam::ALPN => {
+            let mut v = Vec::new();
+            for id in list() {
+                v.push(u8::try_from(id.len()).map_err(|_| invalid())?);
+                v.extend_from_slice(id.as_bytes());
+            }
+            v
+        }
+        SvcParam::NO_DEFAULT_ALPN if value.is_none() => Vec::new(),
+        SvcParam::NO_DEFAULT_ALPN => return Err(invalid()),
+        SvcParam::PORT => value
+            .and_then(|v| v.parse::<u16>().ok())
+            .ok_or_else(invalid)?
+            .to_be_bytes()
+            .to_vec(),
+        SvcParam::IPV4HINT => list()
+            .map(|a| a.parse::<Ipv4Addr>().map(|a| a.octets()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?
+            .concat(),
+        SvcParam::ECH => value.and_then(base64_decode).ok_or_else(invalid)?,
+        SvcParam::IPV6HINT => list()
+            .map(|a| a.parse::<Ipv6Addr>().map(|a| a.octets()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?
+            .concat(),
+        _ => value.unwrap_or_default().as_bytes().to_vec(),
+    };
+    if value.is_empty() && key != SvcParam::NO_DEFAULT_ALPN && !name.starts_with("key") {
+        return Err(invalid());
+    }
+    Ok(SvcParam { key, value })
+}
+
+/// Inverse of [`crate::rdata::svc_key_name`].
+fn parse_svc_key(s: &str) -> Option<u16> {
+    match s {
+        "mandatory" => Some(SvcParam::MANDATORY),
+        "alpn" => Some(SvcParam::ALPN),
+        "no-default-alpn" => Some(SvcParam::NO_DEFAULT_ALPN),
+        "port" => Some(SvcParam::PORT),
+        "ipv4hint" => Some(SvcParam::IPV4HINT),
+        "ech" => Some(SvcParam::ECH),
+        "ipv6hint" => Some(SvcParam::IPV6HINT),
+        _ => s.strip_prefix("key")?.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_ttl, split_transfer, Authoritative, Zone, ZoneError};
+    use crate::{
+        anchors::TrustAnchor,
+        dnssec::{self, DenialChain, SigningKey},
+        edns::Opt,
+        handler::{local_soa, Chain, Context, Transport},
+        proto::{rcode, test::question, Class, Message, Name, Record, Type},
+        rdata::{Mx, RData, SvcParam},
+        resolver::Resolver,
+        validator::Validator,
+    };
+    use anyhow::{bail, Result};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN SOA  ns1 hostmaster (
+                2024010101 ; serial
+                2h 15m 2w 300 )
+        IN NS   ns1
+        IN MX   10 mail
+ns1     IN A    192.0.2.1
+mail    600 IN A 192.0.2.2
+        IN AAAA 2001:db8::2
+www     CNAME   mail
+ftp     CNAME   ftp.example.net.
+txt     TXT     "hello \"world\"" two\ words "\065"
+a.b.deep A      192.0.2.3
+_dns    HTTPS   1 . alpn="h2,h3" port=853 ipv4hint=192.0.2.1
+raw     TYPE999 \# 2 abcd
+*.dyn   A       192.0.2.4
+        TXT     "wildcard"
+*.alias CNAME   mail
+host.dyn A      192.0.2.5
+_sip._tcp SRV   10 60 5060 mail
+"#;
+
+    #[test]
+    fn test_parse() {
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+        assert_eq!(Name::from("example.com"), zone.origin);
+        assert_eq!(17, zone.records().count());
+
+        let soa = zone.soa();
+        assert_eq!(3600, soa.ttl);
+        let RData::SOA(data) = &soa.rdata else {
+            panic!("not a SOA: {:?}", soa);
+        };
+        assert_eq!(Name::from("hostmaster.example.com"), data.rname);
+        assert_eq!(
+            (7200, 900, 1209600, 300),
+            (data.refresh, data.retry, data.expire, data.minimum)
+        );
+
+        let mail = zone.lookup(&question("MAIL.example.com", Type::AAAA));
+        assert_eq!(1, mail.answers.len());
+        // the blank owner is the previous one, the TTL comes from $TTL
+        assert_eq!(3600, mail.answers[0].ttl);
+
+        let mx = zone.lookup(&question("example.com", Type::MX)).answers;
+        assert_eq!(
+            RData::MX(Mx {
+                preference: 10,
+                exchange: Name::from("mail.example.com"),
+            }),
+            mx[0].rdata
+        );
+
+        let txt = zone.lookup(&question("txt.example.com", Type::TXT)).answers;
+        assert_eq!(
+            RData::TXT(vec![
+                "hello \"world\"".into(),
+                "two words".into(),
+                "A".into()
+            ]),
+            txt[0].rdata
+        );
+
+        let https = zone
+            .lookup(&question("_dns.example.com", Type::HTTPS))
+            .answers;
+        let RData::HTTPS(svcb) = &https[0].rdata else {
+            panic!("not HTTPS: {:?}", https);
+        };
+        assert_eq!(
+            vec![SvcParam::ALPN, SvcParam::PORT, SvcParam::IPV4HINT],
+            svcb.params.iter().map(|p| p.key).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "1 . alpn=h2,h3 port=853 ipv4hint=192.0.2.1",
+            https[0].rdata.to_string()
+        );
+
+        let raw = zone.lookup(&question("raw.example.com", Type::from(999)));
+        assert_eq!(RData::Unknown(vec![0xab, 0xcd]), raw.answers[0].rdata);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // records printed in presentation format parse back to themselves
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+        let text: String = zone
+            .records()
+            .map(|r| {
+                format!(
+                    "{} {} {} {} {}\n",
+                    r.name.to_fqdn(),
+                    r.ttl,
+                    r.class,
+                    r.rtype,
+                    r.rdata
+                )
+            })
+            .collect();
+        let again = Zone::parse(&text, None, None).unwrap();
+        for record in zone.records() {
+            assert!(again.records().any(|r| r == record), "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |text: &str| Zone::parse(text, None, None).unwrap_err();
+        assert_eq!(
+            ZoneError {
+                line: 1,
+                message: "relative name www without $ORIGIN".into()
+            },
+            err("www 60 IN A 192.0.2.1")
+        );
+        assert_eq!(
+            ZoneError {
+                line: 0,
+                message: "no SOA record".into()
+            },
+            err("www.example.com. 60 IN A 192.0.2.1")
+        );
+        assert_eq!(
+            3,
+            err("$ORIGIN example.com.\n@ 60 SOA ns1 hm 1 2 3 4 5\nwww 60 A 192.0.2.300").line
+        );
+        assert_eq!(
+            3,
+            err("$ORIGIN example.com.\n@ 60 SOA ns1 hm 1 2 3 4 5\nexample.net. 60 A 192.0.2.1")
+                .line
+        );
+        assert_eq!(1, err("@ 60 SOA ns1. hm. (1 2 3 4 5").line);
+        assert_eq!(1, err("www.example.com. A 192.0.2.1").line);
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(Some(300), parse_ttl("300"));
+        assert_eq!(Some(5400), parse_ttl("1h30m"));
+        assert_eq!(Some(1209600), parse_ttl("2W"));
+        assert_eq!(None, parse_ttl("IN"));
+        assert_eq!(None, parse_ttl("1h30"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+
+        let www = zone.lookup(&question("www.example.com", Type::A));
+        assert_eq!(rcode::NOERROR, www.rcode);
+        assert_eq!(
+            vec![Type::CNAME, Type::A],
+            www.answers.iter().map(|r| r.rtype).collect::<Vec<_>>()
+        );
+        assert_eq!(Name::from("mail.example.com"), www.answers[1].name);
+
+        // the chain leaves the zone, the client resolves the rest
+        let ftp = zone.lookup(&question("ftp.example.com", Type::A));
+        assert_eq!(1, ftp.answers.len());
+
+        // NODATA, with the negative TTL from the SOA
+        let nodata = zone.lookup(&question("ns1.example.com", Type::MX));
+        assert_eq!(rcode::NOERROR, nodata.rcode);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(300, nodata.authorities[0].ttl);
+
+        // empty non-terminal
+        let ent = zone.lookup(&question("b.deep.example.com", Type::A));
+        assert_eq!(rcode::NOERROR, ent.rcode);
+        assert_eq!(Type::SOA, ent.authorities[0].rtype);
+
+        let nx = zone.lookup(&question("nope.example.com", Type::A));
+        assert_eq!(rcode::NXDOMAIN, nx.rcode);
+        assert_eq!(Type::SOA, nx.authorities[0].rtype);
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let zone = Zone::parse(ZONE, None, None).unwrap();
+
+        // synthesized at the query name, also more than one label down
+        for name in ["a.dyn.example.com", "x.y.dyn.example.com"] {
+            let lookup = zone.lookup(&question(name, Type::A));
+            assert_eq!(rcode::NOERROR, lookup.rcode);
+            assert_eq!(Name::from(name), lookup.answers[0].name);
+            assert_eq!(
+                RData::A("192.0.2.4".parse().unwrap()),
+                lookup.answers[0].rdata
+            );
+        }
+        let nodata = zone.lookup(&question("a.dyn.example.com", Type::MX));
+        assert!(nodata.answers.is_empty());
+        assert_eq!(rcode::NOERROR, nodata.rcode);
+
+        // an existing name blocks the wildcard, also below it
+        let host = zone.lookup(&question("host.dyn.example.com", Type::TXT));
+        assert!(host.answers.is_empty());
+        let below = zone.lookup(&question("a.host.dyn.example.com", Type::A));
+        assert_eq!(rcode::NXDOMAIN, below.rcode);
+
+        // the closest encloser of a.b.deep is b.deep, without a wildcard
+        let deep = zone.lookup(&question("x.b.deep.example.com", Type::A));
+        assert_eq!(rcode::NXDOMAIN, deep.rcode);
+
+        let alias = zone.lookup(&question("www.alias.example.com", Type::A));
+        assert_eq!(
+            vec![Type::CNAME, Type::A],
+            alias.answers.iter().map(|r| r.rtype).collect::<Vec<_>>()
+        );
+        assert_eq!(Name::from("www.alias.example.com"), alias.answers[0].name);
+    }
+
+    #[test]
+    fn test_referral() {
+        let text = format!(
+            "{}sub NS ns.sub\n NS ns1\n DS 60485 13 2 {}\nns.sub A 192.0.2.9\n*.sub A 192.0.2.10\n",
+            ZONE,
+            "ab".repeat(32)
+        );
+        let zone = Zone::parse(&text, None, None).unwrap();
+
+        // at the cut and below it, no NODATA or NXDOMAIN of the parent's own
+        for name in ["sub.example.com", "www.Sub.example.com"] {
+            let referral = zone.lookup(&question(name, Type::A));
+            assert!(referral.referral, "{}", name);
+            assert_eq!(rcode::NOERROR, referral.rcode);
+            assert!(referral.answers.is_empty());
+            assert_eq!(
+                vec![Type::NS, Type::NS],
+                referral
+                    .authorities
+                    .iter()
+                    .map(|r| r.rtype)
+                    .collect::<Vec<_>>()
+            );
+            // the glue and the parent's own server
+            assert_eq!(
+                vec![
+                    Name::from("ns.sub.example.com"),
+                    Name::from("ns1.example.com")
+                ],
+                referral
+                    .additionals
+                    .iter()
+                    .map(|r| r.name.clone())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // the parent has the DS records of the cut
+        let ds = zone.lookup(&question("sub.example.com", Type::DS));
+        assert!(!ds.referral);
+        assert_eq!(Type::DS, ds.answers[0].rtype);
+
+        // the wildcard below the cut is occluded
+        assert!(zone.addresses(&Name::from("x.sub.example.com")).is_empty());
+
+        let chain = Chain::default().with(Authoritative::new(vec![zone]));
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = Message {
+            questions: vec![question("www.sub.example.com", Type::A)],
+            ..Message::default()
+        };
+        let reply = chain.handle(&ctx, request).unwrap();
+        assert_eq!((0, rcode::NOERROR), (reply.aa, reply.rcode));
+        assert_eq!((2, 2), (reply.authorities.len(), reply.additionals.len()));
+    }
+
+    #[test]
+    fn test_handler() {
+        // delegated to its own zone, with relative names and no $TTL
+        let lab = Zone::parse(
+            "@ SOA ns1 hostmaster 1 7200 900 1209600 300\n NS ns1\nns1 A 192.0.2.53",
+            Some(&Name::from("lab.example.com")),
+            Some(120),
+        )
+        .unwrap();
+        assert_eq!(120, lab.soa().ttl);
+        let chain = Chain::default().with(Authoritative::new(vec![
+            Zone::parse(ZONE, None, None).unwrap(),
+            lab,
+        ]));
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = |name| Message {
+            id: 7,
+            questions: vec![question(name, Type::A)],
+            ..Message::default()
+        };
+
+        let reply = chain.handle(&ctx, request("ns1.example.com")).unwrap();
+        assert_eq!((7, 1, 1), (reply.id, reply.aa, reply.answers.len()));
+        assert_eq!(
+            RData::A("192.0.2.1".parse().unwrap()),
+            reply.answers[0].rdata
+        );
+
+        // the longest matching zone owns the name
+        let reply = chain.handle(&ctx, request("ns1.lab.example.com")).unwrap();
+        assert_eq!(
+            RData::A("192.0.2.53".parse().unwrap()),
+            reply.answers[0].rdata
+        );
+        let reply = chain.handle(&ctx, request("www.lab.example.com")).unwrap();
+        assert_eq!(rcode::NXDOMAIN, reply.rcode);
+        assert_eq!(Name::from("lab.example.com"), reply.authorities[0].name);
+
+        // addresses of the exchange ride along with the MX
+        let mx = Message {
+            questions: vec![question("example.com", Type::MX)],
+            ..Message::default()
+        };
+        let reply = chain.handle(&ctx, mx).unwrap();
+        assert_eq!(
+            vec![
+                (Name::from("mail.example.com"), Type::A),
+                (Name::from("mail.example.com"), Type::AAAA)
+            ],
+            reply
+                .additionals
+                .iter()
+                .map(|r| (r.name.clone(), r.rtype))
+                .collect::<Vec<_>>()
+        );
+        let srv = Message {
+            questions: vec![question("_sip._tcp.example.com", Type::SRV)],
+            ..Message::default()
+        };
+        assert_eq!(2, chain.handle(&ctx, srv).unwrap().additionals.len());
+        let ns = Message {
+            questions: vec![question("lab.example.com", Type::NS)],
+            ..Message::default()
+        };
+        let reply = chain.handle(&ctx, ns).unwrap();
+        assert_eq!(
+            RData::A("192.0.2.53".parse().unwrap()),
+            reply.additionals[0].rdata
+        );
+
+        // outside the zones, nothing further down the chain answers
+        let reply = chain.handle(&ctx, request("example.net")).unwrap();
+        assert_eq!((0, rcode::REFUSED), (reply.aa, reply.rcode));
+    }
+
+    /// lb.example.net has an A record only, broken.example.net fails to
+    /// resolve. Counts the requests it gets.
+    #[derive(Default)]
+    struct Targets(AtomicUsize);
+
+    impl Resolver for Targets {
+        fn resolve(&self, request: &Message) -> Result<Message> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let q = &request.questions[0];
+            let mut reply = request.reply();
+            match (q.name.to_string().as_str(), q.qtype) {
+                ("lb.example.net", Type::A) => reply.answers.push(Record {
+                    name: q.name.clone(),
+                    rtype: Type::A,
+                    class: Class::IN,
+                    ttl: 30,
+                    rdata: RData::A("198.51.100.7".parse().unwrap()),
+                }),
+                ("lb.example.net", _) => reply.authorities.push(local_soa(&q.name)),
+                _ => bail!("timed out"),
+            }
+            Ok(reply)
+        }
+    }
+
+    #[test]
+    fn test_alias() {
+        let zone = Zone::parse(
+            "@ 300 SOA ns1 hostmaster 1 7200 900 1209600 300\n\
+             @ 300 NS ns1\n\
+             @ 300 ALIAS lb.example.net.\n\
+             ns1 300 A 192.0.2.53\n\
+             www 300 ALIAS broken.example.net.\n\
+             www 300 AAAA 2001:db8::80",
+            Some(&Name::from("example.org")),
+            None,
+        )
+        .unwrap();
+        let targets = Arc::new(Targets::default());
+        let chain = Chain::default()
+            .with(Authoritative::new(vec![zone]).with_alias_resolver(targets.clone()));
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = |name, qtype| Message {
+            questions: vec![question(name, qtype)],
+            ..Message::default()
+        };
+
+        // the target's address at the apex, twice from one resolution
+        for _ in 0..2 {
+            let reply = chain.handle(&ctx, request("example.org", Type::A)).unwrap();
+            assert_eq!((1, rcode::NOERROR), (reply.aa, reply.rcode));
+            assert_eq!(1, reply.answers.len());
+            assert_eq!(Name::from("example.org"), reply.answers[0].name);
+            assert_eq!(
+                RData::A("198.51.100.7".parse().unwrap()),
+                reply.answers[0].rdata
+            );
+            assert!(reply.answers[0].ttl <= 30);
+        }
+        assert_eq!(1, targets.0.load(Ordering::SeqCst));
+
+        // no AAAA for the target, NODATA cached by the negative TTL
+        for _ in 0..2 {
+            let reply = chain
+                .handle(&ctx, request("example.org", Type::AAAA))
+                .unwrap();
+            assert_eq!(rcode::NOERROR, reply.rcode);
+            assert!(reply.answers.is_empty());
+        }
+        assert_eq!(2, targets.0.load(Ordering::SeqCst));
+
+        // the ALIAS itself and the other types at the apex
+        let reply = chain
+            .handle(&ctx, request("example.org", Type::ALIAS))
+            .unwrap();
+        assert_eq!(
+            RData::ALIAS(Name::from("lb.example.net")),
+            reply.answers[0].rdata
+        );
+        let reply = chain
+            .handle(&ctx, request("example.org", Type::NS))
+            .unwrap();
+        assert_eq!(Type::NS, reply.answers[0].rtype);
+
+        // native addresses win, the target is only needed without them
+        let reply = chain
+            .handle(&ctx, request("www.example.org", Type::AAAA))
+            .unwrap();
+        assert_eq!(Type::AAAA, reply.answers[0].rtype);
+        let reply = chain
+            .handle(&ctx, request("www.example.org", Type::A))
+            .unwrap();
+        assert_eq!(rcode::SERVFAIL, reply.rcode);
+
+        // without a resolver there is nothing to flatten with
+        let zone = Zone::parse(
+            "@ 300 SOA ns1 hostmaster 1 7200 900 1209600 300\n@ 300 ALIAS lb.example.net.",
+            Some(&Name::from("example.org")),
+            None,
+        )
+        .unwrap();
+        let chain = Chain::default().with(Authoritative::new(vec![zone]));
+        let reply = chain.handle(&ctx, request("example.org", Type::A)).unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        assert!(reply.answers.is_empty());
+    }
+
+    #[test]
+    fn test_signatures() {
+        let mut zone = Zone::parse(ZONE, None, None).unwrap();
+        zone.sign(
+            &SigningKey::from_seed([7; 32]),
+            &DenialChain::Nsec,
+            1_700_000_000,
+        )
+        .unwrap();
+        let chain = Chain::default().with(Authoritative::new(vec![zone]));
+        let ctx = Context {
+            source: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            key: None,
+        };
+        let request = |name, qtype, dnssec_ok| Message {
+            questions: vec![question(name, qtype)],
+            opt: Some(Opt {
+                dnssec_ok,
+                ..Opt::default()
+            }),
+            ..Message::default()
+        };
+        let types = |records: &[Record]| records.iter().map(|r| r.rtype).collect::<Vec<_>>();
+
+        let reply = chain
+            .handle(&ctx, request("ns1.example.com", Type::A, false))
+            .unwrap();
+        assert_eq!(vec![Type::A], types(&reply.answers));
+        let reply = chain
+            .handle(&ctx, request("ns1.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(vec![Type::A, Type::RRSIG], types(&reply.answers));
+
+        let reply = chain
+            .handle(&ctx, request("example.com", Type::DNSKEY, true))
+            .unwrap();
+        assert_eq!(vec![Type::DNSKEY, Type::RRSIG], types(&reply.answers));
+
+        // the denial's SOA is signed as well, like the NSEC records
+        // covering the name and the wildcard that could have matched
+        let reply = chain
+            .handle(&ctx, request("nope.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(
+            vec![
+                Type::SOA,
+                Type::NSEC,
+                Type::NSEC,
+                Type::RRSIG,
+                Type::RRSIG,
+                Type::RRSIG
+            ],
+            types(&reply.authorities)
+        );
+
+        // a wildcard's signature moves along with its expansion
+        let reply = chain
+            .handle(&ctx, request("a.dyn.example.com", Type::A, true))
+            .unwrap();
+        assert_eq!(vec![Type::A, Type::RRSIG], types(&reply.answers));
+        assert_eq!(Name::from("a.dyn.example.com"), reply.answers[1].name);
+        match &reply.answers[1].rdata {
+            RData::RRSIG(sig) => assert_eq!(3, sig.labels),
+            other => panic!("expected an RRSIG, got {:?}", other),
+        }
+    }
+
+    /// Serves the zone to a validator.
+    struct Served(Chain);
+
+    impl Resolver for Served {
+        fn resolve(&self, request: &Message) -> anyhow::Result<Message> {
+            let ctx = Context {
+                source: "127.0.0.1:5353".parse().unwrap(),
+                transport: Transport::Udp,
+                key: None,
+            };
+            self.0.handle(&ctx, request.clone())
+        }
+    }
+
+    #[test]
+    fn test_denial() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let key = SigningKey::from_seed([7; 32]);
+        let chains = [
+            DenialChain::Nsec,
+            DenialChain::Nsec3 {
+                salt: Vec::new(),
+                iterations: 0,
+            },
+            DenialChain::Nsec3 {
+                salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                iterations: 5,
+            },
+        ];
+        for chain in chains {
+            let mut zone = Zone::parse(ZONE, None, None).unwrap();
+            zone.sign(&key, &chain, now).unwrap();
+            let anchor = TrustAnchor {
+                zone: zone.origin.clone(),
+                ds: dnssec::ds(&zone.origin, &key.dnskey(), dnssec::DIGEST_SHA256).unwrap(),
+            };
+            let served = Served(Chain::default().with(Authoritative::new(vec![zone])));
+            let validator = Validator::new(Arc::new(served), vec![anchor]);
+
+            // every answer, positive or not, validates as secure
+            for (name, qtype, expected) in [
+                ("nope.example.com", Type::A, rcode::NXDOMAIN),
+                ("x.nope.example.com", Type::A, rcode::NXDOMAIN),
+                ("ns1.example.com", Type::MX, rcode::NOERROR),
+                // empty non-terminal
+                ("deep.example.com", Type::A, rcode::NOERROR),
+                ("b.deep.example.com", Type::TXT, rcode::NOERROR),
+                // from wildcards, and where they lack the type
+                ("a.dyn.example.com", Type::A, rcode::NOERROR),
+                ("a.dyn.example.com", Type::MX, rcode::NOERROR),
+                ("x.alias.example.com", Type::A, rcode::NOERROR),
+                ("www.example.com", Type::AAAA, rcode::NOERROR),
+            ] {
+                let request = Message {
+                    questions: vec![question(name, qtype)],
+                    opt: Some(Opt {
+                        dnssec_ok: true,
+                        ..Opt::default()
+                    }),
+                    ..Message::default()
+                };
+                let reply = validator.resolve(&request).unwrap();
+                assert_eq!(
+                    (expected, 1),
+                    (reply.rcode, reply.ad),
+                    "{} {} with {:?}",
+                    name,
+                    qtype,
+                    chain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let zone = Zone {
+            allow_transfer: vec!["192.0.2.0/24".parse().unwrap()],
+            transfer_keys: vec![Name::from("xfr.example.com")],
+            ..Zone::parse(ZONE, None, None).unwrap()
+        };
+        let count = zone.records().count();
+        let chain = Chain::default().with(Authoritative::new(vec![zone]));
+        let ctx = |source: &str, transport| Context {
+            source: source.parse().unwrap(),
+            transport,
+            key: None,
+        };
+        let axfr = |name| Message {
+            questions: vec![question(name, Type::AXFR)],
+            ..Message::default()
+        };
+
+        let reply = chain
+            .handle(&ctx("192.0.2.9:4000", Transport::Tcp), axfr("example.com"))
+            .unwrap();
+        assert_eq!((1, rcode::NOERROR), (reply.aa, reply.rcode));
+        assert_eq!(count + 1, reply.answers.len());
+        assert_eq!(Type::SOA, reply.answers[0].rtype);
+        assert_eq!(Type::SOA, reply.answers[count].rtype);
+
+        let refused = [
+            ctx("192.0.2.9:4000", Transport::Udp),
+            ctx("198.51.100.1:4000", Transport::Tcp),
+        ];
+        for ctx in refused {
+            let reply = chain.handle(&ctx, axfr("example.com")).unwrap();
+            assert_eq!(rcode::REFUSED, reply.rcode);
+        }
+
+        // a transfer key works from anywhere
+        let signed = |key: &str| Context {
+            key: Some(Name::from(key)),
+            ..ctx("198.51.100.1:4000", Transport::Tcp)
+        };
+        let reply = chain
+            .handle(&signed("XFR.example.com."), axfr("example.com"))
+            .unwrap();
+        assert_eq!(rcode::NOERROR, reply.rcode);
+        let reply = chain
+            .handle(&signed("other.example.com"), axfr("example.com"))
+            .unwrap();
+        assert_eq!(rcode::REFUSED, reply.rcode);
+        let reply = chain
+            .handle(
+                &ctx("192.0.2.9:4000", Transport::Tcp),
+                axfr("www.example.com"),
+            )
+            .unwrap();
+        assert_eq!(rcode::NOTAUTH, reply.rcode);
+    }
+
+    #[test]
+    fn test_split_transfer() {
+        let mut text = String::from("$ORIGIN example.com.\n@ 60 SOA ns1 hm 1 2 3 4 5\n");
+        for i in 0..2000 {
+            text.push_str(&format!("host{} 60 A 192.0.2.{}\n", i, i % 256));
+        }
+        let zone = Zone::parse(&text, None, None).unwrap();
+        let reply = Message {
+            questions: vec![question("example.com", Type::AXFR)],
+            answers: zone.transfer(),
+            ..Message::default()
+        };
+
+        let messages = split_transfer(reply).unwrap();
+        assert!(messages.len() > 1);
+        for message in messages.iter() {
+            assert!(message.to_bytes().unwrap().len() <= 16 * 1024);
+            assert_eq!(1, message.questions.len());
+        }
+        let answers: Vec<_> = messages.iter().flat_map(|m| m.answers.iter()).collect();
+        assert_eq!(2002, answers.len());
+        assert_eq!(Type::SOA, answers[0].rtype);
+        assert_eq!(Type::SOA, answers[2001].rtype);
+
+        // anything else is left alone
+        let reply = Message {
+            questions: vec![question("example.com", Type::A)],
+            answers: zone.transfer(),
+            ..Message::default()
+        };
+        assert_eq!(1, split_transfer(reply).unwrap().len());
+    }
+
+    #[test]
+    fn test_origin_mismatch() {
+        let err = Zone::parse(ZONE, Some(&Name::from("example.net")), None).unwrap_err();
+        assert_eq!(
+            "SOA owner example.com isn't the zone example.net",
+            err.message
+        );
+    }
+}